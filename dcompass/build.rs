@@ -0,0 +1,23 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+fn main() {
+    // Only builds with the gRPC API need `protoc`.
+    #[cfg(feature = "grpc")]
+    tonic_build::configure()
+        .build_client(false)
+        .compile(&["proto/resolver.proto"], &["proto"])
+        .expect("failed to compile the protobuf definitions");
+}
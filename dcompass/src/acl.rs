@@ -0,0 +1,71 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Access control on the clients of the listener.
+
+use crate::parser::{AclAction, AclConfig, AclPolicy};
+use droute::utils::{IpCidr, Result, UtilsError};
+use std::net::IpAddr;
+
+/// The address as matched against CIDRs, as peers on IPv4 are seen as IPv4-mapped addresses on dual-stack sockets.
+pub fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+/// Client access control list
+pub struct Acl {
+    allow: IpCidr,
+    deny: IpCidr,
+    default: AclPolicy,
+    pub action: AclAction,
+}
+
+impl TryFrom<AclConfig> for Acl {
+    type Error = UtilsError;
+
+    fn try_from(config: AclConfig) -> Result<Self> {
+        let mut allow = IpCidr::new();
+        for cidr in config.allow {
+            allow.add_cidr(cidr)?;
+        }
+        let mut deny = IpCidr::new();
+        for cidr in config.deny {
+            deny.add_cidr(cidr)?;
+        }
+        Ok(Self {
+            allow,
+            deny,
+            default: config.default,
+            action: config.action,
+        })
+    }
+}
+
+impl Acl {
+    /// Whether queries from the client are allowed. Denied clients take precedence over the allowed ones.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = unmapped(ip);
+        if self.deny.contains(ip) {
+            false
+        } else if self.allow.contains(ip) {
+            true
+        } else {
+            self.default == AclPolicy::Allow
+        }
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Report the rules in the script of a live instance that never matched over a period, off the counters on its control endpoint.
+
+use anyhow::{Context, Result};
+use droute::utils::RuleStats;
+use std::{collections::BTreeMap, time::Duration};
+use structopt::StructOpt;
+
+/// Options of the rule analyzer
+#[derive(Debug, StructOpt)]
+pub struct AnalyzeOpts {
+    /// URL of the control endpoint of the instance, e.g. `http://127.0.0.1:8080`.
+    control: String,
+
+    /// Period in seconds to watch the rules over. Rules never matched since the instance started are reported if zero.
+    #[structopt(short, long, default_value = "0")]
+    period: u64,
+
+    /// Token of the control endpoint, if it requires one.
+    #[structopt(short, long)]
+    token: Option<String>,
+}
+
+async fn fetch(
+    client: &reqwest::Client,
+    url: &str,
+    token: Option<&str>,
+) -> Result<BTreeMap<String, RuleStats>> {
+    let mut req = client.get(url);
+    if let Some(token) = token {
+        req = req.bearer_auth(token);
+    }
+    req.send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .with_context(|| format!("failed to fetch the rules from {}", url))
+}
+
+// Counters accumulated between the two snapshots. Rules first seen in the later one, e.g. after a reload, count from zero.
+fn delta(
+    before: &BTreeMap<String, RuleStats>,
+    after: BTreeMap<String, RuleStats>,
+) -> BTreeMap<String, RuleStats> {
+    after
+        .into_iter()
+        .map(|(name, stats)| {
+            let base = before.get(&name).copied().unwrap_or_default();
+            let delta = RuleStats {
+                evaluations: stats.evaluations.saturating_sub(base.evaluations),
+                hits: stats.hits.saturating_sub(base.hits),
+            };
+            (name, delta)
+        })
+        .collect()
+}
+
+pub async fn run(opts: AnalyzeOpts) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let url = format!("{}/rules", opts.control.trim_end_matches('/'));
+
+    let token = opts.token.as_deref();
+    let mut rules = fetch(&client, &url, token).await?;
+    if opts.period > 0 {
+        println!("watching {} rules for {}s", rules.len(), opts.period);
+        tokio::time::sleep(Duration::from_secs(opts.period)).await;
+        rules = delta(&rules, fetch(&client, &url, token).await?);
+    }
+
+    let unused: Vec<_> = rules.iter().filter(|(_, stats)| stats.hits == 0).collect();
+    println!("{} of {} rules never matched", unused.len(), rules.len());
+    for (name, stats) in unused {
+        println!("{}\t{} evaluations", name, stats.evaluations);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::delta;
+    use droute::utils::RuleStats;
+    use std::collections::BTreeMap;
+
+    fn stats(evaluations: u64, hits: u64) -> RuleStats {
+        RuleStats { evaluations, hits }
+    }
+
+    #[test]
+    fn period() {
+        let before = BTreeMap::from([("ads".to_string(), stats(10, 4))]);
+        let after = BTreeMap::from([
+            ("ads".to_string(), stats(15, 4)),
+            ("corp".to_string(), stats(3, 1)),
+        ]);
+        let delta = delta(&before, after);
+        assert_eq!(delta["ads"], stats(5, 0));
+        assert_eq!(delta["corp"], stats(3, 1));
+    }
+}
@@ -0,0 +1,420 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Batched datagram I/O. `recvmmsg`/`sendmmsg` are used on Linux, other platforms drain the socket one datagram at a time.
+
+use bytes::Bytes;
+use domain::base::Message;
+use droute::privacy;
+use log::*;
+use std::{
+    fmt,
+    io::{ErrorKind, Result},
+    net::{IpAddr, SocketAddr},
+};
+use tokio::net::UdpSocket;
+
+#[cfg(target_os = "linux")]
+pub use mmsg::enable_pktinfo;
+
+/// Maximum number of datagrams handled in one batch.
+pub const BATCH: usize = 32;
+
+// Size recommended by DNS Flag Day 2020: "This is practical for the server operators that know their environment, and the defaults in the DNS software should reflect the minimum safe size which is 1232."
+const BUF_SIZE: usize = 1024;
+
+/// The remote end of a datagram, along with the local address it was sent to if known.
+/// On multi-homed hosts, responses have to be sent from that same local address, or they may be dropped by the clients.
+#[derive(Clone, Copy, Debug)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub local: Option<IpAddr>,
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Self { addr, local: None }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", privacy::client(self.addr))
+    }
+}
+
+/// Receive a batch of datagrams, waiting until at least one of them is available.
+pub async fn recv_batch(socket: &UdpSocket) -> Result<Vec<(Bytes, Peer)>> {
+    loop {
+        socket.readable().await?;
+        match try_recv_batch(socket) {
+            Ok(pkts) => return Ok(pkts),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Send all the responses given, in as few syscalls as possible.
+pub async fn send_batch(socket: &UdpSocket, mut pkts: &[(Message<Bytes>, Peer)]) {
+    while !pkts.is_empty() {
+        if let Err(e) = socket.writable().await {
+            warn!("failed to send back responses: {}", e);
+            return;
+        }
+        match try_send_batch(socket, pkts) {
+            Ok(n) => {
+                for (_, dst) in &pkts[..n] {
+                    info!("response completed. Sent back to {} successfully.", dst);
+                }
+                pkts = &pkts[n..];
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            // On windows, some applications may go away after they got their first response, resulting in a broken pipe, we should discard errors on receiving/sending messages.
+            // The error is about the first datagram in the batch, skip it and carry on with the rest.
+            Err(e) => {
+                warn!("failed to send back response to {}: {}", pkts[0].1, e);
+                pkts = &pkts[1..];
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn try_recv_batch(socket: &UdpSocket) -> Result<Vec<(Bytes, Peer)>> {
+    socket.try_io(tokio::io::Interest::READABLE, || mmsg::recv(socket))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn try_recv_batch(socket: &UdpSocket) -> Result<Vec<(Bytes, Peer)>> {
+    let mut pkts = Vec::new();
+    while pkts.len() < BATCH {
+        let mut buf = bytes::BytesMut::with_capacity(BUF_SIZE);
+        buf.resize(BUF_SIZE, 0);
+        match socket.try_recv_from(&mut buf) {
+            Ok((len, src)) => {
+                buf.truncate(len);
+                pkts.push((buf.freeze(), src.into()));
+            }
+            Err(e) if pkts.is_empty() => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok(pkts)
+}
+
+#[cfg(target_os = "linux")]
+fn try_send_batch(socket: &UdpSocket, pkts: &[(Message<Bytes>, Peer)]) -> Result<usize> {
+    socket.try_io(tokio::io::Interest::WRITABLE, || mmsg::send(socket, pkts))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn try_send_batch(socket: &UdpSocket, pkts: &[(Message<Bytes>, Peer)]) -> Result<usize> {
+    let (resp, dst) = &pkts[0];
+    socket.try_send_to(resp.as_slice(), dst.addr).map(|_| 1)
+}
+
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use super::{Peer, BATCH, BUF_SIZE};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::Message;
+    use libc::{
+        c_int, c_void, in6_addr, in6_pktinfo, in_addr, in_pktinfo, iovec, mmsghdr, msghdr,
+        sa_family_t, sockaddr_in, sockaddr_in6, sockaddr_storage, socklen_t, AF_INET, AF_INET6,
+        IPPROTO_IP, IPPROTO_IPV6, IPV6_PKTINFO, IPV6_RECVPKTINFO, IP_PKTINFO,
+    };
+    use std::{
+        io::{Error, Result},
+        mem,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+        os::unix::io::{AsRawFd, RawFd},
+        ptr,
+    };
+    use tokio::net::UdpSocket;
+
+    // Large enough for a single control message carrying either `in_pktinfo` or `in6_pktinfo`.
+    const CMSG_BUF: usize = 64;
+
+    // Control messages have to be aligned like `cmsghdr`.
+    #[repr(C, align(8))]
+    #[derive(Clone, Copy)]
+    struct Cmsg([u8; CMSG_BUF]);
+
+    /// Ask the kernel for the destination address of each datagram received on the socket, which is bound to an unspecified address.
+    pub fn enable_pktinfo(fd: RawFd, v6: bool, dual_stack: bool) -> Result<()> {
+        if !v6 || dual_stack {
+            setsockopt(fd, IPPROTO_IP, IP_PKTINFO)?;
+        }
+        if v6 {
+            setsockopt(fd, IPPROTO_IPV6, IPV6_RECVPKTINFO)?;
+        }
+        Ok(())
+    }
+
+    fn setsockopt(fd: RawFd, level: c_int, name: c_int) -> Result<()> {
+        let on: c_int = 1;
+        // SAFETY: the option value points to a valid `c_int` with its size given.
+        let r = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &on as *const c_int as *const c_void,
+                mem::size_of::<c_int>() as socklen_t,
+            )
+        };
+        if r < 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn recv(socket: &UdpSocket) -> Result<Vec<(Bytes, Peer)>> {
+        let mut buf = BytesMut::with_capacity(BATCH * BUF_SIZE);
+        buf.resize(BATCH * BUF_SIZE, 0);
+        // SAFETY: all-zero is a valid value for these plain C structs.
+        let mut addrs: [sockaddr_storage; BATCH] = unsafe { mem::zeroed() };
+        let mut cmsgs = [Cmsg([0; CMSG_BUF]); BATCH];
+        let mut iovecs: Vec<iovec> = buf
+            .chunks_mut(BUF_SIZE)
+            .map(|chunk| iovec {
+                iov_base: chunk.as_mut_ptr() as *mut c_void,
+                iov_len: chunk.len(),
+            })
+            .collect();
+        let mut msgs: Vec<mmsghdr> = addrs
+            .iter_mut()
+            .zip(iovecs.iter_mut())
+            .zip(cmsgs.iter_mut())
+            .map(|((addr, iov), cmsg)| {
+                // SAFETY: as above.
+                let mut msg: mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_name = addr as *mut sockaddr_storage as *mut c_void;
+                msg.msg_hdr.msg_namelen = mem::size_of::<sockaddr_storage>() as socklen_t;
+                msg.msg_hdr.msg_iov = iov;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg.msg_hdr.msg_control = cmsg.0.as_mut_ptr() as *mut c_void;
+                msg.msg_hdr.msg_controllen = CMSG_BUF as _;
+                msg
+            })
+            .collect();
+
+        // SAFETY: every message header points to an address, a buffer, and a control buffer that outlive the call.
+        let n = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as _,
+                0,
+                ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(msgs
+            .iter()
+            .zip(addrs.iter())
+            .take(n as usize)
+            .filter_map(|(msg, addr)| {
+                let mut chunk = buf.split_to(BUF_SIZE);
+                chunk.truncate(msg.msg_len as usize);
+                Some((
+                    chunk.freeze(),
+                    Peer {
+                        addr: from_raw(addr)?,
+                        local: local_addr(&msg.msg_hdr),
+                    },
+                ))
+            })
+            .collect())
+    }
+
+    pub fn send(socket: &UdpSocket, pkts: &[(Message<Bytes>, Peer)]) -> Result<usize> {
+        let pkts = &pkts[..pkts.len().min(BATCH)];
+        let mut addrs: Vec<(sockaddr_storage, socklen_t)> =
+            pkts.iter().map(|(_, dst)| to_raw(&dst.addr)).collect();
+        let mut cmsgs = vec![Cmsg([0; CMSG_BUF]); pkts.len()];
+        let mut iovecs: Vec<iovec> = pkts
+            .iter()
+            .map(|(resp, _)| iovec {
+                // sendmmsg never writes to the buffers.
+                iov_base: resp.as_slice().as_ptr() as *mut c_void,
+                iov_len: resp.as_slice().len(),
+            })
+            .collect();
+        let mut msgs: Vec<mmsghdr> = addrs
+            .iter_mut()
+            .zip(iovecs.iter_mut())
+            .zip(cmsgs.iter_mut().zip(pkts.iter()))
+            .map(|(((addr, len), iov), (cmsg, (_, dst)))| {
+                // SAFETY: all-zero is a valid value for this plain C struct.
+                let mut msg: mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_name = addr as *mut sockaddr_storage as *mut c_void;
+                msg.msg_hdr.msg_namelen = *len;
+                msg.msg_hdr.msg_iov = iov;
+                msg.msg_hdr.msg_iovlen = 1;
+                // Send from the address the query was sent to.
+                if let Some(local) = dst.local {
+                    set_local_addr(&mut msg.msg_hdr, cmsg, local);
+                }
+                msg
+            })
+            .collect();
+
+        // SAFETY: every message header points to an address, a buffer, and a control buffer that outlive the call.
+        let n =
+            unsafe { libc::sendmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as _, 0) };
+        if n < 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    // Find the destination address of the datagram in the control messages received.
+    fn local_addr(hdr: &msghdr) -> Option<IpAddr> {
+        // SAFETY: the control buffer is filled in by the kernel, and `CMSG_*` macros never go beyond `msg_controllen`.
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+            while !cmsg.is_null() {
+                match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                    (IPPROTO_IP, IP_PKTINFO) => {
+                        let info = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const in_pktinfo);
+                        return Some(IpAddr::V4(Ipv4Addr::from(
+                            info.ipi_spec_dst.s_addr.to_ne_bytes(),
+                        )));
+                    }
+                    (IPPROTO_IPV6, IPV6_PKTINFO) => {
+                        let info = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const in6_pktinfo);
+                        return Some(IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)));
+                    }
+                    _ => cmsg = libc::CMSG_NXTHDR(hdr, cmsg),
+                }
+            }
+        }
+        None
+    }
+
+    // Attach a control message setting the source address of the datagram.
+    fn set_local_addr(hdr: &mut msghdr, cmsg: &mut Cmsg, local: IpAddr) {
+        let (level, ty, len) = match local {
+            IpAddr::V4(_) => (IPPROTO_IP, IP_PKTINFO, mem::size_of::<in_pktinfo>()),
+            IpAddr::V6(_) => (IPPROTO_IPV6, IPV6_PKTINFO, mem::size_of::<in6_pktinfo>()),
+        };
+        hdr.msg_control = cmsg.0.as_mut_ptr() as *mut c_void;
+        // SAFETY: `CMSG_BUF` is large enough for a single control message of either type, and the buffer is suitably aligned.
+        unsafe {
+            hdr.msg_controllen = libc::CMSG_SPACE(len as u32) as _;
+            let c = libc::CMSG_FIRSTHDR(hdr);
+            (*c).cmsg_level = level;
+            (*c).cmsg_type = ty;
+            (*c).cmsg_len = libc::CMSG_LEN(len as u32) as _;
+            match local {
+                IpAddr::V4(ip) => ptr::write_unaligned(
+                    libc::CMSG_DATA(c) as *mut in_pktinfo,
+                    in_pktinfo {
+                        ipi_ifindex: 0,
+                        ipi_spec_dst: in_addr {
+                            s_addr: u32::from_ne_bytes(ip.octets()),
+                        },
+                        ipi_addr: in_addr { s_addr: 0 },
+                    },
+                ),
+                IpAddr::V6(ip) => ptr::write_unaligned(
+                    libc::CMSG_DATA(c) as *mut in6_pktinfo,
+                    in6_pktinfo {
+                        ipi6_addr: in6_addr {
+                            s6_addr: ip.octets(),
+                        },
+                        ipi6_ifindex: 0,
+                    },
+                ),
+            }
+        }
+    }
+
+    fn from_raw(addr: &sockaddr_storage) -> Option<SocketAddr> {
+        match addr.ss_family as c_int {
+            AF_INET => {
+                // SAFETY: the address family tells us the actual type of the storage.
+                let sin = unsafe { &*(addr as *const sockaddr_storage as *const sockaddr_in) };
+                Some(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()),
+                    u16::from_be(sin.sin_port),
+                )))
+            }
+            AF_INET6 => {
+                // SAFETY: as above.
+                let sin6 = unsafe { &*(addr as *const sockaddr_storage as *const sockaddr_in6) };
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                    u16::from_be(sin6.sin6_port),
+                    sin6.sin6_flowinfo,
+                    sin6.sin6_scope_id,
+                )))
+            }
+            _ => None,
+        }
+    }
+
+    fn to_raw(addr: &SocketAddr) -> (sockaddr_storage, socklen_t) {
+        // SAFETY: all-zero is a valid value for this plain C struct.
+        let mut storage: sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let sin = sockaddr_in {
+                    sin_family: AF_INET as sa_family_t,
+                    sin_port: addr.port().to_be(),
+                    sin_addr: in_addr {
+                        s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                    },
+                    sin_zero: [0; 8],
+                };
+                // SAFETY: sockaddr_storage is large enough and suitably aligned for any address type.
+                unsafe {
+                    ptr::write(
+                        &mut storage as *mut sockaddr_storage as *mut sockaddr_in,
+                        sin,
+                    )
+                };
+                mem::size_of::<sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = sockaddr_in6 {
+                    sin6_family: AF_INET6 as sa_family_t,
+                    sin6_port: addr.port().to_be(),
+                    sin6_flowinfo: addr.flowinfo(),
+                    sin6_addr: in6_addr {
+                        s6_addr: addr.ip().octets(),
+                    },
+                    sin6_scope_id: addr.scope_id(),
+                };
+                // SAFETY: as above.
+                unsafe {
+                    ptr::write(
+                        &mut storage as *mut sockaddr_storage as *mut sockaddr_in6,
+                        sin6,
+                    )
+                };
+                mem::size_of::<sockaddr_in6>()
+            }
+        };
+        (storage, len as socklen_t)
+    }
+}
@@ -0,0 +1,667 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Cluster mode. Followers pull their configuration and rule lists from the leader, or from any HTTP endpoint serving them alongside their signatures, and push their statistics back to the leader.
+//!
+//! Files are served under `/files/<name>`, with the serial and the hex-encoded ed25519 signature of each under `/files/<name>.sig`,
+//! or with the minisign signature under `/files/<name>.minisig` if the files are verified against a minisign key.
+//! Signatures cover the name and the serial of the file along with its content, and followers never go back to an older serial.
+//! Rule lists can also be pulled from their own sources, each verified against the key of its source.
+//! Statistics are pushed to `/stats/<node>` and aggregated under `/stats`.
+
+use crate::{
+    control::bearer,
+    handle::RouterHandle,
+    hooks::{Event, Hooks},
+    parser::ClusterConfig,
+    stats::{now, Stats},
+};
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use blake2::{Blake2b512, Digest};
+use bytes::Bytes;
+use droute::{builders::RuneScript, Router};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::*;
+use rand::RngCore;
+use serde_json::{json, Map, Value};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    ffi::OsStr,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+use structopt::StructOpt;
+use tokio::time::{interval, MissedTickBehavior};
+
+/// Name of the configuration file among the files served
+pub const CONFIG: &str = "config.yaml";
+
+// Files in the directory of the follower, besides the rule lists pulled: the serials of the files applied,
+// the directory the files pulled are written to, and the one the files they replace are kept in until the update is applied.
+const SERIALS: &str = ".serials.json";
+const STAGING: &str = ".staging";
+const BACKUP: &str = ".backup";
+
+// Statistics pushed by followers larger than this are rejected.
+const MAX_REPORT_SIZE: usize = 1 << 20;
+// Nodes kept at most by the leader, and the seconds after which a node no longer pushing its statistics is dropped, e.g. once decommissioned.
+const MAX_NODES: usize = 1024;
+const NODE_EXPIRY: u64 = 3600;
+// Node names longer than this are rejected.
+const MAX_NODE_LEN: usize = 255;
+
+/// Options of the leader
+#[derive(Debug, StructOpt)]
+pub struct LeaderOpts {
+    /// Address to serve the followers on.
+    #[structopt(short, long, default_value = "0.0.0.0:8053")]
+    listen: SocketAddr,
+
+    /// Directory holding the configuration (`config.yaml`) and the rule lists to serve. Files are read on every request.
+    #[structopt(short, long, parse(from_os_str))]
+    dir: PathBuf,
+
+    /// File containing the hex-encoded secret key to sign the files with.
+    #[structopt(short, long, parse(from_os_str))]
+    key: PathBuf,
+
+    /// Token the followers have to present to push their statistics.
+    #[structopt(short, long)]
+    token: Option<String>,
+}
+
+/// Options of the signing tool, for serving the files from a plain HTTP endpoint instead of the leader
+#[derive(Debug, StructOpt)]
+pub struct SignOpts {
+    /// File containing the hex-encoded secret key to sign the files with.
+    #[structopt(short, long, parse(from_os_str))]
+    key: PathBuf,
+
+    /// Files to sign. The signature of each is written next to it with the `.sig` extension appended.
+    #[structopt(parse(from_os_str), required = true)]
+    files: Vec<PathBuf>,
+}
+
+// Message signed for the file, so that neither another file nor an older version of it can pass for it.
+fn signed(name: &str, serial: u64, content: &[u8]) -> Vec<u8> {
+    [format!("{}\n{}\n", name, serial).as_bytes(), content].concat()
+}
+
+/// Sign the file, returning its serial followed by the hex-encoded signature.
+pub fn sign(key: &Keypair, name: &str, serial: u64, content: &[u8]) -> String {
+    format!(
+        "{} {}",
+        serial,
+        hex::encode(key.sign(&signed(name, serial, content)).to_bytes())
+    )
+}
+
+/// Verify the file against the signature, returning the serial signed.
+pub fn verify(key: &PublicKey, name: &str, content: &[u8], sig: &[u8]) -> Result<u64> {
+    let (serial, sig) = std::str::from_utf8(sig)?
+        .trim()
+        .split_once(' ')
+        .context("malformed signature")?;
+    let serial = serial.parse().context("malformed signature")?;
+    let sig = hex::decode(sig)?;
+    key.verify(
+        &signed(name, serial, content),
+        &Signature::try_from(sig.as_slice())?,
+    )?;
+    Ok(serial)
+}
+
+/// Serial of the file to sign, i.e. the time it was last modified in seconds since UNIX epoch.
+pub fn serial(path: &Path) -> Result<u64> {
+    Ok(std::fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)?
+        .as_secs())
+}
+
+/// Key the files pulled are verified against
+pub enum VerifyingKey {
+    /// Hex-encoded ed25519 key, whose signatures are hex-encoded
+    Ed25519(PublicKey),
+    /// Minisign key along with its ID
+    Minisign([u8; 8], PublicKey),
+}
+
+impl VerifyingKey {
+    /// Extension of the signatures appended to the URLs of the files.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Ed25519(_) => ".sig",
+            Self::Minisign(..) => ".minisig",
+        }
+    }
+
+    /// Verify the file named against the signature, either hex-encoded or in the minisign format, returning the serial signed.
+    pub fn verify(&self, name: &str, content: &[u8], sig: &[u8]) -> Result<u64> {
+        let (id, key) = match self {
+            Self::Ed25519(key) => return verify(key, name, content, sig),
+            Self::Minisign(id, key) => (id, key),
+        };
+        // Untrusted comment, signature, trusted comment, and the signature over the signature and the trusted comment
+        let lines: Vec<_> = std::str::from_utf8(sig)?.lines().map(str::trim).collect();
+        let (sig, trusted, global) = match lines.as_slice() {
+            [_, sig, trusted, global, ..] => (
+                STANDARD.decode(sig)?,
+                trusted
+                    .strip_prefix("trusted comment: ")
+                    .context("malformed minisign signature")?,
+                STANDARD.decode(global)?,
+            ),
+            _ => anyhow::bail!("malformed minisign signature"),
+        };
+        if sig.len() != 74 {
+            anyhow::bail!("malformed minisign signature");
+        }
+        if &sig[2..10] != id {
+            anyhow::bail!("signed with another minisign key");
+        }
+        let signature = Signature::try_from(&sig[10..])?;
+        match &sig[..2] {
+            b"Ed" => key.verify(content, &signature)?,
+            // Prehashed
+            b"ED" => key.verify(&Blake2b512::digest(content), &signature)?,
+            _ => anyhow::bail!("unknown minisign signature algorithm"),
+        }
+        key.verify(
+            &[&sig[10..], trusted.as_bytes()].concat(),
+            &Signature::try_from(global.as_slice())?,
+        )?;
+        // The trusted comment tells the file signed and when, e.g. `timestamp:1700000000\tfile:ads.txt`.
+        let field = |key: &str| {
+            trusted
+                .split('\t')
+                .find_map(|f| f.strip_prefix(key)?.strip_prefix(':'))
+        };
+        if field("file") != Some(name) {
+            anyhow::bail!("signature made for another file than {}", name);
+        }
+        field("timestamp")
+            .and_then(|t| t.parse().ok())
+            .context("minisign signature without timestamp")
+    }
+}
+
+/// Parse the public key, either hex-encoded ed25519 or minisign, with or without its untrusted comment.
+pub fn public_key(s: &str) -> Result<VerifyingKey> {
+    let s = s.trim();
+    if let Ok(key) = hex::decode(s) {
+        return Ok(VerifyingKey::Ed25519(PublicKey::from_bytes(&key)?));
+    }
+    let key = STANDARD
+        .decode(s.lines().last().unwrap_or_default().trim())
+        .context("neither a hex-encoded ed25519 key nor a minisign key")?;
+    match key.as_slice() {
+        [b'E', b'd', rest @ ..] if rest.len() == 40 => Ok(VerifyingKey::Minisign(
+            rest[..8].try_into()?,
+            PublicKey::from_bytes(&rest[8..])?,
+        )),
+        _ => anyhow::bail!("invalid minisign public key"),
+    }
+}
+
+// Read the hex-encoded secret key from the file.
+fn keypair(path: &Path) -> Result<Keypair> {
+    let hex = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read the key from {}", path.display()))?;
+    let secret = SecretKey::from_bytes(&hex::decode(hex.trim())?)?;
+    let public = PublicKey::from(&secret);
+    Ok(Keypair { secret, public })
+}
+
+/// Generate a new key pair and print it out.
+pub fn keygen() -> Result<()> {
+    let mut bytes = [0; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let secret = SecretKey::from_bytes(&bytes)?;
+    println!("secret key: {}", hex::encode(secret.as_bytes()));
+    println!(
+        "public key: {}",
+        hex::encode(PublicKey::from(&secret).as_bytes())
+    );
+    Ok(())
+}
+
+/// Sign the files given, writing the signatures next to them.
+pub fn sign_files(opts: SignOpts) -> Result<()> {
+    let key = keypair(&opts.key)?;
+    for path in opts.files {
+        let content =
+            std::fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+        let name = path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .with_context(|| format!("invalid file name: {}", path.display()))?;
+        let sig = sign(&key, name, serial(&path)?, &content);
+        let mut sig_path = path.into_os_string();
+        sig_path.push(".sig");
+        std::fs::write(&sig_path, sig)?;
+        println!("signed {}", Path::new(&sig_path).display());
+    }
+    Ok(())
+}
+
+// Files are only served from and written to the directory itself.
+fn valid_name(name: &str) -> bool {
+    Path::new(name).file_name() == Some(OsStr::new(name))
+}
+
+/// Add up the counters reported by the nodes.
+pub fn sum(total: &mut Value, report: &Value) {
+    match report {
+        Value::Object(report) => {
+            if !total.is_object() {
+                *total = Value::Object(Map::new());
+            }
+            let total = total.as_object_mut().unwrap();
+            for (k, v) in report {
+                sum(total.entry(k.clone()).or_insert(Value::Null), v);
+            }
+        }
+        Value::Number(n) => {
+            if let Some(n) = n.as_u64() {
+                *total = (total.as_u64().unwrap_or(0) + n).into();
+            }
+        }
+        _ => (),
+    }
+}
+
+struct Leader {
+    dir: PathBuf,
+    key: Keypair,
+    token: Option<String>,
+    // The latest report of each node with the time (in seconds since UNIX epoch) it was received
+    nodes: Mutex<HashMap<String, (u64, Value)>>,
+}
+
+impl Leader {
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        match self.route(req).await {
+            Ok(resp) => resp,
+            Err(status) => Response::builder()
+                .status(status)
+                .body(Body::empty())
+                .unwrap(),
+        }
+    }
+
+    async fn route(&self, req: Request<Body>) -> std::result::Result<Response<Body>, StatusCode> {
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            (Method::GET, &["files", name]) => {
+                let (name, sig) = match name.strip_suffix(".sig") {
+                    Some(name) => (name, true),
+                    None => (name, false),
+                };
+                if !valid_name(name) {
+                    return Err(StatusCode::NOT_FOUND);
+                }
+                let path = self.dir.join(name);
+                let content = tokio::fs::read(&path)
+                    .await
+                    .map_err(|_| StatusCode::NOT_FOUND)?;
+                Ok(Response::new(Body::from(if sig {
+                    let serial = serial(&path).map_err(|_| StatusCode::NOT_FOUND)?;
+                    sign(&self.key, name, serial, &content).into_bytes()
+                } else {
+                    content
+                })))
+            }
+            (Method::POST, &["stats", node]) => {
+                if let Some(token) = &self.token {
+                    if !bearer(&req, token) {
+                        return Err(StatusCode::UNAUTHORIZED);
+                    }
+                }
+                if node.len() > MAX_NODE_LEN {
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                let node = node.to_string();
+                let body = crate::doh::read_body(req.into_body(), MAX_REPORT_SIZE).await?;
+                let report: Value =
+                    serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+                let mut nodes = self.nodes.lock().unwrap();
+                expire(&mut nodes);
+                if nodes.len() >= MAX_NODES && !nodes.contains_key(&node) {
+                    warn!(
+                        "statistics from {} rejected, {} nodes reporting already",
+                        node, MAX_NODES
+                    );
+                    return Err(StatusCode::TOO_MANY_REQUESTS);
+                }
+                debug!("statistics received from {}", node);
+                nodes.insert(node, (now(), report));
+                Ok(Response::new(Body::empty()))
+            }
+            (Method::GET, &["stats"]) => {
+                let mut nodes = self.nodes.lock().unwrap();
+                expire(&mut nodes);
+                let mut total = Value::Null;
+                for (_, report) in nodes.values() {
+                    sum(&mut total, report);
+                }
+                let nodes: Map<String, Value> = nodes
+                    .iter()
+                    .map(|(node, (updated, report))| {
+                        (node.clone(), json!({ "updated": updated, "stats": report }))
+                    })
+                    .collect();
+                Ok(Response::new(Body::from(
+                    json!({ "total": total, "nodes": nodes }).to_string(),
+                )))
+            }
+            _ => Err(StatusCode::NOT_FOUND),
+        }
+    }
+}
+
+// Drop the nodes that stopped pushing their statistics.
+fn expire(nodes: &mut HashMap<String, (u64, Value)>) {
+    let now = now();
+    nodes.retain(|_, (updated, _)| now.saturating_sub(*updated) < NODE_EXPIRY);
+}
+
+/// Serve the files to the followers and collect their statistics.
+pub async fn lead(opts: LeaderOpts) -> Result<()> {
+    let leader = Arc::new(Leader {
+        key: keypair(&opts.key)?,
+        dir: opts.dir,
+        token: opts.token,
+        nodes: Mutex::new(HashMap::new()),
+    });
+    let make_svc = make_service_fn(move |_| {
+        let leader = leader.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let leader = leader.clone();
+                async move { Ok::<_, Infallible>(leader.handle(req).await) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&opts.listen)
+        .with_context(|| format!("failed to bind to {}", opts.listen))?;
+    info!("serving followers on {}", opts.listen);
+    server.serve(make_svc).await?;
+    Ok(())
+}
+
+struct Follower {
+    config: ClusterConfig,
+    key: VerifyingKey,
+    // Rule lists pulled from their own sources, along with their keys
+    sources: Vec<(String, String, VerifyingKey)>,
+    client: reqwest::Client,
+    // Content of the files last applied
+    pulled: HashMap<String, Bytes>,
+    // Serials of the files last applied, kept across restarts
+    serials: HashMap<String, u64>,
+}
+
+impl Follower {
+    async fn get(&self, url: &str) -> Result<Bytes> {
+        Ok(self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?)
+    }
+
+    // Fetch the file along with its signature, and verify it against the key, returning its content and serial.
+    // Files are signed under the names they are published with, i.e. the last segment of their URLs.
+    async fn fetch(&self, name: &str, url: &str, key: &VerifyingKey) -> Result<(Bytes, u64)> {
+        let content = self.get(url).await?;
+        let sig = self.get(&format!("{}{}", url, key.extension())).await?;
+        let published = url.rsplit('/').next().unwrap_or_default();
+        let serial = key
+            .verify(published, &content, &sig)
+            .with_context(|| format!("invalid signature on {}", name))?;
+        // Replaying an older version would undo the updates since.
+        match self.serials.get(name) {
+            Some(last) if serial < *last => {
+                anyhow::bail!("{} is older than the version applied", name)
+            }
+            Some(last)
+                if serial == *last && self.pulled.get(name).map_or(false, |c| *c != content) =>
+            {
+                anyhow::bail!("{} changed without a newer serial", name)
+            }
+            _ => Ok((content, serial)),
+        }
+    }
+
+    // Pull the files and build the router out of them, or `None` if nothing has changed.
+    async fn pull(&mut self) -> Result<Option<Router<RuneScript>>> {
+        // Nothing is applied unless every file is authentic.
+        let mut files = HashMap::new();
+        let mut serials = HashMap::new();
+        for name in self.config.files.iter().map(String::as_str).chain([CONFIG]) {
+            let url = format!(
+                "{}/files/{}",
+                self.config.leader.trim_end_matches('/'),
+                name
+            );
+            let (content, serial) = self.fetch(name, &url, &self.key).await?;
+            files.insert(name.to_string(), content);
+            serials.insert(name.to_string(), serial);
+        }
+        for (name, url, key) in &self.sources {
+            let (content, serial) = self.fetch(name, url, key).await?;
+            files.insert(name.clone(), content);
+            serials.insert(name.clone(), serial);
+        }
+        if files == self.pulled {
+            return Ok(None);
+        }
+
+        // The lists changed are written aside first, moved in for the configuration to be built on,
+        // and moved back out if it is rejected, so that the directory never holds a half-applied update.
+        let changed: Vec<&str> = files
+            .iter()
+            .filter(|(name, content)| *name != CONFIG && self.pulled.get(*name) != Some(content))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        let (staging, backup) = (self.config.dir.join(STAGING), self.config.dir.join(BACKUP));
+        for dir in [&staging, &backup] {
+            if tokio::fs::metadata(dir).await.is_ok() {
+                tokio::fs::remove_dir_all(dir).await?;
+            }
+            tokio::fs::create_dir(dir).await?;
+        }
+        for name in &changed {
+            tokio::fs::write(staging.join(name), &files[*name]).await?;
+        }
+        let mut moved = Vec::new();
+        let built = match install(&self.config.dir, &changed, &mut moved).await {
+            Ok(()) => build(&files[CONFIG]).await,
+            Err(e) => Err(e),
+        };
+        let router = match built {
+            Ok(router) => router,
+            Err(e) => {
+                restore(&self.config.dir, &moved).await;
+                return Err(e);
+            }
+        };
+        self.pulled = files;
+        self.serials = serials;
+        if let Err(e) = write(
+            &self.config.dir.join(SERIALS),
+            &serde_json::to_vec(&self.serials)?,
+        )
+        .await
+        {
+            warn!("failed to save the serials of the files pulled: {}", e);
+        }
+        Ok(Some(router))
+    }
+
+    async fn push(&self, router: &RouterHandle, stats: &Stats) -> Result<()> {
+        let mut req = self
+            .client
+            .post(format!(
+                "{}/stats/{}",
+                self.config.leader.trim_end_matches('/'),
+                self.config.node
+            ))
+            .json(&json!({ "listener": stats.snapshot(), "router": router.get().stats() }));
+        if let Some(token) = &self.config.token {
+            req = req.bearer_auth(token);
+        }
+        req.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+// Build the router out of the configuration pulled.
+async fn build(config: &[u8]) -> Result<Router<RuneScript>> {
+    let parsed = crate::profile::parse(&String::from_utf8_lossy(config), None)
+        .with_context(|| "failed to parse the configuration pulled".to_string())?;
+    for deprecation in &parsed.deprecations {
+        warn!("configuration pulled: {}", deprecation);
+    }
+    let (router, ..) = crate::init(crate::stamps::load(parsed).await?).await?;
+    Ok(router)
+}
+
+// Move the files staged into the directory, keeping the ones they replace in the backup, and noting the ones moved.
+async fn install<'a>(dir: &Path, names: &[&'a str], moved: &mut Vec<&'a str>) -> Result<()> {
+    for name in names {
+        let path = dir.join(name);
+        if tokio::fs::metadata(&path).await.is_ok() {
+            tokio::fs::rename(&path, dir.join(BACKUP).join(name)).await?;
+        }
+        moved.push(name);
+        tokio::fs::rename(dir.join(STAGING).join(name), &path).await?;
+    }
+    Ok(())
+}
+
+// Put the files replaced back, and remove the ones which were not there before.
+async fn restore(dir: &Path, moved: &[&str]) {
+    for name in moved {
+        let (path, backup) = (dir.join(name), dir.join(BACKUP).join(name));
+        let res = if tokio::fs::metadata(&backup).await.is_ok() {
+            tokio::fs::rename(&backup, &path).await
+        } else {
+            tokio::fs::remove_file(&path).await
+        };
+        if let Err(e) = res {
+            warn!("failed to restore {}: {}", path.display(), e);
+        }
+    }
+}
+
+// Replace the file at once so that it is never read half-written.
+async fn write(path: &Path, content: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, content).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+/// Follow the leader, replacing the router whenever the files pulled change.
+/// Only the routing part of the configuration pulled (script, upstreams, zones, etc.) is applied, listener settings are kept.
+pub async fn follow(
+    config: ClusterConfig,
+    router: Arc<RouterHandle>,
+    stats: Arc<Stats>,
+    hooks: Arc<Hooks>,
+) -> Result<()> {
+    if let Some(name) = config
+        .files
+        .iter()
+        .chain(config.lists.keys())
+        .find(|name| !valid_name(name) || *name == CONFIG || name.starts_with('.'))
+    {
+        anyhow::bail!("invalid name of the file to pull: {}", name);
+    }
+    if let Some(name) = config.lists.keys().find(|name| config.files.contains(name)) {
+        anyhow::bail!(
+            "rule list pulled both from the leader and its own source: {}",
+            name
+        );
+    }
+    let sources = config
+        .lists
+        .iter()
+        .map(|(name, source)| {
+            Ok((
+                name.clone(),
+                source.url.clone(),
+                public_key(&source.public_key)
+                    .with_context(|| format!("invalid public key of {}", name))?,
+            ))
+        })
+        .collect::<Result<_>>()?;
+    tokio::fs::create_dir_all(&config.dir).await?;
+    let serials = match tokio::fs::read(config.dir.join(SERIALS)).await {
+        Ok(serials) => serde_json::from_slice(&serials)
+            .with_context(|| "failed to read the serials of the files pulled".to_string())?,
+        Err(_) => HashMap::new(),
+    };
+    let mut follower = Follower {
+        key: public_key(&config.public_key)?,
+        sources,
+        client: reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?,
+        config,
+        pulled: HashMap::new(),
+        serials,
+    };
+
+    let mut ticks = interval(Duration::from_secs(follower.config.interval.max(1)));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        match follower.pull().await {
+            Ok(Some(new)) => {
+                router.swap(new);
+                info!("router replaced with the configuration pulled from the leader");
+            }
+            Ok(None) => debug!("configuration pulled from the leader is unchanged"),
+            Err(e) => {
+                warn!("failed to pull from the leader: {:#}", e);
+                hooks.notify(Event::RuleListUpdateFailed {
+                    error: format!("{:#}", e),
+                });
+            }
+        }
+        if follower.config.push_stats {
+            if let Err(e) = follower.push(&router, &stats).await {
+                warn!("failed to push statistics to the leader: {:#}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,327 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Versions of the configuration schema, and the migration of the older ones to the latest at parse time.
+//!
+//! Configurations without `version` are taken as version 1. Each migration takes the configuration one version up, so a change to the schema breaking the configurations
+//! written before has to come along with a new version and a migration to it, and the older configurations under `configs/compat` have to keep parsing.
+//! Options renamed within a version are rewritten as well, and reported as deprecated.
+//!
+//! Unknown fields are rejected with the closest known field suggested, as a misspelled option would otherwise be ignored silently.
+
+use crate::parser::FIELDS;
+use anyhow::{anyhow, bail, Result};
+use serde_yaml::{Mapping, Value};
+use std::fmt::{self, Display, Formatter};
+
+const VERSION: &str = "version";
+
+type Migration = fn(&mut Mapping, &mut Vec<Deprecation>) -> Result<()>;
+
+// Migrations from each version to the next one, starting from version 1
+const MIGRATIONS: &[Migration] = &[v1_to_v2];
+
+// Methods of upstreams renamed, along with their replacements
+const RENAMED_METHODS: &[(&str, &str)] = &[("dhcp", "system")];
+
+/// The latest version of the schema.
+pub const LATEST: u64 = MIGRATIONS.len() as u64 + 1;
+
+/// An option deprecated, which is rewritten into its replacement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deprecation {
+    /// Where the option is, e.g. `upstreams.local.dhcp`
+    pub option: String,
+    /// What replaces it
+    pub replacement: String,
+}
+
+impl Display for Deprecation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` is deprecated, use {} instead",
+            self.option, self.replacement
+        )
+    }
+}
+
+/// Migrate the configuration to the latest version of the schema, returning whether it is changed. Deprecated options found are added to `deprecations`.
+pub fn migrate(config: &mut Value, deprecations: &mut Vec<Deprecation>) -> Result<bool> {
+    let config = match config.as_mapping_mut() {
+        Some(config) => config,
+        // Left to the parser to complain about
+        None => return Ok(false),
+    };
+    let version = match config.get(VERSION) {
+        None => 1,
+        Some(v) => v
+            .as_u64()
+            .filter(|&v| v >= 1)
+            .ok_or_else(|| anyhow!("`version` should be a positive integer"))?,
+    };
+    if version > LATEST {
+        bail!(
+            "the configuration is of version {}, while this build of dcompass only supports up to version {}",
+            version,
+            LATEST
+        );
+    }
+
+    let before = config.clone();
+    for migration in &MIGRATIONS[(version - 1) as usize..] {
+        migration(config, deprecations)?;
+    }
+    rename_methods(config, deprecations);
+    // Configurations without `version` are left without it, so that the unchanged ones are parsed from the text with errors located.
+    if config.contains_key(VERSION) {
+        config.insert(VERSION.into(), LATEST.into());
+    }
+    Ok(*config != before)
+}
+
+// Version 1 took upstreams as a list of `tag`, `method`, and `timeout`, and routed with `table` instead of `script`.
+// Version 2 takes upstreams as a map from the tags to the methods, each with its own `timeout`.
+fn v1_to_v2(config: &mut Mapping, deprecations: &mut Vec<Deprecation>) -> Result<()> {
+    if config.contains_key("table") {
+        bail!("routing with `table` is no longer supported, please rewrite it as `script`, see the README for the examples");
+    }
+    let list = match config.get("upstreams") {
+        Some(Value::Sequence(list)) => list.clone(),
+        // Already a map
+        _ => return Ok(()),
+    };
+
+    let mut upstreams = Mapping::new();
+    for mut upstream in list {
+        let upstream = upstream
+            .as_mapping_mut()
+            .ok_or_else(|| anyhow!("each of `upstreams` should have `tag` and `method`"))?;
+        let tag = upstream
+            .remove("tag")
+            .ok_or_else(|| anyhow!("upstream without `tag`"))?;
+        let name = tag.as_str().unwrap_or_default().to_owned();
+        let mut method = upstream
+            .remove("method")
+            .ok_or_else(|| anyhow!("upstream `{}` without `method`", name))?;
+        // The timeout took no effect on hybrid upstreams, whose methods have no mapping to take it.
+        if let Some(timeout) = upstream.remove("timeout") {
+            if let Some(Value::Mapping(params)) =
+                method.as_mapping_mut().and_then(|m| m.values_mut().next())
+            {
+                params.entry("timeout".into()).or_insert(timeout);
+            }
+        }
+        if let Some((key, _)) = upstream.iter().next() {
+            bail!(
+                "unknown field `{}` of upstream `{}`",
+                key.as_str().unwrap_or_default(),
+                name
+            );
+        }
+        if upstreams.insert(tag, method).is_some() {
+            bail!("duplicate upstream `{}`", name);
+        }
+    }
+    config.insert("upstreams".into(), Value::Mapping(upstreams));
+    deprecations.push(Deprecation {
+        option: "upstreams".into(),
+        replacement: "a map from the tags of the upstreams to their methods".into(),
+    });
+    Ok(())
+}
+
+fn rename_methods(config: &mut Mapping, deprecations: &mut Vec<Deprecation>) {
+    let upstreams = config.get_mut("upstreams").and_then(Value::as_mapping_mut);
+    for (tag, method) in upstreams.into_iter().flatten() {
+        let method = match method.as_mapping_mut() {
+            Some(method) => method,
+            None => continue,
+        };
+        for (old, new) in RENAMED_METHODS {
+            if let Some(params) = method.remove(*old) {
+                method.insert((*new).into(), params);
+                let tag = tag.as_str().unwrap_or_default();
+                deprecations.push(Deprecation {
+                    option: format!("upstreams.{}.{}", tag, old),
+                    replacement: format!("`upstreams.{}.{}`", tag, new),
+                });
+            }
+        }
+    }
+}
+
+// Edit distance between the two
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = (prev + usize::from(ca != *cb)).min(row[j] + 1).min(cur + 1);
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+// The candidate closest to the name, if it is close enough to be a typo of it
+fn closest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    candidates
+        .into_iter()
+        .map(|c| (distance(name, c), c))
+        .filter(|(d, _)| *d <= (name.len() / 3).max(1))
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c)
+}
+
+/// Reject the unknown fields at the top level of the configuration, which serde cannot do for it because of the upstream settings flattened into it.
+pub fn check_fields(config: &Value) -> Result<()> {
+    for key in config.as_mapping().into_iter().flat_map(Mapping::keys) {
+        let key = match key.as_str() {
+            Some(key) if !FIELDS.contains(&key) => key,
+            _ => continue,
+        };
+        match closest(key, FIELDS.iter().copied()) {
+            Some(field) => bail!("unknown field `{}`, did you mean `{}`?", key, field),
+            None => bail!("unknown field `{}`", key),
+        }
+    }
+    Ok(())
+}
+
+/// Suggest the closest field or variant expected on errors about unknown ones, like `unknown field `adr`, expected one of `addr`, `timeout``.
+pub fn suggest(e: serde_yaml::Error) -> anyhow::Error {
+    let msg = e.to_string();
+    let suggestion = ["unknown field `", "unknown variant `"]
+        .iter()
+        .find_map(|p| msg.find(p).map(|i| i + p.len()))
+        .and_then(|start| {
+            let name = msg[start..].split('`').next()?;
+            let expected = &msg[msg[start..].find("expected").map(|i| start + i)?..];
+            // Names quoted in backticks are every other piece.
+            closest(name, expected.split('`').skip(1).step_by(2))
+        });
+    match suggestion {
+        Some(s) => anyhow!("{}, did you mean `{}`?", msg, s),
+        None => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_fields, closest, migrate, suggest, Deprecation, LATEST};
+    use crate::parser::Parsed;
+    use serde_yaml::Value;
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    fn migrate_quietly(config: &mut Value) -> anyhow::Result<bool> {
+        migrate(config, &mut Vec::new())
+    }
+
+    #[test]
+    fn versions() {
+        // Configurations of the latest schema are left as they are.
+        let mut config = yaml("upstreams: {domestic: {udp: {addr: 114.114.114.114:53}}}");
+        assert!(!migrate_quietly(&mut config).unwrap());
+
+        let mut config = yaml(&format!("version: {}", LATEST));
+        assert!(!migrate_quietly(&mut config).unwrap());
+        let mut config = yaml(&format!("version: {}", LATEST + 1));
+        assert!(migrate_quietly(&mut config).is_err());
+        assert!(migrate_quietly(&mut yaml("version: 0")).is_err());
+        assert!(migrate_quietly(&mut yaml("table: []")).is_err());
+    }
+
+    #[test]
+    fn v1_upstreams() {
+        let mut config = yaml(
+            r#"
+upstreams:
+  - tag: domestic
+    method:
+      udp:
+        addr: 114.114.114.114:53
+    timeout: 1
+  - tag: mixed
+    method:
+      hybrid: [domestic]
+    timeout: 3
+"#,
+        );
+        let mut deprecations = Vec::new();
+        assert!(migrate(&mut config, &mut deprecations).unwrap());
+        assert_eq!(deprecations[0].option, "upstreams");
+        assert_eq!(
+            config,
+            yaml(
+                r#"
+upstreams:
+  domestic:
+    udp:
+      addr: 114.114.114.114:53
+      timeout: 1
+  mixed:
+    hybrid: [domestic]
+"#
+            )
+        );
+
+        let mut config =
+            yaml("upstreams: [{tag: a, method: {udp: {}}}, {tag: a, method: {udp: {}}}]");
+        assert!(migrate_quietly(&mut config).is_err());
+    }
+
+    #[test]
+    fn renamed_methods() {
+        let mut config = yaml("upstreams: {local: {dhcp: {port: 53}}}");
+        let mut deprecations = Vec::new();
+        assert!(migrate(&mut config, &mut deprecations).unwrap());
+        assert_eq!(config, yaml("upstreams: {local: {system: {port: 53}}}"));
+        assert_eq!(
+            deprecations,
+            vec![Deprecation {
+                option: "upstreams.local.dhcp".into(),
+                replacement: "`upstreams.local.system`".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn typos() {
+        assert_eq!(
+            closest("verbosty", ["verbosity", "version"]),
+            Some("verbosity")
+        );
+        assert_eq!(closest("tpc", ["tcp", "doh"]), Some("tcp"));
+        assert_eq!(closest("foobar", ["tcp", "doh"]), None);
+
+        let e = check_fields(&yaml("{script: '', cache_sise: 10}")).unwrap_err();
+        assert!(e.to_string().contains("did you mean `cache_size`?"));
+        assert!(check_fields(&yaml("{script: '', cache_size: 10}")).is_ok());
+
+        let e = serde_yaml::from_str::<Parsed>(
+            "{script: '', address: 0.0.0.0:53, verbosity: info, upstreams: {a: {udp: {adr: 1.1.1.1:53}}}}",
+        )
+        .map(|_| ())
+        .unwrap_err();
+        assert!(suggest(e).to_string().contains("did you mean `addr`?"));
+    }
+}
@@ -0,0 +1,54 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Compile domain lists into the binary form the `domain` matcher loads directly, which saves parsing huge lists
+//! on every start and takes a fraction of the memory. Lists compiled before can be among the inputs, and are merged into the output.
+
+use anyhow::{Context, Result};
+use droute::utils::Domain;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Options of the domain list compiler
+#[derive(Debug, StructOpt)]
+pub struct CompileOpts {
+    /// Output file of the compiled list, which can be used in place of the lists in `domain.add_file`.
+    #[structopt(short, long, parse(from_os_str))]
+    output: PathBuf,
+
+    /// Domain lists to compile into one, possibly compressed or compiled already.
+    #[structopt(parse(from_os_str), required = true)]
+    lists: Vec<PathBuf>,
+}
+
+/// Compile the lists given into one.
+pub fn run(opts: CompileOpts) -> Result<()> {
+    let mut domain = Domain::new();
+    for path in &opts.lists {
+        domain
+            .add_file(path.to_string_lossy())
+            .with_context(|| format!("failed to read {}", path.display()))?;
+    }
+    let compiled = domain.compile().context("failed to compile the lists")?;
+    std::fs::write(&opts.output, &compiled)
+        .with_context(|| format!("failed to write {}", opts.output.display()))?;
+    println!(
+        "compiled {} list(s) into {} ({} bytes)",
+        opts.lists.len(),
+        opts.output.display(),
+        compiled.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,72 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Probing of the IPv4 and IPv6 connectivity of the host, with which the upstreams of the families unavailable are skipped on single-stack networks.
+
+use crate::{handle::RouterHandle, parser::ConnectivityConfig};
+use futures::future::join_all;
+use log::*;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::TcpStream,
+    time::{interval, timeout, MissedTickBehavior},
+};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Whether any of the targets of the family given is connected, or `true` if none of them is of the family.
+async fn available(targets: &[SocketAddr], ipv4: bool) -> bool {
+    let targets: Vec<_> = targets.iter().filter(|t| t.is_ipv4() == ipv4).collect();
+    if targets.is_empty() {
+        return true;
+    }
+    join_all(
+        targets
+            .into_iter()
+            .map(|t| timeout(PROBE_TIMEOUT, TcpStream::connect(t))),
+    )
+    .await
+    .into_iter()
+    .any(|r| matches!(r, Ok(Ok(_))))
+}
+
+/// Probe the connectivity every interval, and skip the upstreams of the families unavailable.
+pub async fn probe(config: ConnectivityConfig, router: Arc<RouterHandle>) {
+    let mut ticks = interval(Duration::from_secs(config.interval.max(1)));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last = (true, true);
+    loop {
+        ticks.tick().await;
+        // Probes would touch the network.
+        if router.offline() {
+            continue;
+        }
+        let current = tokio::join!(
+            available(&config.targets, true),
+            available(&config.targets, false)
+        );
+        if current != last {
+            match current {
+                (false, false) => warn!("no connectivity over either IPv4 or IPv6"),
+                (ipv4, ipv6) => info!(
+                    "connectivity over IPv4: {}, over IPv6: {}, upstreams of the families unavailable are skipped",
+                    ipv4, ipv6
+                ),
+            }
+            last = current;
+        }
+        router.set_connectivity(current.0, current.1);
+    }
+}
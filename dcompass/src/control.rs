@@ -0,0 +1,337 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Control endpoint of a live instance, serving over HTTP:
+//!
+//! - `/stats`: the statistics of the listener and of the router, and the memory usage
+//! - `/ranking`: the results of the latest round of probes on the upstreams
+//! - `/history?limit=<n>`: the latest queries answered, the newest first
+//! - `/history.csv`: every query in the history as CSV, for offline analysis
+//! - `/top?n=<n>`: the top queried domains, top blocked domains, and top clients
+//! - `/rules`: the number of times each rule (matcher tracked by name in the script) is evaluated and matched
+//! - `/offline`: whether the queries are answered out of the cache alone, turned on with `PUT` and off with `DELETE` for the virtual resolvers as well
+//! - `/groups`: the state of each group of rules, and `/groups/<name>` turned on with `PUT` and off with `DELETE`, for `?for=<seconds>` if given
+//! - `/capture.pcap`: the upstream traffic captured, streamed live in pcap until the client leaves or the configuration is reloaded
+//! - `/healthz`: whether the process is alive, for liveness probes
+//! - `/readyz`: whether queries can be answered as of the last check in the background, i.e. any upstream answered since the check before or answered a probe, for readiness probes
+//!
+//! With a token configured, every request but `/healthz` and `/readyz` has to carry it as a bearer.
+
+use crate::{handle::RouterHandle, parser::ControlConfig, ranking::Report, stats::Stats};
+use anyhow::{Context, Result};
+use droute::{
+    utils::{group_states, rule_stats, toggle_group},
+    Capture, Label, RankingPolicy, UpstreamStats,
+};
+use futures::future::join_all;
+use hyper::{
+    header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::*;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
+use tokio::sync::broadcast::error::RecvError;
+
+// Number of queries served on `/history` unless `limit` is given
+const HISTORY_LIMIT: usize = 100;
+
+// Number of items of each list served on `/top` unless `n` is given
+const TOP_N: usize = 20;
+
+// Interval between the readiness checks in the background, so that the probes on `/readyz` never wait for the upstreams.
+const READY_INTERVAL: Duration = Duration::from_secs(5);
+
+// Value of the query parameter
+fn param(req: &Request<Body>, name: &str) -> Option<usize> {
+    req.uri()
+        .query()?
+        .split('&')
+        .find_map(|kv| kv.strip_prefix(name)?.strip_prefix('='))?
+        .parse()
+        .ok()
+}
+
+/// Whether the request carries the bearer token given, compared in constant time so that the time taken tells nothing about the token.
+pub fn bearer(req: &Request<Body>, token: &str) -> bool {
+    let expected = format!("Bearer {}", token);
+    match req.headers().get(AUTHORIZATION) {
+        Some(v) if v.as_bytes().len() == expected.len() => {
+            v.as_bytes()
+                .iter()
+                .zip(expected.as_bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+        }
+        _ => false,
+    }
+}
+
+struct Control {
+    // Token required on everything but the probes
+    token: Option<String>,
+    router: Arc<RouterHandle>,
+    // Virtual resolvers, which are taken offline along with the main one
+    resolvers: Vec<Arc<RouterHandle>>,
+    stats: Arc<Stats>,
+    ranking: Arc<RwLock<Report>>,
+    // Statistics of the upstreams as of the last readiness check
+    last_ready: Mutex<Option<HashMap<Label, UpstreamStats>>>,
+    // Result of the last readiness check, not ready until the first one completes
+    ready: AtomicBool,
+}
+
+impl Control {
+    // Whether queries can be answered: any upstream answered since the last check, or answers a probe otherwise, e.g. while idle.
+    // Routers without upstreams querying on their own need none.
+    async fn check_ready(&self) -> bool {
+        let router = self.router.get();
+        let stats = router.stats().upstreams;
+        let answered = |s: &UpstreamStats| s.queries.saturating_sub(s.errors);
+        let last = self.last_ready.lock().unwrap().replace(stats.clone());
+        // Counters restart from zero once the router is replaced, which leaves it to the probes.
+        if stats.iter().any(|(tag, s)| {
+            answered(s) > last.as_ref().and_then(|l| l.get(tag)).map_or(0, answered)
+        }) {
+            return true;
+        }
+        let upstreams = router.upstreams();
+        let policy = RankingPolicy {
+            probes: 1,
+            ..Default::default()
+        };
+        let tags = upstreams.tags();
+        let measurements: Vec<_> = join_all(tags.iter().map(|tag| upstreams.probe(tag, &policy)))
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        measurements.is_empty() || measurements.iter().any(|m| m.reliability > 0.0)
+    }
+
+    // Check the readiness on schedule, for `/readyz` to answer with the result right away.
+    async fn check_ready_periodically(&self) {
+        loop {
+            let ready = self.check_ready().await;
+            if ready != self.ready.swap(ready, Ordering::Relaxed) {
+                info!("instance is {}", if ready { "ready" } else { "not ready" });
+            }
+            tokio::time::sleep(READY_INTERVAL).await;
+        }
+    }
+
+    // Instances in offline mode answer out of the cache regardless of the upstreams.
+    fn ready(&self) -> bool {
+        self.router.offline() || self.ready.load(Ordering::Relaxed)
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        // Probes are left open for the orchestrators, which carry no token.
+        let probe = matches!(req.uri().path(), "/healthz" | "/readyz");
+        if let Some(token) = &self.token {
+            if !probe && !bearer(&req, token) {
+                return Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header(WWW_AUTHENTICATE, "Bearer")
+                    .body(Body::empty())
+                    .unwrap();
+            }
+        }
+        let (content_type, body) = match (req.method(), req.uri().path()) {
+            (&Method::GET, "/healthz") => ("text/plain", "ok".to_string()),
+            (&Method::GET, "/readyz") => {
+                let ready = self.ready();
+                return Response::builder()
+                    .status(if ready {
+                        StatusCode::OK
+                    } else {
+                        StatusCode::SERVICE_UNAVAILABLE
+                    })
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(json!({ "ready": ready }).to_string()))
+                    .unwrap();
+            }
+            (&Method::GET, "/stats") => (
+                "application/json",
+                json!({
+                    "listener": self.stats.snapshot(),
+                    "router": self.router.get().stats(),
+                    "nsids": self.router.get().upstreams().nsids(),
+                    "memory": droute::memory::usage(),
+                })
+                .to_string(),
+            ),
+            (&Method::GET, "/ranking") => (
+                "application/json",
+                json!(*self.ranking.read().unwrap()).to_string(),
+            ),
+            (&Method::GET, "/history") if self.stats.history.is_some() => {
+                let limit = param(&req, "limit").unwrap_or(HISTORY_LIMIT);
+                let history = self.stats.history.as_ref().unwrap();
+                ("application/json", json!(history.recent(limit)).to_string())
+            }
+            (&Method::GET, "/history.csv") if self.stats.history.is_some() => {
+                ("text/csv", self.stats.history.as_ref().unwrap().csv())
+            }
+            (&Method::GET, "/top") if self.stats.top_k.is_some() => {
+                let n = param(&req, "n").unwrap_or(TOP_N);
+                let top_k = self.stats.top_k.as_ref().unwrap();
+                ("application/json", json!(top_k.top(n)).to_string())
+            }
+            (&Method::GET, "/rules") => ("application/json", json!(rule_stats()).to_string()),
+            (&Method::GET, "/groups") => ("application/json", json!(group_states()).to_string()),
+            (&Method::PUT | &Method::DELETE, path) if path.starts_with("/groups/") => {
+                let name = &path["/groups/".len()..];
+                let enabled = req.method() == Method::PUT;
+                let duration = param(&req, "for").map(|s| Duration::from_secs(s as u64));
+                match toggle_group(name, enabled, duration) {
+                    Some(state) => {
+                        // Responses in the fast path were evaluated with the group as it was, both now and once it is turned back.
+                        self.router.get().purge_fast_path();
+                        if let Some(duration) = duration {
+                            let router = self.router.clone();
+                            tokio::spawn(async move {
+                                tokio::time::sleep(duration).await;
+                                router.get().purge_fast_path();
+                            });
+                        }
+                        warn!(
+                            "group {} turned {} on the control endpoint{}",
+                            name,
+                            if enabled { "on" } else { "off" },
+                            duration.map_or(String::new(), |d| format!(" for {}s", d.as_secs()))
+                        );
+                        ("application/json", json!(state).to_string())
+                    }
+                    None => {
+                        return Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty())
+                            .unwrap()
+                    }
+                }
+            }
+            (&Method::GET, "/capture.pcap") => {
+                let router = self.router.get();
+                let capture = match router.upstreams().capture() {
+                    Some(capture) => capture,
+                    None => {
+                        return Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty())
+                            .unwrap()
+                    }
+                };
+                let mut records = capture.subscribe();
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    if sender.send_data(Capture::header()).await.is_err() {
+                        return;
+                    }
+                    loop {
+                        match records.recv().await {
+                            Ok(record) => {
+                                if sender.send_data(record).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Err(RecvError::Lagged(n)) => {
+                                warn!("{} records of the upstream capture streamed are lost", n)
+                            }
+                            Err(RecvError::Closed) => break,
+                        }
+                    }
+                });
+                return Response::builder()
+                    .header(CONTENT_TYPE, "application/vnd.tcpdump.pcap")
+                    .body(body)
+                    .unwrap();
+            }
+            (&Method::GET, "/offline") => (
+                "application/json",
+                json!({ "offline": self.router.offline() }).to_string(),
+            ),
+            (&Method::PUT | &Method::DELETE, "/offline") => {
+                let offline = req.method() == Method::PUT;
+                self.router.set_offline(offline);
+                self.resolvers.iter().for_each(|r| r.set_offline(offline));
+                warn!(
+                    "offline mode turned {} on the control endpoint",
+                    if offline { "on" } else { "off" }
+                );
+                (
+                    "application/json",
+                    json!({ "offline": offline }).to_string(),
+                )
+            }
+            _ => {
+                return Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        };
+        Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap()
+    }
+}
+
+/// Serve the control endpoint.
+pub async fn serve(
+    config: ControlConfig,
+    router: Arc<RouterHandle>,
+    resolvers: Vec<Arc<RouterHandle>>,
+    stats: Arc<Stats>,
+    ranking: Arc<RwLock<Report>>,
+) -> Result<()> {
+    let control = Arc::new(Control {
+        token: config.token.clone(),
+        router,
+        resolvers,
+        stats,
+        ranking,
+        last_ready: Mutex::new(None),
+        ready: AtomicBool::new(false),
+    });
+    tokio::spawn({
+        let control = control.clone();
+        async move { control.check_ready_periodically().await }
+    });
+    let make_svc = make_service_fn(move |_| {
+        let control = control.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let control = control.clone();
+                async move { Ok::<_, Infallible>(control.handle(req).await) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&config.listen)
+        .with_context(|| format!("failed to bind to {}", config.listen))?;
+    info!("serving the control endpoint on {}", config.listen);
+    server.serve(make_svc).await?;
+    Ok(())
+}
@@ -0,0 +1,504 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Self-test of the environment the configuration is run in, i.e. the files it reads, the clock, the addresses to listen on, the upstreams and their certificates,
+//! each failure reported along with what to do about it.
+
+use crate::{init, parser::Parsed, profile, stamps};
+use anyhow::{bail, Result};
+use bytes::{Bytes, BytesMut};
+use domain::base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype};
+use droute::{builders::UpstreamBuilder, errors::ErrorKind, CacheMode, Label, Upstreams};
+use std::{
+    fmt::Display,
+    fs::File,
+    io,
+    net::{SocketAddr, TcpListener, UdpSocket},
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::timeout;
+
+// Time given to each upstream to answer, on top of its own timeout
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Certificates expiring within this many days are warned about.
+const EXPIRY_WARNING: u64 = 14;
+
+// 2023-01-01T00:00:00Z, before which the clock is surely behind
+const CLOCK_FLOOR: u64 = 1672531200;
+
+#[derive(Default)]
+struct Report {
+    passed: usize,
+    warnings: usize,
+    failures: usize,
+}
+
+impl Report {
+    fn pass(&mut self, what: impl Display) {
+        self.passed += 1;
+        println!("[ ok ] {}", what);
+    }
+
+    fn warn(&mut self, what: impl Display, fix: impl Display) {
+        self.warnings += 1;
+        println!("[warn] {}\n       fix: {}", what, fix);
+    }
+
+    fn fail(&mut self, what: impl Display, fix: impl Display) {
+        self.failures += 1;
+        println!("[fail] {}\n       fix: {}", what, fix);
+    }
+}
+
+/// Run the checks on the configuration, and fail if any of them fails.
+pub async fn run(config: &str, profile: Option<&str>) -> Result<()> {
+    let mut report = Report::default();
+    let parsed = match profile::parse(config, profile) {
+        Ok(parsed) => {
+            report.pass("configuration parsed");
+            parsed
+        }
+        Err(e) => {
+            report.fail(
+                format_args!("configuration invalid: {:#}", e),
+                "correct the configuration, then run the checks again",
+            );
+            bail!("the configuration is invalid");
+        }
+    };
+    for deprecation in &parsed.deprecations {
+        report.warn(
+            deprecation,
+            "rewrite the option as suggested before it is removed",
+        );
+    }
+
+    check_files(&mut report, &parsed);
+    check_clock(&mut report);
+    check_listen(&mut report, &parsed);
+
+    match stamps::load(parsed).await {
+        Ok(parsed) => {
+            check_certificates(&mut report, &parsed).await;
+            match init(parsed).await {
+                Ok((router, ..)) => check_upstreams(&mut report, router.upstreams()).await,
+                Err(e) => report.fail(
+                    format_args!("failed to build the router: {}", e),
+                    "correct the script or the upstreams as the error tells",
+                ),
+            }
+        }
+        Err(e) => report.fail(
+            format_args!("{:#}", e),
+            "check the source of the stamp list and the names picked from it",
+        ),
+    }
+
+    println!(
+        "\n{} passed, {} warnings, {} failures",
+        report.passed, report.warnings, report.failures
+    );
+    if report.failures > 0 {
+        bail!("{} checks failed", report.failures);
+    }
+    Ok(())
+}
+
+// Paths of the files loaded by the script, i.e. the string literals passed to `add_file` and `from_path`.
+fn script_files(source: &str) -> Vec<&str> {
+    let mut files: Vec<_> = ["add_file(", "from_path("]
+        .iter()
+        .flat_map(|call| {
+            source
+                .match_indices(call)
+                .filter_map(|(i, _)| {
+                    let rest = source[i + call.len()..].trim_start().strip_prefix('"')?;
+                    Some(&rest[..rest.find('"')?])
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    files.sort_unstable();
+    files.dedup();
+    files
+}
+
+fn check_readable(report: &mut Report, path: &str) {
+    match File::open(path) {
+        Ok(_) => report.pass(format_args!("{} readable", path)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => report.fail(
+            format_args!("{} not found", path),
+            format_args!(
+                "correct the path, relative paths are taken from the working directory {}",
+                std::env::current_dir()
+                    .map(|d| d.display().to_string())
+                    .unwrap_or_default()
+            ),
+        ),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => report.fail(
+            format_args!("{} not readable: {}", path, e),
+            format_args!(
+                "make it readable by the user dcompass runs as, e.g. `chmod a+r {}`",
+                path
+            ),
+        ),
+        Err(e) => report.fail(
+            format_args!("{} not readable: {}", path, e),
+            "check the file and the disk it is on",
+        ),
+    }
+}
+
+fn check_files(report: &mut Report, parsed: &Parsed) {
+    for path in script_files(parsed.script.source()) {
+        check_readable(report, path);
+    }
+    for list in &parsed.stamp_lists {
+        if !list.source.starts_with("https://") && !list.source.starts_with("http://") {
+            check_readable(report, &list.source);
+        }
+    }
+    for script in &parsed.hooks.scripts {
+        let path = script.display();
+        match std::fs::metadata(script) {
+            #[cfg(unix)]
+            Ok(meta)
+                if std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o111 == 0 =>
+            {
+                report.fail(
+                    format_args!("hook {} not executable", path),
+                    format_args!("make it executable, e.g. `chmod +x {}`", path),
+                )
+            }
+            Ok(_) => report.pass(format_args!("hook {} found", path)),
+            Err(e) => report.fail(
+                format_args!("hook {} not accessible: {}", path, e),
+                "correct the path of the hook, or make it accessible to the user dcompass runs as",
+            ),
+        }
+    }
+}
+
+fn check_clock(report: &mut Report) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    if now < CLOCK_FLOOR {
+        report.fail(
+            "system clock is behind, certificates and DNSSEC signatures would be taken as not yet valid",
+            "sync the clock with NTP, e.g. `timedatectl set-ntp true`, which devices without a battery-backed clock need on every boot",
+        );
+    } else {
+        report.pass("system clock plausible");
+    }
+}
+
+fn check_listen(report: &mut Report, parsed: &Parsed) {
+    let mut protocols = vec!["udp"];
+    if parsed.tcp.enabled {
+        protocols.push("tcp");
+    }
+    for addr in parsed.address.addrs() {
+        for protocol in &protocols {
+            let r = match *protocol {
+                "udp" => UdpSocket::bind(addr).map(drop),
+                _ => TcpListener::bind(addr).map(drop),
+            };
+            let what = format!("listen on {}/{}", addr, protocol);
+            match r {
+                Ok(()) => report.pass(what),
+                Err(e) => {
+                    let fix = match e.kind() {
+                        io::ErrorKind::AddrInUse => format!(
+                            "another DNS server is listening on port {}, e.g. systemd-resolved (`systemctl disable --now systemd-resolved`), dnsmasq, or dcompass itself; stop it or change `address`",
+                            addr.port()
+                        ),
+                        io::ErrorKind::PermissionDenied => "ports below 1024 need privileges, run as root or grant the capability with `setcap cap_net_bind_service=+ep $(which dcompass)`".to_string(),
+                        io::ErrorKind::AddrNotAvailable => "the address is not assigned to any interface, correct `address`".to_string(),
+                        _ => "change `address`".to_string(),
+                    };
+                    report.fail(format_args!("{}: {}", what, e), fix)
+                }
+            }
+        }
+    }
+}
+
+// Query for the root name servers, which every resolver should answer
+fn probe() -> Option<Message<Bytes>> {
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(64)).ok()?;
+    builder.header_mut().set_rd(true);
+    let mut builder = builder.question();
+    builder
+        .push((&Dname::<Bytes>::from_str(".").ok()?, Rtype::Ns))
+        .ok()?;
+    Some(builder.into_message())
+}
+
+async fn check_upstreams(report: &mut Report, upstreams: &Upstreams) {
+    let query = probe().unwrap();
+    // Members of the hybrid upstreams are checked on their own.
+    let hybrids = upstreams.hybrids();
+    let mut tags: Vec<Label> = upstreams
+        .tags()
+        .into_iter()
+        .filter(|t| !hybrids.contains_key(t))
+        .collect();
+    tags.sort();
+    for tag in tags {
+        let start = Instant::now();
+        let r = timeout(
+            QUERY_TIMEOUT,
+            upstreams.send(&tag, &CacheMode::Disabled, &query),
+        )
+        .await;
+        let ms = start.elapsed().as_millis();
+        match r {
+            Ok(Ok(resp)) if resp.header().rcode() == Rcode::NoError => {
+                report.pass(format_args!("upstream {} answered in {}ms", tag, ms))
+            }
+            Ok(Ok(resp)) => report.warn(
+                format_args!("upstream {} answered {}", tag, resp.header().rcode()),
+                "the resolver may not serve clients from this network, check its access control or pick another one",
+            ),
+            Ok(Err(e)) => report.fail(
+                format_args!("upstream {} failed: {}", tag, e),
+                match e.kind() {
+                    ErrorKind::Timeout => "no answer in time, check that the firewall lets the traffic to the upstream through, and that its address and port are right",
+                    ErrorKind::Network => "failed to connect, check the network, the address and port of the upstream, and the proxy if any",
+                    ErrorKind::Protocol => "the answer is unusable, check that the protocol and the URL match what the server serves",
+                    ErrorKind::Config => "correct the configuration of the upstream",
+                    ErrorKind::Policy => "the query is turned down by the ratelimit or offline mode, which is not a problem of the upstream",
+                },
+            ),
+            Err(_) => report.fail(
+                format_args!("upstream {} didn't answer within {}s", tag, QUERY_TIMEOUT.as_secs()),
+                "check that the firewall lets the traffic to the upstream through, and that its address and port are right",
+            ),
+        }
+    }
+}
+
+async fn check_certificates(report: &mut Report, parsed: &Parsed) {
+    let mut endpoints: Vec<(&Label, SocketAddr, String)> = parsed
+        .upstreams
+        .upstreams()
+        .iter()
+        .filter_map(|(tag, u)| match u {
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            UpstreamBuilder::Https(https) => {
+                let uri: hyper::Uri = https.uri.parse().ok()?;
+                let addr = SocketAddr::new(https.addr, uri.port_u16().unwrap_or(443));
+                Some((tag, addr, uri.host()?.to_string()))
+            }
+            #[cfg(any(feature = "dot-rustls", feature = "dot-native-tls"))]
+            UpstreamBuilder::Tls(tls) => Some((tag, tls.addr, tls.domain.clone())),
+            _ => None,
+        })
+        .collect();
+    endpoints.sort();
+    for (tag, addr, name) in endpoints {
+        let what = format!("certificate of upstream {} ({} at {})", tag, name, addr);
+        match tls::certificate(addr, &name).await {
+            Ok(Some(der)) => match validity(&der) {
+                Some((not_before, _)) if SystemTime::now() < not_before => report.fail(
+                    format_args!("{} not valid yet", what),
+                    "the system clock is likely behind, sync it with NTP, e.g. `timedatectl set-ntp true`",
+                ),
+                Some((_, not_after)) => match not_after.duration_since(SystemTime::now()) {
+                    Ok(left) if left.as_secs() < EXPIRY_WARNING * 86400 => report.warn(
+                        format_args!("{} expires in {} days", what, left.as_secs() / 86400),
+                        "renew the certificate if the resolver is yours, otherwise prepare another upstream in case it is not renewed",
+                    ),
+                    Ok(left) => report.pass(format_args!("{} valid for {} days", what, left.as_secs() / 86400)),
+                    Err(_) => report.fail(
+                        format_args!("{} expired", what),
+                        "use another upstream until the certificate is renewed, or check the system clock if it is ahead",
+                    ),
+                },
+                None => report.warn(format_args!("{} not understood", what), "nothing to do if the upstream answers"),
+            },
+            Ok(None) => report.warn(format_args!("{} not checked", what), "certificates are not checked on this platform or in this build"),
+            Err(e) => report.fail(
+                format_args!("{} rejected: {}", what, e),
+                "check that `domain` or the host in `uri` is the name the server's certificate is issued for, and that the system clock is right",
+            ),
+        }
+    }
+}
+
+// The DER element at the start, as its tag, contents, and the rest after it
+fn tlv(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *der.first()?;
+    let first = *der.get(1)?;
+    let (len, header) = if first & 0x80 == 0 {
+        (usize::from(first), 2)
+    } else {
+        let n = usize::from(first & 0x7f);
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = der
+            .get(2..2 + n)?
+            .iter()
+            .fold(0, |len, b| len << 8 | usize::from(*b));
+        (len, 2 + n)
+    };
+    Some((tag, der.get(header..header + len)?, &der[header + len..]))
+}
+
+// Days since the epoch of the date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// UTCTime (`YYMMDDHHMMSSZ`) or GeneralizedTime (`YYYYMMDDHHMMSSZ`)
+fn time(tag: u8, s: &[u8]) -> Option<SystemTime> {
+    let s = std::str::from_utf8(s).ok()?.strip_suffix('Z')?;
+    let (year, rest) = match tag {
+        0x17 => {
+            let yy: i64 = s.get(..2)?.parse().ok()?;
+            (if yy >= 50 { 1900 + yy } else { 2000 + yy }, s.get(2..)?)
+        }
+        0x18 => (s.get(..4)?.parse().ok()?, s.get(4..)?),
+        _ => return None,
+    };
+    let field = |i: usize| -> Option<i64> { rest.get(i..i + 2)?.parse().ok() };
+    let days = days_from_civil(year, field(0)?, field(2)?);
+    let secs = days * 86400 + field(4)? * 3600 + field(6)? * 60 + field(8)?;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+// The validity period of the certificate in DER
+fn validity(der: &[u8]) -> Option<(SystemTime, SystemTime)> {
+    let (_, cert, _) = tlv(der)?;
+    let (_, tbs, _) = tlv(cert)?;
+    let (tag, _, mut rest) = tlv(tbs)?;
+    // Skip the serial number after the version if present, then the signature algorithm and the issuer.
+    if tag == 0xa0 {
+        rest = tlv(rest)?.2;
+    }
+    rest = tlv(rest)?.2;
+    rest = tlv(rest)?.2;
+    let (_, validity, _) = tlv(rest)?;
+    let (tag, not_before, rest) = tlv(validity)?;
+    let not_before = time(tag, not_before)?;
+    let (tag, not_after, _) = tlv(rest)?;
+    Some((not_before, time(tag, not_after)?))
+}
+
+// Certificates are fetched with rustls, which is not used on MIPS, and left out of the builds without `doctor-tls`.
+#[cfg(all(
+    feature = "doctor-tls",
+    not(any(target_arch = "mips", target_arch = "mips64"))
+))]
+mod tls {
+    use anyhow::Result;
+    use std::{convert::TryFrom, net::SocketAddr, sync::Arc};
+    use tokio::net::TcpStream;
+    use tokio_rustls::{
+        rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+        TlsConnector,
+    };
+
+    // The certificate of the server, verified against the name.
+    pub async fn certificate(addr: SocketAddr, name: &str) -> Result<Option<Vec<u8>>> {
+        let mut roots = RootCertStore::empty();
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(addr).await?;
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from(name)?, stream)
+            .await?;
+        Ok(stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(|cert| cert.0.clone()))
+    }
+}
+
+#[cfg(not(all(
+    feature = "doctor-tls",
+    not(any(target_arch = "mips", target_arch = "mips64"))
+)))]
+mod tls {
+    use anyhow::Result;
+    use std::net::SocketAddr;
+
+    pub async fn certificate(_: SocketAddr, _: &str) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{script_files, validity};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut v = vec![tag, contents.len() as u8];
+        v.extend_from_slice(contents);
+        v
+    }
+
+    #[test]
+    fn files() {
+        let script = r#"
+            let ads = Domain::new().add_file("ads.txt")?.add_file( "ads.txt")?;
+            let geoip = GeoIp::from_path("GeoLite2-Country.mmdb").await?;
+            let cn = IpCidr::new().add_file(path)?;
+        "#;
+        assert_eq!(
+            script_files(script),
+            vec!["GeoLite2-Country.mmdb", "ads.txt"]
+        );
+    }
+
+    #[test]
+    fn certificate_validity() {
+        let times = [der(0x17, b"230101000000Z"), der(0x18, b"20240229120000Z")].concat();
+        let tbs = [
+            der(0xa0, &der(0x02, &[2])),
+            der(0x02, &[1]),
+            der(0x30, &[]),
+            der(0x30, &[]),
+            der(0x30, &times),
+        ]
+        .concat();
+        let cert = der(0x30, &der(0x30, &tbs));
+        let (not_before, not_after) = validity(&cert).unwrap();
+        assert_eq!(not_before, UNIX_EPOCH + Duration::from_secs(1672531200));
+        assert_eq!(not_after, UNIX_EPOCH + Duration::from_secs(1709208000));
+        assert!(validity(&cert[..cert.len() - 1]).is_none());
+    }
+}
@@ -0,0 +1,484 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! DNS over HTTPS listener, serving over plain HTTP behind a reverse proxy terminating TLS:
+//!
+//! - RFC 8484: `GET ?dns=<base64url>` and `POST` with `application/dns-message`
+//! - JSON API in the style of Google and Cloudflare: `GET ?name=<name>&type=<type>` with `application/dns-json`
+//!
+//! Queries go through the same path as the ones received over UDP and TCP: the ACL, the limit on the queries in flight, the routing script, and the statistics.
+
+use crate::{
+    handle::RouterHandle,
+    parser::DohConfig,
+    proxy::{self, TrustedProxies},
+    stats::Stats,
+    tcp::{self, MAX_CONNECTIONS},
+    worker::{admit, resolve, Limits},
+};
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{iana::Rcode, Dname, Message, MessageBuilder, ParsedDname, Rtype},
+    rdata::{AllRecordData, Soa},
+};
+use droute::{privacy, Listener};
+use hyper::{
+    body::HttpBody,
+    header::{ACCEPT, AGE, CACHE_CONTROL, CONTENT_TYPE, VARY},
+    server::conn::Http,
+    service::service_fn,
+    Body, Method, Request, Response, StatusCode,
+};
+use log::*;
+use serde_json::{json, Value};
+use std::{convert::Infallible, net::SocketAddr, str::FromStr, sync::Arc};
+use tokio::{
+    net::TcpListener,
+    sync::{watch, Semaphore},
+};
+
+const DNS_MESSAGE: &str = "application/dns-message";
+const DNS_JSON: &str = "application/dns-json";
+
+// Queries are at most this long, as they are over TCP.
+const MAX_QUERY_LEN: usize = u16::MAX as usize;
+
+// Value of the query parameter, percent-decoded
+fn param(req: &Request<Body>, name: &str) -> Option<String> {
+    form_urlencoded::parse(req.uri().query()?.as_bytes())
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.into_owned())
+}
+
+// Type given by its mnemonic, e.g. `AAAA`, or its number, defaulting to A
+fn rtype(s: Option<String>) -> Option<Rtype> {
+    match s {
+        None => Some(Rtype::A),
+        Some(s) => match s.parse::<u16>() {
+            Ok(n) => Some(Rtype::from_int(n)),
+            Err(_) => s.to_uppercase().parse().ok(),
+        },
+    }
+}
+
+// Whether the flag in the query parameters is set, e.g. `cd=1` or `do=true`
+fn flag(req: &Request<Body>, name: &str) -> bool {
+    matches!(param(req, name).as_deref(), Some("1" | "true"))
+}
+
+// Build the query out of the JSON API parameters.
+fn json_query(req: &Request<Body>) -> Option<Bytes> {
+    let name = Dname::<Bytes>::from_str(&param(req, "name")?).ok()?;
+    let qtype = rtype(param(req, "type"))?;
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(512)).ok()?;
+    builder.header_mut().set_id(rand::random());
+    builder.header_mut().set_rd(true);
+    builder.header_mut().set_cd(flag(req, "cd"));
+    let mut builder = builder.question();
+    builder.push((&name, qtype)).ok()?;
+    let mut builder = builder.additional();
+    if flag(req, "do") {
+        builder
+            .opt(|opt| {
+                opt.set_udp_payload_size(1232);
+                opt.set_dnssec_ok(true);
+                Ok(())
+            })
+            .ok()?;
+    }
+    Some(builder.finish().freeze())
+}
+
+// Names are fully qualified in the JSON format.
+fn fqdn(name: String) -> String {
+    if name.ends_with('.') {
+        name
+    } else {
+        name + "."
+    }
+}
+
+// Records in the answer or the authority section in the JSON format
+fn json_records(resp: &Message<Bytes>, authority: bool) -> Vec<Value> {
+    let section = if authority {
+        resp.authority()
+    } else {
+        resp.answer()
+    };
+    section
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|item| {
+            item.into_record::<AllRecordData<Bytes, ParsedDname<Bytes>>>()
+                .ok()
+                .flatten()
+        })
+        .map(|r| {
+            json!({
+                "name": fqdn(r.owner().to_string()),
+                "type": r.rtype().to_int(),
+                "TTL": r.ttl(),
+                "data": r.data().to_string(),
+            })
+        })
+        .collect()
+}
+
+// The response in the JSON format
+fn json_response(resp: &Message<Bytes>) -> Value {
+    let header = resp.header();
+    json!({
+        "Status": header.rcode().to_int(),
+        "TC": header.tc(),
+        "RD": header.rd(),
+        "RA": header.ra(),
+        "AD": header.ad(),
+        "CD": header.cd(),
+        "Question": resp.question().flatten().map(|q| json!({
+            "name": fqdn(q.qname().to_string()),
+            "type": q.qtype().to_int(),
+        })).collect::<Vec<_>>(),
+        "Answer": json_records(resp, false),
+        "Authority": json_records(resp, true),
+    })
+}
+
+// Lifetime of the HTTP response (RFC 8484 section 5.1): the least TTL in the answer section, or for answers without records,
+// the negative TTL from the SOA record in the authority section (RFC 2308). Errors other than NXDOMAIN are not to be cached at all.
+fn max_age(resp: &Message<Bytes>) -> Option<u32> {
+    if !matches!(resp.header().rcode(), Rcode::NoError | Rcode::NXDomain) {
+        return None;
+    }
+    let answer = resp
+        .answer()
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|r| r.ttl())
+        .min();
+    Some(answer.unwrap_or_else(|| {
+        resp.authority()
+            .into_iter()
+            .flat_map(|section| section.limit_to::<Soa<ParsedDname<_>>>())
+            .flatten()
+            .map(|r| r.ttl().min(r.data().minimum()))
+            .min()
+            .unwrap_or(0)
+    }))
+}
+
+// Read the body up to `limit` bytes however it is sent, as the length of chunked ones is not known ahead.
+pub(crate) async fn read_body(
+    mut body: Body,
+    limit: usize,
+) -> std::result::Result<Bytes, StatusCode> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if buf.len() + chunk.len() > limit {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.freeze())
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::empty())
+        .unwrap()
+}
+
+struct Doh {
+    config: DohConfig,
+    router: Arc<RouterHandle>,
+    limits: Arc<Limits>,
+    stats: Arc<Stats>,
+}
+
+impl Doh {
+    // Queries are encrypted on the way from the client only if received from a trusted proxy, which terminates TLS.
+    async fn handle(&self, req: Request<Body>, src: SocketAddr, encrypted: bool) -> Response<Body> {
+        if req.uri().path() != self.config.path {
+            return status(StatusCode::NOT_FOUND);
+        }
+        // Clients asking for JSON explicitly, or using the JSON API parameters
+        let json = req
+            .headers()
+            .get(ACCEPT)
+            .map_or(false, |v| v.as_bytes().starts_with(DNS_JSON.as_bytes()))
+            || param(&req, "name").is_some();
+
+        let method = req.method().clone();
+        let query = match (&method, json) {
+            (&Method::GET, true) => json_query(&req),
+            (&Method::GET, false) => param(&req, "dns").and_then(|dns| {
+                URL_SAFE_NO_PAD
+                    .decode(dns.trim_end_matches('='))
+                    .ok()
+                    .map(Bytes::from)
+            }),
+            // Queries are posted in the wire format only (RFC 8484 section 4.1).
+            (&Method::POST, _)
+                if req.headers().get(CONTENT_TYPE).map(|v| v.as_bytes())
+                    != Some(DNS_MESSAGE.as_bytes()) =>
+            {
+                return status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+            }
+            (&Method::POST, _) if req.body().size_hint().lower() > MAX_QUERY_LEN as u64 => {
+                return status(StatusCode::PAYLOAD_TOO_LARGE)
+            }
+            (&Method::POST, _) => match read_body(req.into_body(), MAX_QUERY_LEN).await {
+                Ok(body) => Some(body),
+                Err(code) => return status(code),
+            },
+            _ => return status(StatusCode::METHOD_NOT_ALLOWED),
+        };
+        let query = match query {
+            Some(query) => query,
+            None => return status(StatusCode::BAD_REQUEST),
+        };
+
+        let resp = match admit(&self.limits, &self.stats, &query, src) {
+            Ok(_permit) => {
+                match resolve(
+                    &self.router.get(),
+                    &self.stats,
+                    query,
+                    src,
+                    Listener::Doh,
+                    encrypted,
+                )
+                .await
+                {
+                    Some(resp) => resp,
+                    None => return status(StatusCode::BAD_REQUEST),
+                }
+            }
+            // Replies per ACL action and overflow policy are sent as they are.
+            Err(Some(resp)) => resp,
+            Err(None) => return status(StatusCode::FORBIDDEN),
+        };
+
+        let builder = Response::builder()
+            .header(
+                CACHE_CONTROL,
+                match max_age(&resp) {
+                    Some(age) => format!("max-age={}", age),
+                    None => "no-store".to_string(),
+                },
+            )
+            // TTLs of the responses out of the cache are counted down already, so caches in front are not to count the time again.
+            .header(AGE, "0")
+            // The same URL is answered in either format depending on `Accept`.
+            .header(VARY, ACCEPT.as_str());
+        if json {
+            builder
+                .header(CONTENT_TYPE, DNS_JSON)
+                .body(Body::from(json_response(&resp).to_string()))
+        } else {
+            builder
+                .header(CONTENT_TYPE, DNS_MESSAGE)
+                .body(Body::from(resp.into_octets()))
+        }
+        .unwrap()
+    }
+}
+
+/// Bind to the address to serve DNS over HTTPS on, failing early on configurations that can't be served.
+pub async fn bind(config: &DohConfig) -> Result<(TcpListener, Option<Arc<TrustedProxies>>)> {
+    let proxies = if config.proxy_protocol {
+        Some(Arc::new(TrustedProxies::new(&config.trusted_proxies)?))
+    } else {
+        None
+    };
+    let listener = TcpListener::bind(config.listen).await.with_context(|| {
+        format!(
+            "failed to bind to {} to serve DNS over HTTPS",
+            config.listen
+        )
+    })?;
+    Ok((listener, proxies))
+}
+
+/// Serve DNS over HTTPS on the listener bound, accepting no more connections once `stop` is signaled.
+pub async fn serve(
+    config: DohConfig,
+    (listener, proxies): (TcpListener, Option<Arc<TrustedProxies>>),
+    router: Arc<RouterHandle>,
+    limits: Arc<Limits>,
+    stats: Arc<Stats>,
+    mut stop: watch::Receiver<bool>,
+) {
+    info!("serving DNS over HTTPS on {}", config.listen);
+    let doh = Arc::new(Doh {
+        config,
+        router,
+        limits,
+        stats,
+    });
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        let (mut stream, src, permit) = tokio::select! {
+            accepted = tcp::accept(&listener, &connections) => accepted,
+            _ = stop.changed() => return,
+        };
+        let (doh, proxies) = (doh.clone(), proxies.clone());
+        tokio::spawn(async move {
+            let _permit = permit;
+            let encrypted = proxies.as_ref().map_or(false, |p| p.contains(src.ip()));
+            // Behind a reverse proxy, the clients are the ones it tells rather than the proxy itself.
+            let src = if let Some(proxies) = proxies {
+                match proxy::accept(&mut stream, src, &proxies).await {
+                    Ok(client) => client,
+                    Err(e) => {
+                        debug!(
+                            "dropping DoH connection from {}: {}",
+                            privacy::client(src),
+                            e
+                        );
+                        return;
+                    }
+                }
+            } else {
+                src
+            };
+            let service = service_fn(move |req| {
+                let doh = doh.clone();
+                async move { Ok::<_, Infallible>(doh.handle(req, src, encrypted).await) }
+            });
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                debug!("DoH connection from {} closed: {}", privacy::client(src), e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{json_response, max_age, read_body, rtype, MAX_QUERY_LEN};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype, Serial},
+        rdata::{Soa, A},
+    };
+    use hyper::{Body, StatusCode};
+    use std::str::FromStr;
+
+    #[test]
+    fn json() {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        builder.header_mut().set_rd(true);
+        let mut builder = builder.question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let query = builder.into_message();
+
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&query, Rcode::NoError)
+            .unwrap();
+        builder
+            .push((&name, 300, A::from_octets(192, 0, 2, 1)))
+            .unwrap();
+        let value = json_response(&builder.into_message());
+
+        assert_eq!(value["Status"], 0);
+        assert_eq!(value["RD"], true);
+        assert_eq!(value["Question"][0]["name"], "example.com.");
+        assert_eq!(value["Answer"][0]["type"], 1);
+        assert_eq!(value["Answer"][0]["TTL"], 300);
+        assert_eq!(value["Answer"][0]["data"], "192.0.2.1");
+    }
+
+    #[test]
+    fn types() {
+        assert_eq!(rtype(None), Some(Rtype::A));
+        assert_eq!(rtype(Some("aaaa".to_string())), Some(Rtype::Aaaa));
+        assert_eq!(rtype(Some("65".to_string())), Some(Rtype::Https));
+        assert_eq!(rtype(Some("bogus".to_string())), None);
+    }
+
+    #[test]
+    fn max_ages() {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let query = builder.into_message();
+        let resp = |rcode, answers: &[u32]| -> Message<Bytes> {
+            let mut builder = MessageBuilder::from_target(BytesMut::new())
+                .unwrap()
+                .start_answer(&query, rcode)
+                .unwrap();
+            for ttl in answers {
+                builder
+                    .push((&name, *ttl, A::from_octets(192, 0, 2, 1)))
+                    .unwrap();
+            }
+            let mut builder = builder.authority();
+            builder
+                .push((
+                    &name,
+                    3600,
+                    Soa::new(
+                        name.clone(),
+                        name.clone(),
+                        Serial(1),
+                        7200,
+                        3600,
+                        1209600,
+                        300,
+                    ),
+                ))
+                .unwrap();
+            builder.into_message()
+        };
+
+        assert_eq!(max_age(&resp(Rcode::NoError, &[600, 60])), Some(60));
+        // Negative answers are cached for the negative TTL.
+        assert_eq!(max_age(&resp(Rcode::NXDomain, &[])), Some(300));
+        assert_eq!(max_age(&resp(Rcode::NoError, &[])), Some(300));
+        assert_eq!(max_age(&resp(Rcode::ServFail, &[])), None);
+    }
+
+    #[tokio::test]
+    async fn body_limit() {
+        let (mut tx, body) = Body::channel();
+        tokio::spawn(async move {
+            tx.send_data(Bytes::from_static(b"\x12\x34")).await.unwrap();
+            tx.send_data(Bytes::from_static(b"\x01\x00")).await.unwrap();
+        });
+        assert_eq!(
+            read_body(body, MAX_QUERY_LEN).await.unwrap(),
+            Bytes::from_static(b"\x12\x34\x01\x00")
+        );
+
+        // Chunked bodies telling nothing of their length ahead
+        let (mut tx, body) = Body::channel();
+        tokio::spawn(
+            async move { while tx.send_data(Bytes::from(vec![0; 4096])).await.is_ok() {} },
+        );
+        assert_eq!(
+            read_body(body, MAX_QUERY_LEN).await,
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! gRPC API resolving queries on behalf of the clients given, see `proto/resolver.proto`.
+//!
+//! Queries go through the same path as the ones received over UDP and TCP: the ACL, the limit on the queries in flight, the routing script, and the statistics.
+
+use self::pb::{
+    decision::Verdict,
+    resolver_server::{Resolver, ResolverServer},
+    Decision, Record, ResolveRequest, ResolveResponse,
+};
+use crate::{
+    acl::unmapped,
+    handle::RouterHandle,
+    parser::GrpcConfig,
+    stats::Stats,
+    worker::{admit, resolve, Limits},
+};
+use anyhow::{Context, Result};
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{iana::Rcode, Dname, Message, MessageBuilder, ParsedDname, Rtype},
+    rdata::AllRecordData,
+};
+use droute::{
+    utils::{is_blackhole, IpCidr},
+    Listener,
+};
+use log::*;
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::sync::watch;
+use tonic::{transport::Server, Request, Response, Status};
+
+mod pb {
+    tonic::include_proto!("dcompass.v1");
+}
+
+struct Service {
+    router: Arc<RouterHandle>,
+    limits: Arc<Limits>,
+    stats: Arc<Stats>,
+    trusted_peers: IpCidr,
+}
+
+// Build the query out of the request.
+fn query(req: &ResolveRequest) -> std::result::Result<Bytes, Status> {
+    if !req.query.is_empty() {
+        return Ok(Bytes::copy_from_slice(&req.query));
+    }
+    let qname = Dname::<Bytes>::from_str(&req.name)
+        .map_err(|e| Status::invalid_argument(format!("invalid name `{}`: {}", req.name, e)))?;
+    let qtype = if req.r#type.is_empty() {
+        Rtype::A
+    } else {
+        req.r#type
+            .to_uppercase()
+            .parse()
+            .map_err(|_| Status::invalid_argument(format!("invalid type `{}`", req.r#type)))?
+    };
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(64))
+        .map_err(|e| Status::internal(e.to_string()))?;
+    builder.header_mut().set_id(rand::random());
+    builder.header_mut().set_rd(true);
+    let mut builder = builder.question();
+    builder
+        .push((qname, qtype))
+        .map_err(|e| Status::internal(e.to_string()))?;
+    Ok(builder.finish().freeze())
+}
+
+// Records in the answer section, in presentation format
+fn answers(resp: &Message<Bytes>) -> Vec<Record> {
+    let mut records = Vec::new();
+    for item in resp.answer().into_iter().flatten().flatten() {
+        if let Ok(Some(record)) = item.into_record::<AllRecordData<Bytes, ParsedDname<Bytes>>>() {
+            records.push(Record {
+                name: record.owner().to_string(),
+                r#type: record.rtype().to_string(),
+                ttl: record.ttl(),
+                data: record.data().to_string(),
+            });
+        }
+    }
+    records
+}
+
+impl Service {
+    // Resolve the query on behalf of the peer calling, or the client given by a trusted peer.
+    async fn resolve(
+        &self,
+        req: Request<ResolveRequest>,
+    ) -> std::result::Result<Message<Bytes>, Status> {
+        let peer = req
+            .remote_addr()
+            .ok_or_else(|| Status::invalid_argument("client unknown"))?;
+        let req = req.into_inner();
+        // Anyone else could get past the ACL by naming an allowed client.
+        let client = if req.client.is_empty() || !self.trusted_peers.contains(unmapped(peer.ip())) {
+            peer
+        } else {
+            let ip = IpAddr::from_str(&req.client).map_err(|_| {
+                Status::invalid_argument(format!("invalid client address `{}`", req.client))
+            })?;
+            SocketAddr::new(ip, 0)
+        };
+        let query = query(&req)?;
+
+        let _permit = admit(&self.limits, &self.stats, &query, client).map_err(|_| {
+            if self.limits.acl.allows(client.ip()) {
+                Status::resource_exhausted("too many queries in flight")
+            } else {
+                Status::permission_denied("client denied by ACL")
+            }
+        })?;
+        resolve(
+            &self.router.get(),
+            &self.stats,
+            query,
+            client,
+            Listener::Grpc,
+            false,
+        )
+        .await
+        .ok_or_else(|| Status::invalid_argument("query cannot be answered"))
+    }
+}
+
+#[tonic::async_trait]
+impl Resolver for Service {
+    async fn resolve(
+        &self,
+        req: Request<ResolveRequest>,
+    ) -> std::result::Result<Response<ResolveResponse>, Status> {
+        let resp = Service::resolve(self, req).await?;
+        Ok(Response::new(ResolveResponse {
+            rcode: resp.header().rcode().to_string(),
+            answers: answers(&resp),
+            response: resp.into_octets().to_vec(),
+        }))
+    }
+
+    async fn decide(
+        &self,
+        req: Request<ResolveRequest>,
+    ) -> std::result::Result<Response<Decision>, Status> {
+        let decision = match Service::resolve(self, req).await {
+            Ok(resp) => {
+                let rcode = resp.header().rcode();
+                Decision {
+                    verdict: if rcode == Rcode::Refused || is_blackhole(&resp) {
+                        Verdict::Blocked
+                    } else if rcode == Rcode::ServFail {
+                        Verdict::Failed
+                    } else {
+                        Verdict::Resolved
+                    } as i32,
+                    rcode: rcode.to_string(),
+                }
+            }
+            Err(status) if status.code() == tonic::Code::PermissionDenied => Decision {
+                verdict: Verdict::Denied as i32,
+                rcode: String::new(),
+            },
+            Err(status) => return Err(status),
+        };
+        Ok(Response::new(decision))
+    }
+}
+
+/// Serve the gRPC API.
+pub async fn serve(
+    config: GrpcConfig,
+    router: Arc<RouterHandle>,
+    limits: Arc<Limits>,
+    stats: Arc<Stats>,
+    mut stop: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut trusted_peers = IpCidr::new();
+    for cidr in &config.trusted_peers {
+        trusted_peers.add_cidr(cidr)?;
+    }
+    info!("serving the gRPC API on {}", config.listen);
+    Server::builder()
+        .add_service(ResolverServer::new(Service {
+            router,
+            limits,
+            stats,
+            trusted_peers,
+        }))
+        // No more requests are accepted once `stop` is signaled.
+        .serve_with_shutdown(config.listen, async move {
+            let _ = stop.changed().await;
+        })
+        .await
+        .with_context(|| format!("failed to serve on {}", config.listen))?;
+    Ok(())
+}
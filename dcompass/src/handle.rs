@@ -0,0 +1,110 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Handle to the router in use, which can be replaced at runtime without interrupting the listeners.
+
+use droute::{builders::RuneScript, Router};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+};
+
+/// The router queries are currently resolved with
+pub struct RouterHandle {
+    router: RwLock<Arc<Router<RuneScript>>>,
+    // Router taking over temporarily, e.g. behind a captive portal
+    detour: RwLock<Option<Arc<Router<RuneScript>>>>,
+    // Whether queries are answered out of the cache alone, kept across routers swapped in
+    offline: AtomicBool,
+    // Connectivity over IPv4 and IPv6 probed, kept across routers swapped in
+    connectivity: RwLock<(bool, bool)>,
+}
+
+impl RouterHandle {
+    pub fn new(router: Router<RuneScript>) -> Self {
+        Self {
+            router: RwLock::new(Arc::new(router)),
+            detour: RwLock::new(None),
+            offline: AtomicBool::new(false),
+            connectivity: RwLock::new((true, true)),
+        }
+    }
+
+    /// Get the router in use. Queries already being resolved keep the router they started with.
+    pub fn get(&self) -> Arc<Router<RuneScript>> {
+        match &*self.detour.read().unwrap() {
+            Some(detour) => detour.clone(),
+            None => self.main(),
+        }
+    }
+
+    /// Get the router configured, regardless of any detour.
+    pub fn main(&self) -> Arc<Router<RuneScript>> {
+        self.router.read().unwrap().clone()
+    }
+
+    /// Replace the router configured for the queries to come.
+    pub fn swap(&self, router: Router<RuneScript>) {
+        self.adopt(&router);
+        *self.router.write().unwrap() = Arc::new(router);
+    }
+
+    /// Take a detour to the router given, or return to the one configured with `None`.
+    pub fn detour(&self, router: Option<Router<RuneScript>>) {
+        if let Some(router) = &router {
+            self.adopt(router);
+        }
+        *self.detour.write().unwrap() = router.map(Arc::new);
+    }
+
+    // Carry the state over to the router about to be used.
+    fn adopt(&self, router: &Router<RuneScript>) {
+        let (ipv4, ipv6) = *self.connectivity.read().unwrap();
+        router.upstreams().set_offline(self.offline());
+        router.upstreams().set_connectivity(ipv4, ipv6);
+    }
+
+    /// Answer the queries out of the cache alone, without touching the network, or go back online.
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.store(offline, Ordering::Relaxed);
+        self.main().upstreams().set_offline(offline);
+        if let Some(detour) = &*self.detour.read().unwrap() {
+            detour.upstreams().set_offline(offline);
+        }
+    }
+
+    /// Whether the queries are answered out of the cache alone.
+    pub fn offline(&self) -> bool {
+        self.offline.load(Ordering::Relaxed)
+    }
+
+    /// Skip the upstreams of the address families the host has no connectivity over, see `Upstreams::set_connectivity`.
+    pub fn set_connectivity(&self, ipv4: bool, ipv6: bool) {
+        *self.connectivity.write().unwrap() = (ipv4, ipv6);
+        self.main().upstreams().set_connectivity(ipv4, ipv6);
+        if let Some(detour) = &*self.detour.read().unwrap() {
+            detour.upstreams().set_connectivity(ipv4, ipv6);
+        }
+    }
+
+    /// Drop the connections of the routers in use to their upstreams, e.g. once the network changed.
+    pub async fn reconnect(&self) {
+        let detour = self.detour.read().unwrap().clone();
+        self.main().upstreams().reconnect().await;
+        if let Some(detour) = detour {
+            detour.upstreams().reconnect().await;
+        }
+    }
+}
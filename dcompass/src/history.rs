@@ -0,0 +1,157 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! In-memory history of the queries answered, kept within the retention configured and served on the control endpoint.
+
+use crate::parser::HistoryConfig;
+use bytes::Bytes;
+use domain::base::Message;
+use droute::{
+    memory::{self, Category, Charge},
+    privacy,
+};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    mem::size_of,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A query answered
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Entry {
+    /// Milliseconds since UNIX epoch the query was answered at
+    pub time: u64,
+    /// Client address, shown per the log privacy filter
+    pub client: String,
+    /// Query name, shown per the log privacy filter
+    pub qname: String,
+    pub qtype: String,
+    pub rcode: String,
+    /// Number of records in the answer section
+    pub answers: u16,
+    /// Milliseconds taken to answer
+    pub latency: u64,
+}
+
+impl Entry {
+    /// Create the entry out of the response to the query.
+    pub fn new(src: SocketAddr, resp: &Message<Bytes>, latency: Duration) -> Option<Self> {
+        let question = resp.first_question()?;
+        Some(Self {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            client: privacy::client(src),
+            qname: privacy::qname(&question.qname()),
+            qtype: question.qtype().to_string(),
+            rcode: resp.header().rcode().to_string(),
+            answers: resp.header_counts().ancount(),
+            latency: latency.as_millis() as u64,
+        })
+    }
+
+    // Approximate memory held by the entry in the history.
+    fn size(&self) -> usize {
+        size_of::<(Self, Charge)>()
+            + self.client.len()
+            + self.qname.len()
+            + self.qtype.len()
+            + self.rcode.len()
+    }
+}
+
+/// Ring buffer of the latest queries answered
+pub struct History {
+    max_entries: usize,
+    retention: Option<Duration>,
+    entries: Mutex<VecDeque<(Entry, Charge)>>,
+}
+
+impl History {
+    pub fn new(config: &HistoryConfig) -> Self {
+        Self {
+            max_entries: config.max_entries.max(1),
+            retention: config.retention.map(Duration::from_secs),
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // Drop the entries older than the retention, given the current time in milliseconds.
+    fn expire(&self, entries: &mut VecDeque<(Entry, Charge)>, now: u64) {
+        if let Some(retention) = self.retention {
+            let oldest = now.saturating_sub(retention.as_millis() as u64);
+            while matches!(entries.front(), Some((e, _)) if e.time < oldest) {
+                entries.pop_front();
+            }
+        }
+    }
+
+    pub fn record(&self, entry: Entry) {
+        let mut entries = self.entries.lock().unwrap();
+        self.expire(&mut entries, entry.time);
+        if entries.len() >= self.max_entries {
+            entries.pop_front();
+        }
+        let charge = Charge::new(Category::History, entry.size());
+        entries.push_back((entry, charge));
+        // Cache entries are evicted first, the oldest queries only if that is not enough.
+        while memory::reclaim(Category::History) > 0 && entries.len() > 1 {
+            entries.pop_front();
+        }
+    }
+
+    /// The latest `limit` entries within the retention, the newest first
+    pub fn recent(&self, limit: usize) -> Vec<Entry> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(last) = entries.back().map(|(e, _)| e.time) {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(last);
+            self.expire(&mut entries, now);
+        }
+        entries
+            .iter()
+            .rev()
+            .take(limit)
+            .map(|(e, _)| e.clone())
+            .collect()
+    }
+
+    /// Export every entry within the retention as CSV, the oldest first
+    pub fn csv(&self) -> String {
+        let mut csv = "time,client,qname,qtype,rcode,answers,latency\n".to_string();
+        for e in self.recent(usize::MAX).iter().rev() {
+            // None of the fields contains commas or quotes, except for query names which may contain anything.
+            let _ = writeln!(
+                csv,
+                "{},{},\"{}\",{},{},{},{}",
+                e.time,
+                e.client,
+                e.qname.replace('"', "\"\""),
+                e.qtype,
+                e.rcode,
+                e.answers,
+                e.latency
+            );
+        }
+        csv
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Hooks notified of significant events, so that operators can be alerted without scraping the logs.
+
+use crate::{
+    handle::RouterHandle,
+    parser::HooksConfig,
+    slo::SloMonitor,
+    stats::{Stats, StatsSnapshot},
+};
+use droute::{Label, RouterStats};
+use log::*;
+use serde::Serialize;
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use tokio::{
+    process::Command,
+    time::{interval, MissedTickBehavior},
+};
+
+/// Events the hooks are notified of
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Too many queries to the upstream failed
+    UpstreamDown { upstream: Label, error_ratio: f64 },
+    /// The upstream marked down is working again
+    UpstreamUp { upstream: Label },
+    /// Failed to pull the configuration or the rule lists from the cluster leader
+    #[cfg_attr(not(feature = "cluster"), allow(dead_code))]
+    RuleListUpdateFailed { error: String },
+    /// Too many queries were answered with SERVFAIL
+    ServfailRateExceeded { ratio: f64 },
+    /// The SERVFAIL rate is back below the threshold
+    ServfailRateRecovered { ratio: f64 },
+    /// More queries than allowed by the SLO took longer than its latency in milliseconds
+    SloViolated {
+        slo: String,
+        latency: u64,
+        percentile: f64,
+        slow_ratio: f64,
+    },
+    /// The SLO violated is met again
+    SloRecovered { slo: String, slow_ratio: f64 },
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::UpstreamDown { .. } => "upstream_down",
+            Self::UpstreamUp { .. } => "upstream_up",
+            Self::RuleListUpdateFailed { .. } => "rule_list_update_failed",
+            Self::ServfailRateExceeded { .. } => "servfail_rate_exceeded",
+            Self::ServfailRateRecovered { .. } => "servfail_rate_recovered",
+            Self::SloViolated { .. } => "slo_violated",
+            Self::SloRecovered { .. } => "slo_recovered",
+        }
+    }
+}
+
+/// Webhooks and scripts to notify
+pub struct Hooks {
+    config: HooksConfig,
+    #[cfg(feature = "webhooks")]
+    client: reqwest::Client,
+}
+
+impl Hooks {
+    #[cfg(feature = "webhooks")]
+    pub fn new(config: HooksConfig) -> reqwest::Result<Self> {
+        Ok(Self {
+            config,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+        })
+    }
+
+    #[cfg(not(feature = "webhooks"))]
+    pub fn new(config: HooksConfig) -> anyhow::Result<Self> {
+        if !config.webhooks.is_empty() {
+            warn!("webhooks are not available in this build, only the scripts are notified");
+        }
+        Ok(Self { config })
+    }
+
+    /// Whether there is anything to notify
+    pub fn enabled(&self) -> bool {
+        !(self.config.webhooks.is_empty() && self.config.scripts.is_empty())
+    }
+
+    /// Log the event, and notify every hook of it in the background.
+    pub fn notify(&self, event: Event) {
+        if !self.enabled() {
+            warn!("event occurred: {:?}", event);
+            return;
+        }
+        warn!("notifying hooks of event: {:?}", event);
+        let payload = match serde_json::to_string(&event) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("failed to serialize event {}: {}", event.name(), e);
+                return;
+            }
+        };
+
+        #[cfg(feature = "webhooks")]
+        for url in &self.config.webhooks {
+            let req = self
+                .client
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(payload.clone());
+            let url = url.clone();
+            tokio::spawn(async move {
+                if let Err(e) = req.send().await.and_then(|r| r.error_for_status()) {
+                    warn!("failed to notify webhook {}: {}", url, e);
+                }
+            });
+        }
+
+        for script in &self.config.scripts {
+            let mut cmd = Command::new(script);
+            cmd.env("DCOMPASS_EVENT", event.name())
+                .env("DCOMPASS_PAYLOAD", &payload);
+            let script = script.clone();
+            tokio::spawn(async move {
+                match cmd.status().await {
+                    Ok(status) if status.success() => (),
+                    Ok(status) => warn!("hook script {} exited with {}", script.display(), status),
+                    Err(e) => warn!("failed to run hook script {}: {}", script.display(), e),
+                }
+            });
+        }
+    }
+}
+
+/// Detection of the events out of the statistics sampled every interval.
+#[derive(Default)]
+pub struct Monitor {
+    last: Option<(StatsSnapshot, RouterStats)>,
+    down: HashSet<Label>,
+    servfail: bool,
+}
+
+impl Monitor {
+    /// Compare the statistics with the ones last sampled, returning the events occurred in between.
+    pub fn check(
+        &mut self,
+        config: &HooksConfig,
+        listener: StatsSnapshot,
+        router: RouterStats,
+    ) -> Vec<Event> {
+        let mut events = Vec::new();
+        let (last_listener, last_router) = match self.last.replace((listener, router.clone())) {
+            Some(last) => last,
+            None => return events,
+        };
+        // Counters restart from zero once the router is replaced, leaving nothing to compare in this interval.
+        let ratio = |num: u64, last_num: u64, den: u64, last_den: u64| {
+            let den = den.saturating_sub(last_den);
+            (den >= config.min_queries.max(1))
+                .then(|| num.saturating_sub(last_num) as f64 / den as f64)
+        };
+
+        for (tag, stats) in &router.upstreams {
+            let last = last_router.upstreams.get(tag).copied().unwrap_or_default();
+            let error_ratio = match ratio(stats.errors, last.errors, stats.queries, last.queries) {
+                Some(r) => r,
+                None => continue,
+            };
+            if error_ratio >= config.upstream_down_ratio {
+                if self.down.insert(tag.clone()) {
+                    events.push(Event::UpstreamDown {
+                        upstream: tag.clone(),
+                        error_ratio,
+                    });
+                }
+            } else if self.down.remove(tag) {
+                events.push(Event::UpstreamUp {
+                    upstream: tag.clone(),
+                });
+            }
+        }
+        // Upstreams gone with the router replaced are no longer tracked.
+        self.down.retain(|tag| router.upstreams.contains_key(tag));
+
+        if let Some(ratio) = ratio(
+            listener.servfail,
+            last_listener.servfail,
+            listener.received,
+            last_listener.received,
+        ) {
+            let exceeded = ratio >= config.servfail_ratio;
+            if exceeded != self.servfail {
+                self.servfail = exceeded;
+                events.push(if exceeded {
+                    Event::ServfailRateExceeded { ratio }
+                } else {
+                    Event::ServfailRateRecovered { ratio }
+                });
+            }
+        }
+        events
+    }
+}
+
+/// Sample the statistics every interval and notify the hooks of the events detected.
+pub async fn monitor(hooks: Arc<Hooks>, router: Arc<RouterHandle>, stats: Arc<Stats>) {
+    let mut monitor = Monitor::default();
+    let mut slo_monitor = SloMonitor::default();
+    let mut ticks = interval(Duration::from_secs(hooks.config.interval.max(1)));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let mut events = monitor.check(&hooks.config, stats.snapshot(), router.get().stats());
+        if let Some(slos) = &stats.slos {
+            events.extend(slo_monitor.check(slos));
+        }
+        for event in events {
+            hooks.notify(event);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Synthetic load generator. Queries are drawn from a skewed distribution over domains and query types, roughly resembling what a home or office resolver sees.
+
+use anyhow::{Context, Result};
+use bytes::{Bytes, BytesMut};
+use domain::base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype};
+use futures::future::join_all;
+use rand::{distributions::WeightedIndex, prelude::*, rngs::StdRng};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+use tokio::{
+    net::UdpSocket,
+    time::{interval, timeout, MissedTickBehavior},
+};
+
+// Used if no domain list is given. Earlier ones are queried more often.
+const DOMAINS: &[&str] = &[
+    "google.com",
+    "youtube.com",
+    "facebook.com",
+    "apple.com",
+    "microsoft.com",
+    "amazon.com",
+    "cloudflare.com",
+    "wikipedia.org",
+    "github.com",
+    "twitter.com",
+    "instagram.com",
+    "netflix.com",
+    "baidu.com",
+    "qq.com",
+    "bilibili.com",
+    "taobao.com",
+    "reddit.com",
+    "linkedin.com",
+    "office.com",
+    "stackoverflow.com",
+];
+
+// Query types with their weights. HTTPS (65) queries are issued alongside A/AAAA by modern browsers.
+const QTYPES: &[(Rtype, u32)] = &[
+    (Rtype::A, 55),
+    (Rtype::Aaaa, 30),
+    (Rtype::Int(65), 10),
+    (Rtype::Mx, 2),
+    (Rtype::Txt, 2),
+    (Rtype::Ns, 1),
+];
+
+/// Options of the load generator
+#[derive(Debug, StructOpt)]
+pub struct LoadgenOpts {
+    /// Address of the DNS server to put load on.
+    target: SocketAddr,
+
+    /// Queries per second to send in total.
+    #[structopt(short, long, default_value = "1000")]
+    qps: u64,
+
+    /// Duration of the test in seconds.
+    #[structopt(short, long, default_value = "10")]
+    duration: u64,
+
+    /// Number of concurrent clients, each of which uses its own socket.
+    #[structopt(short, long, default_value = "64")]
+    concurrency: u64,
+
+    /// Time (in milliseconds) to wait for a response before counting the query as timed out.
+    #[structopt(short, long, default_value = "2000")]
+    timeout: u64,
+
+    /// Path to a file with one domain per line, the more popular ones first. Use built-in list if not provided.
+    #[structopt(long, parse(from_os_str))]
+    domains: Option<PathBuf>,
+
+    /// Percentage of queries for random subdomains, which are never cached.
+    #[structopt(long, default_value = "10")]
+    miss_rate: u32,
+}
+
+// The query mix shared by all clients.
+struct Mix {
+    domains: Vec<Dname<Bytes>>,
+    popularity: WeightedIndex<f64>,
+    qtypes: WeightedIndex<u32>,
+    miss_rate: u32,
+}
+
+impl Mix {
+    fn new(domains: Vec<Dname<Bytes>>, miss_rate: u32) -> Result<Self> {
+        // Zipf-like popularity, which is typical for DNS traffic.
+        let popularity = WeightedIndex::new((1..=domains.len()).map(|r| 1.0 / r as f64))
+            .context("no domain to query")?;
+        Ok(Self {
+            domains,
+            popularity,
+            qtypes: WeightedIndex::new(QTYPES.iter().map(|(_, w)| *w)).unwrap(),
+            miss_rate,
+        })
+    }
+
+    fn query(&self, rng: &mut impl Rng) -> Result<Message<Bytes>> {
+        let domain = &self.domains[self.popularity.sample(rng)];
+        let qtype = QTYPES[self.qtypes.sample(rng)].0;
+
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(512))?;
+        builder.header_mut().set_id(rng.gen());
+        builder.header_mut().set_rd(true);
+        let mut builder = builder.question();
+        if rng.gen_range(0..100) < self.miss_rate {
+            let name = Dname::<Bytes>::from_str(&format!("{:08x}.{}", rng.gen::<u32>(), domain))?;
+            builder.push((&name, qtype))?;
+        } else {
+            builder.push((domain, qtype))?;
+        }
+        Ok(builder.into_message())
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    sent: u64,
+    timeouts: u64,
+    errors: u64,
+    rcodes: BTreeMap<String, u64>,
+    // In microseconds
+    latencies: Vec<u64>,
+}
+
+impl Report {
+    fn merge(mut self, other: Report) -> Self {
+        self.sent += other.sent;
+        self.timeouts += other.timeouts;
+        self.errors += other.errors;
+        for (rcode, n) in other.rcodes {
+            *self.rcodes.entry(rcode).or_default() += n;
+        }
+        self.latencies.extend(other.latencies);
+        self
+    }
+
+    fn percentile(&self, p: usize) -> Duration {
+        // Latencies are sorted before displaying
+        match self.latencies.len() {
+            0 => Duration::ZERO,
+            n => Duration::from_micros(self.latencies[(n * p / 100).min(n - 1)]),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "sent: {}, answered: {}, timed out: {}, errored: {}",
+            self.sent,
+            self.latencies.len(),
+            self.timeouts,
+            self.errors
+        )?;
+        for (rcode, n) in &self.rcodes {
+            writeln!(f, "  {}: {}", rcode, n)?;
+        }
+        write!(
+            f,
+            "latency p50: {:?}, p90: {:?}, p99: {:?}, max: {:?}",
+            self.percentile(50),
+            self.percentile(90),
+            self.percentile(99),
+            self.percentile(100)
+        )
+    }
+}
+
+async fn client(
+    target: SocketAddr,
+    mix: Arc<Mix>,
+    period: Duration,
+    deadline: Instant,
+    wait: Duration,
+) -> Result<Report> {
+    let socket = UdpSocket::bind(if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })
+    .await?;
+    socket.connect(target).await?;
+
+    let mut rng = StdRng::from_entropy();
+    let mut ticker = interval(period);
+    // Don't burst to catch up if the server is slow, the achieved rate is reported anyway.
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut report = Report::default();
+    let mut buf = [0; 4096];
+
+    while Instant::now() < deadline {
+        ticker.tick().await;
+        let query = mix.query(&mut rng)?;
+        let start = Instant::now();
+        report.sent += 1;
+        if socket.send(query.as_slice()).await.is_err() {
+            report.errors += 1;
+            continue;
+        }
+
+        // Skip stale responses of the queries timed out earlier.
+        let answer = timeout(wait, async {
+            loop {
+                let len = socket.recv(&mut buf).await?;
+                match Message::from_octets(&buf[..len]) {
+                    Ok(answer) if answer.header().id() == query.header().id() => {
+                        return Ok::<Rcode, std::io::Error>(answer.header().rcode())
+                    }
+                    _ => continue,
+                }
+            }
+        })
+        .await;
+        match answer {
+            Ok(Ok(rcode)) => {
+                report.latencies.push(start.elapsed().as_micros() as u64);
+                *report.rcodes.entry(rcode.to_string()).or_default() += 1;
+            }
+            Ok(Err(_)) => report.errors += 1,
+            Err(_) => report.timeouts += 1,
+        }
+    }
+    Ok(report)
+}
+
+/// Put the load described on the target, and print the report out.
+pub async fn run(opts: LoadgenOpts) -> Result<()> {
+    let domains = match &opts.domains {
+        Some(path) => std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read domains from {}", path.display()))?
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(Dname::from_str)
+            .collect::<std::result::Result<_, _>>()?,
+        None => DOMAINS
+            .iter()
+            .map(|d| Dname::from_str(d))
+            .collect::<std::result::Result<_, _>>()?,
+    };
+    let mix = Arc::new(Mix::new(domains, opts.miss_rate.min(100))?);
+
+    let concurrency = opts.concurrency.max(1);
+    // Each client sends at an equal share of the total rate.
+    let period = Duration::from_secs_f64(concurrency as f64 / opts.qps.max(1) as f64);
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(opts.duration);
+    println!(
+        "sending {} queries per second to {} for {}s with {} clients",
+        opts.qps, opts.target, opts.duration, concurrency
+    );
+
+    let reports = join_all((0..concurrency).map(|_| {
+        tokio::spawn(client(
+            opts.target,
+            mix.clone(),
+            period,
+            deadline,
+            Duration::from_millis(opts.timeout),
+        ))
+    }))
+    .await;
+
+    let mut report = Report::default();
+    for r in reports {
+        report = report.merge(r??);
+    }
+    report.latencies.sort_unstable();
+
+    let elapsed = start.elapsed().as_secs_f64();
+    println!(
+        "achieved {:.0} queries per second, {:.0} answers per second",
+        report.sent as f64 / elapsed,
+        report.latencies.len() as f64 / elapsed
+    );
+    println!("{}", report);
+    Ok(())
+}
@@ -20,30 +20,91 @@
 // #[global_allocator]
 // static GLOBAL: Jemalloc = Jemalloc;
 
+mod acl;
+#[cfg(feature = "remote-config")]
+mod analyze;
+mod batch;
+#[cfg(feature = "cluster")]
+mod cluster;
+mod compat;
+mod compile;
+mod connectivity;
+mod control;
+mod doctor;
+mod doh;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod handle;
+mod history;
+mod hooks;
+mod loadgen;
+mod netwatch;
 mod parser;
+mod portal;
+mod profile;
+mod proxy;
+mod ranking;
+mod root_mirror;
+mod rrl;
+mod runtime;
+#[cfg(unix)]
+mod signals;
+mod slo;
+mod source;
+mod stamps;
+mod stats;
+mod tcp;
+#[cfg(feature = "otlp")]
+mod telemetry;
 #[cfg(test)]
 mod tests;
+mod topk;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
 mod worker;
 
-use self::{parser::Parsed, worker::worker};
+use self::{
+    batch::recv_batch,
+    handle::RouterHandle,
+    history::History,
+    hooks::Hooks,
+    parser::{Backend, FastPathConfig, Parsed, ResolverConfig},
+    proxy::TrustedProxies,
+    rrl::Rrl,
+    slo::Slos,
+    source::ConfigSource,
+    stats::Stats,
+    topk::TopK,
+    worker::{admit, responder, worker, Limits},
+};
 use anyhow::{Context, Result};
-use bytes::BytesMut;
 use droute::{
-    builders::{RouterBuilder, RuneScript},
+    builders::{RouterBuilder, RuneScript, RuneScriptBuilder, UpstreamBuilder, UpstreamsBuilder},
     errors::ScriptError,
-    AsyncTryInto, Router,
+    utils::{declare_group, EdnsPolicy},
+    AnswerOrder, AnyPolicy, AsyncTryInto, ChaosPolicy, FastPath, Router, SlowQueryLog, SpecialUse,
+    SpecialUsePolicy, Zones,
 };
+use futures::future::join_all;
 use log::*;
 use simple_logger::SimpleLogger;
-use std::{net::SocketAddr, path::PathBuf, result::Result as StdResult, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    result::Result as StdResult,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 use structopt::StructOpt;
 use tokio::{
-    fs::File,
-    io::AsyncReadExt,
     net::UdpSocket,
     signal,
-    sync::broadcast::{self, Sender},
-    time::sleep,
+    sync::{
+        broadcast::{self, Sender},
+        mpsc, watch, Semaphore,
+    },
+    time::timeout,
 };
 
 #[derive(Debug, StructOpt)]
@@ -52,158 +113,701 @@ use tokio::{
     about = "High-performance DNS server with freestyle routing scheme support and DoT/DoH functionalities built-in."
 )]
 struct DcompassOpts {
-    /// Path to the configuration file. Use built-in if not provided.
+    /// Path to the configuration file, or HTTPS URL to fetch it from. Use built-in if not provided.
     #[structopt(short, long, parse(from_os_str))]
     config: Option<PathBuf>,
 
+    /// Path of the copy cached of the configuration fetched, used while the URL is unreachable. Default to `config.yaml` under `$XDG_STATE_HOME/dcompass` or `~/.local/state/dcompass`.
+    #[structopt(long, parse(from_os_str))]
+    config_cache: Option<PathBuf>,
+
     /// Set this flag to validate the configuration file only.
     #[structopt(short, long, parse(from_flag))]
     validate: bool,
+
+    /// Name of the profile defined in the configuration file to use. Only the common part is used if not provided.
+    #[structopt(short, long)]
+    profile: Option<String>,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
 }
 
-async fn init(p: Parsed) -> StdResult<(Router<RuneScript>, SocketAddr, LevelFilter), ScriptError> {
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Generate synthetic queries against a DNS server and report the latency and the response codes.
+    Loadgen(loadgen::LoadgenOpts),
+    /// Serve the configuration and rule lists to the followers in the cluster and aggregate their statistics.
+    #[cfg(feature = "cluster")]
+    Leader(cluster::LeaderOpts),
+    /// Generate a key pair to sign the files served to the followers with.
+    #[cfg(feature = "cluster")]
+    Keygen,
+    /// Sign the files to be served to the followers from a plain HTTP endpoint.
+    #[cfg(feature = "cluster")]
+    Sign(cluster::SignOpts),
+    /// Report the rules in the script of a live instance that never matched over a period.
+    #[cfg(feature = "remote-config")]
+    Analyze(analyze::AnalyzeOpts),
+    /// Check the environment the configuration is run in, e.g. the addresses to listen on, the upstreams and their certificates, and the files read.
+    Doctor,
+    /// Compile domain lists into a binary form that loads in no time and takes a fraction of the memory.
+    CompileList(compile::CompileOpts),
+}
+
+async fn init(
+    p: Parsed,
+) -> StdResult<
+    (
+        Router<RuneScript>,
+        Vec<SocketAddr>,
+        LevelFilter,
+        Limits,
+        Backend,
+    ),
+    ScriptError,
+> {
+    for (name, group) in &p.groups {
+        declare_group(name, group.enabled, group.schedule.clone());
+    }
+
+    let policies = Policies::new(&p);
+    let mut zones = Zones::new();
+    for (zone, tag) in p.zones {
+        zones.insert(zone, tag)?;
+    }
+
+    let builder = policies
+        .apply(RouterBuilder::new(p.script, p.upstreams))?
+        .zones(zones)
+        .entries(p.entries);
+
     Ok((
-        RouterBuilder::new(p.script, p.upstreams)
-            .async_try_into()
-            .await?,
-        p.address,
+        builder.async_try_into().await?,
+        p.address.addrs(),
         p.verbosity,
+        Limits {
+            inflight: Arc::new(Semaphore::new(p.max_inflight)),
+            capacity: p.max_inflight,
+            overflow: p.overflow,
+            acl: p.acl.try_into()?,
+            rrl: p.rrl.map(Rrl::new),
+            // Every client is able to take 512 bytes.
+            max_response_size: p.max_response_size.max(512),
+        },
+        p.backend,
     ))
 }
 
-async fn serve(socket: Arc<UdpSocket>, router: Arc<Router<RuneScript>>, tx: &Sender<()>) {
+// Router-level policies of the configuration, which the virtual resolvers are built with as well as the main router.
+// Zones and entries are left to the main router, as they refer to its upstreams and its script.
+struct Policies {
+    any_query: AnyPolicy,
+    edns_options: EdnsPolicy,
+    answer_order: AnswerOrder,
+    chaos: ChaosPolicy,
+    special_use: HashMap<String, SpecialUsePolicy>,
+    root_mirror: bool,
+    slow_query: Option<SlowQueryLog>,
+    deadline: Option<u64>,
+    nsid: Option<String>,
+    fast_path: Option<FastPathConfig>,
+}
+
+impl Policies {
+    fn new(p: &Parsed) -> Self {
+        Self {
+            any_query: p.any_query,
+            edns_options: p.edns_options.clone(),
+            answer_order: p.answer_order,
+            chaos: p.chaos.clone(),
+            special_use: p.special_use.clone(),
+            root_mirror: p.root_mirror.is_some(),
+            slow_query: p.slow_query,
+            deadline: p.deadline,
+            nsid: p.nsid.clone(),
+            fast_path: p.fast_path.clone(),
+        }
+    }
+
+    // Each router gets a fast path of its own.
+    fn apply(
+        &self,
+        builder: RouterBuilder<UpstreamsBuilder<UpstreamBuilder>, RuneScriptBuilder, RuneScript>,
+    ) -> StdResult<
+        RouterBuilder<UpstreamsBuilder<UpstreamBuilder>, RuneScriptBuilder, RuneScript>,
+        ScriptError,
+    > {
+        let mut special_use = SpecialUse::new();
+        for (domain, policy) in &self.special_use {
+            special_use.set(domain, policy.clone())?;
+        }
+
+        let mut builder = builder
+            .any_policy(self.any_query)
+            .edns_policy(self.edns_options.clone())
+            .answer_order(self.answer_order)
+            .chaos(self.chaos.clone())
+            .special_use(special_use);
+        if self.root_mirror {
+            builder = builder.root_mirror(root_mirror::mirror().clone());
+        }
+        if let Some(slow_query) = self.slow_query {
+            builder = builder.slow_query(slow_query);
+        }
+        if let Some(deadline) = self.deadline {
+            builder = builder.deadline(Duration::from_millis(deadline));
+        }
+        if let Some(nsid) = &self.nsid {
+            builder = builder.nsid(nsid.clone());
+        }
+        if let Some(config) = &self.fast_path {
+            let mut fast_path = FastPath::new(config.size);
+            for domain in &config.always_evaluate {
+                fast_path.always_evaluate(domain)?;
+            }
+            builder = builder.fast_path(fast_path);
+        }
+        Ok(builder)
+    }
+}
+
+// Whether two addresses take the same port on a common address, which sockets bound with SO_REUSEPORT would split the queries to.
+fn overlaps(a: &SocketAddr, b: &SocketAddr) -> bool {
+    a.port() == b.port() && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
+
+// Build the routers of the virtual resolvers with the policies of the main one, whose limits, listener settings, statistics and history they share.
+// Their addresses are to overlap with neither the ones of the main router nor the ones of the other resolvers.
+async fn init_resolvers(
+    resolvers: HashMap<String, ResolverConfig>,
+    policies: &Policies,
+    main: &[SocketAddr],
+) -> Result<Vec<(String, Vec<SocketAddr>, Router<RuneScript>)>> {
+    let mut built: Vec<(String, Vec<SocketAddr>, Router<RuneScript>)> =
+        Vec::with_capacity(resolvers.len());
+    for (name, config) in resolvers {
+        let addrs = config.address.addrs();
+        anyhow::ensure!(
+            !addrs.is_empty(),
+            "no address to listen on for the resolver `{}`",
+            name
+        );
+        for addr in &addrs {
+            anyhow::ensure!(
+                !main.iter().any(|a| overlaps(a, addr)),
+                "address {} of the resolver `{}` overlaps with the main one",
+                addr,
+                name
+            );
+            if let Some((other, ..)) = built
+                .iter()
+                .find(|(_, others, _)| others.iter().any(|a| overlaps(a, addr)))
+            {
+                anyhow::bail!(
+                    "address {} of the resolver `{}` overlaps with the resolver `{}`",
+                    addr,
+                    name,
+                    other
+                );
+            }
+        }
+        let builder = policies
+            .apply(RouterBuilder::new(config.script, config.upstreams))
+            .with_context(|| format!("failed to build the resolver `{}`", name))?;
+        let router = builder
+            .async_try_into()
+            .await
+            .with_context(|| format!("failed to build the resolver `{}`", name))?;
+        built.push((name, addrs, router));
+    }
+    Ok(built)
+}
+
+async fn serve(
+    socket: Arc<UdpSocket>,
+    router: Arc<RouterHandle>,
+    limits: &Arc<Limits>,
+    stats: &Arc<Stats>,
+    tx: &Sender<()>,
+) {
+    let (responses, rx) = mpsc::unbounded_channel();
+    tokio::spawn(responder(socket.clone(), rx));
+
     loop {
-        // Size recommended by DNS Flag Day 2020: "This is practical for the server operators that know their environment, and the defaults in the DNS software should reflect the minimum safe size which is 1232."
-        let mut buf = BytesMut::with_capacity(1024);
-        buf.resize(1024, 0);
         // On windows, some applications may go away after they got their first response, resulting in a broken pipe, we should discard errors on receiving/sending messages.
-        let (len, src) = match socket.recv_from(&mut buf).await {
-            Ok(r) => r,
+        let pkts = match recv_batch(&socket).await {
+            Ok(pkts) => pkts,
             Err(e) => {
                 warn!("failed to receive query: {}", e);
                 continue;
             }
         };
 
-        buf.resize(len, 0);
-
-        let router = router.clone();
-        let socket = socket.clone();
-        let mut shutdown = tx.subscribe();
-        #[rustfmt::skip]
-        tokio::spawn(async move {
-            tokio::select! {
-                biased; res = worker(router, socket, buf.freeze(), src) => {
-                    match res {
-                        Ok(_) => (),
-                        Err(e) => warn!("handling query failed: {}", e),
+        for (buf, src) in pkts {
+            let permit = match admit(limits, stats, &buf, src.addr) {
+                Ok(permit) => permit,
+                Err(resp) => {
+                    if let Some(resp) = resp {
+                        let _ = responses.send((resp, src));
                     }
+                    continue;
                 }
-                _ = shutdown.recv() => {
-                    // If a shutdown signal is received, return from the spawned task.
-                    // This will result in the task terminating.
-                    log::warn!("worker shut down");
+            };
+
+            let router = router.get();
+            let (limits, stats) = (limits.clone(), stats.clone());
+            let responses = responses.clone();
+            let mut shutdown = tx.subscribe();
+            #[rustfmt::skip]
+            tokio::spawn(async move {
+                tokio::select! {
+                    biased; res = worker(router, limits, stats, responses, buf, src) => {
+                        match res {
+                            Ok(_) => (),
+                            Err(e) => warn!("handling query failed: {}", e),
+                        }
+                    }
+                    _ = shutdown.recv() => {
+                        // If a shutdown signal is received, return from the spawned task.
+                        // This will result in the task terminating.
+                        log::warn!("worker shut down");
+                    }
                 }
-            }
-        });
+                // Release the slot only after the query is handled.
+                drop(permit);
+            });
+        }
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+// Read the configuration before the runtime configured is built.
+fn read_config(source: &ConfigSource) -> Result<String> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(source.read())
+}
+
+fn main() -> Result<()> {
     // console_subscriber::init();
 
-    let args: DcompassOpts = DcompassOpts::from_args();
+    let mut args: DcompassOpts = DcompassOpts::from_args();
 
-    // If the config path is manually specified with `-c` flag, we use it and any error should fail early.
-    // If there is no specified config but there is `config.yaml` under the path where user is invoking `dcompass` (not the absolute path of the binary), then we shall try that config. If the file exists but we failed to read, this should fail. Otherwise, we shall use the default anyway.
-    let config = if let Some(config_path) = args.config {
-        let display_path = config_path.as_path().display();
-        let mut file = File::open(config_path.clone())
-            .await
-            .with_context(|| format!("Failed to open the file specified: {}", display_path))?;
-        let mut config = String::new();
-        file.read_to_string(&mut config)
-            .await
-            .with_context(|| format!("Failed to read from the file specified: {}", display_path))?;
-        println!("Using the config file specified: {}", display_path);
-        config
-    } else {
-        let mut config_path = std::env::current_dir()?;
-        config_path.push("config.yaml");
-        let display_path = config_path.as_path().display();
-        match File::open(config_path.clone()).await {
-            // We have found the config and successfully opened it.
-            Ok(mut file) => {
-                let mut config = String::new();
-                file.read_to_string(&mut config).await.with_context(|| {
-                    format!("Failed to read from the file found: {}", display_path)
-                })?;
-                println!("Using the config under current path: {}", display_path);
-                config
-            }
-            // No config found, using built-in.
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                println!("No config found or specified, using built-in config.");
-                include_str!("../../configs/default.json").to_owned()
-            }
-            // Found but unable to open. We shall exit as this is intended.
-            Err(e) => {
-                return Err(e).with_context(|| {
-                    format!("`config.yaml` found, but failed to open: {}", display_path)
-                })
-            }
+    match args.cmd.take() {
+        Some(Command::Loadgen(opts)) => {
+            return tokio::runtime::Runtime::new()?.block_on(loadgen::run(opts))
         }
-    };
+        #[cfg(feature = "cluster")]
+        Some(Command::Leader(opts)) => {
+            SimpleLogger::new().with_level(LevelFilter::Info).init()?;
+            return tokio::runtime::Runtime::new()?.block_on(cluster::lead(opts));
+        }
+        #[cfg(feature = "cluster")]
+        Some(Command::Keygen) => return cluster::keygen(),
+        #[cfg(feature = "cluster")]
+        Some(Command::Sign(opts)) => return cluster::sign_files(opts),
+        #[cfg(feature = "remote-config")]
+        Some(Command::Analyze(opts)) => {
+            return tokio::runtime::Runtime::new()?.block_on(analyze::run(opts))
+        }
+        Some(Command::Doctor) => {
+            let source = ConfigSource::new(args.config.clone(), args.config_cache.clone())?;
+            let runtime = tokio::runtime::Runtime::new()?;
+            let config = runtime.block_on(source.read())?;
+            return runtime.block_on(doctor::run(&config, args.profile.as_deref()));
+        }
+        Some(Command::CompileList(opts)) => return compile::run(opts),
+        None => (),
+    }
 
+    let source = ConfigSource::new(args.config.clone(), args.config_cache.clone())?;
+    let config = read_config(&source)?;
+    let mut parsed: Parsed = profile::parse(&config, args.profile.as_deref())
+        .with_context(|| "Failed to parse the configuration file".to_string())?;
+    let runtime_config = std::mem::take(&mut parsed.runtime);
+
+    runtime::build(&runtime_config)
+        .with_context(|| "Failed to build the runtime".to_string())?
+        .block_on(run(args, source, parsed, runtime_config.shards))
+}
+
+async fn run(
+    args: DcompassOpts,
+    source: ConfigSource,
+    mut parsed: Parsed,
+    shards: usize,
+) -> Result<()> {
     // Create whatever we need for get dcompass up and running.
-    let (router, addr, verbosity) = init(
-        serde_yaml::from_str(&config)
-            .with_context(|| "Failed to parse the configuration file".to_string())?,
-    )
-    .await?;
+    let drain_timeout = Duration::from_secs(parsed.drain_timeout);
+    let otlp_endpoint = parsed.otlp_endpoint.clone();
+    let log_privacy = parsed.log_privacy.take();
+    let ipv6_only = parsed.ipv6_only;
+    let tcp_config = parsed.tcp.clone();
+    #[cfg(feature = "cluster")]
+    let cluster = parsed.cluster.take();
+    #[cfg(not(feature = "cluster"))]
+    if let Some(cluster) = parsed.cluster.take() {
+        warn!(
+            "cluster mode following {} is not available in this build, the local configuration is used",
+            cluster.leader
+        );
+    }
+    let captive_portal = parsed.captive_portal.take();
+    let network_watch = parsed.network_watch.clone();
+    let connectivity_config = parsed.connectivity.take();
+    let ranking_config = parsed.ranking.take();
+    // Routers built later on share the mirror maintained here, so the config is kept for them.
+    let root_mirror_config = parsed.root_mirror.clone();
+    let control_config = parsed.control.take();
+    let offline = parsed.offline;
+    let doh_config = parsed.doh.take();
+    let grpc_config = parsed.grpc.take();
+    let history = parsed.history.take();
+    let top_k = parsed.top_k.take();
+    // Caps are in force before the rule lists and the cache fill up.
+    droute::memory::set_caps((&parsed.memory).into());
+    droute::wire::set_case_insensitive_keys(parsed.case_insensitive_cache);
+    let slos = Slos::new(std::mem::take(&mut parsed.slos))?;
+    let hooks = Arc::new(Hooks::new(std::mem::take(&mut parsed.hooks))?);
+    let deprecations = std::mem::take(&mut parsed.deprecations);
+    let resolvers = std::mem::take(&mut parsed.resolvers);
+    let policies = Policies::new(&parsed);
+    let (router, addrs, verbosity, limits, backend) = init(stamps::load(parsed).await?).await?;
+    anyhow::ensure!(!addrs.is_empty(), "no address to listen on");
+    let resolvers = init_resolvers(resolvers, &policies, &addrs).await?;
+    // Every permit is acquired at once to drain the in-flight queries on shutdown.
+    let capacity = u32::try_from(limits.capacity)
+        .with_context(|| format!("max_inflight is over {}", u32::MAX))?;
 
     // If we are only required to validate the config, we shall be safe to exit now.
     if args.validate {
+        for deprecation in &deprecations {
+            println!("Warning: {}", deprecation);
+        }
         println!("The configuration provided is valid.");
         return Ok(());
     }
 
     // Start logging
-    SimpleLogger::new()
-        // These modules are quite chatty, we want to disable it.
-        .with_level(verbosity)
-        .init()?;
+    // The logger itself lets everything through, so that verbosity can be changed at runtime with the max level.
+    SimpleLogger::new().with_level(LevelFilter::Trace).init()?;
+    log::set_max_level(verbosity);
+    if let Some(log_privacy) = log_privacy {
+        droute::privacy::install(log_privacy);
+    }
+    for deprecation in &deprecations {
+        warn!("{}", deprecation);
+    }
+    let memory = droute::memory::usage();
+    if let Some(limit) = memory.caps.total.filter(|&l| memory.rules > l) {
+        warn!(
+            "rule lists take about {} bytes, over the memory limit of {} bytes, so nothing is cached",
+            memory.rules, limit
+        );
+    }
+
+    if let Some(endpoint) = otlp_endpoint {
+        #[cfg(feature = "otlp")]
+        telemetry::init(&endpoint)
+            .with_context(|| format!("failed to set up OTLP exporter to {}", endpoint))?;
+        #[cfg(not(feature = "otlp"))]
+        warn!(
+            "OTLP exporter to {} is not available in this build, traces are not exported",
+            endpoint
+        );
+    }
 
     info!("dcompass ready!");
 
-    let router = Arc::new(router);
-    // Bind an UDP socket
-    let socket = Arc::new(
-        UdpSocket::bind(addr)
-            .await
-            .with_context(|| format!("failed to bind to {}", addr))?,
-    );
+    let router = Arc::new(RouterHandle::new(router));
+    if offline {
+        router.set_offline(true);
+        warn!("offline mode on, queries are answered out of the cache alone");
+    }
+    // Reloads replace the main router alone, the virtual resolvers are kept as they are built here until restarted.
+    let resolvers: Vec<_> = resolvers
+        .into_iter()
+        .map(|(name, addrs, router)| {
+            let router = Arc::new(RouterHandle::new(router));
+            router.set_offline(offline);
+            info!("resolver `{}` listening on {:?}", name, addrs);
+            (name, addrs, router)
+        })
+        .collect();
+    let limits = Arc::new(limits);
+    let stats = Arc::new(Stats {
+        history: history.as_ref().map(History::new),
+        top_k: top_k.as_ref().map(TopK::new),
+        slos: (!slos.is_empty()).then_some(slos),
+        ..Default::default()
+    });
+
+    #[cfg(unix)]
+    {
+        let (router, stats, profile) = (router.clone(), stats.clone(), args.profile.clone());
+        tokio::spawn(async move {
+            if let Err(e) = signals::handle(router, stats, source, profile).await {
+                warn!("failed to install signal handlers: {}", e);
+            }
+        });
+    }
+
+    // SLO violations are logged even without hooks.
+    if hooks.enabled() || stats.slos.is_some() {
+        tokio::spawn(hooks::monitor(hooks.clone(), router.clone(), stats.clone()));
+    }
+
+    if let Some(config) = root_mirror_config {
+        tokio::spawn(root_mirror::maintain(config));
+    }
+
+    if let Some(config) = connectivity_config {
+        tokio::spawn(connectivity::probe(config, router.clone()));
+    }
+
+    if network_watch.enabled {
+        tokio::spawn(netwatch::watch(network_watch, router.clone()));
+    }
+
+    let report = Arc::new(RwLock::new(ranking::Report::default()));
+    if let Some(config) = ranking_config {
+        tokio::spawn(ranking::rank(config, router.clone(), report.clone()));
+    }
+
+    if let Some(config) = control_config {
+        let (router, stats, report) = (router.clone(), stats.clone(), report.clone());
+        let virtuals = resolvers.iter().map(|(_, _, r)| r.clone()).collect();
+        tokio::spawn(async move {
+            if let Err(e) = control::serve(config, router, virtuals, stats, report).await {
+                warn!("failed to serve the control endpoint: {:#}", e);
+            }
+        });
+    }
+
+    // The stream listeners stop accepting once signaled on shutdown, as the UDP ones do once their loops are dropped.
+    let (stop, stopped) = watch::channel(false);
+
+    if let Some(config) = doh_config {
+        let bound = doh::bind(&config).await?;
+        tokio::spawn(doh::serve(
+            config,
+            bound,
+            router.clone(),
+            limits.clone(),
+            stats.clone(),
+            stopped.clone(),
+        ));
+    }
+
+    if let Some(config) = grpc_config {
+        #[cfg(feature = "grpc")]
+        {
+            let (router, limits, stats, stopped) = (
+                router.clone(),
+                limits.clone(),
+                stats.clone(),
+                stopped.clone(),
+            );
+            tokio::spawn(async move {
+                if let Err(e) = grpc::serve(config, router, limits, stats, stopped).await {
+                    warn!("failed to serve the gRPC API: {:#}", e);
+                }
+            });
+        }
+        #[cfg(not(feature = "grpc"))]
+        warn!(
+            "gRPC API on {} is not available in this build, it is not served",
+            config.listen
+        );
+    }
+
+    // Building routers is not `Send`, so tasks doing so are run alongside the serving loops instead of being spawned.
+    let background = {
+        #[cfg(feature = "cluster")]
+        let follow = {
+            let (router, stats, hooks) = (router.clone(), stats.clone(), hooks.clone());
+            async move {
+                if let Some(cluster) = cluster {
+                    info!("following the cluster leader at {}", cluster.leader);
+                    if let Err(e) = cluster::follow(cluster, router, stats, hooks).await {
+                        warn!("failed to follow the cluster leader: {:#}", e);
+                    }
+                }
+            }
+        };
+        #[cfg(not(feature = "cluster"))]
+        let follow = async {};
+        let detect = {
+            let router = router.clone();
+            async move {
+                if let Some(captive_portal) = captive_portal {
+                    if let Err(e) = portal::detect(captive_portal, router).await {
+                        warn!("failed to detect captive portals: {:#}", e);
+                    }
+                }
+            }
+        };
+        async move {
+            tokio::join!(follow, detect);
+            std::future::pending::<()>().await
+        }
+    };
 
     // Create a shutdown broadcast channel
     let (tx, _) = broadcast::channel::<()>(10);
 
-    // We don't have to worry about incoming requests when shutting down, because when we initiate shutdown, the loop was already terminated
+    // TCP listeners are served alongside whichever UDP backend is in use.
+    if tcp_config.enabled {
+        let proxies = if tcp_config.proxy_protocol {
+            Some(Arc::new(TrustedProxies::new(&tcp_config.trusted_proxies)?))
+        } else {
+            None
+        };
+        let listening = std::iter::once((&addrs, &router))
+            .chain(resolvers.iter().map(|(_, addrs, router)| (addrs, router)));
+        for (addrs, router) in listening {
+            let v6only = runtime::v6only(addrs, ipv6_only);
+            for addr in addrs {
+                let listener = match runtime::tcp_listener(*addr, v6only) {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        warn!("failed to listen on {} over TCP: {}", addr, e);
+                        continue;
+                    }
+                };
+                info!("listening on {} over TCP", addr);
+                tokio::spawn(tcp::serve(
+                    listener,
+                    router.clone(),
+                    limits.clone(),
+                    stats.clone(),
+                    tx.clone(),
+                    stopped.clone(),
+                    Duration::from_secs(tcp_config.idle_timeout),
+                    proxies.clone(),
+                ));
+            }
+        }
+    }
+
+    let serving = async {
+        if backend == Backend::IoUring {
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            {
+                // Shards are bound as on tokio, each one served by a ring of its own.
+                let v6only = runtime::v6only(&addrs, ipv6_only);
+                let (shards, reuse_port) = if shards <= 1 {
+                    (1, false)
+                } else {
+                    (shards, true)
+                };
+                let sockets = addrs
+                    .iter()
+                    .flat_map(|addr| std::iter::repeat(addr).take(shards))
+                    .map(|addr| runtime::socket(*addr, v6only, reuse_port))
+                    .collect::<std::io::Result<Vec<_>>>()
+                    .with_context(|| format!("failed to bind to {:?}", addrs))?;
+                // Every ring is started before committing to the backend.
+                let mut rings = Vec::with_capacity(sockets.len());
+                let mut failed = None;
+                for socket in sockets {
+                    match uring::start(
+                        socket,
+                        router.clone(),
+                        limits.clone(),
+                        stats.clone(),
+                        tx.clone(),
+                    )
+                    .await
+                    {
+                        Ok(ring) => rings.push(ring),
+                        Err(e) => {
+                            failed = Some(e);
+                            break;
+                        }
+                    }
+                }
+                match failed {
+                    // The rings keep serving until dropped along with this future.
+                    None => {
+                        let _rings = rings;
+                        return std::future::pending().await;
+                    }
+                    Some(e) => {
+                        warn!(
+                            "failed to start io_uring backend: {}, falling back to tokio",
+                            e
+                        );
+                        // The sockets are bound again by tokio only once released by the rings started.
+                        join_all(rings.into_iter().map(uring::Ring::stop)).await;
+                    }
+                }
+            }
+            #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+            warn!("io_uring backend is not available in this build, falling back to tokio");
+        }
+
+        // Bind UDP sockets, one for each shard on each address
+        let sockets = runtime::bind(&addrs, ipv6_only, shards)
+            .with_context(|| format!("failed to bind to {:?}", addrs))?;
+        join_all(
+            sockets
+                .into_iter()
+                .map(|socket| serve(Arc::new(socket), router.clone(), &limits, &stats, &tx)),
+        )
+        .await;
+        Ok::<(), anyhow::Error>(())
+    };
+
+    // Virtual resolvers are served over tokio whichever the backend is.
+    let virtual_serving =
+        async {
+            let (limits, stats, tx) = (&limits, &stats, &tx);
+            let mut sockets = Vec::new();
+            for (name, addrs, router) in &resolvers {
+                sockets.extend(
+                    runtime::bind(addrs, ipv6_only, shards)
+                        .with_context(|| {
+                            format!("failed to bind to {:?} for the resolver `{}`", addrs, name)
+                        })?
+                        .into_iter()
+                        .map(|socket| (socket, router)),
+                );
+            }
+            join_all(sockets.into_iter().map(|(socket, router)| {
+                serve(Arc::new(socket), router.clone(), limits, stats, tx)
+            }))
+            .await;
+            Ok::<(), anyhow::Error>(())
+        };
+
+    // Once Ctrl-C is received, the receiving loops are dropped and the stream listeners are stopped, so no more queries are accepted.
     #[rustfmt::skip]
     tokio::select! {
-        _ = serve(socket, router, &tx) => (),
+        res = async { tokio::try_join!(serving, virtual_serving).map(|_| ()) } => return res,
+        _ = background => unreachable!(),
         _ = signal::ctrl_c() => {
-            log::warn!("Ctrl-C received, shutting down");
-	    sleep(Duration::from_millis(500)).await;
-            // Error implies that there is no receiver/active worker, we are done
-            if tx.send(()).is_ok() {
-                while tx.receiver_count() != 0 {
-                    log::warn!("waiting 5 seconds for workers to exit...");
-                    sleep(Duration::from_secs(5)).await
-                }
-            }
-            log::warn!("gracefully shut down!");
+            log::warn!("Ctrl-C received, draining in-flight queries");
         }
     };
+    // Error implies that every listener is gone already
+    let _ = stop.send(true);
+
+    // All the permits are back only after every in-flight query is handled.
+    match timeout(drain_timeout, limits.inflight.acquire_many(capacity)).await {
+        Ok(_) => log::warn!("all in-flight queries drained"),
+        Err(_) => {
+            log::warn!(
+                "drain timed out, aborting {} in-flight queries",
+                limits.capacity - limits.inflight.available_permits()
+            );
+            // Error implies that there is no receiver/active worker, we are done
+            let _ = tx.send(());
+        }
+    }
+    #[cfg(feature = "otlp")]
+    telemetry::shutdown();
+    log::warn!("gracefully shut down! {}", stats);
     Ok(())
 }
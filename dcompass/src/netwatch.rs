@@ -0,0 +1,85 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Detection of network changes, e.g. roaming between Wi-Fi networks, after which the connections to the upstreams are likely stale and would stall the first queries until they time out.
+//!
+//! Rather than platform-specific notifications like netlink on Linux or SCNetworkReachability on macOS, the local addresses the system routes outgoing traffic from are checked periodically, which works the same everywhere.
+
+use crate::{handle::RouterHandle, parser::NetworkWatchConfig};
+use log::*;
+use std::{
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::time::{interval, MissedTickBehavior};
+
+// Local address routed to the remote one, or `None` if it is unreachable, e.g. without a default route.
+fn local_addr(remote: SocketAddr) -> Option<IpAddr> {
+    let socket = UdpSocket::bind(if remote.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })
+    .ok()?;
+    socket.connect(remote).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+// Local addresses of IPv4 and IPv6 traffic, which change along with the network.
+// Routes to documentation addresses are looked up, which are never sent anything: connecting UDP sockets merely picks the routes.
+fn routes() -> (Option<IpAddr>, Option<IpAddr>) {
+    (
+        local_addr(SocketAddr::from(([192, 0, 2, 1], 9))),
+        local_addr(SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 9))),
+    )
+}
+
+/// Check the network every interval, and reconnect to the upstreams once it changed.
+/// Changes while offline are picked up once back online, as the upstreams are not to be connected in the meantime.
+pub async fn watch(config: NetworkWatchConfig, router: Arc<RouterHandle>) {
+    let mut ticks = interval(Duration::from_secs(config.interval.max(1)));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last = routes();
+    loop {
+        ticks.tick().await;
+        if router.offline() {
+            continue;
+        }
+        let current = routes();
+        if current == last {
+            continue;
+        }
+        info!(
+            "network changed, local addresses from {:?} to {:?}, reconnecting to the upstreams",
+            last, current
+        );
+        last = current;
+        router.reconnect().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::local_addr;
+
+    #[test]
+    fn loopback() {
+        assert_eq!(
+            local_addr("127.0.0.1:9".parse().unwrap()),
+            Some("127.0.0.1".parse().unwrap())
+        );
+    }
+}
@@ -13,10 +13,18 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use droute::builders::*;
+use crate::compat::Deprecation;
+use droute::{
+    builders::*,
+    memory::Caps,
+    privacy::LogPrivacy,
+    utils::{EdnsPolicy, Schedule},
+    AnswerOrder, AnyPolicy, ChaosPolicy, Label, Listener, RankingPolicy, SlowQueryLog,
+    SpecialUsePolicy,
+};
 use log::LevelFilter;
 use serde::Deserialize;
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::SocketAddr, num::NonZeroUsize, path::PathBuf};
 
 #[derive(Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -30,14 +38,855 @@ enum LevelFilterDef {
     Trace,
 }
 
+/// What to do with incoming queries when too many queries are in flight.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    /// Silently drop the query
+    Drop,
+    /// Answer with SERVFAIL
+    ServFail,
+    /// Answer with REFUSED
+    Refused,
+}
+
+impl Default for OverflowPolicy {
+    fn default() -> Self {
+        Self::Drop
+    }
+}
+
+/// Whether clients not listed in the ACL are allowed.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AclPolicy {
+    Allow,
+    Deny,
+}
+
+impl Default for AclPolicy {
+    fn default() -> Self {
+        Self::Allow
+    }
+}
+
+/// What to do with queries from clients denied by the ACL.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum AclAction {
+    /// Answer with REFUSED
+    Refuse,
+    /// Silently drop the query
+    Drop,
+}
+
+impl Default for AclAction {
+    fn default() -> Self {
+        Self::Refuse
+    }
+}
+
+/// Access control on the clients, applied before any routing.
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AclConfig {
+    /// CIDRs of the clients allowed
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// CIDRs of the clients denied, which take precedence over `allow`
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Policy on clients matching neither of the lists
+    #[serde(default)]
+    pub default: AclPolicy,
+    #[serde(default)]
+    pub action: AclAction,
+}
+
+/// Configuration of the Response Rate Limiting
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RrlConfig {
+    /// Identical responses allowed per second per client network, 0 to disable
+    pub responses_per_second: u32,
+    /// Same as above but for NXDOMAIN responses, default to `responses_per_second`
+    #[serde(default)]
+    pub nxdomains_per_second: Option<u32>,
+    /// Same as above but for other errors regardless of names queried, default to `responses_per_second`
+    #[serde(default)]
+    pub errors_per_second: Option<u32>,
+    /// Seconds over which the rate is averaged
+    #[serde(default = "default_rrl_window")]
+    pub window: u32,
+    /// Send one in every `slip` limited responses truncated instead of dropping it, 0 to always drop
+    #[serde(default = "default_rrl_slip")]
+    pub slip: u64,
+    #[serde(default = "default_ipv4_prefix_length")]
+    pub ipv4_prefix_length: u8,
+    #[serde(default = "default_ipv6_prefix_length")]
+    pub ipv6_prefix_length: u8,
+    /// Maximum number of accounts tracked before the idle ones are purged
+    #[serde(default = "default_rrl_table_size")]
+    pub max_table_size: usize,
+}
+
+const fn default_rrl_window() -> u32 {
+    15
+}
+
+const fn default_rrl_slip() -> u64 {
+    2
+}
+
+const fn default_ipv4_prefix_length() -> u8 {
+    24
+}
+
+const fn default_ipv6_prefix_length() -> u8 {
+    56
+}
+
+const fn default_rrl_table_size() -> usize {
+    20000
+}
+
+/// Configuration of the TCP listener
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TcpConfig {
+    /// Whether to serve queries over TCP on the same addresses as UDP
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Seconds to wait for the next query before closing the connection
+    #[serde(default = "default_tcp_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Whether connections start with the PROXY protocol header telling the client, as sent by reverse proxies in front
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// CIDRs of the reverse proxies whose PROXY protocol headers are trusted, required with `proxy_protocol`
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            idle_timeout: default_tcp_idle_timeout(),
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+const fn default_true() -> bool {
+    true
+}
+
+const fn default_tcp_idle_timeout() -> u64 {
+    10
+}
+
+/// Configuration of the network change detection
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkWatchConfig {
+    /// Whether to reconnect to the upstreams once the network changes
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Seconds between checks of the network
+    #[serde(default = "default_network_watch_interval")]
+    pub interval: u64,
+}
+
+impl Default for NetworkWatchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: default_network_watch_interval(),
+        }
+    }
+}
+
+const fn default_network_watch_interval() -> u64 {
+    2
+}
+
+/// A list of DNS stamps to pick upstreams from
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StampListConfig {
+    /// URL or local path of the list
+    pub source: String,
+    /// Resolvers in the list to use, which are tagged with their names
+    pub names: Vec<String>,
+    /// Public key lists fetched are verified against, either hex-encoded ed25519 or minisign, by default the one the lists of dnscrypt-proxy are signed with
+    #[serde(default = "default_stamp_list_key")]
+    pub public_key: String,
+}
+
+fn default_stamp_list_key() -> String {
+    "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3".to_string()
+}
+
+/// Configuration of a follower in the cluster, which pulls its configuration from the leader.
+#[derive(Deserialize, Clone)]
+#[cfg_attr(not(feature = "cluster"), allow(dead_code))]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Base URL of the leader, or of any HTTP endpoint serving the files alongside their signatures
+    pub leader: String,
+    /// Public key the files are signed with, either hex-encoded ed25519 or minisign
+    pub public_key: String,
+    /// Name of this instance reported to the leader
+    pub node: String,
+    /// Directory the pulled rule lists are stored in
+    pub dir: PathBuf,
+    /// Names of the rule lists to pull besides the configuration
+    #[serde(default)]
+    pub files: Vec<String>,
+    /// Rule lists pulled from their own sources, e.g. the list providers, by the names they are stored under
+    #[serde(default)]
+    pub lists: HashMap<String, ListSource>,
+    /// Seconds between pulls
+    #[serde(default = "default_cluster_interval")]
+    pub interval: u64,
+    /// Whether to push the statistics to the leader after each pull
+    #[serde(default = "default_true")]
+    pub push_stats: bool,
+    /// Bearer token presented to the leader along with the statistics
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// Source of a rule list pulled along with the files from the leader.
+#[derive(Deserialize, Clone)]
+#[cfg_attr(not(feature = "cluster"), allow(dead_code))]
+#[serde(deny_unknown_fields)]
+pub struct ListSource {
+    /// URL of the list, with its signature alongside
+    pub url: String,
+    /// Public key the list is signed with, either hex-encoded ed25519 or minisign
+    pub public_key: String,
+}
+
+const fn default_cluster_interval() -> u64 {
+    60
+}
+
+/// Configuration of the hooks notified of significant events.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HooksConfig {
+    /// URLs the events are posted to as JSON
+    #[serde(default)]
+    pub webhooks: Vec<String>,
+    /// Executables run on events, with the event name in `DCOMPASS_EVENT` and the event as JSON in `DCOMPASS_PAYLOAD`
+    #[serde(default)]
+    pub scripts: Vec<PathBuf>,
+    /// Seconds over which the ratios below are measured
+    #[serde(default = "default_hooks_interval")]
+    pub interval: u64,
+    /// Share of the queries to an upstream failed for it to be marked down
+    #[serde(default = "default_upstream_down_ratio")]
+    pub upstream_down_ratio: f64,
+    /// Share of the queries answered with SERVFAIL to alert on
+    #[serde(default = "default_servfail_ratio")]
+    pub servfail_ratio: f64,
+    /// Minimum number of queries within the interval for the ratios to be considered
+    #[serde(default = "default_hooks_min_queries")]
+    pub min_queries: u64,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            webhooks: Vec::new(),
+            scripts: Vec::new(),
+            interval: default_hooks_interval(),
+            upstream_down_ratio: default_upstream_down_ratio(),
+            servfail_ratio: default_servfail_ratio(),
+            min_queries: default_hooks_min_queries(),
+        }
+    }
+}
+
+const fn default_hooks_interval() -> u64 {
+    30
+}
+
+const fn default_upstream_down_ratio() -> f64 {
+    0.5
+}
+
+const fn default_servfail_ratio() -> f64 {
+    0.1
+}
+
+const fn default_hooks_min_queries() -> u64 {
+    20
+}
+
+/// A latency SLO on a group of domains
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SloConfig {
+    /// Name of the SLO in the alerts
+    pub name: String,
+    /// Domains covered, along with their subdomains
+    pub domains: Vec<String>,
+    /// Milliseconds within which the queries should be answered
+    pub latency: u64,
+    /// Percentile of the queries which should be answered within `latency`
+    #[serde(default = "default_slo_percentile")]
+    pub percentile: f64,
+    /// Minimum number of queries within the interval for the SLO to be evaluated
+    #[serde(default = "default_hooks_min_queries")]
+    pub min_queries: u64,
+}
+
+const fn default_slo_percentile() -> f64 {
+    99.0
+}
+
+/// Configuration of the captive portal detection
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CaptivePortalConfig {
+    /// Resolver provided by the network, e.g. via DHCP. Taken from `/etc/resolv.conf` on every probe if not given.
+    #[serde(default)]
+    pub resolver: Option<SocketAddr>,
+    /// Domain probed, whose addresses should be the same wherever it is resolved
+    #[serde(default = "default_portal_probe")]
+    pub probe: String,
+    /// Seconds between probes
+    #[serde(default = "default_portal_interval")]
+    pub interval: u64,
+}
+
+fn default_portal_probe() -> String {
+    "dns.google".to_string()
+}
+
+const fn default_portal_interval() -> u64 {
+    30
+}
+
+/// Where the root zone is fetched from
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RootZoneSource {
+    /// URL of the zone file
+    Https(String),
+    /// Server allowing zone transfers (AXFR) of the root zone
+    Axfr(SocketAddr),
+}
+
+impl std::fmt::Display for RootZoneSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Https(url) => write!(f, "{}", url),
+            Self::Axfr(addr) => write!(f, "AXFR from {}", addr),
+        }
+    }
+}
+
+fn default_fast_path_size() -> NonZeroUsize {
+    NonZeroUsize::new(4096).unwrap()
+}
+
+/// Configuration of the fast path answering the queries answered before without going through the script
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FastPathConfig {
+    /// Maximum number of responses kept
+    #[serde(default = "default_fast_path_size")]
+    pub size: NonZeroUsize,
+    /// Domains whose queries always go through the script, e.g. the ones answered per client
+    #[serde(default)]
+    pub always_evaluate: Vec<String>,
+}
+
+/// Configuration of the local copy of the root zone
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RootMirrorConfig {
+    /// Sources tried in order on every refresh
+    #[serde(default = "default_root_zone_sources")]
+    pub sources: Vec<RootZoneSource>,
+    /// Seconds between refreshes
+    #[serde(default = "default_root_mirror_refresh")]
+    pub refresh: u64,
+}
+
+fn default_root_zone_sources() -> Vec<RootZoneSource> {
+    vec![
+        RootZoneSource::Https("https://www.internic.net/domain/root.zone".to_string()),
+        // lax.xfr.dns.icann.org and iad.xfr.dns.icann.org, as listed in RFC 8806
+        RootZoneSource::Axfr(([192, 0, 32, 132], 53).into()),
+        RootZoneSource::Axfr(([192, 0, 47, 132], 53).into()),
+    ]
+}
+
+const fn default_root_mirror_refresh() -> u64 {
+    43200
+}
+
+/// Configuration of the upstream ranking
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RankingConfig {
+    /// Seconds between rounds of probes
+    #[serde(default = "default_ranking_interval")]
+    pub interval: u64,
+    /// Hybrid upstreams whose members are pruned per the ranking. Others are only ranked.
+    #[serde(default)]
+    pub prune: Vec<Label>,
+    #[serde(default)]
+    pub policy: RankingPolicy,
+}
+
+const fn default_ranking_interval() -> u64 {
+    300
+}
+
+/// Configuration of the IPv4 and IPv6 connectivity probing
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectivityConfig {
+    /// Seconds between probes
+    #[serde(default = "default_connectivity_interval")]
+    pub interval: u64,
+    /// Addresses connected to over TCP, a family being available if any of its addresses is connected
+    #[serde(default = "default_connectivity_targets")]
+    pub targets: Vec<SocketAddr>,
+}
+
+const fn default_connectivity_interval() -> u64 {
+    60
+}
+
+fn default_connectivity_targets() -> Vec<SocketAddr> {
+    [
+        "1.1.1.1:53",
+        "8.8.8.8:53",
+        "[2606:4700:4700::1111]:53",
+        "[2001:4860:4860::8888]:53",
+    ]
+    .iter()
+    .map(|addr| addr.parse().unwrap())
+    .collect()
+}
+
+/// Configuration of the query history
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Maximum number of queries kept, the oldest are dropped first
+    #[serde(default = "default_history_max_entries")]
+    pub max_entries: usize,
+    /// Seconds queries are kept for, regardless of the number of them
+    #[serde(default)]
+    pub retention: Option<u64>,
+}
+
+const fn default_history_max_entries() -> usize {
+    10000
+}
+
+/// Caps on the memory used, in MiB, see `droute::memory`
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryConfig {
+    /// Cap on the cache, the rule lists, and the history altogether, cache entries are evicted first
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Cap on the cache alone
+    #[serde(default)]
+    pub cache: Option<usize>,
+    /// Cap on the history alone
+    #[serde(default)]
+    pub history: Option<usize>,
+}
+
+impl From<&MemoryConfig> for Caps {
+    fn from(config: &MemoryConfig) -> Self {
+        let bytes = |mib: Option<usize>| mib.map(|m| m.saturating_mul(1024 * 1024));
+        Self {
+            total: bytes(config.limit),
+            cache: bytes(config.cache),
+            history: bytes(config.history),
+        }
+    }
+}
+
+/// Configuration of the top lists
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TopKConfig {
+    /// Number of keys counted in each list, which bounds the memory used
+    #[serde(default = "default_top_k_capacity")]
+    pub capacity: usize,
+}
+
+const fn default_top_k_capacity() -> usize {
+    1000
+}
+
+const fn default_group_enabled() -> bool {
+    true
+}
+
+/// Configuration of a group of rules, which can be toggled on the control endpoint
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GroupConfig {
+    /// Whether the rules in the group are applied unless toggled
+    #[serde(default = "default_group_enabled")]
+    pub enabled: bool,
+    /// Cron expression of the minutes within which the group is on, in local time
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+}
+
+/// Configuration of the control endpoint
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ControlConfig {
+    /// Address to serve the control endpoint on, which should not be exposed publicly
+    pub listen: SocketAddr,
+    /// Token required as a bearer on every request but the health and readiness probes
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// Configuration of the DNS over HTTPS listener
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DohConfig {
+    /// Address to serve on in plain HTTP, with TLS terminated by a reverse proxy
+    pub listen: SocketAddr,
+    /// Path queries are served on
+    #[serde(default = "default_doh_path")]
+    pub path: String,
+    /// Whether connections start with the PROXY protocol header telling the client, as sent by reverse proxies in front
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// CIDRs of the reverse proxies whose PROXY protocol headers are trusted, required with `proxy_protocol`
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+}
+
+fn default_doh_path() -> String {
+    "/dns-query".to_string()
+}
+
+/// Configuration of the gRPC API
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// Address to serve the gRPC API on
+    pub listen: SocketAddr,
+    /// CIDRs of the peers trusted to make queries on behalf of the `client` they give, which is ignored for anyone else
+    #[serde(default)]
+    pub trusted_peers: Vec<String>,
+}
+
+/// The I/O backend used to serve UDP queries.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// Portable backend based on tokio
+    Tokio,
+    /// `io_uring` backend, Linux only. Falls back to tokio if unavailable.
+    IoUring,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Self::Tokio
+    }
+}
+
+/// Address(es) to listen on, either a single one or a list of them.
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Listen {
+    /// A single address
+    One(SocketAddr),
+    /// Multiple addresses, e.g. an IPv4 one and an IPv6 one, all sharing the same router
+    Many(Vec<SocketAddr>),
+}
+
+impl Listen {
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        match self {
+            Self::One(addr) => vec![*addr],
+            Self::Many(addrs) => addrs.clone(),
+        }
+    }
+}
+
+/// Virtual resolver served on its own addresses, with a script, upstreams and a cache of its own, and the router-level policies of the main one.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResolverConfig {
+    /// Address(es) to listen on, which are not to overlap with the ones of the main one or the other resolvers
+    pub address: Listen,
+    /// Script routing its queries
+    pub script: RuneScriptBuilder,
+    /// Its upstreams, along with its cache
+    #[serde(flatten)]
+    pub upstreams: UpstreamsBuilder<UpstreamBuilder>,
+}
+
+/// Configuration of the runtime serving the queries.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeConfig {
+    /// Number of worker threads, default to the number of cores
+    #[serde(default)]
+    pub worker_threads: Option<usize>,
+    /// Maximum number of threads for blocking operations
+    #[serde(default)]
+    pub max_blocking_threads: Option<usize>,
+    /// Name of the threads spawned
+    #[serde(default = "default_thread_name")]
+    pub thread_name: String,
+    /// Pin threads to CPU cores
+    #[serde(default)]
+    pub pin_threads: bool,
+    /// Number of sockets bound to the address with `SO_REUSEPORT`, each served by its own receiving loop
+    #[serde(default = "default_shards")]
+    pub shards: usize,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            worker_threads: None,
+            max_blocking_threads: None,
+            thread_name: default_thread_name(),
+            pin_threads: false,
+            shards: default_shards(),
+        }
+    }
+}
+
+fn default_thread_name() -> String {
+    "dcompass-worker".to_string()
+}
+
+const fn default_shards() -> usize {
+    1
+}
+
+const fn default_drain_timeout() -> u64 {
+    5
+}
+
+const fn default_max_response_size() -> u16 {
+    1232
+}
+
+const fn default_max_inflight() -> usize {
+    4096
+}
+
+/// Fields at the top level of the configuration, which have to be kept along with `Parsed` as unknown fields are rejected by `compat::check_fields` rather than serde.
+pub const FIELDS: &[&str] = &[
+    "version",
+    "script",
+    // Settings of `UpstreamsBuilder`
+    "upstreams",
+    "cache_size",
+    "retry",
+    "svcb_cache_size",
+    "servfail_ttl",
+    "aggressive_nxdomain",
+    "redis",
+    "capture",
+    "upstream_nsid",
+    "hedge",
+    "affinity",
+    "address",
+    "ipv6_only",
+    "verbosity",
+    "any_query",
+    "edns_options",
+    "offline",
+    "answer_order",
+    "chaos",
+    "special_use",
+    "zones",
+    "groups",
+    "root_mirror",
+    "fast_path",
+    "max_inflight",
+    "overflow",
+    "acl",
+    "rrl",
+    "stamp_lists",
+    "max_response_size",
+    "tcp",
+    "backend",
+    "runtime",
+    "slow_query",
+    "deadline",
+    "nsid",
+    "log_privacy",
+    "otlp_endpoint",
+    "drain_timeout",
+    "cluster",
+    "hooks",
+    "slos",
+    "captive_portal",
+    "network_watch",
+    "connectivity",
+    "ranking",
+    "control",
+    "doh",
+    "grpc",
+    "history",
+    "top_k",
+    "memory",
+    "case_insensitive_cache",
+    "resolvers",
+    "entries",
+];
+
+// Fields added here have to be listed in `FIELDS` as well.
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Parsed {
+    // Version of the schema, see `compat`
+    #[serde(default)]
+    pub version: Option<u64>,
+    // Deprecated options found, which are logged once the logger is up
+    #[serde(skip)]
+    pub deprecations: Vec<Deprecation>,
     pub script: RuneScriptBuilder,
     // We are not using UpstreamsBuilder because flatten ruins error location.
     #[serde(flatten)]
     pub upstreams: UpstreamsBuilder<UpstreamBuilder>,
-    pub address: SocketAddr,
+    pub address: Listen,
+    // Whether IPv6 sockets refuse IPv4-mapped traffic, see `runtime::v6only` for the default
+    #[serde(default)]
+    pub ipv6_only: Option<bool>,
     #[serde(with = "LevelFilterDef")]
     pub verbosity: LevelFilter,
+    #[serde(default)]
+    pub any_query: AnyPolicy,
+    // EDNS options supplied by the clients and whether they are forwarded
+    #[serde(default)]
+    pub edns_options: EdnsPolicy,
+    // Answer out of the cache alone from the start, see `RouterHandle::set_offline`
+    #[serde(default)]
+    pub offline: bool,
+    // Order of the address records in the answers
+    #[serde(default)]
+    pub answer_order: AnswerOrder,
+    // Answers to the queries in the CHAOS class, e.g. `version.bind`
+    #[serde(default)]
+    pub chaos: ChaosPolicy,
+    // Overrides on the built-in special-use domain policies
+    #[serde(default)]
+    pub special_use: HashMap<String, SpecialUsePolicy>,
+    // Zones and the upstreams their queries are forwarded to
+    #[serde(default)]
+    pub zones: HashMap<String, Label>,
+    // Groups of rules in the script and whether they are applied
+    #[serde(default)]
+    pub groups: HashMap<String, GroupConfig>,
+    // Local copy of the root zone answering the queries it is authoritative for (RFC 8806)
+    #[serde(default)]
+    pub root_mirror: Option<RootMirrorConfig>,
+    // Responses kept to answer the same queries again without going through the script
+    #[serde(default)]
+    pub fast_path: Option<FastPathConfig>,
+    // Maximum number of queries handled concurrently
+    #[serde(default = "default_max_inflight")]
+    pub max_inflight: usize,
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+    #[serde(default)]
+    pub acl: AclConfig,
+    #[serde(default)]
+    pub rrl: Option<RrlConfig>,
+    // Upstreams picked by name from lists of DNS stamps
+    #[serde(default)]
+    pub stamp_lists: Vec<StampListConfig>,
+    // Maximum size of UDP responses, larger ones are truncated
+    #[serde(default = "default_max_response_size")]
+    pub max_response_size: u16,
+    #[serde(default)]
+    pub tcp: TcpConfig,
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+    #[serde(default)]
+    pub slow_query: Option<SlowQueryLog>,
+    // Milliseconds queries are answered within, or SERVFAIL past that
+    #[serde(default)]
+    pub deadline: Option<u64>,
+    // NSID answered to the clients asking for it (RFC 5001)
+    #[serde(default)]
+    pub nsid: Option<String>,
+    // Hash or truncate the query names and the client addresses in the logs and the traces
+    #[serde(default)]
+    pub log_privacy: Option<LogPrivacy>,
+    // OTLP collector endpoint to export traces to
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    // Seconds to wait for in-flight queries on shutdown
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+    // Follow a cluster leader, whose configuration replaces the routing part of this one
+    #[serde(default)]
+    pub cluster: Option<ClusterConfig>,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    // Latency SLOs evaluated every interval of the hooks
+    #[serde(default)]
+    pub slos: Vec<SloConfig>,
+    // Send queries to the resolver of the network while behind a captive portal
+    #[serde(default)]
+    pub captive_portal: Option<CaptivePortalConfig>,
+    // Reconnect to the upstreams once the network changes
+    #[serde(default)]
+    pub network_watch: NetworkWatchConfig,
+    // Skip the upstreams of the address families the host has no connectivity over
+    #[serde(default)]
+    pub connectivity: Option<ConnectivityConfig>,
+    // Probe the upstreams and rank the members of hybrid upstreams
+    #[serde(default)]
+    pub ranking: Option<RankingConfig>,
+    // HTTP endpoint serving the statistics, the ranking, and the query history
+    #[serde(default)]
+    pub control: Option<ControlConfig>,
+    // DNS over HTTPS listener, including the JSON API
+    #[serde(default)]
+    pub doh: Option<DohConfig>,
+    // gRPC API resolving queries on behalf of the clients given
+    #[serde(default)]
+    pub grpc: Option<GrpcConfig>,
+    // Keep the queries answered recently
+    #[serde(default)]
+    pub history: Option<HistoryConfig>,
+    // Track the top queried domains, top blocked domains, and top clients
+    #[serde(default)]
+    pub top_k: Option<TopKConfig>,
+    // Caps on the memory used by the cache, the rule lists, and the history
+    #[serde(default)]
+    pub memory: MemoryConfig,
+    // Whether names differing only in case share the cache entries
+    #[serde(default = "default_true")]
+    pub case_insensitive_cache: bool,
+    // Virtual resolvers served in the same process, independently of the main one
+    #[serde(default)]
+    pub resolvers: HashMap<String, ResolverConfig>,
+    // Functions of the script the queries from the listeners start at instead of `route`
+    #[serde(default)]
+    pub entries: HashMap<Listener, String>,
 }
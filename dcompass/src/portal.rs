@@ -0,0 +1,199 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Detection of captive portals, behind which queries are sent to the resolver provided by the network until the portal is cleared.
+
+use crate::{handle::RouterHandle, parser::CaptivePortalConfig};
+use anyhow::{Context, Result};
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+    rdata::A,
+};
+use droute::{
+    builders::{
+        RouterBuilder, RuneScript, RuneScriptBuilder, UdpBuilder, UpstreamBuilder, UpstreamsBuilder,
+    },
+    AsyncTryInto, Router,
+};
+use log::*;
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    time::{interval, timeout, MissedTickBehavior},
+};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Script of the router behind the portal, which sends everything to the resolver of the network.
+const SCRIPT: &str = r#"pub async fn route(upstreams, inited, ctx, query) {
+    upstreams.send_default("network", query).await
+}"#;
+
+/// Whether we are behind a captive portal, given the addresses of the probe domain resolved by the upstreams configured and by the resolver of the network.
+/// Portals hijack the queries to the resolver of the network to answer with their own addresses, and block everything else until they are cleared.
+/// `None` if it cannot be told, e.g. when neither of them answered.
+pub fn behind_portal(
+    upstreams: Option<&HashSet<Ipv4Addr>>,
+    network: Option<&HashSet<Ipv4Addr>>,
+) -> Option<bool> {
+    match (upstreams, network) {
+        (Some(upstreams), Some(network)) => Some(upstreams.is_disjoint(network)),
+        (None, Some(_)) => Some(true),
+        (Some(_), None) => Some(false),
+        (None, None) => None,
+    }
+}
+
+// The first non-loopback name server in `/etc/resolv.conf`, as loopback ones are likely ourselves.
+fn system_resolver() -> Option<SocketAddr> {
+    std::fs::read_to_string("/etc/resolv.conf")
+        .ok()?
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("nameserver"))
+        .filter_map(|addr| addr.trim().parse::<IpAddr>().ok())
+        .find(|ip| !ip.is_loopback())
+        .map(|ip| SocketAddr::new(ip, 53))
+}
+
+fn probe(domain: &str) -> Result<Message<Bytes>> {
+    let name = Dname::<Bytes>::from_str(domain)?;
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(512))?;
+    builder.header_mut().set_id(rand::random());
+    builder.header_mut().set_rd(true);
+    let mut builder = builder.question();
+    builder.push((&name, Rtype::A))?;
+    Ok(builder.into_message())
+}
+
+// Addresses answered, or `None` if the query failed.
+fn addrs(resp: &Message<Bytes>) -> Option<HashSet<Ipv4Addr>> {
+    if resp.header().rcode() != Rcode::NoError {
+        return None;
+    }
+    let addrs: HashSet<_> = resp
+        .answer()
+        .ok()?
+        .limit_to::<A>()
+        .filter_map(|r| r.ok())
+        .map(|r| r.data().addr())
+        .collect();
+    (!addrs.is_empty()).then_some(addrs)
+}
+
+// Send the query to the resolver of the network in plain UDP.
+async fn ask(resolver: SocketAddr, query: &Message<Bytes>) -> Option<Message<Bytes>> {
+    let local: SocketAddr = if resolver.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0; 16], 0).into()
+    };
+    let socket = UdpSocket::bind(local).await.ok()?;
+    socket.send_to(query.as_slice(), resolver).await.ok()?;
+    let mut buf = vec![0; 1232];
+    timeout(PROBE_TIMEOUT, async {
+        loop {
+            let (len, src) = socket.recv_from(&mut buf).await.ok()?;
+            let resp = match Message::from_octets(Bytes::copy_from_slice(&buf[..len])) {
+                Ok(resp) if src == resolver && resp.header().id() == query.header().id() => resp,
+                _ => continue,
+            };
+            return Some(resp);
+        }
+    })
+    .await
+    .ok()?
+}
+
+async fn network_router(resolver: SocketAddr) -> Result<Router<RuneScript>> {
+    Ok(RouterBuilder::new(
+        RuneScriptBuilder::new(SCRIPT),
+        UpstreamsBuilder::new(1024)
+            .context("cache size of the network resolver must be positive")?
+            .add_upstream(
+                "network",
+                UpstreamBuilder::Udp(UdpBuilder {
+                    max_pool_size: 256,
+                    ..UdpBuilder::new(resolver)
+                }),
+            ),
+    )
+    .async_try_into()
+    .await?)
+}
+
+/// Probe for captive portals every interval, taking a detour to the resolver of the network while behind one.
+pub async fn detect(config: CaptivePortalConfig, router: Arc<RouterHandle>) -> Result<()> {
+    // Fail early on invalid probe domains.
+    probe(&config.probe)?;
+    let mut detour: Option<SocketAddr> = None;
+    let mut ticks = interval(Duration::from_secs(config.interval.max(1)));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        // Probing touches the network.
+        if router.offline() {
+            continue;
+        }
+        let resolver = match config.resolver.or_else(system_resolver) {
+            Some(resolver) => resolver,
+            None => {
+                debug!("no resolver of the network found to probe captive portals");
+                continue;
+            }
+        };
+
+        let query = probe(&config.probe)?;
+        let main = router.main();
+        let (upstreams, network) = tokio::join!(
+            timeout(PROBE_TIMEOUT, main.resolve(query.clone(), None)),
+            ask(resolver, &query)
+        );
+        let upstreams = upstreams.ok().and_then(|r| r.ok()).and_then(|r| addrs(&r));
+        let network = network.and_then(|r| addrs(&r));
+
+        match (behind_portal(upstreams.as_ref(), network.as_ref()), detour) {
+            (Some(true), None) => match network_router(resolver).await {
+                Ok(network) => {
+                    router.detour(Some(network));
+                    detour = Some(resolver);
+                    warn!(
+                        "captive portal detected, sending queries to {} until it is cleared",
+                        resolver
+                    );
+                }
+                Err(e) => warn!(
+                    "captive portal detected, but failed to switch to {}: {}",
+                    resolver, e
+                ),
+            },
+            (Some(false), Some(resolver)) => {
+                router.detour(None);
+                detour = None;
+                warn!(
+                    "captive portal cleared, queries are no longer sent to {}",
+                    resolver
+                );
+            }
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Named profiles within a single configuration file, e.g. for laptops moving between networks.
+//! Profiles are applied before the configuration is migrated to the latest schema, so they are written in the version of the file.
+
+use crate::{compat, parser::Parsed};
+use anyhow::{anyhow, bail, Result};
+use serde_yaml::Value;
+
+const PROFILES: &str = "profiles";
+
+/// Parse the configuration with the profile named applied onto the common part of it.
+/// Without any profile selected, only the common part is used.
+pub fn parse(config: &str, profile: Option<&str>) -> Result<Parsed> {
+    let mut value: Value = serde_yaml::from_str(config)?;
+    let profiles = value.as_mapping_mut().and_then(|m| m.remove(PROFILES));
+    let merged = match (profiles, profile) {
+        (None, None) => false,
+        (None, Some(name)) => bail!("profile `{}` selected, but no profiles are defined", name),
+        (Some(_), None) => true,
+        (Some(profiles), Some(name)) => {
+            let overlay = profiles.get(name).cloned().ok_or_else(|| {
+                let names: Vec<_> = profiles
+                    .as_mapping()
+                    .into_iter()
+                    .flat_map(|m| m.keys())
+                    .filter_map(Value::as_str)
+                    .collect();
+                anyhow!("no profile named `{}`, available: {:?}", name, names)
+            })?;
+            merge(&mut value, overlay);
+            true
+        }
+    };
+    let mut deprecations = Vec::new();
+    let changed = compat::migrate(&mut value, &mut deprecations)?;
+    compat::check_fields(&value)?;
+    let mut parsed: Parsed = if changed || merged {
+        serde_yaml::from_value(value)
+    } else {
+        // Parse from the text so that errors are located.
+        serde_yaml::from_str(config)
+    }
+    .map_err(compat::suggest)?;
+    parsed.deprecations = deprecations;
+    Ok(parsed)
+}
+
+// Mappings are merged key by key, so that profiles can add to or override e.g. the upstreams defined in common. Anything else is replaced.
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (k, v) in overlay {
+                match base.get_mut(&k) {
+                    Some(b) => merge(b, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! PROXY protocol (v1 and v2) on the stream listeners behind reverse proxies, e.g. HAProxy and NGINX, which tells the addresses of the clients connecting to them.
+
+use crate::acl::unmapped;
+use anyhow::{bail, Result};
+use droute::utils::IpCidr;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time::timeout,
+};
+
+// Signature of the binary header of v2
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// Maximum length of the text header of v1, including the CRLF
+const V1_MAX_LEN: usize = 107;
+// The header comes first thing on the connection, so the ones not sending it promptly are dropped.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reverse proxies whose headers are trusted to tell the clients
+pub struct TrustedProxies(IpCidr);
+
+impl TrustedProxies {
+    /// The proxies in the CIDRs given, which are required as anyone could claim to be any client otherwise.
+    pub fn new(cidrs: &[String]) -> Result<Self> {
+        if cidrs.is_empty() {
+            bail!(
+                "`proxy_protocol` requires `trusted_proxies` listing the addresses of the proxies"
+            );
+        }
+        let mut proxies = IpCidr::new();
+        for cidr in cidrs {
+            proxies.add_cidr(cidr)?;
+        }
+        Ok(Self(proxies))
+    }
+
+    /// Whether the peer is one of the proxies.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.contains(unmapped(ip))
+    }
+}
+
+/// Read the PROXY protocol header at the start of the connection from a trusted proxy, and return the address of the client it tells.
+/// The peer is the client if it is not a trusted proxy, in which case nothing is read and any header is left to fail as a query would,
+/// or if the proxy connects on its own behalf, e.g. for health checks.
+/// Connections from trusted proxies without a valid header are not to be served, as the header can't be told apart from the queries otherwise.
+pub async fn accept<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    peer: SocketAddr,
+    proxies: &TrustedProxies,
+) -> Result<SocketAddr> {
+    if !proxies.contains(peer.ip()) {
+        return Ok(peer);
+    }
+    Ok(read(stream).await?.unwrap_or(peer))
+}
+
+async fn read<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    timeout(HEADER_TIMEOUT, read_header(stream)).await?
+}
+
+async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    // Both "PROXY" and the signature of v2 are at least this long, so nothing past the header is read.
+    let mut start = [0; 5];
+    stream.read_exact(&mut start).await?;
+    if &start == b"PROXY" {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                bail!("PROXY protocol v1 header too long");
+            }
+            line.push(stream.read_u8().await?);
+        }
+        return parse_v1(&line[..line.len() - 2]);
+    }
+
+    let mut header = [0; 16];
+    header[..5].copy_from_slice(&start);
+    stream.read_exact(&mut header[5..]).await?;
+    if &header[..12] != SIGNATURE {
+        bail!("no PROXY protocol header");
+    }
+    let mut rest = vec![0; u16::from_be_bytes([header[14], header[15]]).into()];
+    stream.read_exact(&mut rest).await?;
+    parse_v2(header[12], header[13], &rest)
+}
+
+// e.g. `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443`, or `PROXY UNKNOWN` followed by anything.
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)?;
+    let fields: Vec<_> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _, port, _] => {
+            Ok(Some(SocketAddr::new(src.parse()?, port.parse()?)))
+        }
+        _ => bail!("malformed PROXY protocol v1 header: {}", line),
+    }
+}
+
+// The version and the command, the address family and the transport, and the addresses followed by TLVs, which are of no use here.
+fn parse_v2(ver_cmd: u8, family: u8, rest: &[u8]) -> Result<Option<SocketAddr>> {
+    if ver_cmd >> 4 != 2 {
+        bail!("unsupported PROXY protocol version {}", ver_cmd >> 4);
+    }
+    match ver_cmd & 0xf {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        cmd => bail!("unsupported PROXY protocol command {}", cmd),
+    }
+    // Only streams are proxied to the stream listeners.
+    match family & 0xf {
+        // UNSPEC and STREAM
+        0 | 1 => {}
+        transport => bail!("unsupported PROXY protocol transport {}", transport),
+    }
+    let (ip, port): (IpAddr, _) = match family >> 4 {
+        // AF_INET
+        1 if rest.len() >= 12 => (
+            Ipv4Addr::from(<[u8; 4]>::try_from(&rest[..4])?).into(),
+            &rest[8..10],
+        ),
+        // AF_INET6
+        2 if rest.len() >= 36 => (
+            Ipv6Addr::from(<[u8; 16]>::try_from(&rest[..16])?).into(),
+            &rest[32..34],
+        ),
+        // AF_UNSPEC, and AF_UNIX which has no address worth telling
+        0 | 3 => return Ok(None),
+        _ => bail!("malformed PROXY protocol v2 addresses"),
+    };
+    Ok(Some(SocketAddr::new(
+        ip,
+        u16::from_be_bytes([port[0], port[1]]),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept, read, TrustedProxies};
+
+    #[tokio::test]
+    async fn v1() {
+        let mut stream = &b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n\x00\x1d"[..];
+        assert_eq!(
+            read(&mut stream).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        // Nothing past the header is consumed.
+        assert_eq!(stream, b"\x00\x1d");
+
+        let mut stream = &b"PROXY UNKNOWN\r\n"[..];
+        assert_eq!(read(&mut stream).await.unwrap(), None);
+        let mut stream = &b"PROXY TCP4 192.0.2.1\r\n"[..];
+        assert!(read(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn trusted() {
+        assert!(TrustedProxies::new(&[]).is_err());
+        let proxies = TrustedProxies::new(&["10.0.0.0/8".to_string()]).unwrap();
+        let header = &b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n\x00\x1d"[..];
+
+        let mut stream = header;
+        assert_eq!(
+            accept(
+                &mut stream,
+                "[::ffff:10.0.0.1]:443".parse().unwrap(),
+                &proxies
+            )
+            .await
+            .unwrap(),
+            "192.0.2.1:56324".parse().unwrap()
+        );
+        // Headers from anyone else are not read, and so are not believed.
+        let mut stream = header;
+        assert_eq!(
+            accept(&mut stream, "192.0.2.9:443".parse().unwrap(), &proxies)
+                .await
+                .unwrap(),
+            "192.0.2.9:443".parse().unwrap()
+        );
+        assert_eq!(stream, header);
+    }
+
+    #[tokio::test]
+    async fn v2() {
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+        header.extend_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        header.extend_from_slice(
+            &"2001:db8::2"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        header.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb, 0x00, 0x1d]);
+        let mut stream = header.as_slice();
+        assert_eq!(
+            read(&mut stream).await.unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+        assert_eq!(stream, b"\x00\x1d");
+
+        // Health checks of the proxy itself
+        let mut stream = &b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00"[..];
+        assert_eq!(read(&mut stream).await.unwrap(), None);
+        // Datagrams are not proxied to the stream listeners.
+        let mut stream = &b"\r\n\r\n\0\r\nQUIT\n\x21\x12\x00\x0c\xc0\x00\x02\x01\xc0\x00\x02\x02\xdc\x04\x00\x35"[..];
+        assert!(read(&mut stream).await.is_err());
+        // Queries straight from the clients
+        let mut stream = &b"\x00\x1d\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07"[..];
+        assert!(read(&mut stream).await.is_err());
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Periodic probing of the upstreams, ranking the members of hybrid upstreams and pruning the bad ones out of them.
+
+use crate::{handle::RouterHandle, parser::RankingConfig};
+use droute::{Label, Measurement, Ranking};
+use futures::future::join_all;
+use log::*;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::time::{interval, MissedTickBehavior};
+
+/// Results of the latest round of probes
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    /// Seconds since UNIX epoch the round finished at
+    pub updated: u64,
+    /// Measurements of every upstream querying on its own
+    pub measurements: HashMap<Label, Measurement>,
+    /// Ranking of the members of every hybrid upstream
+    pub hybrids: HashMap<Label, Ranking>,
+}
+
+/// Probe the upstreams of the main router every interval, pruning the hybrid upstreams configured per the ranking.
+pub async fn rank(config: RankingConfig, router: Arc<RouterHandle>, report: Arc<RwLock<Report>>) {
+    let mut ticks = interval(Duration::from_secs(config.interval.max(1)));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        // Routers swapped in start with nothing pruned, and are pruned on the next round.
+        let main = router.main();
+        let upstreams = main.upstreams();
+
+        let tags = upstreams.tags();
+        let measurements: HashMap<Label, Measurement> =
+            join_all(tags.iter().map(|tag| upstreams.probe(tag, &config.policy)))
+                .await
+                .into_iter()
+                .zip(tags)
+                .filter_map(|(m, tag)| Some((tag, m?)))
+                .collect();
+
+        let hybrids: HashMap<Label, Ranking> = upstreams
+            .hybrids()
+            .into_iter()
+            .map(|(tag, members)| {
+                let ranking = config.policy.rank(&members, &measurements);
+                (tag, ranking)
+            })
+            .collect();
+
+        let last = report.read().unwrap().hybrids.clone();
+        for (tag, ranking) in &hybrids {
+            if !config.prune.contains(tag) {
+                continue;
+            }
+            if last.get(tag).map(|r| &r.pruned) != Some(&ranking.pruned) {
+                info!(
+                    "hybrid upstream `{}` ranked as {:?}, with {:?} pruned",
+                    tag, ranking.ranked, ranking.pruned
+                );
+            }
+            upstreams.prune(tag, ranking.pruned.iter().cloned());
+        }
+
+        *report.write().unwrap() = Report {
+            updated: crate::stats::now(),
+            measurements,
+            hybrids,
+        };
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Local copy of the root zone (RFC 8806), fetched from the sources configured and refreshed on schedule.
+
+use crate::parser::{RootMirrorConfig, RootZoneSource};
+use anyhow::{ensure, Result};
+use bytes::{Bytes, BytesMut};
+use domain::base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype};
+use droute::{RootMirror, RootZone};
+use log::*;
+use std::{net::SocketAddr, sync::OnceLock, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+
+// The root zone is about 2MB.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Copies larger than this are refused rather than held in memory.
+const MAX_ZONE_LEN: usize = 16 * 1024 * 1024;
+
+// Time to wait before trying again if every source failed
+const RETRY: Duration = Duration::from_secs(300);
+
+/// The mirror every router built answers from, so that routers swapped in use the copy fetched already.
+pub fn mirror() -> &'static RootMirror {
+    static MIRROR: OnceLock<RootMirror> = OnceLock::new();
+    MIRROR.get_or_init(RootMirror::new)
+}
+
+// Transfer the root zone over TCP, until the closing SOA record.
+async fn axfr(addr: SocketAddr) -> Result<Vec<Message<Bytes>>> {
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(64))?;
+    builder.header_mut().set_id(rand::random());
+    let mut builder = builder.question();
+    builder.push((Dname::root_bytes(), Rtype::Axfr))?;
+    let query = builder.finish();
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_u16(query.len() as u16).await?;
+    stream.write_all(&query).await?;
+
+    let (mut msgs, mut soas, mut len) = (Vec::new(), 0, 0);
+    while soas < 2 {
+        let mut buf = vec![0; stream.read_u16().await? as usize];
+        len += buf.len();
+        ensure!(
+            len <= MAX_ZONE_LEN,
+            "zone transfer larger than {} bytes",
+            MAX_ZONE_LEN
+        );
+        stream.read_exact(&mut buf).await?;
+        let msg = Message::from_octets(Bytes::from(buf))?;
+        let rcode = msg.header().rcode();
+        ensure!(
+            rcode == Rcode::NoError,
+            "zone transfer answered with {}",
+            rcode
+        );
+        for item in msg.answer()? {
+            if item?.rtype() == Rtype::Soa {
+                soas += 1;
+            }
+        }
+        msgs.push(msg);
+    }
+    Ok(msgs)
+}
+
+async fn fetch(source: &RootZoneSource) -> Result<RootZone> {
+    Ok(match source {
+        #[cfg(feature = "remote-config")]
+        RootZoneSource::Https(url) => {
+            let mut resp = reqwest::Client::builder()
+                .timeout(FETCH_TIMEOUT)
+                .build()?
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?;
+            ensure!(
+                resp.content_length().unwrap_or_default() <= MAX_ZONE_LEN as u64,
+                "zone larger than {} bytes",
+                MAX_ZONE_LEN
+            );
+            let mut body = Vec::new();
+            while let Some(chunk) = resp.chunk().await? {
+                body.extend_from_slice(&chunk);
+                ensure!(
+                    body.len() <= MAX_ZONE_LEN,
+                    "zone larger than {} bytes",
+                    MAX_ZONE_LEN
+                );
+            }
+            String::from_utf8(body)?.parse()?
+        }
+        #[cfg(not(feature = "remote-config"))]
+        RootZoneSource::Https(url) => anyhow::bail!(
+            "fetching the root zone from {} is not available in this build",
+            url
+        ),
+        RootZoneSource::Axfr(addr) => {
+            RootZone::from_axfr(&timeout(FETCH_TIMEOUT, axfr(*addr)).await??)?
+        }
+    })
+}
+
+/// Fetch the root zone from the first source that works every refresh interval. Copies not refreshed in time expire per the SOA record.
+pub async fn maintain(config: RootMirrorConfig) {
+    let mirror = mirror();
+    loop {
+        let mut refreshed = false;
+        for source in &config.sources {
+            match fetch(source).await {
+                Ok(zone) => {
+                    if mirror.serial() != Some(zone.serial()) {
+                        info!(
+                            "root zone mirror updated to serial {} with {} top-level domains from {}",
+                            zone.serial(),
+                            zone.tlds(),
+                            source
+                        );
+                    }
+                    mirror.update(zone);
+                    refreshed = true;
+                    break;
+                }
+                Err(e) => warn!("failed to fetch the root zone from {}: {:#}", source, e),
+            }
+        }
+        sleep(if refreshed {
+            Duration::from_secs(config.refresh.max(1))
+        } else {
+            RETRY
+        })
+        .await;
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! BIND-style Response Rate Limiting. Identical responses sent to the same network are accounted, and the ones beyond the rate are dropped or slipped (sent truncated), so that we are of no use in reflection attacks.
+
+use crate::parser::RrlConfig;
+use bytes::Bytes;
+use domain::base::{iana::Rcode, name::ToDname, Dname, Message};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+    time::Instant,
+};
+
+// Responses are accounted by the type of them, as in BIND.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Response,
+    NxDomain,
+    Error,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct Key {
+    prefix: IpAddr,
+    qname: Option<Dname<Bytes>>,
+    kind: Kind,
+}
+
+struct Account {
+    // Number of responses we can still send. Goes negative when limited.
+    credit: f64,
+    last: Instant,
+    // Number of responses limited, used to decide which of them to slip.
+    limited: u64,
+}
+
+/// What to do with a response
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Send,
+    Slip,
+    Drop,
+}
+
+/// Response rate limiter
+pub struct Rrl {
+    config: RrlConfig,
+    accounts: Mutex<HashMap<Key, Account>>,
+}
+
+impl Rrl {
+    pub fn new(config: RrlConfig) -> Self {
+        Self {
+            config,
+            accounts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn prefix(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(v4) => IpAddr::V4(Ipv4Addr::from(
+                u32::from(v4) & mask32(self.config.ipv4_prefix_length),
+            )),
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => self.prefix(IpAddr::V4(v4)),
+                None => IpAddr::V6(Ipv6Addr::from(
+                    u128::from(v6) & mask128(self.config.ipv6_prefix_length),
+                )),
+            },
+        }
+    }
+
+    /// Account the response to be sent to the client.
+    pub fn check(&self, resp: &Message<Bytes>, client: IpAddr) -> Verdict {
+        let (kind, rate) = match resp.header().rcode() {
+            Rcode::NoError => (Kind::Response, self.config.responses_per_second),
+            Rcode::NXDomain => (
+                Kind::NxDomain,
+                self.config
+                    .nxdomains_per_second
+                    .unwrap_or(self.config.responses_per_second),
+            ),
+            _ => (
+                Kind::Error,
+                self.config
+                    .errors_per_second
+                    .unwrap_or(self.config.responses_per_second),
+            ),
+        };
+        // Zero disables limiting on this kind of responses.
+        if rate == 0 {
+            return Verdict::Send;
+        }
+        let rate = rate as f64;
+
+        let key = Key {
+            prefix: self.prefix(client),
+            // Errors are accounted regardless of the name queried, as their names are likely to be random.
+            qname: match kind {
+                Kind::Error => None,
+                _ => resp.first_question().map(|q| q.qname().to_bytes()),
+            },
+            kind,
+        };
+
+        let now = Instant::now();
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.len() >= self.config.max_table_size {
+            // Accounts idle for longer than the window are fully recovered anyway.
+            let window = self.config.window as f64;
+            accounts.retain(|_, a| now.duration_since(a.last).as_secs_f64() < window);
+        }
+        let account = accounts.entry(key).or_insert(Account {
+            credit: rate,
+            last: now,
+            limited: 0,
+        });
+
+        // Credit builds up at the rate, and debt is forgiven after the window.
+        let elapsed = now.duration_since(account.last).as_secs_f64();
+        account.credit = (account.credit + elapsed * rate).min(rate) - 1.0;
+        account.credit = account.credit.max(-(self.config.window as f64) * rate);
+        account.last = now;
+
+        if account.credit >= 0.0 {
+            Verdict::Send
+        } else {
+            account.limited += 1;
+            match self.config.slip {
+                0 => Verdict::Drop,
+                slip if account.limited % slip == 0 => Verdict::Slip,
+                _ => Verdict::Drop,
+            }
+        }
+    }
+}
+
+fn mask32(len: u8) -> u32 {
+    u32::MAX
+        .checked_shl(32 - u32::from(len.min(32)))
+        .unwrap_or(0)
+}
+
+fn mask128(len: u8) -> u128 {
+    u128::MAX
+        .checked_shl(128 - u32::from(len.min(128)))
+        .unwrap_or(0)
+}
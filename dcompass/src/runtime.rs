@@ -0,0 +1,132 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Runtime and listening socket setup per the runtime configuration.
+
+use crate::parser::RuntimeConfig;
+use log::*;
+use std::{
+    io::Result,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    runtime::{Builder, Runtime},
+};
+
+/// Build the multi-threaded runtime.
+pub fn build(config: &RuntimeConfig) -> Result<Runtime> {
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all().thread_name(config.thread_name.clone());
+    if let Some(n) = config.worker_threads {
+        builder.worker_threads(n);
+    }
+    if let Some(n) = config.max_blocking_threads {
+        builder.max_blocking_threads(n);
+    }
+    if config.pin_threads {
+        match core_affinity::get_core_ids() {
+            Some(cores) if !cores.is_empty() => {
+                // Pin threads to cores in a round-robin manner as they are started.
+                let next = Arc::new(AtomicUsize::new(0));
+                builder.on_thread_start(move || {
+                    let core = cores[next.fetch_add(1, Ordering::Relaxed) % cores.len()];
+                    core_affinity::set_for_current(core);
+                });
+            }
+            _ => eprintln!("Unable to get core IDs, threads are not pinned."),
+        }
+    }
+    builder.build()
+}
+
+/// Whether IPv6 sockets should refuse IPv4-mapped traffic.
+/// Unless specified, they are dual-stack when listened on alone, and IPv6-only if an IPv4 address is also listened on so that both can be bound to the same port.
+/// Defaults vary across systems, so we always set it explicitly.
+pub fn v6only(addrs: &[SocketAddr], ipv6_only: Option<bool>) -> bool {
+    ipv6_only.unwrap_or_else(|| addrs.iter().any(SocketAddr::is_ipv4))
+}
+
+/// Bind the UDP sockets to serve on, one per shard for each of the addresses.
+pub fn bind(
+    addrs: &[SocketAddr],
+    ipv6_only: Option<bool>,
+    shards: usize,
+) -> Result<Vec<UdpSocket>> {
+    let v6only = v6only(addrs, ipv6_only);
+    let (shards, reuse_port) = if shards <= 1 {
+        (1, false)
+    } else if cfg!(not(unix)) {
+        warn!("sharding is only supported on unix-like systems, serving with a single socket");
+        (1, false)
+    } else {
+        (shards, true)
+    };
+    let mut sockets = Vec::new();
+    for addr in addrs {
+        for _ in 0..shards {
+            sockets.push(UdpSocket::from_std(socket(*addr, v6only, reuse_port)?)?);
+        }
+        info!("listening on {} with {} socket(s)", addr, shards);
+    }
+    Ok(sockets)
+}
+
+/// Bind a single non-blocking UDP socket.
+pub fn socket(addr: SocketAddr, v6only: bool, reuse_port: bool) -> Result<std::net::UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6only)?;
+    }
+    // Kernel load-balances datagrams across all the sockets bound to the same address.
+    #[cfg(unix)]
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+    // Responses have to leave from the address queries were sent to, which is only known per datagram if we are bound to an unspecified address.
+    #[cfg(target_os = "linux")]
+    if addr.ip().is_unspecified() {
+        use std::os::unix::io::AsRawFd;
+        crate::batch::enable_pktinfo(socket.as_raw_fd(), addr.is_ipv6(), !v6only)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Bind a TCP listener.
+pub fn tcp_listener(addr: SocketAddr, v6only: bool) -> Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6only)?;
+    }
+    // Allow restarting while connections of the previous instance are still in TIME_WAIT.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
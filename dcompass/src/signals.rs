@@ -0,0 +1,80 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Handlers of the signals used to debug and reload a live instance.
+
+use crate::{handle::RouterHandle, source::ConfigSource, stats::Stats};
+use droute::{builders::RuneScript, Router};
+use log::{warn, LevelFilter};
+use std::{io::Result, sync::Arc};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Handle `SIGUSR1` by raising the log verbosity by one level (wrapping around to `error` after `trace`), `SIGUSR2` by dumping the statistics to the log,
+/// and `SIGHUP` by reloading the configuration from its source.
+pub async fn handle(
+    router: Arc<RouterHandle>,
+    stats: Arc<Stats>,
+    source: ConfigSource,
+    profile: Option<String>,
+) -> Result<()> {
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    let mut usr2 = signal(SignalKind::user_defined2())?;
+    let mut hup = signal(SignalKind::hangup())?;
+
+    loop {
+        tokio::select! {
+            Some(()) = usr1.recv() => {
+                let level = raise(log::max_level());
+                log::set_max_level(level);
+                warn!("SIGUSR1 received, log verbosity set to {}", level);
+            }
+            Some(()) = usr2.recv() => {
+                warn!("SIGUSR2 received, statistics: {}", stats);
+                warn!("router statistics: {:?}", router.get().stats());
+            }
+            Some(()) = hup.recv() => match reload(&source, profile.as_deref()).await {
+                Ok(new) => {
+                    router.swap(new);
+                    warn!("SIGHUP received, router replaced with the configuration reloaded");
+                }
+                Err(e) => warn!("SIGHUP received, but failed to reload the configuration: {:#}", e),
+            },
+            else => return Ok(()),
+        }
+    }
+}
+
+// Build the router out of the configuration read again. Only the routing part of it (script, upstreams, zones, etc.) is applied, listener settings are kept.
+async fn reload(
+    source: &ConfigSource,
+    profile: Option<&str>,
+) -> anyhow::Result<Router<RuneScript>> {
+    let parsed = crate::profile::parse(&source.read().await?, profile)?;
+    for deprecation in &parsed.deprecations {
+        warn!("configuration reloaded: {}", deprecation);
+    }
+    let (router, ..) = crate::init(crate::stamps::load(parsed).await?).await?;
+    Ok(router)
+}
+
+fn raise(level: LevelFilter) -> LevelFilter {
+    match level {
+        LevelFilter::Off | LevelFilter::Trace => LevelFilter::Error,
+        LevelFilter::Error => LevelFilter::Warn,
+        LevelFilter::Warn => LevelFilter::Info,
+        LevelFilter::Info => LevelFilter::Debug,
+        LevelFilter::Debug => LevelFilter::Trace,
+    }
+}
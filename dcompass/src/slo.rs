@@ -0,0 +1,131 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Latency SLOs on groups of domains, e.g. corporate domains resolved within 50ms at p99.
+//!
+//! Latency at the percentile is within the threshold if and only if no more than the rest of the queries take longer than that,
+//! so only the queries and the slow ones among them are counted rather than the latency distribution.
+
+use crate::{hooks::Event, parser::SloConfig};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use dmatcher::domain::Domain;
+use domain::base::Dname;
+use droute::utils::parse_domain;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+struct Slo {
+    config: SloConfig,
+    domains: Domain,
+    queries: AtomicU64,
+    slow: AtomicU64,
+}
+
+/// SLOs tracked on the queries answered
+pub struct Slos(Vec<Slo>);
+
+impl Slos {
+    pub fn new(configs: Vec<SloConfig>) -> Result<Self> {
+        configs
+            .into_iter()
+            .map(|config| {
+                let mut domains = Domain::new();
+                for d in &config.domains {
+                    domains.insert(&parse_domain(d).with_context(|| {
+                        format!("invalid domain `{}` in SLO `{}`", d, config.name)
+                    })?);
+                }
+                Ok(Slo {
+                    config,
+                    domains,
+                    queries: AtomicU64::new(0),
+                    slow: AtomicU64::new(0),
+                })
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Count the query against every SLO covering the name.
+    pub fn record(&self, qname: &Dname<Bytes>, latency: Duration) {
+        for slo in self.0.iter().filter(|slo| slo.domains.matches(qname)) {
+            slo.queries.fetch_add(1, Ordering::Relaxed);
+            if latency >= Duration::from_millis(slo.config.latency) {
+                slo.slow.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Detection of the violations out of the counters sampled every interval.
+#[derive(Default)]
+pub struct SloMonitor {
+    // Queries and slow ones of each SLO last sampled
+    last: Vec<(u64, u64)>,
+    violated: Vec<bool>,
+}
+
+impl SloMonitor {
+    /// Compare the counters with the ones last sampled, returning the violations and recoveries occurred in between.
+    pub fn check(&mut self, slos: &Slos) -> Vec<Event> {
+        let now: Vec<_> = slos
+            .0
+            .iter()
+            .map(|slo| {
+                (
+                    slo.queries.load(Ordering::Relaxed),
+                    slo.slow.load(Ordering::Relaxed),
+                )
+            })
+            .collect();
+        let last = std::mem::replace(&mut self.last, now.clone());
+        self.violated.resize(slos.0.len(), false);
+
+        let mut events = Vec::new();
+        for (i, (slo, (queries, slow))) in slos.0.iter().zip(now).enumerate() {
+            let (last_queries, last_slow) = last.get(i).copied().unwrap_or_default();
+            let queries = queries - last_queries;
+            if queries < slo.config.min_queries.max(1) {
+                continue;
+            }
+            let slow_ratio = (slow - last_slow) as f64 / queries as f64;
+            let violated = slow_ratio > 1.0 - slo.config.percentile / 100.0;
+            if violated != self.violated[i] {
+                self.violated[i] = violated;
+                events.push(if violated {
+                    Event::SloViolated {
+                        slo: slo.config.name.clone(),
+                        latency: slo.config.latency,
+                        percentile: slo.config.percentile,
+                        slow_ratio,
+                    }
+                } else {
+                    Event::SloRecovered {
+                        slo: slo.config.name.clone(),
+                        slow_ratio,
+                    }
+                });
+            }
+        }
+        events
+    }
+}
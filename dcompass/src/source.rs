@@ -0,0 +1,351 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Source of the configuration, read at startup and on every reload. Configurations fetched over HTTPS are cached locally,
+//! revalidated with conditional requests, and taken from the cache while the source is unreachable.
+//! The copies cached are trusted as much as the source, so they are kept in a directory private to the user by default.
+
+use anyhow::{Context, Result};
+use log::*;
+#[cfg(feature = "remote-config")]
+use reqwest::{
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+use tokio::io::AsyncWriteExt;
+
+// Environment variable listing the upstreams, which configures a simple setup out of the environment variables if set
+const UPSTREAMS: &str = "DCOMPASS_UPSTREAMS";
+
+// Validators of the copy cached, stored next to it.
+#[derive(Serialize, Deserialize, Default)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Where the configuration is read from
+pub enum ConfigSource {
+    /// File specified or found under the current path
+    File(PathBuf),
+    /// HTTPS URL, along with the path of the copy cached
+    Url(String, PathBuf),
+    /// Environment variables
+    Env,
+    /// The built-in configuration
+    BuiltIn,
+}
+
+impl ConfigSource {
+    /// The configuration at the path or URL given, or `config.yaml` under the current path if any, or the built-in one.
+    pub fn new(path: Option<PathBuf>, cache: Option<PathBuf>) -> Result<Self> {
+        // If the config path is manually specified with `-c` flag, we use it and any error should fail early.
+        // If there is no specified config but there is `config.yaml` under the path where user is invoking `dcompass` (not the absolute path of the binary), then we shall try that config. If the file exists but we failed to read, this should fail. Otherwise, we shall use the default anyway.
+        if let Some(path) = path {
+            if let Some(url) = path.to_str().filter(|p| p.starts_with("http://")) {
+                // Anyone on the path could hand out the script and hooks to run otherwise.
+                anyhow::bail!(
+                    "refusing to fetch the config over plain HTTP, which is not authenticated: {}",
+                    url
+                );
+            }
+            if let Some(url) = path.to_str().filter(|p| p.starts_with("https://")) {
+                println!("Using the config fetched from: {}", url);
+                let cache = match cache {
+                    Some(cache) => cache,
+                    None => state_dir()?.join("config.yaml"),
+                };
+                return Ok(Self::Url(url.to_string(), cache));
+            }
+            println!("Using the config file specified: {}", path.display());
+            return Ok(Self::File(path));
+        }
+        if std::env::var_os(UPSTREAMS).is_some() {
+            println!("Using the config from the environment variables.");
+            return Ok(Self::Env);
+        }
+        let mut path = std::env::current_dir()?;
+        path.push("config.yaml");
+        match std::fs::metadata(&path) {
+            // No config found, using built-in.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                println!("No config found or specified, using built-in config.");
+                Ok(Self::BuiltIn)
+            }
+            _ => {
+                println!("Using the config under current path: {}", path.display());
+                Ok(Self::File(path))
+            }
+        }
+    }
+
+    /// Read the configuration.
+    pub async fn read(&self) -> Result<String> {
+        match self {
+            Self::File(path) => tokio::fs::read_to_string(path).await.with_context(|| {
+                format!("Failed to read from the config file: {}", path.display())
+            }),
+            Self::Url(url, cache) => fetch(url, cache).await,
+            Self::Env => from_env(&std::env::vars().collect()).await,
+            Self::BuiltIn => Ok(include_str!("../../configs/default.json").to_owned()),
+        }
+    }
+}
+
+// The upstream given either as `ip[:port]` over UDP, or as a DNS stamp.
+fn env_upstream(upstream: &str) -> Result<Value> {
+    if upstream.starts_with("sdns://") {
+        return Ok(json!(upstream));
+    }
+    let addr = upstream
+        .parse::<SocketAddr>()
+        .or_else(|_| upstream.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .with_context(|| {
+            format!(
+                "invalid upstream `{}` in {}, which should be either `ip[:port]` or a DNS stamp",
+                upstream, UPSTREAMS
+            )
+        })?;
+    Ok(json!({ "udp": { "addr": addr.to_string() } }))
+}
+
+// Script sending the queries to the upstream, and blackholing the domains on the blocklists at the paths given.
+fn env_script(tag: &str, blocklists: &[PathBuf]) -> String {
+    if blocklists.is_empty() {
+        return format!(
+            "pub async fn route(upstreams, inited, ctx, query) {{ upstreams.send_default({:?}, query).await }}",
+            tag
+        );
+    }
+    let lists: String = blocklists
+        .iter()
+        .map(|p| format!(".add_file({})?", json!(p.to_string_lossy())))
+        .collect();
+    format!(
+        r#"pub async fn route(upstreams, inited, ctx, query) {{
+  if inited.blocklist.0.contains(query.first_question?.qname) {{
+    return blackhole(query);
+  }}
+  upstreams.send_default({:?}, query).await
+}}
+
+pub async fn init() {{
+  Ok(#{{"blocklist": Utils::Domain(Domain::new(){}.seal())}})
+}}
+"#,
+        tag, lists
+    )
+}
+
+/// Configuration of a simple setup out of the environment variables given, for containers run without a configuration file.
+pub async fn from_env(vars: &HashMap<String, String>) -> Result<String> {
+    let var = |name| vars.get(name).filter(|v| !v.trim().is_empty()).cloned();
+    let list = |name| {
+        var(name).map_or_else(Vec::new, |v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+    };
+
+    let mut upstreams = Map::new();
+    for (i, upstream) in list(UPSTREAMS).iter().enumerate() {
+        upstreams.insert(format!("upstream{}", i + 1), env_upstream(upstream)?);
+    }
+    // Queries are raced among the upstreams if more than one.
+    let tag = match upstreams.len() {
+        0 => anyhow::bail!("no upstream is listed in {}", UPSTREAMS),
+        1 => "upstream1",
+        _ => {
+            let members: Vec<_> = upstreams.keys().cloned().collect();
+            upstreams.insert("upstream".to_string(), json!({ "hybrid": members }));
+            "upstream"
+        }
+    };
+
+    // Blocklists are cached locally like the configuration fetched.
+    let urls = list("DCOMPASS_BLOCKLIST_URL");
+    let dir = if urls.is_empty() {
+        PathBuf::new()
+    } else {
+        state_dir()?
+    };
+    let mut blocklists = Vec::new();
+    for (i, url) in urls.iter().enumerate() {
+        let path = dir.join(format!("blocklist-{}.txt", i + 1));
+        fetch(url, &path)
+            .await
+            .with_context(|| format!("failed to fetch the blocklist from {}", url))?;
+        blocklists.push(path);
+    }
+
+    let mut config = json!({
+        "verbosity": var("DCOMPASS_VERBOSITY").unwrap_or_else(|| "info".to_string()),
+        "address": var("DCOMPASS_LISTEN").unwrap_or_else(|| "0.0.0.0:53".to_string()),
+        "upstreams": upstreams,
+        "script": env_script(tag, &blocklists),
+    });
+    if let Some(size) = var("DCOMPASS_CACHE_SIZE") {
+        config["cache_size"] = json!(size
+            .parse::<usize>()
+            .with_context(|| format!("invalid DCOMPASS_CACHE_SIZE `{}`", size))?);
+    }
+    if let Some(listen) = var("DCOMPASS_CONTROL_LISTEN") {
+        config["control"] = json!({ "listen": listen });
+    }
+    Ok(config.to_string())
+}
+
+/// Directory private to the user to cache the files fetched in, i.e. `dcompass` under `$XDG_STATE_HOME` or `~/.local/state`.
+pub fn state_dir() -> Result<PathBuf> {
+    let dir = std::env::var_os("XDG_STATE_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".local/state")))
+        .context("no home directory to cache the files fetched in")?
+        .join("dcompass");
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("failed to create the directory {}", dir.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(dir)
+}
+
+fn validators_path(cache: &Path) -> PathBuf {
+    let mut path = cache.as_os_str().to_owned();
+    path.push(".validators");
+    PathBuf::from(path)
+}
+
+// Replace the file at once so that it is never read half-written.
+// The temporary file is created anew, which never follows a link planted in its place, and is readable by the owner only.
+async fn write(path: &Path, content: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    match tokio::fs::remove_file(&tmp).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+    }
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp).await?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+// Read the file unless it is a link, which may point anywhere.
+async fn read_regular(path: &Path) -> Option<Vec<u8>> {
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) if metadata.is_file() => tokio::fs::read(path).await.ok(),
+        _ => None,
+    }
+}
+
+// Fetch the configuration, or take it from the cache if it is unchanged (304) or the source is unreachable.
+async fn fetch(url: &str, cache: &Path) -> Result<String> {
+    let cached = read_regular(cache)
+        .await
+        .and_then(|c| String::from_utf8(c).ok());
+    // Validators are of no use without the copy they validate.
+    let validators: Validators = match &cached {
+        Some(_) => read_regular(&validators_path(cache))
+            .await
+            .and_then(|v| serde_json::from_slice(&v).ok())
+            .unwrap_or_default(),
+        None => Validators::default(),
+    };
+
+    match revalidate(url, &validators).await {
+        Ok(Some((config, validators))) => {
+            write(cache, config.as_bytes())
+                .await
+                .with_context(|| format!("failed to cache the config at {}", cache.display()))?;
+            write(&validators_path(cache), &serde_json::to_vec(&validators)?).await?;
+            Ok(config)
+        }
+        Ok(None) => {
+            debug!("config at {} is unchanged, using the copy cached", url);
+            cached.with_context(|| format!("{} answered not modified, but no copy is cached", url))
+        }
+        Err(e) => match cached {
+            Some(config) => {
+                warn!(
+                    "failed to fetch the config from {}, using the copy cached at {}: {:#}",
+                    url,
+                    cache.display(),
+                    e
+                );
+                Ok(config)
+            }
+            None => Err(e),
+        },
+    }
+}
+
+// The configuration along with its validators, or `None` if it is unchanged since the validators given.
+#[cfg(feature = "remote-config")]
+async fn revalidate(url: &str, validators: &Validators) -> Result<Option<(String, Validators)>> {
+    let mut req = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .build()?
+        .get(url);
+    if let Some(etag) = &validators.etag {
+        req = req.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        req = req.header(IF_MODIFIED_SINCE, last_modified);
+    }
+    let resp = req
+        .send()
+        .await
+        .with_context(|| format!("failed to fetch the config from {}", url))?;
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    let resp = resp.error_for_status()?;
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let validators = Validators {
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
+    Ok(Some((resp.text().await?, validators)))
+}
+
+// Without the HTTP client, only the copy cached if any is used.
+#[cfg(not(feature = "remote-config"))]
+async fn revalidate(url: &str, _validators: &Validators) -> Result<Option<(String, Validators)>> {
+    anyhow::bail!("fetching {} is not available in this build", url)
+}
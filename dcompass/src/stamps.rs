@@ -0,0 +1,103 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Upstreams picked by name from lists of DNS stamps, like the public resolver list of dnscrypt-proxy.
+
+#[cfg(feature = "cluster")]
+use crate::cluster::public_key;
+use crate::parser::{Parsed, StampListConfig};
+use anyhow::{anyhow, Context, Result};
+use droute::builders::{parse_stamp_list, Stamp, UpstreamBuilder};
+use std::collections::HashMap;
+#[cfg(feature = "cluster")]
+use std::time::Duration;
+
+#[cfg(feature = "cluster")]
+async fn get(url: &str) -> Result<Vec<u8>> {
+    Ok(reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()?
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?
+        .to_vec())
+}
+
+// Fetch the list from the URL and verify it against its signature alongside, or read it from the local path, which is trusted as the configuration is.
+async fn fetch(source: &str, key: &str) -> Result<String> {
+    if source.starts_with("https://") || source.starts_with("http://") {
+        fetch_signed(source, key).await
+    } else {
+        Ok(tokio::fs::read_to_string(source).await?)
+    }
+}
+
+#[cfg(feature = "cluster")]
+async fn fetch_signed(source: &str, key: &str) -> Result<String> {
+    let key = public_key(key).context("invalid public key of the stamp list")?;
+    let list = get(source).await?;
+    let sig = get(&format!("{}{}", source, key.extension())).await?;
+    // Signatures name the file signed by the last segment of its URL.
+    let name = source.rsplit('/').next().unwrap_or_default();
+    key.verify(name, &list, &sig)
+        .context("the stamp list doesn't match its signature")?;
+    Ok(String::from_utf8(list)?)
+}
+
+// Signatures are only verified along with the cluster mode.
+#[cfg(not(feature = "cluster"))]
+async fn fetch_signed(source: &str, _key: &str) -> Result<String> {
+    anyhow::bail!(
+        "fetching the stamp list from {} is not available in this build, give a local path instead",
+        source
+    )
+}
+
+/// Add the upstreams named from each of the stamp lists, tagged with their names in the list.
+pub async fn load(mut parsed: Parsed) -> Result<Parsed> {
+    for StampListConfig {
+        source,
+        names,
+        public_key,
+    } in std::mem::take(&mut parsed.stamp_lists)
+    {
+        let list: HashMap<_, _> = parse_stamp_list(
+            &fetch(&source, &public_key)
+                .await
+                .with_context(|| format!("failed to load the stamp list {}", source))?,
+        )
+        .into_iter()
+        .collect();
+
+        for name in names {
+            let stamp = list.get(&name).ok_or_else(|| {
+                anyhow!(
+                    "resolver {} is not found in the stamp list {}",
+                    name,
+                    source
+                )
+            })?;
+            let upstream = stamp
+                .parse::<Stamp>()
+                .and_then(UpstreamBuilder::try_from)
+                .with_context(|| format!("failed to use resolver {} in {}", name, source))?;
+            parsed.upstreams = parsed.upstreams.add_upstream(name, upstream);
+        }
+    }
+    Ok(parsed)
+}
@@ -0,0 +1,92 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{history::History, slo::Slos, topk::TopK};
+use serde::Serialize;
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Counters and the query history on the listener side
+#[derive(Default)]
+pub struct Stats {
+    /// Number of packets received
+    pub received: AtomicU64,
+    /// Number of queries not handled because too many queries are in flight
+    pub overflowed: AtomicU64,
+    /// Number of queries denied by the ACL
+    pub denied: AtomicU64,
+    /// Number of responses dropped by the RRL
+    pub rate_limited: AtomicU64,
+    /// Number of responses sent truncated by the RRL
+    pub slipped: AtomicU64,
+    /// Number of queries answered with SERVFAIL
+    pub servfail: AtomicU64,
+    /// Queries answered recently, if the history is enabled
+    pub history: Option<History>,
+    /// Top domains and clients, if enabled
+    pub top_k: Option<TopK>,
+    /// Latency SLOs tracked, if any
+    pub slos: Option<Slos>,
+}
+
+/// A snapshot of the counters, e.g. to be reported to the cluster leader
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct StatsSnapshot {
+    pub received: u64,
+    pub overflowed: u64,
+    pub denied: u64,
+    pub rate_limited: u64,
+    pub slipped: u64,
+    pub servfail: u64,
+}
+
+impl Stats {
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            received: self.received.load(Ordering::Relaxed),
+            overflowed: self.overflowed.load(Ordering::Relaxed),
+            denied: self.denied.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            slipped: self.slipped.load(Ordering::Relaxed),
+            servfail: self.servfail.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "received: {}, overflowed: {}, denied: {}, rate limited: {}, slipped: {}, servfail: {}",
+            self.received.load(Ordering::Relaxed),
+            self.overflowed.load(Ordering::Relaxed),
+            self.denied.load(Ordering::Relaxed),
+            self.rate_limited.load(Ordering::Relaxed),
+            self.slipped.load(Ordering::Relaxed),
+            self.servfail.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Seconds since UNIX epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,231 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! DNS over TCP listener, mainly for clients retrying queries answered truncated over UDP.
+
+use crate::{
+    handle::RouterHandle,
+    proxy::{self, TrustedProxies},
+    stats::Stats,
+    worker::{admit, resolve, Limits},
+};
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use domain::base::Message;
+use droute::{
+    privacy,
+    utils::{edns_option, set_edns_option},
+    Listener,
+};
+use log::*;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast::Sender, watch, OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+
+/// Connections served at once on each stream listener, beyond which the next ones wait to be accepted.
+pub const MAX_CONNECTIONS: usize = 1024;
+// Bounds of the time waited after failing to accept, e.g. for running out of file descriptors, which would fail again right away.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Accept the next connection once one of the `connections` is free, which is held until the connection is closed.
+/// Errors are retried after a backoff doubled on every failure in a row.
+pub async fn accept(
+    listener: &TcpListener,
+    connections: &Arc<Semaphore>,
+) -> (TcpStream, SocketAddr, OwnedSemaphorePermit) {
+    let permit = connections
+        .clone()
+        .acquire_owned()
+        .await
+        .expect("the semaphore of the connections is never closed");
+    let mut backoff = MIN_ACCEPT_BACKOFF;
+    loop {
+        match listener.accept().await {
+            Ok((stream, src)) => return (stream, src, permit),
+            Err(e) => {
+                warn!("failed to accept connection: {}", e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Accept connections and serve queries on them until shut down.
+/// No more connections are accepted once `stop` is signaled, while the ones accepted are served on until drained.
+#[allow(clippy::too_many_arguments)]
+pub async fn serve(
+    listener: TcpListener,
+    router: Arc<RouterHandle>,
+    limits: Arc<Limits>,
+    stats: Arc<Stats>,
+    tx: Sender<()>,
+    mut stop: watch::Receiver<bool>,
+    idle_timeout: Duration,
+    proxies: Option<Arc<TrustedProxies>>,
+) {
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        let (stream, src, permit) = tokio::select! {
+            accepted = accept(&listener, &connections) => accepted,
+            _ = stop.changed() => return,
+        };
+
+        let (router, limits, stats, proxies) = (
+            router.clone(),
+            limits.clone(),
+            stats.clone(),
+            proxies.clone(),
+        );
+        let mut shutdown = tx.subscribe();
+        #[rustfmt::skip]
+        tokio::spawn(async move {
+            let _permit = permit;
+            tokio::select! {
+                res = handle(stream, src, router, limits, stats, idle_timeout, proxies) => {
+                    if let Err(e) = res {
+                        debug!(
+                            "TCP connection from {} closed: {}",
+                            privacy::client(src),
+                            e
+                        );
+                    }
+                }
+                _ = shutdown.recv() => {
+                    log::warn!("TCP connection shut down");
+                }
+            }
+        });
+    }
+}
+
+// Code of the edns-tcp-keepalive option (RFC 7828)
+const KEEPALIVE: u16 = 11;
+
+// Whether the client asks how long the idle connections are kept open, with an empty edns-tcp-keepalive option.
+fn asks_keepalive(query: &Bytes) -> bool {
+    Message::from_octets(query.clone()).map_or(
+        false,
+        |msg| matches!(edns_option(&msg, KEEPALIVE), Ok(Some(v)) if v.is_empty()),
+    )
+}
+
+// The response telling the idle timeout in units of 100 milliseconds, so that the client keeps the connection open for the next queries.
+fn tell_keepalive(resp: Message<Bytes>, idle_timeout: Duration) -> Message<Bytes> {
+    let timeout = u16::try_from(idle_timeout.as_millis() / 100).unwrap_or(u16::MAX);
+    set_edns_option(&resp, KEEPALIVE, &timeout.to_be_bytes()).unwrap_or(resp)
+}
+
+// Queries on the same connection are handled one after another.
+async fn handle(
+    mut stream: TcpStream,
+    src: SocketAddr,
+    router: Arc<RouterHandle>,
+    limits: Arc<Limits>,
+    stats: Arc<Stats>,
+    idle_timeout: Duration,
+    proxies: Option<Arc<TrustedProxies>>,
+) -> Result<()> {
+    // Behind a reverse proxy, the clients are the ones it tells rather than the proxy itself.
+    let src = match proxies {
+        Some(proxies) => proxy::accept(&mut stream, src, &proxies).await?,
+        None => src,
+    };
+    loop {
+        // Close the connection if the client has been idle for too long, as recommended by RFC 7766.
+        let mut len = [0; 2];
+        match timeout(idle_timeout, stream.read_exact(&mut len)).await {
+            Ok(res) => res?,
+            Err(_) => return Ok(()),
+        };
+        let len: usize = u16::from_be_bytes(len).into();
+        let mut buf = BytesMut::with_capacity(len);
+        buf.resize(len, 0);
+        timeout(idle_timeout, stream.read_exact(&mut buf)).await??;
+        let buf: Bytes = buf.freeze();
+        let keepalive = asks_keepalive(&buf);
+
+        let permit = match admit(&limits, &stats, &buf, src) {
+            Ok(permit) => permit,
+            Err(Some(resp)) => {
+                write(&mut stream, resp.as_slice()).await?;
+                continue;
+            }
+            Err(None) => return Ok(()),
+        };
+        // Routers replaced at runtime take effect on the connections already established as well.
+        let resp = resolve(&router.get(), &stats, buf, src, Listener::Tcp, false).await;
+        drop(permit);
+
+        match resp {
+            Some(resp) if keepalive => {
+                write(&mut stream, tell_keepalive(resp, idle_timeout).as_slice()).await?
+            }
+            Some(resp) => write(&mut stream, resp.as_slice()).await?,
+            // Nothing worth answering, and the rest of the stream is probably garbage as well.
+            None => return Ok(()),
+        }
+        info!(
+            "response completed. Sent back to {} over TCP.",
+            privacy::client(src)
+        );
+    }
+}
+
+// Prefix the response with its length per RFC, and send it in one go.
+async fn write(stream: &mut TcpStream, resp: &[u8]) -> Result<()> {
+    let mut buf = Vec::with_capacity(resp.len() + 2);
+    buf.extend_from_slice(&u16::try_from(resp.len())?.to_be_bytes());
+    buf.extend_from_slice(resp);
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{asks_keepalive, tell_keepalive, KEEPALIVE};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, Message, MessageBuilder, Rtype};
+    use droute::utils::{edns_option, set_edns_option};
+    use std::{str::FromStr, time::Duration};
+
+    #[test]
+    fn keepalive() {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        let query: Message<Bytes> = builder.into_message();
+        assert!(!asks_keepalive(query.as_octets()));
+        let asking = set_edns_option(&query, KEEPALIVE, &[]).unwrap();
+        assert!(asks_keepalive(asking.as_octets()));
+        // Clients are not to tell the timeout.
+        let telling = set_edns_option(&query, KEEPALIVE, &[0, 100]).unwrap();
+        assert!(!asks_keepalive(telling.as_octets()));
+
+        let resp = tell_keepalive(asking, Duration::from_secs(10));
+        assert_eq!(
+            edns_option(&resp, KEEPALIVE).unwrap(),
+            Some(100u16.to_be_bytes().to_vec())
+        );
+    }
+}
@@ -13,7 +13,9 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::init;
+#[cfg(feature = "cluster")]
+use super::cluster;
+use super::{compat, control, hooks, init, init_resolvers, profile, stamps, Policies};
 use droute::errors::*;
 
 #[tokio::test]
@@ -66,6 +68,7 @@ async fn check_success_query_cache_mode() {
     );
 }
 
+#[cfg(feature = "geoip")]
 #[tokio::test(flavor = "multi_thread")]
 async fn check_success_geoip() {
     assert_eq!(
@@ -86,6 +89,250 @@ async fn check_success_header_yaml() {
     );
 }
 
+#[tokio::test]
+async fn check_success_special_use() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_special_use.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_fail_special_use_missing_tag() {
+    let mut parsed: crate::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_special_use.yaml")).unwrap();
+    parsed.special_use.insert(
+        "internal".to_string(),
+        droute::SpecialUsePolicy::Upstream("nonexistent".into()),
+    );
+    match init(parsed).await.err().unwrap() {
+        ScriptError::UpstreamError(UpstreamError::MissingTag(_)) => {}
+        e => panic!("Not the right error type: {}", e),
+    };
+}
+
+#[tokio::test]
+async fn check_success_zones() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_zones.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_resolvers() {
+    let mut parsed: crate::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_resolvers.yaml")).unwrap();
+    let resolvers = std::mem::take(&mut parsed.resolvers);
+    let policies = Policies::new(&parsed);
+    let main = parsed.address.addrs();
+    let resolvers = init_resolvers(resolvers, &policies, &main).await.unwrap();
+    assert_eq!(resolvers.len(), 1);
+    assert_eq!(resolvers[0].0, "guest");
+    assert_eq!(resolvers[0].1, vec!["0.0.0.0:2054".parse().unwrap()]);
+    init(parsed).await.unwrap();
+}
+
+#[tokio::test]
+async fn check_fail_resolvers_overlap() {
+    let mut parsed: crate::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_resolvers.yaml")).unwrap();
+    let resolvers = std::mem::take(&mut parsed.resolvers);
+    let policies = Policies::new(&parsed);
+    // A specific address on the port of the main one is taken by it as well.
+    let main = vec!["127.0.0.1:2054".parse().unwrap()];
+    assert!(init_resolvers(resolvers, &policies, &main).await.is_err());
+}
+
+#[tokio::test]
+async fn check_success_entries() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_entries.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_fail_entries() {
+    let mut parsed: crate::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_entries.yaml")).unwrap();
+    parsed
+        .entries
+        .insert(droute::Listener::Tcp, "missing".to_string());
+    match init(parsed).await.err().unwrap() {
+        ScriptError::MissingEntry(e) => assert_eq!(e, "missing"),
+        e => panic!("Not the right error type: {}", e),
+    }
+}
+
+#[tokio::test]
+async fn check_success_consensus() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_consensus.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_ech() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_ech.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_root_mirror() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_root_mirror.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_guard() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_guard.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_https_nodata() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_https_nodata.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_fail_zones_missing_tag() {
+    let mut parsed: crate::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_zones.yaml")).unwrap();
+    parsed.zones.insert("lan".to_string(), "nonexistent".into());
+    match init(parsed).await.err().unwrap() {
+        ScriptError::UpstreamError(UpstreamError::MissingTag(_)) => {}
+        e => panic!("Not the right error type: {}", e),
+    };
+}
+
+#[tokio::test]
+async fn check_success_dual_stack() {
+    let parsed: crate::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_dual_stack.yaml")).unwrap();
+    let ipv6_only = parsed.ipv6_only;
+    let (_, addrs, _, _, _) = init(parsed).await.unwrap();
+    assert_eq!(addrs.len(), 2);
+    assert!(crate::runtime::v6only(&addrs, ipv6_only));
+    // IPv6 sockets listened on alone are dual-stack by default.
+    assert!(!crate::runtime::v6only(&addrs[1..], None));
+}
+
+#[tokio::test]
+async fn check_success_acl() {
+    let (_, _, _, limits, _) =
+        init(serde_yaml::from_str(include_str!("../../configs/success_acl.yaml")).unwrap())
+            .await
+            .unwrap();
+    assert!(limits.acl.allows("192.168.1.1".parse().unwrap()));
+    assert!(limits.acl.allows("::ffff:192.168.1.1".parse().unwrap()));
+    assert!(limits.acl.allows("fd00::1".parse().unwrap()));
+    assert!(!limits.acl.allows("192.168.100.1".parse().unwrap()));
+    assert!(!limits.acl.allows("1.1.1.1".parse().unwrap()));
+}
+
+#[tokio::test]
+async fn check_fail_acl_invalid_cidr() {
+    let mut parsed: crate::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_acl.yaml")).unwrap();
+    parsed.acl.allow.push("not a cidr".to_string());
+    match init(parsed).await.err().unwrap() {
+        ScriptError::UtilsError(_) => {}
+        e => panic!("Not the right error type: {}", e),
+    };
+}
+
+#[tokio::test]
+async fn check_success_rrl() {
+    use crate::rrl::Verdict;
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{iana::Rcode, Dname, MessageBuilder, Rtype};
+    use droute::truncation::truncate;
+    use std::str::FromStr;
+
+    let (_, _, _, limits, _) =
+        init(serde_yaml::from_str(include_str!("../../configs/success_rrl.yaml")).unwrap())
+            .await
+            .unwrap();
+    let rrl = limits.rrl.unwrap();
+
+    let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+    builder.header_mut().set_qr(true);
+    let mut builder = builder.question();
+    builder
+        .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+        .unwrap();
+    let resp = builder.into_message();
+
+    let client = "192.0.2.1".parse().unwrap();
+    for _ in 0..5 {
+        assert_eq!(rrl.check(&resp, client), Verdict::Send);
+    }
+    // Every other response limited is slipped.
+    assert_eq!(rrl.check(&resp, client), Verdict::Drop);
+    assert_eq!(rrl.check(&resp, client), Verdict::Slip);
+    // Clients in the same /24 share the account.
+    assert_eq!(
+        rrl.check(&resp, "192.0.2.100".parse().unwrap()),
+        Verdict::Drop
+    );
+    assert_eq!(
+        rrl.check(&resp, "192.0.3.1".parse().unwrap()),
+        Verdict::Send
+    );
+
+    let truncated = truncate(&resp, None).unwrap();
+    assert!(truncated.header().tc());
+    assert_eq!(truncated.header().rcode(), Rcode::NoError);
+    assert_eq!(truncated.header_counts().qdcount(), 1);
+    assert_eq!(truncated.header_counts().ancount(), 0);
+}
+
+#[test]
+fn check_truncation() {
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{Dname, MessageBuilder, Rtype},
+        rdata::A,
+    };
+    use droute::truncation::{client_limit, fit};
+    use std::str::FromStr;
+
+    let name = Dname::<Bytes>::from_str("example.com").unwrap();
+    let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+    builder.header_mut().set_qr(true);
+    let mut builder = builder.question();
+    builder.push((&name, Rtype::A)).unwrap();
+    let mut builder = builder.answer();
+    for i in 0..64 {
+        builder
+            .push((&name, 10, A::from_octets(10, 0, 0, i)))
+            .unwrap();
+    }
+    let resp = builder.into_message();
+    assert!(resp.as_slice().len() > 512);
+
+    // Clients without EDNS can only take 512 bytes.
+    let mut builder = MessageBuilder::from_target(BytesMut::new())
+        .unwrap()
+        .question();
+    builder.push((&name, Rtype::A)).unwrap();
+    assert_eq!(
+        client_limit(&builder.into_message().into_octets()),
+        (512, false)
+    );
+
+    let truncated = fit(resp.clone(), 512, false).unwrap();
+    assert!(truncated.header().tc());
+    assert_eq!(truncated.header_counts().ancount(), 0);
+    assert_eq!(truncated.header_counts().qdcount(), 1);
+
+    let fitted = fit(resp.clone(), 4096, true).unwrap();
+    assert_eq!(fitted.as_slice(), resp.as_slice());
+}
+
 #[tokio::test]
 async fn check_fail_recursion() {
     match init(serde_yaml::from_str(include_str!("../../configs/fail_recursion.json")).unwrap())
@@ -97,3 +344,822 @@ async fn check_fail_recursion() {
         e => panic!("Not the right error type: {}", e),
     };
 }
+
+#[cfg(feature = "cluster")]
+#[tokio::test]
+async fn check_success_cluster() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_cluster.yaml")).unwrap();
+    let cluster = parsed.cluster.clone().unwrap();
+    assert_eq!(cluster.files, vec!["ads.txt"]);
+    assert!(cluster.push_stats);
+    cluster::public_key(&cluster.public_key).unwrap();
+    assert!(matches!(
+        cluster::public_key(&cluster.lists["phishing.txt"].public_key).unwrap(),
+        cluster::VerifyingKey::Minisign(..)
+    ));
+    init(parsed).await.unwrap();
+}
+
+#[cfg(feature = "cluster")]
+#[test]
+fn check_cluster_signature() {
+    let secret = ed25519_dalek::SecretKey::from_bytes(&[7; 32]).unwrap();
+    let public = ed25519_dalek::PublicKey::from(&secret);
+    let key = ed25519_dalek::Keypair { secret, public };
+    let sig = cluster::sign(&key, "config.yaml", 7, b"config");
+    assert_eq!(
+        cluster::verify(&public, "config.yaml", b"config", sig.as_bytes()).unwrap(),
+        7
+    );
+    assert!(cluster::verify(&public, "config.yaml", b"tampered", sig.as_bytes()).is_err());
+    // Neither another file nor another serial passes.
+    assert!(cluster::verify(&public, "ads.txt", b"config", sig.as_bytes()).is_err());
+    let replayed = sig.replacen('7', "8", 1);
+    assert!(cluster::verify(&public, "config.yaml", b"config", replayed.as_bytes()).is_err());
+    assert!(cluster::verify(&public, "config.yaml", b"config", b"7 not hex").is_err());
+}
+
+#[tokio::test]
+async fn check_config_source() {
+    use super::source::ConfigSource;
+
+    // Plain HTTP authenticates nothing.
+    assert!(ConfigSource::new(Some("http://127.0.0.1:9/config.yaml".into()), None).is_err());
+
+    let cache = std::env::temp_dir().join(format!("dcompass-config-{}", std::process::id()));
+    let source = ConfigSource::new(
+        Some("https://127.0.0.1:9/config.yaml".into()),
+        Some(cache.clone()),
+    )
+    .unwrap();
+    assert!(matches!(source, ConfigSource::Url(..)));
+    // Nothing to fall back to
+    assert!(source.read().await.is_err());
+    // The copy cached is used while the source is unreachable.
+    std::fs::write(&cache, "verbosity: \"off\"").unwrap();
+    assert_eq!(source.read().await.unwrap(), "verbosity: \"off\"");
+    std::fs::remove_file(&cache).unwrap();
+}
+
+#[tokio::test]
+async fn check_config_from_env() {
+    use super::source::from_env;
+
+    let vars = [
+        ("DCOMPASS_UPSTREAMS", "1.1.1.1, 9.9.9.9:53"),
+        ("DCOMPASS_LISTEN", "0.0.0.0:2053"),
+        ("DCOMPASS_VERBOSITY", "off"),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+    let config = from_env(&vars).await.unwrap();
+    let parsed = profile::parse(&config, None).unwrap();
+    init(parsed).await.unwrap();
+
+    // No upstream to send the queries to
+    assert!(from_env(&Default::default()).await.is_err());
+}
+
+#[cfg(feature = "cluster")]
+#[test]
+fn check_minisign_signature() {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use blake2::{Blake2b512, Digest};
+    use ed25519_dalek::Signer;
+
+    let secret = ed25519_dalek::SecretKey::from_bytes(&[7; 32]).unwrap();
+    let public = ed25519_dalek::PublicKey::from(&secret);
+    let key = ed25519_dalek::Keypair { secret, public };
+    let id = [1, 2, 3, 4, 5, 6, 7, 8];
+    let verifying = cluster::public_key(&format!(
+        "untrusted comment: minisign public key\n{}",
+        STANDARD.encode([&b"Ed"[..], &id, public.as_bytes()].concat())
+    ))
+    .unwrap();
+    let minisig = |alg: &[u8], signed: &[u8]| {
+        let sig = key.sign(signed).to_bytes();
+        let trusted = "timestamp:1700000000\tfile:ads.txt";
+        let global = key.sign(&[&sig[..], trusted.as_bytes()].concat());
+        format!(
+            "untrusted comment: signature\n{}\ntrusted comment: {}\n{}\n",
+            STANDARD.encode([alg, &id, &sig].concat()),
+            trusted,
+            STANDARD.encode(global.to_bytes())
+        )
+    };
+
+    // Both legacy and prehashed signatures
+    let sig = minisig(b"Ed", b"ads");
+    assert_eq!(
+        verifying.verify("ads.txt", b"ads", sig.as_bytes()).unwrap(),
+        1700000000
+    );
+    let prehashed = minisig(b"ED", Blake2b512::digest(b"ads").as_slice());
+    verifying
+        .verify("ads.txt", b"ads", prehashed.as_bytes())
+        .unwrap();
+
+    assert!(verifying
+        .verify("ads.txt", b"tampered", sig.as_bytes())
+        .is_err());
+    assert!(verifying
+        .verify("ads.txt", b"tampered", prehashed.as_bytes())
+        .is_err());
+    // The trusted comment is signed too.
+    let forged = sig.replace("file:ads.txt", "file:other.txt");
+    assert!(verifying
+        .verify("ads.txt", b"ads", forged.as_bytes())
+        .is_err());
+    assert!(verifying.verify("ads.txt", b"ads", b"unsigned").is_err());
+    // Another list signed with the same key does not pass for this one.
+    assert!(verifying
+        .verify("other.txt", b"ads", sig.as_bytes())
+        .is_err());
+}
+
+#[cfg(feature = "cluster")]
+#[test]
+fn check_cluster_stats_sum() {
+    let mut total = serde_json::Value::Null;
+    cluster::sum(
+        &mut total,
+        &serde_json::json!({ "listener": { "received": 3 }, "router": { "upstreams": { "a": { "queries": 2 } } } }),
+    );
+    cluster::sum(
+        &mut total,
+        &serde_json::json!({ "listener": { "received": 4 }, "router": { "upstreams": { "b": { "queries": 1 } } } }),
+    );
+    assert_eq!(
+        total,
+        serde_json::json!({ "listener": { "received": 7 }, "router": { "upstreams": { "a": { "queries": 2 }, "b": { "queries": 1 } } } })
+    );
+}
+
+#[test]
+fn check_control_bearer() {
+    let req = |auth: &str| {
+        hyper::Request::builder()
+            .header(hyper::header::AUTHORIZATION, auth)
+            .body(hyper::Body::empty())
+            .unwrap()
+    };
+    assert!(control::bearer(&req("Bearer secret"), "secret"));
+    assert!(!control::bearer(&req("Bearer secreT"), "secret"));
+    assert!(!control::bearer(&req("Bearer secret2"), "secret"));
+    assert!(!control::bearer(
+        &hyper::Request::new(hyper::Body::empty()),
+        "secret"
+    ));
+}
+
+#[tokio::test]
+async fn check_success_hooks() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_hooks.yaml")).unwrap();
+    assert_eq!(parsed.hooks.webhooks.len(), 1);
+    assert_eq!(parsed.hooks.min_queries, 20);
+    init(parsed).await.unwrap();
+}
+
+#[tokio::test]
+async fn check_success_stamps() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_stamps.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_stamp_lists() {
+    let path = std::env::temp_dir().join("dcompass-test-resolvers.md");
+    std::fs::write(
+        &path,
+        "# public-resolvers\n\n## cloudflare\n\nCloudflare DNS\n\nsdns://AgcAAAAAAAAABzEuMC4wLjEAEmRucy5jbG91ZGZsYXJlLmNvbQovZG5zLXF1ZXJ5\n\n## opendns\n\nsdns://AQcAAAAAAAAADjIwOC42Ny4yMjAuMjIwILc1EUAgbyJdPivYItf9aR6hwzzI1maNDL4Ev6vKQ_t5GzIuZG5zY3J5cHQtY2VydC5vcGVuZG5zLmNvbQ\n",
+    )
+    .unwrap();
+    let config = |names: &str| {
+        format!(
+            r#"
+verbosity: "off"
+address: 0.0.0.0:2053
+stamp_lists:
+  - source: {}
+    names: [{}]
+script: |
+  pub async fn route(upstreams, inited, ctx, query) {{
+    upstreams.send_default("cloudflare", query).await
+  }}
+upstreams: {{}}
+"#,
+            path.display(),
+            names
+        )
+    };
+
+    let parsed = stamps::load(serde_yaml::from_str(&config("cloudflare")).unwrap())
+        .await
+        .unwrap();
+    init(parsed).await.unwrap();
+
+    // DNSCrypt is not supported.
+    assert!(
+        stamps::load(serde_yaml::from_str(&config("cloudflare, opendns")).unwrap())
+            .await
+            .is_err()
+    );
+    assert!(
+        stamps::load(serde_yaml::from_str(&config("cloudflare, quad9")).unwrap())
+            .await
+            .is_err()
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn check_success_captive_portal() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_captive_portal.yaml")).unwrap();
+    let config = parsed.captive_portal.as_ref().unwrap();
+    assert_eq!(config.resolver, Some("192.168.1.1:53".parse().unwrap()));
+    assert_eq!(config.interval, 10);
+    init(parsed).await.unwrap();
+}
+
+#[test]
+fn check_behind_portal() {
+    use super::portal::behind_portal;
+    use std::collections::HashSet;
+
+    let upstreams: HashSet<_> = ["8.8.8.8".parse().unwrap(), "8.8.4.4".parse().unwrap()].into();
+    let portal: HashSet<_> = ["192.168.1.1".parse().unwrap()].into();
+    let partial: HashSet<_> = ["8.8.4.4".parse().unwrap()].into();
+
+    assert_eq!(
+        behind_portal(Some(&upstreams), Some(&upstreams)),
+        Some(false)
+    );
+    assert_eq!(behind_portal(Some(&upstreams), Some(&partial)), Some(false));
+    assert_eq!(behind_portal(Some(&upstreams), Some(&portal)), Some(true));
+    // Upstreams blocked by the portal
+    assert_eq!(behind_portal(None, Some(&portal)), Some(true));
+    assert_eq!(behind_portal(Some(&upstreams), None), Some(false));
+    assert_eq!(behind_portal(None, None), None);
+}
+
+#[test]
+fn check_hooks_monitor() {
+    use droute::{RouterStats, UpstreamStats};
+    use hooks::{Event, Monitor};
+
+    let config = super::parser::HooksConfig::default();
+    let sample = |received, servfail, queries, errors| {
+        let mut router = RouterStats::default();
+        router.upstreams.insert(
+            "google".into(),
+            UpstreamStats {
+                queries,
+                errors,
+                ..Default::default()
+            },
+        );
+        (
+            super::stats::StatsSnapshot {
+                received,
+                servfail,
+                ..Default::default()
+            },
+            router,
+        )
+    };
+    let mut monitor = Monitor::default();
+    let mut check = |(listener, router)| monitor.check(&config, listener, router);
+
+    assert!(check(sample(0, 0, 0, 0)).is_empty());
+    assert_eq!(
+        check(sample(100, 20, 100, 60)),
+        vec![
+            Event::UpstreamDown {
+                upstream: "google".into(),
+                error_ratio: 0.6
+            },
+            Event::ServfailRateExceeded { ratio: 0.2 }
+        ]
+    );
+    // Too few queries to tell
+    assert!(check(sample(110, 20, 110, 60)).is_empty());
+    assert_eq!(
+        check(sample(210, 20, 210, 60)),
+        vec![
+            Event::UpstreamUp {
+                upstream: "google".into()
+            },
+            Event::ServfailRateRecovered { ratio: 0.0 }
+        ]
+    );
+}
+
+#[tokio::test]
+async fn check_success_profiles() {
+    use super::profile;
+    let config = include_str!("../../configs/success_profiles.yaml");
+
+    // Profiles add to the upstreams defined in common.
+    let home = profile::parse(config, Some("home")).unwrap();
+    assert!(home.tcp.enabled);
+    init(home).await.unwrap();
+
+    let travel = profile::parse(config, Some("travel")).unwrap();
+    assert_eq!(travel.verbosity, log::LevelFilter::Warn);
+    assert!(!travel.tcp.enabled);
+    init(travel).await.unwrap();
+
+    let common = profile::parse(config, None).unwrap();
+    assert_eq!(common.verbosity, log::LevelFilter::Off);
+    init(common).await.unwrap();
+
+    assert!(profile::parse(config, Some("office")).is_err());
+    assert!(profile::parse(
+        include_str!("../../configs/success_cidr.yaml"),
+        Some("home")
+    )
+    .is_err());
+}
+
+#[tokio::test]
+async fn check_success_ranking() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_ranking.yaml")).unwrap();
+    let config = parsed.ranking.as_ref().unwrap();
+    assert_eq!(config.interval, 600);
+    assert_eq!(config.prune, vec![droute::Label::from("fastest")]);
+    assert_eq!(config.policy.probes, 5);
+    assert_eq!(config.policy.min_reliability, 0.5);
+    assert_eq!(config.policy.keep, Some(2));
+    assert_eq!(
+        parsed.control.as_ref().unwrap().listen,
+        "127.0.0.1:8053".parse().unwrap()
+    );
+    let (router, ..) = init(parsed).await.unwrap();
+    assert_eq!(router.upstreams().hybrids()["fastest"].len(), 3);
+}
+
+#[tokio::test]
+async fn check_success_log_privacy() {
+    use droute::privacy::{Privacy, Redaction};
+
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_log_privacy.yaml")).unwrap();
+    let config = parsed.log_privacy.clone().unwrap();
+    assert_eq!(config.qname, Redaction::Truncate);
+    assert_eq!(config.client, Redaction::Hash);
+    assert_eq!(config.ipv4_prefix_length, 24);
+    assert_eq!(config.max_clients, Some(1000));
+    let privacy = Privacy::from(config);
+    assert_eq!(privacy.qname(&"www.example.com"), "*.example.com");
+    init(parsed).await.unwrap();
+}
+
+#[test]
+fn check_success_history() {
+    use super::history::{Entry, History};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, MessageBuilder, Rtype};
+    use std::{str::FromStr, time::Duration};
+
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_history.yaml")).unwrap();
+    let mut config = parsed.history.unwrap();
+    assert_eq!(config.max_entries, 5000);
+    assert_eq!(config.retention, Some(3600));
+
+    config.max_entries = 2;
+    let history = History::new(&config);
+    let entry = |name: &str| {
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        builder.header_mut().set_qr(true);
+        let mut builder = builder.question();
+        builder
+            .push((Dname::<Bytes>::from_str(name).unwrap(), Rtype::A))
+            .unwrap();
+        Entry::new(
+            "192.0.2.1:5353".parse().unwrap(),
+            &builder.into_message(),
+            Duration::from_millis(3),
+        )
+        .unwrap()
+    };
+
+    // Expired regardless of the number of entries
+    let mut old = entry("old.example.com");
+    old.time -= 3601 * 1000;
+    history.record(old);
+    history.record(entry("a.example.com"));
+    assert_eq!(history.recent(10).len(), 1);
+
+    history.record(entry("b.example.com"));
+    history.record(entry("c.example.com"));
+    let recent = history.recent(10);
+    assert_eq!(recent.len(), 2);
+    assert_eq!(recent[0].qname, "c.example.com");
+    assert_eq!(recent[0].qtype, "A");
+    assert_eq!(recent[0].latency, 3);
+    assert_eq!(history.recent(1).len(), 1);
+
+    let csv = history.csv();
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "time,client,qname,qtype,rcode,answers,latency");
+    assert!(lines[1].ends_with(",192.0.2.1:5353,\"b.example.com\",A,NOERROR,0,3"));
+}
+
+#[tokio::test]
+async fn check_success_memory() {
+    use droute::memory::Caps;
+
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_memory.yaml")).unwrap();
+    assert_eq!(
+        Caps::from(&parsed.memory),
+        Caps {
+            total: Some(48 << 20),
+            cache: Some(16 << 20),
+            history: Some(4 << 20),
+        }
+    );
+    init(parsed).await.unwrap();
+}
+
+#[tokio::test]
+async fn check_success_top_k() {
+    use super::topk::{Item, SpaceSaving, TopK};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, MessageBuilder, Rtype};
+    use droute::utils::blackhole;
+    use std::str::FromStr;
+
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_top_k.yaml")).unwrap();
+    let config = parsed.top_k.clone().unwrap();
+    assert_eq!(config.capacity, 500);
+    init(parsed).await.unwrap();
+
+    // Keys occurring more than 1/6 of the time survive the churn of the rare keys.
+    let mut sketch = SpaceSaving::new(6);
+    for i in 0..100 {
+        sketch.insert("heavy");
+        if i % 2 == 0 {
+            sketch.insert("medium");
+        }
+        sketch.insert(&format!("rare{}", i));
+    }
+    let top = sketch.top(2);
+    assert_eq!(top[0].key, "heavy");
+    assert!(top[0].count >= 100 && top[0].count - top[0].error <= 100);
+    assert_eq!(top[1].key, "medium");
+    assert_eq!(sketch.top(10).len(), 6);
+
+    let query = |name: &str| {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str(name).unwrap(), Rtype::Aaaa))
+            .unwrap();
+        builder.into_message()
+    };
+    let top_k = TopK::new(&config);
+    let client = "192.0.2.1".parse().unwrap();
+    top_k.record(client, &blackhole(&query("ads.example.com")).unwrap());
+    top_k.record(client, &query("Example.com"));
+    top_k.record(client, &query("example.com"));
+    let lists = top_k.top(10);
+    assert_eq!(
+        lists.domains[0],
+        Item {
+            key: "example.com".to_string(),
+            count: 2,
+            error: 0
+        }
+    );
+    assert_eq!(lists.blocked.len(), 1);
+    assert_eq!(lists.blocked[0].key, "ads.example.com");
+    assert_eq!(lists.clients[0].count, 3);
+}
+
+#[tokio::test]
+async fn check_success_slos() {
+    use super::slo::{SloMonitor, Slos};
+    use bytes::Bytes;
+    use domain::base::Dname;
+    use hooks::Event;
+    use std::{str::FromStr, time::Duration};
+
+    let mut parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_slos.yaml")).unwrap();
+    let slos = Slos::new(std::mem::take(&mut parsed.slos)).unwrap();
+    init(parsed).await.unwrap();
+
+    let corp = Dname::<Bytes>::from_str("git.corp.example.com").unwrap();
+    let public = Dname::<Bytes>::from_str("example.org").unwrap();
+    let mut monitor = SloMonitor::default();
+    for i in 0..100 {
+        slos.record(&corp, Duration::from_millis(if i == 0 { 80 } else { 10 }));
+        slos.record(&public, Duration::from_millis(300));
+    }
+    // One in a hundred is within p99.
+    assert_eq!(monitor.check(&slos), vec![]);
+
+    for i in 0..100 {
+        slos.record(&corp, Duration::from_millis(if i < 5 { 80 } else { 10 }));
+    }
+    assert_eq!(
+        monitor.check(&slos),
+        vec![Event::SloViolated {
+            slo: "corporate".to_string(),
+            latency: 50,
+            percentile: 99.0,
+            slow_ratio: 0.05,
+        }]
+    );
+    // Not notified again while still violated
+    for _ in 0..5 {
+        slos.record(&corp, Duration::from_millis(80));
+    }
+    for _ in 0..20 {
+        slos.record(&corp, Duration::from_millis(10));
+    }
+    assert_eq!(monitor.check(&slos), vec![]);
+
+    for _ in 0..100 {
+        slos.record(&corp, Duration::from_millis(10));
+    }
+    assert_eq!(
+        monitor.check(&slos),
+        vec![Event::SloRecovered {
+            slo: "corporate".to_string(),
+            slow_ratio: 0.0,
+        }]
+    );
+
+    assert!(Slos::new(vec![super::parser::SloConfig {
+        name: "invalid".to_string(),
+        domains: vec!["a..b".to_string()],
+        latency: 50,
+        percentile: 99.0,
+        min_queries: 20,
+    }])
+    .is_err());
+}
+
+#[tokio::test]
+async fn check_success_edns_options() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_edns_options.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_grpc() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_grpc.yaml")).unwrap();
+    let config = parsed.grpc.as_ref().unwrap();
+    assert_eq!(config.listen, "127.0.0.1:50053".parse().unwrap());
+    assert_eq!(config.trusted_peers, ["127.0.0.1/32"]);
+    init(parsed).await.unwrap();
+}
+
+#[tokio::test]
+async fn check_success_doh() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_doh.yaml")).unwrap();
+    let config = parsed.doh.as_ref().unwrap();
+    assert_eq!(config.listen, "127.0.0.1:8443".parse().unwrap());
+    assert_eq!(config.path, "/dns-query");
+    init(parsed).await.unwrap();
+}
+
+#[tokio::test]
+async fn check_success_proxy_protocol() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_proxy_protocol.yaml")).unwrap();
+    assert!(parsed.tcp.proxy_protocol);
+    let doh = parsed.doh.as_ref().unwrap();
+    assert!(doh.proxy_protocol);
+    for proxies in [&parsed.tcp.trusted_proxies, &doh.trusted_proxies] {
+        super::proxy::TrustedProxies::new(proxies).unwrap();
+    }
+    init(parsed).await.unwrap();
+}
+
+#[tokio::test]
+async fn check_success_answer_order() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_answer_order.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_deadline() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_deadline.yaml")).unwrap();
+    assert_eq!(parsed.deadline, Some(3000));
+    init(parsed).await.unwrap();
+}
+
+#[tokio::test]
+async fn check_success_hedge() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_hedge.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_affinity() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_affinity.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_aggressive_nxdomain() {
+    init(
+        serde_yaml::from_str(include_str!(
+            "../../configs/success_aggressive_nxdomain.yaml"
+        ))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_retry_rcodes() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_retry_rcodes.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_chaos() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_chaos.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_nsid() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_nsid.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_flatten_cname() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_flatten_cname.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_offline() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_offline.yaml")).unwrap();
+    assert!(parsed.offline);
+    assert_eq!(
+        parsed.control.as_ref().unwrap().token.as_deref(),
+        Some("secret")
+    );
+    init(parsed).await.unwrap();
+}
+
+#[tokio::test]
+async fn check_success_warmup() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_warmup.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_resumption() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_resumption.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_network_watch() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_network_watch.yaml")).unwrap();
+    assert!(parsed.network_watch.enabled);
+    assert_eq!(parsed.network_watch.interval, 5);
+    init(parsed).await.unwrap();
+}
+
+#[tokio::test]
+async fn check_success_connectivity() {
+    let parsed: super::parser::Parsed =
+        serde_yaml::from_str(include_str!("../../configs/success_connectivity.yaml")).unwrap();
+    let config = parsed.connectivity.as_ref().unwrap();
+    assert_eq!(config.interval, 120);
+    assert_eq!(config.targets.len(), 2);
+    init(parsed).await.unwrap();
+}
+
+#[tokio::test]
+async fn check_success_fallback() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_fallback.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_response_matcher() {
+    init(
+        serde_yaml::from_str(include_str!("../../configs/success_response_matcher.yaml")).unwrap(),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_query_context() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_query_context.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_rules() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_rules.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_servfail_cache() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_servfail_cache.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_retransmit() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_retransmit.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_fast_path() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_fast_path.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_groups() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_groups.yaml")).unwrap())
+        .await
+        .unwrap();
+    assert!(!droute::utils::group_enabled("parental"));
+}
+
+#[tokio::test]
+async fn check_success_capture() {
+    let (router, ..) =
+        init(serde_yaml::from_str(include_str!("../../configs/success_capture.yaml")).unwrap())
+            .await
+            .unwrap();
+    assert!(router.upstreams().capture().is_some());
+}
+
+#[tokio::test]
+async fn check_compat_v1_upstreams() {
+    init(profile::parse(include_str!("../../configs/compat/v1_upstreams.yaml"), None).unwrap())
+        .await
+        .unwrap();
+}
+
+#[test]
+fn check_compat_corpus() {
+    // Configurations shipped are of the latest schema, and left as they are by the migrations.
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/../configs");
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            continue;
+        }
+        let mut config: serde_yaml::Value =
+            serde_yaml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        if let Some(config) = config.as_mapping_mut() {
+            config.remove("profiles");
+        }
+        assert!(
+            !compat::migrate(&mut config, &mut Vec::new()).unwrap(),
+            "{} is migrated",
+            path.display()
+        );
+        compat::check_fields(&config).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Top queried domains, top blocked domains, and top clients, tracked with SpaceSaving sketches of fixed memory.
+
+use crate::parser::TopKConfig;
+use bytes::Bytes;
+use domain::base::{iana::Rcode, Message};
+use droute::{privacy, utils::is_blackhole};
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+/// An item of the top list
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Item {
+    pub key: String,
+    /// Estimated number of occurrences, which is never an underestimation
+    pub count: u64,
+    /// Maximum overestimation of `count`
+    pub error: u64,
+}
+
+/// SpaceSaving sketch (Metwally et al., 2005) counting at most `capacity` keys.
+/// Every key occurring more than `1 / capacity` of the time is guaranteed to be counted.
+pub struct SpaceSaving {
+    capacity: usize,
+    // Count and error of each key counted
+    counters: HashMap<Arc<str>, (u64, u64)>,
+    // Keys ordered by their counts, to find the one to evict
+    order: BTreeSet<(u64, Arc<str>)>,
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            counters: HashMap::new(),
+            order: BTreeSet::new(),
+        }
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        if let Some((key, (count, _))) = self.counters.get_key_value(key) {
+            let (key, count) = (key.clone(), *count);
+            self.order.remove(&(count, key.clone()));
+            self.order.insert((count + 1, key.clone()));
+            self.counters.get_mut(&key).unwrap().0 += 1;
+            return;
+        }
+
+        let key: Arc<str> = key.into();
+        if self.counters.len() < self.capacity {
+            self.counters.insert(key.clone(), (1, 0));
+            self.order.insert((1, key));
+        } else {
+            // Take over the least counted key, inheriting its count as the error.
+            let (min, evicted) = self.order.iter().next().cloned().unwrap();
+            self.order.remove(&(min, evicted.clone()));
+            self.counters.remove(&evicted);
+            self.counters.insert(key.clone(), (min + 1, min));
+            self.order.insert((min + 1, key));
+        }
+    }
+
+    /// The `n` most counted keys, the most first
+    pub fn top(&self, n: usize) -> Vec<Item> {
+        self.order
+            .iter()
+            .rev()
+            .take(n)
+            .map(|(count, key)| Item {
+                key: key.to_string(),
+                count: *count,
+                error: self.counters[key].1,
+            })
+            .collect()
+    }
+}
+
+/// Top lists served on the control endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct TopLists {
+    pub domains: Vec<Item>,
+    pub blocked: Vec<Item>,
+    pub clients: Vec<Item>,
+}
+
+/// Heavy hitters among the queries answered
+pub struct TopK {
+    domains: Mutex<SpaceSaving>,
+    blocked: Mutex<SpaceSaving>,
+    clients: Mutex<SpaceSaving>,
+}
+
+impl TopK {
+    pub fn new(config: &TopKConfig) -> Self {
+        Self {
+            domains: Mutex::new(SpaceSaving::new(config.capacity)),
+            blocked: Mutex::new(SpaceSaving::new(config.capacity)),
+            clients: Mutex::new(SpaceSaving::new(config.capacity)),
+        }
+    }
+
+    /// Count the query answered. Queries blackholed or refused count as blocked.
+    pub fn record(&self, src: IpAddr, resp: &Message<Bytes>) {
+        if let Some(question) = resp.first_question() {
+            let qname = question.qname().to_string().to_lowercase();
+            if resp.header().rcode() == Rcode::Refused || is_blackhole(resp) {
+                self.blocked.lock().unwrap().insert(&qname);
+            }
+            self.domains.lock().unwrap().insert(&qname);
+        }
+        self.clients.lock().unwrap().insert(&src.to_string());
+    }
+
+    /// The `n` top ones of each list. Keys are shown per the log privacy filter.
+    pub fn top(&self, n: usize) -> TopLists {
+        let shown = |items: Vec<Item>, f: &dyn Fn(&str) -> String| -> Vec<Item> {
+            items
+                .into_iter()
+                .map(|i| Item {
+                    key: f(&i.key),
+                    ..i
+                })
+                .collect()
+        };
+        let qname = |k: &str| privacy::qname(&k);
+        let client = |k: &str| match k.parse() {
+            Ok(ip) => privacy::ip(ip),
+            Err(_) => k.to_string(),
+        };
+        TopLists {
+            domains: shown(self.domains.lock().unwrap().top(n), &qname),
+            blocked: shown(self.blocked.lock().unwrap().top(n), &qname),
+            clients: shown(self.clients.lock().unwrap().top(n), &client),
+        }
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! UDP serving backend based on `io_uring`.
+
+use crate::{
+    batch::Peer,
+    handle::RouterHandle,
+    stats::Stats,
+    worker::{admit, worker, Limits, Responses},
+};
+use bytes::Bytes;
+use domain::base::Message;
+use log::*;
+use std::{
+    io::{Error, ErrorKind, Result},
+    rc::Rc,
+    sync::Arc,
+};
+use tokio::{
+    runtime::Handle,
+    sync::{broadcast::Sender, mpsc, oneshot},
+    task::spawn_blocking,
+};
+use tokio_uring::net::UdpSocket;
+
+// Number of receive operations kept in flight on the ring so that they can be submitted in batches.
+const RECV_TASKS: usize = 32;
+
+/// A ring serving UDP queries on its own thread, which stops once this is dropped.
+pub struct Ring {
+    // Dropped to stop the ring
+    _stop: oneshot::Sender<()>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl Ring {
+    /// Stop the ring, returning once its thread has released the socket, so that the address can be bound again.
+    pub async fn stop(self) {
+        drop(self._stop);
+        let thread = self.thread;
+        let _ = spawn_blocking(move || thread.join()).await;
+    }
+}
+
+/// Start serving UDP queries on an `io_uring` driven socket, which is bound beforehand.
+/// Queries are still resolved on the runtime this is called from. Returns error only if the backend failed to start.
+pub async fn start(
+    socket: std::net::UdpSocket,
+    router: Arc<RouterHandle>,
+    limits: Arc<Limits>,
+    stats: Arc<Stats>,
+    tx: Sender<()>,
+) -> Result<Ring> {
+    let addr = socket.local_addr()?;
+    let handle = Handle::current();
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (stop, mut stopped) = oneshot::channel::<()>();
+
+    let thread = std::thread::Builder::new()
+        .name("dcompass-uring".to_string())
+        .spawn(move || {
+            let rt = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                Ok(rt) => rt,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+
+            rt.block_on(async move {
+                // The socket has to be registered on the ring within its runtime.
+                let socket = Rc::new(UdpSocket::from_std(socket));
+                let _ = ready_tx.send(Ok(()));
+
+                let (resp_tx, mut resp_rx) = mpsc::unbounded_channel::<(Message<Bytes>, Peer)>();
+
+                for _ in 0..RECV_TASKS {
+                    tokio_uring::spawn(recv(
+                        socket.clone(),
+                        handle.clone(),
+                        router.clone(),
+                        limits.clone(),
+                        stats.clone(),
+                        tx.clone(),
+                        resp_tx.clone(),
+                    ));
+                }
+
+                let send = async {
+                    while let Some((resp, dst)) = resp_rx.recv().await {
+                        // On windows, some applications may go away after they got their first response, resulting in a broken pipe, we should discard errors on receiving/sending messages.
+                        // Local addresses are not known to this backend, see `batch::Peer`.
+                        let (res, _) = socket.send_to(resp.as_slice().to_vec(), dst.addr).await;
+                        match res {
+                            Ok(_) => {
+                                info!("response completed. Sent back to {} successfully.", dst)
+                            }
+                            Err(e) => warn!("failed to send back response: {}", e),
+                        }
+                    }
+                };
+                // The receiving tasks, and the socket along with them, are dropped with the runtime once stopped.
+                tokio::select! {
+                    _ = send => (),
+                    _ = &mut stopped => (),
+                }
+            });
+        })?;
+
+    ready_rx
+        .await
+        .map_err(|_| Error::new(ErrorKind::Other, "io_uring thread exited unexpectedly"))??;
+
+    info!("serving UDP queries on {} with io_uring backend", addr);
+    Ok(Ring {
+        _stop: stop,
+        thread,
+    })
+}
+
+async fn recv(
+    socket: Rc<UdpSocket>,
+    handle: Handle,
+    router: Arc<RouterHandle>,
+    limits: Arc<Limits>,
+    stats: Arc<Stats>,
+    tx: Sender<()>,
+    resp_tx: Responses,
+) {
+    loop {
+        // Size recommended by DNS Flag Day 2020.
+        let (res, mut buf) = socket.recv_from(Vec::with_capacity(1024)).await;
+        let (len, src) = match res {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("failed to receive query: {}", e);
+                continue;
+            }
+        };
+        buf.truncate(len);
+        let buf = Bytes::from(buf);
+        let src = Peer::from(src);
+
+        let permit = match admit(&limits, &stats, &buf, src.addr) {
+            Ok(permit) => permit,
+            Err(resp) => {
+                if let Some(resp) = resp {
+                    let _ = resp_tx.send((resp, src));
+                }
+                continue;
+            }
+        };
+
+        let router = router.get();
+        let (limits, stats) = (limits.clone(), stats.clone());
+        let resp_tx = resp_tx.clone();
+        let mut shutdown = tx.subscribe();
+        #[rustfmt::skip]
+        handle.spawn(async move {
+            tokio::select! {
+                biased; res = worker(router, limits, stats, resp_tx, buf, src) => {
+                    match res {
+                        Ok(_) => (),
+                        Err(e) => warn!("handling query failed: {}", e),
+                    }
+                }
+                _ = shutdown.recv() => {
+                    log::warn!("worker shut down");
+                }
+            }
+            // Release the slot only after the query is handled.
+            drop(permit);
+        });
+    }
+}
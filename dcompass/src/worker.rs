@@ -13,39 +13,199 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use crate::{
+    acl::Acl,
+    batch::{send_batch, Peer, BATCH},
+    history::Entry,
+    parser::{AclAction, OverflowPolicy},
+    rrl::{Rrl, Verdict},
+    stats::Stats,
+};
 use anyhow::Result;
-use bytes::Bytes;
-use domain::base::Message;
-use droute::{builders::RuneScript, QueryContext, Router};
+use bytes::{Bytes, BytesMut};
+use domain::base::{iana::Rcode, name::ToDname, Message, MessageBuilder};
+use droute::{
+    builders::RuneScript,
+    privacy,
+    truncation::{client_limit, fit, truncate},
+    ClientInfo, Listener, Router,
+};
 use log::*;
-use std::{net::SocketAddr, sync::Arc};
-use tokio::net::UdpSocket;
+use std::{
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        OwnedSemaphorePermit, Semaphore,
+    },
+};
+
+/// Channel of the responses waiting to be sent back
+pub type Responses = UnboundedSender<(Message<Bytes>, Peer)>;
+
+/// Limits on the queries handled, applied before they are resolved
+pub struct Limits {
+    pub inflight: Arc<Semaphore>,
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+    pub acl: Acl,
+    pub rrl: Option<Rrl>,
+    /// Maximum size of UDP responses regardless of the clients' EDNS buffer size
+    pub max_response_size: u16,
+}
+
+/// Admit an incoming packet if the client is allowed and there is room for it.
+/// Otherwise, return the response per ACL action or overflow policy, or `None` if it should be dropped.
+pub fn admit(
+    limits: &Limits,
+    stats: &Stats,
+    buf: &Bytes,
+    src: SocketAddr,
+) -> std::result::Result<OwnedSemaphorePermit, Option<Message<Bytes>>> {
+    stats.received.fetch_add(1, Ordering::Relaxed);
+
+    if !limits.acl.allows(src.ip()) {
+        stats.denied.fetch_add(1, Ordering::Relaxed);
+        debug!("query from {} denied by ACL", privacy::client(src));
+        return Err(match limits.acl.action {
+            AclAction::Refuse => reply(buf.clone(), Rcode::Refused),
+            AclAction::Drop => None,
+        });
+    }
+
+    // Apply backpressure once we have reached the maximum number of queries in flight.
+    limits.inflight.clone().try_acquire_owned().map_err(|_| {
+        stats.overflowed.fetch_add(1, Ordering::Relaxed);
+        debug!(
+            "too many queries in flight, query from {} overflowed",
+            privacy::client(src)
+        );
+        overflow_reply(limits.overflow, buf.clone())
+    })
+}
+
+/// Resolve a single incoming packet into the response, or `None` if it should be silently dropped.
+#[tracing::instrument(name = "query", skip_all, fields(src = %privacy::client(src)))]
+pub async fn resolve(
+    router: &Router<RuneScript>,
+    stats: &Stats,
+    buf: Bytes,
+    src: SocketAddr,
+    listener: Listener,
+    encrypted: bool,
+) -> Option<Message<Bytes>> {
+    let start = Instant::now();
+    // Responses are fitted for UDP clients only after the RRL is applied.
+    let resp = router
+        .resolve_raw(
+            buf,
+            ClientInfo {
+                addr: src,
+                udp_limit: None,
+                listener,
+                encrypted,
+            },
+        )
+        .await?;
+    if resp.header().rcode() == Rcode::ServFail {
+        stats.servfail.fetch_add(1, Ordering::Relaxed);
+    }
+    if let Some(slos) = &stats.slos {
+        if let Some(question) = resp.first_question() {
+            slos.record(&question.qname().to_bytes(), start.elapsed());
+        }
+    }
+    if let Some(top_k) = &stats.top_k {
+        top_k.record(src.ip(), &resp);
+    }
+    if let Some(history) = &stats.history {
+        if let Some(entry) = Entry::new(src, &resp, start.elapsed()) {
+            history.record(entry);
+        }
+    }
+    Some(resp)
+}
 
 /// Handle a single incoming packet
 pub async fn worker(
     router: Arc<Router<RuneScript>>,
-    socket: Arc<UdpSocket>,
+    limits: Arc<Limits>,
+    stats: Arc<Stats>,
+    responses: Responses,
     buf: Bytes,
-    src: SocketAddr,
+    src: Peer,
 ) -> Result<()> {
-    socket
-        .send_to(
-            router
-                .resolve(
-                    Message::from_octets(buf)?,
-                    Some(QueryContext { ip: src.ip() }),
-                )
-                .await?
-                .as_slice(),
-            src,
-        )
-        .await
-        .unwrap_or_else(|e| {
-            warn!("failed to send back response: {}", e);
-            0
-        });
+    let (limit, edns) = client_limit(&buf);
+    let limit = limit.min(limits.max_response_size);
+    let mut resp = match resolve(&router, &stats, buf, src.addr, Listener::Udp, false).await {
+        Some(resp) => resp,
+        None => return Ok(()),
+    };
+    if let Some(rrl) = &limits.rrl {
+        match rrl.check(&resp, src.addr.ip()) {
+            Verdict::Send => (),
+            Verdict::Slip => {
+                stats.slipped.fetch_add(1, Ordering::Relaxed);
+                debug!("response to {} slipped by RRL", src);
+                resp = match truncate(&resp, edns.then_some(limit)) {
+                    Some(resp) => resp,
+                    None => return Ok(()),
+                };
+            }
+            Verdict::Drop => {
+                stats.rate_limited.fetch_add(1, Ordering::Relaxed);
+                debug!("response to {} dropped by RRL", src);
+                return Ok(());
+            }
+        }
+    }
+    // Never send datagrams larger than what the client can take.
+    let resp = match fit(resp, limit, edns) {
+        Some(resp) => resp,
+        None => return Ok(()),
+    };
+    if responses.send((resp, src)).is_err() {
+        warn!("failed to send back response: responder has gone away");
+    }
+    Ok(())
+}
 
-    info!("response completed. Sent back to {} successfully.", src);
+/// Send back the responses queued in batches
+pub async fn responder(socket: Arc<UdpSocket>, mut rx: UnboundedReceiver<(Message<Bytes>, Peer)>) {
+    while let Some(first) = rx.recv().await {
+        let mut pkts = vec![first];
+        while pkts.len() < BATCH {
+            match rx.try_recv() {
+                Ok(pkt) => pkts.push(pkt),
+                Err(_) => break,
+            }
+        }
+        send_batch(&socket, &pkts).await;
+    }
+}
 
-    Ok(())
+// Create the response to a query that cannot be handled because too many queries are in flight.
+fn overflow_reply(policy: OverflowPolicy, buf: Bytes) -> Option<Message<Bytes>> {
+    match policy {
+        OverflowPolicy::Drop => None,
+        OverflowPolicy::ServFail => reply(buf, Rcode::ServFail),
+        OverflowPolicy::Refused => reply(buf, Rcode::Refused),
+    }
+}
+
+// Create an empty response to a query that is not resolved.
+fn reply(buf: Bytes, rcode: Rcode) -> Option<Message<Bytes>> {
+    // Malformed queries are not worth a response.
+    let query = Message::from_octets(buf).ok()?;
+    Some(
+        MessageBuilder::from_target(BytesMut::with_capacity(query.as_slice().len()))
+            .ok()?
+            .start_answer(&query, rcode)
+            .ok()?
+            .into_message(),
+    )
 }
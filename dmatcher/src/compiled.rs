@@ -0,0 +1,341 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Compiled domain matcher, matching right on a compact binary form of the rule set, which loads without parsing and takes a fraction of the memory.
+//!
+//! Layout, with integers in little endian:
+//!
+//! - Header: the magic `DMTRIE\0\x01`, the number of nodes (`u32`), and the number of edges (`u32`)
+//! - Nodes: the index of the first edge and the number of edges of each node (`u32` each), with the root first
+//! - Edges: the offset and the length of the label (`u32` each), and the index of the node it leads to (`u32`).
+//!   Edges of the same node are contiguous and sorted by their labels.
+//! - Labels: lowercased labels the edges refer to
+
+use crate::{
+    domain::LevelNode,
+    filter::{self, Filter},
+};
+use bytes::Bytes;
+use domain::base::{
+    name::{Label, OwnedLabel},
+    Dname,
+};
+use std::{cmp::Ordering, fmt, sync::Arc};
+
+const MAGIC: &[u8; 8] = b"DMTRIE\x00\x01";
+const HEADER_LEN: usize = 16;
+const NODE_LEN: usize = 8;
+const EDGE_LEN: usize = 12;
+
+/// The data given is not a valid compiled rule set.
+#[derive(Debug)]
+pub struct FormatError(&'static str);
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid compiled domain list: {}", self.0)
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+// Encode the nodes given by their edges, i.e. the labels and the indices of the nodes they lead to, with the root first.
+pub(crate) fn encode(nodes: Vec<Vec<(Vec<u8>, u32)>>) -> Vec<u8> {
+    let edges: usize = nodes.iter().map(|n| n.len()).sum();
+    let labels_start = HEADER_LEN + nodes.len() * NODE_LEN + edges * EDGE_LEN;
+    let mut buf = Vec::with_capacity(labels_start);
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&(nodes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(edges as u32).to_le_bytes());
+
+    let mut first = 0;
+    for node in &nodes {
+        buf.extend_from_slice(&(first as u32).to_le_bytes());
+        buf.extend_from_slice(&(node.len() as u32).to_le_bytes());
+        first += node.len();
+    }
+
+    let mut labels = Vec::new();
+    for mut node in nodes {
+        node.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        for (label, next) in node {
+            buf.extend_from_slice(&(labels.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&(label.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&next.to_le_bytes());
+            labels.extend_from_slice(&label);
+        }
+    }
+    buf.extend_from_slice(&labels);
+    buf
+}
+
+/// Domain matcher on a rule set compiled by `Domain::compile`, matching the same way.
+#[derive(Clone)]
+pub struct CompiledDomain {
+    buf: Bytes,
+    nodes: usize,
+    edges: usize,
+}
+
+impl CompiledDomain {
+    /// Whether the data is in the compiled form, judging by the magic only.
+    pub fn is_compiled(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    /// Load the rule set compiled, checking that it is well-formed so that matching never goes out of it.
+    pub fn from_bytes(buf: Bytes) -> Result<Self, FormatError> {
+        if !Self::is_compiled(&buf) || buf.len() < HEADER_LEN {
+            return Err(FormatError("bad header"));
+        }
+        let mut compiled = Self {
+            buf,
+            nodes: 0,
+            edges: 0,
+        };
+        compiled.nodes = compiled.u32_at(8);
+        compiled.edges = compiled.u32_at(12);
+        let labels = compiled
+            .nodes
+            .checked_mul(NODE_LEN)
+            .zip(compiled.edges.checked_mul(EDGE_LEN))
+            .and_then(|(n, e)| n.checked_add(e)?.checked_add(HEADER_LEN))
+            .filter(|l| *l <= compiled.buf.len())
+            .ok_or(FormatError("truncated"))?;
+        if compiled.nodes == 0 {
+            return Err(FormatError("no root"));
+        }
+        // The rule set compiled is never empty, which would match everything.
+        if compiled.nodes == 1 {
+            return Err(FormatError("no rule"));
+        }
+        for i in 0..compiled.nodes {
+            let (first, count) = compiled.node(i);
+            if first
+                .checked_add(count)
+                .map_or(true, |e| e > compiled.edges)
+            {
+                return Err(FormatError("edges out of range"));
+            }
+        }
+        for i in 0..compiled.edges {
+            let (offset, len, next) = compiled.edge(i);
+            if offset
+                .checked_add(len)
+                .and_then(|e| e.checked_add(labels))
+                .map_or(true, |e| e > compiled.buf.len())
+                || next >= compiled.nodes
+            {
+                return Err(FormatError("labels or nodes out of range"));
+            }
+        }
+        // Nodes are numbered breadth-first, which leaves no cycle to walk into.
+        for i in 0..compiled.nodes {
+            let (first, count) = compiled.node(i);
+            if (first..first + count).any(|e| compiled.edge(e).2 <= i) {
+                return Err(FormatError("nodes out of order"));
+            }
+        }
+        Ok(compiled)
+    }
+
+    /// Size of the compiled rule set in bytes, which is about all the memory it takes.
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn u32_at(&self, pos: usize) -> usize {
+        u32::from_le_bytes(self.buf[pos..pos + 4].try_into().unwrap()) as usize
+    }
+
+    // The first edge and the number of edges of the node.
+    fn node(&self, i: usize) -> (usize, usize) {
+        let pos = HEADER_LEN + i * NODE_LEN;
+        (self.u32_at(pos), self.u32_at(pos + 4))
+    }
+
+    // The offset and the length of the label, and the node the edge leads to.
+    fn edge(&self, i: usize) -> (usize, usize, usize) {
+        let pos = HEADER_LEN + self.nodes * NODE_LEN + i * EDGE_LEN;
+        (self.u32_at(pos), self.u32_at(pos + 4), self.u32_at(pos + 8))
+    }
+
+    fn label(&self, offset: usize, len: usize) -> &[u8] {
+        let start = HEADER_LEN + self.nodes * NODE_LEN + self.edges * EDGE_LEN + offset;
+        &self.buf[start..start + len]
+    }
+
+    // The node the edge labeled `label`, lowercased, of the node given leads to, if any.
+    fn next(&self, (first, count): (usize, usize), label: &[u8]) -> Option<usize> {
+        let (mut lo, mut hi) = (first, first + count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let (offset, len, next) = self.edge(mid);
+            match self.label(offset, len).cmp(label) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Some(next),
+            }
+        }
+        None
+    }
+
+    /// Number of rules in effect, as told by `Domain::rules`.
+    pub fn rules(&self) -> usize {
+        (0..self.nodes).filter(|i| self.node(*i).1 == 0).count()
+    }
+
+    /// Add the rules to the filter, which tells the domains matching none of them.
+    pub fn fill(&self, filter: &mut Filter) {
+        let mut stack = vec![(0, filter::ROOT)];
+        while let Some((node, hash)) = stack.pop() {
+            let (first, count) = self.node(node);
+            if count == 0 {
+                filter.insert(hash);
+            }
+            stack.extend((first..first + count).map(|i| {
+                let (offset, len, next) = self.edge(i);
+                (next, filter::step(hash, self.label(offset, len)))
+            }));
+        }
+    }
+
+    // Insert the rules into the trie, as `Domain::insert` does with the domains they were compiled from.
+    pub(crate) fn merge_into(&self, root: &mut LevelNode) {
+        let mut stack = vec![(0, Vec::new())];
+        while let Some((node, path)) = stack.pop() {
+            let (first, count) = self.node(node);
+            if count == 0 {
+                let mut ptr = &mut *root;
+                for label in path {
+                    ptr = Arc::make_mut(
+                        ptr.next_lvs
+                            .entry(label)
+                            .or_insert_with(|| Arc::new(LevelNode::new())),
+                    );
+                }
+                ptr.end = true;
+                continue;
+            }
+            for i in first..first + count {
+                let (offset, len, next) = self.edge(i);
+                // Labels longer than 63 bytes are never compiled out of names.
+                if let Ok(label) = Label::from_slice(self.label(offset, len)) {
+                    let mut path = path.clone();
+                    path.push(Arc::new(OwnedLabel::from_label(label)));
+                    stack.push((next, path));
+                }
+            }
+        }
+    }
+
+    /// Match the domain against the rule set compiled, the same way `Domain::matches` does.
+    pub fn matches(&self, domain: &Dname<Bytes>) -> bool {
+        let mut node = self.node(0);
+        for lv in domain.iter().rev() {
+            // We have reached the end of our rule set, breaking
+            if node.1 == 0 {
+                break;
+            }
+            node = match self.next(node, &lv.as_slice().to_ascii_lowercase()) {
+                Some(next) => self.node(next),
+                None => return false,
+            };
+        }
+        node.1 == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CompiledDomain;
+    use crate::domain::Domain;
+    use bytes::Bytes;
+    use domain::base::Dname;
+    use std::str::FromStr;
+
+    macro_rules! dname {
+        ($s:expr) => {
+            Dname::from_str($s).unwrap()
+        };
+    }
+
+    #[test]
+    fn matches() {
+        let mut matcher = Domain::new();
+        matcher.insert(&dname!("tejia.taobao.com"));
+        matcher.insert(&dname!("temai.m.taobao.com"));
+        matcher.insert(&dname!("apple.cn"));
+        matcher.insert(&dname!("Apple.COM"));
+        let compiled = CompiledDomain::from_bytes(Bytes::from(matcher.compile().unwrap())).unwrap();
+
+        for name in [
+            "store.apple.com",
+            "STORE.APPLE.COM.",
+            "apple.cn",
+            "a.temai.m.taobao.com",
+            "tejia.taobao.com",
+            "m.taobao.com",
+            "taobao.com",
+            "baidu.com",
+            "cn",
+        ] {
+            assert_eq!(
+                compiled.matches(&dname!(name)),
+                matcher.matches(&dname!(name)),
+                "{}",
+                name
+            );
+        }
+        assert!(compiled.matches(&dname!("store.apple.com")));
+        assert!(!compiled.matches(&dname!("baidu.com")));
+    }
+
+    #[test]
+    fn malformed() {
+        let mut matcher = Domain::new();
+        matcher.insert(&dname!("apple.com"));
+        let compiled = matcher.compile().unwrap();
+        assert!(CompiledDomain::from_bytes(Bytes::from_static(b"apple.com\n")).is_err());
+        // Nothing to match, which the root alone would match everything with
+        assert!(Domain::new().compile().is_none());
+        let empty = super::encode(vec![Vec::new()]);
+        assert!(CompiledDomain::from_bytes(Bytes::from(empty)).is_err());
+        for len in 0..compiled.len() {
+            assert!(CompiledDomain::from_bytes(Bytes::copy_from_slice(&compiled[..len])).is_err());
+        }
+        // A node out of range
+        let mut bad = compiled.clone();
+        let edge = super::HEADER_LEN + 4 * super::NODE_LEN;
+        bad[edge + 8..edge + 12].copy_from_slice(&100u32.to_le_bytes());
+        assert!(CompiledDomain::from_bytes(Bytes::from(bad)).is_err());
+    }
+
+    #[test]
+    fn merge() {
+        let mut matcher = Domain::new();
+        matcher.insert(&dname!("apple.com"));
+        matcher.insert(&dname!("tejia.taobao.com"));
+        let compiled = CompiledDomain::from_bytes(Bytes::from(matcher.compile().unwrap())).unwrap();
+
+        let mut merged = Domain::new();
+        merged.insert(&dname!("baidu.com"));
+        merged.merge(&compiled);
+        for name in ["store.APPLE.com", "tejia.taobao.com", "www.baidu.com"] {
+            assert!(merged.matches(&dname!(name)), "{}", name);
+        }
+        assert!(!merged.matches(&dname!("taobao.com")));
+    }
+}
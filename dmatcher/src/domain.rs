@@ -21,21 +21,62 @@
 //! -  No dependencies
 //!
 
+use crate::{
+    compiled::CompiledDomain,
+    filter::{self, Filter},
+};
 use bytes::Bytes;
-use domain::base::{name::OwnedLabel, Dname};
-use std::{collections::HashMap, sync::Arc};
+use domain::base::{
+    name::{DnameBuilder, Label, OwnedLabel},
+    Dname,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
 
+// Nodes are shared between the clones of the matcher and copied on write, so that a clone updated costs only the nodes changed.
 #[derive(PartialEq, Clone)]
-struct LevelNode {
-    next_lvs: HashMap<Arc<OwnedLabel>, LevelNode>,
+pub(crate) struct LevelNode {
+    pub(crate) next_lvs: HashMap<Arc<OwnedLabel>, Arc<LevelNode>>,
+    // Whether a domain inserted ends here, which keeps the node when the domains below are removed.
+    pub(crate) end: bool,
 }
 
 impl LevelNode {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             next_lvs: HashMap::new(),
+            end: false,
         }
     }
+
+    // Remove the domain of the labels given below the node, pruning the nodes left with neither domains nor children.
+    // Returns whether it was inserted.
+    fn remove(&mut self, labels: &[&Label]) -> bool {
+        let (lv, rest) = match labels.split_first() {
+            Some(split) => split,
+            None => return std::mem::replace(&mut self.end, false),
+        };
+        let key = (*lv).to_owned();
+        let removed = match self.next_lvs.get_mut(&key) {
+            Some(next) => Arc::make_mut(next).remove(rest),
+            None => return false,
+        };
+        if removed && self.next_lvs[&key].next_lvs.is_empty() && !self.next_lvs[&key].end {
+            self.next_lvs.remove(&key);
+        }
+        removed
+    }
+}
+
+// The name of the labels from the root down, which are valid as they are taken from names.
+fn dname(labels: &[&OwnedLabel]) -> Option<Dname<Bytes>> {
+    let mut builder = DnameBuilder::new_bytes();
+    for label in labels.iter().rev() {
+        builder.append_label(label.as_slice()).ok()?;
+    }
+    builder.into_dname().ok()
 }
 
 /// Domain matcher algorithm
@@ -58,6 +99,11 @@ impl Domain {
         }
     }
 
+    /// Whether no rule has been inserted.
+    pub fn is_empty(&self) -> bool {
+        self.root.next_lvs.is_empty()
+    }
+
     /// Pass in a string containing `\n` and get all domains inserted.
     pub fn insert_multi(&mut self, domain: &[Dname<Bytes>]) {
         // This gets rid of empty substrings for stability reasons. See also https://github.com/LEXUGE/dcompass/issues/33.
@@ -70,15 +116,26 @@ impl Domain {
     pub fn insert(&mut self, domain: &Dname<Bytes>) {
         let mut ptr = &mut self.root;
         for lv in domain.iter().rev() {
-            ptr = ptr
-                .next_lvs
-                .entry(Arc::new(lv.to_owned()))
-                .or_insert_with(LevelNode::new);
+            ptr = Arc::make_mut(
+                ptr.next_lvs
+                    .entry(Arc::new(lv.to_owned()))
+                    .or_insert_with(|| Arc::new(LevelNode::new())),
+            );
         }
+        ptr.end = true;
+    }
+
+    /// Remove a domain inserted, as if the matcher were built without it. Domains not inserted are ignored.
+    pub fn remove(&mut self, domain: &Dname<Bytes>) {
+        self.root.remove(&domain.iter().rev().collect::<Vec<_>>());
     }
 
     /// Match the domain against inserted domain rules. If `apple.com` is inserted, then `www.apple.com` and `stores.www.apple.com` is considered as matched while `apple.cn` is not.
+    /// Nothing matches the empty matcher, including the one emptied by removing the domains inserted, unless the root is inserted.
     pub fn matches(&self, domain: &Dname<Bytes>) -> bool {
+        if self.is_empty() {
+            return self.root.end;
+        }
         let mut ptr = &self.root;
         for lv in domain.iter().rev() {
             // We have reached the end of our rule set, breaking
@@ -99,6 +156,84 @@ impl Domain {
         // If there are still rules left but we have reached the end of our test case, then it is not a match.
         // e.g. apple.com is not a match for apps.apple.com
     }
+
+    /// Number of rules in effect, i.e. of the ends of the rule set, as rules below other rules are covered by them.
+    pub fn rules(&self) -> usize {
+        if self.is_empty() {
+            return self.root.end.into();
+        }
+        let mut stack = vec![&self.root];
+        let mut rules = 0;
+        while let Some(node) = stack.pop() {
+            if node.next_lvs.is_empty() {
+                rules += 1;
+            }
+            stack.extend(node.next_lvs.values().map(|n| &**n));
+        }
+        rules
+    }
+
+    /// Add the rules to the filter, which tells the domains matching none of them.
+    pub fn fill(&self, filter: &mut Filter) {
+        if self.is_empty() && !self.root.end {
+            return;
+        }
+        let mut stack = vec![(&self.root, filter::ROOT)];
+        while let Some((node, hash)) = stack.pop() {
+            if node.next_lvs.is_empty() {
+                filter.insert(hash);
+            }
+            stack.extend(
+                node.next_lvs
+                    .iter()
+                    .map(|(label, next)| (&**next, filter::step(hash, label.as_slice()))),
+            );
+        }
+    }
+
+    /// The domains inserted and not removed since.
+    pub fn names(&self) -> Vec<Dname<Bytes>> {
+        let mut names = Vec::new();
+        let mut stack = vec![(&self.root, Vec::new())];
+        while let Some((node, path)) = stack.pop() {
+            if node.end {
+                names.extend(dname(&path));
+            }
+            for (label, next) in &node.next_lvs {
+                let mut path = path.clone();
+                path.push(&**label);
+                stack.push((&**next, path));
+            }
+        }
+        names
+    }
+
+    /// Insert the rules of the rule set compiled, as if the domains it was compiled from were inserted.
+    pub fn merge(&mut self, compiled: &CompiledDomain) {
+        compiled.merge_into(&mut self.root);
+    }
+
+    /// Compile the rule set into the binary form loaded by `CompiledDomain`, which matches the same way.
+    /// `None` if no rule has been inserted, as the empty rule set matches everything, which is not worth compiling.
+    pub fn compile(&self) -> Option<Vec<u8>> {
+        if self.is_empty() {
+            return None;
+        }
+        // Nodes are numbered in the order they are visited breadth-first, with the root as 0.
+        let mut nodes = Vec::new();
+        let mut queue = VecDeque::from([&self.root]);
+        let mut visited = 1;
+        while let Some(node) = queue.pop_front() {
+            let mut edges = Vec::with_capacity(node.next_lvs.len());
+            for (label, next) in &node.next_lvs {
+                edges.push((label.as_slice().to_ascii_lowercase(), visited));
+                queue.push_back(next);
+                visited += 1;
+            }
+            nodes.push(edges);
+        }
+        Some(crate::compiled::encode(nodes))
+    }
 }
 
 #[cfg(test)]
@@ -143,4 +278,49 @@ mod tests {
         assert_eq!(matcher.matches(&dname!("store.apple.com.")), true);
         assert_eq!(matcher.matches(&dname!("baidu.com")), false);
     }
+
+    #[test]
+    fn remove() {
+        let mut matcher = Domain::new();
+        matcher.insert_multi(&[
+            dname!("apple.com"),
+            dname!("www.apple.com"),
+            dname!("tejia.taobao.com"),
+        ]);
+        let old = matcher.clone();
+        assert_eq!(matcher.matches(&dname!("store.apple.com")), false);
+
+        // The domains above are left, and match again as they would have without the ones removed.
+        matcher.remove(&dname!("www.apple.com"));
+        assert_eq!(matcher.matches(&dname!("store.apple.com")), true);
+        // Nodes on the way are pruned.
+        matcher.remove(&dname!("tejia.taobao.com"));
+        matcher.remove(&dname!("m.taobao.com"));
+        assert!(
+            matcher.root == {
+                let mut rebuilt = Domain::new();
+                rebuilt.insert(&dname!("apple.com"));
+                rebuilt.root
+            }
+        );
+        // Clones are left untouched.
+        assert_eq!(old.matches(&dname!("tejia.taobao.com")), true);
+        assert_eq!(old.matches(&dname!("store.apple.com")), false);
+        let mut names = old.names();
+        names.sort_by_key(|n| n.to_string());
+        assert_eq!(
+            names,
+            [
+                dname!("apple.com"),
+                dname!("tejia.taobao.com"),
+                dname!("www.apple.com")
+            ]
+        );
+
+        // Emptied, it matches nothing.
+        matcher.remove(&dname!("apple.com"));
+        assert!(matcher.is_empty());
+        assert_eq!(matcher.matches(&dname!("store.apple.com")), false);
+        assert_eq!(matcher.rules(), 0);
+    }
 }
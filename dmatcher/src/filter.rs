@@ -0,0 +1,178 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Bloom filter in front of the domain matchers, telling with a few bit probes per label that a domain matches none of the rules,
+//! which is the common case for huge blocklists.
+//!
+//! The filter holds the rules, i.e. the paths from the root to the ends of the rule set, hashed label by label from the root
+//! as the matchers walk them. A domain may only match if one of the paths it walks is in the filter.
+
+use bytes::Bytes;
+use domain::base::Dname;
+
+// Bits per rule and probes per hash, which make about 1% of false positives.
+const BITS_PER_RULE: usize = 10;
+const PROBES: u64 = 7;
+
+// Hash of the path of no label, i.e. the root (FNV-1a offset basis).
+pub(crate) const ROOT: u64 = 0xcbf2_9ce4_8422_2325;
+
+// Hash of the path extended by the label, with the label lowercased as the matchers compare labels regardless of their case.
+pub(crate) fn step(hash: u64, label: &[u8]) -> u64 {
+    // The length goes first so that paths split differently hash differently.
+    std::iter::once(label.len() as u8)
+        .chain(label.iter().map(|b| b.to_ascii_lowercase()))
+        .fold(hash, |h, b| {
+            (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+// The bits of the hash in a filter of `words` words, derived by double hashing from the hash mixed (splitmix64 finalizer).
+fn probes(hash: u64, words: usize) -> impl Iterator<Item = usize> {
+    let mut h = hash;
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^= h >> 31;
+    let (h1, h2) = (h, (h >> 32) | 1);
+    let len = words as u64 * 64;
+    (0..PROBES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+}
+
+/// Bloom filter of the rules of domain matchers
+#[derive(Clone)]
+pub struct Filter {
+    bits: Vec<u64>,
+    // Rules the filter is sized for, and the ones inserted
+    capacity: usize,
+    len: usize,
+}
+
+impl Filter {
+    /// Create an empty filter sized for the number of rules given, as told by `rules()` of the matchers.
+    pub fn new(rules: usize) -> Self {
+        Self {
+            bits: vec![0; rules * BITS_PER_RULE / 64 + 1],
+            capacity: rules,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, hash: u64) {
+        self.len += 1;
+        for bit in probes(hash, self.bits.len()) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Add the domain inserted into the matchers after they were filled in, as a rule of its own.
+    pub fn insert_domain(&mut self, domain: &Dname<Bytes>) {
+        let hash = domain
+            .iter()
+            .rev()
+            .fold(ROOT, |hash, lv| step(hash, lv.as_slice()));
+        self.insert(hash);
+    }
+
+    /// Whether the filter holds half as many rules again as it is sized for, by when false positives are several times as likely,
+    /// and it is better filled in again. Refilling then takes linear time overall, as the rules grow geometrically in between.
+    pub fn is_full(&self) -> bool {
+        self.len > self.capacity + self.capacity / 2
+    }
+
+    fn contains(&self, hash: u64) -> bool {
+        probes(hash, self.bits.len()).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Whether the domain may match any rule of the matchers filled in. `false` means it surely matches none.
+    pub fn may_match(&self, domain: &Dname<Bytes>) -> bool {
+        let mut hash = ROOT;
+        if self.contains(hash) {
+            return true;
+        }
+        for lv in domain.iter().rev() {
+            hash = step(hash, lv.as_slice());
+            if self.contains(hash) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Size of the filter in bytes.
+    pub fn size(&self) -> usize {
+        self.bits.len() * 8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filter;
+    use crate::{compiled::CompiledDomain, domain::Domain};
+    use bytes::Bytes;
+    use domain::base::Dname;
+    use std::str::FromStr;
+
+    macro_rules! dname {
+        ($s:expr) => {
+            Dname::from_str($s).unwrap()
+        };
+    }
+
+    #[test]
+    fn filter() {
+        let mut matcher = Domain::new();
+        for i in 0..1000 {
+            matcher.insert(&dname!(&format!("ads{}.example.com", i)));
+        }
+        matcher.insert(&dname!("Tracker.NET"));
+        let compiled = CompiledDomain::from_bytes(Bytes::from(matcher.compile().unwrap())).unwrap();
+        assert_eq!(matcher.rules(), 1001);
+        assert_eq!(compiled.rules(), 1001);
+
+        let mut filter = Filter::new(matcher.rules());
+        matcher.fill(&mut filter);
+        let mut compiled_filter = Filter::new(compiled.rules());
+        compiled.fill(&mut compiled_filter);
+        assert_eq!(filter.bits, compiled_filter.bits);
+
+        // No false negative
+        assert!(filter.may_match(&dname!("a.ads42.example.com")));
+        assert!(filter.may_match(&dname!("cdn.tracker.net")));
+        // Few false positives
+        let positives = (0..1000)
+            .filter(|i| filter.may_match(&dname!(&format!("site{}.example.org", i))))
+            .count();
+        assert!(positives < 50, "{}", positives);
+
+        // The empty matcher matches everything.
+        let mut filter = Filter::new(0);
+        Domain::new().fill(&mut filter);
+        assert!(filter.may_match(&dname!("example.org")));
+    }
+
+    #[test]
+    fn inserted_domains() {
+        let mut matcher = Domain::new();
+        matcher.insert(&dname!("apple.com"));
+        let mut filter = Filter::new(matcher.rules());
+        matcher.fill(&mut filter);
+        assert!(!filter.is_full());
+
+        filter.insert_domain(&dname!("ADS.example.net"));
+        assert!(filter.may_match(&dname!("x.ads.example.net")));
+        assert!(filter.may_match(&dname!("store.apple.com")));
+        assert!(filter.is_full());
+    }
+}
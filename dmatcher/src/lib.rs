@@ -17,4 +17,6 @@
 #![deny(unsafe_code)]
 //! This is a library providing a set of domain and IP address matching algorithms.
 
+pub mod compiled;
 pub mod domain;
+pub mod filter;
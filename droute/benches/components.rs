@@ -0,0 +1,129 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use bytes::{Bytes, BytesMut};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use domain::{
+    base::{Dname, Message, MessageBuilder, Rtype},
+    rdata::A,
+};
+use droute::{
+    builders::*,
+    mock::Server,
+    utils::{Domain, IpCidr},
+    AsyncTryInto, CacheMode, Upstreams,
+};
+use once_cell::sync::Lazy;
+use std::{net::IpAddr, str::FromStr};
+use tokio::net::UdpSocket;
+
+static DUMMY_MSG: Lazy<Message<BytesMut>> = Lazy::new(|| {
+    let name = Dname::<Bytes>::from_str("cloudflare-dns.com").unwrap();
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1232)).unwrap();
+    let header = builder.header_mut();
+    header.set_id(0);
+    header.set_qr(true);
+    let mut builder = builder.question();
+    builder.push((&name, Rtype::A)).unwrap();
+    let mut builder = builder.answer();
+    builder
+        .push((&name, 10, A::from_octets(1, 1, 1, 1)))
+        .unwrap();
+    Message::from_octets(BytesMut::from(builder.as_slice())).unwrap()
+});
+
+static QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
+    let name = Dname::<Bytes>::from_str("cloudflare-dns.com").unwrap();
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1232)).unwrap();
+    builder.header_mut().set_id(0);
+    let mut builder = builder.question();
+    builder.push((&name, Rtype::A)).unwrap();
+    builder.into_message()
+});
+
+fn bench_matchers(c: &mut Criterion) {
+    let mut domain = Domain::new();
+    domain.add_file("../data/china.txt").unwrap();
+    let mut ipcidr = IpCidr::new();
+    ipcidr.add_file("../data/ipcn.txt").unwrap();
+
+    let hit = Dname::<Bytes>::from_str("www.baidu.com").unwrap();
+    let miss = Dname::<Bytes>::from_str("cloudflare-dns.com").unwrap();
+    c.bench_function("domain_matcher", |b| {
+        b.iter(|| {
+            black_box(domain.contains(&hit));
+            black_box(domain.contains(&miss));
+        })
+    });
+
+    let hit: IpAddr = "114.114.114.114".parse().unwrap();
+    let miss: IpAddr = "1.1.1.1".parse().unwrap();
+    c.bench_function("ipcidr_matcher", |b| {
+        b.iter(|| {
+            black_box(ipcidr.contains(hit));
+            black_box(ipcidr.contains(miss));
+        })
+    });
+}
+
+fn bench_upstreams(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let mut builder = UpstreamsBuilder::new(4096).unwrap().add_upstream(
+        "hybrid",
+        UpstreamBuilder::Hybrid(HybridBuilder::new().add_tag("mock1").add_tag("mock2")),
+    );
+    for (tag, addr) in [("mock1", "127.0.0.1:53536"), ("mock2", "127.0.0.1:53537")] {
+        let socket = rt.block_on(UdpSocket::bind(addr)).unwrap();
+        rt.spawn(Server::new(socket, vec![0; 1024], None).run(DUMMY_MSG.clone()));
+        builder = builder.add_upstream(
+            tag,
+            UpstreamBuilder::Udp(UdpBuilder {
+                max_pool_size: 256,
+                timeout: 1,
+                ..UdpBuilder::new(addr.parse().unwrap())
+            }),
+        );
+    }
+    let upstreams: Upstreams = rt.block_on(builder.async_try_into()).unwrap();
+
+    // The cache is bypassed on the way in, so that the upstreams are raced every time.
+    c.bench_function("hybrid_resolve", |b| {
+        b.to_async(&rt).iter(|| async {
+            upstreams
+                .send(&"hybrid".into(), &CacheMode::Disabled, &QUERY)
+                .await
+                .unwrap()
+        })
+    });
+
+    // Cache lookup alone, without the overhead of the router and the script.
+    rt.block_on(upstreams.send(&"mock1".into(), &CacheMode::Standard, &QUERY))
+        .unwrap();
+    c.bench_function("cache_hit", |b| {
+        b.to_async(&rt).iter(|| async {
+            upstreams
+                .send(&"mock1".into(), &CacheMode::Standard, &QUERY)
+                .await
+                .unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, bench_matchers, bench_upstreams);
+criterion_main!(benches);
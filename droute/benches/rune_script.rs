@@ -55,10 +55,9 @@ async fn create_router<T: ScriptBackend>(script_builder: impl ScriptBuilder<T>)
         UpstreamsBuilder::new(4096).unwrap().add_upstream(
             "mock",
             UpstreamBuilder::Udp(UdpBuilder {
-                addr: "127.0.0.1:53533".parse().unwrap(),
                 max_pool_size: 256,
                 timeout: 1,
-                ratelimit: None,
+                ..UdpBuilder::new("127.0.0.1:53533".parse().unwrap())
             }),
         ),
     )
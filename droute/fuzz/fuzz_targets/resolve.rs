@@ -0,0 +1,75 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Feed arbitrary packets through the router the way the server does. Router should never panic, and should always come up with a response.
+
+#![no_main]
+
+use bytes::Bytes;
+use domain::base::Message;
+use droute::{
+    builders::*, errors::ScriptError, AnyPolicy, AsyncTryInto, NativeScript, QueryContext, Router,
+    Upstreams,
+};
+use futures::future::BoxFuture;
+use libfuzzer_sys::fuzz_target;
+use once_cell::sync::Lazy;
+use tokio::runtime::{Builder, Runtime};
+
+type Echo = fn(
+    Upstreams,
+    Message<Bytes>,
+    Option<QueryContext>,
+) -> BoxFuture<'static, Result<Message<Bytes>, ScriptError>>;
+
+// The script never reaches out to the upstream, so that the fuzzer doesn't depend on network.
+fn echo(
+    _: Upstreams,
+    query: Message<Bytes>,
+    _: Option<QueryContext>,
+) -> BoxFuture<'static, Result<Message<Bytes>, ScriptError>> {
+    Box::pin(async move { Ok(query) })
+}
+
+static RT: Lazy<Runtime> =
+    Lazy::new(|| Builder::new_current_thread().enable_all().build().unwrap());
+
+static ROUTER: Lazy<
+    Router<NativeScript<Echo, BoxFuture<'static, Result<Message<Bytes>, ScriptError>>>>,
+> = Lazy::new(|| {
+    RT.block_on(
+        RouterBuilder::new(
+            NativeScriptBuilder::new(echo as Echo),
+            UpstreamsBuilder::new(1).unwrap().add_upstream(
+                "mock",
+                UdpBuilder {
+                    max_pool_size: 1,
+                    timeout: 1,
+                    ..UdpBuilder::new("127.0.0.1:53533".parse().unwrap())
+                },
+            ),
+        )
+        .any_policy(AnyPolicy::Split)
+        .async_try_into(),
+    )
+    .unwrap()
+});
+
+fuzz_target!(|data: &[u8]| {
+    // Packets shorter than a header are dropped by the server before reaching the router.
+    if let Ok(msg) = Message::from_octets(Bytes::copy_from_slice(data)) {
+        RT.block_on(ROUTER.resolve(msg, None)).unwrap();
+    }
+});
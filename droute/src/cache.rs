@@ -13,18 +13,37 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use self::RecordStatus::*;
-use crate::{Label, MAX_TTL};
+mod clock;
+#[cfg(feature = "redis-cache")]
+mod redis;
+
+#[cfg(feature = "redis-cache")]
+pub use self::redis::RedisCache;
+use self::{clock::Timestamp, RecordStatus::*};
+use crate::{
+    memory::{self, Category, Charge, Reclaim},
+    privacy,
+    router::{
+        script::utils::is_svcb,
+        stats::{CacheCounters, CacheStats},
+    },
+    wire, Label, MAX_TTL,
+};
+use async_trait::async_trait;
 use bytes::Bytes;
 use clru::CLruCache;
-use domain::base::{name::ToDname, Message};
+use domain::{
+    base::{name::ToDname, Message, ParsedDname},
+    rdata::Soa,
+};
 use log::*;
 use std::{
     borrow::Borrow,
     hash::{Hash, Hasher},
+    mem::size_of,
     num::NonZeroUsize,
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
 };
 
 // Code to use (&A, &B) for accessing HashMap, clipped from https://stackoverflow.com/questions/45786717/how-to-implement-hashmap-with-two-keys/45795699#45795699.
@@ -77,7 +96,7 @@ where
 
 #[derive(Clone)]
 pub struct CacheRecord<T> {
-    created_instant: Instant,
+    created: Timestamp,
     content: T,
     ttl: Duration,
 }
@@ -85,7 +104,7 @@ pub struct CacheRecord<T> {
 impl<T: Clone> CacheRecord<T> {
     pub fn new(content: T, ttl: Duration) -> Self {
         Self {
-            created_instant: Instant::now(),
+            created: Timestamp::now(),
             content,
             ttl,
         }
@@ -95,78 +114,246 @@ impl<T: Clone> CacheRecord<T> {
         self.content.clone()
     }
 
+    /// Time elapsed since the record was created.
+    pub fn age(&self) -> Duration {
+        Timestamp::now().saturating_duration_since(self.created)
+    }
+
     pub fn validate(&self) -> bool {
-        Instant::now().saturating_duration_since(self.created_instant) <= self.ttl
+        self.validate_at(Timestamp::now())
+    }
+
+    fn validate_at(&self, now: Timestamp) -> bool {
+        now.saturating_duration_since(self.created) <= self.ttl
     }
 }
 
+// Time the response is cached for, i.e. the least TTL of the answer records, up to `MAX_TTL`.
+// Negative responses without any are cached for the TTL of the SOA record in the authority section capped by its MINIMUM (RFC 2308),
+// or not at all without one.
+pub(crate) fn ttl(msg: &Message<Bytes>) -> Duration {
+    let answer = msg
+        .answer()
+        .ok()
+        .and_then(|records| records.filter_map(|r| r.ok()).map(|r| r.ttl()).min());
+    let ttl = answer.unwrap_or_else(|| {
+        msg.authority()
+            .into_iter()
+            .flat_map(|section| section.limit_to::<Soa<ParsedDname<_>>>())
+            .flatten()
+            .map(|r| r.ttl().min(r.data().minimum()))
+            .min()
+            .unwrap_or(0)
+    });
+    Duration::from_secs(u64::from(ttl.min(MAX_TTL)))
+}
+
+// TTL of the expired records served, per RFC 8767.
+const STALE_TTL: u32 = 30;
+
+// The response with the TTLs counted down by the time it has been cached, so that downstream caches don't keep it beyond its expiry.
+pub(crate) fn count_down(msg: &Message<Bytes>, age: Duration) -> Message<Bytes> {
+    let age = u32::try_from(age.as_secs()).unwrap_or(u32::MAX);
+    wire::map_ttls(msg, |ttl| ttl.saturating_sub(age)).unwrap_or_else(|| msg.clone())
+}
+
+// The expired response with the TTLs set to `STALE_TTL`.
+pub(crate) fn stale(msg: &Message<Bytes>) -> Message<Bytes> {
+    wire::map_ttls(msg, |_| STALE_TTL).unwrap_or_else(|| msg.clone())
+}
+
+/// Status of a cached record
 pub enum RecordStatus<T> {
+    /// The record is within its TTL
     Alive(T),
+    /// The record has outlived its TTL, which may still be served while it is being updated
     Expired(T),
 }
 
-// A LRU cache for responses
+/// A store of the responses from upstreams, keyed by the upstream tag and the query, i.e. [`crate::wire::cache_key`].
+/// Implementations only have to keep the responses and tell whether they have outlived their TTLs. Which responses to cache, their TTLs, and the statistics are handled by `droute`.
+/// Responses looked up are returned with their TTLs counted down by the time they have been cached, e.g. with [`crate::wire::map_ttls`], and expired ones with short TTLs, as clients cache them in turn.
+#[async_trait]
+pub trait Cache: Send + Sync {
+    /// Look up the response to the query from the upstream with the tag given.
+    async fn get(
+        &self,
+        tag: &Label,
+        query: &Message<Bytes>,
+    ) -> Option<RecordStatus<Message<Bytes>>>;
+
+    /// Store the response to the query from the upstream with the tag given, which is alive for `ttl`.
+    async fn put(&self, tag: Label, query: &Message<Bytes>, resp: Message<Bytes>, ttl: Duration);
+
+    /// Remove all the responses stored.
+    async fn purge(&self);
+}
+
+// Each entry carries the memory accounted for it, which is released once it is evicted.
+type Lru = Mutex<CLruCache<(Label, Bytes), (CacheRecord<Message<Bytes>>, Charge)>>;
+
+// Rough bookkeeping overhead of an entry besides the messages and the tag.
+const ENTRY_OVERHEAD: usize = 128;
+
+impl Reclaim for Lru {
+    fn reclaim(&self, bytes: usize) -> usize {
+        let mut lru = self.lock().unwrap();
+        let mut freed = 0;
+        while freed < bytes {
+            match lru.pop_back() {
+                Some((_, (_, charge))) => freed += charge.bytes(),
+                None => break,
+            }
+        }
+        freed
+    }
+}
+
+/// The in-memory LRU cache, which is used unless another one is plugged in.
+pub struct MemoryCache {
+    cache: Arc<Lru>,
+    // Responses to HTTPS and SVCB queries, if they are cached separately.
+    svcb: Option<Arc<Lru>>,
+}
+
+fn new_lru(size: NonZeroUsize) -> Arc<Lru> {
+    let lru = Arc::new(Mutex::new(CLruCache::new(size)));
+    // Cache entries are the first to go when the total memory cap is exceeded.
+    memory::register(Arc::downgrade(&lru) as Weak<dyn Reclaim>);
+    lru
+}
+
+impl MemoryCache {
+    /// Create a cache holding at most `size` responses.
+    pub fn new(size: NonZeroUsize) -> Self {
+        Self {
+            cache: new_lru(size),
+            svcb: None,
+        }
+    }
+
+    /// Cache responses to HTTPS and SVCB queries separately with the capacity given, so that they are not evicted by the far more A and AAAA ones.
+    pub fn with_svcb(mut self, size: NonZeroUsize) -> Self {
+        self.svcb = Some(new_lru(size));
+        self
+    }
+
+    fn lru(&self, query: &Message<Bytes>) -> &Lru {
+        match &self.svcb {
+            Some(svcb) if query.first_question().map_or(false, |q| is_svcb(q.qtype())) => svcb,
+            _ => &self.cache,
+        }
+    }
+}
+
+#[async_trait]
+impl Cache for MemoryCache {
+    async fn get(
+        &self,
+        tag: &Label,
+        query: &Message<Bytes>,
+    ) -> Option<RecordStatus<Message<Bytes>>> {
+        self.lru(query)
+            .lock()
+            .unwrap()
+            .get(&(tag, wire::cache_key(query)) as &dyn KeyPair<Label, Bytes>)
+            .map(|(r, _)| {
+                // Get record only once.
+                if r.validate() {
+                    Alive(count_down(&r.get(), r.age()))
+                } else {
+                    Expired(stale(&r.get()))
+                }
+            })
+    }
+
+    async fn put(&self, tag: Label, query: &Message<Bytes>, resp: Message<Bytes>, ttl: Duration) {
+        let key = wire::cache_key(query);
+        let charge = Charge::new(
+            Category::Cache,
+            key.len()
+                + resp.as_octets().len()
+                + tag.len()
+                + size_of::<CacheRecord<Message<Bytes>>>()
+                + ENTRY_OVERHEAD,
+        );
+        let mut lru = self.lru(query).lock().unwrap();
+        // Clone should be cheap here
+        lru.put((tag, key), (CacheRecord::new(resp, ttl), charge));
+        // Keep at least the response just stored.
+        while memory::excess(Category::Cache) > 0 && lru.len() > 1 {
+            lru.pop_back();
+        }
+    }
+
+    async fn purge(&self) {
+        self.cache.lock().unwrap().clear();
+        if let Some(svcb) = &self.svcb {
+            svcb.lock().unwrap().clear();
+        }
+    }
+}
+
+// The cache shared by the upstreams, which decides what to cache and keeps the statistics.
 #[derive(Clone)]
 pub struct RespCache {
-    #[allow(clippy::type_complexity)]
-    cache: Arc<Mutex<CLruCache<(Label, Bytes), CacheRecord<Message<Bytes>>>>>,
+    cache: Arc<dyn Cache>,
+    counters: Arc<CacheCounters>,
 }
 
 impl RespCache {
-    pub fn new(size: NonZeroUsize) -> Self {
+    pub fn new(cache: impl Cache + 'static) -> Self {
         Self {
-            cache: Arc::new(Mutex::new(CLruCache::new(size))),
+            cache: Arc::new(cache),
+            counters: Arc::new(CacheCounters::default()),
         }
     }
 
-    pub fn put(&self, tag: Label, query: &Message<Bytes>, msg: Message<Bytes>) {
+    pub fn stats(&self) -> CacheStats {
+        self.counters.snapshot()
+    }
+
+    pub async fn purge(&self) {
+        self.cache.purge().await
+    }
+
+    pub async fn put(&self, tag: Label, query: &Message<Bytes>, msg: Message<Bytes>) {
         if msg.no_error() {
-            // We are assured that it should parse and exist
-            let ttl = Duration::from_secs(u64::from(
-                query
-                    .answer()
-                    .ok()
-                    .and_then(|records| {
-                        records
-                            .filter(|r| r.is_ok())
-                            .map(|r| r.unwrap().ttl())
-                            .min()
-                    })
-                    .unwrap_or(MAX_TTL),
-            ));
-            self.cache.lock().unwrap().put(
-                // We discard the first two bytes which are the places for ID
-                (tag, query.as_octets().slice(2..)),
-                // Clone should be cheap here
-                CacheRecord::new(msg, ttl),
-            );
+            let ttl = ttl(&msg);
+            self.cache.put(tag, query, msg, ttl).await;
         } else {
             info!("response errored, not caching erroneous upstream response.");
         };
     }
 
-    pub fn get(&self, tag: &Label, msg: &Message<Bytes>) -> Option<RecordStatus<Message<Bytes>>> {
+    pub async fn get(
+        &self,
+        tag: &Label,
+        msg: &Message<Bytes>,
+    ) -> Option<RecordStatus<Message<Bytes>>> {
         let question = msg.first_question().unwrap();
         let qname = question.qname().to_bytes();
 
-        match self
-            .cache
-            .lock()
-            .unwrap()
-            .get(&(tag, msg.as_octets().slice(2..)) as &dyn KeyPair<Label, Bytes>)
-        {
-            Some(r) => {
-                // Get record only once.
-                if r.validate() {
-                    info!("cache hit for {}", qname);
-                    Some(Alive(r.get()))
-                } else {
-                    info!("TTL passed for {}, returning expired record.", qname);
-                    Some(Expired(r.get()))
-                }
+        let r = self.cache.get(tag, msg).await;
+        match &r {
+            Some(Alive(_)) => {
+                info!("cache hit for {}", privacy::qname(&qname));
+                self.counters.hits.inc();
             }
-            Option::None => Option::None,
+            Some(Expired(_)) => {
+                info!(
+                    "TTL passed for {}, returning expired record.",
+                    privacy::qname(&qname)
+                );
+                self.counters.expired.inc();
+            }
+            Option::None => self.counters.misses.inc(),
         }
+        // Cached for the same names, possibly in another case.
+        r.map(|r| match r {
+            Alive(r) => Alive(wire::match_case(&r, msg)),
+            Expired(r) => Expired(wire::match_case(&r, msg)),
+        })
     }
 }
 
@@ -215,3 +402,131 @@ impl RespCache {
 //         Self::new()
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        clock::Timestamp, count_down, stale, ttl, Cache, CacheRecord, MemoryCache, RecordStatus::*,
+        STALE_TTL,
+    };
+    use crate::{Label, MAX_TTL};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{Dname, Message, MessageBuilder, Rtype, Serial},
+        rdata::{Soa, A},
+    };
+    use std::{num::NonZeroUsize, str::FromStr, time::Duration};
+
+    #[test]
+    fn record_expiry() {
+        let record = CacheRecord::new((), Duration::from_secs(10));
+        let created = record.created;
+        assert!(record.validate_at(created + Duration::from_secs(10)));
+        assert!(!record.validate_at(created + Duration::from_secs(11)));
+        // Records are never considered older than they are, whatever the clock read.
+        assert!(record.validate_at(Timestamp::now()));
+
+        let (t1, t2) = (Timestamp::now(), Timestamp::now());
+        assert!(t1 <= t2);
+    }
+
+    fn resp(ttls: &[u32]) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let mut builder = builder.answer();
+        for ttl in ttls {
+            builder
+                .push((&name, *ttl, A::from_octets(192, 0, 2, 1)))
+                .unwrap();
+        }
+        builder.into_message()
+    }
+
+    // NODATA response with an SOA record of the TTL and the MINIMUM given.
+    fn negative(ttl: u32, minimum: u32) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let mut builder = builder.authority();
+        builder
+            .push((
+                &name,
+                ttl,
+                Soa::new(
+                    name.clone(),
+                    name.clone(),
+                    Serial(1),
+                    7200,
+                    3600,
+                    1209600,
+                    minimum,
+                ),
+            ))
+            .unwrap();
+        builder.into_message()
+    }
+
+    fn ttls(msg: &Message<Bytes>) -> Vec<u32> {
+        msg.answer().unwrap().map(|r| r.unwrap().ttl()).collect()
+    }
+
+    #[test]
+    fn ttl_from_response() {
+        assert_eq!(ttl(&resp(&[300, 60])), Duration::from_secs(60));
+        // Negative responses are cached for the negative TTL, and not at all without an SOA record.
+        assert_eq!(ttl(&negative(3600, 300)), Duration::from_secs(300));
+        assert_eq!(ttl(&negative(60, 300)), Duration::from_secs(60));
+        assert_eq!(ttl(&resp(&[])), Duration::ZERO);
+        assert_eq!(
+            ttl(&resp(&[MAX_TTL * 2])),
+            Duration::from_secs(MAX_TTL.into())
+        );
+    }
+
+    #[test]
+    fn ttls_counted_down() {
+        let msg = resp(&[300, 60]);
+        assert_eq!(ttls(&count_down(&msg, Duration::ZERO)), vec![300, 60]);
+        assert_eq!(
+            ttls(&count_down(&msg, Duration::from_millis(45_900))),
+            vec![255, 15]
+        );
+        assert_eq!(
+            ttls(&count_down(&msg, Duration::from_secs(100))),
+            vec![200, 0]
+        );
+        assert_eq!(ttls(&stale(&msg)), vec![STALE_TTL, STALE_TTL]);
+    }
+
+    #[tokio::test]
+    async fn ttls_of_cached() {
+        let cache = MemoryCache::new(NonZeroUsize::new(8).unwrap());
+        let (tag, query): (Label, _) = ("upstream".into(), resp(&[]));
+        cache
+            .put(
+                tag.clone(),
+                &query,
+                resp(&[300, 60]),
+                Duration::from_secs(60),
+            )
+            .await;
+        match cache.get(&tag, &query).await {
+            Some(Alive(r)) => assert_eq!(ttls(&r), vec![300, 60]),
+            _ => panic!("response not cached"),
+        }
+
+        cache
+            .put(tag.clone(), &query, resp(&[300, 60]), Duration::ZERO)
+            .await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        match cache.get(&tag, &query).await {
+            Some(Expired(r)) => assert_eq!(ttls(&r), vec![STALE_TTL, STALE_TTL]),
+            _ => panic!("response not expired"),
+        }
+    }
+}
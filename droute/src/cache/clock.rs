@@ -0,0 +1,72 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// The clock cached records are aged with.
+//
+// The system clock is stepped by NTP and by hand, which would expire every record at once or keep them way beyond their TTLs.
+// `Instant` never jumps, but it stops while the system is suspended on Linux, so records would outlive their TTLs by the time suspended.
+// The boot time clock of Linux and Android does neither, so it is used there, and `Instant` elsewhere.
+
+use std::{
+    ops::Add,
+    time::{Duration, Instant},
+};
+
+/// A point in time on a clock never going backwards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(Duration);
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[allow(unsafe_code)]
+fn since_boot() -> Option<Duration> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` is a valid timespec to write into.
+    if unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) } == 0 {
+        Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+    } else {
+        None
+    }
+}
+
+fn since_start() -> Duration {
+    static START: once_cell::sync::OnceCell<Instant> = once_cell::sync::OnceCell::new();
+    START.get_or_init(Instant::now).elapsed()
+}
+
+impl Timestamp {
+    pub fn now() -> Self {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(d) = since_boot() {
+            return Self(d);
+        }
+        Self(since_start())
+    }
+
+    /// Time elapsed from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn saturating_duration_since(self, earlier: Self) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+}
+
+impl Add<Duration> for Timestamp {
+    type Output = Self;
+
+    fn add(self, rhs: Duration) -> Self {
+        Self(self.0 + rhs)
+    }
+}
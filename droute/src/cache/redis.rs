@@ -0,0 +1,175 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{count_down, stale, ttl, Cache, MemoryCache, RecordStatus, RecordStatus::*};
+use crate::{wire, Label};
+use ::redis::{aio::ConnectionManager, AsyncCommands, RedisResult};
+use async_trait::async_trait;
+use bytes::Bytes;
+use domain::base::Message;
+use std::{
+    num::NonZeroUsize,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A cache shared by multiple instances through Redis, with a local in-memory cache in front of it.
+/// Entries are stored as the absolute expiry time (8 bytes of big-endian UNIX timestamp in seconds) followed by the response in wire format, so that every instance sees the same remaining TTL.
+pub struct RedisCache {
+    conn: ConnectionManager,
+    l1: MemoryCache,
+    prefix: String,
+    stale: Duration,
+}
+
+impl RedisCache {
+    /// Connect to the Redis server at `url` (e.g. `redis://127.0.0.1:6379/0`).
+    /// Keys are prefixed with `prefix`, and entries are kept for `stale` after they expire so that they can still be served in `persistent` cache mode. The local cache holds at most `l1_size` responses.
+    pub async fn new(
+        url: &str,
+        prefix: impl Into<String>,
+        stale: Duration,
+        l1_size: NonZeroUsize,
+    ) -> RedisResult<Self> {
+        let conn = ConnectionManager::new(::redis::Client::open(url)?).await?;
+        Ok(Self {
+            conn,
+            l1: MemoryCache::new(l1_size),
+            prefix: prefix.into(),
+            stale,
+        })
+    }
+
+    fn key(&self, tag: &Label, query: &Message<Bytes>) -> String {
+        format!(
+            "{}{}:{}",
+            self.prefix,
+            tag,
+            hex::encode(wire::cache_key(query))
+        )
+    }
+
+    async fn fetch(
+        &self,
+        tag: &Label,
+        query: &Message<Bytes>,
+    ) -> RedisResult<Option<RecordStatus<Message<Bytes>>>> {
+        let value: Option<Vec<u8>> = self.conn.clone().get(self.key(tag, query)).await?;
+        let value = match value {
+            Some(v) if v.len() > 8 => v,
+            _ => return Ok(None),
+        };
+        let expiry = u64::from_be_bytes(value[..8].try_into().unwrap());
+        let resp = match Message::from_octets(Bytes::copy_from_slice(&value[8..])) {
+            Ok(resp) => resp,
+            // Garbage is treated as a miss.
+            Err(_) => return Ok(None),
+        };
+        Ok(Some(match expiry.checked_sub(now()) {
+            Some(remaining) if remaining > 0 => {
+                // The expiry is on the system clock, which may have been stepped back since, so it is never taken beyond the TTL of the response.
+                let remaining = Duration::from_secs(remaining).min(ttl(&resp));
+                // Counted down here, as the local cache counts down from the time it is put.
+                let resp = count_down(&resp, ttl(&resp) - remaining);
+                // Keep it locally for the rest of its TTL.
+                self.l1
+                    .put(tag.clone(), query, resp.clone(), remaining)
+                    .await;
+                Alive(resp)
+            }
+            _ => Expired(stale(&resp)),
+        }))
+    }
+
+    async fn store(
+        &self,
+        tag: &Label,
+        query: &Message<Bytes>,
+        resp: &Message<Bytes>,
+        ttl: Duration,
+    ) -> RedisResult<()> {
+        let mut value = (now() + ttl.as_secs()).to_be_bytes().to_vec();
+        value.extend_from_slice(resp.as_slice());
+        self.conn
+            .clone()
+            .set_ex(
+                self.key(tag, query),
+                value,
+                (ttl + self.stale).as_secs().max(1) as usize,
+            )
+            .await
+    }
+
+    async fn clear(&self) -> RedisResult<()> {
+        let mut conn = self.conn.clone();
+        let keys: Vec<String> = {
+            let mut iter = conn
+                .scan_match::<_, String>(format!("{}*", self.prefix))
+                .await?;
+            let mut keys = Vec::new();
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+            keys
+        };
+        for chunk in keys.chunks(1000) {
+            conn.del::<_, ()>(chunk).await?;
+        }
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[async_trait]
+impl Cache for RedisCache {
+    async fn get(
+        &self,
+        tag: &Label,
+        query: &Message<Bytes>,
+    ) -> Option<RecordStatus<Message<Bytes>>> {
+        let local = match self.l1.get(tag, query).await {
+            Some(Alive(r)) => return Some(Alive(r)),
+            local => local,
+        };
+        // Another instance may have updated it.
+        match self.fetch(tag, query).await {
+            Ok(Some(r)) => Some(r),
+            Ok(None) => local,
+            Err(e) => {
+                log::warn!("failed to look up Redis cache: {}", e);
+                local
+            }
+        }
+    }
+
+    async fn put(&self, tag: Label, query: &Message<Bytes>, resp: Message<Bytes>, ttl: Duration) {
+        if let Err(e) = self.store(&tag, query, &resp, ttl).await {
+            log::warn!("failed to store response into Redis cache: {}", e);
+        }
+        self.l1.put(tag, query, resp, ttl).await;
+    }
+
+    async fn purge(&self) {
+        if let Err(e) = self.clear().await {
+            log::warn!("failed to purge Redis cache: {}", e);
+        }
+        self.l1.purge().await;
+    }
+}
@@ -0,0 +1,46 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use serde::Serialize;
+use std::fmt;
+
+/// Stable categories of the errors, for retry and alert policies to act on without matching the messages.
+/// Every error in `droute` falls into one of them via its `kind` method, regardless of how deep it is wrapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorKind {
+    /// Failed to reach the upstream or the service behind, e.g. connection refused or reset.
+    Network,
+    /// The upstream didn't answer in time.
+    Timeout,
+    /// The upstream answered, but with something unusable, e.g. malformed messages, HTTP errors, or failure response codes.
+    Protocol,
+    /// The configuration is invalid, including the script and the files it loads.
+    Config,
+    /// The query is turned down by the policies of our own, e.g. rate limiting.
+    Policy,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Network => "network",
+            Self::Timeout => "timeout",
+            Self::Protocol => "protocol",
+            Self::Config => "config",
+            Self::Policy => "policy",
+        })
+    }
+}
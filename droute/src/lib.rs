@@ -26,9 +26,16 @@
 // Documentation
 //! This is the core library for dcompass. It implements configuration parsing scheme, DNS query routing rules, and upstream managements.
 pub(crate) mod cache;
+mod error_kind;
+pub mod memory;
 #[doc(hidden)]
 pub mod mock;
+pub mod privacy;
 mod router;
+pub mod runtime;
+pub mod testing;
+pub mod truncation;
+pub mod wire;
 
 #[cfg(all(feature = "doh-native-tls", feature = "doh-rustls"))]
 compile_error!("You should only choose one TLS backend for DNS over HTTPS implementation");
@@ -47,17 +54,22 @@ pub mod builders {
 
 /// A collection of all errors in `droute`
 pub mod errors {
+    pub use super::error_kind::ErrorKind;
     pub use super::router::{
-        script::{MessageError, ScriptError},
-        upstreams::error::UpstreamError,
+        script::{utils::UtilsError, MessageError, ScriptError},
+        upstreams::{error::UpstreamError, stamp::StampError},
     };
 }
 
 // All the major components
+#[cfg(feature = "redis-cache")]
+pub use self::cache::RedisCache;
+pub use self::cache::{Cache, MemoryCache, RecordStatus};
 pub use self::router::{
-    script::{native::NativeScript, utils, QueryContext, ScriptBackend, ScriptBuilder},
-    upstreams::{CacheMode, Upstream, Upstreams},
-    Router,
+    script::{native::NativeScript, utils, Listener, QueryContext, ScriptBackend, ScriptBuilder},
+    upstreams::{CacheMode, Capture, Measurement, Ranking, RankingPolicy, Upstream, Upstreams},
+    AnswerOrder, AnyPolicy, CacheStats, ChaosPolicy, ClientInfo, FastPath, RootMirror, RootZone,
+    Router, RouterStats, SlowQueryLog, SpecialUse, SpecialUsePolicy, UpstreamStats, Zones,
 };
 
 // Maximum TTL as defined in https://tools.ietf.org/html/rfc2181, 2147483647
@@ -0,0 +1,250 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Approximate accounting of the memory held by the cache, the rule lists, and the query history, with optional caps on them.
+//! Figures are estimates from the sizes of the stored data plus a fixed overhead per entry, not allocator statistics.
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    RwLock, Weak,
+};
+
+/// What the memory is used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Category {
+    /// Cached responses
+    Cache,
+    /// Domain lists, CIDR lists and GeoIP databases loaded by the scripts
+    Rules,
+    /// Query history kept for the control endpoint
+    History,
+}
+
+impl Category {
+    fn index(self) -> usize {
+        match self {
+            Self::Cache => 0,
+            Self::Rules => 1,
+            Self::History => 2,
+        }
+    }
+}
+
+// Bytes used per category.
+static USED: [AtomicUsize; 3] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+// Caps on the total, the cache, and the history respectively. `usize::MAX` for none.
+static CAPS: [AtomicUsize; 3] = [
+    AtomicUsize::new(usize::MAX),
+    AtomicUsize::new(usize::MAX),
+    AtomicUsize::new(usize::MAX),
+];
+
+static RECLAIMERS: Lazy<RwLock<Vec<Weak<dyn Reclaim>>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+/// Caps on the memory used, in bytes. Rule lists cannot be shrunk, so they only count towards the total.
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Caps {
+    /// Cap on the cache, the rule lists, and the history altogether
+    pub total: Option<usize>,
+    /// Cap on the cache alone
+    pub cache: Option<usize>,
+    /// Cap on the history alone
+    pub history: Option<usize>,
+}
+
+/// The memory used at some point, in bytes.
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct MemoryUsage {
+    /// Used by cached responses
+    pub cache: usize,
+    /// Used by rule lists
+    pub rules: usize,
+    /// Used by the query history
+    pub history: usize,
+    /// Sum of the above
+    pub total: usize,
+    /// Caps in force
+    pub caps: Caps,
+}
+
+fn cap(slot: usize) -> Option<usize> {
+    match CAPS[slot].load(Ordering::Relaxed) {
+        usize::MAX => None,
+        c => Some(c),
+    }
+}
+
+/// Set the caps, which apply to the usage from then on.
+pub fn set_caps(caps: Caps) {
+    for (slot, c) in [caps.total, caps.cache, caps.history]
+        .into_iter()
+        .enumerate()
+    {
+        CAPS[slot].store(c.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+}
+
+/// The caps in force.
+pub fn caps() -> Caps {
+    Caps {
+        total: cap(0),
+        cache: cap(1),
+        history: cap(2),
+    }
+}
+
+/// Memory used by the category given.
+pub fn used(category: Category) -> usize {
+    USED[category.index()].load(Ordering::Relaxed)
+}
+
+/// Current memory usage.
+pub fn usage() -> MemoryUsage {
+    let (cache, rules, history) = (
+        used(Category::Cache),
+        used(Category::Rules),
+        used(Category::History),
+    );
+    MemoryUsage {
+        cache,
+        rules,
+        history,
+        total: cache + rules + history,
+        caps: caps(),
+    }
+}
+
+/// Bytes the category given has to free to get within both its own cap and the total one.
+pub fn excess(category: Category) -> usize {
+    let total = USED
+        .iter()
+        .map(|u| u.load(Ordering::Relaxed))
+        .sum::<usize>();
+    let over = |used: usize, cap: Option<usize>| cap.map_or(0, |c| used.saturating_sub(c));
+    let own = match category {
+        Category::Cache => over(used(category), cap(1)),
+        Category::History => over(used(category), cap(2)),
+        Category::Rules => 0,
+    };
+    own.max(over(total, cap(0)))
+}
+
+/// Something that can free memory on demand, i.e. a cache.
+pub trait Reclaim: Send + Sync {
+    /// Free at least `bytes` if possible, returning the bytes actually freed.
+    fn reclaim(&self, bytes: usize) -> usize;
+}
+
+/// Register a cache to be shrunk when the total cap is exceeded by others. It is dropped from the registry once it is gone.
+pub fn register(reclaimer: Weak<dyn Reclaim>) {
+    let mut reclaimers = RECLAIMERS.write().unwrap();
+    reclaimers.retain(|r| r.strong_count() > 0);
+    reclaimers.push(reclaimer);
+}
+
+/// Evict cache entries until the total usage is within its cap, returning the bytes that still have to be freed by the category given.
+pub fn reclaim(category: Category) -> usize {
+    let over = excess(category);
+    if over > 0 {
+        let mut needed = cap(0).map_or(0, |c| usage().total.saturating_sub(c));
+        for r in RECLAIMERS.read().unwrap().iter().filter_map(Weak::upgrade) {
+            if needed == 0 {
+                break;
+            }
+            needed = needed.saturating_sub(r.reclaim(needed));
+        }
+    }
+    excess(category)
+}
+
+/// Bytes accounted to a category for as long as the charge is alive. Put it in an `Arc` where what it accounts for is shared.
+#[derive(Debug)]
+pub struct Charge {
+    category: Category,
+    bytes: AtomicUsize,
+}
+
+impl Charge {
+    /// Account `bytes` to the category given.
+    pub fn new(category: Category, bytes: usize) -> Self {
+        USED[category.index()].fetch_add(bytes, Ordering::Relaxed);
+        Self {
+            category,
+            bytes: AtomicUsize::new(bytes),
+        }
+    }
+
+    /// Account more bytes.
+    pub fn add(&self, bytes: usize) {
+        USED[self.category.index()].fetch_add(bytes, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Account fewer bytes, e.g. once part of what is accounted is dropped.
+    pub fn sub(&self, bytes: usize) {
+        // Never below zero, even with the charge shared.
+        let prev = self
+            .bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| {
+                Some(b.saturating_sub(bytes))
+            })
+            .unwrap_or_default();
+        USED[self.category.index()].fetch_sub(bytes.min(prev), Ordering::Relaxed);
+    }
+
+    /// Bytes accounted.
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        USED[self.category.index()].fetch_sub(*self.bytes.get_mut(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Only the history is accounted here, as nothing else in `droute` records it.
+    #[test]
+    fn charges() {
+        let base = used(Category::History);
+        let charge = Charge::new(Category::History, 100);
+        charge.add(50);
+        let other = Charge::new(Category::History, 150);
+        assert_eq!(used(Category::History), base + 300);
+        drop(charge);
+        assert_eq!(used(Category::History), base + 150);
+
+        set_caps(Caps {
+            history: Some(base + 100),
+            ..Default::default()
+        });
+        assert_eq!(excess(Category::History), 50);
+        drop(other);
+        assert_eq!(excess(Category::History), 0);
+        set_caps(Caps::default());
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Privacy filter on the query names and the client addresses in the logs and the trace spans.
+//!
+//! The filter is process-wide, as is the logger. Names and addresses are shown as they are until a filter is installed.
+
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{
+        hash_map::{DefaultHasher, RandomState},
+        HashSet,
+    },
+    fmt::Display,
+    hash::{BuildHasher, Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Mutex,
+};
+
+static FILTER: OnceCell<Privacy> = OnceCell::new();
+
+// Shown in place of the values beyond the cardinality cap.
+const OTHER: &str = "<other>";
+
+/// How a kind of value is shown.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Redaction {
+    /// As it is
+    Plain,
+    /// Salted hash of it, so that the same value can still be followed across the logs
+    Hash,
+    /// Query names are cut down to their last labels, and client addresses to their network prefixes
+    Truncate,
+}
+
+const fn default_qname() -> Redaction {
+    Redaction::Hash
+}
+
+const fn default_client() -> Redaction {
+    Redaction::Truncate
+}
+
+const fn default_keep_labels() -> usize {
+    2
+}
+
+const fn default_ipv4_prefix_length() -> u8 {
+    24
+}
+
+const fn default_ipv6_prefix_length() -> u8 {
+    48
+}
+
+/// Configuration of the log privacy filter.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LogPrivacy {
+    /// How query names are shown
+    #[serde(default = "default_qname")]
+    pub qname: Redaction,
+    /// How client addresses are shown
+    #[serde(default = "default_client")]
+    pub client: Redaction,
+    /// Salt of the hashes. A random one is picked on start if not given, with which hashes cannot be followed across restarts.
+    #[serde(default)]
+    pub salt: Option<String>,
+    /// Number of the last labels of query names kept on truncation
+    #[serde(default = "default_keep_labels")]
+    pub keep_labels: usize,
+    /// Prefix length client IPv4 addresses are truncated to
+    #[serde(default = "default_ipv4_prefix_length")]
+    pub ipv4_prefix_length: u8,
+    /// Prefix length client IPv6 addresses are truncated to
+    #[serde(default = "default_ipv6_prefix_length")]
+    pub ipv6_prefix_length: u8,
+    /// Maximum number of distinct query names shown, beyond which they are shown as `<other>`
+    #[serde(default)]
+    pub max_qnames: Option<usize>,
+    /// Maximum number of distinct clients shown, beyond which they are shown as `<other>`
+    #[serde(default)]
+    pub max_clients: Option<usize>,
+}
+
+impl Default for LogPrivacy {
+    fn default() -> Self {
+        Self {
+            qname: default_qname(),
+            client: default_client(),
+            salt: None,
+            keep_labels: default_keep_labels(),
+            ipv4_prefix_length: default_ipv4_prefix_length(),
+            ipv6_prefix_length: default_ipv6_prefix_length(),
+            max_qnames: None,
+            max_clients: None,
+        }
+    }
+}
+
+// Distinct values shown so far, up to the cap.
+struct Cap {
+    max: Option<usize>,
+    seen: Mutex<HashSet<u64>>,
+}
+
+impl Cap {
+    fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn admit(&self, shown: &str) -> bool {
+        let max = match self.max {
+            Some(max) => max,
+            None => return true,
+        };
+        let mut hasher = DefaultHasher::new();
+        shown.hash(&mut hasher);
+        let key = hasher.finish();
+        let mut seen = self.seen.lock().unwrap();
+        if seen.contains(&key) {
+            true
+        } else if seen.len() < max {
+            seen.insert(key);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The privacy filter built from `LogPrivacy`.
+pub struct Privacy {
+    config: LogPrivacy,
+    salt: String,
+    qnames: Cap,
+    clients: Cap,
+}
+
+impl From<LogPrivacy> for Privacy {
+    fn from(config: LogPrivacy) -> Self {
+        Self {
+            salt: config
+                .salt
+                .clone()
+                .unwrap_or_else(|| hex::encode(rand_salt())),
+            qnames: Cap::new(config.max_qnames),
+            clients: Cap::new(config.max_clients),
+            config,
+        }
+    }
+}
+
+// Keys of `RandomState` are randomly seeded per process.
+fn rand_salt() -> [u8; 16] {
+    let mut salt = [0; 16];
+    for chunk in salt.chunks_exact_mut(8) {
+        chunk.copy_from_slice(&RandomState::new().build_hasher().finish().to_be_bytes());
+    }
+    salt
+}
+
+impl Privacy {
+    fn hash(&self, value: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt.as_bytes());
+        hasher.update(value.as_bytes());
+        format!("#{}", hex::encode(&hasher.finalize()[..8]))
+    }
+
+    /// Show the query name per the filter.
+    pub fn qname(&self, qname: &impl Display) -> String {
+        let qname = qname.to_string();
+        let shown = match self.config.qname {
+            Redaction::Plain => qname,
+            Redaction::Hash => self.hash(&qname.to_lowercase()),
+            Redaction::Truncate => {
+                let labels: Vec<_> = qname.trim_end_matches('.').split('.').collect();
+                if labels.len() <= self.config.keep_labels {
+                    qname
+                } else {
+                    let kept = &labels[labels.len() - self.config.keep_labels..];
+                    if kept.is_empty() {
+                        "*".to_string()
+                    } else {
+                        format!("*.{}", kept.join("."))
+                    }
+                }
+            }
+        };
+        if self.qnames.admit(&shown) {
+            shown
+        } else {
+            OTHER.to_string()
+        }
+    }
+
+    /// Show the address of the client per the filter. The port is left out unless shown as it is.
+    pub fn client(&self, addr: SocketAddr) -> String {
+        match self.config.client {
+            Redaction::Plain => self.admit_client(addr.to_string()),
+            _ => self.ip(addr.ip()),
+        }
+    }
+
+    /// Show the IP address of the client per the filter.
+    pub fn ip(&self, ip: IpAddr) -> String {
+        let shown = match self.config.client {
+            Redaction::Plain => ip.to_string(),
+            Redaction::Hash => self.hash(&ip.to_string()),
+            Redaction::Truncate => match ip {
+                IpAddr::V4(ip) => {
+                    let len = self.config.ipv4_prefix_length.min(32);
+                    let mask = u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0);
+                    format!("{}/{}", Ipv4Addr::from(u32::from(ip) & mask), len)
+                }
+                IpAddr::V6(ip) => {
+                    let len = self.config.ipv6_prefix_length.min(128);
+                    let mask = u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0);
+                    format!("{}/{}", Ipv6Addr::from(u128::from(ip) & mask), len)
+                }
+            },
+        };
+        self.admit_client(shown)
+    }
+
+    fn admit_client(&self, shown: String) -> String {
+        if self.clients.admit(&shown) {
+            shown
+        } else {
+            OTHER.to_string()
+        }
+    }
+}
+
+/// Install the privacy filter for the rest of the process. Returns `false` if one has already been installed, which is kept.
+pub fn install(config: LogPrivacy) -> bool {
+    FILTER.set(config.into()).is_ok()
+}
+
+/// Show the query name per the filter installed, or as it is if none is.
+pub fn qname(qname: &impl Display) -> String {
+    match FILTER.get() {
+        Some(filter) => filter.qname(qname),
+        None => qname.to_string(),
+    }
+}
+
+/// Show the address of the client per the filter installed, or as it is if none is.
+pub fn client(addr: SocketAddr) -> String {
+    match FILTER.get() {
+        Some(filter) => filter.client(addr),
+        None => addr.to_string(),
+    }
+}
+
+/// Show the IP address of the client per the filter installed, or as it is if none is.
+pub fn ip(ip: IpAddr) -> String {
+    match FILTER.get() {
+        Some(filter) => filter.ip(ip),
+        None => ip.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogPrivacy, Privacy, Redaction};
+
+    #[test]
+    fn redact() {
+        let privacy = Privacy::from(LogPrivacy {
+            salt: Some("salt".to_string()),
+            ..Default::default()
+        });
+        let hashed = privacy.qname(&"www.example.com");
+        assert!(hashed.starts_with('#') && !hashed.contains("example"));
+        // Stable with the same salt, regardless of the case
+        assert_eq!(hashed, privacy.qname(&"WWW.example.com"));
+        assert_ne!(
+            hashed,
+            Privacy::from(LogPrivacy::default()).qname(&"www.example.com")
+        );
+        assert_eq!(
+            privacy.client("192.0.2.10:5353".parse().unwrap()),
+            "192.0.2.0/24"
+        );
+        assert_eq!(
+            privacy.client("[2001:db8:1:2::1]:53".parse().unwrap()),
+            "2001:db8:1::/48"
+        );
+
+        let privacy = Privacy::from(LogPrivacy {
+            qname: Redaction::Truncate,
+            client: Redaction::Plain,
+            ..Default::default()
+        });
+        assert_eq!(privacy.qname(&"a.b.example.com."), "*.example.com");
+        assert_eq!(privacy.qname(&"example.com"), "example.com");
+        assert_eq!(
+            privacy.client("192.0.2.10:5353".parse().unwrap()),
+            "192.0.2.10:5353"
+        );
+    }
+
+    #[test]
+    fn cardinality_cap() {
+        let privacy = Privacy::from(LogPrivacy {
+            qname: Redaction::Plain,
+            max_qnames: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(privacy.qname(&"a.com"), "a.com");
+        assert_eq!(privacy.qname(&"b.com"), "b.com");
+        assert_eq!(privacy.qname(&"c.com"), "<other>");
+        // Values shown before are still shown.
+        assert_eq!(privacy.qname(&"a.com"), "a.com");
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{errors::MessageError, MAX_LEN};
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{Message, MessageBuilder, Rtype},
+    rdata::AllRecordData,
+};
+use serde::{Deserialize, Serialize};
+
+type Result<T> = std::result::Result<T, MessageError>;
+
+/// The way `Router` handles queries of type ANY.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnyPolicy {
+    /// Route ANY queries like any other queries.
+    Forward,
+    /// Answer ANY queries locally with a synthesized HINFO record per RFC 8482.
+    Hinfo,
+    /// Route an A query and an AAAA query instead, and merge their answers.
+    Split,
+}
+
+impl Default for AnyPolicy {
+    fn default() -> Self {
+        Self::Forward
+    }
+}
+
+// Rebuild the query with the same header, question name and class, but a different question type.
+pub fn requery(msg: &Message<Bytes>, qtype: Rtype) -> Result<Message<Bytes>> {
+    let question = msg.sole_question()?;
+
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?;
+    *builder.header_mut() = msg.header();
+
+    let mut builder = builder.question();
+    builder.push((question.qname(), qtype, question.qclass()))?;
+
+    // Keep the additional section as it carries the OPT record.
+    let mut builder = builder.additional();
+    for item in msg.additional()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+            builder.push(record)?;
+        }
+    }
+
+    Ok(builder.into_message())
+}
+
+// Merge the answers of the split queries into a single response to the original ANY query.
+pub fn merge(query: &Message<Bytes>, responses: &[Message<Bytes>]) -> Result<Message<Bytes>> {
+    // The merged response fails only if all of the split queries failed.
+    let rcode = responses
+        .iter()
+        .find(|r| r.no_error())
+        .or_else(|| responses.first())
+        .map(|r| r.header().rcode())
+        .unwrap_or(domain::base::iana::Rcode::ServFail);
+
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+        .start_answer(query, rcode)?;
+    builder
+        .header_mut()
+        .set_ra(responses.iter().any(|r| r.header().ra()));
+
+    for resp in responses {
+        for item in resp.answer()? {
+            if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+                builder.push(record)?;
+            }
+        }
+    }
+
+    Ok(builder.into_message())
+}
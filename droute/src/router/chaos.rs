@@ -0,0 +1,145 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::script::MessageError;
+use crate::MAX_LEN;
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{
+        iana::{Class, Rcode},
+        question::Question,
+        Dname, Message, MessageBuilder, Record, Rtype,
+    },
+    rdata::Txt,
+};
+use serde::{Deserialize, Serialize};
+
+/// Answers to the queries in the CHAOS class, which are answered locally and never sent to the upstreams.
+/// Names without an answer configured, and any other name in the class, are answered REFUSED.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ChaosPolicy {
+    /// Answer to `version.bind` and `version.server`
+    #[serde(default)]
+    pub version: Option<String>,
+    /// Answer to `hostname.bind`
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Answer to `id.server` (RFC 4892), default to `hostname`
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+impl ChaosPolicy {
+    // The answer configured for the name given.
+    fn text(&self, qname: &Dname<Bytes>) -> Option<&String> {
+        match qname.to_string().to_lowercase().as_str() {
+            "version.bind" | "version.server" => self.version.as_ref(),
+            "hostname.bind" => self.hostname.as_ref(),
+            "id.server" => self.id.as_ref().or(self.hostname.as_ref()),
+            _ => None,
+        }
+    }
+
+    // Answer the query if it is in the CHAOS class.
+    pub(super) fn answer(
+        &self,
+        msg: &Message<Bytes>,
+        question: &Question<Dname<Bytes>>,
+    ) -> Result<Option<Message<Bytes>>, MessageError> {
+        if question.qclass() != Class::Ch {
+            return Ok(None);
+        }
+        let text = match self.text(question.qname()) {
+            Some(text) => text,
+            None => {
+                return Ok(Some(
+                    MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+                        .start_answer(msg, Rcode::Refused)?
+                        .into_message(),
+                ))
+            }
+        };
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+            .start_answer(msg, Rcode::NoError)?;
+        builder.header_mut().set_aa(true);
+        // Other types of the names are answered with no data.
+        if matches!(question.qtype(), Rtype::Txt | Rtype::Any) {
+            builder.push(Record::new(
+                question.qname().clone(),
+                Class::Ch,
+                0,
+                Txt::<Bytes>::from_slice(text.as_bytes())?,
+            ))?;
+        }
+        Ok(Some(builder.into_message()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChaosPolicy;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{
+            iana::{Class, Rcode},
+            question::Question,
+            Dname, Message, MessageBuilder, Rtype,
+        },
+        rdata::Txt,
+    };
+    use std::str::FromStr;
+
+    fn answer(policy: &ChaosPolicy, qname: &str, qclass: Class) -> Option<Message<Bytes>> {
+        let question = Question::new(Dname::<Bytes>::from_str(qname).unwrap(), Rtype::Txt, qclass);
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push(question.clone()).unwrap();
+        policy.answer(&builder.into_message(), &question).unwrap()
+    }
+
+    #[test]
+    fn chaos() {
+        let policy = ChaosPolicy {
+            version: Some("dcompass".to_string()),
+            hostname: Some("resolver-1".to_string()),
+            id: None,
+        };
+        assert!(answer(&policy, "version.bind", Class::In).is_none());
+
+        let resp = answer(&policy, "VERSION.BIND", Class::Ch).unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        let txt = resp
+            .answer()
+            .unwrap()
+            .limit_to::<Txt<_>>()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(txt.class(), Class::Ch);
+        assert_eq!(txt.data().as_flat_slice(), Some(&b"dcompass"[..]));
+
+        // `id.server` falls back to the hostname.
+        let resp = answer(&policy, "id.server", Class::Ch).unwrap();
+        assert_eq!(resp.header_counts().ancount(), 1);
+
+        // Names not configured are refused rather than sent upstream.
+        let resp = answer(&ChaosPolicy::default(), "version.bind", Class::Ch).unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::Refused);
+        let resp = answer(&policy, "authors.bind", Class::Ch).unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::Refused);
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! End-to-end deadline of the queries. Once it passes, the query is dropped along with every upstream attempt still in flight.
+
+use crate::runtime::timeout;
+use futures::future::{self, Either};
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+tokio::task_local! {
+    static DEADLINE: Arc<Deadline>;
+}
+
+struct Deadline {
+    start: Instant,
+    at: Mutex<Option<Instant>>,
+    // Woken up once the script moves the deadline.
+    changed: Notify,
+}
+
+/// The query went past its deadline, which is given relative to the start of the query.
+pub(crate) struct Exceeded(pub Duration);
+
+/// Run the future within the deadline given, which the future may move with `set_deadline`.
+pub(crate) async fn scope<F: Future>(
+    deadline: Option<Duration>,
+    f: F,
+) -> Result<F::Output, Exceeded> {
+    let start = Instant::now();
+    let state = Arc::new(Deadline {
+        start,
+        at: Mutex::new(deadline.map(|d| start + d)),
+        changed: Notify::new(),
+    });
+    DEADLINE
+        .scope(state.clone(), async move {
+            futures::pin_mut!(f);
+            loop {
+                // Created before reading the deadline, so that no change is missed.
+                let changed = Box::pin(state.changed.notified());
+                let at = *state.at.lock().unwrap();
+                let wait = future::select(f.as_mut(), changed);
+                let r = match at {
+                    Some(at) => {
+                        match timeout(at.saturating_duration_since(Instant::now()), wait).await {
+                            Ok(r) => r,
+                            Err(_) => return Err(Exceeded(at - start)),
+                        }
+                    }
+                    None => wait.await,
+                };
+                match r {
+                    Either::Left((output, _)) => return Ok(output),
+                    Either::Right(_) => continue,
+                }
+            }
+        })
+        .await
+}
+
+/// Set the deadline of the current query to `deadline` after its start, overriding the default one. This has no effect outside of a query.
+pub fn set_deadline(deadline: Duration) {
+    // Fails if we are not in a query, which is fine.
+    let _ = DEADLINE.try_with(|d| {
+        *d.at.lock().unwrap() = Some(d.start + deadline);
+        d.changed.notify_waiters();
+    });
+}
+
+/// Time left until the deadline of the current query, if it has one, so that the upstreams don't wait past it.
+pub(crate) fn remaining() -> Option<Duration> {
+    DEADLINE
+        .try_with(|d| {
+            d.at.lock()
+                .unwrap()
+                .map(|at| at.saturating_duration_since(Instant::now()))
+        })
+        .ok()
+        .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{remaining, scope, set_deadline};
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    #[tokio::test]
+    async fn deadlines() {
+        assert_eq!(scope(None, async { 1 }).await.ok(), Some(1));
+
+        let slow = || async {
+            sleep(Duration::from_millis(200)).await;
+            1
+        };
+        assert_eq!(
+            scope(Some(Duration::from_millis(20)), slow())
+                .await
+                .err()
+                .map(|e| e.0),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(
+            scope(Some(Duration::from_secs(1)), slow()).await.ok(),
+            Some(1)
+        );
+
+        // Moved by the future itself, both shortened and extended.
+        let shortened = async {
+            set_deadline(Duration::from_millis(20));
+            slow().await
+        };
+        assert!(scope(Some(Duration::from_secs(1)), shortened)
+            .await
+            .is_err());
+        let extended = async {
+            set_deadline(Duration::from_secs(1));
+            slow().await
+        };
+        assert_eq!(
+            scope(Some(Duration::from_millis(20)), extended).await.ok(),
+            Some(1)
+        );
+        let set = async {
+            set_deadline(Duration::from_millis(20));
+            slow().await
+        };
+        assert!(scope(None, set).await.is_err());
+
+        // Time left, within a query with a deadline only
+        assert_eq!(remaining(), None);
+        assert_eq!(scope(None, async { remaining() }).await.ok(), Some(None));
+        let left = scope(Some(Duration::from_secs(1)), async { remaining() })
+            .await
+            .ok()
+            .flatten()
+            .unwrap();
+        assert!(left <= Duration::from_secs(1) && left > Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{
+    cache::{count_down, ttl, CacheRecord},
+    errors::MessageError,
+    utils::parse_domain,
+    wire,
+};
+use bytes::Bytes;
+use clru::CLruCache;
+use dmatcher::domain::Domain;
+use domain::base::{Dname, Message};
+use std::{num::NonZeroUsize, sync::Mutex};
+
+/// Cache of the responses `Router` returned, by which a query answered before is answered again right away, without going through the policies or the script.
+/// As the script is skipped on hits, the responses have to be the same for every client, so names answered per client (e.g. by `ctx` in the script) should be evaluated always.
+pub struct FastPath {
+    // Keyed by the query without its ID, per `wire::cache_key`
+    cache: Mutex<CLruCache<Bytes, CacheRecord<Message<Bytes>>>>,
+    always: Domain,
+}
+
+impl FastPath {
+    /// Create a fast path holding at most `size` responses.
+    pub fn new(size: NonZeroUsize) -> Self {
+        Self {
+            cache: Mutex::new(CLruCache::new(size)),
+            always: Domain::new(),
+        }
+    }
+
+    /// Always evaluate the queries for the domain given and its subdomains, bypassing the fast path.
+    pub fn always_evaluate(&mut self, domain: impl AsRef<str>) -> Result<(), MessageError> {
+        self.always.insert(&parse_domain(domain.as_ref())?);
+        Ok(())
+    }
+
+    // The response cached for the query, with its ID set to the query's.
+    pub(super) fn get(&self, msg: &Message<Bytes>, qname: &Dname<Bytes>) -> Option<Message<Bytes>> {
+        if self.always.matches(qname) {
+            return None;
+        }
+        let key = wire::cache_key(msg);
+        let resp = {
+            let mut cache = self.cache.lock().unwrap();
+            let record = cache.get(&key)?;
+            if !record.validate() {
+                cache.pop(&key);
+                return None;
+            }
+            count_down(&record.get(), record.age())
+        };
+        Some(wire::set_id(
+            &wire::match_case(&resp, msg),
+            msg.header().id(),
+        ))
+    }
+
+    /// Drop every response held, e.g. once the rules they were evaluated by have changed.
+    pub fn purge(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    // Cache the successful response to the query.
+    pub(super) fn put(&self, msg: &Message<Bytes>, qname: &Dname<Bytes>, resp: &Message<Bytes>) {
+        if self.always.matches(qname) || !resp.no_error() {
+            return;
+        }
+        self.cache.lock().unwrap().put(
+            wire::cache_key(msg),
+            CacheRecord::new(resp.clone(), ttl(resp)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FastPath;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::A,
+    };
+    use std::{num::NonZeroUsize, str::FromStr};
+
+    fn query(name: &str, id: u16) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        builder.header_mut().set_id(id);
+        let mut builder = builder.question();
+        builder
+            .push((Dname::<Bytes>::from_str(name).unwrap(), Rtype::A))
+            .unwrap();
+        builder.into_message()
+    }
+
+    fn answer(query: &Message<Bytes>, rcode: Rcode) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(query, rcode)
+            .unwrap();
+        builder
+            .push((
+                query.sole_question().unwrap().qname(),
+                300,
+                A::from_octets(192, 0, 2, 1),
+            ))
+            .unwrap();
+        builder.into_message()
+    }
+
+    #[test]
+    fn fast_path() {
+        let mut fast_path = FastPath::new(NonZeroUsize::new(8).unwrap());
+        fast_path.always_evaluate("per-client.example").unwrap();
+        let name = |s: &str| Dname::<Bytes>::from_str(s).unwrap();
+
+        let q = query("example.com", 1);
+        assert!(fast_path.get(&q, &name("example.com")).is_none());
+        fast_path.put(&q, &name("example.com"), &answer(&q, Rcode::NoError));
+        // Hit regardless of the ID, which is rewritten.
+        let resp = fast_path
+            .get(&query("example.com", 2), &name("example.com"))
+            .unwrap();
+        assert_eq!(resp.header().id(), 2);
+        assert_eq!(resp.header_counts().ancount(), 1);
+
+        // Failures and names always evaluated are never cached.
+        let q = query("broken.example", 1);
+        fast_path.put(&q, &name("broken.example"), &answer(&q, Rcode::ServFail));
+        assert!(fast_path.get(&q, &name("broken.example")).is_none());
+        let q = query("www.per-client.example", 1);
+        fast_path.put(
+            &q,
+            &name("www.per-client.example"),
+            &answer(&q, Rcode::NoError),
+        );
+        assert!(fast_path.get(&q, &name("www.per-client.example")).is_none());
+
+        // Nothing is answered once purged.
+        fast_path.purge();
+        assert!(fast_path
+            .get(&query("example.com", 2), &name("example.com"))
+            .is_none());
+    }
+}
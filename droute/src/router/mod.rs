@@ -15,32 +15,117 @@
 
 //! Router is the core concept of `droute`.
 
+mod any;
+mod chaos;
+pub(crate) mod deadline;
+mod fast_path;
+mod order;
+mod root_mirror;
 pub mod script;
+pub(crate) mod slow_query;
+mod special_use;
+pub(crate) mod stats;
 pub mod upstreams;
+mod zonemd;
+mod zones;
 
 use std::marker::PhantomData;
 
+pub use self::{
+    any::AnyPolicy,
+    chaos::ChaosPolicy,
+    fast_path::FastPath,
+    order::AnswerOrder,
+    root_mirror::{RootMirror, RootZone},
+    slow_query::SlowQueryLog,
+    special_use::{SpecialUse, SpecialUsePolicy},
+    stats::{CacheStats, RouterStats, UpstreamStats},
+    zones::Zones,
+};
 use self::{
-    script::QueryContext,
-    upstreams::{error::UpstreamError, Upstreams},
+    deadline::Exceeded,
+    script::{Listener, QueryContext},
+    slow_query::QueryTrace,
+    stats::RouterCounters,
+    upstreams::{error::UpstreamError, with_client, Upstreams},
 };
 use crate::{
-    errors::ScriptError, AsyncTryInto, Label, ScriptBackend, ScriptBuilder, Validatable, MAX_LEN,
+    errors::ScriptError,
+    privacy,
+    truncation::{client_limit, fit},
+    utils::{edns_option, minimal_any, set_edns_option, EdnsPolicy, NSID},
+    AsyncTryInto, CacheMode, Label, ScriptBackend, ScriptBuilder, Validatable, MAX_LEN,
 };
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
-use domain::base::{iana::rcode::Rcode, Message, MessageBuilder};
-use log::warn;
+use domain::{
+    base::{
+        iana::{rcode::Rcode, Class},
+        name::ToDname,
+        question::Question,
+        Dname, Message, MessageBuilder, Record, Rtype,
+    },
+    rdata::UnknownRecordData,
+};
+use futures::{future::try_join, Stream, StreamExt};
+use log::{debug, info, warn};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::AtomicUsize, Arc},
+    time::Duration,
+};
+use tracing::{field, Instrument, Span};
+
+// Extended DNS Error "No Reachable Authority" (RFC 8914), answered once the deadline passes
+const EDE_NO_REACHABLE_AUTHORITY: u16 = 22;
+// Code of the Extended DNS Error option
+const EDE_OPTION: u16 = 15;
+
+/// Information on the client a query packet comes from.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientInfo {
+    /// Address of the client
+    pub addr: SocketAddr,
+    /// For queries over UDP, the maximum size of the responses regardless of the client's EDNS buffer size, e.g. 1232 per DNS Flag Day 2020.
+    /// `None` for queries over TCP and other streams, whose responses are never truncated.
+    pub udp_limit: Option<u16>,
+    /// Listener the packet is received on
+    pub listener: Listener,
+    /// Whether the packet is encrypted on the way from the client. No listener terminates TLS on its own, so this is up to the proxies trusted to.
+    pub encrypted: bool,
+}
 
 /// Router implementation.
 pub struct Router<T: ScriptBackend> {
     script: T,
+    any_policy: AnyPolicy,
+    edns_policy: EdnsPolicy,
+    answer_order: AnswerOrder,
+    // Number of responses rotated in round-robin
+    rotation: AtomicUsize,
+    chaos: ChaosPolicy,
+    special_use: SpecialUse,
+    zones: Zones,
+    root_mirror: Option<RootMirror>,
+    slow_query: Option<SlowQueryLog>,
+    fast_path: Option<FastPath>,
+    deadline: Option<Duration>,
+    nsid: Option<Vec<u8>>,
+    // Functions of the script the queries from the listeners start at
+    entries: HashMap<Listener, Arc<str>>,
+    counters: RouterCounters,
 }
 
 impl<T: ScriptBackend> Validatable for Router<T> {
     type Error = ScriptError;
     fn validate(&self, _: Option<&Vec<Label>>) -> Result<(), Self::Error> {
         self.script.validate(None)?;
+        self.special_use.validate(self.script.upstreams())?;
+        self.zones.validate(self.script.upstreams())?;
+        if let Some(entry) = self.entries.values().find(|e| !self.script.has_entry(e)) {
+            return Err(ScriptError::MissingEntry(entry.to_string()));
+        }
         Ok(())
     }
 }
@@ -48,40 +133,437 @@ impl<T: ScriptBackend> Validatable for Router<T> {
 impl<T: ScriptBackend> Router<T> {
     /// Create a new `Router` from raw
     pub fn new(script: T) -> Result<Self, ScriptError> {
-        let router = Self { script };
+        let router = Self {
+            script,
+            any_policy: AnyPolicy::default(),
+            edns_policy: EdnsPolicy::default(),
+            answer_order: AnswerOrder::default(),
+            rotation: AtomicUsize::new(0),
+            chaos: ChaosPolicy::default(),
+            special_use: SpecialUse::default(),
+            zones: Zones::default(),
+            root_mirror: None,
+            slow_query: None,
+            fast_path: None,
+            deadline: None,
+            nsid: None,
+            entries: HashMap::new(),
+            counters: RouterCounters::default(),
+        };
         router.validate(None)?;
         Ok(router)
     }
 
+    /// Get a snapshot of the statistics collected since the router was created.
+    pub fn stats(&self) -> RouterStats {
+        let upstreams = self.script.upstreams();
+        RouterStats {
+            queries: self.counters.queries.get(),
+            errors: self.counters.errors.get(),
+            special_use: self.counters.special_use.get(),
+            zones: self.counters.zones.get(),
+            root_mirror: self.counters.root_mirror.get(),
+            chaos: self.counters.chaos.get(),
+            any: self.counters.any.get(),
+            fast_path: self.counters.fast_path.get(),
+            cache: upstreams.cache_stats(),
+            upstreams: upstreams.stats(),
+        }
+    }
+
+    /// Drop the responses in the fast path if any, e.g. as the groups of rules they were evaluated by are toggled.
+    pub fn purge_fast_path(&self) {
+        if let Some(fast_path) = &self.fast_path {
+            fast_path.purge();
+        }
+    }
+
+    /// The upstreams the router routes queries to, e.g. to probe them.
+    pub fn upstreams(&self) -> &Upstreams {
+        self.script.upstreams()
+    }
+
+    // Create a response with nothing but the rcode given.
+    fn reply(msg: &Message<Bytes>, rcode: Rcode) -> Result<Message<Bytes>, ScriptError> {
+        Ok(
+            MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+                .start_answer(msg, rcode)?
+                .into_message(),
+        )
+    }
+
+    // Create a SERVFAIL response carrying the Extended DNS Error given, if the client speaks EDNS.
+    fn servfail(
+        msg: &Message<Bytes>,
+        info_code: u16,
+        text: &str,
+    ) -> Result<Message<Bytes>, ScriptError> {
+        let builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+            .start_answer(msg, Rcode::ServFail)?;
+        if msg.opt().is_none() {
+            return Ok(builder.into_message());
+        }
+        let mut rdata = Vec::with_capacity(6 + text.len());
+        rdata.extend_from_slice(&EDE_OPTION.to_be_bytes());
+        rdata.extend_from_slice(&(2 + text.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(&info_code.to_be_bytes());
+        rdata.extend_from_slice(text.as_bytes());
+        let mut builder = builder.additional();
+        builder.push(Record::new(
+            Dname::root_bytes(),
+            Class::Int(MAX_LEN as u16),
+            0,
+            UnknownRecordData::from_octets(Rtype::Opt, Bytes::from(rdata)),
+        ))?;
+        Ok(builder.into_message())
+    }
+
+    // Apply the router-level policies, and hand the query over to the script if none of them applies.
+    async fn route(
+        &self,
+        msg: &Message<Bytes>,
+        question: &Question<Dname<Bytes>>,
+        qctx: Option<QueryContext>,
+    ) -> Result<Message<Bytes>, ScriptError> {
+        // Every policy and the script see the query only with the EDNS options the clients are allowed to forward.
+        let msg = &self.edns_policy.apply(msg)?;
+
+        // Queries in the CHAOS class are about the server itself, so they never leave it.
+        if let Some(resp) = self.chaos.answer(msg, question)? {
+            self.counters.chaos.inc();
+            QueryTrace::note(|| "answered in the CHAOS class locally".to_string());
+            return Ok(resp);
+        }
+
+        if let Some(policy) = self.special_use.get(question.qname()) {
+            if policy != &SpecialUsePolicy::Forward {
+                self.counters.special_use.inc();
+            }
+            QueryTrace::note(|| {
+                format!(
+                    "special-use domain policy applied: {}",
+                    match policy {
+                        SpecialUsePolicy::Forward => "forward".to_string(),
+                        SpecialUsePolicy::Refuse => "refuse".to_string(),
+                        SpecialUsePolicy::Nxdomain => "nxdomain".to_string(),
+                        SpecialUsePolicy::Upstream(tag) => format!("upstream {}", tag),
+                    }
+                )
+            });
+            match policy {
+                SpecialUsePolicy::Forward => {}
+                SpecialUsePolicy::Refuse => {
+                    info!(
+                        "refusing query for special-use domain {}",
+                        privacy::qname(question.qname())
+                    );
+                    return Self::reply(msg, Rcode::Refused);
+                }
+                SpecialUsePolicy::Nxdomain => {
+                    info!(
+                        "answering NXDOMAIN for special-use domain {}",
+                        privacy::qname(question.qname())
+                    );
+                    return Self::reply(msg, Rcode::NXDomain);
+                }
+                SpecialUsePolicy::Upstream(tag) => {
+                    return Ok(self
+                        .script
+                        .upstreams()
+                        .send(tag, &CacheMode::default(), msg)
+                        .await?)
+                }
+            }
+        }
+
+        if let Some(tag) = self.zones.get(question.qname()) {
+            self.counters.zones.inc();
+            QueryTrace::note(|| format!("forwarding to upstream {} per zones", tag));
+            return Ok(self
+                .script
+                .upstreams()
+                .send(tag, &CacheMode::default(), msg)
+                .await?);
+        }
+
+        // Zones come first, so that private top-level domains can still be forwarded.
+        if let Some(resp) = match &self.root_mirror {
+            Some(mirror) => mirror.answer(msg, question)?,
+            None => None,
+        } {
+            self.counters.root_mirror.inc();
+            QueryTrace::note(|| "answered from the root zone mirror".to_string());
+            return Ok(resp);
+        }
+
+        if question.qtype() == Rtype::Any {
+            self.route_any(msg, qctx).await
+        } else {
+            QueryTrace::note(|| "routing with script".to_string());
+            // Clone should be cheap here guaranteed by Bytes
+            self.script
+                .route(msg.clone(), qctx)
+                .instrument(tracing::info_span!("script"))
+                .await
+        }
+    }
+
+    // Route ANY queries per the ANY policy configured.
+    async fn route_any(
+        &self,
+        msg: &Message<Bytes>,
+        qctx: Option<QueryContext>,
+    ) -> Result<Message<Bytes>, ScriptError> {
+        if self.any_policy != AnyPolicy::Forward {
+            self.counters.any.inc();
+        }
+        QueryTrace::note(|| {
+            format!(
+                "ANY policy applied: {}",
+                match self.any_policy {
+                    AnyPolicy::Forward => "forward",
+                    AnyPolicy::Hinfo => "hinfo",
+                    AnyPolicy::Split => "split",
+                }
+            )
+        });
+        match self.any_policy {
+            AnyPolicy::Forward => self.script.route(msg.clone(), qctx).await,
+            AnyPolicy::Hinfo => {
+                info!("answering ANY query with synthesized HINFO record");
+                Ok(minimal_any(msg)?)
+            }
+            AnyPolicy::Split => {
+                info!("splitting ANY query into A and AAAA queries");
+                let (a, aaaa) = try_join(
+                    self.script
+                        .route(any::requery(msg, Rtype::A)?, qctx.clone()),
+                    self.script.route(any::requery(msg, Rtype::Aaaa)?, qctx),
+                )
+                .await?;
+                Ok(any::merge(msg, &[a, aaaa])?)
+            }
+        }
+    }
+
     /// Resolve the DNS query with routing rules defined.
+    #[tracing::instrument(name = "resolve", skip_all, fields(qname = field::Empty, qtype = field::Empty))]
     pub async fn resolve(
         &self,
         msg: Message<Bytes>,
         qctx: Option<QueryContext>,
     ) -> Result<Message<Bytes>, ScriptError> {
+        let n = self.counters.queries.fetch_inc();
         // We have to ensure the number of queries is larger than 0 as it is a gurantee for actions/matchers.
         // Not using `query_count()` because it is manually set, and may not be correct.
-        Ok(match msg.sole_question() {
-            Ok(_) => {
-                // Clone should be cheap here guaranteed by Bytes
-                match self.script.route(msg.clone(), qctx).await {
-                    Ok(m) => m,
-                    Err(e) => {
-                        // Catch all server failure here and return server fail
-                        warn!("upstream encountered error: {}, returning SERVFAIL", e);
-                        MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
-                            .start_answer(&msg, Rcode::ServFail)?
-                            .into_message()
-                    }
+        let question = match msg.sole_question() {
+            Ok(q) => {
+                let span = Span::current();
+                if !span.is_disabled() {
+                    span.record("qname", &field::display(privacy::qname(&q.qname())));
                 }
+                span.record("qtype", &field::display(q.qtype()));
+                Question::new(q.qname().to_bytes(), q.qtype(), q.qclass())
+            }
+            // Malformed queries are common on the open internet, don't flood the log.
+            Err(e) => {
+                debug!("malformed query: {}, returning FORMERR", e);
+                self.counters.errors.inc();
+                return Self::reply(&msg, Rcode::FormErr);
+            }
+        };
+
+        let client = qctx.as_ref().map(|c| c.ip);
+        // Queries starting at other entries may well be answered differently, so they are kept out of the fast path.
+        let fast_path = self
+            .fast_path
+            .as_ref()
+            .filter(|_| qctx.as_ref().map_or(true, |c| c.entry.is_none()));
+        if let Some(fast_path) = fast_path {
+            if let Some(resp) = fast_path.get(&msg, question.qname()) {
+                self.counters.fast_path.inc();
+                return self.reorder(self.identify(&msg, resp), client);
+            }
+        }
+
+        let slow_query = match self.slow_query {
+            Some(s) => s,
+            None => {
+                let resp = self.resolve_question(&msg, &question, qctx).await?;
+                let resp = Self::remember(fast_path, &msg, &question, resp);
+                return self.reorder(self.identify(&msg, resp), client);
             }
+        };
+
+        let (resp, trace) = QueryTrace::scope(self.resolve_question(&msg, &question, qctx)).await;
+        let elapsed = trace.elapsed();
+        if elapsed >= slow_query.threshold() {
+            warn!(
+                "slow query for {} {} took {}ms: {}",
+                privacy::qname(question.qname()),
+                question.qtype(),
+                elapsed.as_millis(),
+                trace
+            );
+        } else if slow_query.sampled(n) {
+            info!(
+                "sampled query for {} {} took {}ms: {}",
+                privacy::qname(question.qname()),
+                question.qtype(),
+                elapsed.as_millis(),
+                trace
+            );
+        }
+        let resp = Self::remember(fast_path, &msg, &question, resp?);
+        self.reorder(self.identify(&msg, resp), client)
+    }
+
+    // Keep the response in the fast path if there is one.
+    fn remember(
+        fast_path: Option<&FastPath>,
+        msg: &Message<Bytes>,
+        question: &Question<Dname<Bytes>>,
+        resp: Message<Bytes>,
+    ) -> Message<Bytes> {
+        if let Some(fast_path) = fast_path {
+            fast_path.put(msg, question.qname(), &resp);
+        }
+        resp
+    }
+
+    // Answer the NSID of the server (RFC 5001) if the client asked for it, in place of the upstream's, leaving the response as it is if that fails.
+    fn identify(&self, msg: &Message<Bytes>, resp: Message<Bytes>) -> Message<Bytes> {
+        match (&self.nsid, edns_option(msg, NSID)) {
+            (Some(nsid), Ok(Some(_))) => set_edns_option(&resp, NSID, nsid).unwrap_or_else(|e| {
+                debug!("failed to answer the NSID: {}", e);
+                resp
+            }),
+            _ => resp,
+        }
+    }
+
+    // Reorder the addresses in the response per the answer order, leaving it as it is if that fails.
+    fn reorder(
+        &self,
+        resp: Message<Bytes>,
+        client: Option<IpAddr>,
+    ) -> Result<Message<Bytes>, ScriptError> {
+        Ok(self
+            .answer_order
+            .apply(&resp, client, &self.rotation)
+            .unwrap_or_else(|e| {
+                debug!("failed to reorder the answers: {}", e);
+                resp
+            }))
+    }
+
+    /// Resolve a query packet from the client the way a DNS server does, on top of `resolve`: malformed packets and responses are dropped,
+    /// and responses over UDP are truncated to what the client can take. Returns `None` if nothing should be sent back.
+    pub async fn resolve_raw(&self, query: Bytes, client: ClientInfo) -> Option<Message<Bytes>> {
+        let limit = client.udp_limit.map(|max| {
+            let (limit, edns) = client_limit(&query);
+            (limit.min(max), edns)
+        });
+        // Packets without even a complete header cannot be answered.
+        let msg = match Message::from_octets(query) {
+            Ok(msg) => msg,
+            Err(_) => {
+                debug!(
+                    "dropping malformed packet from {}",
+                    privacy::client(client.addr)
+                );
+                return None;
+            }
+        };
+        // Never answer responses, which may otherwise form a loop.
+        if msg.header().qr() {
+            debug!(
+                "dropping response packet from {}",
+                privacy::client(client.addr)
+            );
+            return None;
+        }
+        let opt = msg.opt();
+        let qctx = QueryContext {
+            ip: client.addr.ip(),
+            listener: client.listener,
+            encrypted: client.encrypted,
+            edns: opt.is_some(),
+            dnssec_ok: opt.map_or(false, |opt| opt.dnssec_ok()),
+            entry: self.entries.get(&client.listener).cloned(),
+        };
+        let resp = match self.resolve(msg, Some(qctx)).await {
+            Ok(resp) => resp,
             Err(e) => {
-                warn!("DNS message parsing errored: {}.", e);
-                MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
-                    .start_answer(&msg, Rcode::ServFail)?
-                    .into_message()
+                warn!(
+                    "failed to answer query from {}: {}",
+                    privacy::client(client.addr),
+                    e
+                );
+                return None;
             }
-        })
+        };
+        match limit {
+            Some((limit, edns)) => fit(resp, limit, edns),
+            None => Some(resp),
+        }
+    }
+
+    /// Resolve the queries from the stream with at most `concurrency` of them in flight, e.g. for bulk lookups.
+    /// Responses are yielded in the same order as the queries.
+    pub fn resolve_stream<'a>(
+        &'a self,
+        queries: impl Stream<Item = Message<Bytes>> + 'a,
+        concurrency: usize,
+    ) -> impl Stream<Item = Result<Message<Bytes>, ScriptError>> + 'a {
+        queries
+            .map(move |query| self.resolve(query, None))
+            .buffered(concurrency.max(1))
+    }
+
+    async fn resolve_question(
+        &self,
+        msg: &Message<Bytes>,
+        question: &Question<Dname<Bytes>>,
+        qctx: Option<QueryContext>,
+    ) -> Result<Message<Bytes>, ScriptError> {
+        let client = qctx.as_ref().map(|c| c.ip);
+        let route = with_client(client, self.route(msg, question, qctx));
+        match deadline::scope(self.deadline, route).await {
+            Ok(Ok(m)) => Ok(m),
+            // Every upstream attempt in flight has been dropped along with the query by now.
+            Err(Exceeded(deadline)) => {
+                warn!(
+                    "query for {} {} exceeded its deadline of {}ms, returning SERVFAIL",
+                    privacy::qname(question.qname()),
+                    question.qtype(),
+                    deadline.as_millis()
+                );
+                QueryTrace::note(|| {
+                    format!(
+                        "answering SERVFAIL past the deadline of {}ms",
+                        deadline.as_millis()
+                    )
+                });
+                self.counters.errors.inc();
+                Self::servfail(
+                    msg,
+                    EDE_NO_REACHABLE_AUTHORITY,
+                    &format!("deadline of {}ms exceeded", deadline.as_millis()),
+                )
+            }
+            Ok(Err(e)) => {
+                // Catch all server failure here and return server fail
+                warn!(
+                    "upstream encountered {} error: {}, returning SERVFAIL",
+                    e.kind(),
+                    e
+                );
+                QueryTrace::note(|| format!("answering SERVFAIL on {} error: {}", e.kind(), e));
+                self.counters.errors.inc();
+                Self::reply(msg, Rcode::ServFail)
+            }
+        }
     }
 }
 
@@ -94,6 +576,18 @@ where
 {
     script: S,
     upstreams: U,
+    any_policy: AnyPolicy,
+    edns_policy: EdnsPolicy,
+    answer_order: AnswerOrder,
+    chaos: ChaosPolicy,
+    special_use: SpecialUse,
+    zones: Zones,
+    root_mirror: Option<RootMirror>,
+    slow_query: Option<SlowQueryLog>,
+    fast_path: Option<FastPath>,
+    deadline: Option<Duration>,
+    nsid: Option<Vec<u8>>,
+    entries: HashMap<Listener, Arc<str>>,
     _phantom: PhantomData<T>,
 }
 
@@ -108,9 +602,94 @@ where
         Self {
             script,
             upstreams,
+            any_policy: AnyPolicy::default(),
+            edns_policy: EdnsPolicy::default(),
+            answer_order: AnswerOrder::default(),
+            chaos: ChaosPolicy::default(),
+            special_use: SpecialUse::default(),
+            zones: Zones::default(),
+            root_mirror: None,
+            slow_query: None,
+            fast_path: None,
+            deadline: None,
+            nsid: None,
+            entries: HashMap::new(),
             _phantom: PhantomData::default(),
         }
     }
+
+    /// Set the way ANY queries are handled
+    pub fn any_policy(mut self, policy: AnyPolicy) -> Self {
+        self.any_policy = policy;
+        self
+    }
+
+    /// Set which EDNS options supplied by the clients are forwarded. Options stripped here are out of reach of the script.
+    pub fn edns_policy(mut self, policy: EdnsPolicy) -> Self {
+        self.edns_policy = policy;
+        self
+    }
+
+    /// Set the order of the address records in the answers
+    pub fn answer_order(mut self, order: AnswerOrder) -> Self {
+        self.answer_order = order;
+        self
+    }
+
+    /// Set the answers to the queries in the CHAOS class, e.g. `version.bind`. Queries in the class are never sent to the upstreams.
+    pub fn chaos(mut self, chaos: ChaosPolicy) -> Self {
+        self.chaos = chaos;
+        self
+    }
+
+    /// Set the special-use domains and the policies applied to them
+    pub fn special_use(mut self, special_use: SpecialUse) -> Self {
+        self.special_use = special_use;
+        self
+    }
+
+    /// Set the zones whose queries are forwarded to the designated upstreams
+    pub fn zones(mut self, zones: Zones) -> Self {
+        self.zones = zones;
+        self
+    }
+
+    /// Answer the queries the root zone is authoritative for out of the local copy in the mirror given (RFC 8806)
+    pub fn root_mirror(mut self, mirror: RootMirror) -> Self {
+        self.root_mirror = Some(mirror);
+        self
+    }
+
+    /// Enable the slow-query log
+    pub fn slow_query(mut self, slow_query: SlowQueryLog) -> Self {
+        self.slow_query = Some(slow_query);
+        self
+    }
+
+    /// Answer the queries answered before out of the fast path given, skipping the policies and the script
+    pub fn fast_path(mut self, fast_path: FastPath) -> Self {
+        self.fast_path = Some(fast_path);
+        self
+    }
+
+    /// Answer SERVFAIL with an Extended DNS Error to the queries not answered within `deadline`, cancelling the upstream attempts in flight.
+    /// Scripts may override it for the query with `set_deadline`.
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Answer the NSID given (RFC 5001) to the clients asking for it, e.g. to tell the instances behind an anycast address apart
+    pub fn nsid(mut self, nsid: impl Into<Vec<u8>>) -> Self {
+        self.nsid = Some(nsid.into());
+        self
+    }
+
+    /// Start the queries from the listeners given at the functions of the script named so instead of the default one, e.g. to apply stricter policies to DoH clients.
+    pub fn entries(mut self, entries: HashMap<Listener, String>) -> Self {
+        self.entries = entries.into_iter().map(|(l, e)| (l, e.into())).collect();
+        self
+    }
 }
 
 #[async_trait(?Send)]
@@ -125,6 +704,24 @@ where
     /// Build a new `Router` from configuration and check the validity. `data` is the content of the configuration file.
     async fn async_try_into(self) -> Result<Router<T>, ScriptError> {
         let upstreams = self.upstreams.async_try_into().await?;
-        Router::new(self.script.build(upstreams).await?)
+        let router = Router {
+            script: self.script.build(upstreams).await?,
+            any_policy: self.any_policy,
+            edns_policy: self.edns_policy,
+            answer_order: self.answer_order,
+            rotation: AtomicUsize::new(0),
+            chaos: self.chaos,
+            special_use: self.special_use,
+            zones: self.zones,
+            root_mirror: self.root_mirror,
+            slow_query: self.slow_query,
+            fast_path: self.fast_path,
+            deadline: self.deadline,
+            nsid: self.nsid,
+            entries: self.entries,
+            counters: RouterCounters::default(),
+        };
+        router.validate(None)?;
+        Ok(router)
     }
 }
@@ -0,0 +1,70 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::utils::{rotate_answers, shuffle_answers, Result};
+use bytes::Bytes;
+use domain::base::Message;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The order of the address records in the answers `Router` returns.
+/// Reordering is applied to every response including the cached ones, which would otherwise keep the order first received until they expire.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnswerOrder {
+    /// Keep the order the upstreams (or the script) returned.
+    Keep,
+    /// Shuffle the addresses for every response.
+    Shuffle,
+    /// Rotate the addresses by one for every response, like BIND's cyclic order.
+    RoundRobin,
+    /// Rotate the addresses by an amount fixed for each client, so that every client sees a stable order while the clients as a whole are spread.
+    PerClient,
+}
+
+impl Default for AnswerOrder {
+    fn default() -> Self {
+        Self::Keep
+    }
+}
+
+impl AnswerOrder {
+    // Reorder the response to the client given. `counter` is the number of responses reordered so far.
+    pub(super) fn apply(
+        &self,
+        resp: &Message<Bytes>,
+        client: Option<IpAddr>,
+        counter: &AtomicUsize,
+    ) -> Result<Message<Bytes>> {
+        match self {
+            Self::Keep => Ok(resp.clone()),
+            Self::Shuffle => shuffle_answers(resp),
+            Self::RoundRobin => rotate_answers(resp, counter.fetch_add(1, Ordering::Relaxed)),
+            Self::PerClient => match client {
+                Some(ip) => {
+                    let mut hasher = DefaultHasher::new();
+                    ip.hash(&mut hasher);
+                    rotate_answers(resp, hasher.finish() as usize)
+                }
+                None => Ok(resp.clone()),
+            },
+        }
+    }
+}
@@ -0,0 +1,354 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Local copy of the root zone (RFC 8806), answering the queries the root zone is authoritative for without sending them anywhere.
+//!
+//! Names under top-level domains not in the root zone are answered NXDOMAIN, and the SOA and NS records of the root and the DS records of the top-level domains are answered from the copy.
+//! Everything else is left to the rest of the router.
+
+use super::script::MessageError;
+use crate::MAX_LEN;
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{
+        iana::{Class, DigestAlg, Rcode, SecAlg},
+        name::ParsedDname,
+        question::Question,
+        Dname, Message, MessageBuilder, Record, Rtype, Serial,
+    },
+    rdata::{AllRecordData, Ds, Ns, Soa},
+};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+type ZoneRecord = Record<Dname<Bytes>, AllRecordData<Bytes, Dname<Bytes>>>;
+
+/// Records of the root zone needed to answer, i.e. the SOA and NS records of the root and the NS and DS records of the top-level domains.
+pub struct RootZone {
+    soa: Soa<Dname<Bytes>>,
+    soa_ttl: u32,
+    apex: Vec<ZoneRecord>,
+    // Records of each top-level domain, by the lowercased label
+    tlds: HashMap<String, Vec<ZoneRecord>>,
+}
+
+fn int<T: FromStr>(s: &str, line: &str) -> Result<T, MessageError> {
+    s.parse()
+        .map_err(|_| MessageError::MalformedZone(line.to_string()))
+}
+
+impl FromStr for RootZone {
+    type Err = MessageError;
+
+    /// Parse the root zone in the format it is published in, e.g. on `https://www.internic.net/domain/root.zone`, with one record per line in full.
+    /// The zone is used only if it passes the verification of its ZONEMD records (RFC 8976).
+    fn from_str(s: &str) -> Result<Self, MessageError> {
+        super::zonemd::verify(s)?;
+        let mut records = Vec::new();
+        for line in s.lines() {
+            let fields: Vec<_> = line
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .split_whitespace()
+                .collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let malformed = || MessageError::MalformedZone(line.to_string());
+            let (owner, ttl, class, rtype, rdata) = match fields.as_slice() {
+                [owner, ttl, class, rtype, rdata @ ..] => (owner, ttl, class, rtype, rdata),
+                _ => return Err(malformed()),
+            };
+            if !class.eq_ignore_ascii_case("IN") {
+                continue;
+            }
+            let data = match (rtype.to_ascii_uppercase().as_str(), rdata) {
+                ("SOA", [mname, rname, serial, refresh, retry, expire, minimum]) => {
+                    AllRecordData::Soa(Soa::new(
+                        Dname::from_str(mname)?,
+                        Dname::from_str(rname)?,
+                        Serial(int(serial, line)?),
+                        int(refresh, line)?,
+                        int(retry, line)?,
+                        int(expire, line)?,
+                        int(minimum, line)?,
+                    ))
+                }
+                ("NS", [nsdname]) => AllRecordData::Ns(Ns::new(Dname::from_str(nsdname)?)),
+                ("DS", [key_tag, algorithm, digest_type, digest @ ..]) if !digest.is_empty() => {
+                    AllRecordData::Ds(Ds::new(
+                        int(key_tag, line)?,
+                        SecAlg::from_int(int(algorithm, line)?),
+                        DigestAlg::from_int(int(digest_type, line)?),
+                        hex::decode(digest.concat())
+                            .map_err(|_| malformed())?
+                            .into(),
+                    ))
+                }
+                ("SOA" | "NS" | "DS", _) => return Err(malformed()),
+                // Glue and DNSSEC records are not needed to answer.
+                _ => continue,
+            };
+            records.push(Record::new(
+                Dname::from_str(owner)?,
+                Class::In,
+                int(ttl, line)?,
+                data,
+            ));
+        }
+        Self::new(records)
+    }
+}
+
+impl RootZone {
+    fn new(records: Vec<ZoneRecord>) -> Result<Self, MessageError> {
+        let mut soa = None;
+        let mut apex = Vec::new();
+        let mut tlds: HashMap<String, Vec<ZoneRecord>> = HashMap::new();
+        for record in records {
+            match (record.owner().label_count(), record.data()) {
+                (1, AllRecordData::Soa(s)) => {
+                    // Zone transfers start and end with the SOA record.
+                    if soa.is_none() {
+                        soa = Some((s.clone(), record.ttl()));
+                    }
+                }
+                (1, AllRecordData::Ns(_)) => apex.push(record),
+                (2, AllRecordData::Ns(_) | AllRecordData::Ds(_)) => {
+                    // Owners with two labels have their top-level label first.
+                    let tld = record.owner().first().to_string().to_lowercase();
+                    tlds.entry(tld).or_default().push(record);
+                }
+                _ => (),
+            }
+        }
+        let (soa, soa_ttl) = soa
+            .ok_or_else(|| MessageError::MalformedZone("no SOA record of the root".to_string()))?;
+        Ok(Self {
+            soa,
+            soa_ttl,
+            apex,
+            tlds,
+        })
+    }
+
+    /// Build the root zone out of the messages of a zone transfer (AXFR), verified the same way as the zone published.
+    pub fn from_axfr(msgs: &[Message<Bytes>]) -> Result<Self, MessageError> {
+        let mut zone = String::new();
+        for msg in msgs {
+            for item in msg.answer()? {
+                let record =
+                    match item?.into_record::<AllRecordData<Bytes, ParsedDname<&Bytes>>>()? {
+                        Some(record) => record,
+                        None => continue,
+                    };
+                let owner = record.owner().to_string();
+                zone.push_str(&format!(
+                    "{}{} {} {} {} {}\n",
+                    owner,
+                    if owner.ends_with('.') { "" } else { "." },
+                    record.ttl(),
+                    record.class(),
+                    record.rtype(),
+                    record.data()
+                ));
+            }
+        }
+        zone.parse()
+    }
+
+    /// Serial number of the zone
+    pub fn serial(&self) -> u32 {
+        self.soa.serial().into_int()
+    }
+
+    /// Number of top-level domains in the zone
+    pub fn tlds(&self) -> usize {
+        self.tlds.len()
+    }
+
+    // Answer the query if the root zone is authoritative for it.
+    fn answer(
+        &self,
+        msg: &Message<Bytes>,
+        question: &Question<Dname<Bytes>>,
+    ) -> Result<Option<Message<Bytes>>, MessageError> {
+        if question.qclass() != Class::In {
+            return Ok(None);
+        }
+        let (qname, qtype) = (question.qname(), question.qtype());
+        let (rcode, answers): (_, Vec<_>) = if qname.is_root() {
+            match qtype {
+                Rtype::Soa => (
+                    Rcode::NoError,
+                    vec![Record::new(
+                        qname.clone(),
+                        Class::In,
+                        self.soa_ttl,
+                        AllRecordData::Soa(self.soa.clone()),
+                    )],
+                ),
+                Rtype::Ns => (Rcode::NoError, self.apex.clone()),
+                _ => return Ok(None),
+            }
+        } else {
+            // The label before the root label is the top-level one.
+            let tld = qname
+                .iter()
+                .nth(qname.label_count() - 2)
+                .map(|l| l.to_string().to_lowercase())
+                .unwrap_or_default();
+            match self.tlds.get(&tld) {
+                None => (Rcode::NXDomain, Vec::new()),
+                // DS records are the only ones at the top-level domains the root zone is authoritative for.
+                Some(records) if qname.label_count() == 2 && qtype == Rtype::Ds => (
+                    Rcode::NoError,
+                    records
+                        .iter()
+                        .filter(|r| r.rtype() == Rtype::Ds)
+                        .cloned()
+                        .collect(),
+                ),
+                Some(_) => return Ok(None),
+            }
+        };
+
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+            .start_answer(msg, rcode)?;
+        builder.header_mut().set_aa(true);
+        let negative = answers.is_empty();
+        for record in answers {
+            builder.push(record)?;
+        }
+        let mut builder = builder.authority();
+        if negative {
+            // Negative answers are cached for the lesser of the TTL and the minimum of the SOA record (RFC 2308).
+            builder.push((
+                Dname::root_bytes(),
+                self.soa_ttl.min(self.soa.minimum()),
+                self.soa.clone(),
+            ))?;
+        }
+        Ok(Some(builder.into_message()))
+    }
+}
+
+/// Handle to the copy of the root zone in use, which can be shared among routers and updated as the zone is refreshed.
+#[derive(Clone, Default)]
+pub struct RootMirror(Arc<RwLock<Option<(Arc<RootZone>, Instant)>>>);
+
+impl RootMirror {
+    /// Create a mirror with no copy of the root zone, which answers nothing until it is updated.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the copy of the root zone in use.
+    pub fn update(&self, zone: RootZone) {
+        *self.0.write().unwrap() = Some((Arc::new(zone), Instant::now()));
+    }
+
+    /// Serial number of the copy of the root zone in use, if any.
+    pub fn serial(&self) -> Option<u32> {
+        self.zone().map(|z| z.serial())
+    }
+
+    // The copy of the root zone in use. Copies not refreshed within the expire time of the SOA record are no longer used (RFC 8806).
+    fn zone(&self) -> Option<Arc<RootZone>> {
+        match &*self.0.read().unwrap() {
+            Some((zone, updated))
+                if updated.elapsed() <= Duration::from_secs(zone.soa.expire().into()) =>
+            {
+                Some(zone.clone())
+            }
+            _ => None,
+        }
+    }
+
+    // Answer the query out of the copy of the root zone if it is authoritative for it.
+    pub(super) fn answer(
+        &self,
+        msg: &Message<Bytes>,
+        question: &Question<Dname<Bytes>>,
+    ) -> Result<Option<Message<Bytes>>, MessageError> {
+        match self.zone() {
+            Some(zone) => zone.answer(msg, question),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RootMirror, RootZone};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{iana::Rcode, question::Question, Dname, Message, MessageBuilder, Rtype};
+    use std::str::FromStr;
+
+    const ZONE: &str = "\
+.			86400	IN	SOA	a.root-servers.net. nstld.verisign-grs.com. 2023010300 1800 900 604800 86400
+.			518400	IN	NS	a.root-servers.net.
+.			86400	IN	RRSIG	SOA 8 0 86400 20230116050000 20230103040000 951 . c29tZXRoaW5n
+.			86400	IN	ZONEMD	2023010300 1 1 84E8FFAC2F42F66BC977878103BE267A31123C946C3380589DDA57F2A711455933FE8FF271F6AF5FF570FBFBC3C3E651
+com.			172800	IN	NS	a.gtld-servers.net.
+com.			86400	IN	DS	30909 8 2 E2D3C916F6DEEAC73294E8268FB5885044A833FC5459588F4A9184CF C41A5766
+a.root-servers.net.	518400	IN	A	198.41.0.4
+";
+
+    fn query(name: &str, qtype: Rtype) -> (Message<Bytes>, Question<Dname<Bytes>>) {
+        let name = Dname::<Bytes>::from_str(name).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((&name, qtype)).unwrap();
+        (builder.into_message(), Question::new_in(name, qtype))
+    }
+
+    #[test]
+    fn answer_from_mirror() {
+        let mirror = RootMirror::new();
+        let (msg, question) = query("www.example.invalid", Rtype::A);
+        // Nothing is answered before the zone is there.
+        assert!(mirror.answer(&msg, &question).unwrap().is_none());
+
+        // Copies not matching their digest are refused.
+        assert!(RootZone::from_str(&ZONE.replace("198.41.0.4", "192.0.2.1")).is_err());
+        mirror.update(RootZone::from_str(ZONE).unwrap());
+        assert_eq!(mirror.serial(), Some(2023010300));
+
+        let resp = mirror.answer(&msg, &question).unwrap().unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+        assert!(resp.header().aa());
+        assert_eq!(resp.header_counts().nscount(), 1);
+
+        let (msg, question) = query("com", Rtype::Ds);
+        let resp = mirror.answer(&msg, &question).unwrap().unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert_eq!(resp.header_counts().ancount(), 1);
+
+        let (msg, question) = query(".", Rtype::Ns);
+        let resp = mirror.answer(&msg, &question).unwrap().unwrap();
+        assert_eq!(resp.header_counts().ancount(), 1);
+
+        // Names under existing top-level domains are left to the upstreams.
+        let (msg, question) = query("www.example.COM", Rtype::A);
+        assert!(mirror.answer(&msg, &question).unwrap().is_none());
+    }
+}
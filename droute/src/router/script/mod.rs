@@ -29,7 +29,7 @@ pub mod builders {
     pub use super::native::NativeScriptBuilder;
 }
 
-use crate::{Upstreams, Validatable};
+use crate::{errors::ErrorKind, Upstreams, Validatable};
 use async_trait::async_trait;
 use bytes::Bytes;
 use domain::base::{
@@ -38,9 +38,11 @@ use domain::base::{
     octets::ParseError,
     Message, ShortBuf,
 };
+use serde::{Deserialize, Serialize};
 use std::{
     net::{AddrParseError, IpAddr},
     string::FromUtf8Error,
+    sync::Arc,
 };
 use thiserror::Error;
 
@@ -104,6 +106,38 @@ pub enum MessageError {
     /// Buffer is too short
     #[error(transparent)]
     ShortBuf(#[from] ShortBuf),
+
+    /// The zone given is malformed
+    #[error("malformed zone: {0}")]
+    MalformedZone(String),
+
+    /// The zone fails the verification of its message digest
+    #[error("the zone fails the verification of its digest: {0}")]
+    ZoneDigest(String),
+}
+
+impl MessageError {
+    /// The category of the error. Values given by the script are part of the configuration, while messages and zones are fetched.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::ParseError(_)
+            | Self::ShortBuf(_)
+            | Self::NoFirstQuestion
+            | Self::MalformedZone(_)
+            | Self::ZoneDigest(_) => ErrorKind::Protocol,
+            Self::RecordUnsupported
+            | Self::OptionUnsupported
+            | Self::InvalidIpAddrType(_)
+            | Self::FromUtf8Error(_)
+            | Self::OpcodeFromStrErr(_)
+            | Self::RtypeFromStrErr(_)
+            | Self::ClassFromStrErr(_)
+            | Self::AddrParseError(_)
+            | Self::DnameParseError(_)
+            | Self::RcodeParseError(_)
+            | Self::PushError(_) => ErrorKind::Config,
+        }
+    }
 }
 
 /// Errors generated by the `script` module.
@@ -126,6 +160,10 @@ pub enum ScriptError {
     #[error(transparent)]
     UpstreamError(#[from] crate::errors::UpstreamError),
 
+    /// The script has no function of the name given to start the queries at
+    #[error("no entry function `{0}` found in the script")]
+    MissingEntry(String),
+
     /// Rune Emit Error
     #[cfg(feature = "rune-scripting")]
     #[error(transparent)]
@@ -147,12 +185,73 @@ pub enum ScriptError {
     RuneVmError(#[from] rune::runtime::VmError),
 }
 
+impl ScriptError {
+    /// The category of the error, taken from the innermost error wrapped. Failures of the script itself are considered as configuration errors.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::ShortBuf(_) => ErrorKind::Protocol,
+            Self::UtilsError(e) => e.kind(),
+            Self::MessageError(e) => e.kind(),
+            Self::UpstreamError(e) => e.kind(),
+            Self::MissingEntry(_) => ErrorKind::Config,
+            #[cfg(feature = "rune-scripting")]
+            Self::RuneEmitError(_)
+            | Self::RuneBuildError(_)
+            | Self::RuneContextError(_)
+            | Self::RuneVmError(_) => ErrorKind::Config,
+        }
+    }
+}
+
+/// The kind of listener a query is received on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Listener {
+    /// Plain DNS over UDP
+    Udp,
+    /// Plain DNS over TCP
+    Tcp,
+    /// DNS over HTTPS, served in plain HTTP with TLS terminated by a reverse proxy if any
+    Doh,
+    /// The gRPC API
+    Grpc,
+}
+
+impl Listener {
+    /// The protocol spoken on the listener: `dns`, `doh`, or `grpc`.
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            Self::Udp | Self::Tcp => "dns",
+            Self::Doh => "doh",
+            Self::Grpc => "grpc",
+        }
+    }
+
+    /// The transport the protocol runs over: `udp` or `tcp`.
+    pub fn transport(&self) -> &'static str {
+        match self {
+            Self::Udp => "udp",
+            Self::Tcp | Self::Doh | Self::Grpc => "tcp",
+        }
+    }
+}
+
 /// Query Context
 #[derive(Clone)]
 #[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
 pub struct QueryContext {
     /// Query sender's IP address
     pub ip: IpAddr,
+    /// Listener the query is received on
+    pub listener: Listener,
+    /// Whether the query is encrypted on the way from the client, i.e. received from a trusted proxy terminating TLS
+    pub encrypted: bool,
+    /// Whether the query carries an OPT record
+    pub edns: bool,
+    /// Whether the query has the DNSSEC OK bit set
+    pub dnssec_ok: bool,
+    /// Function of the script the query starts at if not the default one, per the listener it is received on
+    pub entry: Option<Arc<str>>,
 }
 
 /// A script backend routes every message with query context and the query itself.
@@ -164,6 +263,14 @@ pub trait ScriptBackend: Validatable<Error = ScriptError> {
         query: Message<Bytes>,
         ctx: Option<QueryContext>,
     ) -> Result<Message<Bytes>>;
+
+    /// The upstreams the script routes queries to.
+    fn upstreams(&self) -> &Upstreams;
+
+    /// Whether the script has the entry function given. Scripts without named functions take the entry from the query context instead.
+    fn has_entry(&self, _name: &str) -> bool {
+        true
+    }
 }
 
 /// A script builder is a type that builds itself into a script backend.
@@ -42,6 +42,10 @@ where
     ) -> Result<Message<Bytes>> {
         (self.script)(self.upstreams.clone(), query, ctx).await
     }
+
+    fn upstreams(&self) -> &Upstreams {
+        &self.upstreams
+    }
 }
 
 impl<F, T> Validatable for NativeScript<F, T>
@@ -13,7 +13,7 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::types::*;
+use super::{types::*, utils::SealedIpCidr};
 use crate::{errors::ScriptError, CacheMode, QueryContext, Upstreams};
 use once_cell::sync::Lazy;
 use rune::{runtime::Protocol, Module};
@@ -45,9 +45,61 @@ pub static BASIS_MODULE: Lazy<Module> = Lazy::new(|| {
             .into())
     }
 
+    async fn flatten_cname(
+        upstreams: &Upstreams,
+        tag: &str,
+        msg: &Message,
+    ) -> Result<Message, ScriptError> {
+        Ok(upstreams
+            .flatten_cname(&tag.into(), &CacheMode::default(), &msg.into())
+            .await?
+            .into())
+    }
+
+    async fn send_fallback(
+        upstreams: &Upstreams,
+        tag: &str,
+        fallback: &str,
+        msg: &Message,
+    ) -> Result<Message, ScriptError> {
+        Ok(upstreams
+            .send_fallback(
+                &tag.into(),
+                &fallback.into(),
+                &CacheMode::default(),
+                &msg.into(),
+                |_| false,
+            )
+            .await?
+            .into())
+    }
+
+    async fn send_fallback_reject(
+        upstreams: &Upstreams,
+        tag: &str,
+        fallback: &str,
+        msg: &Message,
+        reject: &SealedIpCidr,
+    ) -> Result<Message, ScriptError> {
+        Ok(upstreams
+            .send_fallback(
+                &tag.into(),
+                &fallback.into(),
+                &CacheMode::default(),
+                &msg.into(),
+                |ip| reject.0.contains(ip),
+            )
+            .await?
+            .into())
+    }
+
     m.ty::<Upstreams>().unwrap();
     m.async_inst_fn("send", send).unwrap();
     m.async_inst_fn("send_default", send_default).unwrap();
+    m.async_inst_fn("flatten_cname", flatten_cname).unwrap();
+    m.async_inst_fn("send_fallback", send_fallback).unwrap();
+    m.async_inst_fn("send_fallback_reject", send_fallback_reject)
+        .unwrap();
 
     m.ty::<CacheMode>().unwrap();
 
@@ -62,6 +114,28 @@ pub static BASIS_MODULE: Lazy<Module> = Lazy::new(|| {
         |qctx: &mut QueryContext, ip: IpAddr| qctx.ip = ip.into(),
     )
     .unwrap();
+    m.field_fn(Protocol::GET, "protocol", |qctx: &QueryContext| -> String {
+        qctx.listener.protocol().to_string()
+    })
+    .unwrap();
+    m.field_fn(
+        Protocol::GET,
+        "transport",
+        |qctx: &QueryContext| -> String { qctx.listener.transport().to_string() },
+    )
+    .unwrap();
+    m.field_fn(Protocol::GET, "encrypted", |qctx: &QueryContext| -> bool {
+        qctx.encrypted
+    })
+    .unwrap();
+    m.field_fn(Protocol::GET, "edns", |qctx: &QueryContext| -> bool {
+        qctx.edns
+    })
+    .unwrap();
+    m.field_fn(Protocol::GET, "dnssec_ok", |qctx: &QueryContext| -> bool {
+        qctx.dnssec_ok
+    })
+    .unwrap();
 
     m
 });
@@ -59,9 +59,10 @@ impl ScriptBackend for RuneScript {
         let send_exec = {
             let vm = Vm::new(self.context.clone(), self.unit.clone());
             let query: NewMessage = query.into();
+            let entry = ctx.as_ref().and_then(|c| c.entry.clone());
 
             vm.send_execute(
-                ["route"],
+                [entry.as_deref().unwrap_or("route")],
                 (self.upstreams.clone(), self.inited.clone(), ctx, query),
             )?
         };
@@ -73,6 +74,14 @@ impl ScriptBackend for RuneScript {
             .into(),
         )
     }
+
+    fn upstreams(&self) -> &Upstreams {
+        &self.upstreams
+    }
+
+    fn has_entry(&self, name: &str) -> bool {
+        self.unit.function(rune::Hash::type_hash([name])).is_some()
+    }
 }
 
 impl Validatable for RuneScript {
@@ -86,6 +95,7 @@ impl Validatable for RuneScript {
 
 /// A builder for `RuneScript`.
 /// Two pub async functionas are required in the script: `pub async fn init()` and `pub async fn route(upstreams, init, ctx, query)`.
+/// Queries from the listeners given other entries start at the functions of the same signature named so instead of `route`.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct RuneScriptBuilder(String);
 
@@ -94,6 +104,11 @@ impl RuneScriptBuilder {
     pub fn new(script: impl ToString) -> Self {
         Self(script.to_string())
     }
+
+    /// The script source code.
+    pub fn source(&self) -> &str {
+        &self.0
+    }
 }
 
 #[async_trait(?Send)]
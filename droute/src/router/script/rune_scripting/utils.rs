@@ -14,9 +14,18 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use super::types::*;
+#[cfg(feature = "geoip")]
+use crate::utils::GeoIp;
+#[cfg(feature = "wasm-plugins")]
+use crate::utils::Plugin;
 use crate::{
     errors::ScriptError,
-    utils::{blackhole, Domain, GeoIp, IpCidr},
+    utils::{
+        blackhole, edns_option_code, group_enabled, minimal_any, nodata, queries_svcb,
+        replace_edns_option, rotate_answers, rule_counter, set_deadline, shuffle_answers,
+        strip_ech, strip_edns_option, strip_ip_hints, Domain, IpCidr, ResponseMatcher, Rewrite,
+        RuleCounter, UtilsError,
+    },
 };
 use once_cell::sync::Lazy;
 use rune::Module;
@@ -26,20 +35,47 @@ use std::sync::Arc;
 pub enum Utils {
     #[rune(constructor)]
     Domain(#[rune(get)] SealedDomain),
+    #[cfg(feature = "geoip")]
     #[rune(constructor)]
     GeoIp(#[rune(get)] SealedGeoIp),
     #[rune(constructor)]
     IpCidr(#[rune(get)] SealedIpCidr),
+    #[rune(constructor)]
+    ResponseMatcher(#[rune(get)] SealedResponseMatcher),
+    #[rune(constructor)]
+    Rewrite(#[rune(get)] SealedRewrite),
+    #[cfg(feature = "wasm-plugins")]
+    #[rune(constructor)]
+    Plugin(#[rune(get)] SealedPlugin),
 }
 
 #[derive(rune::Any, Clone)]
-pub struct SealedDomain(Arc<Domain>);
+pub struct SealedDomain(Arc<Domain>, Option<Arc<RuleCounter>>);
+
+#[cfg(feature = "geoip")]
+#[derive(rune::Any, Clone)]
+pub struct SealedGeoIp(Arc<GeoIp>, Option<Arc<RuleCounter>>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedIpCidr(pub(super) Arc<IpCidr>, Option<Arc<RuleCounter>>);
+
+#[derive(rune::Any, Clone)]
+pub struct SealedResponseMatcher(Arc<ResponseMatcher>, Option<Arc<RuleCounter>>);
 
 #[derive(rune::Any, Clone)]
-pub struct SealedGeoIp(Arc<GeoIp>);
+pub struct SealedRewrite(Arc<Rewrite>);
 
+#[cfg(feature = "wasm-plugins")]
 #[derive(rune::Any, Clone)]
-pub struct SealedIpCidr(Arc<IpCidr>);
+pub struct SealedPlugin(Arc<Plugin>);
+
+// Count the evaluation of the matcher if it is tracked, returning whether it matched as it is.
+fn record(counter: &Option<Arc<RuleCounter>>, hit: bool) -> bool {
+    match counter {
+        Some(counter) => counter.record(hit),
+        None => hit,
+    }
+}
 
 pub static UTILS_MODULE: Lazy<Module> = Lazy::new(|| {
     let mut m = Module::new();
@@ -55,6 +91,100 @@ pub static UTILS_MODULE: Lazy<Module> = Lazy::new(|| {
         .unwrap();
     }
 
+    // NODATA
+    {
+        m.function(
+            &["nodata"],
+            |msg: &Message| -> Result<Message, ScriptError> { Ok(nodata(&msg.into())?.into()) },
+        )
+        .unwrap();
+    }
+
+    // Minimal ANY response
+    {
+        m.function(
+            &["minimal_any"],
+            |msg: &Message| -> Result<Message, ScriptError> {
+                Ok(minimal_any(&msg.into())?.into())
+            },
+        )
+        .unwrap();
+    }
+
+    // SVCB and HTTPS records
+    {
+        m.function(&["queries_svcb"], |msg: &Message| -> bool {
+            queries_svcb(&msg.into())
+        })
+        .unwrap();
+        m.function(
+            &["strip_ech"],
+            |msg: &Message| -> Result<Message, ScriptError> { Ok(strip_ech(&msg.into())?.into()) },
+        )
+        .unwrap();
+        m.function(
+            &["strip_ip_hints"],
+            |msg: &Message| -> Result<Message, ScriptError> {
+                Ok(strip_ip_hints(&msg.into())?.into())
+            },
+        )
+        .unwrap();
+    }
+
+    // Order of the addresses in the answers
+    {
+        m.function(
+            &["shuffle_answers"],
+            |msg: &Message| -> Result<Message, ScriptError> {
+                Ok(shuffle_answers(&msg.into())?.into())
+            },
+        )
+        .unwrap();
+        m.function(
+            &["rotate_answers"],
+            |msg: &Message, n: usize| -> Result<Message, ScriptError> {
+                Ok(rotate_answers(&msg.into(), n)?.into())
+            },
+        )
+        .unwrap();
+    }
+
+    // EDNS options, given by name or code
+    {
+        m.function(
+            &["strip_edns_option"],
+            |msg: &Message, option: &str| -> Result<Message, ScriptError> {
+                Ok(strip_edns_option(&msg.into(), edns_option_code(option)?)?.into())
+            },
+        )
+        .unwrap();
+        m.function(
+            &["replace_edns_option"],
+            |msg: &Message, option: &str, value: &str| -> Result<Message, ScriptError> {
+                let value = hex::decode(value)
+                    .map_err(|_| UtilsError::InvalidEdnsValue(value.to_string()))?;
+                Ok(replace_edns_option(&msg.into(), edns_option_code(option)?, &value)?.into())
+            },
+        )
+        .unwrap();
+    }
+
+    // Deadline of the current query in milliseconds, overriding the default one
+    {
+        m.function(&["set_deadline"], |ms: u64| {
+            set_deadline(std::time::Duration::from_millis(ms))
+        })
+        .unwrap();
+    }
+
+    // Groups of rules toggled at runtime
+    {
+        m.function(&["group_enabled"], |name: &str| -> bool {
+            group_enabled(name)
+        })
+        .unwrap();
+    }
+
     // Domain list
     {
         m.ty::<Domain>().unwrap();
@@ -78,18 +208,41 @@ pub static UTILS_MODULE: Lazy<Module> = Lazy::new(|| {
         )
         .unwrap();
 
+        m.inst_fn(
+            "add_patch",
+            |mut domain: Domain, path: &str| -> Result<Domain, ScriptError> {
+                domain.add_patch(path)?;
+                Ok(domain)
+            },
+        )
+        .unwrap();
+        m.inst_fn("filter", |mut domain: Domain| -> Domain {
+            domain.filter();
+            domain
+        })
+        .unwrap();
+
         m.inst_fn("seal", |domain: Domain| -> SealedDomain {
-            SealedDomain(Arc::new(domain))
+            SealedDomain(Arc::new(domain), None)
         })
         .unwrap();
 
+        m.inst_fn(
+            "track",
+            |domain: SealedDomain, name: &str| -> SealedDomain {
+                SealedDomain(domain.0, Some(rule_counter(name)))
+            },
+        )
+        .unwrap();
+
         m.inst_fn("contains", |domain: &SealedDomain, qname: &Dname| -> bool {
-            domain.0.contains(&qname.into())
+            record(&domain.1, domain.0.contains(&qname.into()))
         })
         .unwrap();
     }
 
     // GeoIP
+    #[cfg(feature = "geoip")]
     {
         m.ty::<GeoIp>().unwrap();
         m.ty::<SealedGeoIp>().unwrap();
@@ -97,22 +250,27 @@ pub static UTILS_MODULE: Lazy<Module> = Lazy::new(|| {
         m.function(
             &["GeoIp", "create_default"],
             || -> Result<SealedGeoIp, ScriptError> {
-                Ok(SealedGeoIp(Arc::new(GeoIp::create_default()?)))
+                Ok(SealedGeoIp(Arc::new(GeoIp::create_default()?), None))
             },
         )
         .unwrap();
 
         async fn geoip_from_path(path: &str) -> Result<SealedGeoIp, ScriptError> {
-            Ok(SealedGeoIp(Arc::new(GeoIp::from_path(path).await?)))
+            Ok(SealedGeoIp(Arc::new(GeoIp::from_path(path).await?), None))
         }
 
         m.async_function(&["GeoIp", "from_path"], geoip_from_path)
             .unwrap();
 
+        m.inst_fn("track", |geoip: SealedGeoIp, name: &str| -> SealedGeoIp {
+            SealedGeoIp(geoip.0, Some(rule_counter(name)))
+        })
+        .unwrap();
+
         m.inst_fn(
             "contains",
             |geoip: &SealedGeoIp, ip: &IpAddr, code: &str| -> bool {
-                geoip.0.contains(ip.into(), code)
+                record(&geoip.1, geoip.0.contains(ip.into(), code))
             },
         )
         .unwrap();
@@ -133,16 +291,184 @@ pub static UTILS_MODULE: Lazy<Module> = Lazy::new(|| {
         )
         .unwrap();
 
+        m.inst_fn(
+            "add_cidr",
+            |mut ipcidr: IpCidr, cidr: &str| -> Result<IpCidr, ScriptError> {
+                ipcidr.add_cidr(cidr)?;
+                Ok(ipcidr)
+            },
+        )
+        .unwrap();
+
         m.inst_fn("seal", |cidr: IpCidr| -> SealedIpCidr {
-            SealedIpCidr(Arc::new(cidr))
+            SealedIpCidr(Arc::new(cidr), None)
         })
         .unwrap();
 
+        m.inst_fn(
+            "track",
+            |ipcidr: SealedIpCidr, name: &str| -> SealedIpCidr {
+                SealedIpCidr(ipcidr.0, Some(rule_counter(name)))
+            },
+        )
+        .unwrap();
+
         m.inst_fn("contains", |ipcidr: &SealedIpCidr, ip: &IpAddr| -> bool {
-            ipcidr.0.contains(ip.into())
+            record(&ipcidr.1, ipcidr.0.contains(ip.into()))
         })
         .unwrap();
     }
 
+    // Response matcher
+    {
+        m.ty::<ResponseMatcher>().unwrap();
+        m.ty::<SealedResponseMatcher>().unwrap();
+
+        m.function(&["ResponseMatcher", "new"], ResponseMatcher::new)
+            .unwrap();
+        m.inst_fn(
+            "add_rcode",
+            |mut matcher: ResponseMatcher, rcode: &str| -> Result<ResponseMatcher, ScriptError> {
+                matcher.add_rcode(rcode)?;
+                Ok(matcher)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "add_cidr",
+            |mut matcher: ResponseMatcher, cidr: &str| -> Result<ResponseMatcher, ScriptError> {
+                matcher.add_cidr(cidr)?;
+                Ok(matcher)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "add_cidr_file",
+            |mut matcher: ResponseMatcher, path: &str| -> Result<ResponseMatcher, ScriptError> {
+                matcher.add_cidr_file(path)?;
+                Ok(matcher)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "add_cname",
+            |mut matcher: ResponseMatcher, cname: &str| -> Result<ResponseMatcher, ScriptError> {
+                matcher.add_cname(cname)?;
+                Ok(matcher)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "add_cname_file",
+            |mut matcher: ResponseMatcher, path: &str| -> Result<ResponseMatcher, ScriptError> {
+                matcher.add_cname_file(path)?;
+                Ok(matcher)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "ttl_below",
+            |mut matcher: ResponseMatcher, ttl: u32| -> ResponseMatcher {
+                matcher.ttl_below(ttl);
+                matcher
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "unauthenticated",
+            |mut matcher: ResponseMatcher| -> ResponseMatcher {
+                matcher.unauthenticated();
+                matcher
+            },
+        )
+        .unwrap();
+
+        m.inst_fn(
+            "seal",
+            |matcher: ResponseMatcher| -> SealedResponseMatcher {
+                SealedResponseMatcher(Arc::new(matcher), None)
+            },
+        )
+        .unwrap();
+
+        m.inst_fn(
+            "track",
+            |matcher: SealedResponseMatcher, name: &str| -> SealedResponseMatcher {
+                SealedResponseMatcher(matcher.0, Some(rule_counter(name)))
+            },
+        )
+        .unwrap();
+
+        m.inst_fn(
+            "matches",
+            |matcher: &SealedResponseMatcher, msg: &Message| -> Result<bool, ScriptError> {
+                Ok(record(&matcher.1, matcher.0.matches(&msg.into())?))
+            },
+        )
+        .unwrap();
+    }
+
+    // Answer rewriting
+    {
+        m.ty::<Rewrite>().unwrap();
+        m.ty::<SealedRewrite>().unwrap();
+
+        m.function(&["Rewrite", "new"], Rewrite::new).unwrap();
+        m.inst_fn(
+            "add",
+            |mut rewrite: Rewrite, from: &str, to: &str| -> Result<Rewrite, ScriptError> {
+                rewrite.add(from, to)?;
+                Ok(rewrite)
+            },
+        )
+        .unwrap();
+        m.inst_fn(
+            "add_file",
+            |mut rewrite: Rewrite, path: &str| -> Result<Rewrite, ScriptError> {
+                rewrite.add_file(path)?;
+                Ok(rewrite)
+            },
+        )
+        .unwrap();
+
+        m.inst_fn("seal", |rewrite: Rewrite| -> SealedRewrite {
+            SealedRewrite(Arc::new(rewrite))
+        })
+        .unwrap();
+
+        m.inst_fn(
+            "apply",
+            |rewrite: &SealedRewrite, msg: &Message| -> Result<Message, ScriptError> {
+                Ok(rewrite.0.apply(&msg.into())?.into())
+            },
+        )
+        .unwrap();
+    }
+
+    // WebAssembly plugins
+    #[cfg(feature = "wasm-plugins")]
+    {
+        m.ty::<SealedPlugin>().unwrap();
+
+        m.function(
+            &["Plugin", "from_path"],
+            |path: &str| -> Result<SealedPlugin, ScriptError> {
+                Ok(SealedPlugin(Arc::new(Plugin::from_path(path)?)))
+            },
+        )
+        .unwrap();
+
+        async fn plugin_matches(plugin: &SealedPlugin, msg: &Message) -> Result<bool, ScriptError> {
+            Ok(plugin.0.matches(&msg.into()).await?)
+        }
+
+        async fn plugin_apply(plugin: &SealedPlugin, msg: &Message) -> Result<Message, ScriptError> {
+            Ok(plugin.0.apply(&msg.into()).await?.into())
+        }
+
+        m.async_inst_fn("matches", plugin_matches).unwrap();
+        m.async_inst_fn("apply", plugin_apply).unwrap();
+    }
+
     m
 });
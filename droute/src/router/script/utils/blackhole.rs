@@ -17,7 +17,7 @@ use super::Result;
 use crate::MAX_TTL;
 use bytes::{Bytes, BytesMut};
 use domain::{
-    base::{Dname, Message, MessageBuilder},
+    base::{iana::Rcode, Dname, Message, MessageBuilder},
     rdata::Soa,
 };
 use once_cell::sync::Lazy;
@@ -44,10 +44,49 @@ static SOA_RDATA: Lazy<(Dname<Bytes>, u32, Soa<Dname<Bytes>>)> = Lazy::new(|| {
 pub fn blackhole(query: &Message<Bytes>) -> Result<Message<Bytes>> {
     // Is 50 a good number?
     let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(50))?
-        .start_answer(query, domain::base::iana::Rcode::NoError)?
+        .start_answer(query, Rcode::NoError)?
         .additional();
 
     builder.push(SOA_RDATA.clone())?;
 
     Ok(builder.into_message())
 }
+
+/// Whether the response is created by `blackhole`, i.e. the query is blocked.
+pub fn is_blackhole(resp: &Message<Bytes>) -> bool {
+    let counts = resp.header_counts();
+    if resp.header().rcode() != Rcode::NoError || counts.ancount() != 0 || counts.nscount() != 0 {
+        return false;
+    }
+    // Normal negative responses carry the SOA record in the authority section instead.
+    resp.additional().map_or(false, |mut additional| {
+        additional.any(|r| {
+            r.ok()
+                .and_then(|r| r.into_record::<Soa<_>>().ok().flatten())
+                .map_or(false, |r| {
+                    r.owner().is_root() && r.data().mname() == SOA_RDATA.2.mname()
+                })
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{blackhole, is_blackhole};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, MessageBuilder, Rtype};
+    use std::str::FromStr;
+
+    #[test]
+    fn detect_blackhole() {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        let query = builder.into_message();
+        assert!(is_blackhole(&blackhole(&query).unwrap()));
+        assert!(!is_blackhole(&query));
+    }
+}
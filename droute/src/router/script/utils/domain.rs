@@ -13,29 +13,69 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::Result;
+use super::{Result, UtilsError};
+use crate::memory::{Category, Charge};
 use bytes::Bytes;
-use dmatcher::domain::Domain as DomainAlg;
+use dmatcher::{compiled::CompiledDomain, domain::Domain as DomainAlg, filter::Filter};
 use domain::base::{name::FromStrError, Dname};
-use std::{path::PathBuf, str::FromStr};
+use std::{collections::HashSet, path::PathBuf, str::FromStr, sync::Arc};
 
-/// The domain matcher
+/// The domain matcher. Besides the trie, the compiled lists, the filter and the charge, the path of the plain list the trie
+/// was loaded from as a whole is kept, so that the list loaded again is updated by its differences.
 #[derive(Clone)]
 #[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
-pub struct Domain(DomainAlg);
+pub struct Domain(
+    DomainAlg,
+    Vec<CompiledDomain>,
+    Option<Filter>,
+    // Shared by the clones, which are accounted once.
+    Arc<Charge>,
+    Option<String>,
+);
+
+// Rough size of a name in the matcher besides its labels.
+const NAME_OVERHEAD: usize = 64;
+
+/// Parse the domain name given either in ASCII or in Unicode, which is converted to punycode, the form names are queried in.
+pub fn parse_domain(name: &str) -> std::result::Result<Dname<Bytes>, FromStrError> {
+    if name.is_ascii() {
+        return Dname::from_str(name);
+    }
+    match idna::domain_to_ascii(name) {
+        Ok(ascii) => Dname::from_str(&ascii),
+        Err(_) => Err(FromStrError::IllegalCharacter(
+            name.chars().find(|c| !c.is_ascii()).unwrap_or_default(),
+        )),
+    }
+}
+
+// The name in its canonical form, i.e. lowercased, and in punycode if internationalized, or `None` if it is not a host name.
+// The trailing dot is up to `Dname`, which takes names either with or without it.
+fn canonical(name: &str) -> Option<String> {
+    let name = if name.is_ascii() {
+        name.to_ascii_lowercase()
+    } else {
+        idna::domain_to_ascii(name).ok()?
+    };
+    (!name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.'))
+    .then_some(name)
+}
+
+// Memory the names take in the trie, roughly.
+fn cost(names: &[Dname<Bytes>]) -> usize {
+    names
+        .iter()
+        .map(|n| n.as_slice().len() + NAME_OVERHEAD)
+        .sum()
+}
 
 fn into_dnames(list: &str) -> std::result::Result<Vec<Dname<Bytes>>, FromStrError> {
-    list.split('\n')
-        .filter(|&x| {
-            (!x.is_empty())
-                && (x.chars().all(|c| {
-                    char::is_ascii_alphabetic(&c)
-                        | char::is_ascii_digit(&c)
-                        | (c == '-')
-                        | (c == '.')
-                }))
-        })
-        .map(Dname::from_str)
+    list.lines()
+        .filter_map(|x| canonical(x.trim()))
+        .map(|x| Dname::from_str(&x))
         .collect()
 }
 
@@ -48,27 +88,264 @@ impl Default for Domain {
 impl Domain {
     /// Create an empty `domain` matcher
     pub fn new() -> Self {
-        Self(DomainAlg::new())
+        Self(
+            DomainAlg::new(),
+            Vec::new(),
+            None,
+            Arc::new(Charge::new(Category::Rules, 0)),
+            None,
+        )
     }
 
     /// Add a question name to the domain matcher's list
     pub fn add_qname(&mut self, s: impl AsRef<str>) -> Result<()> {
-        self.0.insert_multi(&into_dnames(s.as_ref())?);
+        self.insert(&into_dnames(s.as_ref())?);
+        self.4 = None;
         Ok(())
     }
 
-    /// Add all question names in a file to the domain matcher's list
+    /// Add all question names in a file to the domain matcher's list, either a plain list or one compiled by `dcompass compile-list`
     pub fn add_file(&mut self, path: impl AsRef<str>) -> Result<()> {
+        // from_str is Infallible
+        let (mut file, _) = niffler::from_path(PathBuf::from_str(path.as_ref()).unwrap())?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        // Compiled lists are matched as they are, without being parsed.
+        if CompiledDomain::is_compiled(&data) {
+            let compiled = CompiledDomain::from_bytes(Bytes::from(data))?;
+            self.3.add(compiled.size());
+            if let Some(filter) = &mut self.2 {
+                compiled.fill(filter);
+            }
+            self.1.push(compiled);
+            self.refill();
+            return Ok(());
+        }
+        let data = String::from_utf8(data)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let names = into_dnames(&data)?;
+        match &self.4 {
+            // The same list loaded again, e.g. once refreshed, is updated by the names added and removed since.
+            Some(source) if source == path.as_ref() => self.update(source.clone(), &names),
+            _ if self.0.is_empty() => {
+                self.insert(&names);
+                self.4 = Some(path.as_ref().to_string());
+            }
+            // Merged into other names, the trie is no longer the list.
+            _ => {
+                self.insert(&names);
+                self.4 = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the incremental update in the file given, as published by some list providers, to the names added so far.
+    /// Each line is either `+name` to add the name, or `-name` to remove it. Other lines are skipped.
+    pub fn add_patch(&mut self, path: impl AsRef<str>) -> Result<()> {
         // from_str is Infallible
         let (mut file, _) = niffler::from_path(PathBuf::from_str(path.as_ref()).unwrap())?;
         let mut data = String::new();
         file.read_to_string(&mut data)?;
-        self.0.insert_multi(&into_dnames(&data)?);
+        let (mut added, mut removed) = (Vec::new(), Vec::new());
+        for line in data.lines().map(str::trim) {
+            let names = match line.chars().next() {
+                Some('+') => &mut added,
+                Some('-') => &mut removed,
+                _ => continue,
+            };
+            let name = &line[1..];
+            if let Some(name) = canonical(name.trim()) {
+                names.push(Dname::from_str(&name)?);
+            }
+        }
+        self.remove(&removed);
+        self.insert(&added);
+        self.4 = None;
         Ok(())
     }
 
+    // Update the trie loaded from the list to its new names.
+    fn update(&mut self, path: String, names: &[Dname<Bytes>]) {
+        let old: HashSet<_> = self.0.names().into_iter().collect();
+        let new: HashSet<_> = names.iter().cloned().collect();
+        let removed: Vec<_> = old.difference(&new).cloned().collect();
+        let added: Vec<_> = new.difference(&old).cloned().collect();
+        log::debug!(
+            "list `{}` updated by {} name(s) added and {} removed",
+            path,
+            added.len(),
+            removed.len()
+        );
+        self.remove(&removed);
+        self.insert(&added);
+    }
+
+    /// Compile the names in the lists, compiled or not, and question names added into the binary form loaded by `add_file`.
+    pub fn compile(&self) -> Result<Vec<u8>> {
+        let mut all = self.0.clone();
+        self.1.iter().for_each(|c| all.merge(c));
+        all.compile().ok_or(UtilsError::EmptyList)
+    }
+
+    fn insert(&mut self, names: &[Dname<Bytes>]) {
+        self.0.insert_multi(names);
+        self.charge(names);
+        if let Some(filter) = &mut self.2 {
+            names.iter().for_each(|n| filter.insert_domain(n));
+        }
+        self.refill();
+    }
+
+    fn remove(&mut self, names: &[Dname<Bytes>]) {
+        names.iter().for_each(|n| self.0.remove(n));
+        self.3.sub(cost(names));
+        // Rules removed are left in the filter, which only tells the names matching none of the rules.
+    }
+
+    fn charge(&self, names: &[Dname<Bytes>]) {
+        self.3.add(cost(names));
+    }
+
+    /// Put a Bloom filter in front of the matcher, which tells most names matching none of the rules with a few bit probes
+    /// before walking the rules, at about 10 bits per rule. It pays off for huge lists queried at a high rate.
+    pub fn filter(&mut self) {
+        let rules = self.1.iter().map(|c| c.rules()).sum::<usize>() + self.0.rules();
+        let mut filter = Filter::new(rules);
+        self.0.fill(&mut filter);
+        self.1.iter().for_each(|c| c.fill(&mut filter));
+        let charged = self.2.as_ref().map_or(0, |f| f.size());
+        self.3.add(filter.size().saturating_sub(charged));
+        self.2 = Some(filter);
+    }
+
+    // Rules added after the filter is built are inserted into it as they are, and it is filled in again only once too full.
+    fn refill(&mut self) {
+        if self.2.as_ref().map_or(false, |f| f.is_full()) {
+            self.filter();
+        }
+    }
+
     /// Check if the question name matches any in the matcher.
     pub fn contains(&self, qname: &Dname<Bytes>) -> bool {
-        self.0.matches(qname)
+        if let Some(filter) = &self.2 {
+            if !filter.may_match(qname) {
+                return false;
+            }
+        }
+        self.0.matches(qname) || self.1.iter().any(|c| c.matches(qname))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_domain, Domain};
+    use bytes::Bytes;
+    use domain::base::Dname;
+    use std::str::FromStr;
+
+    fn name(s: &str) -> Dname<Bytes> {
+        Dname::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn canonical_names() {
+        let mut domain = Domain::new();
+        domain
+            .add_qname("Example.COM.\r\n例子.测试\nbücher.example\n# not a name\n")
+            .unwrap();
+        // Names are matched regardless of the case and the trailing dot, and internationalized ones in punycode, as they are on the wire.
+        assert!(domain.contains(&name("www.example.com")));
+        assert!(domain.contains(&name("WWW.EXAMPLE.COM.")));
+        assert!(domain.contains(&name("xn--fsqu00a.xn--0zwm56d")));
+        assert!(domain.contains(&name("a.XN--BCHER-KVA.example")));
+        assert!(!domain.contains(&name("example.net")));
+    }
+
+    #[test]
+    fn unicode_domains() {
+        assert_eq!(
+            parse_domain("例子.测试").unwrap(),
+            name("xn--fsqu00a.xn--0zwm56d")
+        );
+        assert_eq!(
+            parse_domain("bücher.example").unwrap(),
+            name("xn--bcher-kva.example")
+        );
+        assert_eq!(parse_domain("example.com").unwrap(), name("example.com"));
+    }
+
+    #[test]
+    fn compiled_list() {
+        let mut plain = Domain::new();
+        plain.add_qname("example.com\nads.example.net\n").unwrap();
+        let path = std::env::temp_dir().join(format!("dcompass-compiled-{}", std::process::id()));
+        std::fs::write(&path, plain.compile().unwrap()).unwrap();
+
+        let mut domain = Domain::new();
+        domain.add_file(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(domain.contains(&name("www.EXAMPLE.com")));
+        assert!(domain.contains(&name("ads.example.net")));
+        assert!(!domain.contains(&name("example.net")));
+
+        // Compiled lists are compiled along with the rest, while nothing to compile is refused.
+        domain.add_qname("example.org").unwrap();
+        std::fs::write(&path, domain.compile().unwrap()).unwrap();
+        let mut recompiled = Domain::new();
+        recompiled.add_file(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        for n in ["www.example.com", "ads.example.net", "example.org"] {
+            assert!(recompiled.contains(&name(n)), "{}", n);
+        }
+        assert!(Domain::new().compile().is_err());
+    }
+
+    #[test]
+    fn updated_lists() {
+        let path = std::env::temp_dir().join(format!("dcompass-updated-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        std::fs::write(path, "example.com\nwww.example.net\nexample.net\n").unwrap();
+        let mut domain = Domain::new();
+        domain.add_file(path).unwrap();
+        let old = domain.clone();
+        assert!(domain.contains(&name("a.example.com")));
+        assert!(!domain.contains(&name("a.example.net")));
+        // The list loaded again matches as if it were loaded from scratch, while the clones are left as they are.
+        std::fs::write(path, "www.example.net\nexample.org\n").unwrap();
+        domain.add_file(path).unwrap();
+        assert!(!domain.contains(&name("a.example.com")));
+        assert!(domain.contains(&name("a.example.org")));
+        assert!(domain.contains(&name("www.example.net")));
+        assert!(!domain.contains(&name("a.example.net")));
+        assert!(old.contains(&name("a.example.com")));
+        // Emptied, the list matches nothing.
+        std::fs::write(path, "").unwrap();
+        domain.add_file(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(!domain.contains(&name("www.example.net")));
+
+        // Incremental updates
+        let patch = std::env::temp_dir().join(format!("dcompass-patch-{}", std::process::id()));
+        std::fs::write(&patch, "# update\n-www.example.net\n+ads.example.com\n").unwrap();
+        let mut domain = old;
+        domain.add_patch(patch.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&patch).unwrap();
+        assert!(!domain.contains(&name("www.example.net")));
+        assert!(domain.contains(&name("x.ads.example.com")));
+        assert!(domain.contains(&name("a.example.com")));
+    }
+
+    #[test]
+    fn filtered() {
+        let mut domain = Domain::new();
+        domain.add_qname("example.com\nads.example.net\n").unwrap();
+        domain.filter();
+        assert!(domain.contains(&name("www.example.com")));
+        assert!(domain.contains(&name("ADS.example.net")));
+        assert!(!domain.contains(&name("example.net")));
+        // Names added afterwards are in the filter too.
+        domain.add_qname("tracker.org").unwrap();
+        assert!(domain.contains(&name("cdn.tracker.org")));
+    }
@@ -0,0 +1,84 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::Result;
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{iana::Rtype, Message, MessageBuilder, Record},
+    rdata::{AllRecordData, UnknownRecordData},
+};
+
+// Rebuild the message with the record data in the answer and additional sections edited.
+// `edit` is given the type and the wire format of the record data, and returns the new one if it is to be changed.
+// Record data returned must not contain compressed names, which is the case for the types we edit (A, AAAA, SVCB, HTTPS, and OPT).
+pub(super) fn edit_records(
+    msg: &Message<Bytes>,
+    mut edit: impl FnMut(Rtype, &[u8]) -> Option<Vec<u8>>,
+) -> Result<Message<Bytes>> {
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(msg.as_slice().len()))?;
+    *builder.header_mut() = msg.header();
+
+    let mut builder = builder.question();
+    for q in msg.question() {
+        builder.push(q?)?;
+    }
+
+    let mut builder = builder.answer();
+    for item in msg.answer()? {
+        let item = item?;
+        let raw = item.clone().into_record::<UnknownRecordData<_>>()?;
+        match raw.and_then(|r| edit(r.rtype(), r.data().data().as_ref()).map(|new| (r, new))) {
+            Some((r, new)) => builder.push(Record::new(
+                r.owner().clone(),
+                r.class(),
+                r.ttl(),
+                UnknownRecordData::from_octets(r.rtype(), Bytes::from(new)),
+            ))?,
+            None => {
+                if let Some(record) = item.into_record::<AllRecordData<_, _>>()? {
+                    builder.push(record)?;
+                }
+            }
+        }
+    }
+
+    let mut builder = builder.authority();
+    for item in msg.authority()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+            builder.push(record)?;
+        }
+    }
+
+    let mut builder = builder.additional();
+    for item in msg.additional()? {
+        let item = item?;
+        let raw = item.clone().into_record::<UnknownRecordData<_>>()?;
+        match raw.and_then(|r| edit(r.rtype(), r.data().data().as_ref()).map(|new| (r, new))) {
+            Some((r, new)) => builder.push(Record::new(
+                r.owner().clone(),
+                r.class(),
+                r.ttl(),
+                UnknownRecordData::from_octets(r.rtype(), Bytes::from(new)),
+            ))?,
+            None => {
+                if let Some(record) = item.into_record::<AllRecordData<_, _>>()? {
+                    builder.push(record)?;
+                }
+            }
+        }
+    }
+
+    Ok(builder.into_message())
+}
@@ -0,0 +1,412 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// EDNS options supplied by the clients are edited in the wire format of the OPT record, so that the ones `domain` doesn't know are kept as they are.
+
+use super::{edit::edit_records, svcb::Param, Result, UtilsError};
+use crate::MAX_LEN;
+use bytes::{BufMut, Bytes, BytesMut};
+use domain::{
+    base::{iana::Rtype, Message},
+    rdata::UnknownRecordData,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+
+// Code of the NSID option (RFC 5001)
+pub(crate) const NSID: u16 = 3;
+const ECS: u16 = 8;
+const COOKIE: u16 = 10;
+const KEEPALIVE: u16 = 11;
+const PADDING: u16 = 12;
+const EXTENDED_ERROR: u16 = 15;
+
+/// What to do with an EDNS option supplied by the client.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EdnsAction {
+    /// Forward it as it is
+    Forward,
+    /// Remove it
+    Strip,
+    /// Replace its value with the one given in hex. It is not added if the client didn't supply it.
+    Replace(#[serde(serialize_with = "to_hex", deserialize_with = "from_hex")] Vec<u8>),
+}
+
+fn to_hex<S: Serializer>(value: &[u8], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(value))
+}
+
+fn from_hex<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<u8>, D::Error> {
+    hex::decode(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+const fn strip() -> EdnsAction {
+    EdnsAction::Strip
+}
+
+/// Which EDNS options supplied by the clients are forwarded to the upstreams.
+/// By default, EDNS Client Subnet is stripped so that the clients' addresses are not revealed, and so are the options only meant for the hop between the client and `dcompass` (cookies, keepalive, and padding).
+/// Options not listed are forwarded.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct EdnsPolicy {
+    /// EDNS Client Subnet (RFC 7871)
+    #[serde(default = "strip")]
+    pub ecs: EdnsAction,
+    /// DNS cookies (RFC 7873)
+    #[serde(default = "strip")]
+    pub cookie: EdnsAction,
+    /// TCP keepalive (RFC 7828)
+    #[serde(default = "strip")]
+    pub keepalive: EdnsAction,
+    /// Padding (RFC 7830)
+    #[serde(default = "strip")]
+    pub padding: EdnsAction,
+    /// Extended DNS errors (RFC 8914), which only make sense in responses
+    #[serde(default = "strip")]
+    pub extended_error: EdnsAction,
+    /// Actions on other options by their codes
+    #[serde(default)]
+    pub others: HashMap<u16, EdnsAction>,
+}
+
+impl Default for EdnsPolicy {
+    fn default() -> Self {
+        Self {
+            ecs: strip(),
+            cookie: strip(),
+            keepalive: strip(),
+            padding: strip(),
+            extended_error: strip(),
+            others: HashMap::new(),
+        }
+    }
+}
+
+impl EdnsPolicy {
+    fn action(&self, code: u16) -> &EdnsAction {
+        match code {
+            ECS => &self.ecs,
+            COOKIE => &self.cookie,
+            KEEPALIVE => &self.keepalive,
+            PADDING => &self.padding,
+            EXTENDED_ERROR => &self.extended_error,
+            _ => self.others.get(&code).unwrap_or(&EdnsAction::Forward),
+        }
+    }
+
+    /// Apply the policy to the EDNS options of the query.
+    pub fn apply(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        edit_options(msg, |code, _| match self.action(code) {
+            EdnsAction::Forward => Param::Keep,
+            EdnsAction::Strip => Param::Drop,
+            EdnsAction::Replace(value) => Param::Replace(value.clone()),
+        })
+    }
+}
+
+// Edit the options in the OPT record data. Returns `None` if nothing is changed or the record data is malformed.
+fn edit_opt(rdata: &[u8], edit: &mut impl FnMut(u16, &[u8]) -> Param) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(rdata.len());
+    let mut changed = false;
+    let mut pos = 0;
+    while pos < rdata.len() {
+        let code = u16::from_be_bytes([*rdata.get(pos)?, *rdata.get(pos + 1)?]);
+        let len = usize::from(u16::from_be_bytes([
+            *rdata.get(pos + 2)?,
+            *rdata.get(pos + 3)?,
+        ]));
+        let value = rdata.get(pos + 4..pos + 4 + len)?;
+        pos += 4 + len;
+
+        let value = match edit(code, value) {
+            Param::Keep => value.to_vec(),
+            Param::Drop => {
+                changed = true;
+                continue;
+            }
+            Param::Replace(new) => {
+                changed = true;
+                new
+            }
+        };
+        out.extend_from_slice(&code.to_be_bytes());
+        out.extend_from_slice(&u16::try_from(value.len()).ok()?.to_be_bytes());
+        out.extend_from_slice(&value);
+    }
+    changed.then_some(out)
+}
+
+// The record data of the OPT record of the message, if any.
+fn opt_data(msg: &Message<Bytes>) -> Result<Option<Bytes>> {
+    for item in msg.additional()? {
+        if let Some(r) = item?.into_record::<UnknownRecordData<_>>()? {
+            if r.rtype() == Rtype::Opt {
+                return Ok(Some(r.data().data().clone()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Edit the options of the OPT record, leaving the message as it is if nothing is changed.
+fn edit_options(
+    msg: &Message<Bytes>,
+    mut edit: impl FnMut(u16, &[u8]) -> Param,
+) -> Result<Message<Bytes>> {
+    let mut new = None;
+    for item in msg.additional()? {
+        if let Some(r) = item?.into_record::<UnknownRecordData<_>>()? {
+            if r.rtype() == Rtype::Opt {
+                new = edit_opt(r.data().data().as_ref(), &mut edit);
+                break;
+            }
+        }
+    }
+    match new {
+        Some(new) => edit_records(msg, |rtype, _| (rtype == Rtype::Opt).then(|| new.clone())),
+        None => Ok(msg.clone()),
+    }
+}
+
+/// Code of the EDNS option given by its name (`nsid`, `ecs`, `cookie`, `keepalive`, `padding`, or `extended_error`) or in decimal.
+pub fn edns_option_code(name: &str) -> Result<u16> {
+    Ok(match name {
+        "nsid" => NSID,
+        "ecs" => ECS,
+        "cookie" => COOKIE,
+        "keepalive" => KEEPALIVE,
+        "padding" => PADDING,
+        "extended_error" => EXTENDED_ERROR,
+        _ => name
+            .parse()
+            .map_err(|_| UtilsError::UnknownEdnsOption(name.to_string()))?,
+    })
+}
+
+/// Remove the EDNS option with the code given from the query.
+pub fn strip_edns_option(msg: &Message<Bytes>, code: u16) -> Result<Message<Bytes>> {
+    edit_options(
+        msg,
+        |c, _| if c == code { Param::Drop } else { Param::Keep },
+    )
+}
+
+/// Value of the EDNS option with the code given, if the message carries it.
+pub fn edns_option(msg: &Message<Bytes>, code: u16) -> Result<Option<Vec<u8>>> {
+    let mut found = None;
+    if let Some(rdata) = opt_data(msg)? {
+        edit_opt(&rdata, &mut |c, value| {
+            if c == code && found.is_none() {
+                found = Some(value.to_vec());
+            }
+            Param::Keep
+        });
+    }
+    Ok(found)
+}
+
+/// Set the EDNS option with the code given to the value, adding the option, as well as the OPT record, if the message doesn't carry them.
+pub fn set_edns_option(msg: &Message<Bytes>, code: u16, value: &[u8]) -> Result<Message<Bytes>> {
+    let len =
+        u16::try_from(value.len()).map_err(|_| UtilsError::InvalidEdnsValue(hex::encode(value)))?;
+    let mut option = Vec::with_capacity(4 + value.len());
+    option.extend_from_slice(&code.to_be_bytes());
+    option.extend_from_slice(&len.to_be_bytes());
+    option.extend_from_slice(value);
+
+    if let Some(rdata) = opt_data(msg)? {
+        let mut new = edit_opt(&rdata, &mut |c, _| {
+            if c == code {
+                Param::Drop
+            } else {
+                Param::Keep
+            }
+        })
+        .unwrap_or_else(|| rdata.to_vec());
+        new.extend_from_slice(&option);
+        return edit_records(msg, |rtype, _| (rtype == Rtype::Opt).then(|| new.clone()));
+    }
+
+    // The additional section comes last, so the OPT record is appended to the wire format as it is.
+    let arcount = u16::from_be_bytes([msg.as_slice()[10], msg.as_slice()[11]]);
+    let mut buf = BytesMut::from(msg.as_slice());
+    buf[10..12].copy_from_slice(&arcount.saturating_add(1).to_be_bytes());
+    buf.put_u8(0);
+    buf.put_u16(Rtype::Opt.to_int());
+    buf.put_u16(MAX_LEN as u16);
+    buf.put_u32(0);
+    buf.put_u16(option.len() as u16);
+    buf.put_slice(&option);
+    Ok(Message::from_octets(buf.freeze())?)
+}
+
+/// Replace the value of the EDNS option with the code given in the query, if the client supplied it.
+pub fn replace_edns_option(
+    msg: &Message<Bytes>,
+    code: u16,
+    value: &[u8],
+) -> Result<Message<Bytes>> {
+    edit_options(msg, |c, _| {
+        if c == code {
+            Param::Replace(value.to_vec())
+        } else {
+            Param::Keep
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        edns_option, replace_edns_option, set_edns_option, strip_edns_option, EdnsAction,
+        EdnsPolicy, COOKIE, ECS, NSID, PADDING,
+    };
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Class, Dname, Message, MessageBuilder, Record, Rtype},
+        rdata::UnknownRecordData,
+    };
+    use std::str::FromStr;
+
+    // A query with the EDNS options given, in the order given
+    fn query(options: &[(u16, &[u8])]) -> Message<Bytes> {
+        let mut rdata = Vec::new();
+        for (code, value) in options {
+            rdata.extend_from_slice(&code.to_be_bytes());
+            rdata.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            rdata.extend_from_slice(value);
+        }
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let mut builder = builder.additional();
+        builder
+            .push(Record::new(
+                Dname::root_bytes(),
+                Class::Int(1232),
+                0,
+                UnknownRecordData::from_octets(Rtype::Opt, Bytes::from(rdata)),
+            ))
+            .unwrap();
+        builder.into_message()
+    }
+
+    // UDP payload size and options of the OPT record
+    fn options(msg: &Message<Bytes>) -> (Class, Vec<(u16, Vec<u8>)>) {
+        let opt = msg
+            .additional()
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .into_record::<UnknownRecordData<_>>()
+            .unwrap()
+            .unwrap();
+        let (mut rdata, mut options) = (opt.data().data().as_ref(), Vec::new());
+        while !rdata.is_empty() {
+            let len = usize::from(u16::from_be_bytes([rdata[2], rdata[3]]));
+            options.push((
+                u16::from_be_bytes([rdata[0], rdata[1]]),
+                rdata[4..4 + len].to_vec(),
+            ));
+            rdata = &rdata[4 + len..];
+        }
+        (opt.class(), options)
+    }
+
+    const SUBNET: &[u8] = &[0, 1, 24, 0, 192, 0, 2];
+
+    #[test]
+    fn default_policy() {
+        let msg = query(&[(ECS, SUBNET), (COOKIE, &[1; 8]), (3, b"nsid")]);
+        assert_eq!(
+            options(&EdnsPolicy::default().apply(&msg).unwrap()),
+            (Class::Int(1232), vec![(3, b"nsid".to_vec())])
+        );
+
+        // Queries without anything to strip are left as they are.
+        let msg = query(&[(3, b"nsid")]);
+        assert_eq!(
+            EdnsPolicy::default().apply(&msg).unwrap().as_slice(),
+            msg.as_slice()
+        );
+    }
+
+    #[test]
+    fn custom_policy() {
+        let policy = EdnsPolicy {
+            // Source prefix length 0 opts out of ECS (RFC 7871).
+            ecs: EdnsAction::Replace(vec![0, 1, 0, 0]),
+            padding: EdnsAction::Forward,
+            others: [(3, EdnsAction::Strip)].into_iter().collect(),
+            ..Default::default()
+        };
+        let msg = query(&[(ECS, SUBNET), (PADDING, &[0; 4]), (3, b"nsid")]);
+        assert_eq!(
+            options(&policy.apply(&msg).unwrap()).1,
+            vec![(ECS, vec![0, 1, 0, 0]), (PADDING, vec![0; 4])]
+        );
+
+        assert_eq!(
+            options(&strip_edns_option(&msg, PADDING).unwrap()).1,
+            vec![(ECS, SUBNET.to_vec()), (3, b"nsid".to_vec())]
+        );
+        assert_eq!(
+            options(&replace_edns_option(&msg, 3, b"id").unwrap()).1,
+            vec![
+                (ECS, SUBNET.to_vec()),
+                (PADDING, vec![0; 4]),
+                (3, b"id".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn set_option() {
+        let msg = query(&[(ECS, SUBNET), (NSID, b"")]);
+        assert_eq!(edns_option(&msg, NSID).unwrap(), Some(Vec::new()));
+        assert_eq!(edns_option(&msg, PADDING).unwrap(), None);
+
+        // Replaced in place of the one there, or added
+        let msg = set_edns_option(&msg, NSID, b"node-1").unwrap();
+        assert_eq!(
+            options(&msg).1,
+            vec![(ECS, SUBNET.to_vec()), (NSID, b"node-1".to_vec())]
+        );
+        assert_eq!(
+            options(&set_edns_option(&msg, PADDING, &[0; 2]).unwrap()).1,
+            vec![
+                (ECS, SUBNET.to_vec()),
+                (NSID, b"node-1".to_vec()),
+                (PADDING, vec![0; 2])
+            ]
+        );
+
+        // Along with the OPT record if there is none
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let msg = set_edns_option(&builder.into_message(), NSID, b"node-1").unwrap();
+        assert_eq!(msg.header_counts().arcount(), 1);
+        assert_eq!(options(&msg).1, vec![(NSID, b"node-1".to_vec())]);
+        assert_eq!(edns_option(&msg, NSID).unwrap(), Some(b"node-1".to_vec()));
+    }
+}
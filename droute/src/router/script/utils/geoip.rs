@@ -16,6 +16,7 @@
 use super::Result;
 #[cfg(not(any(feature = "geoip-cn", feature = "geoip-maxmind")))]
 use super::UtilsError;
+use crate::memory::{Category, Charge};
 use log::info;
 use maxminddb::{geoip2::Country, Reader};
 use std::{net::IpAddr, path::PathBuf, str::FromStr, sync::Arc};
@@ -25,6 +26,8 @@ use std::{net::IpAddr, path::PathBuf, str::FromStr, sync::Arc};
 #[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
 pub struct GeoIp {
     db: Arc<Reader<Vec<u8>>>,
+    // Shared by the clones, like the database.
+    _charge: Arc<Charge>,
 }
 
 // If both geoip-maxmind and geoip-cn are enabled, geoip-maxmind will be used
@@ -38,29 +41,31 @@ fn get_builtin_db() -> Result<Vec<u8>> {
 }
 
 impl GeoIp {
+    fn new(buf: Vec<u8>) -> Result<Self> {
+        let charge = Arc::new(Charge::new(Category::Rules, buf.len()));
+        Ok(Self {
+            db: Arc::new(Reader::from_source(buf)?),
+            _charge: charge,
+        })
+    }
+
     /// Create a geoip matcher from the database file with the given path
     pub async fn from_path(path: impl AsRef<str>) -> Result<Self> {
         // Per std documentation, this is infallible
         let buf: Vec<u8> = tokio::fs::read(PathBuf::from_str(path.as_ref()).unwrap()).await?;
-        Ok(Self {
-            db: Arc::new(Reader::from_source(buf)?),
-        })
+        Self::new(buf)
     }
 
     /// Create a geoip matcher from the database file with the given buffer
     #[cfg(test)]
     pub fn from_buf(buf: Vec<u8>) -> Result<Self> {
-        Ok(Self {
-            db: Arc::new(Reader::from_source(buf)?),
-        })
+        Self::new(buf)
     }
 
     /// Create a geoip matcher from the buffer
     pub fn create_default() -> Result<Self> {
         let buf = get_builtin_db()?;
-        Ok(Self {
-            db: Arc::new(Reader::from_source(buf)?),
-        })
+        Self::new(buf)
     }
 
     /// Whether the given country code contains the given IP address
@@ -0,0 +1,172 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Groups of rules in the script, e.g. ad blocking, which can be turned on and off at runtime.
+// Groups live across reloads, and so do the toggles on them, like the counters of the rules.
+// Groups scheduled are on only within their schedules, unless toggled otherwise.
+
+use super::Schedule;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+static GROUPS: Lazy<RwLock<BTreeMap<String, Arc<Group>>>> = Lazy::new(Default::default);
+
+/// The state of a group of rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupState {
+    /// Whether the rules in the group are applied
+    pub enabled: bool,
+    /// Seconds before the group is turned back to the state configured, if it is toggled for a while
+    pub revert_in: Option<u64>,
+}
+
+struct Group {
+    // The state configured
+    configured: AtomicBool,
+    // The schedule configured, if any, within which the group is on
+    schedule: RwLock<Option<Schedule>>,
+    // The state toggled at runtime, which lasts until the deadline if any
+    toggled: RwLock<Option<(bool, Option<Instant>)>>,
+}
+
+impl Group {
+    fn new(enabled: bool) -> Self {
+        Self {
+            configured: AtomicBool::new(enabled),
+            schedule: RwLock::new(None),
+            toggled: RwLock::new(None),
+        }
+    }
+
+    fn state(&self) -> GroupState {
+        let now = Instant::now();
+        match *self.toggled.read().unwrap() {
+            Some((enabled, deadline)) if deadline.map_or(true, |d| now < d) => GroupState {
+                enabled,
+                revert_in: deadline.map(|d| (d - now).as_secs()),
+            },
+            _ => GroupState {
+                enabled: self.configured.load(Ordering::Relaxed)
+                    && self
+                        .schedule
+                        .read()
+                        .unwrap()
+                        .as_ref()
+                        .map_or(true, Schedule::active),
+                revert_in: None,
+            },
+        }
+    }
+}
+
+fn group(name: &str, enabled: bool) -> Arc<Group> {
+    if let Some(group) = GROUPS.read().unwrap().get(name) {
+        return group.clone();
+    }
+    GROUPS
+        .write()
+        .unwrap()
+        .entry(name.to_string())
+        .or_insert_with(|| Arc::new(Group::new(enabled)))
+        .clone()
+}
+
+/// Declare the group with the state configured, and the schedule within which it is on if any. Toggles made at runtime are kept.
+pub fn declare_group(name: &str, enabled: bool, schedule: Option<Schedule>) {
+    let group = group(name, enabled);
+    group.configured.store(enabled, Ordering::Relaxed);
+    *group.schedule.write().unwrap() = schedule;
+}
+
+/// Whether the rules in the group are applied. Groups not declared are enabled, and can be toggled once evaluated.
+pub fn group_enabled(name: &str) -> bool {
+    group(name, true).state().enabled
+}
+
+/// Turn the group on or off, for the duration given or until it is toggled again. Returns the state of the group, or `None` if the group is neither declared nor evaluated.
+pub fn toggle_group(name: &str, enabled: bool, duration: Option<Duration>) -> Option<GroupState> {
+    let group = GROUPS.read().unwrap().get(name)?.clone();
+    *group.toggled.write().unwrap() = Some((enabled, duration.map(|d| Instant::now() + d)));
+    Some(group.state())
+}
+
+/// The state of every group by name.
+pub fn group_states() -> BTreeMap<String, GroupState> {
+    GROUPS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(name, group)| (name.clone(), group.state()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{declare_group, group_enabled, group_states, toggle_group};
+    use crate::router::script::utils::Schedule;
+    use std::time::Duration;
+
+    #[test]
+    fn toggle() {
+        assert!(toggle_group("test-unknown", false, None).is_none());
+
+        declare_group("test-toggle", true, None);
+        assert!(group_enabled("test-toggle"));
+        let state = toggle_group("test-toggle", false, Some(Duration::from_secs(60))).unwrap();
+        assert!(!state.enabled);
+        assert!(state.revert_in.unwrap() > 0);
+        assert!(!group_enabled("test-toggle"));
+        // The toggle outlives the configuration reloaded.
+        declare_group("test-toggle", true, None);
+        assert!(!group_enabled("test-toggle"));
+
+        // Turned back once the time is up
+        toggle_group("test-toggle", false, Some(Duration::ZERO));
+        assert!(group_enabled("test-toggle"));
+        assert_eq!(group_states()["test-toggle"].revert_in, None);
+    }
+
+    #[test]
+    fn schedule() {
+        // Never on, as there is no February 31st
+        let never: Schedule = "* * 31 feb *".parse().unwrap();
+        declare_group("test-schedule", true, Some(never.clone()));
+        assert!(!group_enabled("test-schedule"));
+        // Toggles override the schedule.
+        toggle_group("test-schedule", true, None);
+        assert!(group_enabled("test-schedule"));
+
+        declare_group(
+            "test-schedule-always",
+            true,
+            Some("* * * * *".parse().unwrap()),
+        );
+        assert!(group_enabled("test-schedule-always"));
+        declare_group(
+            "test-schedule-always",
+            false,
+            Some("* * * * *".parse().unwrap()),
+        );
+        assert!(!group_enabled("test-schedule-always"));
+    }
+}
@@ -0,0 +1,45 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::Result;
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{charstr::CharStr, iana::Rcode, Message, MessageBuilder},
+    rdata::Hinfo,
+};
+use once_cell::sync::Lazy;
+
+// TTL suggested by RFC 8482 section 4.2 and used by most public resolvers.
+const HINFO_TTL: u32 = 3789;
+
+// Per RFC 8482, CPU field is set to "RFC8482" and OS field is left empty.
+static HINFO_RDATA: Lazy<Hinfo<Bytes>> = Lazy::new(|| {
+    Hinfo::new(
+        CharStr::from_octets(Bytes::from_static(b"RFC8482")).unwrap(),
+        CharStr::from_octets(Bytes::new()).unwrap(),
+    )
+});
+
+/// Create a minimal response to an ANY query as described in RFC 8482, carrying a single synthesized HINFO record.
+pub fn minimal_any(query: &Message<Bytes>) -> Result<Message<Bytes>> {
+    let question = query.sole_question()?;
+
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(50))?
+        .start_answer(query, Rcode::NoError)?;
+
+    builder.push((question.qname(), HINFO_TTL, HINFO_RDATA.clone()))?;
+
+    Ok(builder.into_message())
+}
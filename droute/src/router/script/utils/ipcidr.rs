@@ -1,15 +1,21 @@
 use super::Result;
+use crate::memory::{Category, Charge};
 use cidr_utils::{
     cidr::{IpCidr as Cidr, IpCidrError},
     utils::IpCidrCombiner as CidrCombiner,
 };
-use std::{net::IpAddr, path::Path};
+use std::{net::IpAddr, path::Path, sync::Arc};
+
+// Rough size of a CIDR in the matcher.
+const CIDR_SIZE: usize = 48;
 
 /// IP CIDR matcher.
 #[derive(Clone)]
 #[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
 pub struct IpCidr {
     matcher: CidrCombiner,
+    // Shared by the clones, which are accounted once.
+    charge: Arc<Charge>,
 }
 
 impl IpCidr {
@@ -17,6 +23,7 @@ impl IpCidr {
     pub fn new() -> Self {
         Self {
             matcher: CidrCombiner::new(),
+            charge: Arc::new(Charge::new(Category::Rules, 0)),
         }
     }
 
@@ -29,12 +36,20 @@ impl IpCidr {
         data.split('\n').filter(|&x| !x.is_empty()).try_for_each(
             |x| -> std::result::Result<(), IpCidrError> {
                 self.matcher.push(Cidr::from_str(x)?);
+                self.charge.add(CIDR_SIZE);
                 Ok(())
             },
         )?;
         Ok(())
     }
 
+    /// Add a single IP CIDR, e.g. `10.0.0.0/8` or `fd00::/8`.
+    pub fn add_cidr(&mut self, s: impl AsRef<str>) -> Result<()> {
+        self.matcher.push(Cidr::from_str(s.as_ref())?);
+        self.charge.add(CIDR_SIZE);
+        Ok(())
+    }
+
     /// Check if IP CIDR set contains the given IP address.
     pub fn contains(&self, ip: IpAddr) -> bool {
         self.matcher.contains(ip)
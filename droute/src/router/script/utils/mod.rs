@@ -17,15 +17,50 @@
 
 mod blackhole;
 mod domain;
+mod edit;
+mod edns;
+#[cfg(feature = "geoip")]
 mod geoip;
+mod groups;
+mod hinfo;
 mod ipcidr;
+mod nodata;
+mod order;
+#[cfg(feature = "wasm-plugins")]
+mod plugin;
+mod response;
+mod rewrite;
+mod rules;
+mod schedule;
+mod svcb;
 
-pub use self::domain::Domain;
-pub use blackhole::blackhole;
+pub use self::domain::{parse_domain, Domain};
+pub use crate::router::deadline::set_deadline;
+pub use blackhole::{blackhole, is_blackhole};
+pub use edns::{
+    edns_option, edns_option_code, replace_edns_option, set_edns_option, strip_edns_option,
+    EdnsAction, EdnsPolicy,
+};
+pub(crate) use edns::NSID;
+#[cfg(feature = "geoip")]
 pub use geoip::GeoIp;
+pub use groups::{declare_group, group_enabled, group_states, toggle_group, GroupState};
+pub use hinfo::minimal_any;
 pub use ipcidr::IpCidr;
+pub use nodata::nodata;
+pub use order::{rotate_answers, shuffle_answers};
+#[cfg(feature = "wasm-plugins")]
+pub use plugin::Plugin;
+pub use response::ResponseMatcher;
+pub use rewrite::Rewrite;
+pub use rules::{rule_counter, rule_stats, RuleCounter, RuleStats};
+pub use schedule::Schedule;
+pub(crate) use svcb::{has_ech, is_svcb};
+pub use svcb::{queries_svcb, strip_ech, strip_ip_hints};
 
+use crate::errors::ErrorKind;
 use ::domain::base::{name::FromStrError, octets::ParseError};
+#[cfg(feature = "geoip")]
 use maxminddb::MaxMindDBError;
 use thiserror::Error;
 
@@ -40,6 +75,7 @@ pub enum UtilsError {
     IoError(#[from] std::io::Error),
 
     /// Error related to GeoIP usages.
+    #[cfg(feature = "geoip")]
     #[error("An error happened when using `geoip` matcher.")]
     GeoIpError(#[from] MaxMindDBError),
 
@@ -48,7 +84,10 @@ pub enum UtilsError {
     IpCidrError(#[from] cidr_utils::cidr::IpCidrError),
 
     /// No path to GeoIP database specified while no builtin database is provided.
-    #[cfg(not(any(feature = "geoip-cn", feature = "geoip-maxmind")))]
+    #[cfg(all(
+        feature = "geoip",
+        not(any(feature = "geoip-cn", feature = "geoip-maxmind"))
+    ))]
     #[error("This build doesn't contain a built-in GeoIP database, please specify your own database or use other builds.")]
     NoBuiltInDb,
 
@@ -60,6 +99,14 @@ pub enum UtilsError {
     #[error(transparent)]
     FromStrError(#[from] FromStrError),
 
+    /// Nothing to compile the domain lists into, as the empty list would match everything once compiled.
+    #[error("No domain to compile the lists into.")]
+    EmptyList,
+
+    /// The domain list is compiled but malformed, e.g. truncated.
+    #[error("{0}. Compile the list again with `dcompass compile-list`.")]
+    CompiledListError(#[from] dmatcher::compiled::FormatError),
+
     /// Failed to parse the record
     #[error(transparent)]
     ParseError(#[from] ParseError),
@@ -67,4 +114,58 @@ pub enum UtilsError {
     /// Short Buf
     #[error(transparent)]
     ShortBuf(#[from] ::domain::base::ShortBuf),
+
+    /// Invalid address rewrite rule
+    #[error("Invalid address rewrite rule: {0}. Addresses can only be mapped onto the same family, and ranges onto a single address or ranges of the same prefix length.")]
+    InvalidRewrite(String),
+
+    /// Invalid cron expression of a schedule
+    #[error("Invalid schedule `{0}`, which should be a cron expression of five fields: minute, hour, day of month, month, and day of week.")]
+    InvalidSchedule(String),
+
+    /// EDNS option neither known by name nor given as a code
+    #[error("Unknown EDNS option `{0}`. Use one of `nsid`, `ecs`, `cookie`, `keepalive`, `padding`, `extended_error`, or the option code.")]
+    UnknownEdnsOption(String),
+
+    /// EDNS option value not in hex
+    #[error("Invalid EDNS option value `{0}`, which should be in hex.")]
+    InvalidEdnsValue(String),
+
+    /// Response code unknown by its mnemonic
+    #[error("Unknown response code `{0}`. Use one of `noerror`, `formerr`, `servfail`, `nxdomain`, `notimp`, or `refused`.")]
+    UnknownRcode(String),
+
+    /// Failed to load or run the WebAssembly plugin
+    #[cfg(feature = "wasm-plugins")]
+    #[error("WebAssembly plugin failed: {0}")]
+    PluginError(String),
+}
+
+impl UtilsError {
+    /// The category of the error. Matchers and actions are set up per the configuration, and messages are parsed from the upstreams.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::ParseError(_) | Self::ShortBuf(_) => ErrorKind::Protocol,
+            Self::IoError(_)
+            | Self::IpCidrError(_)
+            | Self::DecompError(_)
+            | Self::FromStrError(_)
+            | Self::EmptyList
+            | Self::CompiledListError(_)
+            | Self::InvalidRewrite(_)
+            | Self::InvalidSchedule(_)
+            | Self::UnknownEdnsOption(_)
+            | Self::InvalidEdnsValue(_)
+            | Self::UnknownRcode(_) => ErrorKind::Config,
+            #[cfg(feature = "geoip")]
+            Self::GeoIpError(_) => ErrorKind::Config,
+            #[cfg(all(
+                feature = "geoip",
+                not(any(feature = "geoip-cn", feature = "geoip-maxmind"))
+            ))]
+            Self::NoBuiltInDb => ErrorKind::Config,
+            #[cfg(feature = "wasm-plugins")]
+            Self::PluginError(_) => ErrorKind::Config,
+        }
+    }
 }
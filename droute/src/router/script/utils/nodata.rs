@@ -0,0 +1,65 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::Result;
+use bytes::{Bytes, BytesMut};
+use domain::base::{iana::Rcode, Message, MessageBuilder};
+
+/// Create an empty NOERROR response (NODATA) to the query, telling the client the name exists but has no records of the type queried.
+/// Unlike `blackhole`, clients carry on with the other types, e.g. A and AAAA after their HTTPS queries are answered so.
+pub fn nodata(query: &Message<Bytes>) -> Result<Message<Bytes>> {
+    Ok(MessageBuilder::from_target(BytesMut::with_capacity(50))?
+        .start_answer(query, Rcode::NoError)?
+        .into_message())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::nodata;
+    use crate::utils::queries_svcb;
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{iana::Rcode, Dname, MessageBuilder, Rtype};
+    use std::str::FromStr;
+
+    #[test]
+    fn https_nodata() {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((
+                Dname::<Bytes>::from_str("example.com").unwrap(),
+                Rtype::Int(65),
+            ))
+            .unwrap();
+        let query = builder.into_message();
+        assert!(queries_svcb(&query));
+
+        let resp = nodata(&query).unwrap();
+        assert!(resp.header().qr());
+        assert_eq!(resp.header().id(), query.header().id());
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert_eq!(resp.header_counts().qdcount(), 1);
+        assert_eq!(resp.header_counts().ancount(), 0);
+
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        assert!(!queries_svcb(&builder.into_message()));
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Address records are reordered among the positions they take in the answer section, so that CNAME chains stay in place.
+
+use super::Result;
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{iana::Rtype, Message, MessageBuilder},
+    rdata::AllRecordData,
+};
+use rand::seq::SliceRandom;
+
+// Rebuild the response with the A records and the AAAA records in the answer section each reordered by `reorder`.
+// The response is returned as it is if there is nothing to reorder.
+fn reorder_answers(
+    msg: &Message<Bytes>,
+    mut reorder: impl FnMut(&mut [usize]),
+) -> Result<Message<Bytes>> {
+    let mut records = Vec::new();
+    for item in msg.answer()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+            records.push(record);
+        }
+    }
+
+    let mut order: Vec<usize> = (0..records.len()).collect();
+    let mut changed = false;
+    for rtype in [Rtype::A, Rtype::Aaaa] {
+        let positions: Vec<usize> = (0..records.len())
+            .filter(|&i| records[i].rtype() == rtype)
+            .collect();
+        if positions.len() < 2 {
+            continue;
+        }
+        let mut picked = positions.clone();
+        reorder(&mut picked);
+        for (pos, i) in positions.into_iter().zip(picked) {
+            order[pos] = i;
+        }
+        changed = true;
+    }
+    if !changed {
+        return Ok(msg.clone());
+    }
+
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(msg.as_slice().len()))?;
+    *builder.header_mut() = msg.header();
+
+    let mut builder = builder.question();
+    for q in msg.question() {
+        builder.push(q?)?;
+    }
+
+    let mut builder = builder.answer();
+    for i in order {
+        builder.push(records[i].clone())?;
+    }
+
+    let mut builder = builder.authority();
+    for item in msg.authority()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+            builder.push(record)?;
+        }
+    }
+
+    let mut builder = builder.additional();
+    for item in msg.additional()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+            builder.push(record)?;
+        }
+    }
+
+    Ok(builder.into_message())
+}
+
+/// Shuffle the A records and the AAAA records in the answer section of the response, so that clients picking the first address are spread across all of them.
+pub fn shuffle_answers(msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+    reorder_answers(msg, |records| records.shuffle(&mut rand::thread_rng()))
+}
+
+/// Rotate the A records and the AAAA records in the answer section of the response to the left by `n`, e.g. by a counter for round-robin.
+pub fn rotate_answers(msg: &Message<Bytes>, n: usize) -> Result<Message<Bytes>> {
+    reorder_answers(msg, |records| {
+        let len = records.len();
+        records.rotate_left(n % len)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rotate_answers, shuffle_answers};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::{Cname, A},
+    };
+    use std::str::FromStr;
+
+    // Response with a CNAME followed by the addresses given
+    fn response(addrs: &[u8]) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("www.example.com").unwrap();
+        let target = Dname::<Bytes>::from_str("cdn.example.net").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&builder.into_message(), Rcode::NoError)
+            .unwrap();
+        builder
+            .push((&name, 300, Cname::new(target.clone())))
+            .unwrap();
+        for &addr in addrs {
+            builder
+                .push((&target, 300, A::from_octets(192, 0, 2, addr)))
+                .unwrap();
+        }
+        builder.into_message()
+    }
+
+    fn addrs(msg: &Message<Bytes>) -> Vec<u8> {
+        msg.answer()
+            .unwrap()
+            .limit_to::<A>()
+            .map(|r| r.unwrap().data().addr().octets()[3])
+            .collect()
+    }
+
+    #[test]
+    fn rotate() {
+        let msg = response(&[1, 2, 3]);
+        let rotated = rotate_answers(&msg, 4).unwrap();
+        assert_eq!(addrs(&rotated), vec![2, 3, 1]);
+        // The CNAME stays in front.
+        assert_eq!(
+            rotated.answer().unwrap().next().unwrap().unwrap().rtype(),
+            Rtype::Cname
+        );
+        assert_eq!(rotated.header().id(), msg.header().id());
+
+        let single = response(&[1]);
+        assert_eq!(
+            rotate_answers(&single, 1).unwrap().as_slice(),
+            single.as_slice()
+        );
+    }
+
+    #[test]
+    fn shuffle() {
+        let msg = response(&[1, 2, 3, 4]);
+        let mut shuffled = addrs(&shuffle_answers(&msg).unwrap());
+        shuffled.sort_unstable();
+        assert_eq!(shuffled, vec![1, 2, 3, 4]);
+    }
+}
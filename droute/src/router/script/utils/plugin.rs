@@ -0,0 +1,224 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{Result, UtilsError};
+use bytes::Bytes;
+use domain::base::Message;
+use futures::channel::oneshot;
+use std::{path::Path, sync::Arc};
+use wasmtime::{
+    Config, Engine, InstancePre, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    WasmResults,
+};
+
+// Instructions a single call may execute before it is aborted.
+const FUEL: u64 = 10_000_000;
+
+// Memory a single instance may grow to.
+const MAX_MEMORY: usize = 16 << 20;
+
+fn plugin_error(e: impl std::fmt::Display) -> UtilsError {
+    UtilsError::PluginError(format!("{:#}", e))
+}
+
+/// A matcher and/or an action implemented by a WebAssembly module.
+///
+/// The module is expected to export:
+/// - `memory`, the memory the messages are exchanged through;
+/// - `alloc(len: i32) -> i32`, which returns the offset of `len` free bytes in `memory` for the message to be copied into;
+/// - `matches(ptr: i32, len: i32) -> i32` to act as a matcher, which returns non-zero if the message at `ptr` matches;
+/// - `apply(ptr: i32, len: i32) -> i64` to act as an action, which returns the offset of the new message in the high 32 bits and its length in the low 32 bits, or `-1` to leave the message unchanged.
+///
+/// No function is provided to the module, and every call runs in a fresh instance with limited fuel and memory, so plugins can do nothing but compute on the message given.
+/// Calls run on threads allowed to block, as they may compute for a while before running out of fuel.
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct Plugin {
+    engine: Engine,
+    pre: InstancePre<StoreLimits>,
+}
+
+impl Plugin {
+    /// Load the plugin from a WebAssembly module in either binary or text format.
+    pub fn new(module: impl AsRef<[u8]>) -> Result<Self> {
+        let engine = Self::engine()?;
+        let module = Module::new(&engine, module).map_err(plugin_error)?;
+        Self::link(engine, module)
+    }
+
+    /// Load the plugin from the WebAssembly module file.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let engine = Self::engine()?;
+        let module = Module::from_file(&engine, path).map_err(plugin_error)?;
+        Self::link(engine, module)
+    }
+
+    fn engine() -> Result<Engine> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        Engine::new(&config).map_err(plugin_error)
+    }
+
+    fn link(engine: Engine, module: Module) -> Result<Self> {
+        // Modules importing anything fail to link against the empty linker.
+        let pre = Linker::new(&engine)
+            .instantiate_pre(&module)
+            .map_err(plugin_error)?;
+        Ok(Self { engine, pre })
+    }
+
+    // Copy the message into a fresh instance, and call the function exported with it.
+    fn call<R: WasmResults>(
+        &self,
+        name: &str,
+        msg: &Message<Bytes>,
+    ) -> Result<(Store<StoreLimits>, Memory, R)> {
+        let mut store = Store::new(
+            &self.engine,
+            StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+        );
+        store.limiter(|limits| limits);
+        store.add_fuel(FUEL).map_err(plugin_error)?;
+        let instance = self.pre.instantiate(&mut store).map_err(plugin_error)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| plugin_error("no memory exported"))?;
+
+        let data = msg.as_slice();
+        let len = i32::try_from(data.len()).map_err(plugin_error)?;
+        let ptr = instance
+            .get_typed_func::<i32, i32>(&mut store, "alloc")
+            .map_err(plugin_error)?
+            .call(&mut store, len)
+            .map_err(plugin_error)?;
+        memory
+            .write(&mut store, ptr as u32 as usize, data)
+            .map_err(plugin_error)?;
+        let r = instance
+            .get_typed_func::<(i32, i32), R>(&mut store, name)
+            .map_err(plugin_error)?
+            .call(&mut store, (ptr, len))
+            .map_err(plugin_error)?;
+        Ok((store, memory, r))
+    }
+
+    // Run the call off the async threads, which would otherwise be held up for as long as it computes.
+    async fn blocking<T: Send + 'static>(
+        self: &Arc<Self>,
+        msg: &Message<Bytes>,
+        f: fn(&Self, &Message<Bytes>) -> Result<T>,
+    ) -> Result<T> {
+        let (plugin, msg) = (self.clone(), msg.clone());
+        let (tx, rx) = oneshot::channel();
+        crate::runtime::spawn_blocking(move || {
+            // The caller may have given up on the result.
+            let _ = tx.send(f(&plugin, &msg));
+        });
+        rx.await.map_err(plugin_error)?
+    }
+
+    /// Whether the message matches per the plugin.
+    pub async fn matches(self: &Arc<Self>, msg: &Message<Bytes>) -> Result<bool> {
+        self.blocking(msg, Self::matches_blocking).await
+    }
+
+    /// Apply the plugin on the message, returning the new one.
+    pub async fn apply(self: &Arc<Self>, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        self.blocking(msg, Self::apply_blocking).await
+    }
+
+    fn matches_blocking(&self, msg: &Message<Bytes>) -> Result<bool> {
+        let (_, _, r) = self.call::<i32>("matches", msg)?;
+        Ok(r != 0)
+    }
+
+    fn apply_blocking(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let (store, memory, r) = self.call::<i64>("apply", msg)?;
+        if r < 0 {
+            return Ok(msg.clone());
+        }
+        let (ptr, len) = ((r >> 32) as usize, (r & 0xffff_ffff) as usize);
+        if ptr.saturating_add(len) > memory.data_size(&store) {
+            return Err(plugin_error("message returned is out of bounds"));
+        }
+        let mut buf = vec![0; len];
+        memory.read(&store, ptr, &mut buf).map_err(plugin_error)?;
+        Ok(Message::from_octets(Bytes::from(buf))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Plugin;
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, Message, MessageBuilder, Rtype};
+    use std::{str::FromStr, sync::Arc};
+
+    const MODULE: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+          (func (export "matches") (param i32 i32) (result i32)
+            (i32.eq (i32.load8_u (i32.add (local.get 0) (i32.const 1))) (i32.const 42)))
+          (func (export "apply") (param i32 i32) (result i64)
+            (i32.store8 (i32.add (local.get 0) (i32.const 1)) (i32.const 43))
+            (i64.or
+              (i64.shl (i64.extend_i32_u (local.get 0)) (i64.const 32))
+              (i64.extend_i32_u (local.get 1)))))
+    "#;
+
+    fn query(id: u16) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        builder.header_mut().set_id(id);
+        let mut builder = builder.question();
+        builder.push((&name, Rtype::A)).unwrap();
+        builder.into_message()
+    }
+
+    #[tokio::test]
+    async fn matches_and_apply() {
+        let plugin = Arc::new(Plugin::new(MODULE).unwrap());
+        assert!(plugin.matches(&query(42)).await.unwrap());
+        assert!(!plugin.matches(&query(1)).await.unwrap());
+
+        let resp = plugin.apply(&query(42)).await.unwrap();
+        assert_eq!(resp.header().id(), 43);
+        assert_eq!(
+            resp.sole_question().unwrap().qname(),
+            query(42).sole_question().unwrap().qname()
+        );
+    }
+
+    #[tokio::test]
+    async fn sandboxed() {
+        // Plugins running forever are aborted.
+        let plugin = Arc::new(
+            Plugin::new(
+            r#"(module
+                 (memory (export "memory") 1)
+                 (func (export "alloc") (param i32) (result i32) (i32.const 0))
+                 (func (export "matches") (param i32 i32) (result i32) (loop (br 0)) (i32.const 0)))"#,
+            )
+            .unwrap(),
+        );
+        assert!(plugin.matches(&query(42)).await.is_err());
+
+        // Nothing is provided to the plugins.
+        assert!(Plugin::new(
+            r#"(module (import "wasi_snapshot_preview1" "fd_write" (func (param i32 i32 i32 i32) (result i32))))"#
+        )
+        .is_err());
+    }
+}
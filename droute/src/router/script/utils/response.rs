@@ -0,0 +1,206 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{Domain, IpCidr, Result, UtilsError};
+use bytes::Bytes;
+use domain::{
+    base::{iana::Rcode, Message},
+    rdata::AllRecordData,
+};
+
+// Response codes given by their mnemonics, in any case
+fn rcode(s: &str) -> Result<Rcode> {
+    Ok(match s.to_lowercase().as_str() {
+        "noerror" => Rcode::NoError,
+        "formerr" => Rcode::FormErr,
+        "servfail" => Rcode::ServFail,
+        "nxdomain" => Rcode::NXDomain,
+        "notimp" => Rcode::NotImp,
+        "refused" => Rcode::Refused,
+        _ => return Err(UtilsError::UnknownRcode(s.to_string())),
+    })
+}
+
+/// Matcher on the responses from the upstreams rather than the queries, e.g. to tell the poisoned answers by their addresses and query elsewhere.
+/// It matches the response if any of the conditions added does.
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct ResponseMatcher {
+    rcodes: Vec<Rcode>,
+    ips: Option<IpCidr>,
+    cnames: Option<Domain>,
+    ttl_below: Option<u32>,
+    unauthenticated: bool,
+}
+
+impl ResponseMatcher {
+    /// Create a matcher matching nothing
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Match the responses with the response code, e.g. `nxdomain` or `servfail`.
+    pub fn add_rcode(&mut self, s: impl AsRef<str>) -> Result<()> {
+        self.rcodes.push(rcode(s.as_ref())?);
+        Ok(())
+    }
+
+    /// Match the responses with any A or AAAA record in the answer section within the CIDR.
+    pub fn add_cidr(&mut self, s: impl AsRef<str>) -> Result<()> {
+        self.ips.get_or_insert_with(IpCidr::new).add_cidr(s)
+    }
+
+    /// Match the responses with any A or AAAA record in the answer section within the CIDRs in the file, one per line.
+    pub fn add_cidr_file(&mut self, path: impl AsRef<str>) -> Result<()> {
+        self.ips
+            .get_or_insert_with(IpCidr::new)
+            .add_file(path.as_ref())
+    }
+
+    /// Match the responses with any CNAME record in the answer section targeting the domain or its subdomains.
+    pub fn add_cname(&mut self, s: impl AsRef<str>) -> Result<()> {
+        self.cnames.get_or_insert_with(Domain::new).add_qname(s)
+    }
+
+    /// Match the responses with any CNAME record in the answer section targeting the domains in the file or their subdomains.
+    pub fn add_cname_file(&mut self, path: impl AsRef<str>) -> Result<()> {
+        self.cnames.get_or_insert_with(Domain::new).add_file(path)
+    }
+
+    /// Match the responses with any record in the answer section whose TTL is below `ttl`, e.g. the ones forged with tiny TTLs.
+    pub fn ttl_below(&mut self, ttl: u32) {
+        self.ttl_below = Some(ttl);
+    }
+
+    /// Match the responses not authenticated by the upstreams, i.e. without the AD bit set, e.g. to require validated answers for security-sensitive domains.
+    pub fn unauthenticated(&mut self) {
+        self.unauthenticated = true;
+    }
+
+    /// Check if the response matches.
+    pub fn matches(&self, msg: &Message<Bytes>) -> Result<bool> {
+        if self.rcodes.contains(&msg.header().rcode())
+            || (self.unauthenticated && !msg.header().ad())
+        {
+            return Ok(true);
+        }
+        for item in msg.answer()? {
+            let item = item?;
+            if self.ttl_below.map_or(false, |ttl| item.ttl() < ttl) {
+                return Ok(true);
+            }
+            if self.ips.is_none() && self.cnames.is_none() {
+                continue;
+            }
+            if let Some(record) = item.into_record::<AllRecordData<_, _>>()? {
+                match (record.data(), &self.ips, &self.cnames) {
+                    (AllRecordData::A(a), Some(ips), _) if ips.contains(a.addr().into()) => {
+                        return Ok(true)
+                    }
+                    (AllRecordData::Aaaa(aaaa), Some(ips), _)
+                        if ips.contains(aaaa.addr().into()) =>
+                    {
+                        return Ok(true)
+                    }
+                    (AllRecordData::Cname(cname), _, Some(cnames))
+                        if cnames.contains(&cname.cname().to_bytes()) =>
+                    {
+                        return Ok(true)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ResponseMatcher;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::{Cname, A},
+    };
+    use std::str::FromStr;
+
+    // Response with a CNAME to `cdn.example.net`, and an address under it
+    fn response(rcode: Rcode, addr: A, ttl: u32) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("www.example.com").unwrap();
+        let target = Dname::<Bytes>::from_str("cdn.example.net").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&builder.into_message(), rcode)
+            .unwrap();
+        builder
+            .push((&name, 600, Cname::new(target.clone())))
+            .unwrap();
+        builder.push((&target, ttl, addr)).unwrap();
+        builder.into_message()
+    }
+
+    #[test]
+    fn matches() {
+        let clean = response(Rcode::NoError, A::from_octets(192, 0, 2, 1), 300);
+        assert!(!ResponseMatcher::new().matches(&clean).unwrap());
+
+        let mut matcher = ResponseMatcher::new();
+        matcher.add_cidr("198.51.100.0/24").unwrap();
+        assert!(!matcher.matches(&clean).unwrap());
+        assert!(matcher
+            .matches(&response(
+                Rcode::NoError,
+                A::from_octets(198, 51, 100, 7),
+                300
+            ))
+            .unwrap());
+
+        let mut matcher = ResponseMatcher::new();
+        matcher.add_rcode("NXDOMAIN").unwrap();
+        assert!(!matcher.matches(&clean).unwrap());
+        assert!(matcher
+            .matches(&response(
+                Rcode::NXDomain,
+                A::from_octets(192, 0, 2, 1),
+                300
+            ))
+            .unwrap());
+        assert!(matcher.add_rcode("bogus").is_err());
+
+        let mut matcher = ResponseMatcher::new();
+        matcher.add_cname("example.net").unwrap();
+        assert!(matcher.matches(&clean).unwrap());
+
+        let mut matcher = ResponseMatcher::new();
+        matcher.ttl_below(60);
+        assert!(!matcher.matches(&clean).unwrap());
+        assert!(matcher
+            .matches(&response(Rcode::NoError, A::from_octets(192, 0, 2, 1), 5))
+            .unwrap());
+
+        let mut matcher = ResponseMatcher::new();
+        matcher.unauthenticated();
+        assert!(matcher.matches(&clean).unwrap());
+        let mut authenticated = Message::from_octets(BytesMut::from(clean.as_slice())).unwrap();
+        authenticated.header_mut().set_ad(true);
+        let authenticated = Message::from_octets(authenticated.into_octets().freeze()).unwrap();
+        assert!(!matcher.matches(&authenticated).unwrap());
+    }
+}
@@ -0,0 +1,294 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{
+    edit::edit_records,
+    svcb::{edit_params, is_svcb, Param, IPV4HINT, IPV6HINT},
+    Result, UtilsError,
+};
+use bytes::Bytes;
+use domain::base::{iana::Rtype, Message};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+// An address range in the form of `address/prefix length`, with the length omitted for a single address.
+#[derive(Clone, Copy)]
+struct Range {
+    addr: u128,
+    len: u8,
+    v4: bool,
+}
+
+impl Range {
+    fn parse(s: &str) -> Result<Self> {
+        let invalid = || UtilsError::InvalidRewrite(s.to_string());
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len.parse::<u8>().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        let (addr, v4) = match addr.parse::<IpAddr>().map_err(|_| invalid())? {
+            IpAddr::V4(v4) => (u32::from(v4).into(), true),
+            IpAddr::V6(v6) => (u128::from(v6), false),
+        };
+        let max = if v4 { 32 } else { 128 };
+        let len = len.unwrap_or(max);
+        if len > max {
+            return Err(invalid());
+        }
+        Ok(Self {
+            addr: addr & mask(len, v4),
+            len,
+            v4,
+        })
+    }
+
+    fn single(&self) -> bool {
+        self.len == if self.v4 { 32 } else { 128 }
+    }
+}
+
+// Mask of the prefix within the address width.
+fn mask(len: u8, v4: bool) -> u128 {
+    let width = if v4 { 32 } else { 128 };
+    let all = u128::MAX >> (128 - width);
+    all ^ (all.checked_shr(len.into()).unwrap_or(0))
+}
+
+/// Rewrite of the addresses in answers, like a NAT. Among the rules matching the address, the most specific one applies.
+/// A range can be mapped onto a single address, or onto another range of the same size with the host part kept.
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub struct Rewrite {
+    rules: Vec<(Range, Range)>,
+}
+
+impl Rewrite {
+    /// Create an empty rewrite map
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule rewriting addresses in `from` into `to`, e.g. `0.0.0.0` to `192.168.1.2` or `203.0.113.0/24` to `10.0.113.0/24`.
+    pub fn add(&mut self, from: impl AsRef<str>, to: impl AsRef<str>) -> Result<()> {
+        let (from_range, to_range) = (Range::parse(from.as_ref())?, Range::parse(to.as_ref())?);
+        // Record types cannot be changed, and ranges have to be of the same size to keep the host part.
+        if from_range.v4 != to_range.v4 || !(to_range.single() || from_range.len == to_range.len) {
+            return Err(UtilsError::InvalidRewrite(format!(
+                "{} -> {}",
+                from.as_ref(),
+                to.as_ref()
+            )));
+        }
+        self.rules.push((from_range, to_range));
+        // Most specific rules first
+        self.rules.sort_by(|a, b| b.0.len.cmp(&a.0.len));
+        Ok(())
+    }
+
+    /// Add rules from a file where each line is a rule like `from to`.
+    pub fn add_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let (mut file, _) = niffler::from_path(path)?;
+        let mut data = String::new();
+        file.read_to_string(&mut data)?;
+        for line in data.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [from, to] => self.add(from, to)?,
+                _ => return Err(UtilsError::InvalidRewrite(line.to_string())),
+            }
+        }
+        Ok(())
+    }
+
+    /// Translate a single address, or `None` if no rule applies.
+    pub fn translate(&self, ip: IpAddr) -> Option<IpAddr> {
+        let (addr, v4) = match ip {
+            IpAddr::V4(v4) => (u32::from(v4).into(), true),
+            IpAddr::V6(v6) => (u128::from(v6), false),
+        };
+        let (from, to) = self
+            .rules
+            .iter()
+            .find(|(from, _)| from.v4 == v4 && addr & mask(from.len, v4) == from.addr)?;
+        let translated = if to.single() {
+            to.addr
+        } else {
+            to.addr | (addr & !mask(from.len, v4))
+        };
+        Some(if v4 {
+            IpAddr::V4(Ipv4Addr::from(translated as u32))
+        } else {
+            IpAddr::V6(Ipv6Addr::from(translated))
+        })
+    }
+
+    /// Rewrite the addresses in the A and AAAA records, and the address hints in the SVCB and HTTPS records, of the answer and additional sections.
+    pub fn apply(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        edit_records(msg, |rtype, rdata| match rtype {
+            Rtype::A | Rtype::Aaaa => self.translate_octets(rdata),
+            rtype if is_svcb(rtype) => edit_params(rdata, |key, value| match key {
+                IPV4HINT | IPV6HINT => {
+                    let width = if key == IPV4HINT { 4 } else { 16 };
+                    let mut changed = false;
+                    let mut new = Vec::with_capacity(value.len());
+                    for addr in value.chunks(width) {
+                        match self.translate_octets(addr) {
+                            Some(addr) => {
+                                changed = true;
+                                new.extend_from_slice(&addr);
+                            }
+                            None => new.extend_from_slice(addr),
+                        }
+                    }
+                    if changed {
+                        Param::Replace(new)
+                    } else {
+                        Param::Keep
+                    }
+                }
+                _ => Param::Keep,
+            }),
+            _ => None,
+        })
+    }
+
+    // Translate an address in network byte order.
+    fn translate_octets(&self, octets: &[u8]) -> Option<Vec<u8>> {
+        let ip = match octets.len() {
+            4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(octets).ok()?)),
+            16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(octets).ok()?)),
+            _ => return None,
+        };
+        Some(match self.translate(ip)? {
+            IpAddr::V4(v4) => v4.octets().to_vec(),
+            IpAddr::V6(v6) => v6.octets().to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rewrite;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{Dname, Message, MessageBuilder, Rtype},
+        rdata::{Aaaa, AllRecordData, A},
+    };
+    use std::str::FromStr;
+
+    fn rewrite() -> Rewrite {
+        let mut rewrite = Rewrite::new();
+        rewrite.add("0.0.0.0", "192.168.1.2").unwrap();
+        rewrite.add("203.0.113.0/24", "10.0.113.0/24").unwrap();
+        rewrite.add("203.0.113.7", "10.0.0.7").unwrap();
+        rewrite.add("2001:db8::/32", "fd00:1::/32").unwrap();
+        rewrite
+    }
+
+    #[test]
+    fn translate() {
+        let rewrite = rewrite();
+        let t = |ip: &str| {
+            rewrite
+                .translate(ip.parse().unwrap())
+                .map(|ip| ip.to_string())
+        };
+        assert_eq!(t("0.0.0.0").unwrap(), "192.168.1.2");
+        assert_eq!(t("203.0.113.42").unwrap(), "10.0.113.42");
+        // Most specific rule takes precedence.
+        assert_eq!(t("203.0.113.7").unwrap(), "10.0.0.7");
+        assert_eq!(t("2001:db8::1").unwrap(), "fd00:1::1");
+        assert_eq!(t("1.1.1.1"), None);
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let mut rewrite = Rewrite::new();
+        assert!(rewrite.add("0.0.0.0", "::1").is_err());
+        assert!(rewrite.add("10.0.0.0/8", "10.0.0.0/16").is_err());
+        assert!(rewrite.add("10.0.0.0/33", "10.0.0.1").is_err());
+        assert!(rewrite.add("not an ip", "10.0.0.1").is_err());
+    }
+
+    #[test]
+    fn apply() {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        builder.header_mut().set_id(42);
+        let mut builder = builder.question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let mut builder = builder.answer();
+        builder
+            .push((&name, 10, A::from_octets(0, 0, 0, 0)))
+            .unwrap();
+        builder
+            .push((&name, 10, A::from_octets(1, 1, 1, 1)))
+            .unwrap();
+        builder
+            .push((&name, 10, Aaaa::new("2001:db8::2".parse().unwrap())))
+            .unwrap();
+        let msg: Message<Bytes> = builder.into_message();
+
+        let resp = rewrite().apply(&msg).unwrap();
+        assert_eq!(resp.header().id(), 42);
+        assert_eq!(resp.sole_question().unwrap().qname(), &name);
+        let answers: Vec<String> = resp
+            .answer()
+            .unwrap()
+            .map(|r| {
+                match r
+                    .unwrap()
+                    .into_record::<AllRecordData<_, _>>()
+                    .unwrap()
+                    .unwrap()
+                    .data()
+                {
+                    AllRecordData::A(a) => a.addr().to_string(),
+                    AllRecordData::Aaaa(aaaa) => aaaa.addr().to_string(),
+                    _ => unreachable!(),
+                }
+            })
+            .collect();
+        assert_eq!(answers, vec!["192.168.1.2", "1.1.1.1", "fd00:1::2"]);
+    }
+
+    #[test]
+    fn apply_hints() {
+        use super::super::svcb::tests::{answer, message, rdata};
+        let msg = message(rdata(&[
+            (1, b"\x02h2"),
+            (4, &[0, 0, 0, 0, 1, 1, 1, 1]),
+            (
+                6,
+                &"2001:db8::1"
+                    .parse::<std::net::Ipv6Addr>()
+                    .unwrap()
+                    .octets(),
+            ),
+        ]));
+        assert_eq!(
+            answer(&rewrite().apply(&msg).unwrap()),
+            rdata(&[
+                (1, b"\x02h2"),
+                (4, &[192, 168, 1, 2, 1, 1, 1, 1]),
+                (
+                    6,
+                    &"fd00:1::1".parse::<std::net::Ipv6Addr>().unwrap().octets()
+                ),
+            ])
+        );
+    }
+}
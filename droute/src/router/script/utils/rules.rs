@@ -0,0 +1,105 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Matchers in the script tracked under a name count how often they are evaluated and how often they match.
+// Counters live across reloads, so that the rules never matched can be told over a long period.
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+static RULES: Lazy<RwLock<BTreeMap<String, Arc<RuleCounter>>>> = Lazy::new(Default::default);
+
+/// A snapshot of the counters of a rule.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleStats {
+    /// Number of times the rule is evaluated
+    pub evaluations: u64,
+    /// Number of times the rule matched
+    pub hits: u64,
+}
+
+/// Counters of a rule, i.e. a matcher tracked under a name.
+#[derive(Default)]
+pub struct RuleCounter {
+    evaluations: AtomicU64,
+    hits: AtomicU64,
+}
+
+impl RuleCounter {
+    /// Count an evaluation of the rule, returning whether it matched as it is.
+    pub fn record(&self, hit: bool) -> bool {
+        self.evaluations.fetch_add(1, Ordering::Relaxed);
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        hit
+    }
+
+    fn snapshot(&self) -> RuleStats {
+        RuleStats {
+            evaluations: self.evaluations.load(Ordering::Relaxed),
+            hits: self.hits.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The counters of the rule with the name, created if there is none yet. Matchers tracked under the same name share the counters.
+pub fn rule_counter(name: &str) -> Arc<RuleCounter> {
+    if let Some(counter) = RULES.read().unwrap().get(name) {
+        return counter.clone();
+    }
+    RULES
+        .write()
+        .unwrap()
+        .entry(name.to_string())
+        .or_default()
+        .clone()
+}
+
+/// A snapshot of the counters of every rule by name.
+pub fn rule_stats() -> BTreeMap<String, RuleStats> {
+    RULES
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(name, counter)| (name.clone(), counter.snapshot()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rule_counter, rule_stats, RuleStats};
+
+    #[test]
+    fn count() {
+        let counter = rule_counter("test-count");
+        assert!(counter.record(true));
+        assert!(!rule_counter("test-count").record(false));
+        assert_eq!(
+            rule_stats()["test-count"],
+            RuleStats {
+                evaluations: 2,
+                hits: 1
+            }
+        );
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Timetables in cron syntax, telling whether each minute (in local time) is within them.
+
+use super::{Result, UtilsError};
+use chrono::{DateTime, Datelike, Local, TimeZone, Timelike};
+use serde::{Deserialize, Serialize};
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// Parse the value, either a number or a name whose position counts from `min`.
+fn value(s: &str, min: u32, names: &[&str]) -> Option<u32> {
+    s.parse().ok().or_else(|| {
+        names
+            .iter()
+            .position(|n| n.eq_ignore_ascii_case(s))
+            .map(|i| i as u32 + min)
+    })
+}
+
+// Parse a field of lists, ranges, and steps (e.g. `1-5`, `*/15`, `mon,wed`) into the set of values within `min..=max` it covers.
+fn field(s: &str, min: u32, max: u32, names: &[&str]) -> Option<u64> {
+    let mut set = 0_u64;
+    for part in s.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().ok().filter(|&s: &u32| s > 0)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (value(start, min, names)?, value(end, min, names)?),
+                None => {
+                    let v = value(range, min, names)?;
+                    // `5/15` runs from 5 to the end, as in Vixie cron.
+                    (v, if step > 1 { max } else { v })
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        for v in (start..=end).step_by(step as usize) {
+            set |= 1_u64 << v;
+        }
+    }
+    Some(set)
+}
+
+/// A timetable in cron syntax: minute, hour, day of month, month, and day of week, in local time. E.g. `* 22-23,0-6 * * *` covers every night.
+/// As in cron, a day matches if either the day of month or the day of week does when both of them are restricted.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    expr: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Whether the day of month and the day of week are restricted
+    days_restricted: bool,
+    weekdays_restricted: bool,
+    // Minute (since the epoch) evaluated last, shifted left by one, along with the result at the lowest bit
+    last: AtomicU64,
+}
+
+impl Clone for Schedule {
+    fn clone(&self) -> Self {
+        Self {
+            expr: self.expr.clone(),
+            last: AtomicU64::new(u64::MAX),
+            ..*self
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = UtilsError;
+
+    fn from_str(expr: &str) -> Result<Self> {
+        let invalid = || UtilsError::InvalidSchedule(expr.to_string());
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(invalid());
+        }
+        // Sunday is both 0 and 7.
+        let weekdays = field(fields[4], 0, 7, &WEEKDAYS).ok_or_else(invalid)?;
+        Ok(Self {
+            expr: expr.to_string(),
+            minutes: field(fields[0], 0, 59, &[]).ok_or_else(invalid)?,
+            hours: field(fields[1], 0, 23, &[]).ok_or_else(invalid)?,
+            days: field(fields[2], 1, 31, &[]).ok_or_else(invalid)?,
+            months: field(fields[3], 1, 12, &MONTHS).ok_or_else(invalid)?,
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            days_restricted: fields[2] != "*",
+            weekdays_restricted: fields[4] != "*",
+            last: AtomicU64::new(u64::MAX),
+        })
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = UtilsError;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(schedule: Schedule) -> Self {
+        schedule.expr
+    }
+}
+
+impl Schedule {
+    /// Whether the time given is within the timetable.
+    pub fn contains<Tz: TimeZone>(&self, t: &DateTime<Tz>) -> bool {
+        let bit = |set: u64, v: u32| set & (1_u64 << v) != 0;
+        let day = bit(self.days, t.day());
+        let weekday = bit(self.weekdays, t.weekday().num_days_from_sunday());
+        let day = match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        };
+        bit(self.minutes, t.minute())
+            && bit(self.hours, t.hour())
+            && bit(self.months, t.month())
+            && day
+    }
+
+    /// Whether now is within the timetable. The result is kept for the rest of the minute, so that it is cheap to ask on every query.
+    pub fn active(&self) -> bool {
+        let now = Local::now();
+        let minute = (now.timestamp() / 60) as u64;
+        let last = self.last.load(Ordering::Relaxed);
+        if last >> 1 == minute {
+            return last & 1 == 1;
+        }
+        let active = self.contains(&now);
+        self.last
+            .store(minute << 1 | u64::from(active), Ordering::Relaxed);
+        active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Schedule;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn parse() {
+        for expr in [
+            "* * * *",
+            "60 * * * *",
+            "* 5-2 * * *",
+            "*/0 * * * *",
+            "* * * * funday",
+        ] {
+            assert!(expr.parse::<Schedule>().is_err(), "{}", expr);
+        }
+        assert!("*/15 9-17 * jan-jun mon-fri".parse::<Schedule>().is_ok());
+    }
+
+    #[test]
+    fn timetable() {
+        // Every night on weekdays
+        let nights: Schedule = "* 22-23,0-6 * * mon-fri".parse().unwrap();
+        // Friday, 2023-03-03
+        assert!(nights.contains(&Utc.with_ymd_and_hms(2023, 3, 3, 23, 30, 0).unwrap()));
+        assert!(!nights.contains(&Utc.with_ymd_and_hms(2023, 3, 3, 12, 0, 0).unwrap()));
+        // Saturday
+        assert!(!nights.contains(&Utc.with_ymd_and_hms(2023, 3, 4, 23, 30, 0).unwrap()));
+
+        // Sunday is both 0 and 7.
+        let sundays: Schedule = "0 12 * * 7".parse().unwrap();
+        assert!(sundays.contains(&Utc.with_ymd_and_hms(2023, 3, 5, 12, 0, 0).unwrap()));
+        assert!(!sundays.contains(&Utc.with_ymd_and_hms(2023, 3, 5, 12, 1, 0).unwrap()));
+
+        // Either the day of month or the day of week
+        let either: Schedule = "* * 1 * sat".parse().unwrap();
+        assert!(either.contains(&Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap()));
+        assert!(either.contains(&Utc.with_ymd_and_hms(2023, 3, 4, 0, 0, 0).unwrap()));
+        assert!(!either.contains(&Utc.with_ymd_and_hms(2023, 3, 5, 0, 0, 0).unwrap()));
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// SVCB and HTTPS records (RFC 9460) are not understood by `domain` yet, so we handle their wire format here.
+
+use super::{edit::edit_records, Result};
+use bytes::Bytes;
+use domain::{
+    base::{iana::Rtype, Message},
+    rdata::UnknownRecordData,
+};
+
+pub(super) const SVCB: u16 = 64;
+pub(super) const HTTPS: u16 = 65;
+
+pub(super) const IPV4HINT: u16 = 4;
+pub(super) const ECH: u16 = 5;
+pub(super) const IPV6HINT: u16 = 6;
+
+/// What to do with a SvcParam
+pub(super) enum Param {
+    Keep,
+    Drop,
+    Replace(Vec<u8>),
+}
+
+pub(crate) fn is_svcb(rtype: Rtype) -> bool {
+    matches!(rtype.to_int(), SVCB | HTTPS)
+}
+
+// Edit the SvcParams of the record data. Returns `None` if nothing is changed or the record data is malformed.
+pub(super) fn edit_params(
+    rdata: &[u8],
+    mut edit: impl FnMut(u16, &[u8]) -> Param,
+) -> Option<Vec<u8>> {
+    // SvcPriority
+    let mut pos = 2;
+    // TargetName, which is never compressed
+    loop {
+        let len = usize::from(*rdata.get(pos)?);
+        pos += 1 + len;
+        if len == 0 {
+            break;
+        }
+        // Compression pointers or reserved label types
+        if len > 63 {
+            return None;
+        }
+    }
+    if pos > rdata.len() {
+        return None;
+    }
+
+    let mut out = rdata[..pos].to_vec();
+    let mut changed = false;
+    while pos < rdata.len() {
+        let key = u16::from_be_bytes([*rdata.get(pos)?, *rdata.get(pos + 1)?]);
+        let len = usize::from(u16::from_be_bytes([
+            *rdata.get(pos + 2)?,
+            *rdata.get(pos + 3)?,
+        ]));
+        let value = rdata.get(pos + 4..pos + 4 + len)?;
+        pos += 4 + len;
+
+        let value = match edit(key, value) {
+            Param::Keep => value.to_vec(),
+            Param::Drop => {
+                changed = true;
+                continue;
+            }
+            Param::Replace(new) => {
+                changed = true;
+                new
+            }
+        };
+        out.extend_from_slice(&key.to_be_bytes());
+        out.extend_from_slice(&u16::try_from(value.len()).ok()?.to_be_bytes());
+        out.extend_from_slice(&value);
+    }
+
+    changed.then_some(out)
+}
+
+// Drop the SvcParams with the keys given from all the SVCB and HTTPS records.
+fn strip(msg: &Message<Bytes>, keys: &[u16]) -> Result<Message<Bytes>> {
+    edit_records(msg, |rtype, rdata| {
+        if !is_svcb(rtype) {
+            return None;
+        }
+        edit_params(rdata, |key, _| {
+            if keys.contains(&key) {
+                Param::Drop
+            } else {
+                Param::Keep
+            }
+        })
+    })
+}
+
+// Whether any SVCB or HTTPS record in the answer section carries ECH configs.
+pub(crate) fn has_ech(msg: &Message<Bytes>) -> bool {
+    let answer = match msg.answer() {
+        Ok(answer) => answer,
+        Err(_) => return false,
+    };
+    answer
+        .filter_map(|item| item.ok()?.into_record::<UnknownRecordData<_>>().ok()?)
+        .filter(|r| is_svcb(r.rtype()))
+        .any(|r| {
+            let mut ech = false;
+            edit_params(r.data().data().as_ref(), |key, _| {
+                ech |= key == ECH;
+                Param::Keep
+            });
+            ech
+        })
+}
+
+/// Whether the query asks for SVCB or HTTPS records, e.g. to answer them differently from A and AAAA queries.
+pub fn queries_svcb(msg: &Message<Bytes>) -> bool {
+    msg.first_question().map_or(false, |q| is_svcb(q.qtype()))
+}
+
+/// Remove the ECH configs from SVCB and HTTPS records, so that clients connect without Encrypted Client Hello.
+pub fn strip_ech(msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+    strip(msg, &[ECH])
+}
+
+/// Remove `ipv4hint` and `ipv6hint` from SVCB and HTTPS records, so that clients have to resolve the addresses with A and AAAA queries.
+pub fn strip_ip_hints(msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+    strip(msg, &[IPV4HINT, IPV6HINT])
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::{has_ech, strip_ech, strip_ip_hints, HTTPS};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{Dname, Message, MessageBuilder, Rtype},
+        rdata::UnknownRecordData,
+    };
+    use std::str::FromStr;
+
+    // HTTPS record data with SvcPriority 1, TargetName `.`, and the given params.
+    pub fn rdata(params: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut rdata = vec![0, 1, 0];
+        for (key, value) in params {
+            rdata.extend_from_slice(&key.to_be_bytes());
+            rdata.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            rdata.extend_from_slice(value);
+        }
+        rdata
+    }
+
+    pub fn message(rdata: Vec<u8>) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        let mut builder = builder.question();
+        builder.push((&name, Rtype::Int(HTTPS))).unwrap();
+        let mut builder = builder.answer();
+        builder
+            .push((
+                &name,
+                10,
+                UnknownRecordData::from_octets(Rtype::Int(HTTPS), Bytes::from(rdata)),
+            ))
+            .unwrap();
+        builder.into_message()
+    }
+
+    pub fn answer(msg: &Message<Bytes>) -> Vec<u8> {
+        msg.answer()
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .into_record::<UnknownRecordData<_>>()
+            .unwrap()
+            .unwrap()
+            .data()
+            .data()
+            .to_vec()
+    }
+
+    const V4: &[u8] = &[1, 1, 1, 1];
+    const ECH: &[u8] = b"ech config";
+    const V6: &[u8] = &[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+
+    #[test]
+    fn strip() {
+        let msg = message(rdata(&[(1, b"\x02h2"), (4, V4), (5, ECH), (6, V6)]));
+        assert_eq!(
+            answer(&strip_ech(&msg).unwrap()),
+            rdata(&[(1, b"\x02h2"), (4, V4), (6, V6)])
+        );
+        assert_eq!(
+            answer(&strip_ip_hints(&msg).unwrap()),
+            rdata(&[(1, b"\x02h2"), (5, ECH)])
+        );
+        assert!(has_ech(&msg));
+        assert!(!has_ech(&strip_ech(&msg).unwrap()));
+    }
+
+    #[test]
+    fn malformed() {
+        // Value of the last param is truncated, which is kept as is.
+        let mut data = rdata(&[(4, V4), (5, ECH)]);
+        data.pop();
+        let msg = message(data.clone());
+        assert_eq!(answer(&strip_ech(&msg).unwrap()), data);
+    }
+}
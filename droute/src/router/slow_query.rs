@@ -0,0 +1,105 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Slow-query log and sampling. Decisions made along the query path are noted down in a per-query trace, which is logged if the query turns out to be slow or sampled.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    fmt,
+    future::Future,
+    num::NonZeroU64,
+    time::{Duration, Instant},
+};
+
+tokio::task_local! {
+    static TRACE: RefCell<QueryTrace>;
+}
+
+/// Configuration of the slow-query log.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SlowQueryLog {
+    /// Queries taking longer than this (in milliseconds) end-to-end are logged with their trace.
+    pub threshold: u64,
+    /// Additionally log the trace of one in every `sample` queries regardless of their latency.
+    #[serde(default)]
+    pub sample: Option<NonZeroU64>,
+}
+
+impl SlowQueryLog {
+    pub(super) fn threshold(&self) -> Duration {
+        Duration::from_millis(self.threshold)
+    }
+
+    // `n` is the sequence number of the query.
+    pub(super) fn sampled(&self, n: u64) -> bool {
+        self.sample.map(|s| n % s.get() == 0).unwrap_or(false)
+    }
+}
+
+/// Decisions made and their timing relative to the start of the query.
+pub(crate) struct QueryTrace {
+    start: Instant,
+    steps: Vec<(Duration, String)>,
+}
+
+impl Default for QueryTrace {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            steps: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for QueryTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (at, step)) in self.steps.iter().enumerate() {
+            if i != 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "[+{}ms] {}", at.as_millis(), step)?;
+        }
+        Ok(())
+    }
+}
+
+impl QueryTrace {
+    /// Run the future with a fresh trace, returning its output along with the trace.
+    pub async fn scope<F: Future>(f: F) -> (F::Output, Self) {
+        TRACE
+            .scope(RefCell::new(Self::default()), async {
+                let r = f.await;
+                (r, TRACE.with(|t| t.take()))
+            })
+            .await
+    }
+
+    /// Time elapsed since the start of the query.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Note down a step of the current query if it is being traced. The message is only built on need.
+    pub fn note(step: impl FnOnce() -> String) {
+        // Fails if we are not in a traced query, which is fine.
+        let _ = TRACE.try_with(|t| {
+            let mut t = t.borrow_mut();
+            let at = t.start.elapsed();
+            t.steps.push((at, step()));
+        });
+    }
+}
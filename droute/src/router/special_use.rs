@@ -0,0 +1,101 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{
+    errors::{MessageError, UpstreamError},
+    utils::parse_domain,
+    Label, Upstreams,
+};
+use bytes::Bytes;
+use domain::base::Dname;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+// Special-use domains that should never be sent to public resolvers.
+// See also RFC 6761, RFC 7686, RFC 8375, and https://www.iana.org/domains/reserved
+const BUILTIN_DOMAINS: [&str; 5] = ["onion", "home.arpa", "internal", "test", "invalid"];
+
+/// Policy applied to the queries under a special-use domain.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpecialUsePolicy {
+    /// Route the query like any other queries.
+    Forward,
+    /// Answer the query with REFUSED.
+    Refuse,
+    /// Answer the query with NXDOMAIN locally.
+    Nxdomain,
+    /// Send the query to the upstream with the given tag, bypassing the script.
+    Upstream(Label),
+}
+
+/// Special-use domains and the policies applied to the queries under them.
+#[derive(Clone)]
+pub struct SpecialUse(Vec<(Dname<Bytes>, SpecialUsePolicy)>);
+
+impl Default for SpecialUse {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpecialUse {
+    /// Create the set of built-in special-use domains, all of which are answered with NXDOMAIN locally.
+    pub fn new() -> Self {
+        Self(
+            BUILTIN_DOMAINS
+                .iter()
+                // Built-in domains are always valid
+                .map(|d| (Dname::from_str(d).unwrap(), SpecialUsePolicy::Nxdomain))
+                .collect(),
+        )
+    }
+
+    /// Set the policy for the given domain, overriding the built-in one if there is any.
+    pub fn set(
+        &mut self,
+        domain: impl AsRef<str>,
+        policy: SpecialUsePolicy,
+    ) -> Result<(), MessageError> {
+        let domain = parse_domain(domain.as_ref())?;
+        self.0.retain(|(d, _)| d != &domain);
+        self.0.push((domain, policy));
+        // Keep the most specific domains in front so that they take precedence.
+        self.0
+            .sort_by_key(|(d, _)| std::cmp::Reverse(d.label_count()));
+        Ok(())
+    }
+
+    // Get the policy for the query name given, if it is under any of the special-use domains.
+    pub(super) fn get(&self, qname: &Dname<Bytes>) -> Option<&SpecialUsePolicy> {
+        self.0
+            .iter()
+            .find(|(d, _)| qname.ends_with(d))
+            .map(|(_, p)| p)
+    }
+
+    // Check if all the upstreams referred to exist.
+    pub(super) fn validate(&self, upstreams: &Upstreams) -> Result<(), UpstreamError> {
+        let tags = upstreams.tags();
+        for (_, policy) in &self.0 {
+            if let SpecialUsePolicy::Upstream(tag) = policy {
+                if !tags.contains(tag) {
+                    return Err(UpstreamError::MissingTag(tag.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Counters collected along the query path, and their snapshots.
+
+use crate::Label;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// A snapshot of the statistics of a `Router`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RouterStats {
+    /// Number of queries resolved
+    pub queries: u64,
+    /// Number of queries answered with SERVFAIL because of errors
+    pub errors: u64,
+    /// Number of queries answered by the special-use domain policies without going through the script
+    pub special_use: u64,
+    /// Number of queries forwarded per the zones
+    pub zones: u64,
+    /// Number of queries answered from the local copy of the root zone
+    pub root_mirror: u64,
+    /// Number of queries in the CHAOS class answered locally
+    pub chaos: u64,
+    /// Number of ANY queries handled per the ANY policy
+    pub any: u64,
+    /// Number of queries answered by the fast path without going through the policies or the script
+    pub fast_path: u64,
+    /// Statistics of the response cache
+    pub cache: CacheStats,
+    /// Statistics of each upstream by tag
+    pub upstreams: HashMap<Label, UpstreamStats>,
+}
+
+/// A snapshot of the statistics of the response cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    /// Number of lookups answered by a record within TTL
+    pub hits: u64,
+    /// Number of lookups answered by an expired record
+    pub expired: u64,
+    /// Number of lookups with no record found
+    pub misses: u64,
+}
+
+/// A snapshot of the statistics of a single upstream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct UpstreamStats {
+    /// Number of queries sent to the upstream, including the ones answered from cache
+    pub queries: u64,
+    /// Number of queries failed
+    pub errors: u64,
+    /// Number of queries the upstreams disagreed on, only counted for consensus upstreams
+    pub disagreements: u64,
+    /// Number of answers to HTTPS and SVCB queries with ECH configs
+    pub ech: u64,
+    /// Number of answers the upstream gave to names the trusted upstream of a guard upstream answered NXDOMAIN, i.e. NXDOMAIN redirections detected
+    pub hijacks: u64,
+    /// Number of queries failed right away as they failed on the upstream recently, per the SERVFAIL cache
+    pub cached_failures: u64,
+    /// Number of queries sent a second time as the upstream was slower than usual, per the hedging policy
+    pub hedges: u64,
+    /// Number of queries answered NXDOMAIN right away as a name above was answered NXDOMAIN, per the aggressive NXDOMAIN cache
+    pub nxdomain_cuts: u64,
+}
+
+// A counter that can be shared and incremented concurrently.
+#[derive(Default)]
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Increment the counter and return the count before, so that concurrent callers each get a distinct one.
+    pub fn fetch_inc(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct RouterCounters {
+    pub queries: Counter,
+    pub errors: Counter,
+    pub special_use: Counter,
+    pub zones: Counter,
+    pub root_mirror: Counter,
+    pub chaos: Counter,
+    pub any: Counter,
+    pub fast_path: Counter,
+}
+
+#[derive(Default)]
+pub(crate) struct CacheCounters {
+    pub hits: Counter,
+    pub expired: Counter,
+    pub misses: Counter,
+}
+
+impl CacheCounters {
+    pub fn snapshot(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.get(),
+            expired: self.expired.get(),
+            misses: self.misses.get(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct UpstreamCounters {
+    pub queries: Counter,
+    pub errors: Counter,
+    pub disagreements: Counter,
+    pub ech: Counter,
+    pub hijacks: Counter,
+    pub cached_failures: Counter,
+    pub hedges: Counter,
+    pub nxdomain_cuts: Counter,
+    // The NSID the upstream answered last, if asked for
+    pub nsid: Mutex<Option<String>>,
+}
+
+impl UpstreamCounters {
+    pub fn snapshot(&self) -> UpstreamStats {
+        UpstreamStats {
+            queries: self.queries.get(),
+            errors: self.errors.get(),
+            disagreements: self.disagreements.get(),
+            ech: self.ech.get(),
+            hijacks: self.hijacks.get(),
+            cached_failures: self.cached_failures.get(),
+            hedges: self.hedges.get(),
+            nxdomain_cuts: self.nxdomain_cuts.get(),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Client affinity of hybrid upstreams: instead of racing the members, the queries from the same client subnet go to the same member,
+// picked by rendezvous hashing so that only the clients of a member move once it is pruned or unreachable.
+// This keeps the geo-affinity of CDNs and the cache locality on the upstreams, which races scatter across all of them.
+
+use crate::Label;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::DefaultHasher,
+    future::Future,
+    hash::{Hash, Hasher},
+    net::IpAddr,
+};
+
+tokio::task_local! {
+    static CLIENT: IpAddr;
+}
+
+const fn default_ipv4_prefix_length() -> u8 {
+    24
+}
+
+const fn default_ipv6_prefix_length() -> u8 {
+    56
+}
+
+/// Policy to keep the queries from the same client subnet on the same member of the hybrid upstreams given.
+/// If the member failed, the rest of the members are raced as usual.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AffinityPolicy {
+    /// Tags of the hybrid upstreams to stick the clients to one of the members of
+    pub hybrids: Vec<Label>,
+    /// Length of the prefixes IPv4 clients are grouped by
+    #[serde(default = "default_ipv4_prefix_length")]
+    pub ipv4_prefix_length: u8,
+    /// Length of the prefixes IPv6 clients are grouped by
+    #[serde(default = "default_ipv6_prefix_length")]
+    pub ipv6_prefix_length: u8,
+}
+
+impl AffinityPolicy {
+    /// Stick the clients to the members of the hybrid upstreams given.
+    pub fn new(hybrids: Vec<Label>) -> Self {
+        Self {
+            hybrids,
+            ipv4_prefix_length: default_ipv4_prefix_length(),
+            ipv6_prefix_length: default_ipv6_prefix_length(),
+        }
+    }
+
+    fn subnet(&self, client: IpAddr) -> IpAddr {
+        match client {
+            IpAddr::V4(ip) => {
+                let len = self.ipv4_prefix_length.min(32);
+                let mask = u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0);
+                IpAddr::from((u32::from(ip) & mask).to_be_bytes())
+            }
+            IpAddr::V6(ip) => {
+                let len = self.ipv6_prefix_length.min(128);
+                let mask = u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0);
+                IpAddr::from((u128::from(ip) & mask).to_be_bytes())
+            }
+        }
+    }
+
+    /// The member the client of the current query sticks to, if the hybrid upstream is covered and the query comes from a client.
+    pub(super) fn pick<'a>(&self, tag: &Label, members: &[&'a Label]) -> Option<&'a Label> {
+        if members.len() < 2 || !self.hybrids.contains(tag) {
+            return None;
+        }
+        let subnet = self.subnet(CLIENT.try_with(|c| *c).ok()?);
+        members.iter().copied().max_by_key(|m| {
+            let mut hasher = DefaultHasher::new();
+            (subnet, m).hash(&mut hasher);
+            hasher.finish()
+        })
+    }
+}
+
+/// Run the future on behalf of the client given, to which the hybrid upstreams with affinity stick.
+pub(crate) async fn with_client<F: Future>(client: Option<IpAddr>, f: F) -> F::Output {
+    match client {
+        Some(client) => CLIENT.scope(client, f).await,
+        None => f.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{with_client, AffinityPolicy};
+    use crate::Label;
+
+    #[tokio::test]
+    async fn picks() {
+        let policy = AffinityPolicy::new(vec!["hybrid".into()]);
+        let members: Vec<Label> = (0..8).map(|i| i.to_string().into()).collect();
+        let members: Vec<&Label> = members.iter().collect();
+        let (p, m) = (&policy, &members);
+        let pick = move |client: &str| {
+            with_client(Some(client.parse().unwrap()), async move {
+                p.pick(&"hybrid".into(), m)
+            })
+        };
+
+        // Not covered, or not on behalf of a client
+        assert_eq!(policy.pick(&"other".into(), &members), None);
+        assert_eq!(
+            with_client(None, async { policy.pick(&"hybrid".into(), &members) }).await,
+            None
+        );
+
+        // The same subnet sticks to the same member.
+        let picked = pick("192.0.2.1").await.unwrap();
+        assert_eq!(pick("192.0.2.200").await, Some(picked));
+        assert_eq!(pick("2001:db8::1").await, pick("2001:db8:0:ff::1").await);
+
+        // Clients of the other members stay where they are once a member is gone.
+        let clients = ["198.51.100.1", "203.0.113.1", "10.0.0.1", "172.16.0.1"];
+        let mut before = Vec::new();
+        for c in clients {
+            before.push(pick(c).await.unwrap());
+        }
+        let rest: Vec<&Label> = members.iter().copied().filter(|m| *m != picked).collect();
+        for (c, b) in clients.into_iter().zip(before) {
+            let after = with_client(Some(c.parse().unwrap()), async {
+                policy.pick(&"hybrid".into(), &rest)
+            })
+            .await
+            .unwrap();
+            if b != picked {
+                assert_eq!(after, b);
+            }
+        }
+    }
+}
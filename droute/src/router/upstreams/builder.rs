@@ -13,21 +13,91 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-pub use super::upstream::builder::*;
+pub use super::{
+    affinity::AffinityPolicy,
+    capture::CaptureBuilder,
+    consensus::ConsensusMode,
+    hedge::HedgePolicy,
+    retry::{RetryPolicy, RetryRcode},
+    upstream::{
+        builder::*,
+        stamp::{parse_list as parse_stamp_list, Stamp},
+    },
+};
 
 use super::{
     error::{Result, UpstreamError},
     QHandleError, Upstreams,
 };
-use crate::{AsyncTryInto, Label, Upstream};
+#[cfg(feature = "redis-cache")]
+use crate::cache::RedisCache;
+use crate::{cache::MemoryCache, AsyncTryInto, Label, Upstream};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, num::NonZeroUsize};
+use std::{collections::HashMap, num::NonZeroUsize, time::Duration};
 
 fn default_cache_size() -> NonZeroUsize {
     NonZeroUsize::new(2048).unwrap()
 }
 
+#[cfg(feature = "redis-cache")]
+fn default_redis_prefix() -> String {
+    "dcompass:".to_string()
+}
+
+// Expired responses are served for at most a day in persistent cache mode.
+#[cfg(feature = "redis-cache")]
+const fn default_redis_stale() -> u64 {
+    86400
+}
+
+#[cfg(feature = "redis-cache")]
+fn default_redis_l1_size() -> NonZeroUsize {
+    NonZeroUsize::new(1024).unwrap()
+}
+
+/// The builder for the Redis cache shared by multiple instances
+#[cfg(feature = "redis-cache")]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RedisCacheBuilder {
+    /// URL of the Redis server, e.g. `redis://127.0.0.1:6379/0`
+    pub url: String,
+    /// Prefix of the keys
+    #[serde(default = "default_redis_prefix")]
+    pub prefix: String,
+    /// The time in second to keep responses after they expire
+    #[serde(default = "default_redis_stale")]
+    pub stale: u64,
+    /// Capacity of the local cache in front of Redis
+    #[serde(default = "default_redis_l1_size")]
+    pub l1_size: NonZeroUsize,
+}
+
+#[cfg(feature = "redis-cache")]
+impl RedisCacheBuilder {
+    /// Create a Redis cache builder for the server at `url` with the settings defaulted as in the configuration.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            prefix: default_redis_prefix(),
+            stale: default_redis_stale(),
+            l1_size: default_redis_l1_size(),
+        }
+    }
+
+    /// Connect to the Redis server.
+    pub async fn build(self) -> Result<RedisCache> {
+        Ok(RedisCache::new(
+            &self.url,
+            self.prefix,
+            Duration::from_secs(self.stale),
+            self.l1_size,
+        )
+        .await?)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
 /// The Builder for upstreams
@@ -35,6 +105,25 @@ pub struct UpstreamsBuilder<U: AsyncTryInto<Upstream, Error = QHandleError>> {
     upstreams: HashMap<Label, U>,
     #[serde(default = "default_cache_size")]
     cache_size: NonZeroUsize,
+    #[serde(default)]
+    retry: Option<RetryPolicy>,
+    #[serde(default)]
+    svcb_cache_size: Option<NonZeroUsize>,
+    #[serde(default)]
+    servfail_ttl: Option<u64>,
+    #[serde(default)]
+    aggressive_nxdomain: bool,
+    #[serde(default)]
+    capture: Option<CaptureBuilder>,
+    #[serde(default)]
+    upstream_nsid: bool,
+    #[serde(default)]
+    hedge: Option<HedgePolicy>,
+    #[serde(default)]
+    affinity: Option<AffinityPolicy>,
+    #[cfg(feature = "redis-cache")]
+    #[serde(default)]
+    redis: Option<RedisCacheBuilder>,
 }
 
 impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
@@ -43,6 +132,16 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
         Self {
             upstreams: upstreams.into_iter().map(|(k, v)| (k.into(), v)).collect(),
             cache_size,
+            retry: None,
+            svcb_cache_size: None,
+            servfail_ttl: None,
+            aggressive_nxdomain: false,
+            capture: None,
+            upstream_nsid: false,
+            hedge: None,
+            affinity: None,
+            #[cfg(feature = "redis-cache")]
+            redis: None,
         }
     }
 
@@ -51,14 +150,84 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
         std::num::NonZeroUsize::new(cache_size).map(|c| Self {
             upstreams: HashMap::new(),
             cache_size: c,
+            retry: None,
+            svcb_cache_size: None,
+            servfail_ttl: None,
+            aggressive_nxdomain: false,
+            capture: None,
+            upstream_nsid: false,
+            hedge: None,
+            affinity: None,
+            #[cfg(feature = "redis-cache")]
+            redis: None,
         })
     }
 
+    /// The upstream builders by tag
+    pub fn upstreams(&self) -> &HashMap<Label, U> {
+        &self.upstreams
+    }
+
     /// Add an upstream builder
     pub fn add_upstream(mut self, tag: impl Into<Label>, upstream: U) -> Self {
         self.upstreams.insert(tag.into(), upstream);
         self
     }
+
+    /// Retry queries answered with failure response codes
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Cache responses to HTTPS and SVCB queries separately with the size given
+    pub fn svcb_cache_size(mut self, size: NonZeroUsize) -> Self {
+        self.svcb_cache_size = Some(size);
+        self
+    }
+
+    /// Cache the failures of the upstreams for the number of seconds given
+    pub fn servfail_ttl(mut self, ttl: u64) -> Self {
+        self.servfail_ttl = Some(ttl);
+        self
+    }
+
+    /// Answer the names below the ones answered NXDOMAIN with NXDOMAIN out of the cache
+    pub fn aggressive_nxdomain(mut self, enabled: bool) -> Self {
+        self.aggressive_nxdomain = enabled;
+        self
+    }
+
+    /// Capture the traffic to the upstreams
+    pub fn capture(mut self, capture: CaptureBuilder) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Ask the upstreams querying on their own for their NSID
+    pub fn upstream_nsid(mut self, enabled: bool) -> Self {
+        self.upstream_nsid = enabled;
+        self
+    }
+
+    /// Hedge the queries to the upstreams querying on their own
+    pub fn hedge(mut self, hedge: HedgePolicy) -> Self {
+        self.hedge = Some(hedge);
+        self
+    }
+
+    /// Keep the clients on the same member of the hybrid upstreams
+    pub fn affinity(mut self, affinity: AffinityPolicy) -> Self {
+        self.affinity = Some(affinity);
+        self
+    }
+
+    /// Share the response cache with other instances through Redis, in place of the in-memory one
+    #[cfg(feature = "redis-cache")]
+    pub fn redis(mut self, redis: RedisCacheBuilder) -> Self {
+        self.redis = Some(redis);
+        self
+    }
 }
 
 #[async_trait(?Send)]
@@ -73,6 +242,35 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> AsyncTryInto<Upstreams>
         for (tag, u) in self.upstreams {
             v.insert(tag, u.async_try_into().await?);
         }
-        Upstreams::new(v, self.cache_size)
+        let mut upstreams = Upstreams::new(v, self.cache_size)?;
+        if let Some(size) = self.svcb_cache_size {
+            upstreams = upstreams.with_cache(MemoryCache::new(self.cache_size).with_svcb(size));
+        }
+        #[cfg(feature = "redis-cache")]
+        if let Some(redis) = self.redis {
+            upstreams = upstreams.with_cache(redis.build().await?);
+        }
+        if let Some(ttl) = self.servfail_ttl {
+            upstreams = upstreams.with_servfail_cache(Duration::from_secs(ttl), self.cache_size);
+        }
+        if self.aggressive_nxdomain {
+            upstreams = upstreams.with_aggressive_nxdomain(self.cache_size);
+        }
+        if let Some(capture) = self.capture {
+            upstreams = upstreams.with_capture(capture.build()?)?;
+        }
+        if self.upstream_nsid {
+            upstreams = upstreams.with_nsid();
+        }
+        if let Some(hedge) = self.hedge {
+            upstreams = upstreams.with_hedging(hedge)?;
+        }
+        if let Some(affinity) = self.affinity {
+            upstreams = upstreams.with_affinity(affinity)?;
+        }
+        match self.retry {
+            Some(retry) => upstreams.with_retry(retry),
+            None => Ok(upstreams),
+        }
     }
 }
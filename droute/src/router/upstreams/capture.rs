@@ -0,0 +1,360 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Capture of the queries sent to the upstreams and the responses received, in pcap, for debugging interop problems with specific resolvers.
+// Messages are captured as DNS over UDP on port 53 of the server regardless of the transport, so that Wireshark dissects them, while
+// the local address is left unspecified, as are the addresses of the upstreams without one, e.g. `system`.
+
+use super::{
+    error::{Result, UpstreamError},
+    upstream::{QHandle, QHandleError},
+};
+use crate::{utils::parse_domain, Label};
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use dmatcher::domain::Domain;
+use domain::base::Message;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
+
+// Number of records buffered for each subscriber, beyond which the oldest are lost
+const BUFFER: usize = 1024;
+
+// Port the queries are sent from in the capture
+const CLIENT_PORT: u16 = 49152;
+
+// Header of the pcap file: magic of microsecond timestamps, version 2.4, snap length, and link type of raw IP
+const HEADER: [u8; 24] = [
+    0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 101, 0, 0, 0,
+];
+
+const fn default_max_size() -> u64 {
+    16
+}
+
+const fn default_files() -> usize {
+    4
+}
+
+/// The builder of the capture of the upstream traffic
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureBuilder {
+    /// Path of the pcap file to write to, which is rotated to `<path>.1`, `<path>.2`, and so on. Traffic is only streamed to the subscribers if not given.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Size in MiB the file grows up to before it is rotated
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+    /// Number of the files rotated to keep
+    #[serde(default = "default_files")]
+    pub files: usize,
+    /// Tags of the upstreams to capture, or all of them if empty
+    #[serde(default)]
+    pub tags: Vec<Label>,
+    /// Domains to capture the queries for, including their subdomains, or all of them if empty
+    #[serde(default)]
+    pub domains: Vec<String>,
+}
+
+impl CaptureBuilder {
+    /// Capture the traffic to all the upstreams to the file given.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            max_size: default_max_size(),
+            files: default_files(),
+            tags: Vec::new(),
+            domains: Vec::new(),
+        }
+    }
+
+    pub(super) fn build(self) -> Result<Capture> {
+        let domains = if self.domains.is_empty() {
+            None
+        } else {
+            let mut matcher = Domain::new();
+            for domain in self.domains {
+                matcher.insert(
+                    &parse_domain(&domain)
+                        .map_err(|_| UpstreamError::InvalidDomain(domain.clone()))?,
+                );
+            }
+            Some(matcher)
+        };
+        let (sender, _) = broadcast::channel(BUFFER);
+        if let Some(path) = self.path {
+            let mut writer = Writer {
+                file: Writer::open(&path, self.files).map_err(UpstreamError::CaptureError)?,
+                path,
+                max_size: self.max_size << 20,
+                files: self.files,
+                size: HEADER.len() as u64,
+            };
+            let mut records = sender.subscribe();
+            // Written on a blocking thread, so that the disk doesn't hold up the queries.
+            crate::runtime::spawn_blocking(move || loop {
+                match records.blocking_recv() {
+                    Ok(record) => {
+                        if let Err(e) = writer.write(&record) {
+                            log::warn!("failed to write the upstream capture: {}", e);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        log::warn!("{} records of the upstream capture are lost", n)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            });
+        }
+        Ok(Capture {
+            tags: self.tags.into_iter().collect(),
+            domains,
+            sender,
+        })
+    }
+}
+
+// The pcap file rotated
+struct Writer {
+    path: PathBuf,
+    max_size: u64,
+    files: usize,
+    file: File,
+    size: u64,
+}
+
+impl Writer {
+    fn rotated(path: &Path, n: usize) -> PathBuf {
+        let mut name = OsString::from(path.as_os_str());
+        name.push(format!(".{}", n));
+        name.into()
+    }
+
+    // Open the file afresh, with the one left before rotated.
+    fn open(path: &Path, files: usize) -> io::Result<File> {
+        if files > 0 && path.exists() {
+            for n in (1..files).rev() {
+                let from = Self::rotated(path, n);
+                if from.exists() {
+                    fs::rename(from, Self::rotated(path, n + 1))?;
+                }
+            }
+            fs::rename(path, Self::rotated(path, 1))?;
+        }
+        let mut file = File::create(path)?;
+        file.write_all(&HEADER)?;
+        Ok(file)
+    }
+
+    fn write(&mut self, record: &[u8]) -> io::Result<()> {
+        if self.size + record.len() as u64 > self.max_size {
+            self.file = Self::open(&self.path, self.files)?;
+            self.size = HEADER.len() as u64;
+        }
+        self.file.write_all(record)?;
+        self.size += record.len() as u64;
+        Ok(())
+    }
+}
+
+/// Capture of the upstream traffic, written to a rotating pcap file, and streamed live to the subscribers.
+pub struct Capture {
+    tags: HashSet<Label>,
+    domains: Option<Domain>,
+    sender: broadcast::Sender<Bytes>,
+}
+
+impl Capture {
+    /// The header of the pcap file, which the records streamed follow.
+    pub fn header() -> Bytes {
+        Bytes::from_static(&HEADER)
+    }
+
+    /// Subscribe to the records captured from now on, until the upstreams are dropped, e.g. on reloads. Records not received in time are lost.
+    pub fn subscribe(&self) -> broadcast::Receiver<Bytes> {
+        self.sender.subscribe()
+    }
+
+    pub(super) fn tags(&self) -> &HashSet<Label> {
+        &self.tags
+    }
+
+    // Whether the message to or from the upstream is captured.
+    fn wants(&self, tag: &Label, msg: &Message<Bytes>) -> bool {
+        self.sender.receiver_count() > 0
+            && (self.tags.is_empty() || self.tags.contains(tag))
+            && self.domains.as_ref().map_or(true, |domains| {
+                msg.first_question()
+                    .map_or(false, |q| domains.matches(&q.qname().to_bytes()))
+            })
+    }
+
+    // Capture the message sent to the server or received from it.
+    fn record(&self, server: Option<IpAddr>, sent: bool, msg: &Message<Bytes>) {
+        if let Some(packet) = packet(server, sent, msg.as_slice()) {
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let mut record = BytesMut::with_capacity(16 + packet.len());
+            record.put_u32_le(time.as_secs() as u32);
+            record.put_u32_le(time.subsec_micros());
+            record.put_u32_le(packet.len() as u32);
+            record.put_u32_le(packet.len() as u32);
+            record.put_slice(&packet);
+            // Nobody is listening if it fails.
+            let _ = self.sender.send(record.freeze());
+        }
+    }
+}
+
+// The IP packet carrying the message over UDP, or `None` if the message doesn't fit in one.
+fn packet(server: Option<IpAddr>, sent: bool, payload: &[u8]) -> Option<BytesMut> {
+    let server = server.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let udp_len = u16::try_from(8 + payload.len()).ok()?;
+    let mut buf = BytesMut::with_capacity(48 + payload.len());
+    let local = |ipv4| {
+        if ipv4 {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        }
+    };
+    let (src, dst) = if sent {
+        (local(server.is_ipv4()), server)
+    } else {
+        (server, local(server.is_ipv4()))
+    };
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let total = udp_len.checked_add(20)?;
+            buf.put_slice(&[0x45, 0]);
+            buf.put_u16(total);
+            // ID, don't fragment, TTL, and UDP
+            buf.put_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]);
+            buf.put_slice(&src.octets());
+            buf.put_slice(&dst.octets());
+            let sum = buf
+                .chunks(2)
+                .map(|w| u32::from(u16::from_be_bytes([w[0], w[1]])))
+                .sum::<u32>();
+            let sum = (sum & 0xffff) + (sum >> 16);
+            buf[10..12].copy_from_slice(&(!((sum & 0xffff) + (sum >> 16)) as u16).to_be_bytes());
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            buf.put_u32(0x6000_0000);
+            buf.put_u16(udp_len);
+            // UDP, and hop limit
+            buf.put_slice(&[17, 64]);
+            buf.put_slice(&src.octets());
+            buf.put_slice(&dst.octets());
+        }
+        _ => unreachable!(),
+    }
+    let (src_port, dst_port) = if sent {
+        (CLIENT_PORT, 53)
+    } else {
+        (53, CLIENT_PORT)
+    };
+    buf.put_u16(src_port);
+    buf.put_u16(dst_port);
+    buf.put_u16(udp_len);
+    // The checksum is left out.
+    buf.put_u16(0);
+    buf.put_slice(payload);
+    Some(buf)
+}
+
+// Upstream whose traffic is captured.
+pub(super) struct Captured {
+    pub inner: Arc<dyn QHandle>,
+    pub tag: Label,
+    pub capture: Arc<Capture>,
+}
+
+#[async_trait]
+impl QHandle for Captured {
+    async fn query(
+        &self,
+        msg: &Message<Bytes>,
+    ) -> std::result::Result<Message<Bytes>, QHandleError> {
+        if !self.capture.wants(&self.tag, msg) {
+            return self.inner.query(msg).await;
+        }
+        let server = self.inner.addr();
+        self.capture.record(server, true, msg);
+        let resp = self.inner.query(msg).await?;
+        self.capture.record(server, false, &resp);
+        Ok(resp)
+    }
+
+    async fn warmup(&self) -> std::result::Result<(), QHandleError> {
+        self.inner.warmup().await
+    }
+
+    async fn reset(&self) {
+        self.inner.reset().await
+    }
+
+    fn addr(&self) -> Option<IpAddr> {
+        self.inner.addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{packet, HEADER};
+    use std::net::IpAddr;
+
+    #[test]
+    fn packets() {
+        assert_eq!(
+            u32::from_le_bytes(HEADER[..4].try_into().unwrap()),
+            0xa1b2c3d4
+        );
+
+        let server: IpAddr = "192.0.2.1".parse().unwrap();
+        let query = packet(Some(server), true, &[0; 12]).unwrap();
+        assert_eq!(query.len(), 20 + 8 + 12);
+        assert_eq!(&query[16..20], &[192, 0, 2, 1]);
+        // The header sums up to all ones with its checksum.
+        let sum = query[..20]
+            .chunks(2)
+            .map(|w| u32::from(u16::from_be_bytes([w[0], w[1]])))
+            .sum::<u32>();
+        assert_eq!((sum & 0xffff) + (sum >> 16), 0xffff);
+        // Answered from port 53 of the server
+        let resp = packet(Some(server), false, &[0; 12]).unwrap();
+        assert_eq!(&resp[12..16], &[192, 0, 2, 1]);
+        assert_eq!(&resp[20..22], &53_u16.to_be_bytes());
+
+        let server: IpAddr = "2001:db8::1".parse().unwrap();
+        let query = packet(Some(server), true, &[0; 12]).unwrap();
+        assert_eq!(query.len(), 40 + 8 + 12);
+        assert_eq!(query[0] >> 4, 6);
+
+        assert!(packet(None, true, &[0; 65535]).is_none());
+    }
+}
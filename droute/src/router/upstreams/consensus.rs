@@ -0,0 +1,259 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::error::Result;
+use crate::Label;
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{Message, MessageBuilder},
+    rdata::AllRecordData,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fmt::Display};
+
+/// How the responses of a consensus upstream are combined into one.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsensusMode {
+    /// Return the answer agreed by most of the upstreams, the earliest one wins on a tie.
+    Majority,
+    /// Return the union of the answer records from all the upstreams.
+    Merge,
+}
+
+impl Default for ConsensusMode {
+    fn default() -> Self {
+        Self::Majority
+    }
+}
+
+// The parts of a response that upstreams should agree on, regardless of TTLs and the order of records.
+fn fingerprint(msg: &Message<Bytes>) -> Result<(String, Vec<String>)> {
+    let mut records = Vec::new();
+    for item in msg.answer()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+            records.push(record_key(record.owner(), record.rtype(), record.data()));
+        }
+    }
+    records.sort_unstable();
+    Ok((msg.header().rcode().to_string(), records))
+}
+
+fn record_key(owner: impl Display, rtype: impl Display, data: impl Display) -> String {
+    format!("{} {} {}", owner, rtype, data).to_lowercase()
+}
+
+/// Combine the responses, in the order they arrived, from the upstreams of the consensus upstream `tag`. Disagreements are logged.
+/// The AD bit counts only in the responses of the `trusted` upstreams.
+pub(super) fn combine(
+    tag: &Label,
+    mode: ConsensusMode,
+    trusted: &[Label],
+    resps: Vec<(&Label, Message<Bytes>)>,
+) -> Result<(Message<Bytes>, bool)> {
+    // Group the upstreams by what they answered, and whether any trusted one authenticated it
+    let mut groups: Vec<((String, Vec<String>), Vec<&Label>, Message<Bytes>, bool)> = Vec::new();
+    for (t, resp) in resps {
+        let fp = fingerprint(&resp)?;
+        let authenticated = resp.header().ad() && trusted.contains(t);
+        match groups.iter_mut().find(|(g, _, _, _)| g == &fp) {
+            Some((_, tags, first, group_authenticated)) => {
+                tags.push(t);
+                // The response validated by its upstream stands for the group.
+                if !*group_authenticated && authenticated {
+                    *first = resp;
+                    *group_authenticated = true;
+                }
+            }
+            None => groups.push((fp, vec![t], resp, authenticated)),
+        }
+    }
+
+    let disagreed = groups.len() > 1;
+    if disagreed {
+        log::warn!(
+            "upstreams of consensus upstream `{}` disagree: {}",
+            tag,
+            groups
+                .iter()
+                .map(|((rcode, records), tags, _, authenticated)| format!(
+                    "[{}] answered {} with [{}]{}",
+                    tags.iter()
+                        .map(|t| t.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    rcode,
+                    records.join("; "),
+                    if *authenticated {
+                        " (authenticated)"
+                    } else {
+                        ""
+                    }
+                ))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    // Answers authenticated by the trusted upstreams, i.e. with the AD bit set, win over the unauthenticated ones regardless of the mode,
+    // as those upstreams validated them with DNSSEC while a forged answer may well be the majority.
+    // The bit is only as good as the path to the upstream though, as anyone on a plain one can set it, hence the trust.
+    if groups.iter().any(|(_, _, _, authenticated)| *authenticated) {
+        groups.retain(|(_, _, _, authenticated)| *authenticated);
+    }
+
+    let resp = match mode {
+        ConsensusMode::Majority => {
+            let mut best = 0;
+            for (i, (_, tags, _, _)) in groups.iter().enumerate() {
+                if tags.len() > groups[best].1.len() {
+                    best = i;
+                }
+            }
+            groups.swap_remove(best).2
+        }
+        ConsensusMode::Merge => {
+            merge(groups.into_iter().map(|(_, _, resp, _)| resp).collect())?
+        }
+    };
+    Ok((resp, disagreed))
+}
+
+// Merge the answer sections into the first response, with duplicate records removed.
+fn merge(mut resps: Vec<Message<Bytes>>) -> Result<Message<Bytes>> {
+    let first = resps.remove(0);
+    if resps.is_empty() {
+        return Ok(first);
+    }
+
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(first.as_slice().len()))?;
+    *builder.header_mut() = first.header();
+
+    let mut builder = builder.question();
+    for q in first.question() {
+        builder.push(q?)?;
+    }
+
+    let mut seen = HashSet::new();
+    let mut builder = builder.answer();
+    for resp in std::iter::once(&first).chain(resps.iter()) {
+        // Responses with other response codes, e.g. NXDOMAIN against NOERROR, cannot be merged.
+        if resp.header().rcode() != first.header().rcode() {
+            continue;
+        }
+        for item in resp.answer()? {
+            if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+                if seen.insert(record_key(record.owner(), record.rtype(), record.data())) {
+                    builder.push(record)?;
+                }
+            }
+        }
+    }
+
+    let mut builder = builder.authority();
+    for item in first.authority()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+            builder.push(record)?;
+        }
+    }
+
+    let mut builder = builder.additional();
+    for item in first.additional()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+            builder.push(record)?;
+        }
+    }
+
+    Ok(builder.into_message())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{combine, ConsensusMode};
+    use crate::Label;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::A,
+    };
+    use std::str::FromStr;
+
+    fn resp(addr: &str, ad: bool) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&builder.into_message(), Rcode::NoError)
+            .unwrap();
+        builder.header_mut().set_ad(ad);
+        builder
+            .push((&name, 300, A::new(addr.parse().unwrap())))
+            .unwrap();
+        builder.into_message()
+    }
+
+    #[test]
+    fn authenticated() {
+        let tags: Vec<Label> = vec!["a".into(), "b".into(), "c".into()];
+        let (forged, genuine) = ("198.51.100.1", "192.0.2.1");
+
+        // Outnumbered, the answer authenticated by a trusted upstream still wins in either mode.
+        for mode in [ConsensusMode::Majority, ConsensusMode::Merge] {
+            let (r, disagreed) = combine(
+                &"consensus".into(),
+                mode,
+                &tags[2..],
+                vec![
+                    (&tags[0], resp(forged, false)),
+                    (&tags[1], resp(forged, false)),
+                    (&tags[2], resp(genuine, true)),
+                ],
+            )
+            .unwrap();
+            assert!(disagreed);
+            assert!(r.header().ad());
+            assert_eq!(r.header_counts().ancount(), 1);
+        }
+
+        // Otherwise the majority wins, even against the AD bit set by an upstream not trusted.
+        for genuine_ad in [false, true] {
+            let (r, _) = combine(
+                &"consensus".into(),
+                ConsensusMode::Majority,
+                &tags[1..],
+                vec![
+                    (&tags[0], resp(genuine, genuine_ad)),
+                    (&tags[1], resp(forged, false)),
+                    (&tags[2], resp(forged, false)),
+                ],
+            )
+            .unwrap();
+            assert_eq!(
+                r.answer()
+                    .unwrap()
+                    .limit_to::<A>()
+                    .next()
+                    .unwrap()
+                    .unwrap()
+                    .data()
+                    .addr(),
+                forged.parse::<std::net::Ipv4Addr>().unwrap()
+            );
+        }
+    }
+}
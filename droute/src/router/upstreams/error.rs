@@ -14,7 +14,7 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use super::upstream::QHandleError;
-use crate::Label;
+use crate::{errors::ErrorKind, Label};
 use std::{collections::HashSet, fmt::Debug};
 use thiserror::Error;
 
@@ -35,15 +35,89 @@ pub enum UpstreamError {
     #[error("`hybrid` upstream method with tag `{0}` contains no upstreams to race")]
     EmptyHybrid(Label),
 
+    /// None of the upstreams of the consensus upstream answered in time.
+    #[error("no upstream of consensus upstream `{0}` answered in time")]
+    ConsensusTimeout(Label),
+
     /// Error forwarded from `QHandle`.
     #[error(transparent)]
     QHandleError(#[from] QHandleError),
 
+    /// Error from the Redis cache
+    #[cfg(feature = "redis-cache")]
+    #[error(transparent)]
+    RedisError(#[from] redis::RedisError),
+
     /// The buffer is too short
     #[error(transparent)]
     ShortBuf(#[from] domain::base::ShortBuf),
 
+    /// Failed to parse the response
+    #[error(transparent)]
+    ParseError(#[from] domain::base::octets::ParseError),
+
+    /// The CNAME chain being flattened is too long, which is probably a loop.
+    #[error("CNAME chain to `{0}` is too long or loops")]
+    CnameChain(String),
+
+    /// The upstream answered with a response code considered as failure per the retry policy.
+    #[error("upstream `{0}` answered with {1}")]
+    FailedRcode(Label, domain::base::iana::Rcode),
+
+    /// The query is not cached, while the upstream is not queried in offline mode.
+    #[error(
+        "query to upstream `{0}` is not cached, and upstreams are not queried in offline mode"
+    )]
+    Offline(Label),
+
+    /// The query failed on the upstream recently, and the failure is cached.
+    #[error("query to upstream `{0}` failed recently, and the failure is cached")]
+    CachedFailure(Label),
+
+    /// The domain to capture the queries for is invalid.
+    #[error("invalid domain `{0}` to capture")]
+    InvalidDomain(String),
+
+    /// Failed to open the file to capture the upstream traffic to.
+    #[error("failed to open the capture file: {0}")]
+    CaptureError(std::io::Error),
+
+    /// The upstream to hedge, or to hedge to, doesn't exist or doesn't query on its own.
+    #[error("upstream `{0}` to hedge is missing or is not a lone upstream")]
+    HedgeSibling(Label),
+
+    /// The upstream to keep the clients on the same member of is not a hybrid upstream.
+    #[error("upstream `{0}` to keep the clients on the same member of is missing or is not a hybrid upstream")]
+    NotHybrid(Label),
+
     /// Some of the upstreams are unused.
     #[error("Some of the upstreams are not used: {0:?}")]
     UnusedUpstreams(HashSet<Label>),
 }
+
+impl UpstreamError {
+    /// The category of the error
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::MissingTag(_)
+            | Self::HybridRecursion(_)
+            | Self::EmptyHybrid(_)
+            | Self::UnusedUpstreams(_)
+            | Self::InvalidDomain(_)
+            | Self::HedgeSibling(_)
+            | Self::NotHybrid(_) => ErrorKind::Config,
+            Self::CaptureError(_) => ErrorKind::Network,
+            Self::ConsensusTimeout(_) => ErrorKind::Timeout,
+            Self::Offline(_) | Self::CachedFailure(_) => ErrorKind::Policy,
+            Self::QHandleError(e) => e.kind(),
+            #[cfg(feature = "redis-cache")]
+            Self::RedisError(e) if e.is_timeout() => ErrorKind::Timeout,
+            #[cfg(feature = "redis-cache")]
+            Self::RedisError(_) => ErrorKind::Network,
+            Self::ShortBuf(_)
+            | Self::ParseError(_)
+            | Self::FailedRcode(..)
+            | Self::CnameChain(_) => ErrorKind::Protocol,
+        }
+    }
+}
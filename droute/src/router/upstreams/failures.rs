@@ -0,0 +1,115 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Resolution failures are cached per RFC 9520, so that broken upstreams are not hammered with retries for the same name.
+
+use crate::{wire, Label};
+use bytes::Bytes;
+use clru::CLruCache;
+use domain::base::{iana::Rcode, Message};
+use std::{
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// RFC 9520 section 3.2: failures are cached for at least one second and at most five minutes.
+const MIN_TTL: Duration = Duration::from_secs(1);
+const MAX_TTL: Duration = Duration::from_secs(300);
+
+// The failures of the upstreams recently seen, keyed like the response cache by the upstream tag and the query without its ID.
+pub(super) struct Failures {
+    ttl: Duration,
+    lru: Mutex<CLruCache<(Label, Bytes), Instant>>,
+}
+
+impl Failures {
+    pub fn new(ttl: Duration, size: NonZeroUsize) -> Self {
+        Self {
+            ttl: ttl.clamp(MIN_TTL, MAX_TTL),
+            lru: Mutex::new(CLruCache::new(size)),
+        }
+    }
+
+    // Whether the query to the upstream failed within the TTL.
+    pub fn get(&self, tag: &Label, query: &Message<Bytes>) -> bool {
+        let key = (tag.clone(), wire::cache_key(query));
+        let mut lru = self.lru.lock().unwrap();
+        match lru.get(&key) {
+            Some(at) if at.elapsed() < self.ttl => true,
+            Some(_) => {
+                lru.pop(&key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    // Record the outcome of the query to the upstream if it failed, i.e. errored or answered with SERVFAIL.
+    pub fn put<E>(&self, tag: &Label, query: &Message<Bytes>, r: &Result<Message<Bytes>, E>) {
+        let failed = match r {
+            Ok(resp) => resp.header().rcode() == Rcode::ServFail,
+            Err(_) => true,
+        };
+        if failed {
+            self.lru
+                .lock()
+                .unwrap()
+                .put((tag.clone(), wire::cache_key(query)), Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Failures;
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype};
+    use std::{num::NonZeroUsize, str::FromStr, time::Duration};
+
+    fn query(id: u16) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        builder.header_mut().set_id(id);
+        let mut builder = builder.question();
+        builder
+            .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        builder.into_message()
+    }
+
+    #[test]
+    fn failures() {
+        let failures = Failures::new(Duration::from_secs(5), NonZeroUsize::new(8).unwrap());
+        let (tag, other) = ("broken".into(), "other".into());
+        let resp = |rcode| {
+            Ok::<_, ()>(
+                MessageBuilder::from_target(BytesMut::new())
+                    .unwrap()
+                    .start_answer(&query(1), rcode)
+                    .unwrap()
+                    .into_message(),
+            )
+        };
+
+        failures.put(&tag, &query(1), &resp(Rcode::NoError));
+        assert!(!failures.get(&tag, &query(1)));
+        failures.put(&tag, &query(1), &resp(Rcode::ServFail));
+        // Queries are told apart regardless of their IDs.
+        assert!(failures.get(&tag, &query(2)));
+        assert!(!failures.get(&other, &query(1)));
+        failures.put(&other, &query(1), &Err::<Message<Bytes>, _>(()));
+        assert!(failures.get(&other, &query(1)));
+    }
+}
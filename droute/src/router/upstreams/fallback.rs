@@ -0,0 +1,78 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Fallback per query, for scripts to resend the queries the upstreams chosen failed to answer properly.
+
+use super::{error::Result, retry::RetryPolicy, CacheMode, Upstreams};
+use crate::{router::slow_query::QueryTrace, Label};
+use bytes::Bytes;
+use domain::{
+    base::Message,
+    rdata::{Aaaa, A},
+};
+use std::net::IpAddr;
+
+// The first address in the answer section which is rejected
+fn rejected(resp: &Message<Bytes>, reject: &impl Fn(IpAddr) -> bool) -> Result<Option<IpAddr>> {
+    for record in resp.answer()?.limit_to::<A>() {
+        let ip = IpAddr::from(record?.data().addr());
+        if reject(ip) {
+            return Ok(Some(ip));
+        }
+    }
+    for record in resp.answer()?.limit_to::<Aaaa>() {
+        let ip = IpAddr::from(record?.data().addr());
+        if reject(ip) {
+            return Ok(Some(ip));
+        }
+    }
+    Ok(None)
+}
+
+impl Upstreams {
+    /// Send the query to the upstream, and resend it to the fallback upstream if the upstream failed, answered with a response code considered as failure per the retry policy
+    /// (SERVFAIL and REFUSED by default), or answered with an address `reject` returns `true` for, e.g. one known to be forged by DNS poisoning.
+    pub async fn send_fallback(
+        &self,
+        tag: &Label,
+        fallback: &Label,
+        cache_mode: &CacheMode,
+        msg: &Message<Bytes>,
+        reject: impl Fn(IpAddr) -> bool,
+    ) -> Result<Message<Bytes>> {
+        let reason = match self.send(tag, cache_mode, msg).await {
+            Ok(resp) => {
+                let failed = match &self.retry {
+                    Some(retry) => retry.failed(tag, &resp),
+                    None => RetryPolicy::default().failed(tag, &resp),
+                };
+                match (failed, rejected(&resp, &reject)?) {
+                    (Some(rcode), _) => format!("answered with {}", rcode),
+                    (None, Some(ip)) => format!("answered with rejected address {}", ip),
+                    (None, None) => return Ok(resp),
+                }
+            }
+            Err(e) => format!("failed: {}", e),
+        };
+        log::info!(
+            "upstream `{}` {}, resending to fallback upstream `{}`",
+            tag,
+            reason,
+            fallback
+        );
+        QueryTrace::note(|| format!("upstream {} {}, falling back to {}", tag, reason, fallback));
+        self.send(fallback, cache_mode, msg).await
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// CNAME flattening for clients that cannot follow CNAME chains themselves.
+
+use super::{
+    error::{Result, UpstreamError},
+    CacheMode, Upstreams,
+};
+use crate::{Label, MAX_LEN};
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{
+        iana::Rcode, name::ToDname, Dname, Message, MessageBuilder, ParsedDname, Record, Rtype,
+    },
+    rdata::AllRecordData,
+};
+
+// Maximum number of CNAME records followed, beyond which the chain is taken as a loop
+const MAX_CNAMES: usize = 16;
+
+// Follow the CNAME chain in the answer section from `name`, returning the end of it, the least TTL along it, and the number of CNAME records followed.
+fn follow(resp: &Message<Bytes>, mut name: Dname<Bytes>) -> Result<(Dname<Bytes>, u32, usize)> {
+    let (mut ttl, mut hops) = (u32::MAX, 0);
+    'chain: loop {
+        for item in resp.answer()? {
+            if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+                if let AllRecordData::Cname(cname) = record.data() {
+                    if record.owner().name_eq(&name) {
+                        name = cname.cname().to_bytes();
+                        ttl = ttl.min(record.ttl());
+                        hops += 1;
+                        if hops > MAX_CNAMES {
+                            return Err(UpstreamError::CnameChain(name.to_string()));
+                        }
+                        continue 'chain;
+                    }
+                }
+            }
+        }
+        return Ok((name, ttl, hops));
+    }
+}
+
+// Whether the answer section contains records of the type under the name
+fn answered(resp: &Message<Bytes>, name: &Dname<Bytes>, qtype: Rtype) -> Result<bool> {
+    for item in resp.answer()? {
+        let item = item?;
+        if item.rtype() == qtype && item.owner().name_eq(name) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// The query for the name, with the same header and additional section (hence the OPT record) as the original one
+fn requery(msg: &Message<Bytes>, name: &Dname<Bytes>, qtype: Rtype) -> Result<Message<Bytes>> {
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?;
+    *builder.header_mut() = msg.header();
+    let mut builder = builder.question();
+    builder.push((name, qtype))?;
+    let mut builder = builder.additional();
+    for item in msg.additional()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, ParsedDname<_>>>()? {
+            builder.push(record)?;
+        }
+    }
+    Ok(builder.into_message())
+}
+
+// The response to the query with the records of the type at the end of the chain moved under the name queried, whose TTLs are capped by the chain's.
+// Authority records are kept for negative responses, e.g. the SOA record for negative caching.
+fn flattened(
+    msg: &Message<Bytes>,
+    resp: &Message<Bytes>,
+    end: &Dname<Bytes>,
+    ttl: u32,
+) -> Result<Message<Bytes>> {
+    let question = msg.sole_question()?;
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+        .start_answer(msg, resp.header().rcode())?;
+    builder.header_mut().set_ra(resp.header().ra());
+    let mut answers = 0;
+    for item in resp.answer()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, ParsedDname<_>>>()? {
+            if record.rtype() == question.qtype() && record.owner().name_eq(end) {
+                builder.push(Record::new(
+                    question.qname(),
+                    record.class(),
+                    record.ttl().min(ttl),
+                    record.data().clone(),
+                ))?;
+                answers += 1;
+            }
+        }
+    }
+    let mut builder = builder.authority();
+    if answers == 0 {
+        for item in resp.authority()? {
+            if let Some(record) = item?.into_record::<AllRecordData<_, ParsedDname<_>>>()? {
+                builder.push(record)?;
+            }
+        }
+    }
+    Ok(builder.into_message())
+}
+
+impl Upstreams {
+    /// Send the query to the upstream, and flatten the CNAME chain in the response: the chain is followed, with further queries to the same upstream if it ends without
+    /// records of the type queried, and only those records are returned, under the name queried. Queries of type CNAME and responses without CNAME records are left as they are.
+    pub async fn flatten_cname(
+        &self,
+        tag: &Label,
+        cache_mode: &CacheMode,
+        msg: &Message<Bytes>,
+    ) -> Result<Message<Bytes>> {
+        let question = msg.sole_question()?;
+        let qtype = question.qtype();
+        let mut resp = self.send(tag, cache_mode, msg).await?;
+        if matches!(qtype, Rtype::Cname | Rtype::Any) {
+            return Ok(resp);
+        }
+
+        let (mut name, mut ttl, mut hops) = (question.qname().to_bytes(), u32::MAX, 0);
+        loop {
+            let (end, chain_ttl, followed) = follow(&resp, name)?;
+            ttl = ttl.min(chain_ttl);
+            hops += followed;
+            if hops == 0 {
+                return Ok(resp);
+            }
+            if followed == 0
+                || resp.header().rcode() != Rcode::NoError
+                || answered(&resp, &end, qtype)?
+            {
+                return flattened(msg, &resp, &end, ttl);
+            }
+            if hops > MAX_CNAMES {
+                return Err(UpstreamError::CnameChain(end.to_string()));
+            }
+            resp = self
+                .send(tag, cache_mode, &requery(msg, &end, qtype)?)
+                .await?;
+            name = end;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{answered, follow, requery};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, name::ToDname, Dname, Message, MessageBuilder, Rtype},
+        rdata::{Cname, A},
+    };
+    use std::str::FromStr;
+
+    fn name(s: &str) -> Dname<Bytes> {
+        Dname::from_str(s).unwrap()
+    }
+
+    // Response for `www.example.com` with the CNAME chain given, and an address under the last name if `resolved`
+    fn response(chain: &[(&str, &str, u32)], resolved: bool) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((name("www.example.com"), Rtype::A)).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&builder.into_message(), Rcode::NoError)
+            .unwrap();
+        for (owner, target, ttl) in chain {
+            builder
+                .push((name(owner), *ttl, Cname::new(name(target))))
+                .unwrap();
+        }
+        if resolved {
+            builder
+                .push((
+                    name(chain.last().unwrap().1),
+                    300,
+                    A::from_octets(192, 0, 2, 1),
+                ))
+                .unwrap();
+        }
+        builder.into_message()
+    }
+
+    #[test]
+    fn chain() {
+        let resp = response(
+            &[
+                ("www.example.com", "cdn.example.net", 600),
+                ("cdn.example.net", "edge.example.org", 60),
+            ],
+            true,
+        );
+        let (end, ttl, hops) = follow(&resp, name("WWW.example.com")).unwrap();
+        assert_eq!(end, name("edge.example.org"));
+        assert_eq!((ttl, hops), (60, 2));
+        assert!(answered(&resp, &end, Rtype::A).unwrap());
+        assert!(!answered(&resp, &end, Rtype::Aaaa).unwrap());
+
+        let resp = response(&[("www.example.com", "cdn.example.net", 600)], false);
+        let (end, ..) = follow(&resp, name("www.example.com")).unwrap();
+        assert!(!answered(&resp, &end, Rtype::A).unwrap());
+        let query = requery(&resp, &end, Rtype::A).unwrap();
+        assert_eq!(
+            query.sole_question().unwrap().qname().to_bytes(),
+            name("cdn.example.net")
+        );
+    }
+
+    #[test]
+    fn chain_loop() {
+        let resp = response(
+            &[
+                ("www.example.com", "cdn.example.net", 600),
+                ("cdn.example.net", "www.example.com", 600),
+            ],
+            false,
+        );
+        assert!(follow(&resp, name("www.example.com")).is_err());
+    }
+}
@@ -0,0 +1,244 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Hedged requests: once a lone upstream is slower than usual on a query, the same query is sent a second time, and the first answer wins.
+// This cuts the tail latency at the cost of a few percents more queries, instead of racing every query as hybrid upstreams do.
+
+use super::{
+    super::{slow_query::QueryTrace, stats::UpstreamCounters},
+    upstream::{QHandle, QHandleError},
+};
+use crate::{runtime::timeout, Label};
+use async_trait::async_trait;
+use bytes::Bytes;
+use domain::base::Message;
+use futures::future::select_ok;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// Number of the latest latencies the delay is derived from
+const WINDOW: usize = 128;
+
+// Number of latencies needed before the delay is derived from them instead of `max_delay`
+const MIN_SAMPLES: usize = 16;
+
+const fn default_percentile() -> u8 {
+    95
+}
+
+const fn default_min_delay() -> u64 {
+    20
+}
+
+const fn default_max_delay() -> u64 {
+    1000
+}
+
+/// Policy to hedge the queries to the upstreams querying on their own, i.e. not hybrid, consensus, or the like.
+/// If an upstream hasn't answered within the percentile given of its recent latencies, the query is sent again, and the first answer is taken.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HedgePolicy {
+    /// Percentile of the latencies after which the query is hedged
+    #[serde(default = "default_percentile")]
+    pub percentile: u8,
+    /// The least time in milliseconds to wait before hedging
+    #[serde(default = "default_min_delay")]
+    pub min_delay: u64,
+    /// The most time in milliseconds to wait before hedging, which is also the delay until enough latencies are seen
+    #[serde(default = "default_max_delay")]
+    pub max_delay: u64,
+    /// The upstream to send the second query to by the tag of the upstream hedged, instead of the same one
+    #[serde(default)]
+    pub siblings: HashMap<Label, Label>,
+}
+
+impl Default for HedgePolicy {
+    fn default() -> Self {
+        Self {
+            percentile: default_percentile(),
+            min_delay: default_min_delay(),
+            max_delay: default_max_delay(),
+            siblings: HashMap::new(),
+        }
+    }
+}
+
+impl HedgePolicy {
+    fn min_delay(&self) -> Duration {
+        Duration::from_millis(self.min_delay)
+    }
+
+    fn max_delay(&self) -> Duration {
+        Duration::from_millis(self.max_delay.max(self.min_delay))
+    }
+}
+
+// The latest latencies of an upstream, and the delay derived from them.
+struct Latencies {
+    samples: Mutex<(Vec<Duration>, usize)>,
+    // In microseconds, zero until enough samples are seen
+    delay: AtomicU64,
+}
+
+impl Latencies {
+    fn new() -> Self {
+        Self {
+            samples: Mutex::new((Vec::with_capacity(WINDOW), 0)),
+            delay: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, latency: Duration, percentile: u8) {
+        let mut guard = self.samples.lock().unwrap();
+        let (samples, next) = &mut *guard;
+        if samples.len() < WINDOW {
+            samples.push(latency);
+        } else {
+            samples[*next] = latency;
+        }
+        *next = (*next + 1) % WINDOW;
+        if samples.len() >= MIN_SAMPLES {
+            let mut sorted = samples.clone();
+            sorted.sort_unstable();
+            let rank = (sorted.len() * usize::from(percentile.min(100))).saturating_sub(1) / 100;
+            self.delay
+                .store(sorted[rank].as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn delay(&self, policy: &HedgePolicy) -> Duration {
+        match self.delay.load(Ordering::Relaxed) {
+            0 => policy.max_delay(),
+            d => Duration::from_micros(d).clamp(policy.min_delay(), policy.max_delay()),
+        }
+    }
+}
+
+// Upstream whose queries are hedged.
+pub struct Hedged {
+    pub inner: Arc<dyn QHandle>,
+    // The upstream the second query goes to, the same one if none
+    pub sibling: Option<Arc<dyn QHandle>>,
+    pub tag: Label,
+    pub policy: Arc<HedgePolicy>,
+    pub counters: Arc<HashMap<Label, UpstreamCounters>>,
+    latencies: Latencies,
+}
+
+impl Hedged {
+    pub fn new(
+        inner: Arc<dyn QHandle>,
+        sibling: Option<Arc<dyn QHandle>>,
+        tag: Label,
+        policy: Arc<HedgePolicy>,
+        counters: Arc<HashMap<Label, UpstreamCounters>>,
+    ) -> Self {
+        Self {
+            inner,
+            sibling,
+            tag,
+            policy,
+            counters,
+            latencies: Latencies::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl QHandle for Hedged {
+    async fn query(
+        &self,
+        msg: &Message<Bytes>,
+    ) -> std::result::Result<Message<Bytes>, QHandleError> {
+        let start = Instant::now();
+        let delay = self.latencies.delay(&self.policy);
+        let mut first = self.inner.query(msg);
+        let resp = match timeout(delay, &mut first).await {
+            Ok(resp) => resp,
+            Err(_) => {
+                self.counters[&self.tag].hedges.inc();
+                QueryTrace::note(|| {
+                    format!(
+                        "hedged upstream `{}` after {}ms",
+                        self.tag,
+                        delay.as_millis()
+                    )
+                });
+                let second = self.sibling.as_ref().unwrap_or(&self.inner).query(msg);
+                // The slower one is dropped, and so is the other if both failed.
+                select_ok([first, second]).await.map(|(resp, _)| resp)
+            }
+        };
+        if resp.is_ok() {
+            self.latencies
+                .record(start.elapsed(), self.policy.percentile);
+        }
+        resp
+    }
+
+    async fn warmup(&self) -> std::result::Result<(), QHandleError> {
+        self.inner.warmup().await
+    }
+
+    async fn reset(&self) {
+        self.inner.reset().await
+    }
+
+    fn addr(&self) -> Option<IpAddr> {
+        self.inner.addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HedgePolicy, Latencies, MIN_SAMPLES, WINDOW};
+    use std::time::Duration;
+
+    #[test]
+    fn delays() {
+        let policy = HedgePolicy::default();
+        let latencies = Latencies::new();
+        // Not enough samples yet
+        for _ in 0..MIN_SAMPLES - 1 {
+            latencies.record(Duration::from_millis(50), 95);
+        }
+        assert_eq!(latencies.delay(&policy), Duration::from_millis(1000));
+
+        // 1ms to 128ms, whose 95th percentile is 122ms.
+        for i in 1..=WINDOW as u64 {
+            latencies.record(Duration::from_millis(i), 95);
+        }
+        assert_eq!(latencies.delay(&policy), Duration::from_millis(122));
+
+        // Clamped to the bounds
+        for _ in 0..WINDOW {
+            latencies.record(Duration::from_millis(1), 95);
+        }
+        assert_eq!(latencies.delay(&policy), Duration::from_millis(20));
+        for _ in 0..WINDOW {
+            latencies.record(Duration::from_secs(5), 95);
+        }
+        assert_eq!(latencies.delay(&policy), Duration::from_millis(1000));
+    }
+}
@@ -16,21 +16,79 @@
 //! `Upstream` wraps around the `QHandle` to manage cache-related business. It is method (UDP, TCP, Zone File, etc.) agnostic.
 //! `Upstreams` is a set of `Upstream` that manages `Hybrid` querying types and more.
 
+mod affinity;
 /// A module containing the builders for Upstreams, Upstream, and each client builder.
 pub mod builder;
+mod capture;
+mod consensus;
 /// Module which contains the error type for the `upstreams` section.
 pub mod error;
+mod failures;
+mod fallback;
+mod flatten;
+mod hedge;
+mod nsid;
+mod nxdomain;
+mod ranking;
+mod retry;
 mod upstream;
 
-use self::error::{Result, UpstreamError};
-use crate::{cache::RespCache, Label, Validatable, ValidateCell};
-use bytes::{Bytes, BytesMut};
-use domain::base::Message;
-use futures::future::{select_ok, BoxFuture, FutureExt};
+pub(super) use self::affinity::with_client;
+use self::{
+    affinity::AffinityPolicy,
+    capture::Captured,
+    consensus::ConsensusMode,
+    error::{Result, UpstreamError},
+    failures::Failures,
+    hedge::{HedgePolicy, Hedged},
+    nsid::Identified,
+    nxdomain::Nxdomains,
+    retry::RetryPolicy,
+};
+pub use self::{
+    capture::Capture,
+    ranking::{Measurement, Ranking, RankingPolicy},
+};
+use super::{
+    script::utils::{has_ech, is_svcb},
+    slow_query::QueryTrace,
+    stats::{CacheStats, UpstreamCounters, UpstreamStats},
+};
+use crate::{
+    cache::{
+        Cache, MemoryCache,
+        RecordStatus::{Alive, Expired},
+        RespCache,
+    },
+    privacy,
+    runtime::timeout,
+    wire, Label, Validatable, ValidateCell,
+};
+use bytes::Bytes;
+use domain::base::{iana::Rcode, Message, Rtype};
+use futures::{
+    future::{ready, select_ok, BoxFuture, FutureExt, TryFutureExt},
+    stream::{FuturesUnordered, StreamExt},
+};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, num::NonZeroUsize, str::FromStr};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    num::NonZeroUsize,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{field, Instrument, Span};
 pub use upstream::*;
 
+// Time to wait for responses with ECH configs after the first response without them.
+const ECH_GRACE: Duration = Duration::from_millis(200);
+
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
 #[serde(rename_all = "lowercase")]
@@ -73,6 +131,22 @@ pub struct Upstreams {
     upstreams: HashMap<Label, Upstream>,
     // All the responses are cached together, however, they are seperately tagged, so there should be no contamination in place.
     cache: RespCache,
+    counters: Arc<HashMap<Label, UpstreamCounters>>,
+    retry: Option<Arc<RetryPolicy>>,
+    // Members pruned from each hybrid upstream per the ranking
+    pruned: Arc<RwLock<HashMap<Label, HashSet<Label>>>>,
+    // Whether queries are answered out of the cache alone
+    offline: Arc<AtomicBool>,
+    // Whether the host has connectivity over IPv4 and IPv6 respectively
+    connectivity: Arc<(AtomicBool, AtomicBool)>,
+    // Failures recently seen, if they are cached
+    failures: Option<Arc<Failures>>,
+    // Names answered NXDOMAIN, if the names below them are answered out of the cache
+    nxdomains: Option<Arc<Nxdomains>>,
+    // Capture of the traffic to the upstreams
+    capture: Option<Arc<Capture>>,
+    // Hybrid upstreams keeping the clients on the same member
+    affinity: Option<Arc<AffinityPolicy>>,
 }
 
 impl Validatable for Upstreams {
@@ -95,19 +169,301 @@ impl Upstreams {
     /// Create a new `Upstreams` by passing a bunch of `Upstream`s, with their respective labels, and cache capacity.
     pub fn new(upstreams: HashMap<Label, Upstream>, cache_size: NonZeroUsize) -> Result<Self> {
         let u = Self {
+            counters: Arc::new(
+                upstreams
+                    .keys()
+                    .map(|tag| (tag.clone(), UpstreamCounters::default()))
+                    .collect(),
+            ),
             upstreams,
-            cache: RespCache::new(cache_size),
+            cache: RespCache::new(MemoryCache::new(cache_size)),
+            retry: None,
+            pruned: Arc::new(RwLock::new(HashMap::new())),
+            offline: Arc::new(AtomicBool::new(false)),
+            connectivity: Arc::new((AtomicBool::new(true), AtomicBool::new(true))),
+            failures: None,
+            nxdomains: None,
+            capture: None,
+            affinity: None,
         };
         // Validate on the assumption that every upstream is gonna be used.
         u.validate(Some(&u.tags()))?;
         Ok(u)
     }
 
+    /// Retry queries answered with failure response codes per the policy given.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Result<Self> {
+        if let Some(fallback) = &retry.fallback {
+            if !self.upstreams.contains_key(fallback) {
+                return Err(UpstreamError::MissingTag(fallback.clone()));
+            }
+        }
+        if let Some(tag) = retry
+            .upstreams
+            .keys()
+            .find(|t| !self.upstreams.contains_key(*t))
+        {
+            return Err(UpstreamError::MissingTag(tag.clone()));
+        }
+        self.retry = Some(Arc::new(retry));
+        Ok(self)
+    }
+
+    /// Use the cache given instead of the in-memory one of the size given on creation.
+    pub fn with_cache(mut self, cache: impl Cache + 'static) -> Self {
+        self.cache = RespCache::new(cache);
+        self
+    }
+
+    /// Cache the failures of the upstreams, i.e. errors and SERVFAIL responses, for `ttl` (between one second and five minutes) per RFC 9520.
+    /// Queries failed on an upstream within the TTL fail right away instead of being sent to it again, unless the cache is disabled for them.
+    pub fn with_servfail_cache(mut self, ttl: Duration, size: NonZeroUsize) -> Self {
+        self.failures = Some(Arc::new(Failures::new(ttl, size)));
+        self
+    }
+
+    /// Answer the queries for the names below the ones answered NXDOMAIN with NXDOMAIN out of the cache per RFC 8020, instead of sending them to the same upstream, for the negative TTL.
+    /// Up to `size` names are kept. Queries with the cache disabled are sent regardless.
+    pub fn with_aggressive_nxdomain(mut self, size: NonZeroUsize) -> Self {
+        self.nxdomains = Some(Arc::new(Nxdomains::new(size)));
+        self
+    }
+
+    /// Capture the queries sent to the upstreams querying on their own and the responses received, per the capture given.
+    pub fn with_capture(mut self, capture: Capture) -> Result<Self> {
+        if let Some(tag) = capture
+            .tags()
+            .iter()
+            .find(|t| !self.upstreams.contains_key(*t))
+        {
+            return Err(UpstreamError::MissingTag(tag.clone()));
+        }
+        let capture = Arc::new(capture);
+        for (tag, u) in self.upstreams.iter_mut() {
+            if let Upstream::Others(inner) = u {
+                *inner = Arc::new(Captured {
+                    inner: inner.clone(),
+                    tag: tag.clone(),
+                    capture: capture.clone(),
+                });
+            }
+        }
+        self.capture = Some(capture);
+        Ok(self)
+    }
+
+    /// Hedge the queries to the upstreams querying on their own per the policy given, sending them a second time if the upstreams are slower than usual.
+    pub fn with_hedging(mut self, hedge: HedgePolicy) -> Result<Self> {
+        let mut siblings = HashMap::new();
+        for (tag, sibling) in &hedge.siblings {
+            for t in [tag, sibling] {
+                if !matches!(self.upstreams.get(t), Some(Upstream::Others(_))) {
+                    return Err(UpstreamError::HedgeSibling(t.clone()));
+                }
+            }
+            if let Some(Upstream::Others(inner)) = self.upstreams.get(sibling) {
+                siblings.insert(tag.clone(), inner.clone());
+            }
+        }
+        let hedge = Arc::new(hedge);
+        for (tag, u) in self.upstreams.iter_mut() {
+            if let Upstream::Others(inner) = u {
+                *inner = Arc::new(Hedged::new(
+                    inner.clone(),
+                    siblings.remove(tag),
+                    tag.clone(),
+                    hedge.clone(),
+                    self.counters.clone(),
+                ));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Ask the upstreams querying on their own for their NSID (RFC 5001) on every query with EDNS, and keep the one answered last.
+    /// NSIDs are stripped from the responses unless the clients asked for them.
+    pub fn with_nsid(mut self) -> Self {
+        for (tag, u) in self.upstreams.iter_mut() {
+            if let Upstream::Others(inner) = u {
+                *inner = Arc::new(Identified {
+                    inner: inner.clone(),
+                    tag: tag.clone(),
+                    counters: self.counters.clone(),
+                });
+            }
+        }
+        self
+    }
+
+    /// Send the queries from the same client subnet to the same member of the hybrid upstreams per the policy given, instead of racing the members.
+    pub fn with_affinity(mut self, affinity: AffinityPolicy) -> Result<Self> {
+        for tag in &affinity.hybrids {
+            if !matches!(self.upstreams.get(tag), Some(Upstream::Hybrid(_))) {
+                return Err(UpstreamError::NotHybrid(tag.clone()));
+            }
+        }
+        self.affinity = Some(Arc::new(affinity));
+        Ok(self)
+    }
+
+    /// The capture of the traffic to the upstreams, if any.
+    pub fn capture(&self) -> Option<&Arc<Capture>> {
+        self.capture.as_ref()
+    }
+
+    /// Answer the queries out of the cache alone, including the expired responses, without touching the network, e.g. on flights or behind captive portals.
+    /// Queries not cached fail in offline mode.
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.store(offline, Ordering::Relaxed);
+    }
+
+    /// Whether the queries are answered out of the cache alone.
+    pub fn offline(&self) -> bool {
+        self.offline.load(Ordering::Relaxed)
+    }
+
+    /// Drop the connections to all the upstreams, which are likely stale once the network changed, e.g. after roaming between Wi-Fi networks.
+    /// They are established again on the next queries, or right away for the upstreams asked to warm up.
+    pub async fn reconnect(&self) {
+        self.upstreams
+            .values()
+            .filter_map(|u| match u {
+                Upstream::Others(inner) => Some(inner.reset()),
+                _ => None,
+            })
+            .collect::<FuturesUnordered<_>>()
+            .collect::<Vec<_>>()
+            .await;
+    }
+
+    /// Remove all the responses cached.
+    pub async fn purge_cache(&self) {
+        self.cache.purge().await
+    }
+
+    /// Statistics of each upstream by tag.
+    pub fn stats(&self) -> HashMap<Label, UpstreamStats> {
+        self.counters
+            .iter()
+            .map(|(tag, c)| (tag.clone(), c.snapshot()))
+            .collect()
+    }
+
+    /// The NSID each upstream answered last, if they are asked for per `with_nsid`.
+    pub fn nsids(&self) -> HashMap<Label, String> {
+        self.counters
+            .iter()
+            .filter_map(|(tag, c)| Some((tag.clone(), c.nsid.lock().unwrap().clone()?)))
+            .collect()
+    }
+
+    /// Statistics of the response cache shared by all the upstreams.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
     /// Return the tags of all the upstreams.
     pub fn tags(&self) -> Vec<Label> {
         self.upstreams.keys().cloned().collect()
     }
 
+    /// Return the members of each hybrid upstream.
+    pub fn hybrids(&self) -> HashMap<Label, Vec<Label>> {
+        self.upstreams
+            .iter()
+            .filter_map(|(tag, u)| match u {
+                Upstream::Hybrid(v) => Some((tag.clone(), v.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Probe the upstream per the policy, bypassing the cache. Returns `None` if the upstream doesn't query on its own, e.g. a hybrid one.
+    pub async fn probe(&self, tag: &Label, policy: &RankingPolicy) -> Option<Measurement> {
+        if self.offline() {
+            return None;
+        }
+        let inner = match self.upstreams.get(tag)? {
+            Upstream::Others(inner) => inner,
+            _ => return None,
+        };
+        let probe = policy.probe()?;
+        let probes = policy.probes.max(1);
+        let (mut latencies, mut dnssec, mut edns) = (Vec::new(), false, false);
+        for _ in 0..probes {
+            let start = Instant::now();
+            match inner.query(&probe).await {
+                Ok(resp) if RankingPolicy::answered(&resp) => {
+                    latencies.push(start.elapsed().as_millis() as u64);
+                    edns |= resp.opt().is_some();
+                    dnssec |= resp.answer().map_or(false, |mut answer| {
+                        answer.any(|r| r.map_or(false, |r| r.rtype() == Rtype::Rrsig))
+                    });
+                }
+                Ok(_) => (),
+                Err(e) => log::debug!("probe to upstream `{}` failed: {}", tag, e),
+            }
+        }
+        latencies.sort_unstable();
+        Some(Measurement {
+            latency: latencies.get(latencies.len() / 2).copied(),
+            reliability: latencies.len() as f64 / probes as f64,
+            dnssec,
+            edns,
+        })
+    }
+
+    /// Set whether the host has working connectivity over IPv4 and IPv6. Members of `hybrid`, `consensus` and `ech` upstreams connecting over a family unavailable are skipped,
+    /// unless every member would be, instead of being waited for until they time out on single-stack networks. Both are assumed available until set otherwise.
+    pub fn set_connectivity(&self, ipv4: bool, ipv6: bool) {
+        self.connectivity.0.store(ipv4, Ordering::Relaxed);
+        self.connectivity.1.store(ipv6, Ordering::Relaxed);
+    }
+
+    /// Whether the host has working connectivity over IPv4 and IPv6 respectively.
+    pub fn connectivity(&self) -> (bool, bool) {
+        (
+            self.connectivity.0.load(Ordering::Relaxed),
+            self.connectivity.1.load(Ordering::Relaxed),
+        )
+    }
+
+    // Members not connecting over a family unavailable, or all of them if none is.
+    fn reachable<'a>(&self, members: &'a [Label]) -> Cow<'a, [Label]> {
+        let (ipv4, ipv6) = self.connectivity();
+        if ipv4 && ipv6 {
+            return Cow::Borrowed(members);
+        }
+        let reachable: Vec<Label> = members
+            .iter()
+            .filter(|tag| match self.upstreams.get(*tag) {
+                Some(Upstream::Others(inner)) => match inner.addr() {
+                    Some(IpAddr::V4(_)) => ipv4,
+                    Some(IpAddr::V6(_)) => ipv6,
+                    None => true,
+                },
+                _ => true,
+            })
+            .cloned()
+            .collect();
+        if reachable.is_empty() || reachable.len() == members.len() {
+            Cow::Borrowed(members)
+        } else {
+            Cow::Owned(reachable)
+        }
+    }
+
+    /// Leave the members given out of the hybrid upstream, in place of the ones pruned before.
+    pub fn prune(&self, tag: &Label, members: impl IntoIterator<Item = Label>) {
+        let members: HashSet<_> = members.into_iter().collect();
+        let mut pruned = self.pruned.write().unwrap();
+        if members.is_empty() {
+            pruned.remove(tag);
+        } else {
+            pruned.insert(tag.clone(), members);
+        }
+    }
+
     // Check any upstream types
     fn traverse(
         bucket: &mut HashMap<&Label, (ValidateCell, &Upstream)>,
@@ -144,9 +500,296 @@ impl Upstreams {
         Ok(())
     }
 
-    // Write out in this way to allow recursion for async functions
     /// Send the query to a tagged upstream and a given cache mode.
-    pub fn send<'a>(
+    pub async fn send(
+        &self,
+        tag: &Label,
+        cache_mode: &CacheMode,
+        msg: &Message<Bytes>,
+    ) -> Result<Message<Bytes>> {
+        let r = self.send_inner(tag, cache_mode, msg).await;
+        let (retry, fallback) = match &self.retry {
+            Some(retry) => match &retry.fallback {
+                Some(fallback) if fallback != tag => (retry, fallback),
+                _ => return r,
+            },
+            None => return r,
+        };
+
+        match &r {
+            Ok(resp) if retry.failed(tag, resp).is_none() => r,
+            _ => {
+                log::warn!(
+                    "query to upstream `{}` failed, retrying with fallback upstream `{}`",
+                    tag,
+                    fallback
+                );
+                QueryTrace::note(|| format!("retrying with fallback upstream {}", fallback));
+                self.send_inner(fallback, cache_mode, msg).await
+            }
+        }
+    }
+
+    // Responses with failure response codes are treated as errors among hybrid and consensus upstreams.
+    fn check_rcode(&self, tag: &Label, resp: Message<Bytes>) -> Result<Message<Bytes>> {
+        match self.retry.as_ref().and_then(|p| p.failed(tag, &resp)) {
+            Some(rcode) => Err(UpstreamError::FailedRcode(tag.clone(), rcode)),
+            None => Ok(resp),
+        }
+    }
+
+    // Race the upstreams of a hybrid upstream, the first successful response wins.
+    async fn race<'a>(
+        &'a self,
+        tag: &Label,
+        tags: impl Iterator<Item = &'a Label>,
+        cache_mode: &'a CacheMode,
+        msg: &'a Message<Bytes>,
+    ) -> Result<Message<Bytes>> {
+        // Hybrid will never call `u.send_internal()`
+        let v = tags.map(|t| {
+            self.send_inner(t, cache_mode, msg)
+                .and_then(move |r| ready(self.check_rcode(t, r).map(|r| (r, t))))
+        });
+        select_ok(v).await.map(|((r, winner), rest)| {
+            // The attempts still in flight are cancelled right away rather than left to time out.
+            drop(rest);
+            Span::current().record("winner", &field::display(winner));
+            QueryTrace::note(|| format!("hybrid upstream {} won by {}", tag, winner));
+            r
+        })
+    }
+
+    // Send the query to the member of a hybrid upstream the client sticks to, and race the rest of the members only if it failed.
+    async fn stick<'a>(
+        &'a self,
+        tag: &Label,
+        member: &'a Label,
+        members: &[&'a Label],
+        cache_mode: &'a CacheMode,
+        msg: &'a Message<Bytes>,
+    ) -> Result<Message<Bytes>> {
+        QueryTrace::note(|| format!("hybrid upstream {} sticks to {}", tag, member));
+        match self
+            .send_inner(member, cache_mode, msg)
+            .await
+            .and_then(|r| self.check_rcode(member, r))
+        {
+            Ok(r) => {
+                Span::current().record("winner", &field::display(member));
+                Ok(r)
+            }
+            Err(e) => {
+                log::warn!(
+                    "member `{}` of hybrid upstream `{}` failed: {}, racing the rest",
+                    member,
+                    tag,
+                    e
+                );
+                let rest = members.iter().copied().filter(|t| *t != member);
+                self.race(tag, rest, cache_mode, msg).await
+            }
+        }
+    }
+
+    // For HTTPS and SVCB queries, race the upstreams known to return ECH configs.
+    // If none is known or they all failed, race all of them, and give the rest a moment to come up with ECH configs after the first response.
+    async fn prefer_ech(
+        &self,
+        tag: &Label,
+        tags: &[Label],
+        cache_mode: &CacheMode,
+        msg: &Message<Bytes>,
+    ) -> Result<Message<Bytes>> {
+        if !msg.first_question().map_or(false, |q| is_svcb(q.qtype())) {
+            return self.race(tag, tags.iter(), cache_mode, msg).await;
+        }
+
+        let known: Vec<_> = tags
+            .iter()
+            .filter(|t| self.counters[*t].ech.get() > 0)
+            .collect();
+        if !known.is_empty() {
+            match self.race(tag, known.into_iter(), cache_mode, msg).await {
+                Ok(r) => return Ok(r),
+                Err(e) => log::warn!(
+                    "upstreams known to return ECH configs in `{}` failed: {}",
+                    tag,
+                    e
+                ),
+            }
+        }
+
+        let mut pending: FuturesUnordered<_> = tags
+            .iter()
+            .map(|t| self.send_inner(t, cache_mode, msg).map(move |r| (t, r)))
+            .collect();
+        let mut err = None;
+        let first = loop {
+            match pending.next().await {
+                Some((t, r)) => match r.and_then(|r| self.check_rcode(t, r)) {
+                    Ok(r) if has_ech(&r) => return Ok(r),
+                    Ok(r) => break r,
+                    Err(e) => err = Some(e),
+                },
+                None => return Err(err.unwrap_or_else(|| UpstreamError::EmptyHybrid(tag.clone()))),
+            }
+        };
+        let ech = timeout(ECH_GRACE, async {
+            while let Some((_, r)) = pending.next().await {
+                match r {
+                    Ok(r) if has_ech(&r) => return Some(r),
+                    _ => continue,
+                }
+            }
+            None
+        })
+        .await;
+        Ok(ech.ok().flatten().unwrap_or(first))
+    }
+
+    // Query all the upstreams of a consensus upstream, and combine the responses arrived in time.
+    async fn consensus(
+        &self,
+        tag: &Label,
+        tags: &[Label],
+        (mode, trusted): (ConsensusMode, &[Label]),
+        wait: Duration,
+        cache_mode: &CacheMode,
+        msg: &Message<Bytes>,
+    ) -> Result<Message<Bytes>> {
+        let mut pending: FuturesUnordered<_> = tags
+            .iter()
+            .map(|t| self.send_inner(t, cache_mode, msg).map(move |r| (t, r)))
+            .collect();
+        let mut resps = Vec::new();
+        let mut err = None;
+        // Upstreams not answered by then are left out, we don't care whether it is timed out.
+        let _ = timeout(wait, async {
+            while let Some((t, r)) = pending.next().await {
+                match r.and_then(|r| self.check_rcode(t, r)) {
+                    Ok(r) => resps.push((t, r)),
+                    Err(e) => err = Some(e),
+                }
+            }
+        })
+        .await;
+
+        if resps.is_empty() {
+            return Err(err.unwrap_or_else(|| UpstreamError::ConsensusTimeout(tag.clone())));
+        }
+        QueryTrace::note(|| {
+            format!(
+                "consensus upstream {} answered by {} out of {} upstreams",
+                tag,
+                resps.len(),
+                tags.len()
+            )
+        });
+        let (resp, disagreed) = consensus::combine(tag, mode, trusted, resps)?;
+        if disagreed {
+            self.counters[tag].disagreements.inc();
+        }
+        Ok(resp)
+    }
+
+    // Query the plain upstream and the trusted one together. Records answered by the plain one to a name the trusted one answered NXDOMAIN
+    // are taken as NXDOMAIN redirection, which is counted against the plain one, and the NXDOMAIN is returned in place of them.
+    async fn guard(
+        &self,
+        tag: &Label,
+        plain: &Label,
+        trusted: &Label,
+        cache_mode: &CacheMode,
+        msg: &Message<Bytes>,
+    ) -> Result<Message<Bytes>> {
+        let (p, t) = futures::join!(
+            self.send_inner(plain, cache_mode, msg),
+            self.send_inner(trusted, cache_mode, msg)
+        );
+        match (p, t) {
+            (Ok(p), Ok(t))
+                if t.header().rcode() == Rcode::NXDomain
+                    && p.header().rcode() == Rcode::NoError
+                    && p.header_counts().ancount() > 0 =>
+            {
+                self.counters[plain].hijacks.inc();
+                log::warn!(
+                    "upstream `{}` of guard upstream `{}` answered `{}` which trusted upstream `{}` answered NXDOMAIN, likely NXDOMAIN redirection",
+                    plain,
+                    tag,
+                    msg.first_question()
+                        .map(|q| privacy::qname(&q.qname()))
+                        .unwrap_or_default(),
+                    trusted
+                );
+                QueryTrace::note(|| format!("NXDOMAIN redirection by upstream {} detected", plain));
+                Ok(t)
+            }
+            (Ok(p), _) => Ok(p),
+            (Err(e), t) => {
+                log::warn!(
+                    "upstream `{}` of guard upstream `{}` failed: {}",
+                    plain,
+                    tag,
+                    e
+                );
+                t
+            }
+        }
+    }
+
+    // Answer out of the cache alone in offline mode, regardless of the TTL.
+    async fn cached(&self, tag: &Label, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        match self.cache.get(tag, msg).await {
+            Some(Alive(r)) | Some(Expired(r)) => {
+                QueryTrace::note(|| format!("upstream {}: answered from cache offline", tag));
+                Ok(r)
+            }
+            None => Err(UpstreamError::Offline(tag.clone())),
+        }
+    }
+
+    // Resolve the query with an upstream querying on its own, unless its failure or an NXDOMAIN above the name is cached.
+    async fn lone(
+        &self,
+        tag: &Label,
+        u: &Upstream,
+        cache_mode: &CacheMode,
+        msg: &Message<Bytes>,
+    ) -> Result<Message<Bytes>> {
+        let counters = &self.counters[tag];
+        if cache_mode == &CacheMode::Disabled {
+            return u.resolve(tag, &self.cache, cache_mode, msg).await;
+        }
+        if let Some(nxdomains) = &self.nxdomains {
+            if let Some(resp) = nxdomains.get(tag, msg)? {
+                counters.nxdomain_cuts.inc();
+                QueryTrace::note(|| format!("upstream {}: NXDOMAIN cached above", tag));
+                return Ok(resp);
+            }
+        }
+        let r = match &self.failures {
+            Some(failures) => {
+                if failures.get(tag, msg) {
+                    counters.cached_failures.inc();
+                    QueryTrace::note(|| format!("upstream {}: failure cached", tag));
+                    return Err(UpstreamError::CachedFailure(tag.clone()));
+                }
+                let r = u.resolve(tag, &self.cache, cache_mode, msg).await;
+                failures.put(tag, msg, &r);
+                r
+            }
+            None => u.resolve(tag, &self.cache, cache_mode, msg).await,
+        };
+        if let (Some(nxdomains), Ok(resp)) = (&self.nxdomains, &r) {
+            nxdomains.put(tag, msg, resp);
+        }
+        r
+    }
+
+    // Write out in this way to allow recursion for async functions
+    fn send_inner<'a>(
         &'a self,
         tag: &'a Label,
         cache_mode: &'a CacheMode,
@@ -157,21 +800,80 @@ impl Upstreams {
                 .upstreams
                 .get(tag)
                 .ok_or_else(|| UpstreamError::MissingTag(tag.clone()))?;
-            let resp = if let Some(v) = u.try_hybrid() {
-                // Hybrid will never call `u.send_internal()`
-                let v = v.iter().map(|t| self.send(t, cache_mode, msg));
-                let (r, _) = select_ok(v).await?;
-                r
-            } else {
-                u.resolve(tag, &self.cache, cache_mode, msg).await?
-            };
+            // Counters are created for every upstream on construction.
+            let counters = &self.counters[tag];
+            counters.queries.inc();
+            let start = Instant::now();
+            QueryTrace::note(|| format!("sending to upstream {}", tag));
+            let resp = match u {
+                Upstream::Hybrid(v) => {
+                    let v = self.reachable(v);
+                    let pruned = self.pruned.read().unwrap().get(tag).cloned();
+                    let members: Vec<_> = match pruned {
+                        // Members pruned are raced only if every member is pruned.
+                        Some(pruned) if v.iter().any(|t| !pruned.contains(t)) => {
+                            v.iter().filter(|t| !pruned.contains(*t)).collect()
+                        }
+                        _ => v.iter().collect(),
+                    };
+                    match self.affinity.as_ref().and_then(|a| a.pick(tag, &members)) {
+                        Some(member) => self.stick(tag, member, &members, cache_mode, msg).await,
+                        None => self.race(tag, members.into_iter(), cache_mode, msg).await,
+                    }
+                }
+                Upstream::Consensus(v, mode, wait, trusted) => {
+                    self.consensus(
+                        tag,
+                        &self.reachable(v),
+                        (*mode, trusted),
+                        *wait,
+                        cache_mode,
+                        msg,
+                    )
+                    .await
+                }
+                Upstream::Ech(v) => {
+                    self.prefer_ech(tag, &self.reachable(v), cache_mode, msg)
+                        .await
+                }
+                Upstream::Guard(plain, trusted) => {
+                    self.guard(tag, plain, trusted, cache_mode, msg).await
+                }
+                Upstream::Others(_) if self.offline() => self.cached(tag, msg).await,
+                Upstream::Others(_) => self.lone(tag, u, cache_mode, msg).await,
+            }
+            .map_err(|e| {
+                counters.errors.inc();
+                QueryTrace::note(|| {
+                    format!(
+                        "upstream {} failed in {}ms: {}",
+                        tag,
+                        start.elapsed().as_millis(),
+                        e
+                    )
+                });
+                e
+            })?;
+            if msg.first_question().map_or(false, |q| is_svcb(q.qtype())) && has_ech(&resp) {
+                counters.ech.inc();
+            }
+            QueryTrace::note(|| {
+                format!(
+                    "upstream {} answered in {}ms",
+                    tag,
+                    start.elapsed().as_millis()
+                )
+            });
 
             // Set back the message ID
-            let mut resp = Message::from_octets(BytesMut::from(resp.as_slice()))?;
-            resp.header_mut().set_id(msg.header().id());
-
-            Ok(Message::from_octets(resp.into_octets().freeze())?)
+            Ok(wire::set_id(&resp, msg.header().id()))
         }
+        .instrument(tracing::info_span!(
+            "upstream",
+            tag = %tag,
+            winner = field::Empty,
+            cache = field::Empty
+        ))
         .boxed()
     }
 }
@@ -181,10 +883,61 @@ mod tests {
     use crate::AsyncTryInto;
 
     use super::{
-        builder::{HybridBuilder, UdpBuilder, UpstreamBuilder, UpstreamsBuilder},
+        builder::{HybridBuilder, RetryPolicy, UdpBuilder, UpstreamBuilder, UpstreamsBuilder},
         UpstreamError,
     };
 
+    #[cfg(feature = "redis-cache")]
+    #[tokio::test]
+    async fn fail_redis() {
+        use super::builder::RedisCacheBuilder;
+
+        // Nothing listens on the port, so the cache can't be built, nor the upstreams.
+        for url in ["redis://127.0.0.1:1/0", "not a url"] {
+            match UpstreamsBuilder::new(1)
+                .unwrap()
+                .add_upstream(
+                    "udp",
+                    UpstreamBuilder::Udp(UdpBuilder::new("127.0.0.1:53533".parse().unwrap())),
+                )
+                .redis(RedisCacheBuilder::new(url))
+                .async_try_into()
+                .await
+                .err()
+                .unwrap()
+            {
+                UpstreamError::RedisError(_) => (),
+                e => panic!("Not the right error type: {}", e),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fail_missing_fallback() {
+        match UpstreamsBuilder::new(1)
+            .unwrap()
+            .add_upstream(
+                "udp",
+                UpstreamBuilder::Udp(UdpBuilder {
+                    max_pool_size: 32,
+                    timeout: 1,
+                    ..UdpBuilder::new("127.0.0.1:53533".parse().unwrap())
+                }),
+            )
+            .retry(RetryPolicy {
+                fallback: Some("nonexistent".into()),
+                ..Default::default()
+            })
+            .async_try_into()
+            .await
+            .err()
+            .unwrap()
+        {
+            UpstreamError::MissingTag(_) => (),
+            e => panic!("Not the right error type: {}", e),
+        }
+    }
+
     #[tokio::test]
     async fn should_not_fail_recursion() {
         // This should not fail because for the hybrid1, graph is like hybrid1 -> ((hybrid2 -> foo), foo), which is not recursive.
@@ -194,10 +947,9 @@ mod tests {
             .add_upstream(
                 "udp",
                 UpstreamBuilder::Udp(UdpBuilder {
-                    addr: "127.0.0.1:53533".parse().unwrap(),
                     max_pool_size: 32,
                     timeout: 1,
-                    ratelimit: None,
+                    ..UdpBuilder::new("127.0.0.1:53533".parse().unwrap())
                 }),
             )
             .add_upstream(
@@ -221,10 +973,9 @@ mod tests {
             .add_upstream(
                 "udp",
                 UpstreamBuilder::Udp(UdpBuilder {
-                    addr: "127.0.0.1:53533".parse().unwrap(),
                     max_pool_size: 256,
                     timeout: 1,
-                    ratelimit: None,
+                    ..UdpBuilder::new("127.0.0.1:53533".parse().unwrap())
                 }),
             )
             .add_upstream(
@@ -0,0 +1,96 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// NSID of the upstreams (RFC 5001): the queries ask for the identifier of the server answering,
+// which tells which anycast node of a public resolver is actually behind the address.
+
+use super::{
+    super::{slow_query::QueryTrace, stats::UpstreamCounters},
+    upstream::{QHandle, QHandleError},
+};
+use crate::{
+    utils::{edns_option, set_edns_option, strip_edns_option, NSID},
+    Label,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use domain::base::Message;
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+// The NSID in text if it is printable, as most are, or in hex otherwise.
+pub(crate) fn display(nsid: &[u8]) -> String {
+    match std::str::from_utf8(nsid) {
+        Ok(s) if s.chars().all(|c| c.is_ascii_graphic() || c == ' ') => s.to_string(),
+        _ => hex::encode(nsid),
+    }
+}
+
+// Upstream whose queries ask for its NSID.
+pub struct Identified {
+    pub inner: Arc<dyn QHandle>,
+    pub tag: Label,
+    pub counters: Arc<HashMap<Label, UpstreamCounters>>,
+}
+
+#[async_trait]
+impl QHandle for Identified {
+    async fn query(
+        &self,
+        msg: &Message<Bytes>,
+    ) -> std::result::Result<Message<Bytes>, QHandleError> {
+        // Only queries with EDNS can carry the option, and the ones already asking for it are left as they are.
+        let query = match (msg.opt().is_some(), edns_option(msg, NSID)) {
+            (true, Ok(None)) => set_edns_option(msg, NSID, &[]).ok(),
+            _ => None,
+        };
+        let resp = self.inner.query(query.as_ref().unwrap_or(msg)).await?;
+        if let Ok(Some(nsid)) = edns_option(&resp, NSID) {
+            if !nsid.is_empty() {
+                let nsid = display(&nsid);
+                log::debug!("upstream `{}` answered by NSID `{}`", self.tag, nsid);
+                QueryTrace::note(|| format!("upstream {} answered by NSID {}", self.tag, nsid));
+                *self.counters[&self.tag].nsid.lock().unwrap() = Some(nsid);
+            }
+        }
+        // The client didn't ask for it.
+        Ok(match query {
+            Some(_) => strip_edns_option(&resp, NSID).unwrap_or(resp),
+            None => resp,
+        })
+    }
+
+    async fn warmup(&self) -> std::result::Result<(), QHandleError> {
+        self.inner.warmup().await
+    }
+
+    async fn reset(&self) {
+        self.inner.reset().await
+    }
+
+    fn addr(&self) -> Option<IpAddr> {
+        self.inner.addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::display;
+
+    #[test]
+    fn displays() {
+        assert_eq!(display(b"gpdns-lax"), "gpdns-lax");
+        assert_eq!(display(&[0xde, 0xad, 0x0a]), "dead0a");
+    }
+}
@@ -0,0 +1,233 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// NXDOMAIN cut per RFC 8020: a name answered NXDOMAIN has nothing below it, so the queries for the names below are answered NXDOMAIN
+// out of the cache instead of being sent to the upstream, which cuts the queries for junk subdomains, e.g. of random subdomain attacks.
+
+use super::error::Result;
+use crate::{
+    cache::{count_down, CacheRecord},
+    Label, MAX_LEN, MAX_TTL,
+};
+use bytes::{Bytes, BytesMut};
+use clru::CLruCache;
+use domain::{
+    base::{iana::Rcode, name::ToDname, Dname, Message, MessageBuilder, ParsedDname, Rtype},
+    rdata::{AllRecordData, Soa},
+};
+use std::{num::NonZeroUsize, sync::Mutex, time::Duration};
+
+// The names answered NXDOMAIN by the upstreams, keyed by the upstream tag and the name, along with the responses.
+pub(super) struct Nxdomains {
+    lru: Mutex<CLruCache<(Label, Dname<Bytes>), CacheRecord<Message<Bytes>>>>,
+}
+
+// Negative TTL of the response per RFC 2308, i.e. the lesser of the TTL and the MINIMUM field of the SOA record in the authority section.
+fn negative_ttl(resp: &Message<Bytes>) -> Option<u32> {
+    resp.authority()
+        .ok()?
+        .limit_to::<Soa<ParsedDname<_>>>()
+        .filter_map(|r| r.ok())
+        .map(|r| r.ttl().min(r.data().minimum()))
+        .min()
+}
+
+// The NXDOMAIN response to the query, carrying the authority and additional records of the one cached for the name above.
+// The OPT record is only carried if the query has one, as clients without EDNS are not to be sent any (RFC 6891, section 7).
+fn synthesize(query: &Message<Bytes>, cached: &Message<Bytes>) -> Result<Message<Bytes>> {
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(MAX_LEN))?
+        .start_answer(query, Rcode::NXDomain)?;
+    builder.header_mut().set_ra(cached.header().ra());
+    let mut builder = builder.authority();
+    for item in cached.authority()? {
+        if let Some(record) = item?.into_record::<AllRecordData<_, ParsedDname<_>>>()? {
+            builder.push(record)?;
+        }
+    }
+    let mut builder = builder.additional();
+    let edns = query.opt().is_some();
+    for item in cached.additional()? {
+        let item = item?;
+        if item.rtype() == Rtype::Opt && !edns {
+            continue;
+        }
+        if let Some(record) = item.into_record::<AllRecordData<_, ParsedDname<_>>>()? {
+            builder.push(record)?;
+        }
+    }
+    Ok(builder.into_message())
+}
+
+impl Nxdomains {
+    pub fn new(size: NonZeroUsize) -> Self {
+        Self {
+            lru: Mutex::new(CLruCache::new(size)),
+        }
+    }
+
+    // The NXDOMAIN response to the query if the upstream answered NXDOMAIN for the name queried or any name above it within the negative TTL.
+    pub fn get(&self, tag: &Label, query: &Message<Bytes>) -> Result<Option<Message<Bytes>>> {
+        let qname = query.sole_question()?.qname().to_bytes();
+        let cached = {
+            let mut lru = self.lru.lock().unwrap();
+            qname.iter_suffixes().find_map(|name| {
+                let key = (tag.clone(), name);
+                match lru.get(&key) {
+                    Some(r) if r.validate() => Some(count_down(&r.get(), r.age())),
+                    Some(_) => {
+                        lru.pop(&key);
+                        None
+                    }
+                    None => None,
+                }
+            })
+        };
+        cached.map(|resp| synthesize(query, &resp)).transpose()
+    }
+
+    // Remember the name queried if the upstream answered NXDOMAIN for it, with an SOA record to take the negative TTL from.
+    // Responses with answers are skipped, as the NXDOMAIN applies to the end of the CNAME chain rather than the name queried.
+    pub fn put(&self, tag: &Label, query: &Message<Bytes>, resp: &Message<Bytes>) {
+        if resp.header().rcode() != Rcode::NXDomain || resp.header_counts().ancount() > 0 {
+            return;
+        }
+        let question = match query.sole_question() {
+            Ok(q) if q.qtype() != Rtype::Any => q,
+            _ => return,
+        };
+        if let Some(ttl) = negative_ttl(resp) {
+            self.lru.lock().unwrap().put(
+                (tag.clone(), question.qname().to_bytes()),
+                CacheRecord::new(resp.clone(), Duration::from_secs(ttl.min(MAX_TTL).into())),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Nxdomains;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype, Serial},
+        rdata::{Cname, Soa},
+    };
+    use std::{num::NonZeroUsize, str::FromStr};
+
+    fn name(s: &str) -> Dname<Bytes> {
+        Dname::from_str(s).unwrap()
+    }
+
+    fn query(qname: &str, id: u16) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        builder.header_mut().set_id(id);
+        let mut builder = builder.question();
+        builder.push((name(qname), Rtype::A)).unwrap();
+        builder.into_message()
+    }
+
+    fn edns_query(qname: &str, id: u16) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        builder.header_mut().set_id(id);
+        let mut builder = builder.question();
+        builder.push((name(qname), Rtype::A)).unwrap();
+        let mut builder = builder.additional();
+        builder
+            .opt(|opt| {
+                opt.set_udp_payload_size(1232);
+                Ok(())
+            })
+            .unwrap();
+        builder.into_message()
+    }
+
+    fn nxdomain(query: &Message<Bytes>, cname: bool) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(query, Rcode::NXDomain)
+            .unwrap();
+        if cname {
+            let qname = query.sole_question().unwrap().qname().to_bytes();
+            builder
+                .push((qname, 300, Cname::new(name("gone.example.net"))))
+                .unwrap();
+        }
+        let mut builder = builder.authority();
+        builder
+            .push((
+                name("example.com"),
+                3600,
+                Soa::new(
+                    name("ns.example.com"),
+                    name("hostmaster.example.com"),
+                    Serial(1),
+                    7200,
+                    3600,
+                    1209600,
+                    300,
+                ),
+            ))
+            .unwrap();
+        let mut builder = builder.additional();
+        builder
+            .opt(|opt| {
+                opt.set_udp_payload_size(1232);
+                Ok(())
+            })
+            .unwrap();
+        builder.into_message()
+    }
+
+    #[test]
+    fn nxdomains() {
+        let nxdomains = Nxdomains::new(NonZeroUsize::new(8).unwrap());
+        let tag = "upstream".into();
+        let q = query("junk.example.com", 1);
+        nxdomains.put(&tag, &q, &nxdomain(&q, false));
+
+        // Names below are answered NXDOMAIN, along with the SOA record for negative caching.
+        let below = query("a.b.junk.example.com", 2);
+        let resp = nxdomains.get(&tag, &below).unwrap().unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+        assert_eq!(resp.header().id(), 2);
+        assert_eq!(resp.header_counts().nscount(), 1);
+        assert!(nxdomains.get(&tag, &q).unwrap().is_some());
+        // The OPT record of the response cached is only carried to the queries with one.
+        assert!(resp.opt().is_none());
+        let resp = nxdomains
+            .get(&tag, &edns_query("a.b.junk.example.com", 2))
+            .unwrap()
+            .unwrap();
+        assert!(resp.opt().is_some());
+        // Neither the names above or aside, nor the other upstreams.
+        assert!(nxdomains
+            .get(&tag, &query("example.com", 3))
+            .unwrap()
+            .is_none());
+        assert!(nxdomains
+            .get(&tag, &query("other.example.com", 3))
+            .unwrap()
+            .is_none());
+        assert!(nxdomains.get(&"other".into(), &below).unwrap().is_none());
+
+        // The NXDOMAIN of the end of a CNAME chain says nothing about the name queried.
+        let q = query("alias.example.com", 4);
+        nxdomains.put(&tag, &q, &nxdomain(&q, true));
+        assert!(nxdomains
+            .get(&tag, &query("a.alias.example.com", 5))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Probing of the upstreams, and the ranking of the members of hybrid upstreams out of the measurements.
+
+use crate::Label;
+use bytes::{Bytes, BytesMut};
+use domain::base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, str::FromStr};
+
+fn default_probes() -> usize {
+    3
+}
+
+// Signed, and not going anywhere.
+fn default_probe_domain() -> String {
+    "example.com".to_string()
+}
+
+const fn default_min_reliability() -> f64 {
+    0.5
+}
+
+/// Measurements of an upstream out of a round of probes
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Measurement {
+    /// Median round trip time in milliseconds of the probes answered, `None` if none was answered
+    pub latency: Option<u64>,
+    /// Share of the probes answered
+    pub reliability: f64,
+    /// Whether signatures are returned for queries with the DO bit set
+    pub dnssec: bool,
+    /// Whether the OPT record is returned for queries with EDNS
+    pub edns: bool,
+}
+
+/// Members of a hybrid upstream ranked
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Ranking {
+    /// Members not pruned, the best first
+    pub ranked: Vec<Label>,
+    /// Members pruned from the hybrid upstream
+    pub pruned: Vec<Label>,
+}
+
+/// How the upstreams are probed and scored
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RankingPolicy {
+    /// Number of probes sent to each upstream every round
+    #[serde(default = "default_probes")]
+    pub probes: usize,
+    /// Domain to query. It should be signed so that DNSSEC compliance can be told.
+    #[serde(default = "default_probe_domain")]
+    pub domain: String,
+    /// Upstreams answering fewer probes than this share are pruned
+    #[serde(default = "default_min_reliability")]
+    pub min_reliability: f64,
+    /// Upstreams slower than this in milliseconds are pruned
+    #[serde(default)]
+    pub max_latency: Option<u64>,
+    /// Prune upstreams not returning signatures
+    #[serde(default)]
+    pub require_dnssec: bool,
+    /// Prune upstreams not supporting EDNS
+    #[serde(default)]
+    pub require_edns: bool,
+    /// Keep at most this number of the best upstreams in each hybrid upstream
+    #[serde(default)]
+    pub keep: Option<usize>,
+}
+
+impl Default for RankingPolicy {
+    fn default() -> Self {
+        Self {
+            probes: default_probes(),
+            domain: default_probe_domain(),
+            min_reliability: default_min_reliability(),
+            max_latency: None,
+            require_dnssec: false,
+            require_edns: false,
+            keep: None,
+        }
+    }
+}
+
+impl RankingPolicy {
+    /// The probe sent, with EDNS and the DO bit set
+    pub(super) fn probe(&self) -> Option<Message<Bytes>> {
+        let name = Dname::<Bytes>::from_str(&self.domain).ok()?;
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(512)).ok()?;
+        builder.header_mut().set_rd(true);
+        let mut builder = builder.question();
+        builder.push((&name, Rtype::A)).ok()?;
+        let mut builder = builder.additional();
+        builder
+            .opt(|opt| {
+                opt.set_udp_payload_size(1232);
+                opt.set_dnssec_ok(true);
+                Ok(())
+            })
+            .ok()?;
+        Some(builder.into_message())
+    }
+
+    /// Whether the response counts as an answer to the probe
+    pub(super) fn answered(resp: &Message<Bytes>) -> bool {
+        matches!(resp.header().rcode(), Rcode::NoError | Rcode::NXDomain)
+    }
+
+    /// Whether the upstream is good enough to be kept at all
+    pub fn eligible(&self, m: &Measurement) -> bool {
+        m.latency.is_some()
+            && m.reliability >= self.min_reliability
+            && self
+                .max_latency
+                .map_or(true, |max| m.latency.unwrap_or(u64::MAX) <= max)
+            && (!self.require_dnssec || m.dnssec)
+            && (!self.require_edns || m.edns)
+    }
+
+    /// Score of the upstream, the higher the better
+    pub fn score(&self, m: &Measurement) -> f64 {
+        match m.latency {
+            Some(latency) => m.reliability * 1000.0 / (latency as f64 + 1.0),
+            None => 0.0,
+        }
+    }
+
+    /// Rank the members of a hybrid upstream by their measurements.
+    /// Members not measured are kept as they are, and hybrid upstreams are never pruned to empty.
+    pub fn rank(&self, members: &[Label], measurements: &HashMap<Label, Measurement>) -> Ranking {
+        let (mut measured, unmeasured): (Vec<_>, Vec<_>) = members
+            .iter()
+            .partition(|tag| measurements.contains_key(*tag));
+        measured.sort_by(|a, b| {
+            self.score(&measurements[*b])
+                .total_cmp(&self.score(&measurements[*a]))
+        });
+
+        let (mut ranked, mut pruned): (Vec<Label>, Vec<Label>) = measured
+            .into_iter()
+            .cloned()
+            .partition(|tag| self.eligible(&measurements[tag]));
+        if let Some(keep) = self.keep {
+            if ranked.len() > keep {
+                pruned.extend(ranked.split_off(keep.max(1)));
+            }
+        }
+        ranked.extend(unmeasured.into_iter().cloned());
+        if ranked.is_empty() {
+            return Ranking {
+                ranked: pruned,
+                pruned: Vec::new(),
+            };
+        }
+        Ranking { ranked, pruned }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Measurement, Ranking, RankingPolicy};
+    use crate::Label;
+    use std::collections::HashMap;
+
+    fn m(latency: Option<u64>, reliability: f64, dnssec: bool) -> Measurement {
+        Measurement {
+            latency,
+            reliability,
+            dnssec,
+            edns: true,
+        }
+    }
+
+    #[test]
+    fn rank() {
+        let members: Vec<Label> = vec!["slow".into(), "fast".into(), "down".into(), "new".into()];
+        let measurements: HashMap<Label, _> = [
+            ("slow".into(), m(Some(200), 1.0, false)),
+            ("fast".into(), m(Some(20), 1.0, true)),
+            ("down".into(), m(None, 0.0, false)),
+        ]
+        .into();
+
+        let policy = RankingPolicy::default();
+        assert_eq!(
+            policy.rank(&members, &measurements),
+            Ranking {
+                ranked: vec!["fast".into(), "slow".into(), "new".into()],
+                pruned: vec!["down".into()],
+            }
+        );
+
+        let policy = RankingPolicy {
+            require_dnssec: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.rank(&members, &measurements).pruned,
+            vec![Label::from("slow"), "down".into()]
+        );
+
+        let policy = RankingPolicy {
+            keep: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.rank(&members, &measurements).pruned,
+            vec![Label::from("down"), "slow".into()]
+        );
+
+        // Never pruned to empty
+        let members: Vec<Label> = vec!["down".into()];
+        assert_eq!(
+            policy.rank(&members, &measurements),
+            Ranking {
+                ranked: vec!["down".into()],
+                pruned: vec![],
+            }
+        );
+    }
+}
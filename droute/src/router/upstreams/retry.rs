@@ -0,0 +1,140 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::Label;
+use bytes::Bytes;
+use domain::base::{iana::Rcode, Message};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Response codes that can be considered as failures of an upstream.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RetryRcode {
+    /// SERVFAIL
+    ServFail,
+    /// REFUSED
+    Refused,
+    /// NXDOMAIN, e.g. for upstreams known to answer censored names with it
+    NxDomain,
+    /// NOTIMP
+    NotImp,
+    /// FORMERR
+    FormErr,
+}
+
+impl From<RetryRcode> for Rcode {
+    fn from(r: RetryRcode) -> Self {
+        match r {
+            RetryRcode::ServFail => Rcode::ServFail,
+            RetryRcode::Refused => Rcode::Refused,
+            RetryRcode::NxDomain => Rcode::NXDomain,
+            RetryRcode::NotImp => Rcode::NotImp,
+            RetryRcode::FormErr => Rcode::FormErr,
+        }
+    }
+}
+
+fn default_rcodes() -> Vec<RetryRcode> {
+    vec![RetryRcode::ServFail, RetryRcode::Refused]
+}
+
+/// Policy to retry queries answered with failure response codes.
+/// Within a hybrid upstream, such responses lose the race so that the rest of the upstreams get the chance to answer. If the query still fails, it is retried once against the fallback upstream.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Response codes considered as failures
+    #[serde(default = "default_rcodes")]
+    pub rcodes: Vec<RetryRcode>,
+    /// Response codes considered as failures of the upstreams given by their tags, instead of `rcodes`
+    #[serde(default)]
+    pub upstreams: HashMap<Label, Vec<RetryRcode>>,
+    /// The upstream to retry on if the query failed
+    #[serde(default)]
+    pub fallback: Option<Label>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            rcodes: default_rcodes(),
+            upstreams: HashMap::new(),
+            fallback: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether the response from the upstream tagged is considered as failed.
+    pub(super) fn failed(&self, tag: &Label, resp: &Message<Bytes>) -> Option<Rcode> {
+        let rcode = resp.header().rcode();
+        self.upstreams
+            .get(tag)
+            .unwrap_or(&self.rcodes)
+            .iter()
+            .any(|r| Rcode::from(*r) == rcode)
+            .then(|| rcode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetryPolicy, RetryRcode};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype};
+    use std::str::FromStr;
+
+    fn resp(rcode: Rcode) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        let query = builder.into_message();
+        MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&query, rcode)
+            .unwrap()
+            .into_message()
+    }
+
+    #[test]
+    fn per_upstream() {
+        let mut policy = RetryPolicy::default();
+        policy.upstreams.insert(
+            "censored".into(),
+            vec![RetryRcode::Refused, RetryRcode::NxDomain],
+        );
+        let (tag, censored) = ("other".into(), "censored".into());
+        assert_eq!(
+            policy.failed(&tag, &resp(Rcode::ServFail)),
+            Some(Rcode::ServFail)
+        );
+        assert_eq!(policy.failed(&tag, &resp(Rcode::NXDomain)), None);
+        // Overridden rather than extended
+        assert_eq!(policy.failed(&censored, &resp(Rcode::ServFail)), None);
+        assert_eq!(
+            policy.failed(&censored, &resp(Rcode::Refused)),
+            Some(Rcode::Refused)
+        );
+        assert_eq!(
+            policy.failed(&censored, &resp(Rcode::NXDomain)),
+            Some(Rcode::NXDomain)
+        );
+        assert_eq!(policy.failed(&censored, &resp(Rcode::NoError)), None);
+    }
+}
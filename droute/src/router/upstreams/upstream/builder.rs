@@ -17,22 +17,33 @@
 use super::qhandle::https::Https;
 #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
 use super::qhandle::tls::Tls;
+pub use super::qhandle::udp::Retransmit;
+use super::stamp::Stamp;
 use super::{
-    qhandle::{udp::Udp, ConnPool, Result},
-    QHandleError, Upstream,
+    super::consensus::ConsensusMode,
+    qhandle::{ddr::Ddr, system::System, tcp::Tcp, udp::Udp, ConnPool, Result, Warm},
+    QHandle, QHandleError, Upstream,
 };
 use crate::{AsyncTryInto, Label};
 use async_trait::async_trait;
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{self, value::MapAccessDeserializer, MapAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
 use std::net::IpAddr;
-use std::{net::SocketAddr, num::NonZeroU32, sync::Arc, time::Duration};
+use std::{net::SocketAddr, num::NonZeroU32, path::PathBuf, sync::Arc, time::Duration};
 
 // Default value for timeout
-const fn default_timeout() -> u64 {
+pub(super) const fn default_timeout() -> u64 {
     5
 }
 
+// Slow upstreams shouldn't hold up the consensus for long.
+const fn default_consensus_wait() -> u64 {
+    1000
+}
+
 // RATIONALE BEHIND THIS DEFAULT VALUE
 // Actually, if the tolerance level is 2, then the expected number of queries needed to get a valid response is about E(n) = 1.34*n + 1.66
 // That means we have to have on average 344.265 queries by a single sender in order to get one valid response given all the connections in pool are broken and the pool size is 256.
@@ -43,34 +54,83 @@ const fn default_timeout() -> u64 {
 // This means: for each 1.3 second we wait on recovery, we can get about 200 more qps. Quite a good deal!
 //
 // Let's say finally we are willing to wait 60 seconds on recovery. We could then take a pool size of 43, which corresponds to a recovery time of 59.6425
-const fn default_udp_max_pool_size() -> usize {
+pub(super) const fn default_udp_max_pool_size() -> usize {
     43
 }
 
+fn default_system_path() -> PathBuf {
+    PathBuf::from("/etc/resolv.conf")
+}
+
+const fn default_system_port() -> u16 {
+    53
+}
+
+// Queries are pipelined over TCP connections, so a few of them are sufficient.
+const fn default_tcp_connections() -> usize {
+    4
+}
+
+const fn default_tcp_max_reuse() -> usize {
+    2000
+}
+
+const fn default_tcp_reuse_timeout() -> u64 {
+    60000
+}
+
 // We do cache TLS connections. However, they expire quite soon.
 // Therefore, pool size is not of problems.
 #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
-const fn default_tls_max_pool_size() -> usize {
+pub(super) const fn default_tls_max_pool_size() -> usize {
     256
 }
 
 #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
-const fn default_tls_max_reuse() -> usize {
+pub(super) const fn default_tls_max_reuse() -> usize {
     200
 }
 
 #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
-const fn default_tls_reuse_timeout() -> u64 {
+pub(super) const fn default_tls_reuse_timeout() -> u64 {
     60000
 }
 
+// TLS sessions kept by each upstream for resumption, which are hardly ever more than the connections open at once.
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-native-tls",
+    feature = "dot-rustls"
+))]
+pub(super) const fn default_tls_sessions() -> usize {
+    256
+}
+
 // We don't cache HTTPS connections. That means we wouldn't need any recovery! Indeed, we store clients.
 // On average, HTTPS query roundtrip time is 750ms. That means a bigger connection pool is almost always better.
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
-const fn default_https_max_pool_size() -> usize {
+pub(super) const fn default_https_max_pool_size() -> usize {
     1024
 }
 
+// Connections are established lazily on the first queries. Upstreams asked to warm up have them established in the background on start instead,
+// for latency-critical upstreams not to make the first queries wait for the handshakes, and again once the network changes.
+fn warm(inner: Arc<dyn QHandle>, warmup: bool, name: String) -> Upstream {
+    if !warmup {
+        return Upstream::Others(inner);
+    }
+    let inner: Arc<dyn QHandle> = Arc::new(Warm(inner));
+    let handle = inner.clone();
+    crate::runtime::spawn(async move {
+        match handle.warmup().await {
+            Ok(()) => log::info!("warmed up upstream {}", name),
+            Err(e) => log::warn!("failed to warm up upstream {}: {}", name, e),
+        }
+    });
+    Upstream::Others(inner)
+}
+
 /// A builder for hybrid upstream
 #[derive(Serialize, Deserialize, Clone)]
 pub struct HybridBuilder(Vec<Label>);
@@ -103,10 +163,122 @@ impl AsyncTryInto<Upstream> for HybridBuilder {
     }
 }
 
+/// A builder for ECH preferring upstream
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct EchBuilder(Vec<Label>);
+
+impl EchBuilder {
+    /// Create an empty ECH preferring builder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add another upstream to the ECH preferring upstream about to build
+    pub fn add_tag(mut self, tag: impl Into<Label>) -> Self {
+        self.0.push(tag.into());
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for EchBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        Ok(Upstream::Ech(self.0))
+    }
+}
+
+/// A builder for consensus upstream
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub struct ConsensusBuilder {
+    /// Upstreams to query
+    pub tags: Vec<Label>,
+    /// How the responses are combined
+    #[serde(default)]
+    pub mode: ConsensusMode,
+    /// The time in millisecond to wait for the responses. Upstreams not answered by then are left out.
+    #[serde(default = "default_consensus_wait")]
+    pub wait: u64,
+    /// Upstreams whose AD bit is trusted, usually the encrypted ones, as anyone on the path to a plain one can set it
+    #[serde(default)]
+    pub trusted: Vec<Label>,
+}
+
+impl ConsensusBuilder {
+    /// Create an empty consensus builder
+    pub fn new(mode: ConsensusMode, wait: u64) -> Self {
+        Self {
+            tags: Vec::new(),
+            mode,
+            wait,
+            trusted: Vec::new(),
+        }
+    }
+
+    /// Add another upstream to the consensus upstream about to build
+    pub fn add_tag(mut self, tag: impl Into<Label>) -> Self {
+        self.tags.push(tag.into());
+        self
+    }
+
+    /// Add another upstream, whose AD bit is trusted, to the consensus upstream about to build
+    pub fn add_trusted(mut self, tag: impl Into<Label>) -> Self {
+        let tag = tag.into();
+        self.tags.push(tag.clone());
+        self.trusted.push(tag);
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for ConsensusBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        Ok(Upstream::Consensus(
+            self.tags,
+            self.mode,
+            Duration::from_millis(self.wait),
+            self.trusted,
+        ))
+    }
+}
+
+/// A builder for NXDOMAIN redirection guarding upstream
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub struct GuardBuilder {
+    /// The upstream guarded, usually a plain UDP one
+    pub plain: Label,
+    /// The upstream trusted on NXDOMAIN, usually an encrypted or DNSSEC-validating one
+    pub trusted: Label,
+}
+
+impl GuardBuilder {
+    /// Create a guard builder on the upstreams given
+    pub fn new(plain: impl Into<Label>, trusted: impl Into<Label>) -> Self {
+        Self {
+            plain: plain.into(),
+            trusted: trusted.into(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for GuardBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        Ok(Upstream::Guard(self.plain, self.trusted))
+    }
+}
+
 /// A builder for DNS over HTTPS upstream
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
 pub struct HttpsBuilder {
     /// The URL of the DoH server. e.g. `https://cloudflare-dns.com/dns-query`
     pub uri: String,
@@ -126,6 +298,30 @@ pub struct HttpsBuilder {
     /// SNI
     #[serde(default)]
     pub sni: bool,
+    /// Prepare the connection pool on start instead of on the first query
+    #[serde(default)]
+    pub warmup: bool,
+    /// Number of TLS sessions kept for the connections to resume, `0` to always go through the full handshake
+    #[serde(default = "default_tls_sessions")]
+    pub sessions: usize,
+}
+
+#[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+impl HttpsBuilder {
+    /// Create a DoH upstream builder for the URL and the address of the server with the settings defaulted as in the configuration.
+    pub fn new(uri: impl Into<String>, addr: IpAddr) -> Self {
+        Self {
+            uri: uri.into(),
+            addr,
+            proxy: None,
+            timeout: default_timeout(),
+            max_pool_size: default_https_max_pool_size(),
+            ratelimit: None,
+            sni: false,
+            warmup: false,
+            sessions: default_tls_sessions(),
+        }
+    }
 }
 
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
@@ -134,19 +330,32 @@ impl AsyncTryInto<Upstream> for HttpsBuilder {
     type Error = QHandleError;
 
     async fn async_try_into(self) -> Result<Upstream> {
-        Ok(Upstream::Others(Arc::new(ConnPool::new(
-            Https::new(self.uri, self.addr, self.proxy, self.sni).await?,
-            self.max_pool_size,
-            Duration::from_secs(self.timeout),
-            self.ratelimit.into(),
-        )?)))
+        let name = self.uri.clone();
+        // native-tls leaves session resumption to the platform library.
+        #[cfg(feature = "doh-native-tls")]
+        if self.sessions != default_tls_sessions() {
+            log::warn!(
+                "`sessions` of upstream {} takes no effect in native-tls builds",
+                name
+            );
+        }
+        Ok(warm(
+            Arc::new(ConnPool::new(
+                Https::new(self.uri, self.addr, self.proxy, self.sni, self.sessions).await?,
+                self.max_pool_size,
+                Duration::from_secs(self.timeout),
+                self.ratelimit.into(),
+            )?),
+            self.warmup,
+            name,
+        ))
     }
 }
 
 /// A builder for DNS over TLS upstream
 #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
 pub struct TlsBuilder {
     /// The domain of the DoH server. e.g. `cloudflare-dns.com`
     pub domain: String,
@@ -170,6 +379,35 @@ pub struct TlsBuilder {
     /// SNI
     #[serde(default)]
     pub sni: bool,
+    /// Establish a connection on start instead of on the first query
+    #[serde(default)]
+    pub warmup: bool,
+    /// Number of TLS sessions kept for the reconnections to resume, `0` to always go through the full handshake
+    #[serde(default = "default_tls_sessions")]
+    pub sessions: usize,
+    /// Send the first query on resumed connections in TLS 1.3 early data (0-RTT), which may be replayed by anyone on the path
+    #[serde(default)]
+    pub early_data: bool,
+}
+
+#[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+impl TlsBuilder {
+    /// Create a DoT upstream builder for the domain and the address of the server with the settings defaulted as in the configuration.
+    pub fn new(domain: impl Into<String>, addr: SocketAddr) -> Self {
+        Self {
+            domain: domain.into(),
+            addr,
+            timeout: default_timeout(),
+            max_pool_size: default_tls_max_pool_size(),
+            reuse_timeout: default_tls_reuse_timeout(),
+            max_reuse: default_tls_max_reuse(),
+            ratelimit: None,
+            sni: false,
+            warmup: false,
+            sessions: default_tls_sessions(),
+            early_data: false,
+        }
+    }
 }
 
 #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
@@ -178,24 +416,39 @@ impl AsyncTryInto<Upstream> for TlsBuilder {
     type Error = QHandleError;
 
     async fn async_try_into(self) -> Result<Upstream> {
-        Ok(Upstream::Others(Arc::new(ConnPool::new(
-            Tls::new(
-                self.domain,
-                self.addr,
-                self.sni,
-                self.reuse_timeout,
-                self.max_reuse,
-            )?,
-            self.max_pool_size,
-            Duration::from_secs(self.timeout),
-            self.ratelimit.into(),
-        )?)))
+        let name = self.domain.clone();
+        // native-tls leaves session resumption to the platform library and has no early data.
+        #[cfg(feature = "dot-native-tls")]
+        if self.sessions != default_tls_sessions() || self.early_data {
+            log::warn!(
+                "`sessions` and `early_data` of upstream {} take no effect in native-tls builds",
+                name
+            );
+        }
+        Ok(warm(
+            Arc::new(ConnPool::new(
+                Tls::new(
+                    self.domain,
+                    self.addr,
+                    self.sni,
+                    self.reuse_timeout,
+                    self.max_reuse,
+                    self.sessions,
+                    self.early_data,
+                )?,
+                self.max_pool_size,
+                Duration::from_secs(self.timeout),
+                self.ratelimit.into(),
+            )?),
+            self.warmup,
+            name,
+        ))
     }
 }
 
 /// A builder for UDP upstream
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
 pub struct UdpBuilder {
     /// Address of the remote server
     pub addr: SocketAddr,
@@ -208,6 +461,34 @@ pub struct UdpBuilder {
     /// Timeout length
     #[serde(default = "default_timeout")]
     pub timeout: u64,
+    /// Discard answers arriving earlier than the upstream could possibly reply, which are injected on the path
+    #[serde(default)]
+    pub anti_pollution: bool,
+    /// Retransmit the queries unanswered, instead of waiting for the timeout after sending them once
+    #[serde(default)]
+    pub retransmit: Option<Retransmit>,
+    /// Upgrade to the encrypted resolver designated by the upstream if any (RFC 9462), which is discovered on the first query
+    #[serde(default)]
+    pub ddr: bool,
+    /// Discover the designated resolver and connect to it on start instead of on the first query
+    #[serde(default)]
+    pub warmup: bool,
+}
+
+impl UdpBuilder {
+    /// Create a UDP upstream builder for the remote server with the settings defaulted as in the configuration.
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            max_pool_size: default_udp_max_pool_size(),
+            ratelimit: None,
+            timeout: default_timeout(),
+            anti_pollution: false,
+            retransmit: None,
+            ddr: false,
+            warmup: false,
+        }
+    }
 }
 
 #[async_trait(?Send)]
@@ -215,23 +496,142 @@ impl AsyncTryInto<Upstream> for UdpBuilder {
     type Error = QHandleError;
 
     async fn async_try_into(self) -> Result<Upstream> {
-        Ok(Upstream::Others(Arc::new(ConnPool::new(
-            Udp::new(self.addr).await?,
+        let plain = Arc::new(ConnPool::new(
+            Udp::new(self.addr, self.anti_pollution, self.retransmit).await?,
             self.max_pool_size,
             Duration::from_secs(self.timeout),
             self.ratelimit.into(),
-        )?)))
+        )?);
+        let inner: Arc<dyn QHandle> = if self.ddr {
+            Arc::new(Ddr::new(
+                plain,
+                self.addr,
+                self.max_pool_size,
+                Duration::from_secs(self.timeout),
+                self.ratelimit,
+            ))
+        } else {
+            plain
+        };
+        Ok(warm(inner, self.warmup, self.addr.to_string()))
+    }
+}
+
+/// A builder for plain DNS over TCP upstream
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub struct TcpBuilder {
+    /// Address of the remote server
+    pub addr: SocketAddr,
+    /// Number of persistent connections to pipeline queries on
+    #[serde(default = "default_tcp_connections")]
+    pub connections: usize,
+    /// The time in millisecond to keep a persistent TCP connection open for reuse
+    #[serde(default = "default_tcp_reuse_timeout")]
+    pub reuse_timeout: u64,
+    /// The maximum number of queries allowed to send over a single TCP connection
+    #[serde(default = "default_tcp_max_reuse")]
+    pub max_reuse: usize,
+    /// Maximum number of query per second and the query burst size allowed to upstream using Leaky Bucket algorithm
+    #[serde(default)]
+    pub ratelimit: Option<NonZeroU32>,
+    /// Timeout length
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Establish the persistent connections on start instead of on the first queries
+    #[serde(default)]
+    pub warmup: bool,
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for TcpBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        Ok(warm(
+            Arc::new(Tcp::new(
+                self.addr,
+                self.connections,
+                Duration::from_secs(self.timeout),
+                Duration::from_millis(self.reuse_timeout),
+                self.max_reuse,
+                self.ratelimit.into(),
+            )),
+            self.warmup,
+            self.addr.to_string(),
+        ))
     }
 }
 
+/// A builder for the upstream forwarding to the name servers configured on the system
 #[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub struct SystemBuilder {
+    /// The resolver configuration listing the name servers, which is followed as it changes
+    #[serde(default = "default_system_path")]
+    pub path: PathBuf,
+    /// Port of the name servers
+    #[serde(default = "default_system_port")]
+    pub port: u16,
+    /// Max connection pool size of each name server
+    #[serde(default = "default_udp_max_pool_size")]
+    pub max_pool_size: usize,
+    /// Maximum number of query per second and the query burst size allowed to each name server using Leaky Bucket algorithm
+    #[serde(default)]
+    pub ratelimit: Option<NonZeroU32>,
+    /// Timeout length
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for SystemBuilder {
+    fn default() -> Self {
+        Self {
+            path: default_system_path(),
+            port: default_system_port(),
+            max_pool_size: default_udp_max_pool_size(),
+            ratelimit: None,
+            timeout: default_timeout(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for SystemBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        Ok(Upstream::Others(Arc::new(System::new(
+            self.path,
+            self.port,
+            self.max_pool_size,
+            Duration::from_secs(self.timeout),
+            self.ratelimit,
+        ))))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+// Implemented by hand below, so that upstreams can be given as DNS stamps as well.
+#[serde(remote = "Self")]
 #[serde(rename_all = "lowercase")]
 /// The builder for `Upstream`
 pub enum UpstreamBuilder {
     /// Race various different upstreams concurrently. You can use it recursively, meaning Hybrid over (Hybrid over (DoH + UDP) + UDP) is legal.
     Hybrid(HybridBuilder),
+    /// Query various different upstreams concurrently, and combine their responses. Like `Hybrid`, it can be used recursively.
+    Consensus(ConsensusBuilder),
+    /// Race the upstreams like `Hybrid`, except that the ones known to return ECH configs are preferred for HTTPS and SVCB queries.
+    Ech(EchBuilder),
+    /// Query a plain upstream and a trusted one together, returning the NXDOMAIN of the trusted one in place of the records the plain one redirects to.
+    Guard(GuardBuilder),
     /// UDP connection.
     Udp(UdpBuilder),
+    /// Plain TCP connection.
+    Tcp(TcpBuilder),
+    /// UDP connections to the name servers configured on the system, e.g. by DHCP, following them as the network changes.
+    #[serde(alias = "dhcp")]
+    System(SystemBuilder),
     #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
     /// HTTPS connection.
     Https(HttpsBuilder),
@@ -240,6 +640,41 @@ pub enum UpstreamBuilder {
     Tls(TlsBuilder),
 }
 
+impl Serialize for UpstreamBuilder {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        UpstreamBuilder::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for UpstreamBuilder {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct UpstreamVisitor;
+
+        impl<'de> Visitor<'de> for UpstreamVisitor {
+            type Value = UpstreamBuilder;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("an upstream or a DNS stamp")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Self::Value, E> {
+                v.parse::<Stamp>()
+                    .and_then(UpstreamBuilder::try_from)
+                    .map_err(E::custom)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> std::result::Result<Self::Value, A::Error> {
+                UpstreamBuilder::deserialize(MapAccessDeserializer::new(map))
+            }
+        }
+
+        deserializer.deserialize_any(UpstreamVisitor)
+    }
+}
+
 #[async_trait(?Send)]
 impl AsyncTryInto<Upstream> for UpstreamBuilder {
     /// Build the Upstream from an UpstreamBuilder
@@ -247,9 +682,21 @@ impl AsyncTryInto<Upstream> for UpstreamBuilder {
         Ok(match self {
             Self::Hybrid(v) => v.async_try_into().await?,
 
+            Self::Consensus(c) => c.async_try_into().await?,
+
+            Self::Ech(e) => e.async_try_into().await?,
+
+            Self::Guard(g) => g.async_try_into().await?,
+
             // UDP Upstream
             Self::Udp(u) => u.async_try_into().await?,
 
+            // TCP Upstream
+            Self::Tcp(t) => t.async_try_into().await?,
+
+            // System name servers
+            Self::System(s) => s.async_try_into().await?,
+
             #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
             Self::Https(h) => h.async_try_into().await?,
 
@@ -15,18 +15,21 @@
 
 pub mod builder;
 mod qhandle;
+pub mod stamp;
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use bytes::Bytes;
 pub use qhandle::{QHandle, QHandleError};
 
-use super::{error::Result, CacheMode};
+use super::{consensus::ConsensusMode, error::Result, CacheMode};
 use crate::{
     cache::{RecordStatus::*, RespCache},
+    router::slow_query::QueryTrace,
     Label,
 };
 use domain::base::Message;
+use tracing::{Instrument, Span};
 
 /// A single upstream. Opposite to the `Upstreams`.
 #[derive(Clone)]
@@ -34,6 +37,12 @@ pub enum Upstream {
     /// Hybrid upstream type
     // We don't use HashSet because we don't need to look up
     Hybrid(Vec<Label>),
+    /// Query all the upstreams and combine their responses, waiting up to the time given. The AD bit counts only from the trusted ones given last.
+    Consensus(Vec<Label>, ConsensusMode, Duration, Vec<Label>),
+    /// Hybrid upstream type racing the upstreams known to return ECH configs first for HTTPS and SVCB queries
+    Ech(Vec<Label>),
+    /// Query the plain upstream and the trusted one together, preferring the NXDOMAIN of the trusted one over the answer of the plain one
+    Guard(Label, Label),
     /// Other upstream types, like Zone or ClientPool.
     Others(Arc<dyn QHandle>),
 }
@@ -41,7 +50,8 @@ pub enum Upstream {
 impl Upstream {
     pub(super) fn try_hybrid(&self) -> Option<Vec<&Label>> {
         match &self {
-            Self::Hybrid(v) => Some(v.iter().collect()),
+            Self::Hybrid(v) | Self::Consensus(v, _, _, _) | Self::Ech(v) => Some(v.iter().collect()),
+            Self::Guard(plain, trusted) => Some(vec![plain, trusted]),
             _ => None,
         }
     }
@@ -56,19 +66,35 @@ impl Upstream {
     ) -> Result<Message<Bytes>> {
         if let Self::Others(inner) = &self {
             log::info!("querying with upstream: {}", tag);
+            let query = || inner.query(msg).instrument(tracing::info_span!("query"));
+            let span = Span::current();
             // Manage cache with caching policies
             let r = match cache_mode {
-                CacheMode::Disabled => inner.query(msg).await?,
-                CacheMode::Standard => match cache.get(tag, msg) {
+                CacheMode::Disabled => query().await?,
+                CacheMode::Standard => match cache.get(tag, msg).await {
                     // Cache available within TTL constraints
-                    Some(Alive(r)) => r,
+                    Some(Alive(r)) => {
+                        span.record("cache", "hit");
+                        QueryTrace::note(|| format!("upstream {}: cache hit", tag));
+                        r
+                    }
                     // No cache or cache expired
-                    Some(Expired(_)) | None => inner.query(msg).await?,
+                    Some(Expired(_)) | None => {
+                        span.record("cache", "miss");
+                        QueryTrace::note(|| format!("upstream {}: cache miss", tag));
+                        query().await?
+                    }
                 },
-                CacheMode::Persistent => match cache.get(tag, msg) {
+                CacheMode::Persistent => match cache.get(tag, msg).await {
                     // Cache available within TTL constraints
-                    Some(Alive(r)) => r,
+                    Some(Alive(r)) => {
+                        span.record("cache", "hit");
+                        QueryTrace::note(|| format!("upstream {}: cache hit", tag));
+                        r
+                    }
                     Some(Expired(r)) => {
+                        span.record("cache", "expired");
+                        QueryTrace::note(|| format!("upstream {}: cache expired", tag));
                         // Cache records exists, but TTL exceeded.
                         // We try to update the cache and return back the outdated value.
                         let inner = inner.clone();
@@ -76,20 +102,24 @@ impl Upstream {
                         let cache = cache.clone();
                         let msg = msg.clone();
                         let tag = tag.clone();
-                        tokio::spawn(async move {
+                        crate::runtime::spawn(async move {
                             // We have to update the cache though
                             // We don't care about failures here.
                             if let Ok(r) = inner.query(&msg).await {
-                                cache.put(tag, &msg, r)
+                                cache.put(tag, &msg, r).await
                             }
                         });
                         r
                     }
-                    None => inner.query(msg).await?,
+                    None => {
+                        span.record("cache", "miss");
+                        QueryTrace::note(|| format!("upstream {}: cache miss", tag));
+                        query().await?
+                    }
                 },
             };
             if cache_mode != &CacheMode::Disabled {
-                cache.put(tag.clone(), msg, r.clone());
+                cache.put(tag.clone(), msg, r.clone()).await;
             }
             log::info!("query successfully completed.");
             Ok(r)
@@ -0,0 +1,474 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Discovery of Designated Resolvers (RFC 9462), with which plain upstreams are upgraded to their encrypted equivalents.
+
+#[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+use super::https::Https;
+#[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+use super::tls::Tls;
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-native-tls",
+    feature = "dot-rustls"
+))]
+use super::ConnPool;
+use super::{QHandle, Result};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{Dname, Message, MessageBuilder, Rtype},
+    rdata::UnknownRecordData,
+};
+use once_cell::sync::Lazy;
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU32,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+
+const SVCB: u16 = 64;
+
+// Discovery failed, e.g. as the query is lost, is tried again after this long.
+const RETRY_DISCOVERY: Duration = Duration::from_secs(60);
+
+const ALPN: u16 = 1;
+const PORT: u16 = 3;
+const IPV4HINT: u16 = 4;
+const IPV6HINT: u16 = 6;
+const DOHPATH: u16 = 7;
+
+// Connections to designated resolvers over TLS are kept the same as the defaults of DoT upstreams.
+#[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+const TLS_REUSE_TIMEOUT: u64 = 60000;
+#[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+const TLS_MAX_REUSE: usize = 200;
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-native-tls",
+    feature = "dot-rustls"
+))]
+const TLS_SESSIONS: usize = 256;
+// Early data may be replayed by anyone on the path, and designated resolvers have no configuration to opt in with.
+#[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+const TLS_EARLY_DATA: bool = false;
+
+static DDR_QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
+    let name = Dname::<Bytes>::from_str("_dns.resolver.arpa").unwrap();
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(512)).unwrap();
+    builder.header_mut().set_rd(true);
+    let mut builder = builder.question();
+    builder.push((&name, Rtype::Int(SVCB))).unwrap();
+    builder.into_message()
+});
+
+/// An encrypted resolver designated by a plain one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Designation {
+    pub priority: u16,
+    pub target: String,
+    pub alpn: Vec<String>,
+    pub port: Option<u16>,
+    pub hints: Vec<IpAddr>,
+    pub dohpath: Option<String>,
+}
+
+impl Designation {
+    // Parse the SVCB record data, or `None` if it is malformed or in AliasMode.
+    fn parse(rdata: &[u8]) -> Option<Self> {
+        let priority = u16::from_be_bytes([*rdata.first()?, *rdata.get(1)?]);
+        if priority == 0 {
+            return None;
+        }
+
+        // TargetName, which is never compressed
+        let mut pos = 2;
+        let mut labels = Vec::new();
+        loop {
+            let len = usize::from(*rdata.get(pos)?);
+            pos += 1;
+            if len == 0 {
+                break;
+            }
+            if len > 63 {
+                return None;
+            }
+            labels.push(String::from_utf8_lossy(rdata.get(pos..pos + len)?).into_owned());
+            pos += len;
+        }
+
+        let mut designation = Self {
+            priority,
+            target: labels.join("."),
+            alpn: Vec::new(),
+            port: None,
+            hints: Vec::new(),
+            dohpath: None,
+        };
+        while pos < rdata.len() {
+            let key = u16::from_be_bytes([*rdata.get(pos)?, *rdata.get(pos + 1)?]);
+            let len = usize::from(u16::from_be_bytes([
+                *rdata.get(pos + 2)?,
+                *rdata.get(pos + 3)?,
+            ]));
+            let value = rdata.get(pos + 4..pos + 4 + len)?;
+            pos += 4 + len;
+
+            match key {
+                ALPN => {
+                    let mut i = 0;
+                    while i < value.len() {
+                        let len = usize::from(value[i]);
+                        let id = value.get(i + 1..i + 1 + len)?;
+                        designation
+                            .alpn
+                            .push(String::from_utf8_lossy(id).into_owned());
+                        i += 1 + len;
+                    }
+                }
+                PORT => designation.port = Some(u16::from_be_bytes(value.try_into().ok()?)),
+                IPV4HINT => designation.hints.extend(
+                    value
+                        .chunks_exact(4)
+                        .map(|c| IpAddr::from(<[u8; 4]>::try_from(c).unwrap())),
+                ),
+                IPV6HINT => designation.hints.extend(
+                    value
+                        .chunks_exact(16)
+                        .map(|c| IpAddr::from(<[u8; 16]>::try_from(c).unwrap())),
+                ),
+                DOHPATH => designation.dohpath = Some(String::from_utf8_lossy(value).into_owned()),
+                _ => (),
+            }
+        }
+        Some(designation)
+    }
+}
+
+/// Encrypted resolvers designated in the response to the DDR query, in the order of their priorities.
+pub fn designations(resp: &Message<Bytes>) -> Vec<Designation> {
+    let answer = match resp.answer() {
+        Ok(answer) => answer,
+        Err(_) => return Vec::new(),
+    };
+    let mut designations: Vec<_> = answer
+        .filter_map(|item| item.ok()?.into_record::<UnknownRecordData<_>>().ok()?)
+        .filter(|r| r.rtype().to_int() == SVCB)
+        .filter_map(|r| Designation::parse(r.data().data().as_ref()))
+        .collect();
+    designations.sort_by_key(|d| d.priority);
+    designations
+}
+
+// The address to connect to the designated resolver at.
+// The certificate is always verified against the address of the plain resolver, which is the designated one itself unless hinted otherwise.
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-native-tls",
+    feature = "dot-rustls"
+))]
+fn connect_addr(d: &Designation, addr: IpAddr) -> IpAddr {
+    if d.hints.is_empty() || d.hints.contains(&addr) {
+        addr
+    } else {
+        d.hints[0]
+    }
+}
+
+#[allow(unused_variables)]
+async fn upgrade_to(
+    d: &Designation,
+    addr: IpAddr,
+    max_pool_size: usize,
+    timeout: Duration,
+    ratelimit: Option<NonZeroU32>,
+) -> Option<Arc<dyn QHandle>> {
+    #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+    if let Some(path) = &d.dohpath {
+        // Addresses other than the one verified cannot be connected to, as there is no name to resolve.
+        if d.alpn
+            .iter()
+            .any(|a| a.starts_with("h2") || a == "http/1.1")
+            && connect_addr(d, addr) == addr
+        {
+            let host = match addr {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => format!("[{}]", ip),
+            };
+            let port = d.port.map(|p| format!(":{}", p)).unwrap_or_default();
+            let path = path.split('{').next().unwrap_or_default();
+            let uri = format!("https://{}{}{}", host, port, path);
+            if let Ok(https) = Https::new(uri, addr, None, true, TLS_SESSIONS).await {
+                if let Ok(pool) = ConnPool::new(https, max_pool_size, timeout, ratelimit.into()) {
+                    return Some(Arc::new(pool));
+                }
+            }
+        }
+    }
+
+    #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+    if d.alpn.iter().any(|a| a == "dot") {
+        let tls = Tls::new(
+            addr.to_string(),
+            SocketAddr::new(connect_addr(d, addr), d.port.unwrap_or(853)),
+            true,
+            TLS_REUSE_TIMEOUT,
+            TLS_MAX_REUSE,
+            TLS_SESSIONS,
+            TLS_EARLY_DATA,
+        )
+        .ok()?;
+        if let Ok(pool) = ConnPool::new(tls, max_pool_size, timeout, ratelimit.into()) {
+            return Some(Arc::new(pool));
+        }
+    }
+
+    None
+}
+
+// Discover the encrypted resolvers designated by the plain one, and return the first of them verified to be working,
+// or `Err` if the discovery itself failed, which is worth another try.
+// Designated resolvers have to present certificates valid for the address of the plain resolver, or they are not used.
+async fn upgrade(
+    plain: &dyn QHandle,
+    addr: SocketAddr,
+    max_pool_size: usize,
+    timeout: Duration,
+    ratelimit: Option<NonZeroU32>,
+) -> std::result::Result<Option<Arc<dyn QHandle>>, ()> {
+    let resp = match crate::runtime::timeout(timeout, plain.query(&DDR_QUERY)).await {
+        Ok(Ok(resp)) => resp,
+        Ok(Err(e)) => {
+            log::warn!("failed to discover designated resolvers of {}: {}", addr, e);
+            return Err(());
+        }
+        Err(_) => {
+            log::warn!("discovering designated resolvers of {} timed out", addr);
+            return Err(());
+        }
+    };
+
+    for d in designations(&resp) {
+        let upstream = match upgrade_to(&d, addr.ip(), max_pool_size, timeout, ratelimit).await {
+            Some(upstream) => upstream,
+            None => {
+                log::debug!("designated resolver {:?} is not supported", d);
+                continue;
+            }
+        };
+        // The certificate is verified on the first query.
+        match upstream.query(&DDR_QUERY).await {
+            Ok(_) => {
+                log::info!(
+                    "upgraded {} to its designated resolver {} ({})",
+                    addr,
+                    d.target,
+                    d.alpn.join(",")
+                );
+                return Ok(Some(upstream));
+            }
+            Err(e) => log::warn!(
+                "designated resolver {} of {} failed verification: {}",
+                d.target,
+                addr,
+                e
+            ),
+        }
+    }
+    Ok(None)
+}
+
+/// A plain upstream upgraded to its designated resolver on the first query rather than on start, so that upstreams never routed to are never connected.
+pub struct Ddr {
+    plain: Arc<dyn QHandle>,
+    addr: SocketAddr,
+    max_pool_size: usize,
+    timeout: Duration,
+    ratelimit: Option<NonZeroU32>,
+    upgraded: OnceCell<Arc<dyn QHandle>>,
+    // When the discovery last failed
+    failed: Mutex<Option<Instant>>,
+}
+
+impl Ddr {
+    pub fn new(
+        plain: Arc<dyn QHandle>,
+        addr: SocketAddr,
+        max_pool_size: usize,
+        timeout: Duration,
+        ratelimit: Option<NonZeroU32>,
+    ) -> Self {
+        Self {
+            plain,
+            addr,
+            max_pool_size,
+            timeout,
+            ratelimit,
+            upgraded: OnceCell::new(),
+            failed: Mutex::new(None),
+        }
+    }
+
+    // The designated resolver, or the plain upstream if none of them is usable. Discovery is done once it succeeds,
+    // and retried every `RETRY_DISCOVERY` until then, querying in plain meanwhile.
+    async fn inner(&self) -> &Arc<dyn QHandle> {
+        if let Some(upgraded) = self.upgraded.get() {
+            return upgraded;
+        }
+        if matches!(*self.failed.lock().unwrap(), Some(t) if t.elapsed() < RETRY_DISCOVERY) {
+            return &self.plain;
+        }
+        let upgraded = self
+            .upgraded
+            .get_or_try_init(|| async {
+                match upgrade(
+                    self.plain.as_ref(),
+                    self.addr,
+                    self.max_pool_size,
+                    self.timeout,
+                    self.ratelimit,
+                )
+                .await?
+                {
+                    Some(upgraded) => Ok(upgraded),
+                    None => {
+                        log::warn!(
+                            "no designated resolver of {} is usable, querying it in plain",
+                            self.addr
+                        );
+                        Ok(self.plain.clone())
+                    }
+                }
+            })
+            .await;
+        match upgraded {
+            Ok(upgraded) => upgraded,
+            Err(()) => {
+                *self.failed.lock().unwrap() = Some(Instant::now());
+                &self.plain
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl QHandle for Ddr {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        self.inner().await.query(msg).await
+    }
+
+    async fn warmup(&self) -> Result<()> {
+        self.inner().await.warmup().await
+    }
+
+    // The designated resolver is kept, as it is designated by the same upstream wherever we are.
+    async fn reset(&self) {
+        if let Some(upgraded) = self.upgraded.get() {
+            upgraded.reset().await;
+        }
+        self.plain.reset().await;
+    }
+
+    // Designated resolvers may be connected over the other family, which is known only once discovered.
+    fn addr(&self) -> Option<IpAddr> {
+        match self.upgraded.get() {
+            Some(upgraded) => upgraded.addr(),
+            None => self.plain.addr(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{designations, Designation, SVCB};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{Dname, Message, MessageBuilder, Rtype},
+        rdata::UnknownRecordData,
+    };
+    use std::str::FromStr;
+
+    fn message(rdatas: Vec<Vec<u8>>) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("_dns.resolver.arpa").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        let mut builder = builder.question();
+        builder.push((&name, Rtype::Int(SVCB))).unwrap();
+        let mut builder = builder.answer();
+        for rdata in rdatas {
+            builder
+                .push((
+                    &name,
+                    10,
+                    UnknownRecordData::from_octets(Rtype::Int(SVCB), Bytes::from(rdata)),
+                ))
+                .unwrap();
+        }
+        builder.into_message()
+    }
+
+    #[test]
+    fn parse_designations() {
+        // As served by 1.1.1.1
+        let doh = [
+            &[0, 1][..],
+            b"\x03one\x03one\x03one\x03one\x00",
+            &[0, 1, 0, 3, 2],
+            b"h2",
+            &[0, 3, 0, 2, 1, 187],
+            &[0, 4, 0, 8, 1, 1, 1, 1, 1, 0, 0, 1],
+            &[0, 7, 0, 16],
+            b"/dns-query{?dns}",
+        ]
+        .concat();
+        let dot = [
+            &[0, 2][..],
+            b"\x03one\x03one\x03one\x03one\x00",
+            &[0, 1, 0, 4, 3],
+            b"dot",
+            &[0, 4, 0, 4, 1, 1, 1, 1],
+        ]
+        .concat();
+        // AliasMode records are left out.
+        let alias = [&[0, 0][..], b"\x03one\x00"].concat();
+
+        assert_eq!(
+            designations(&message(vec![dot, alias, doh])),
+            vec![
+                Designation {
+                    priority: 1,
+                    target: "one.one.one.one".to_string(),
+                    alpn: vec!["h2".to_string()],
+                    port: Some(443),
+                    hints: vec!["1.1.1.1".parse().unwrap(), "1.0.0.1".parse().unwrap()],
+                    dohpath: Some("/dns-query{?dns}".to_string()),
+                },
+                Designation {
+                    priority: 2,
+                    target: "one.one.one.one".to_string(),
+                    alpn: vec!["dot".to_string()],
+                    port: None,
+                    hints: vec!["1.1.1.1".parse().unwrap()],
+                    dohpath: None,
+                }
+            ]
+        );
+    }
+}
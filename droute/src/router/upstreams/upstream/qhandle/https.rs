@@ -15,13 +15,14 @@
 
 #[cfg(feature = "doh-rustls")]
 mod rustls_cfgs {
-    use once_cell::sync::Lazy;
-    use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
-
-    pub static NO_SNI_CLIENT_CFG: Lazy<ClientConfig> = Lazy::new(|| create_client_config(&false));
-    pub static CLIENT_CFG: Lazy<ClientConfig> = Lazy::new(|| create_client_config(&true));
-
-    fn create_client_config(sni: &bool) -> ClientConfig {
+    use rustls::{
+        client::{ClientSessionMemoryCache, NoClientSessionStorage},
+        ClientConfig, OwnedTrustAnchor, RootCertStore,
+    };
+    use std::sync::Arc;
+
+    // Every upstream has a config of its own, so that its clients share the sessions kept for resumption.
+    pub fn client_config(sni: bool, sessions: usize) -> ClientConfig {
         let mut root_store = RootCertStore::empty();
         root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
             OwnedTrustAnchor::from_subject_spki_name_constraints(
@@ -36,7 +37,14 @@ mod rustls_cfgs {
             .with_root_certificates(root_store)
             .with_no_client_auth();
 
-        client_config.enable_sni = *sni; // Disable SNI on need.
+        client_config.enable_sni = sni; // Disable SNI on need.
+
+        // Connections resume the sessions kept instead of going through the full handshake again.
+        client_config.session_storage = match sessions {
+            0 => Arc::new(NoClientSessionStorage {}),
+            n => ClientSessionMemoryCache::new(n),
+        };
+        client_config.enable_tickets = sessions > 0;
 
         client_config
     }
@@ -50,15 +58,25 @@ mod native_tls_cfgs {
     pub static NO_SNI_CLIENT_CFG: Lazy<TlsConnector> =
         Lazy::new(|| TlsConnector::builder().use_sni(false).build().unwrap());
     pub static CLIENT_CFG: Lazy<TlsConnector> = Lazy::new(|| TlsConnector::new().unwrap());
+
+    // native-tls leaves session resumption to the platform library.
+    pub fn client_config(sni: bool, _sessions: usize) -> TlsConnector {
+        if sni {
+            CLIENT_CFG.clone()
+        } else {
+            NO_SNI_CLIENT_CFG.clone()
+        }
+    }
 }
 
 #[cfg(feature = "doh-rustls")]
-use rustls_cfgs::{CLIENT_CFG, NO_SNI_CLIENT_CFG};
+use rustls_cfgs::client_config;
 
 #[cfg(feature = "doh-native-tls")]
-use native_tls_cfgs::{CLIENT_CFG, NO_SNI_CLIENT_CFG};
+use native_tls_cfgs::client_config;
 
 use super::{ConnInitiator, QHandle, QHandleError, Result};
+use crate::router::deadline;
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use domain::base::Message;
@@ -73,6 +91,7 @@ use std::{
 #[derive(Clone)]
 pub struct Https {
     client: PostClient,
+    addr: IpAddr,
 }
 
 static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
@@ -82,23 +101,30 @@ impl Https {
     // We *CANNOT* reuse the client *WITH* connection pool because if the network changes, *connection* inside client pool of each client remains the same, and cloning them inevitably leads to no reconnection but using stale connections.
     // However, we are able to disable the connection pool and use the client.
     // We cannot store ClientBuilder because it is not Clone.
-    pub async fn new(uri: String, addr: IpAddr, proxy: Option<String>, sni: bool) -> Result<Self> {
+    // Up to `sessions` TLS sessions are kept for the connections to resume.
+    pub async fn new(
+        uri: String,
+        addr: IpAddr,
+        proxy: Option<String>,
+        sni: bool,
+        sessions: usize,
+    ) -> Result<Self> {
         let uri = Url::from_str(&uri).map_err(|_| QHandleError::InvalidUri(uri))?;
-        // Check domain validness
-        let _ = uri
-            .domain()
-            .ok_or_else(|| QHandleError::InvalidDomain(uri.clone()))?;
-
-        // This has already been checked and it is safe to unwrap
-        let domain = uri.domain().unwrap();
-        let client = Client::builder()
+        let client = match (uri.domain(), uri.host_str()) {
             // The port in socket addr doesn't take effect here per documentation
-            .resolve(domain, SocketAddr::new(addr, 0))
-            .use_preconfigured_tls(if sni {
-                CLIENT_CFG.clone()
-            } else {
-                NO_SNI_CLIENT_CFG.clone()
-            })
+            (Some(domain), _) => Client::builder().resolve(domain, SocketAddr::new(addr, 0)),
+            // Servers given by addresses are connected to directly, with their certificates verified against the addresses.
+            // There is no name to resolve to `addr` then, so the two have to be the same.
+            (None, Some(host)) => {
+                if host.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>().ok() != Some(addr) {
+                    return Err(QHandleError::AddrMismatch(uri, addr));
+                }
+                Client::builder()
+            }
+            (None, None) => return Err(QHandleError::InvalidDomain(uri)),
+        };
+        let client = client
+            .use_preconfigured_tls(client_config(sni, sessions))
             .https_only(true)
             .user_agent(APP_USER_AGENT)
             .connect_timeout(Duration::from_secs(3))
@@ -113,6 +139,7 @@ impl Https {
         };
 
         Ok(Self {
+            addr,
             client: PostClient(
                 client.build().map_err(|_| {
                     std::io::Error::new(
@@ -137,6 +164,10 @@ impl ConnInitiator for Https {
     fn conn_type(&self) -> &'static str {
         "HTTPS"
     }
+
+    fn addr(&self) -> Option<IpAddr> {
+        Some(self.addr)
+    }
 }
 
 #[derive(Clone)]
@@ -150,13 +181,16 @@ impl QHandle for PostClient {
         msg.header_mut().set_id(0);
 
         let body: reqwest::Body = msg.into_octets().freeze().into();
-        let res = self
+        let mut req = self
             .0
             .post(self.1.clone())
             .header("content-type", "application/dns-message")
-            .body(body)
-            .send()
-            .await?;
+            .body(body);
+        // The HTTP client gives up at the deadline of the query as well, connecting included.
+        if let Some(remaining) = deadline::remaining() {
+            req = req.timeout(remaining);
+        }
+        let res = req.send().await?;
 
         if res.status().is_success() {
             let res = res.bytes().await?;
@@ -171,3 +205,22 @@ impl QHandle for PostClient {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Https;
+
+    #[tokio::test]
+    async fn addr_literal() {
+        for uri in ["https://1.1.1.1/dns-query", "https://[2606:4700::1111]/dns-query"] {
+            let ip = if uri.contains('[') { "2606:4700::1111" } else { "1.1.1.1" };
+            assert!(Https::new(uri.into(), ip.parse().unwrap(), None, true, 0)
+                .await
+                .is_ok());
+            // The address the server is given by is the one connected to, so another one can't be asked for.
+            assert!(Https::new(uri.into(), "8.8.8.8".parse().unwrap(), None, true, 0)
+                .await
+                .is_err());
+        }
+    }
+}
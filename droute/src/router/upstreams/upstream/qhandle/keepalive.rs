@@ -0,0 +1,79 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// EDNS TCP keepalive (RFC 7828): queries over TCP and TLS ask the servers how long they keep idle connections open,
+// so that the connections are reused for as long as the servers allow rather than for a fixed period.
+
+use crate::utils::{edns_option, set_edns_option, strip_edns_option};
+use bytes::Bytes;
+use domain::base::Message;
+use std::time::Duration;
+
+// Code of the edns-tcp-keepalive option
+const KEEPALIVE: u16 = 11;
+
+// The query asking for the idle timeout of the server. Queries without EDNS are left as they are.
+pub(super) fn ask(msg: &Message<Bytes>) -> Message<Bytes> {
+    match msg.opt() {
+        Some(_) => set_edns_option(msg, KEEPALIVE, &[]).unwrap_or_else(|_| msg.clone()),
+        None => msg.clone(),
+    }
+}
+
+// The idle timeout told by the server, along with the response stripped of the option, which only concerns the connection.
+pub(super) fn take(resp: Message<Bytes>) -> (Message<Bytes>, Option<Duration>) {
+    let value = match edns_option(&resp, KEEPALIVE) {
+        Ok(Some(value)) => value,
+        _ => return (resp, None),
+    };
+    // The timeout is in units of 100 milliseconds.
+    let timeout = <[u8; 2]>::try_from(value.as_slice())
+        .ok()
+        .map(|t| Duration::from_millis(u64::from(u16::from_be_bytes(t)) * 100));
+    (strip_edns_option(&resp, KEEPALIVE).unwrap_or(resp), timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ask, take, KEEPALIVE};
+    use crate::utils::{edns_option, set_edns_option};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, Message, MessageBuilder, Rtype};
+    use std::{str::FromStr, time::Duration};
+
+    fn query() -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        builder.into_message()
+    }
+
+    #[test]
+    fn keepalive() {
+        // Only queries with EDNS ask for it.
+        assert!(ask(&query()).opt().is_none());
+        let edns = set_edns_option(&query(), 3, &[]).unwrap();
+        assert_eq!(edns_option(&ask(&edns), KEEPALIVE).unwrap(), Some(vec![]));
+
+        let resp = set_edns_option(&query(), KEEPALIVE, &100u16.to_be_bytes()).unwrap();
+        let (resp, timeout) = take(resp);
+        assert_eq!(timeout, Some(Duration::from_secs(10)));
+        assert_eq!(edns_option(&resp, KEEPALIVE).unwrap(), None);
+        assert_eq!(take(resp).1, None);
+    }
+}
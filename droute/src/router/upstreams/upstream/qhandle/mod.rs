@@ -13,15 +13,24 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+pub mod ddr;
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
 pub mod https;
+mod keepalive;
 #[cfg_attr(target_pointer_width = "64", path = "qos_governor.rs")]
 #[cfg_attr(not(target_pointer_width = "64"), path = "qos_none.rs")]
 mod qos;
+pub mod system;
+pub mod tcp;
 #[cfg(any(feature = "dot-rustls", feature = "dot-native-tls"))]
 pub mod tls;
 pub mod udp;
 
+use crate::{
+    errors::ErrorKind,
+    router::deadline,
+    runtime::{timeout, Elapsed},
+};
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use deadpool::{
@@ -33,9 +42,13 @@ use once_cell::sync::Lazy;
 use qos::QosPolicy;
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
 use reqwest::{StatusCode, Url};
-use std::{str::FromStr, time::Duration};
+use std::{
+    net::IpAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use thiserror::Error;
-use tokio::time::{error::Elapsed, timeout};
 
 const MAX_ERROR_TOLERANCE: u8 = 2;
 const WAIT_TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
@@ -58,6 +71,11 @@ pub trait ConnInitiator: Send + Sync + 'static {
     async fn create(&self) -> std::io::Result<Self::Connection>;
 
     fn conn_type(&self) -> &'static str;
+
+    // Address of the server connected to, if known
+    fn addr(&self) -> Option<IpAddr> {
+        None
+    }
 }
 
 // A local ConnInitiator wrapper
@@ -101,6 +119,45 @@ pub trait QHandle: Send + Sync {
     async fn reusable(&self) -> managed::RecycleResult<std::io::Error> {
         Ok(())
     }
+
+    // Establish connections ahead of the queries, so that the first of them don't wait for the handshakes.
+    // Connections are otherwise established lazily on the first queries.
+    async fn warmup(&self) -> Result<()> {
+        Ok(())
+    }
+
+    // Drop the connections established, which are likely stale after the network changed.
+    async fn reset(&self) {}
+
+    // Address of the server queried, by which it is skipped if its family is unavailable
+    fn addr(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+// Upstream asked to warm up, which is warmed up again once reset.
+pub struct Warm(pub Arc<dyn QHandle>);
+
+#[async_trait]
+impl QHandle for Warm {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        self.0.query(msg).await
+    }
+
+    async fn warmup(&self) -> Result<()> {
+        self.0.warmup().await
+    }
+
+    async fn reset(&self) {
+        self.0.reset().await;
+        if let Err(e) = self.0.warmup().await {
+            log::warn!("failed to warm up upstream again: {}", e);
+        }
+    }
+
+    fn addr(&self) -> Option<IpAddr> {
+        self.0.addr()
+    }
 }
 
 pub type Result<T> = std::result::Result<T, QHandleError>;
@@ -108,7 +165,7 @@ pub type Result<T> = std::result::Result<T, QHandleError>;
 /// Error related to client pools
 #[derive(Debug, Error)]
 pub enum QHandleError {
-    /// Error forwarded from `tokio::time::error`. This indicates a timeout probably.
+    /// The query or the connection timed out.
     #[error(transparent)]
     TimeError(#[from] Elapsed),
 
@@ -135,6 +192,10 @@ pub enum QHandleError {
     #[error("the URL '{0}' doesn't contain a valid domain")]
     InvalidDomain(Url),
 
+    #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+    #[error("the URL '{0}' is given by an address other than {1}")]
+    AddrMismatch(Url, IpAddr),
+
     #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
     #[error("unsuccessful HTTP code: {0}")]
     FailedHttp(StatusCode),
@@ -150,11 +211,44 @@ pub enum QHandleError {
     Throttled,
 }
 
+impl QHandleError {
+    /// The category of the error
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::TimeError(_) | Self::PoolRunError(managed::PoolError::Timeout(_)) => {
+                ErrorKind::Timeout
+            }
+            Self::IoError(e) if e.kind() == std::io::ErrorKind::TimedOut => ErrorKind::Timeout,
+            Self::IoError(_) | Self::PoolRunError(_) => ErrorKind::Network,
+            Self::PoolBuildError(_) => ErrorKind::Config,
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            Self::ReqwestError(e) if e.is_timeout() => ErrorKind::Timeout,
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            Self::ReqwestError(e) if e.is_builder() => ErrorKind::Config,
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            Self::ReqwestError(e) if e.is_decode() || e.is_status() => ErrorKind::Protocol,
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            Self::ReqwestError(_) => ErrorKind::Network,
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            Self::InvalidUri(_) | Self::InvalidDomain(_) | Self::AddrMismatch(..) => {
+                ErrorKind::Config
+            }
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            Self::FailedHttp(_) => ErrorKind::Protocol,
+            #[cfg(any(feature = "dot-native-tls"))]
+            Self::NativeTlsError(_) => ErrorKind::Network,
+            Self::ShortBuf(_) => ErrorKind::Protocol,
+            Self::Throttled => ErrorKind::Policy,
+        }
+    }
+}
+
 // For HTTPS connections, ConnPool enables parallelism
 pub struct ConnPool<T: ConnInitiator> {
     pool: Pool<ConnInitWrapper<T>>,
     timeout: Duration,
     ratelimiter: QosPolicy,
+    addr: Option<IpAddr>,
 }
 
 impl<T: ConnInitiator> ConnPool<T> {
@@ -165,6 +259,7 @@ impl<T: ConnInitiator> ConnPool<T> {
         ratelimiter: QosPolicy,
     ) -> std::result::Result<Self, BuildError<<ConnInitWrapper<T> as Manager>::Error>> {
         Ok(Self {
+            addr: initiator.addr(),
             pool: Pool::builder(ConnInitWrapper(initiator))
                 .max_size(max_pool_size)
                 .wait_timeout(WAIT_TIMEOUT)
@@ -180,7 +275,17 @@ impl<T: ConnInitiator> ConnPool<T> {
 impl<T: ConnInitiator> QHandle for ConnPool<T> {
     async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
         if self.ratelimiter.check() {
-            let mut conn = self.pool.get().await?;
+            // With a deadline on the query, waiting for a connection and the query itself share what is left of it, up to the timeout.
+            // Otherwise the query has the whole timeout after the connection is got, as the pool bounds the wait on its own.
+            let (mut conn, budget) = match deadline::remaining() {
+                Some(d) => {
+                    let start = Instant::now();
+                    let budget = d.min(self.timeout);
+                    let conn = timeout(budget, self.pool.get()).await??;
+                    (conn, budget.saturating_sub(start.elapsed()))
+                }
+                None => (self.pool.get().await?, self.timeout),
+            };
 
             log::debug!(
                 "got connection from pool; recycled {} times",
@@ -188,7 +293,7 @@ impl<T: ConnInitiator> QHandle for ConnPool<T> {
             );
 
             // Use flatten in the future
-            match timeout(self.timeout, conn.0.query(msg)).await {
+            match timeout(budget, conn.0.query(msg)).await {
                 // Within the timeout, query was successful
                 Ok(Ok(m)) => {
                     conn.1 = 0;
@@ -214,4 +319,19 @@ impl<T: ConnInitiator> QHandle for ConnPool<T> {
     async fn reusable(&self) -> managed::RecycleResult<std::io::Error> {
         Ok(())
     }
+
+    // One connection is created and put back into the pool.
+    async fn warmup(&self) -> Result<()> {
+        drop(timeout(self.timeout, self.pool.get()).await??);
+        Ok(())
+    }
+
+    // Connections in use are put back and reused, as they can't be told apart from the new ones.
+    async fn reset(&self) {
+        self.pool.retain(|_, _| false);
+    }
+
+    fn addr(&self) -> Option<IpAddr> {
+        self.addr
+    }
 }
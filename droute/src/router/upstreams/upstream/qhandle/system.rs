@@ -0,0 +1,217 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{udp::Udp, ConnPool, QHandle, QHandleError, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use domain::base::Message;
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU32,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+// Time after which the resolver configuration is checked again for changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Name servers listed in the resolver configuration, in the order they appear.
+/// Loopback ones are left out, as they are likely ourselves.
+pub fn nameservers(conf: &str, port: u16) -> Vec<SocketAddr> {
+    conf.lines()
+        .map(|l| l.split(|c| c == '#' || c == ';').next().unwrap_or_default())
+        .filter_map(|l| {
+            let mut fields = l.split_whitespace();
+            (fields.next() == Some("nameserver"))
+                .then(|| fields.next())
+                .flatten()
+        })
+        .filter_map(|addr| addr.parse::<IpAddr>().ok())
+        .filter(|ip| !ip.is_loopback())
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect()
+}
+
+struct State {
+    modified: Option<SystemTime>,
+    // Never checked if `None`
+    checked: Option<Instant>,
+    // Whether the configuration failed to be read last time, which is warned about once until it is read again
+    failing: bool,
+    servers: Arc<Vec<(SocketAddr, Arc<ConnPool<Udp>>)>>,
+}
+
+/// Upstream forwarding to the name servers currently configured on the system, which are updated once the network changes.
+pub struct System {
+    path: PathBuf,
+    port: u16,
+    max_pool_size: usize,
+    timeout: Duration,
+    ratelimit: Option<NonZeroU32>,
+    state: RwLock<State>,
+}
+
+impl System {
+    /// Create the upstream following the resolver configuration at `path`, e.g. `/etc/resolv.conf`.
+    pub fn new(
+        path: PathBuf,
+        port: u16,
+        max_pool_size: usize,
+        timeout: Duration,
+        ratelimit: Option<NonZeroU32>,
+    ) -> Self {
+        Self {
+            path,
+            port,
+            max_pool_size,
+            timeout,
+            ratelimit,
+            state: RwLock::new(State {
+                modified: None,
+                checked: None,
+                failing: false,
+                servers: Arc::new(Vec::new()),
+            }),
+        }
+    }
+
+    // Pick up the name servers anew if the configuration has changed since last checked.
+    async fn servers(&self) -> Result<Arc<Vec<(SocketAddr, Arc<ConnPool<Udp>>)>>> {
+        let last = {
+            let state = self.state.read().unwrap();
+            if matches!(state.checked, Some(t) if t.elapsed() < CHECK_INTERVAL) {
+                return Ok(state.servers.clone());
+            }
+            state.modified
+        };
+
+        let modified = tokio::fs::metadata(&self.path).await?.modified().ok();
+        if modified.is_some() && modified == last {
+            let mut state = self.state.write().unwrap();
+            state.checked = Some(Instant::now());
+            state.failing = false;
+            return Ok(state.servers.clone());
+        }
+
+        let addrs = nameservers(&tokio::fs::read_to_string(&self.path).await?, self.port);
+        let old = self.state.read().unwrap().servers.clone();
+        let mut servers = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            // Keep the connections to the servers still listed.
+            let pool = match old.iter().find(|(a, _)| *a == addr) {
+                Some((_, pool)) => pool.clone(),
+                None => Arc::new(ConnPool::new(
+                    Udp::new(addr, false).await?,
+                    self.max_pool_size,
+                    self.timeout,
+                    self.ratelimit.into(),
+                )?),
+            };
+            servers.push((addr, pool));
+        }
+        if servers
+            .iter()
+            .map(|(a, _)| a)
+            .ne(old.iter().map(|(a, _)| a))
+        {
+            log::warn!(
+                "system name servers changed to {:?}",
+                servers.iter().map(|(a, _)| a).collect::<Vec<_>>()
+            );
+        }
+
+        let servers = Arc::new(servers);
+        let mut state = self.state.write().unwrap();
+        *state = State {
+            modified,
+            checked: Some(Instant::now()),
+            failing: false,
+            servers: servers.clone(),
+        };
+        Ok(servers)
+    }
+}
+
+#[async_trait]
+impl QHandle for System {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let servers = match self.servers().await {
+            Ok(servers) => servers,
+            // Keep using the servers known if the configuration is temporarily unavailable, e.g. while it is being rewritten,
+            // and only check it again after the interval, warning once until it is read again.
+            Err(e) => {
+                let mut state = self.state.write().unwrap();
+                state.checked = Some(Instant::now());
+                if !std::mem::replace(&mut state.failing, true) {
+                    log::warn!(
+                        "failed to read name servers from {}: {}",
+                        self.path.display(),
+                        e
+                    );
+                }
+                state.servers.clone()
+            }
+        };
+
+        // Fall back to the next server listed on failure, like the system resolver does.
+        let mut err = None;
+        for (addr, pool) in servers.iter() {
+            match pool.query(msg).await {
+                Ok(resp) => return Ok(resp),
+                Err(e) => {
+                    log::debug!("system name server {} failed: {}", addr, e);
+                    err = Some(e);
+                }
+            }
+        }
+        Err(err.unwrap_or_else(|| {
+            QHandleError::IoError(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no name server found in {}", self.path.display()),
+            ))
+        }))
+    }
+
+    // The name servers are likely changed along with the network, so the configuration is checked again on the next query.
+    async fn reset(&self) {
+        let servers = {
+            let mut state = self.state.write().unwrap();
+            state.checked = None;
+            state.servers.clone()
+        };
+        for (_, pool) in servers.iter() {
+            pool.reset().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::nameservers;
+
+    #[test]
+    fn parse_resolv_conf() {
+        let conf = "# Generated by NetworkManager\nsearch lan\nnameserver 192.168.1.1\nnameserver 127.0.0.53\nnameserver fe80::1%eth0\nnameserver 2001:db8::1 # router\noptions edns0\n";
+        assert_eq!(
+            nameservers(conf, 53),
+            vec![
+                "192.168.1.1:53".parse().unwrap(),
+                "[2001:db8::1]:53".parse().unwrap()
+            ]
+        );
+        assert!(nameservers("nameserver\n", 53).is_empty());
+    }
+}
@@ -0,0 +1,285 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{keepalive, qos::QosPolicy, QHandle, QHandleError, Result};
+use crate::runtime::{spawn, timeout};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use domain::base::Message;
+use futures::future::{self, AbortHandle};
+use log::debug;
+use socket2::{Socket, TcpKeepalive};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::{Error, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::{oneshot, Mutex},
+};
+
+type Pending = Arc<std::sync::Mutex<HashMap<u16, oneshot::Sender<Message<Bytes>>>>>;
+// Time of the latest response and the idle timeout the server told in it
+type Keepalive = Arc<std::sync::Mutex<Option<(Instant, Duration)>>>;
+
+/// Client for plain DNS over TCP. Queries are pipelined over a fixed number of persistent connections, which are reestablished on need.
+pub struct Tcp {
+    addr: SocketAddr,
+    conns: Vec<Mutex<Option<Arc<TcpConn>>>>,
+    next: AtomicUsize,
+    timeout: Duration,
+    reuse_timeout: Duration,
+    max_reuse: usize,
+    ratelimiter: QosPolicy,
+}
+
+impl Tcp {
+    /// Create a new TCP client with the given remote server address. Connections are established lazily.
+    pub fn new(
+        addr: SocketAddr,
+        connections: usize,
+        timeout: Duration,
+        reuse_timeout: Duration,
+        max_reuse: usize,
+        ratelimiter: QosPolicy,
+    ) -> Self {
+        Self {
+            addr,
+            conns: (0..connections.max(1)).map(|_| Mutex::new(None)).collect(),
+            next: AtomicUsize::new(0),
+            timeout,
+            reuse_timeout,
+            max_reuse,
+            ratelimiter,
+        }
+    }
+
+    // Get a usable connection in a round-robin manner, (re)connecting if necessary.
+    async fn conn(&self) -> Result<Arc<TcpConn>> {
+        let slot = &self.conns[self.next.fetch_add(1, Ordering::Relaxed) % self.conns.len()];
+        let mut slot = slot.lock().await;
+        match &*slot {
+            Some(conn) if conn.usable(self.reuse_timeout, self.max_reuse) => Ok(conn.clone()),
+            _ => {
+                debug!("establishing new TCP connection to {}", self.addr);
+                let conn = Arc::new(TcpConn::connect(self.addr).await?);
+                *slot = Some(conn.clone());
+                Ok(conn)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl QHandle for Tcp {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        if !self.ratelimiter.check() {
+            return Err(QHandleError::Throttled);
+        }
+        timeout(self.timeout, async { self.conn().await?.query(msg).await }).await?
+    }
+
+    // Every persistent connection is established.
+    async fn warmup(&self) -> Result<()> {
+        for _ in 0..self.conns.len() {
+            timeout(self.timeout, self.conn()).await??;
+        }
+        Ok(())
+    }
+
+    async fn reset(&self) {
+        for slot in &self.conns {
+            *slot.lock().await = None;
+        }
+    }
+
+    fn addr(&self) -> Option<IpAddr> {
+        Some(self.addr.ip())
+    }
+}
+
+// A single persistent TCP connection with queries pipelined on.
+struct TcpConn {
+    writer: Mutex<OwnedWriteHalf>,
+    pending: Pending,
+    closed: Arc<AtomicBool>,
+    keepalive: Keepalive,
+    established: Instant,
+    sent: AtomicUsize,
+    reader: AbortHandle,
+}
+
+impl Drop for TcpConn {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+// Remove the pending query on drop, e.g. on timeout.
+struct PendingGuard<'a> {
+    pending: &'a Pending,
+    id: u16,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl TcpConn {
+    async fn connect(addr: SocketAddr) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+
+        // Good default as reqwest also sets this.
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(60));
+        let socket: Socket = stream.into_std()?.into();
+        socket.set_tcp_keepalive(&keepalive)?;
+        let stream = TcpStream::from_std(socket.into())?;
+
+        let (reader, writer) = stream.into_split();
+        let pending = Pending::default();
+        let closed = Arc::new(AtomicBool::new(false));
+        let keepalive = Keepalive::default();
+        let reader = {
+            let (pending, closed, keepalive) = (pending.clone(), closed.clone(), keepalive.clone());
+            let (task, handle) = future::abortable(async move {
+                if let Err(e) = read_responses(reader, &pending, &keepalive).await {
+                    debug!("TCP connection closed: {}", e);
+                }
+                closed.store(true, Ordering::Relaxed);
+                // Wake up all the queries waiting.
+                pending.lock().unwrap().clear();
+            });
+            spawn(async move {
+                let _ = task.await;
+            });
+            handle
+        };
+
+        Ok(Self {
+            writer: Mutex::new(writer),
+            pending,
+            closed,
+            keepalive,
+            established: Instant::now(),
+            sent: AtomicUsize::new(0),
+            reader,
+        })
+    }
+
+    // TCP connections all expire a certain amount of time after they were established, as the server may have got a timeout timer set on our connections.
+    // Servers telling their idle timeouts keep the connections for as long as they are not idle for that long instead.
+    // Most of the servers also limit the number of queries on a single connection.
+    fn usable(&self, reuse_timeout: Duration, max_reuse: usize) -> bool {
+        let alive = match *self.keepalive.lock().unwrap() {
+            Some((last, timeout)) => last.elapsed() < timeout,
+            None => self.established.elapsed() < reuse_timeout,
+        };
+        !self.closed.load(Ordering::Relaxed) && self.sent.load(Ordering::Relaxed) < max_reuse && alive
+    }
+
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let msg = keepalive::ask(msg);
+        let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
+        let (tx, rx) = oneshot::channel();
+        // Pick an ID not used by any other query in flight on this connection.
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+            loop {
+                msg.header_mut().set_random_id();
+                if let Entry::Vacant(e) = pending.entry(msg.header().id()) {
+                    e.insert(tx);
+                    break msg.header().id();
+                }
+            }
+        };
+        let _guard = PendingGuard {
+            pending: &self.pending,
+            id,
+        };
+        let msg = msg.for_slice();
+        self.sent.fetch_add(1, Ordering::Relaxed);
+
+        // Prefix our payload with length per RFC.
+        let len = u16::try_from(msg.as_slice().len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "request too long"))?
+            .to_be_bytes();
+
+        let written = async {
+            let mut writer = self.writer.lock().await;
+            writer.write_all(&len).await?;
+            writer.write_all(msg.as_slice()).await?;
+            writer.flush().await
+        }
+        .await;
+        if let Err(e) = written {
+            self.closed.store(true, Ordering::Relaxed);
+            return Err(e.into());
+        }
+
+        let answer = rx
+            .await
+            .map_err(|_| Error::new(ErrorKind::ConnectionAborted, "TCP connection closed"))?;
+        if !answer.is_answer(&msg) {
+            return Err(Error::new(ErrorKind::InvalidData, "mismatched response").into());
+        }
+        Ok(answer)
+    }
+}
+
+// Dispatch the responses to the queries waiting by ID.
+async fn read_responses(
+    mut reader: OwnedReadHalf,
+    pending: &Pending,
+    keepalive: &Keepalive,
+) -> std::io::Result<()> {
+    loop {
+        // Get the length of the response
+        let mut len = [0; 2];
+        reader.read_exact(&mut len).await?;
+        let len: usize = u16::from_be_bytes(len).into();
+
+        // Read the response
+        let mut buf = BytesMut::with_capacity(len);
+        buf.resize(len, 0);
+        reader.read_exact(&mut buf).await?;
+
+        // We ignore garbage since there is a timer on every query.
+        let answer = match Message::from_octets(buf.freeze()) {
+            Ok(answer) => answer,
+            Err(_) => continue,
+        };
+        let (answer, timeout) = keepalive::take(answer);
+        if let Some(timeout) = timeout {
+            *keepalive.lock().unwrap() = Some((Instant::now(), timeout));
+        }
+        if let Some(tx) = pending.lock().unwrap().remove(&answer.header().id()) {
+            // The query may have timed out.
+            let _ = tx.send(answer);
+        }
+    }
+}
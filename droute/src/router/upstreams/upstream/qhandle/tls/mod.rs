@@ -18,7 +18,7 @@
 #[cfg(any(feature = "dot-rustls", feature = "dot-native-tls"))]
 mod connector;
 
-use super::{ConnInitiator, QHandle, Result};
+use super::{keepalive, ConnInitiator, QHandle, Result};
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 pub use connector::Tls;
@@ -26,7 +26,7 @@ use connector::TlsStream;
 use deadpool::managed::{self, RecycleError};
 use domain::base::Message;
 use log::debug;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
@@ -35,8 +35,15 @@ use tokio::{
 
 // Instant: Time the connection established
 // usize: Number of query sent
+// Option<(Instant, Duration)>: Time of the latest response and the idle timeout the server told in it
+type Connection = (
+    Mutex<(TlsStream<TcpStream>, Instant, usize, Option<(Instant, Duration)>)>,
+    u64,
+    usize,
+);
+
 #[async_trait]
-impl QHandle for (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize) {
+impl QHandle for Connection {
     async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
         let mut guard = self.0.lock().await;
 
@@ -46,9 +53,10 @@ impl QHandle for (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize) {
             guard.2 += 1;
         }
 
-        let stream = &mut guard.0;
+        let (stream, _, _, timeout) = &mut *guard;
 
         // Randomnize the message
+        let msg = keepalive::ask(msg);
         let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
         msg.header_mut().set_random_id();
         let msg = msg.for_slice();
@@ -89,6 +97,10 @@ impl QHandle for (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize) {
                 continue;
             }
 
+            let (answer, told) = keepalive::take(answer);
+            if let Some(told) = told {
+                *timeout = Some((Instant::now(), told));
+            }
             return Ok(answer);
         }
     }
@@ -103,7 +115,12 @@ impl QHandle for (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize) {
             log::debug!("TlsStream has reached maximum number of queries that can be sent on the underlying persistent TCP connection.");
             return Err(RecycleError::StaticMessage("max reuse TCP queries reached"));
         }
-        if guard.1.elapsed().as_millis() >= self.1.into() {
+        // Servers telling their idle timeouts keep the connections for as long as they are not idle for that long instead.
+        let expired = match guard.3 {
+            Some((last, timeout)) => last.elapsed() >= timeout,
+            None => guard.1.elapsed().as_millis() >= self.1.into(),
+        };
+        if expired {
             guard.0.shutdown().await?;
             log::debug!("TlsStream has reached period dcompass will keep the underlying TCP persistent connections open.");
             return Err(RecycleError::StaticMessage("TCP reuse timeout reached"));
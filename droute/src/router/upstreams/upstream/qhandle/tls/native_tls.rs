@@ -17,7 +17,10 @@ use super::{ConnInitiator, Result};
 use async_trait::async_trait;
 use native_tls::{Protocol, TlsConnector as NativeTlsConnector};
 use socket2::{Socket, TcpKeepalive};
-use std::{net::SocketAddr, time::Instant};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
 use tokio::{net::TcpStream, sync::Mutex};
 use tokio_native_tls::TlsConnector;
 pub use tokio_native_tls::TlsStream;
@@ -34,12 +37,15 @@ pub struct Tls {
 
 impl Tls {
     /// Create a new TLS connection creator instance. with the given remote server address.
+    /// native-tls leaves session resumption to the platform library and has no early data, so `sessions` and `early_data` take no effect.
     pub fn new(
         domain: String,
         addr: SocketAddr,
         sni: bool,
         tcp_reuse_timeout: u64,
         max_reuse_tcp_queries: usize,
+        _sessions: usize,
+        _early_data: bool,
     ) -> Result<Self> {
         Ok(Self {
             client: NativeTlsConnector::builder()
@@ -57,13 +63,13 @@ impl Tls {
 
 #[async_trait]
 impl ConnInitiator for Tls {
-    type Connection = (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize);
+    type Connection = super::Connection;
 
     async fn create(&self) -> std::io::Result<Self::Connection> {
         let mut stream = TcpStream::connect(self.addr).await?;
 
         // Good default as reqwest also sets this
-        let keepalive = TcpKeepalive::new().with_time(std::time::Duration::from_secs(60));
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(60));
         let socket: Socket = stream.into_std()?.into();
         socket.set_tcp_keepalive(&keepalive)?;
         stream = TcpStream::from_std(socket.into())?;
@@ -76,6 +82,7 @@ impl ConnInitiator for Tls {
                     .map_err(|e| std::io::Error::new(std::io::ErrorKind::WouldBlock, e))?,
                 Instant::now(),
                 0,
+                None,
             )),
             self.tcp_reuse_timeout,
             self.max_reuse_tcp_queries,
@@ -85,4 +92,8 @@ impl ConnInitiator for Tls {
     fn conn_type(&self) -> &'static str {
         "TLS"
     }
+
+    fn addr(&self) -> Option<IpAddr> {
+        Some(self.addr.ip())
+    }
 }
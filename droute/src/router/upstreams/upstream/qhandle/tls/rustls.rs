@@ -15,14 +15,21 @@
 
 use super::{ConnInitiator, Result};
 use async_trait::async_trait;
-use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
+use rustls::{
+    client::{ClientSessionMemoryCache, NoClientSessionStorage},
+    ClientConfig, OwnedTrustAnchor, RootCertStore,
+};
 use socket2::{Socket, TcpKeepalive};
-use std::{net::SocketAddr, sync::Arc, time::Instant};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::{net::TcpStream, sync::Mutex};
 pub use tokio_rustls::client::TlsStream;
 use tokio_rustls::TlsConnector;
 
-fn create_client_config(sni: &bool) -> ClientConfig {
+fn create_client_config(sni: &bool, sessions: usize, early_data: bool) -> ClientConfig {
     let mut root_store = RootCertStore::empty();
     root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
         OwnedTrustAnchor::from_subject_spki_name_constraints(
@@ -39,6 +46,14 @@ fn create_client_config(sni: &bool) -> ClientConfig {
 
     client_config.enable_sni = *sni; // Disable SNI on need.
 
+    // Reconnections resume the sessions kept instead of going through the full handshake again.
+    client_config.session_storage = match sessions {
+        0 => Arc::new(NoClientSessionStorage {}),
+        n => ClientSessionMemoryCache::new(n),
+    };
+    client_config.enable_tickets = sessions > 0;
+    client_config.enable_early_data = early_data;
+
     client_config
 }
 
@@ -54,15 +69,21 @@ pub struct Tls {
 
 impl Tls {
     /// Create a new TLS connection creator instance. with the given remote server address.
+    /// Up to `sessions` sessions are kept for resumption, and the first query on a resumed connection is sent in TLS 1.3 early data (0-RTT) if `early_data` is set and the server accepts it.
     pub fn new(
         domain: String,
         addr: SocketAddr,
         sni: bool,
         tcp_reuse_timeout: u64,
         max_reuse_tcp_queries: usize,
+        sessions: usize,
+        early_data: bool,
     ) -> Result<Self> {
         Ok(Self {
-            client: TlsConnector::from(Arc::new(create_client_config(&sni))),
+            client: TlsConnector::from(Arc::new(create_client_config(
+                &sni, sessions, early_data,
+            )))
+            .early_data(early_data),
             addr,
             domain,
             tcp_reuse_timeout,
@@ -73,13 +94,13 @@ impl Tls {
 
 #[async_trait]
 impl ConnInitiator for Tls {
-    type Connection = (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize);
+    type Connection = super::Connection;
 
     async fn create(&self) -> std::io::Result<Self::Connection> {
         let mut stream = TcpStream::connect(self.addr).await?;
 
         // Good default as reqwest also sets this.
-        let keepalive = TcpKeepalive::new().with_time(std::time::Duration::from_secs(60));
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(60));
         let socket: Socket = stream.into_std()?.into();
         socket.set_tcp_keepalive(&keepalive)?;
         stream = TcpStream::from_std(socket.into())?;
@@ -95,6 +116,7 @@ impl ConnInitiator for Tls {
                     .map_err(|e| std::io::Error::new(std::io::ErrorKind::WouldBlock, e))?,
                 Instant::now(),
                 0,
+                None,
             )),
             self.tcp_reuse_timeout,
             self.max_reuse_tcp_queries,
@@ -104,4 +126,8 @@ impl ConnInitiator for Tls {
     fn conn_type(&self) -> &'static str {
         "TLS"
     }
+
+    fn addr(&self) -> Option<IpAddr> {
+        Some(self.addr.ip())
+    }
 }
@@ -13,41 +13,254 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::MAX_LEN;
+use crate::{
+    runtime::{spawn, timeout},
+    MAX_LEN,
+};
 
 use super::{ConnInitiator, QHandle, Result};
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
-use domain::base::Message;
-use std::net::SocketAddr;
+use domain::base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use tokio::net::UdpSocket;
 
+// Names under `invalid.` never resolve, and resolvers answer them without any recursion (RFC 6761).
+// The round trip time of the probe is thus the least time for a legitimate answer to arrive.
+static PROBE_QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
+    let name = Dname::<Bytes>::from_str("dcompass-probe.invalid").unwrap();
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(512)).unwrap();
+    builder.header_mut().set_rd(true);
+    let mut builder = builder.question();
+    builder.push((&name, Rtype::A)).unwrap();
+    builder.into_message()
+});
+
+// Time after which the round trip time is probed again, in case the network has changed.
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Answers arriving within this fraction of the round trip time are considered as injected.
+// The margin accounts for jitters, while injectors on the path are usually much closer than the upstream.
+const RTT_RATIO: f64 = 0.5;
+
+const fn default_initial() -> u64 {
+    1000
+}
+
+const fn default_retries() -> u32 {
+    2
+}
+
+const fn default_backoff() -> f64 {
+    2.0
+}
+
+const fn default_jitter() -> f64 {
+    0.2
+}
+
+/// Retransmission of the queries unanswered over UDP, all within the timeout of the upstream.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Retransmit {
+    /// Time in milliseconds to wait for the answer before the first retransmission
+    #[serde(default = "default_initial")]
+    pub initial: u64,
+    /// Maximum number of retransmissions
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Factor the time to wait grows by after each retransmission
+    #[serde(default = "default_backoff")]
+    pub backoff: f64,
+    /// Fraction of the time to wait randomly added or taken, so that clients behind the same NAT don't retransmit in lockstep
+    #[serde(default = "default_jitter")]
+    pub jitter: f64,
+}
+
+impl Default for Retransmit {
+    fn default() -> Self {
+        Self {
+            initial: default_initial(),
+            retries: default_retries(),
+            backoff: default_backoff(),
+            jitter: default_jitter(),
+        }
+    }
+}
+
+impl Retransmit {
+    // Time to wait for the answer after the `n`th transmission, counting from zero.
+    fn interval(&self, n: u32) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = self.backoff.max(1.0).powi(n as i32)
+            * (1.0 + rand::thread_rng().gen_range(-jitter..=jitter));
+        Duration::from_millis(self.initial).mul_f64(factor)
+    }
+}
+
+// Round trip time to the upstream, shared by all the sockets to it.
+struct Probe {
+    addr: SocketAddr,
+    // In microseconds, zero if unknown
+    rtt: AtomicU64,
+    // In milliseconds since `epoch`
+    last: AtomicU64,
+    epoch: Instant,
+    running: AtomicBool,
+}
+
+impl Probe {
+    fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            rtt: AtomicU64::new(0),
+            last: AtomicU64::new(0),
+            epoch: Instant::now(),
+            running: AtomicBool::new(false),
+        }
+    }
+
+    // Least time for a legitimate answer to arrive.
+    fn floor(&self) -> Option<Duration> {
+        match self.rtt.load(Ordering::Relaxed) {
+            0 => None,
+            rtt => Some(Duration::from_micros(rtt).mul_f64(RTT_RATIO)),
+        }
+    }
+
+    fn stale(&self) -> bool {
+        let last = self.last.load(Ordering::Relaxed);
+        last == 0
+            || (self.epoch.elapsed().as_millis() as u64).saturating_sub(last)
+                > PROBE_INTERVAL.as_millis() as u64
+    }
+
+    // Probe in the background unless another probe is on the way.
+    fn refresh(self: &Arc<Self>) {
+        if !self.stale() || self.running.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let probe = self.clone();
+        spawn(async move {
+            match timeout(PROBE_TIMEOUT, probe.run()).await {
+                Ok(Ok(rtt)) => {
+                    log::debug!("probed round trip time to {}: {:?}", probe.addr, rtt);
+                    probe
+                        .rtt
+                        .store(rtt.as_micros().max(1) as u64, Ordering::Relaxed);
+                }
+                Ok(Err(e)) => log::warn!("failed to probe upstream {}: {}", probe.addr, e),
+                Err(_) => log::warn!("probing upstream {} timed out", probe.addr),
+            }
+            // Don't retry too often even if failed.
+            probe.last.store(
+                probe.epoch.elapsed().as_millis().max(1) as u64,
+                Ordering::Relaxed,
+            );
+            probe.running.store(false, Ordering::Relaxed);
+        });
+    }
+
+    // Measure the round trip time on a fresh socket.
+    async fn run(&self) -> Result<Duration> {
+        let socket = UdpSocket::bind(bind_addr(self.addr.is_ipv4())).await?;
+        socket.connect(self.addr).await?;
+        let mut query = Message::from_octets(BytesMut::from(PROBE_QUERY.as_slice()))?;
+        query.header_mut().set_random_id();
+        let query = query.for_slice();
+
+        let start = Instant::now();
+        socket.send(query.as_slice()).await?;
+        let mut warned = false;
+        loop {
+            let answer = recv(&socket).await?;
+            if !answer.is_answer(&query) {
+                continue;
+            }
+            // Nothing but NXDOMAIN should come back. Addresses in the answer are forged on the way, and the next one may be genuine.
+            if answer.header().rcode() != Rcode::NXDomain || answer.header_counts().ancount() > 0 {
+                if !warned {
+                    log::warn!(
+                        "probe to upstream {} was answered with forged records, the path is likely poisoned",
+                        self.addr
+                    );
+                    warned = true;
+                }
+                continue;
+            }
+            return Ok(start.elapsed());
+        }
+    }
+}
+
 /// Client instance for UDP connections
 #[derive(Clone)]
 pub struct Udp {
     addr: SocketAddr,
+    probe: Option<Arc<Probe>>,
+    retransmit: Option<Retransmit>,
 }
 
 impl Udp {
     /// Create a new UDP client creator instance. with the given remote server address.
-    pub async fn new(addr: SocketAddr) -> Result<Self> {
-        Ok(Self { addr })
+    /// With `anti_pollution`, answers arriving too early to be sent by the upstream are discarded once its round trip time is probed.
+    /// With `retransmit`, queries unanswered are sent again per the policy, otherwise they are sent only once.
+    pub async fn new(
+        addr: SocketAddr,
+        anti_pollution: bool,
+        retransmit: Option<Retransmit>,
+    ) -> Result<Self> {
+        Ok(Self {
+            addr,
+            probe: anti_pollution.then(|| Arc::new(Probe::new(addr))),
+            retransmit,
+        })
     }
 }
 
+/// A UDP socket connected to the upstream
+pub struct UdpConn {
+    socket: UdpSocket,
+    probe: Option<Arc<Probe>>,
+    retransmit: Option<Retransmit>,
+}
+
 #[async_trait]
 impl ConnInitiator for Udp {
-    type Connection = UdpSocket;
+    type Connection = UdpConn;
 
     async fn create(&self) -> std::io::Result<Self::Connection> {
         let socket = UdpSocket::bind(bind_addr(self.addr.is_ipv4())).await?;
         socket.connect(self.addr).await?;
-        Ok(socket)
+        if let Some(probe) = &self.probe {
+            probe.refresh();
+        }
+        Ok(UdpConn {
+            socket,
+            probe: self.probe.clone(),
+            retransmit: self.retransmit,
+        })
     }
 
     fn conn_type(&self) -> &'static str {
         "UDP"
     }
+
+    fn addr(&self) -> Option<IpAddr> {
+        Some(self.addr.ip())
+    }
 }
 
 fn bind_addr(is_ipv4: bool) -> SocketAddr {
@@ -58,39 +271,102 @@ fn bind_addr(is_ipv4: bool) -> SocketAddr {
     }
 }
 
+async fn recv(socket: &UdpSocket) -> Result<Message<Bytes>> {
+    loop {
+        let mut buf = BytesMut::with_capacity(MAX_LEN);
+        buf.resize(MAX_LEN, 0);
+        let len = socket.recv(&mut buf).await?;
+        buf.resize(len, 0);
+
+        // We ignore garbage since there is a timer on this whole thing.
+        if let Ok(answer) = Message::from_octets(buf.freeze()) {
+            return Ok(answer);
+        }
+    }
+}
+
 #[async_trait]
-impl QHandle for UdpSocket {
+impl QHandle for UdpConn {
     async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
         // Randomnize the message
         let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
         msg.header_mut().set_random_id();
         let msg = msg.for_slice();
 
-        self.send(msg.as_slice()).await?;
+        let floor = self.probe.as_ref().and_then(|p| p.floor());
+        let start = Instant::now();
+        self.socket.send(msg.as_slice()).await?;
 
+        // The same query (and thus the same ID) is retransmitted, so that a late answer to any of the transmissions is taken.
+        let mut sent = 0;
         loop {
-            let mut buf = BytesMut::with_capacity(MAX_LEN);
-            buf.resize(MAX_LEN, 0);
-            let len = self.recv(&mut buf).await?;
-            buf.resize(len, 0);
-
-            // We ignore garbage since there is a timer on this whole thing.
-            let answer = match Message::from_octets(buf.freeze()) {
-                Ok(answer) => answer,
-                Err(_) => continue,
+            let answer = match self.retransmit {
+                Some(retransmit) if sent <= retransmit.retries => {
+                    match timeout(retransmit.interval(sent), recv(&self.socket)).await {
+                        Ok(answer) => answer?,
+                        Err(_) => {
+                            sent += 1;
+                            if sent <= retransmit.retries {
+                                log::debug!("retransmitting query, attempt {}", sent);
+                                self.socket.send(msg.as_slice()).await?;
+                            }
+                            continue;
+                        }
+                    }
+                }
+                _ => recv(&self.socket).await?,
             };
             if !answer.is_answer(&msg) {
                 continue;
             }
+            if let Some(floor) = floor {
+                let elapsed = start.elapsed();
+                if elapsed < floor {
+                    log::warn!(
+                        "discarded answer arrived in {:?}, earlier than the upstream could reply in {:?}",
+                        elapsed,
+                        floor
+                    );
+                    continue;
+                }
+            }
             return Ok(answer);
         }
     }
 
     async fn reusable(&self) -> deadpool::managed::RecycleResult<std::io::Error> {
+        if let Some(probe) = &self.probe {
+            probe.refresh();
+        }
         // We don't care about the response of our test query because we would ignore unrelated response that up in receive loop.
-        self.send(super::DUMMY_QUERY.as_slice())
+        self.socket
+            .send(super::DUMMY_QUERY.as_slice())
             .await
             .map(|_| ())
             .map_err(deadpool::managed::RecycleError::Backend)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Retransmit;
+    use std::time::Duration;
+
+    #[test]
+    fn backoff() {
+        let retransmit = Retransmit {
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(retransmit.interval(0), Duration::from_secs(1));
+        assert_eq!(retransmit.interval(2), Duration::from_secs(4));
+
+        let jittered = Retransmit::default();
+        for _ in 0..100 {
+            let interval = jittered.interval(1);
+            assert!(
+                interval >= Duration::from_millis(1600) && interval <= Duration::from_millis(2400)
+            );
+        }
+    }
+}
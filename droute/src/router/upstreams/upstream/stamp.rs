@@ -0,0 +1,408 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! DNS stamps (`sdns://`), which encode everything needed to connect to a resolver in a single string.
+//! See <https://dnscrypt.info/stamps-specifications> for the format.
+
+#[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+use super::builder::HttpsBuilder;
+#[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+use super::builder::TlsBuilder;
+use super::builder::{UdpBuilder, UpstreamBuilder};
+use crate::errors::ErrorKind;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+use thiserror::Error;
+
+/// Error related to DNS stamps
+#[derive(Debug, Error)]
+pub enum StampError {
+    /// The stamp is not `sdns://` followed by URL-safe base64.
+    #[error("malformed DNS stamp: {0}")]
+    Malformed(String),
+
+    /// The stamp is valid, but the protocol is not supported.
+    #[error("DNS stamps of {0} are not supported")]
+    Unsupported(&'static str),
+}
+
+impl StampError {
+    /// The category of the error
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::Config
+    }
+}
+
+type Result<T> = std::result::Result<T, StampError>;
+
+/// A resolver described by a DNS stamp
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stamp {
+    /// Plain DNS
+    Plain {
+        /// Address of the resolver, with the port defaulting to 53
+        addr: SocketAddr,
+    },
+    /// DNSCrypt, which is not supported as an upstream
+    DnsCrypt {
+        /// Address of the resolver, with the port defaulting to 443
+        addr: String,
+        /// Public key of the provider
+        public_key: Vec<u8>,
+        /// Name of the provider
+        provider_name: String,
+    },
+    /// DNS over HTTPS
+    Https {
+        /// Address of the resolver, or empty if it should be resolved from `host`
+        addr: String,
+        /// SHA256 digests of the TBS certificates in the chain
+        hashes: Vec<Vec<u8>>,
+        /// Host name, optionally with the port
+        host: String,
+        /// Absolute URI path, e.g. `/dns-query`
+        path: String,
+    },
+    /// DNS over TLS
+    Tls {
+        /// Address of the resolver, or empty if it should be resolved from `host`
+        addr: String,
+        /// SHA256 digests of the TBS certificates in the chain
+        hashes: Vec<Vec<u8>>,
+        /// Host name, optionally with the port
+        host: String,
+    },
+    /// Protocols parsed no further, e.g. DNS over QUIC
+    Other(&'static str),
+}
+
+// Reader of the length-prefixed fields of the stamp.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(StampError::Malformed("unexpected end".to_string()));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    // LP(x)
+    fn lp(&mut self) -> Result<&'a [u8]> {
+        let len = usize::from(self.bytes(1)?[0]);
+        self.bytes(len)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.lp()?.to_vec()).map_err(|e| StampError::Malformed(e.to_string()))
+    }
+
+    // VLP(x1, x2, ...xn), in which the high bit of the length marks that there are more
+    fn vlp(&mut self) -> Result<Vec<Vec<u8>>> {
+        let mut items = Vec::new();
+        loop {
+            let len = self.bytes(1)?[0];
+            items.push(self.bytes(usize::from(len & 0x7f))?.to_vec());
+            if len & 0x80 == 0 {
+                return Ok(items);
+            }
+        }
+    }
+
+    // A single empty hash stands for no hash at all.
+    fn hashes(&mut self) -> Result<Vec<Vec<u8>>> {
+        Ok(self.vlp()?.into_iter().filter(|h| !h.is_empty()).collect())
+    }
+}
+
+// Address with the default port if the stamp doesn't carry one.
+fn socket_addr(addr: &str, port: u16) -> Result<SocketAddr> {
+    addr.parse::<SocketAddr>()
+        .or_else(|_| {
+            addr.trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, port))
+        })
+        .map_err(|_| StampError::Malformed(format!("invalid address '{}'", addr)))
+}
+
+// Host name and the port if it carries one. IPv6 addresses are either bare or in brackets followed by the port.
+fn host_port(host: &str) -> Result<(&str, Option<u16>)> {
+    let malformed = || StampError::Malformed(format!("invalid host '{}'", host));
+    let port = |p: &str| p.parse().map(Some).map_err(|_| malformed());
+    if let Some(rest) = host.strip_prefix('[') {
+        return match rest.split_once(']').ok_or_else(malformed)? {
+            (ip, "") => Ok((ip, None)),
+            (ip, rest) => Ok((ip, port(rest.strip_prefix(':').ok_or_else(malformed)?)?)),
+        };
+    }
+    if host.parse::<Ipv6Addr>().is_ok() {
+        return Ok((host, None));
+    }
+    match host.rsplit_once(':') {
+        Some((name, p)) => Ok((name, port(p)?)),
+        None => Ok((host, None)),
+    }
+}
+
+impl FromStr for Stamp {
+    type Err = StampError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let encoded = s
+            .strip_prefix("sdns://")
+            .ok_or_else(|| StampError::Malformed("missing sdns:// prefix".to_string()))?;
+        let decoded = URL_SAFE_NO_PAD
+            .decode(encoded.trim_end_matches('='))
+            .map_err(|e| StampError::Malformed(e.to_string()))?;
+        let mut r = Reader(&decoded);
+
+        let protocol = r.bytes(1)?[0];
+        // Relays don't carry the properties.
+        if protocol == 0x81 {
+            return Ok(Self::Other("anonymized DNSCrypt relays"));
+        }
+        // Properties, which are informative only
+        r.bytes(8)?;
+
+        Ok(match protocol {
+            0x00 => Self::Plain {
+                addr: socket_addr(&r.string()?, 53)?,
+            },
+            0x01 => Self::DnsCrypt {
+                addr: r.string()?,
+                public_key: r.lp()?.to_vec(),
+                provider_name: r.string()?,
+            },
+            0x02 => Self::Https {
+                addr: r.string()?,
+                hashes: r.hashes()?,
+                host: r.string()?,
+                path: r.string()?,
+            },
+            0x03 => Self::Tls {
+                addr: r.string()?,
+                hashes: r.hashes()?,
+                host: r.string()?,
+            },
+            0x04 => Self::Other("DNS over QUIC"),
+            0x05 => Self::Other("oblivious DoH targets"),
+            0x85 => Self::Other("oblivious DoH relays"),
+            _ => {
+                return Err(StampError::Malformed(format!(
+                    "unknown protocol {}",
+                    protocol
+                )))
+            }
+        })
+    }
+}
+
+impl TryFrom<Stamp> for UpstreamBuilder {
+    type Error = StampError;
+
+    fn try_from(stamp: Stamp) -> Result<Self> {
+        match stamp {
+            Stamp::Plain { addr } => Ok(Self::Udp(UdpBuilder::new(addr))),
+            Stamp::DnsCrypt { .. } => Err(StampError::Unsupported("DNSCrypt")),
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            Stamp::Https {
+                addr, host, path, ..
+            } => {
+                if addr.is_empty() {
+                    return Err(StampError::Unsupported("DoH without addresses"));
+                }
+                // The port of the address takes precedence over the one of the host name.
+                let (name, port) = host_port(&host)?;
+                let addr = socket_addr(&addr, port.unwrap_or(443))?;
+                let name = if name.contains(':') {
+                    format!("[{}]", name)
+                } else {
+                    name.to_string()
+                };
+                let uri = match addr.port() {
+                    443 => format!("https://{}{}", name, path),
+                    port => format!("https://{}:{}{}", name, port, path),
+                };
+                Ok(Self::Https(HttpsBuilder {
+                    sni: true,
+                    ..HttpsBuilder::new(uri, addr.ip())
+                }))
+            }
+            #[cfg(not(any(feature = "doh-rustls", feature = "doh-native-tls")))]
+            Stamp::Https { .. } => Err(StampError::Unsupported("DoH in this build")),
+            #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
+            Stamp::Tls { addr, host, .. } => {
+                if addr.is_empty() {
+                    return Err(StampError::Unsupported("DoT without addresses"));
+                }
+                // The port is either in the address or in the host name.
+                let (name, port) = host_port(&host)?;
+                Ok(Self::Tls(TlsBuilder {
+                    sni: true,
+                    ..TlsBuilder::new(name.to_string(), socket_addr(&addr, port.unwrap_or(853))?)
+                }))
+            }
+            #[cfg(not(any(feature = "dot-native-tls", feature = "dot-rustls")))]
+            Stamp::Tls { .. } => Err(StampError::Unsupported("DoT in this build")),
+            Stamp::Other(protocol) => Err(StampError::Unsupported(protocol)),
+        }
+    }
+}
+
+/// Resolvers in a list of the format used by dnscrypt-proxy (e.g. `public-resolvers.md`), where each resolver is introduced by a `## name` heading and followed by its stamps.
+/// Only the first stamp of each resolver is returned.
+pub fn parse_list(list: &str) -> Vec<(String, String)> {
+    let mut resolvers = Vec::new();
+    let mut name = None;
+    for line in list.lines().map(str::trim) {
+        if let Some(heading) = line.strip_prefix("## ") {
+            name = Some(heading.trim().to_string());
+        } else if line.starts_with("sdns://") {
+            if let Some(name) = name.take() {
+                resolvers.push((name, line.to_string()));
+            }
+        }
+    }
+    resolvers
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+    use super::UpstreamBuilder;
+    use super::{host_port, parse_list, Stamp, StampError};
+
+    #[test]
+    fn parse() {
+        // From dnscrypt-proxy's public resolver list
+        assert_eq!(
+            "sdns://AAcAAAAAAAAABzEuMS4xLjE".parse::<Stamp>().unwrap(),
+            Stamp::Plain {
+                addr: "1.1.1.1:53".parse().unwrap()
+            }
+        );
+        assert_eq!(
+            "sdns://AgcAAAAAAAAABzEuMC4wLjEAEmRucy5jbG91ZGZsYXJlLmNvbQovZG5zLXF1ZXJ5"
+                .parse::<Stamp>()
+                .unwrap(),
+            Stamp::Https {
+                addr: "1.0.0.1".to_string(),
+                hashes: vec![],
+                host: "dns.cloudflare.com".to_string(),
+                path: "/dns-query".to_string(),
+            }
+        );
+        match "sdns://AQcAAAAAAAAADjIwOC42Ny4yMjAuMjIwILc1EUAgbyJdPivYItf9aR6hwzzI1maNDL4Ev6vKQ_t5GzIuZG5zY3J5cHQtY2VydC5vcGVuZG5zLmNvbQ"
+            .parse::<Stamp>()
+            .unwrap()
+        {
+            Stamp::DnsCrypt {
+                addr,
+                provider_name,
+                ..
+            } => {
+                assert_eq!(addr, "208.67.220.220");
+                assert_eq!(provider_name, "2.dnscrypt-cert.opendns.com");
+            }
+            _ => unreachable!(),
+        }
+        assert!(matches!(
+            "https://dns.google".parse::<Stamp>(),
+            Err(StampError::Malformed(_))
+        ));
+        assert!(matches!(
+            "sdns://AgcAAAA".parse::<Stamp>(),
+            Err(StampError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn host() {
+        assert_eq!(host_port("dns.example").unwrap(), ("dns.example", None));
+        assert_eq!(
+            host_port("dns.example:8443").unwrap(),
+            ("dns.example", Some(8443))
+        );
+        assert_eq!(
+            host_port("2606:4700::1111").unwrap(),
+            ("2606:4700::1111", None)
+        );
+        assert_eq!(
+            host_port("[2606:4700::1111]:853").unwrap(),
+            ("2606:4700::1111", Some(853))
+        );
+        assert_eq!(host_port("[::1]").unwrap(), ("::1", None));
+        assert!(host_port("[::1]853").is_err());
+        assert!(host_port("dns.example:https").is_err());
+    }
+
+    #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+    #[test]
+    fn https_port() {
+        let builder = |addr: &str, host: &str| match UpstreamBuilder::try_from(Stamp::Https {
+            addr: addr.to_string(),
+            hashes: vec![],
+            host: host.to_string(),
+            path: "/dns-query".to_string(),
+        })
+        .unwrap()
+        {
+            UpstreamBuilder::Https(b) => (b.uri, b.addr),
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            builder("1.1.1.1", "dns.example"),
+            (
+                "https://dns.example/dns-query".to_string(),
+                [1, 1, 1, 1].into()
+            )
+        );
+        // Non-default ports are kept, whether in the address or in the host name.
+        assert_eq!(
+            builder("1.1.1.1:8443", "dns.example").0,
+            "https://dns.example:8443/dns-query"
+        );
+        assert_eq!(
+            builder("[2606:4700::1111]", "dns.example:8443").0,
+            "https://dns.example:8443/dns-query"
+        );
+    }
+
+    #[test]
+    fn list() {
+        let list = "# public-resolvers\n\n## cloudflare\n\nCloudflare DNS\n\nsdns://AgcAAAAAAAAABzEuMC4wLjEAEmRucy5jbG91ZGZsYXJlLmNvbQovZG5zLXF1ZXJ5\nsdns://AgcAAAAAAAAABzEuMS4xLjE\n\n## plain\n\nsdns://AAcAAAAAAAAABzEuMS4xLjE\n";
+        assert_eq!(
+            parse_list(list),
+            vec![
+                (
+                    "cloudflare".to_string(),
+                    "sdns://AgcAAAAAAAAABzEuMC4wLjEAEmRucy5jbG91ZGZsYXJlLmNvbQovZG5zLXF1ZXJ5"
+                        .to_string()
+                ),
+                (
+                    "plain".to_string(),
+                    "sdns://AAcAAAAAAAAABzEuMS4xLjE".to_string()
+                )
+            ]
+        );
+    }
+}
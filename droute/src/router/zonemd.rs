@@ -0,0 +1,337 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Verification of the message digest of a zone (ZONEMD, RFC 8976), telling that a copy of the zone is complete and unaltered before it is used.
+//!
+//! Records are put in the canonical form of RFC 4034 and hashed in the canonical order. Only the SIMPLE scheme with SHA-384 or SHA-512 is supported, and only the record types the root zone is made of can be put in the canonical form.
+
+use super::script::MessageError;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use domain::base::{
+    iana::{DigestAlg, SecAlg},
+    Dname, Rtype,
+};
+use sha2::{Digest, Sha384, Sha512};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+const ZONEMD: u16 = 63;
+const SCHEME_SIMPLE: u8 = 1;
+const HASH_SHA384: u8 = 1;
+const HASH_SHA512: u8 = 2;
+
+// A record in the canonical form, ordered by its owner (lowercased labels from the top), its type and then its data
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Canonical {
+    labels: Vec<Vec<u8>>,
+    rtype: u16,
+    rdata: Vec<u8>,
+    ttl: u32,
+}
+
+impl Canonical {
+    fn owner(&self) -> impl Iterator<Item = u8> + '_ {
+        self.labels
+            .iter()
+            .rev()
+            .flat_map(|l| std::iter::once(l.len() as u8).chain(l.iter().copied()))
+            .chain(std::iter::once(0))
+    }
+
+    fn hash(&self, hasher: &mut impl Digest) {
+        hasher.update(self.owner().collect::<Vec<_>>());
+        hasher.update(self.rtype.to_be_bytes());
+        // Only records of the class IN are kept.
+        hasher.update(1u16.to_be_bytes());
+        hasher.update(self.ttl.to_be_bytes());
+        hasher.update((self.rdata.len() as u16).to_be_bytes());
+        hasher.update(&self.rdata);
+    }
+}
+
+fn name(s: &str, lowercase: bool, out: &mut Vec<u8>) -> Result<(), MessageError> {
+    for label in Dname::<Bytes>::from_str(s)?.iter() {
+        out.push(label.len() as u8);
+        if lowercase {
+            out.extend(label.as_slice().to_ascii_lowercase());
+        } else {
+            out.extend(label.as_slice());
+        }
+    }
+    Ok(())
+}
+
+fn rtype(s: &str) -> Option<u16> {
+    match s.to_ascii_uppercase().as_str() {
+        "ZONEMD" => Some(ZONEMD),
+        s => match s.strip_prefix("TYPE") {
+            Some(n) => n.parse().ok(),
+            None => Rtype::from_str(s).ok().map(|t| t.to_int()),
+        },
+    }
+}
+
+// Algorithms are given by number in zone files and by mnemonic when zone transfers are printed.
+fn sec_alg(s: &str) -> Option<u8> {
+    s.parse()
+        .ok()
+        .or_else(|| SecAlg::from_str(s).ok().map(|a| a.to_int()))
+}
+
+fn digest_alg(s: &str) -> Option<u8> {
+    s.parse()
+        .ok()
+        .or_else(|| DigestAlg::from_str(s).ok().map(|a| a.to_int()))
+}
+
+// Signature times are either YYYYMMDDHHmmSS or seconds since the epoch (RFC 4034, section 3.2).
+fn time(s: &str) -> Option<u32> {
+    if s.len() != 14 || !s.bytes().all(|c| c.is_ascii_digit()) {
+        return s.parse().ok();
+    }
+    let field = |r: std::ops::Range<usize>| s[r].parse::<i64>().ok();
+    let (y, m, d) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    // Days since the epoch of the civil date
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    let secs = days * 86400 + field(8..10)? * 3600 + field(10..12)? * 60 + field(12..14)?;
+    Some(secs as u32)
+}
+
+// Types of the NSEC records in the windowed bitmap format (RFC 4034, section 4.1.2)
+fn bitmap(types: &[u16], out: &mut Vec<u8>) {
+    let mut types = types.to_vec();
+    types.sort_unstable();
+    types.dedup();
+    let mut windows: Vec<(u8, Vec<u8>)> = Vec::new();
+    for t in types {
+        let (window, bit) = ((t >> 8) as u8, (t & 0xff) as usize);
+        if windows.last().map(|(w, _)| *w) != Some(window) {
+            windows.push((window, Vec::new()));
+        }
+        let bits = &mut windows.last_mut().unwrap().1;
+        if bits.len() <= bit / 8 {
+            bits.resize(bit / 8 + 1, 0);
+        }
+        bits[bit / 8] |= 0x80 >> (bit % 8);
+    }
+    for (window, bits) in windows {
+        out.push(window);
+        out.push(bits.len() as u8);
+        out.extend(bits);
+    }
+}
+
+// The data of the record in the canonical form, names of the types listed in RFC 4034, section 6.2 lowercased
+fn rdata(rtype: u16, fields: &[&str]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    // Data in the generic format of RFC 3597
+    if let ["\\#", _, data @ ..] = fields {
+        return hex::decode(data.concat()).ok();
+    }
+    match (Rtype::from_int(rtype), fields) {
+        (Rtype::A, [addr]) => out.extend(Ipv4Addr::from_str(addr).ok()?.octets()),
+        (Rtype::Aaaa, [addr]) => out.extend(Ipv6Addr::from_str(addr).ok()?.octets()),
+        (Rtype::Ns, [nsdname]) => name(nsdname, true, &mut out).ok()?,
+        (Rtype::Soa, [mname, rname, numbers @ ..]) if numbers.len() == 5 => {
+            name(mname, true, &mut out).ok()?;
+            name(rname, true, &mut out).ok()?;
+            for n in numbers {
+                out.extend(n.parse::<u32>().ok()?.to_be_bytes());
+            }
+        }
+        (Rtype::Ds, [key_tag, alg, digest_type, digest @ ..]) => {
+            out.extend(key_tag.parse::<u16>().ok()?.to_be_bytes());
+            out.push(sec_alg(alg)?);
+            out.push(digest_alg(digest_type)?);
+            out.extend(hex::decode(digest.concat()).ok()?);
+        }
+        (Rtype::Dnskey, [flags, protocol, alg, key @ ..]) => {
+            out.extend(flags.parse::<u16>().ok()?.to_be_bytes());
+            out.push(protocol.parse().ok()?);
+            out.push(sec_alg(alg)?);
+            out.extend(STANDARD.decode(key.concat()).ok()?);
+        }
+        (
+            Rtype::Rrsig,
+            [covered, alg, labels, ttl, expiration, inception, key_tag, signer, signature @ ..],
+        ) => {
+            out.extend(self::rtype(covered)?.to_be_bytes());
+            out.push(sec_alg(alg)?);
+            out.push(labels.parse().ok()?);
+            out.extend(ttl.parse::<u32>().ok()?.to_be_bytes());
+            out.extend(time(expiration)?.to_be_bytes());
+            out.extend(time(inception)?.to_be_bytes());
+            out.extend(key_tag.parse::<u16>().ok()?.to_be_bytes());
+            name(signer, true, &mut out).ok()?;
+            out.extend(STANDARD.decode(signature.concat()).ok()?);
+        }
+        // The next name of NSEC records is not lowercased (RFC 6840, section 5.1).
+        (Rtype::Nsec, [next, types @ ..]) => {
+            name(next, false, &mut out).ok()?;
+            let types = types
+                .iter()
+                .map(|t| self::rtype(t))
+                .collect::<Option<Vec<_>>>()?;
+            bitmap(&types, &mut out);
+        }
+        (_, [serial, scheme, hash, digest @ ..]) if rtype == ZONEMD => {
+            out.extend(serial.parse::<u32>().ok()?.to_be_bytes());
+            out.push(scheme.parse().ok()?);
+            out.push(hash.parse().ok()?);
+            out.extend(hex::decode(digest.concat()).ok()?);
+        }
+        _ => return None,
+    }
+    Some(out)
+}
+
+/// Verify the zone given in the format it is published in, with one record per line in full, against its ZONEMD records.
+/// Zones without ZONEMD records of the serial of the zone, or of which none matches, fail the verification.
+pub fn verify(zone: &str) -> Result<(), MessageError> {
+    let mut records = Vec::new();
+    for line in zone.lines() {
+        let fields: Vec<_> = line
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect();
+        if fields.is_empty() {
+            continue;
+        }
+        let malformed = || MessageError::MalformedZone(line.to_string());
+        let (owner, ttl, class, rtype, data) = match fields.as_slice() {
+            [owner, ttl, class, rtype, data @ ..] => (owner, ttl, class, rtype, data),
+            _ => return Err(malformed()),
+        };
+        if !class.eq_ignore_ascii_case("IN") {
+            continue;
+        }
+        let rtype = self::rtype(rtype).ok_or_else(malformed)?;
+        let mut labels: Vec<_> = Dname::<Bytes>::from_str(owner)?
+            .iter()
+            .filter(|l| !l.is_root())
+            .map(|l| l.as_slice().to_ascii_lowercase())
+            .collect();
+        labels.reverse();
+        records.push(Canonical {
+            labels,
+            rtype,
+            rdata: rdata(rtype, data).ok_or_else(malformed)?,
+            ttl: ttl.parse().map_err(|_| malformed())?,
+        });
+    }
+    // Zone transfers repeat the SOA record at the end, and duplicates are counted once.
+    records.sort_unstable();
+    records.dedup_by(|a, b| (&a.labels, a.rtype, &a.rdata) == (&b.labels, b.rtype, &b.rdata));
+
+    let soa = records
+        .iter()
+        .find(|r| r.rtype == Rtype::Soa.to_int())
+        .ok_or_else(|| MessageError::MalformedZone("no SOA record".to_string()))?;
+    let apex = soa.labels.clone();
+    // The serial follows the two names of the SOA record.
+    let serial = &soa.rdata[soa.rdata.len() - 20..soa.rdata.len() - 16];
+    let digests: Vec<_> = records
+        .iter()
+        .filter(|r| r.labels == apex && r.rtype == ZONEMD && r.rdata.len() > 6)
+        .map(|r| r.rdata.clone())
+        .collect();
+    if digests.is_empty() {
+        return Err(MessageError::ZoneDigest("no ZONEMD record".to_string()));
+    }
+
+    // The ZONEMD records of the apex and their signatures are not part of the digest.
+    let digested: Vec<_> = records
+        .iter()
+        .filter(|r| {
+            r.labels != apex
+                || !(r.rtype == ZONEMD
+                    || (r.rtype == Rtype::Rrsig.to_int() && r.rdata[..2] == ZONEMD.to_be_bytes()))
+        })
+        .collect();
+    for zonemd in &digests {
+        if &zonemd[..4] != serial || zonemd[4] != SCHEME_SIMPLE {
+            continue;
+        }
+        let digest = match zonemd[5] {
+            HASH_SHA384 => {
+                let mut hasher = Sha384::new();
+                digested.iter().for_each(|r| r.hash(&mut hasher));
+                hasher.finalize().to_vec()
+            }
+            HASH_SHA512 => {
+                let mut hasher = Sha512::new();
+                digested.iter().for_each(|r| r.hash(&mut hasher));
+                hasher.finalize().to_vec()
+            }
+            _ => continue,
+        };
+        if digest[..] == zonemd[6..] {
+            return Ok(());
+        }
+    }
+    Err(MessageError::ZoneDigest(
+        "no ZONEMD record of the serial of the zone and a supported algorithm matches".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::verify;
+
+    // The simple example of RFC 8976, appendix A.1
+    const ZONE: &str = "\
+example.     86400 IN SOA    ns1.example. admin.example. 2018031900 1800 900 604800 86400
+example.     86400 IN NS     ns1.example.
+example.     86400 IN NS     ns2.example.
+example.     86400 IN ZONEMD 2018031900 1 1 c68090d90a7aed716bc459f9340e3d7c1370d4d24b7e2fc3a1ddc0b9a87153b9 a9713b3c9ae5cc27777f98b8e730044c
+ns1.example. 3600  IN A      203.0.113.63
+ns2.example. 3600  IN AAAA   2001:db8::63
+";
+
+    #[test]
+    fn zone_digest() {
+        verify(ZONE).unwrap();
+        // Names are compared and hashed lowercased.
+        verify(&ZONE.replace("ns2.example.", "NS2.Example.")).unwrap();
+
+        // Altered or incomplete copies fail.
+        assert!(verify(&ZONE.replace("203.0.113.63", "203.0.113.64")).is_err());
+        let incomplete: String = ZONE
+            .lines()
+            .filter(|l| !l.contains("AAAA"))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        assert!(verify(&incomplete).is_err());
+
+        // So do copies without a digest of their serial.
+        assert!(verify(&ZONE.replace("ZONEMD 2018031900", "ZONEMD 2018031901")).is_err());
+        let undigested: String = ZONE
+            .lines()
+            .filter(|l| !l.contains("ZONEMD"))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        assert!(verify(&undigested).is_err());
+    }
+}
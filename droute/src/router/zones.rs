@@ -0,0 +1,63 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{
+    errors::{MessageError, UpstreamError},
+    utils::parse_domain,
+    Label, Upstreams,
+};
+use bytes::Bytes;
+use domain::base::Dname;
+use std::collections::HashMap;
+
+/// Zones and the upstreams queries under them are forwarded to, bypassing the script.
+/// The most specific zone takes precedence, and the root zone `.` matches every query.
+// Zones are keyed by their names, so that a query takes a lookup per label of its name however many zones there are.
+#[derive(Clone, Default)]
+pub struct Zones(HashMap<Dname<Bytes>, Label>);
+
+impl Zones {
+    /// Create an empty set of zones.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forward the queries under the zone given to the upstream with the given tag.
+    pub fn insert(
+        &mut self,
+        zone: impl AsRef<str>,
+        tag: impl Into<Label>,
+    ) -> Result<(), MessageError> {
+        self.0.insert(parse_domain(zone.as_ref())?, tag.into());
+        Ok(())
+    }
+
+    // Get the tag of the upstream for the query name given, if it is under any of the zones.
+    pub(super) fn get(&self, qname: &Dname<Bytes>) -> Option<&Label> {
+        // Suffixes come from the name itself up to the root, i.e. the most specific zone first.
+        qname.iter_suffixes().find_map(|name| self.0.get(&name))
+    }
+
+    // Check if all the upstreams referred to exist.
+    pub(super) fn validate(&self, upstreams: &Upstreams) -> Result<(), UpstreamError> {
+        let tags = upstreams.tags();
+        for tag in self.0.values() {
+            if !tags.contains(tag) {
+                return Err(UpstreamError::MissingTag(tag.clone()));
+            }
+        }
+        Ok(())
+    }
+}
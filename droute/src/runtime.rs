@@ -0,0 +1,110 @@
+// Copyright 2020 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The executor `droute` runs its background tasks and timeouts on, which is tokio unless another one is installed.
+//!
+//! Applications built on async-std, smol or others install their own with [`install`] before building the router.
+//! Sockets and connection pools are still tokio's, so the futures of `droute` have to be polled where a tokio reactor can be reached,
+//! e.g. wrapped in `async_compat::Compat`.
+
+use futures::future::{self, BoxFuture, Either};
+use once_cell::sync::OnceCell;
+use std::{future::Future, time::Duration};
+use thiserror::Error;
+
+/// An executor with a timer.
+pub trait Runtime: Send + Sync + 'static {
+    /// Run the future in the background.
+    fn spawn(&self, fut: BoxFuture<'static, ()>);
+
+    /// Run the function on a thread that is allowed to block.
+    fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>);
+
+    /// A future that completes once the duration has passed.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The tokio runtime the caller is on, used unless another one is installed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tokio;
+
+impl Runtime for Tokio {
+    fn spawn(&self, fut: BoxFuture<'static, ()>) {
+        tokio::spawn(fut);
+    }
+
+    fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>) {
+        tokio::task::spawn_blocking(f);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+static RUNTIME: OnceCell<Box<dyn Runtime>> = OnceCell::new();
+
+/// Install the runtime for `droute` to run on. Only the first one takes effect, so `false` is returned if `droute` has already picked one, either installed or tokio by default.
+pub fn install(runtime: impl Runtime) -> bool {
+    RUNTIME.set(Box::new(runtime)).is_ok()
+}
+
+fn runtime() -> &'static dyn Runtime {
+    RUNTIME.get_or_init(|| Box::new(Tokio)).as_ref()
+}
+
+pub(crate) fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
+    runtime().spawn(Box::pin(fut))
+}
+
+pub(crate) fn spawn_blocking(f: impl FnOnce() + Send + 'static) {
+    runtime().spawn_blocking(Box::new(f))
+}
+
+/// The future didn't complete in time.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("deadline has elapsed")]
+pub struct Elapsed;
+
+/// Wait for the future for at most `duration`.
+pub(crate) async fn timeout<F: Future>(duration: Duration, fut: F) -> Result<F::Output, Elapsed> {
+    futures::pin_mut!(fut);
+    match future::select(fut, runtime().sleep(duration)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(Elapsed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{spawn, timeout, Elapsed};
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn timeouts() {
+        assert_eq!(timeout(Duration::from_secs(1), async { 1 }).await, Ok(1));
+        assert_eq!(
+            timeout(Duration::from_millis(10), futures::future::pending::<()>()).await,
+            Err(Elapsed)
+        );
+
+        let (tx, rx) = oneshot::channel();
+        spawn(async move {
+            let _ = tx.send(());
+        });
+        assert!(timeout(Duration::from_secs(1), rx).await.unwrap().is_ok());
+    }
+}
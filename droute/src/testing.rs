@@ -0,0 +1,289 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Mock upstream serving scripted replies over UDP in process, so that routing policies can be tested deterministically without the network.
+//!
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! use domain::base::iana::Rcode;
+//! use droute::testing::{MockUpstream, Reply};
+//! use std::time::Duration;
+//!
+//! let mock = MockUpstream::new()
+//!     .on("ads.example.com", [Reply::rcode(Rcode::Refused)])
+//!     // Lose the first query, then answer after 50ms.
+//!     .otherwise([
+//!         Reply::dropped(),
+//!         Reply::addrs(["192.0.2.1".parse().unwrap()], 300).delay(Duration::from_millis(50)),
+//!     ])
+//!     .spawn()
+//!     .await?;
+//! // Point a UDP upstream to `mock.addr()`.
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::wire;
+use bytes::{Bytes, BytesMut};
+use dmatcher::domain::Domain;
+use domain::{
+    base::{iana::Rcode, name::ToDname, Dname, Message, MessageBuilder, Rtype},
+    rdata::{Aaaa, A},
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+#[derive(Clone)]
+enum Action {
+    Answer(Message<Bytes>),
+    Rcode(Rcode),
+    Addrs(Vec<IpAddr>, u32),
+    Dropped,
+    Truncated,
+}
+
+/// What the mock upstream does with a query.
+#[derive(Clone)]
+pub struct Reply {
+    action: Action,
+    delay: Duration,
+    injected: Option<Message<Bytes>>,
+}
+
+impl Reply {
+    fn new(action: Action) -> Self {
+        Self {
+            action,
+            delay: Duration::ZERO,
+            injected: None,
+        }
+    }
+
+    /// Answer with the response given, with its ID set to the query's.
+    pub fn answer(resp: Message<Bytes>) -> Self {
+        Self::new(Action::Answer(resp))
+    }
+
+    /// Answer with an empty response of the response code given.
+    pub fn rcode(rcode: Rcode) -> Self {
+        Self::new(Action::Rcode(rcode))
+    }
+
+    /// Answer with the addresses of the type queried among the ones given, under the name queried.
+    pub fn addrs(addrs: impl IntoIterator<Item = IpAddr>, ttl: u32) -> Self {
+        Self::new(Action::Addrs(addrs.into_iter().collect(), ttl))
+    }
+
+    /// Never answer, as if the query were lost.
+    pub fn dropped() -> Self {
+        Self::new(Action::Dropped)
+    }
+
+    /// Answer with an empty response with the TC bit set.
+    pub fn truncated() -> Self {
+        Self::new(Action::Truncated)
+    }
+
+    /// Reply only after the delay given.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Answer with the forged response given right away before replying, as injected on the path.
+    pub fn injected(mut self, forged: Message<Bytes>) -> Self {
+        self.injected = Some(forged);
+        self
+    }
+
+    // The response to the query, if any.
+    fn respond(&self, query: &Message<Bytes>) -> Option<Message<Bytes>> {
+        let answer = |rcode| {
+            MessageBuilder::from_target(BytesMut::new())
+                .ok()?
+                .start_answer(query, rcode)
+                .ok()
+        };
+        match &self.action {
+            Action::Answer(resp) => Some(wire::set_id(resp, query.header().id())),
+            Action::Rcode(rcode) => Some(answer(*rcode)?.into_message()),
+            Action::Addrs(addrs, ttl) => {
+                let question = query.sole_question().ok()?;
+                let mut builder = answer(Rcode::NoError)?;
+                for addr in addrs {
+                    match (addr, question.qtype()) {
+                        (IpAddr::V4(v4), Rtype::A) => {
+                            builder.push((question.qname(), *ttl, A::new(*v4))).ok()?
+                        }
+                        (IpAddr::V6(v6), Rtype::Aaaa) => builder
+                            .push((question.qname(), *ttl, Aaaa::new(*v6)))
+                            .ok()?,
+                        _ => (),
+                    }
+                }
+                Some(builder.into_message())
+            }
+            Action::Dropped => None,
+            Action::Truncated => {
+                let mut builder = answer(Rcode::NoError)?;
+                builder.header_mut().set_tc(true);
+                Some(builder.into_message())
+            }
+        }
+    }
+}
+
+// Replies to the queries matched, used in turn with the last one repeated
+struct Script {
+    replies: Vec<Reply>,
+    received: AtomicUsize,
+}
+
+impl Script {
+    fn new(replies: impl IntoIterator<Item = Reply>) -> Self {
+        Self {
+            replies: replies.into_iter().collect(),
+            received: AtomicUsize::new(0),
+        }
+    }
+
+    fn pick(&self) -> Option<&Reply> {
+        let n = self.received.fetch_add(1, Ordering::Relaxed);
+        self.replies.get(n).or_else(|| self.replies.last())
+    }
+}
+
+/// Mock upstream answering queries per the replies scripted for the domains they are under.
+pub struct MockUpstream {
+    rules: Vec<(Domain, Script)>,
+    otherwise: Script,
+}
+
+impl Default for MockUpstream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockUpstream {
+    /// Create a mock upstream answering every query with REFUSED.
+    pub fn new() -> Self {
+        Self {
+            rules: Vec::new(),
+            otherwise: Script::new([Reply::rcode(Rcode::Refused)]),
+        }
+    }
+
+    /// Reply to the queries under the domain given (including itself) in turn, repeating the last reply. Rules are matched in the order they are added.
+    /// Panics if the domain is invalid.
+    pub fn on(mut self, domain: &str, replies: impl IntoIterator<Item = Reply>) -> Self {
+        let mut matcher = Domain::new();
+        matcher.insert(&Dname::<Bytes>::from_str(domain).expect("invalid domain"));
+        self.rules.push((matcher, Script::new(replies)));
+        self
+    }
+
+    /// Reply to the queries matching no rule in turn, repeating the last reply.
+    pub fn otherwise(mut self, replies: impl IntoIterator<Item = Reply>) -> Self {
+        self.otherwise = Script::new(replies);
+        self
+    }
+
+    fn reply(&self, query: &Message<Bytes>) -> Option<&Reply> {
+        let qname = query.first_question()?.qname().to_bytes();
+        self.rules
+            .iter()
+            .find(|(matcher, _)| matcher.matches(&qname))
+            .map_or(&self.otherwise, |(_, script)| script)
+            .pick()
+    }
+
+    /// Serve on a port picked on the loopback address until the handle returned is dropped.
+    pub async fn spawn(self) -> std::io::Result<MockHandle> {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let addr = socket.local_addr()?;
+        let received = Arc::new(AtomicUsize::new(0));
+        let mock = Arc::new(self);
+
+        let task = {
+            let received = received.clone();
+            tokio::spawn(async move {
+                let mut buf = vec![0; u16::MAX as usize];
+                while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                    let query = match Message::from_octets(Bytes::copy_from_slice(&buf[..len])) {
+                        Ok(query) => query,
+                        Err(_) => continue,
+                    };
+                    received.fetch_add(1, Ordering::Relaxed);
+                    let reply = match mock.reply(&query) {
+                        Some(reply) => reply.clone(),
+                        None => continue,
+                    };
+                    // Replies are sent concurrently, so that a delayed one doesn't hold up the rest.
+                    if let Some(forged) = &reply.injected {
+                        let forged = wire::set_id(forged, query.header().id());
+                        socket.send_to(forged.as_slice(), peer).await.ok();
+                    }
+                    let socket = socket.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(reply.delay).await;
+                        if let Some(resp) = reply.respond(&query) {
+                            socket.send_to(resp.as_slice(), peer).await.ok();
+                        }
+                    });
+                }
+            })
+        };
+
+        Ok(MockHandle {
+            addr,
+            received,
+            task,
+        })
+    }
+}
+
+/// Handle to a mock upstream serving, which is stopped once the handle is dropped.
+pub struct MockHandle {
+    addr: SocketAddr,
+    received: Arc<AtomicUsize>,
+    task: JoinHandle<()>,
+}
+
+impl MockHandle {
+    /// The address the mock upstream serves on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The number of queries received so far, including the ones dropped.
+    pub fn received(&self) -> usize {
+        self.received.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for MockHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,61 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Truncation of UDP responses per RFC 1035 and RFC 6891, for DNS servers built upon `droute`.
+
+use bytes::{Bytes, BytesMut};
+use domain::base::{Message, MessageBuilder};
+
+// Maximum size of UDP responses to clients without EDNS.
+const MIN_UDP_SIZE: u16 = 512;
+
+/// Maximum size of the UDP response the client can take, along with whether the client supports EDNS.
+pub fn client_limit(query: &Bytes) -> (u16, bool) {
+    match Message::from_octets(query.clone())
+        .ok()
+        .and_then(|q| q.opt())
+    {
+        Some(opt) => (opt.udp_payload_size().max(MIN_UDP_SIZE), true),
+        None => (MIN_UDP_SIZE, false),
+    }
+}
+
+/// Truncate the response if it is larger than the limit given.
+pub fn fit(resp: Message<Bytes>, limit: u16, edns: bool) -> Option<Message<Bytes>> {
+    if resp.as_slice().len() <= limit.into() {
+        Some(resp)
+    } else {
+        truncate(&resp, edns.then_some(limit))
+    }
+}
+
+/// Create the truncated version of the response, with no record but the OPT record if `edns` is given. Clients then retry over TCP.
+pub fn truncate(resp: &Message<Bytes>, edns: Option<u16>) -> Option<Message<Bytes>> {
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(resp.as_slice().len()))
+        .ok()?
+        .start_answer(resp, resp.header().rcode())
+        .ok()?;
+    builder.header_mut().set_tc(true);
+    let mut builder = builder.additional();
+    if let Some(size) = edns {
+        builder
+            .opt(|opt| {
+                opt.set_udp_payload_size(size);
+                Ok(())
+            })
+            .ok()?;
+    }
+    Some(builder.into_message())
+}
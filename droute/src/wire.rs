@@ -0,0 +1,352 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Rewrites of DNS messages on the wire, for DNS servers built upon `droute`.
+//!
+//! Messages are patched in place instead of being parsed and built again, so that they are cheap, and that names stay compressed and records of types unknown to `domain` are kept as they are.
+//! Functions return `None` if the message is malformed.
+
+use bytes::{Bytes, BytesMut};
+use domain::base::Message;
+use std::{
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+const HEADER_LEN: usize = 12;
+
+// Offsets of the record counts in the header
+const QDCOUNT: usize = 4;
+const ANCOUNT: usize = 6;
+const NSCOUNT: usize = 8;
+const ARCOUNT: usize = 10;
+
+// Type of the OPT record, whose TTL field holds the extended RCODE and flags instead
+const OPT: u16 = 41;
+
+fn u16_at(buf: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(buf.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+// Position right after the name at `pos`, either ending with the root label or a compression pointer
+fn skip_name(buf: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *buf.get(pos)?;
+        match len & 0xC0 {
+            0xC0 => return Some(pos + 2).filter(|&end| end <= buf.len()),
+            0x00 if len == 0 => return Some(pos + 1),
+            0x00 => pos += 1 + usize::from(len),
+            // Extended label types are obsolete.
+            _ => return None,
+        }
+    }
+}
+
+// A record on the wire, by the position of its type, TTL and end
+struct RawRecord {
+    start: usize,
+    rtype: u16,
+    ttl: usize,
+    end: usize,
+}
+
+// Positions of the sections, i.e. the end of the question section, and the records of the rest in order.
+fn scan(buf: &[u8]) -> Option<(usize, Vec<RawRecord>)> {
+    let mut pos = HEADER_LEN;
+    for _ in 0..u16_at(buf, QDCOUNT)? {
+        pos = skip_name(buf, pos)? + 4;
+    }
+    let questions = pos;
+    let count = [ANCOUNT, NSCOUNT, ARCOUNT]
+        .into_iter()
+        .map(|c| u16_at(buf, c).map(usize::from))
+        .sum::<Option<usize>>()?;
+    let mut records = Vec::with_capacity(count);
+    for _ in 0..count {
+        let start = pos;
+        pos = skip_name(buf, pos)?;
+        let rtype = u16_at(buf, pos)?;
+        let rdlen = u16_at(buf, pos + 8)?;
+        let end = pos + 10 + usize::from(rdlen);
+        if end > buf.len() {
+            return None;
+        }
+        records.push(RawRecord {
+            start,
+            rtype,
+            ttl: pos + 4,
+            end,
+        });
+        pos = end;
+    }
+    (questions <= buf.len()).then_some((questions, records))
+}
+
+// Positions of the labels of the question names, not following compression pointers as questions are hardly ever compressed.
+fn question_labels(buf: &[u8]) -> Option<Vec<Range<usize>>> {
+    let mut labels = Vec::new();
+    let mut pos = HEADER_LEN;
+    for _ in 0..u16_at(buf, QDCOUNT)? {
+        let end = skip_name(buf, pos)?;
+        while pos < end {
+            let len = usize::from(buf[pos]);
+            if len == 0 || len & 0xC0 != 0 {
+                break;
+            }
+            labels.push(pos + 1..pos + 1 + len);
+            pos += 1 + len;
+        }
+        pos = end + 4;
+    }
+    (pos <= buf.len()).then_some(labels)
+}
+
+fn freeze(buf: BytesMut) -> Option<Message<Bytes>> {
+    Message::from_octets(buf.freeze()).ok()
+}
+
+/// Set the ID of the message, e.g. back to the query's on the response cached.
+pub fn set_id(msg: &Message<Bytes>, id: u16) -> Message<Bytes> {
+    let mut buf = BytesMut::from(msg.as_slice());
+    buf[..2].copy_from_slice(&id.to_be_bytes());
+    // The header is kept, so the message is still valid.
+    Message::from_octets(buf.freeze()).unwrap()
+}
+
+/// Set the TTL of every record but the OPT record to the one `f` maps it to, e.g. to cap the TTLs or to count down the time cached.
+pub fn map_ttls(msg: &Message<Bytes>, mut f: impl FnMut(u32) -> u32) -> Option<Message<Bytes>> {
+    let mut buf = BytesMut::from(msg.as_slice());
+    for record in scan(&buf)?.1 {
+        if record.rtype != OPT {
+            let ttl = &mut buf[record.ttl..record.ttl + 4];
+            let patched = f(u32::from_be_bytes(ttl.as_ref().try_into().ok()?));
+            ttl.copy_from_slice(&patched.to_be_bytes());
+        }
+    }
+    freeze(buf)
+}
+
+// Whether the cache keys ignore the case of the names queried
+static CASE_INSENSITIVE_KEYS: AtomicBool = AtomicBool::new(true);
+
+/// Set whether the cache keys ignore the case of the names queried, which they do by default, so that clients randomizing the case (i.e. 0x20 encoding) still hit the cache.
+pub fn set_case_insensitive_keys(enabled: bool) {
+    CASE_INSENSITIVE_KEYS.store(enabled, Ordering::Relaxed);
+}
+
+/// The key of the query in the caches, i.e. the query without its ID, with the question names lowercased unless the keys are set to be case-sensitive.
+pub fn cache_key(query: &Message<Bytes>) -> Bytes {
+    let slice = query.as_slice();
+    if !CASE_INSENSITIVE_KEYS.load(Ordering::Relaxed) {
+        return query.as_octets().slice(2..);
+    }
+    let labels = match question_labels(slice) {
+        Some(labels)
+            if labels
+                .iter()
+                .any(|l| slice[l.clone()].iter().any(u8::is_ascii_uppercase)) =>
+        {
+            labels
+        }
+        _ => return query.as_octets().slice(2..),
+    };
+    let mut buf = BytesMut::from(slice);
+    for label in labels {
+        buf[label].make_ascii_lowercase();
+    }
+    buf.freeze().slice(2..)
+}
+
+/// Set the question names of the response to the ones of the query, e.g. on the response cached for the same names in another case, as clients randomizing the case check it.
+/// The response is returned as it is if the questions differ otherwise.
+pub fn match_case(resp: &Message<Bytes>, query: &Message<Bytes>) -> Message<Bytes> {
+    let (r, q) = (resp.as_slice(), query.as_slice());
+    let (labels, end) = match (question_labels(q), scan(q)) {
+        (Some(labels), Some((end, _)))
+            if r.len() >= end && question_labels(r).as_ref() == Some(&labels) =>
+        {
+            (labels, end)
+        }
+        _ => return resp.clone(),
+    };
+    let mut buf = BytesMut::from(r);
+    for label in labels {
+        buf[label.clone()].copy_from_slice(&q[label]);
+    }
+    // The questions are the same but for the case.
+    if buf[QDCOUNT..QDCOUNT + 2] != q[QDCOUNT..QDCOUNT + 2]
+        || buf[HEADER_LEN..end] != q[HEADER_LEN..end]
+        || !buf[HEADER_LEN..end].eq_ignore_ascii_case(&r[HEADER_LEN..end])
+    {
+        return resp.clone();
+    }
+    freeze(buf).unwrap_or_else(|| resp.clone())
+}
+
+/// Truncate the message to at most `limit` bytes with the TC bit set, dropping the records not fitting as a whole along with the rest of their section and the ones after, but keeping the OPT record.
+/// The message is returned as it is if it fits, and `None` if even the question section and the OPT record don't.
+pub fn truncate_at(msg: &Message<Bytes>, limit: usize) -> Option<Message<Bytes>> {
+    let slice = msg.as_slice();
+    if slice.len() <= limit {
+        return Some(msg.clone());
+    }
+    let (questions, records) = scan(slice)?;
+    let opt = records
+        .iter()
+        .find(|r| r.rtype == OPT)
+        .map(|r| &slice[r.start..r.end]);
+    let room = limit.checked_sub(opt.map_or(0, <[u8]>::len))?;
+    if questions > room {
+        return None;
+    }
+
+    // As compression pointers only point backward, the records kept still refer to names within them.
+    let kept = records
+        .iter()
+        .take_while(|r| r.end <= room && r.rtype != OPT)
+        .count();
+    let (mut counts, mut left) = ([0_u16; 3], kept);
+    for (i, c) in [ANCOUNT, NSCOUNT, ARCOUNT].into_iter().enumerate() {
+        counts[i] = u16_at(slice, c)?.min(u16::try_from(left).unwrap_or(u16::MAX));
+        left -= usize::from(counts[i]);
+    }
+
+    let end = match kept {
+        0 => questions,
+        n => records[n - 1].end,
+    };
+    let mut buf = BytesMut::with_capacity(limit);
+    buf.extend_from_slice(&slice[..end]);
+    buf[2] |= 0x02;
+    for (i, c) in [ANCOUNT, NSCOUNT, ARCOUNT].into_iter().enumerate() {
+        buf[c..c + 2].copy_from_slice(&counts[i].to_be_bytes());
+    }
+    if let Some(opt) = opt {
+        buf.extend_from_slice(opt);
+        buf[ARCOUNT..ARCOUNT + 2].copy_from_slice(&(counts[2] + 1).to_be_bytes());
+    }
+    freeze(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cache_key, map_ttls, match_case, set_id, truncate_at};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::{UnknownRecordData, A},
+    };
+    use std::str::FromStr;
+
+    // Response with the addresses given, a record of a type unknown, and an OPT record
+    fn response(addrs: u8) -> Message<Bytes> {
+        let name = Dname::<Bytes>::from_str("example.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        builder.header_mut().set_id(7);
+        let mut builder = builder.question();
+        builder.push((&name, Rtype::A)).unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&builder.into_message(), Rcode::NoError)
+            .unwrap();
+        builder
+            .push((
+                &name,
+                300,
+                UnknownRecordData::from_octets(Rtype::Int(65280), Bytes::from_static(b"opaque")),
+            ))
+            .unwrap();
+        for addr in 0..addrs {
+            builder
+                .push((&name, 300, A::from_octets(192, 0, 2, addr)))
+                .unwrap();
+        }
+        let mut builder = builder.additional();
+        builder
+            .opt(|opt| {
+                opt.set_udp_payload_size(1232);
+                Ok(())
+            })
+            .unwrap();
+        builder.into_message()
+    }
+
+    #[test]
+    fn id() {
+        let msg = set_id(&response(1), 42);
+        assert_eq!(msg.header().id(), 42);
+        assert_eq!(msg.as_slice()[2..], response(1).as_slice()[2..]);
+    }
+
+    #[test]
+    fn ttls() {
+        let msg = map_ttls(&response(2), |ttl| ttl.min(60)).unwrap();
+        assert!(msg.answer().unwrap().all(|r| r.unwrap().ttl() == 60));
+        // The record of the type unknown is kept.
+        assert_eq!(msg.answer().unwrap().count(), 3);
+        // The OPT record is left as it is.
+        assert_eq!(msg.opt().unwrap().udp_payload_size(), 1232);
+    }
+
+    #[test]
+    fn truncation() {
+        let msg = response(16);
+        assert_eq!(truncate_at(&msg, 512).unwrap().as_slice(), msg.as_slice());
+
+        let truncated = truncate_at(&msg, 100).unwrap();
+        assert!(truncated.as_slice().len() <= 100);
+        assert!(truncated.header().tc());
+        let answers = truncated.answer().unwrap().count();
+        assert!(answers > 0 && answers < 17);
+        assert_eq!(truncated.header_counts().ancount() as usize, answers);
+        assert_eq!(truncated.opt().unwrap().udp_payload_size(), 1232);
+
+        assert!(truncate_at(&msg, 20).is_none());
+    }
+
+    #[test]
+    fn case() {
+        let query = |name: &str, id: u16| {
+            let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+            builder.header_mut().set_id(id);
+            let mut builder = builder.question();
+            // Type 65 is an uppercase letter, which has to be left as it is.
+            builder
+                .push((Dname::<Bytes>::from_str(name).unwrap(), Rtype::Int(65)))
+                .unwrap();
+            builder.into_message()
+        };
+        let lower = query("www.example.com", 1);
+        let mixed = query("wWw.ExAmple.COM", 2);
+        assert_eq!(cache_key(&mixed), cache_key(&lower));
+        assert_eq!(cache_key(&lower), lower.as_octets().slice(2..));
+        assert_ne!(cache_key(&query("www.example.net", 1)), cache_key(&lower));
+
+        let resp = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .start_answer(&lower, Rcode::NoError)
+            .unwrap()
+            .into_message();
+        let matched = match_case(&resp, &mixed);
+        assert_eq!(
+            matched.sole_question().unwrap().qname().to_string(),
+            "wWw.ExAmple.COM"
+        );
+        assert_eq!(matched.sole_question().unwrap().qtype(), Rtype::Int(65));
+        // Left as it is for other names.
+        let other = query("ww.example.com", 2);
+        assert_eq!(match_case(&resp, &other).as_slice(), resp.as_slice());
+    }
+}
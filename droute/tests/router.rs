@@ -13,16 +13,31 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::str::FromStr;
+use std::{net::IpAddr, str::FromStr};
 
+use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use domain::{
-    base::{Dname, Message, MessageBuilder, Rtype},
-    rdata::A,
+    base::{
+        iana::{Class, Rcode},
+        Dname, Message, MessageBuilder, Record, Rtype,
+    },
+    rdata::{UnknownRecordData, A},
 };
-use droute::{builders::*, errors::*, mock::Server, AsyncTryInto, QueryContext, Upstreams};
+use droute::{
+    builders::*,
+    errors::*,
+    mock::Server,
+    testing::{MockUpstream, Reply},
+    AnyPolicy, AsyncTryInto, Cache, CacheMode, ClientInfo, Label, Listener, QueryContext,
+    RecordStatus, SlowQueryLog, Upstreams,
+};
+use futures::StreamExt;
 use once_cell::sync::Lazy;
-use tokio::net::UdpSocket;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+};
 
 static DUMMY_MSG: Lazy<Message<BytesMut>> = Lazy::new(|| {
     let name = Dname::<Bytes>::from_str("cloudflare-dns.com").unwrap();
@@ -39,6 +54,22 @@ static DUMMY_MSG: Lazy<Message<BytesMut>> = Lazy::new(|| {
     Message::from_octets(BytesMut::from(builder.as_slice())).unwrap()
 });
 
+// The same as DUMMY_MSG except that the answer is forged.
+static POISONED_MSG: Lazy<Message<BytesMut>> = Lazy::new(|| {
+    let name = Dname::<Bytes>::from_str("cloudflare-dns.com").unwrap();
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1232)).unwrap();
+    let header = builder.header_mut();
+    header.set_id(0);
+    header.set_qr(true);
+    let mut builder = builder.question();
+    builder.push((&name, Rtype::A)).unwrap();
+    let mut builder = builder.answer();
+    builder
+        .push((&name, 10, A::from_octets(10, 0, 0, 1)))
+        .unwrap();
+    Message::from_octets(BytesMut::from(builder.as_slice())).unwrap()
+});
+
 static QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
     let name = Dname::<Bytes>::from_str("cloudflare-dns.com").unwrap();
     let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1232)).unwrap();
@@ -48,6 +79,183 @@ static QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
     builder.into_message()
 });
 
+static ANY_QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
+    let name = Dname::<Bytes>::from_str("cloudflare-dns.com").unwrap();
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1232)).unwrap();
+    builder.header_mut().set_id(0);
+    let mut builder = builder.question();
+    builder.push((&name, Rtype::Any)).unwrap();
+    builder.into_message()
+});
+
+#[tokio::test]
+async fn test_any_hinfo() {
+    // No mock server is needed as the query should never reach the upstream.
+    let router = RouterBuilder::new(
+        NativeScriptBuilder::new(resolve_script),
+        UpstreamsBuilder::new(1).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                max_pool_size: 256,
+                timeout: 1,
+                ..UdpBuilder::new("127.0.0.1:53534".parse().unwrap())
+            },
+        ),
+    )
+    .any_policy(AnyPolicy::Hinfo)
+    .async_try_into()
+    .await
+    .unwrap();
+
+    let resp = router.resolve(ANY_QUERY.clone(), None).await.unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::NoError);
+    assert_eq!(
+        resp.answer().unwrap().next().unwrap().unwrap().rtype(),
+        Rtype::Hinfo
+    );
+    assert_eq!(router.stats().any, 1);
+    assert_eq!(router.stats().upstreams["mock"].queries, 0);
+}
+
+#[tokio::test]
+async fn test_deadline() {
+    let mock = MockUpstream::new()
+        .otherwise([Reply::addrs(["192.0.2.1".parse().unwrap()], 60)
+            .delay(std::time::Duration::from_secs(2))])
+        .spawn()
+        .await
+        .unwrap();
+    let router = RouterBuilder::new(
+        NativeScriptBuilder::new(resolve_script),
+        UpstreamsBuilder::new(1).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                max_pool_size: 256,
+                ..UdpBuilder::new(mock.addr())
+            },
+        ),
+    )
+    .deadline(std::time::Duration::from_millis(200))
+    .async_try_into()
+    .await
+    .unwrap();
+
+    // A query with an empty OPT record
+    let mut builder = MessageBuilder::from_target(BytesMut::new())
+        .unwrap()
+        .question();
+    builder
+        .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+        .unwrap();
+    let mut builder = builder.additional();
+    builder
+        .push(Record::new(
+            Dname::root_bytes(),
+            Class::Int(1232),
+            0,
+            UnknownRecordData::from_octets(Rtype::Opt, Bytes::new()),
+        ))
+        .unwrap();
+
+    let start = std::time::Instant::now();
+    let resp = router.resolve(builder.into_message(), None).await.unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    assert_eq!(resp.header().rcode(), Rcode::ServFail);
+    let opt = resp
+        .additional()
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .into_record::<UnknownRecordData<_>>()
+        .unwrap()
+        .unwrap();
+    let rdata = opt.data().data().as_ref();
+    // Extended DNS Error "No Reachable Authority"
+    assert_eq!(&rdata[..2], &[0, 15]);
+    assert_eq!(&rdata[4..6], &[0, 22]);
+    assert_eq!(&rdata[6..], b"deadline of 200ms exceeded");
+    assert_eq!(router.stats().errors, 1);
+}
+
+// Regression corpus of malformed queries, each of which must be answered with FORMERR without reaching the upstream.
+const MALFORMED: &[&[u8]] = &[
+    // No question
+    &[0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    // Two questions
+    &[
+        0, 1, 1, 0, 0, 2, 0, 0, 0, 0, 0, 0, 1, b'a', 0, 0, 1, 0, 1, 1, b'b', 0, 0, 1, 0, 1,
+    ],
+    // Question claimed but missing
+    &[0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0],
+    // Truncated question
+    &[
+        0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 3, b'c', b'o', b'm', 0, 0,
+    ],
+    // Label longer than the packet
+    &[0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 63, b'a', 0, 0, 1, 0, 1],
+    // Compression pointer pointing to itself
+    &[0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 12, 0, 1, 0, 1],
+    // Reserved label type
+    &[0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0x80, 0, 0, 1, 0, 1],
+];
+
+#[tokio::test]
+async fn test_malformed_queries() {
+    // No mock server is needed as the queries should never reach the upstream.
+    let router = RouterBuilder::new(
+        NativeScriptBuilder::new(resolve_script),
+        UpstreamsBuilder::new(1).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                max_pool_size: 256,
+                timeout: 1,
+                ..UdpBuilder::new("127.0.0.1:53534".parse().unwrap())
+            },
+        ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    for pkt in MALFORMED {
+        let msg = Message::from_octets(Bytes::from_static(pkt)).unwrap();
+        let resp = router.resolve(msg, None).await.unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::FormErr);
+        assert_eq!(resp.header().id(), 1);
+        assert!(resp.header().qr());
+    }
+    assert_eq!(router.stats().errors, MALFORMED.len() as u64);
+    assert_eq!(router.stats().upstreams["mock"].queries, 0);
+}
+
+#[tokio::test]
+async fn test_slow_query_log() {
+    // Every query is slow with zero threshold, tracing should not affect the result.
+    let router = RouterBuilder::new(
+        NativeScriptBuilder::new(resolve_script),
+        UpstreamsBuilder::new(1).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                max_pool_size: 256,
+                timeout: 1,
+                ..UdpBuilder::new("127.0.0.1:53534".parse().unwrap())
+            },
+        ),
+    )
+    .any_policy(AnyPolicy::Hinfo)
+    .slow_query(SlowQueryLog {
+        threshold: 0,
+        sample: None,
+    })
+    .async_try_into()
+    .await
+    .unwrap();
+
+    let resp = router.resolve(ANY_QUERY.clone(), None).await.unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::NoError);
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn test_resolve() {
     let socket = UdpSocket::bind(&"127.0.0.1:53533").await.unwrap();
@@ -59,10 +267,9 @@ async fn test_resolve() {
         UpstreamsBuilder::new(1).unwrap().add_upstream(
             "mock",
             UdpBuilder {
-                addr: "127.0.0.1:53533".parse().unwrap(),
                 max_pool_size: 256,
                 timeout: 10,
-                ratelimit: None,
+                ..UdpBuilder::new("127.0.0.1:53533".parse().unwrap())
             },
         ),
     )
@@ -78,6 +285,942 @@ async fn test_resolve() {
             .into_octets(),
         DUMMY_MSG.clone().into_octets()
     );
+
+    let stats = router.stats();
+    assert_eq!(stats.queries, 1);
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.cache.misses, 1);
+    assert_eq!(stats.upstreams["mock"].queries, 1);
+    assert_eq!(stats.upstreams["mock"].errors, 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_resolve_raw() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53546").await.unwrap();
+    let server = Server::new(socket, vec![0; 1024], None);
+    tokio::spawn(server.run(DUMMY_MSG.clone()));
+
+    let router = RouterBuilder::new(
+        NativeScriptBuilder::new(context_script),
+        UpstreamsBuilder::new(1).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                max_pool_size: 256,
+                timeout: 10,
+                ..UdpBuilder::new("127.0.0.1:53546".parse().unwrap())
+            },
+        ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+    let client = ClientInfo {
+        addr: "127.0.0.1:5353".parse().unwrap(),
+        udp_limit: Some(1232),
+        listener: Listener::Udp,
+        encrypted: false,
+    };
+
+    assert_eq!(
+        router
+            .resolve_raw(QUERY.clone().into_octets(), client)
+            .await
+            .unwrap()
+            .into_octets(),
+        DUMMY_MSG.clone().into_octets()
+    );
+    // Neither incomplete headers nor responses are answered.
+    assert!(router
+        .resolve_raw(Bytes::from_static(&[0, 1, 1]), client)
+        .await
+        .is_none());
+    assert!(router
+        .resolve_raw(DUMMY_MSG.clone().into_octets().freeze(), client)
+        .await
+        .is_none());
+    assert_eq!(router.stats().queries, 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_entries() {
+    let mock = MockUpstream::new()
+        .otherwise([Reply::addrs(["192.0.2.1".parse().unwrap()], 60)])
+        .spawn()
+        .await
+        .unwrap();
+    let script = r#"
+pub async fn route(upstreams, inited, ctx, query) {
+  upstreams.send_default("mock", query).await
+}
+
+pub async fn strict(upstreams, inited, ctx, query) {
+  blackhole(query)
+}
+"#;
+    let builder = |entry: &str| {
+        RouterBuilder::new(
+            RuneScriptBuilder::new(script),
+            UpstreamsBuilder::new(1).unwrap().add_upstream(
+                "mock",
+                UpstreamBuilder::Udp(UdpBuilder {
+                    max_pool_size: 1,
+                    timeout: 2,
+                    ..UdpBuilder::new(mock.addr())
+                }),
+            ),
+        )
+        .entries([(Listener::Doh, entry.to_string())].into_iter().collect())
+    };
+
+    let router = builder("strict").async_try_into().await.unwrap();
+    let client = |listener| ClientInfo {
+        addr: "127.0.0.1:5353".parse().unwrap(),
+        udp_limit: None,
+        listener,
+        encrypted: false,
+    };
+    // Queries over UDP start at `route`, and the ones over DoH at `strict`.
+    let resp = router
+        .resolve_raw(QUERY.clone().into_octets(), client(Listener::Udp))
+        .await
+        .unwrap();
+    assert_eq!(resp.header_counts().ancount(), 1);
+    let resp = router
+        .resolve_raw(QUERY.clone().into_octets(), client(Listener::Doh))
+        .await
+        .unwrap();
+    assert!(droute::utils::is_blackhole(&resp));
+    assert_eq!(mock.received(), 1);
+
+    assert!(matches!(
+        builder("missing").async_try_into().await,
+        Err(ScriptError::MissingEntry(e)) if e == "missing"
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_resolve_stream() {
+    let socket = UdpSocket::bind(&"127.0.0.1:53547").await.unwrap();
+    let server = Server::new(socket, vec![0; 1024], None);
+    tokio::spawn(server.run(DUMMY_MSG.clone()));
+
+    let router = RouterBuilder::new(
+        NativeScriptBuilder::new(resolve_script),
+        UpstreamsBuilder::new(1).unwrap().add_upstream(
+            "mock",
+            UdpBuilder {
+                max_pool_size: 256,
+                timeout: 10,
+                ..UdpBuilder::new("127.0.0.1:53547".parse().unwrap())
+            },
+        ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    let resps: Vec<_> = router
+        .resolve_stream(futures::stream::iter(vec![QUERY.clone(); 32]), 4)
+        .collect()
+        .await;
+    assert_eq!(resps.len(), 32);
+    for resp in resps {
+        assert_eq!(resp.unwrap().into_octets(), DUMMY_MSG.clone().into_octets());
+    }
+    assert_eq!(router.stats().queries, 32);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_ddr_fallback() {
+    // The mock server designates no encrypted resolver, answering the discovery query with the dummy message.
+    let socket = UdpSocket::bind(&"127.0.0.1:53548").await.unwrap();
+    let server = Server::new(socket, vec![0; 1024], None);
+    tokio::spawn(server.run(DUMMY_MSG.clone()));
+
+    let upstreams: Upstreams = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream(
+            "mock",
+            UdpBuilder {
+                max_pool_size: 256,
+                timeout: 10,
+                ddr: true,
+                ..UdpBuilder::new("127.0.0.1:53548".parse().unwrap())
+            },
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        upstreams
+            .send(&"mock".into(), &CacheMode::Disabled, &QUERY)
+            .await
+            .unwrap()
+            .into_octets(),
+        DUMMY_MSG.clone().into_octets()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_anti_pollution() {
+    // An upstream 100ms away, with forged answers injected right away on the path.
+    let delay = std::time::Duration::from_millis(100);
+    let mock = MockUpstream::new()
+        .on("invalid", [Reply::rcode(Rcode::NXDomain).delay(delay)])
+        .otherwise([Reply::answer(
+            Message::from_octets(DUMMY_MSG.clone().into_octets().freeze()).unwrap(),
+        )
+        .delay(delay)
+        .injected(Message::from_octets(POISONED_MSG.clone().into_octets().freeze()).unwrap())])
+        .spawn()
+        .await
+        .unwrap();
+
+    let upstreams: Upstreams = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream(
+            "mock",
+            UdpBuilder {
+                max_pool_size: 1,
+                timeout: 10,
+                anti_pollution: true,
+                ..UdpBuilder::new(mock.addr())
+            },
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+    let send = || upstreams.send(&"mock".into(), &CacheMode::Disabled, &QUERY);
+
+    // The forged answer is taken until the round trip time is probed, which starts along with the first query.
+    let resp = send().await.unwrap();
+    assert_eq!(resp.into_octets(), POISONED_MSG.clone().into_octets());
+    tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        while send().await.unwrap().into_octets() != DUMMY_MSG.clone().into_octets() {}
+    })
+    .await
+    .unwrap();
+
+    // Once probed, the forged answers are discarded every time while the genuine ones are waited for.
+    for _ in 0..4 {
+        let resp = send().await.unwrap();
+        assert_eq!(resp.into_octets(), DUMMY_MSG.clone().into_octets());
+    }
+}
+
+#[tokio::test]
+async fn test_error_kinds() {
+    // An upstream that never answers.
+    let _socket = UdpSocket::bind("127.0.0.1:53545").await.unwrap();
+    let upstreams: Upstreams = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream(
+            "mock",
+            UdpBuilder {
+                max_pool_size: 256,
+                timeout: 1,
+                ..UdpBuilder::new("127.0.0.1:53545".parse().unwrap())
+            },
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+
+    let e = upstreams
+        .send(&"mock".into(), &CacheMode::Disabled, &QUERY)
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Timeout);
+    // Categories are kept however deep the error is wrapped.
+    assert_eq!(ScriptError::from(e).kind(), ErrorKind::Timeout);
+
+    let e = upstreams
+        .send(&"missing".into(), &CacheMode::Disabled, &QUERY)
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Config);
+    assert_eq!(e.kind().to_string(), "config");
+}
+
+// A cache answering every query with the dummy message.
+struct DummyCache;
+
+#[async_trait]
+impl Cache for DummyCache {
+    async fn get(&self, _: &Label, _: &Message<Bytes>) -> Option<RecordStatus<Message<Bytes>>> {
+        Some(RecordStatus::Alive(
+            Message::from_octets(DUMMY_MSG.clone().into_octets().freeze()).unwrap(),
+        ))
+    }
+
+    async fn put(&self, _: Label, _: &Message<Bytes>, _: Message<Bytes>, _: std::time::Duration) {}
+
+    async fn purge(&self) {}
+}
+
+#[tokio::test]
+async fn test_custom_cache() {
+    // Nothing listens on the upstream, so the response must come from the cache.
+    let upstreams: Upstreams = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream(
+            "mock",
+            UdpBuilder {
+                max_pool_size: 256,
+                timeout: 1,
+                ..UdpBuilder::new("127.0.0.1:53544".parse().unwrap())
+            },
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+    let upstreams = upstreams.with_cache(DummyCache);
+
+    let resp = upstreams
+        .send(&"mock".into(), &CacheMode::Standard, &QUERY)
+        .await
+        .unwrap();
+    assert_eq!(resp.into_octets(), DUMMY_MSG.clone().into_octets());
+    assert_eq!(upstreams.cache_stats().hits, 1);
+}
+
+// HTTPS response with SvcPriority 1, TargetName `.`, and the SvcParams given.
+fn https_msg(params: &[u8]) -> Message<BytesMut> {
+    let name = Dname::<Bytes>::from_str("cloudflare-dns.com").unwrap();
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1232)).unwrap();
+    builder.header_mut().set_qr(true);
+    let mut builder = builder.question();
+    builder.push((&name, Rtype::Int(65))).unwrap();
+    let mut builder = builder.answer();
+    let rdata = [&[0, 1, 0][..], params].concat();
+    builder
+        .push((
+            &name,
+            10,
+            UnknownRecordData::from_octets(Rtype::Int(65), Bytes::from(rdata)),
+        ))
+        .unwrap();
+    Message::from_octets(BytesMut::from(builder.as_slice())).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_prefer_ech() {
+    let mut builder = UpstreamsBuilder::new(1).unwrap().add_upstream(
+        "ech",
+        UpstreamBuilder::Ech(EchBuilder::new().add_tag("plain").add_tag("with_ech")),
+    );
+    for (tag, addr, msg) in [
+        // alpn=h2
+        (
+            "plain",
+            "127.0.0.1:53542",
+            https_msg(&[0, 1, 0, 3, 2, b'h', b'2']),
+        ),
+        // ech="ech"
+        (
+            "with_ech",
+            "127.0.0.1:53543",
+            https_msg(&[0, 5, 0, 3, b'e', b'c', b'h']),
+        ),
+    ] {
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        tokio::spawn(Server::new(socket, vec![0; 1024], None).run(msg));
+        builder = builder.add_upstream(
+            tag,
+            UpstreamBuilder::Udp(UdpBuilder {
+                max_pool_size: 256,
+                timeout: 10,
+                ..UdpBuilder::new(addr.parse().unwrap())
+            }),
+        );
+    }
+    let upstreams: Upstreams = builder.async_try_into().await.unwrap();
+
+    let query = {
+        let name = Dname::<Bytes>::from_str("cloudflare-dns.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1232)).unwrap();
+        let mut builder = builder.question();
+        builder.push((&name, Rtype::Int(65))).unwrap();
+        builder.into_message()
+    };
+
+    // All the upstreams are raced at first, and the response with ECH configs is preferred.
+    upstreams
+        .send(&"ech".into(), &CacheMode::Disabled, &query)
+        .await
+        .unwrap();
+    let stats = upstreams.stats();
+    assert_eq!(stats["with_ech"].ech, 1);
+    assert_eq!(stats["plain"].ech, 0);
+    assert_eq!(stats["ech"].ech, 1);
+
+    // Then only the upstream known to return ECH configs is queried.
+    upstreams
+        .send(&"ech".into(), &CacheMode::Disabled, &query)
+        .await
+        .unwrap();
+    let stats = upstreams.stats();
+    assert_eq!(stats["plain"].queries, 1);
+    assert_eq!(stats["with_ech"].queries, 2);
+    assert_eq!(stats["ech"].ech, 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_consensus() {
+    let mut builder = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream(
+            "majority",
+            UpstreamBuilder::Consensus(
+                ConsensusBuilder::new(ConsensusMode::Majority, 2000)
+                    .add_tag("mock1")
+                    .add_tag("mock2")
+                    .add_tag("poisoned"),
+            ),
+        )
+        .add_upstream(
+            "merge",
+            UpstreamBuilder::Consensus(
+                ConsensusBuilder::new(ConsensusMode::Merge, 2000)
+                    .add_tag("mock1")
+                    .add_tag("poisoned"),
+            ),
+        );
+    for (tag, addr, msg) in [
+        ("mock1", "127.0.0.1:53538", &DUMMY_MSG),
+        ("mock2", "127.0.0.1:53539", &DUMMY_MSG),
+        ("poisoned", "127.0.0.1:53540", &POISONED_MSG),
+    ] {
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        tokio::spawn(Server::new(socket, vec![0; 1024], None).run(Message::clone(msg)));
+        builder = builder.add_upstream(
+            tag,
+            UpstreamBuilder::Udp(UdpBuilder {
+                max_pool_size: 256,
+                timeout: 10,
+                ..UdpBuilder::new(addr.parse().unwrap())
+            }),
+        );
+    }
+    let upstreams: Upstreams = builder.async_try_into().await.unwrap();
+
+    // The forged answer is outvoted.
+    let resp = upstreams
+        .send(&"majority".into(), &CacheMode::Disabled, &QUERY)
+        .await
+        .unwrap();
+    assert_eq!(resp.into_octets(), DUMMY_MSG.clone().into_octets());
+    assert_eq!(upstreams.stats()["majority"].disagreements, 1);
+
+    let resp = upstreams
+        .send(&"merge".into(), &CacheMode::Disabled, &QUERY)
+        .await
+        .unwrap();
+    let mut answers: Vec<_> = resp
+        .answer()
+        .unwrap()
+        .limit_to::<A>()
+        .map(|r| r.unwrap().data().addr().to_string())
+        .collect();
+    answers.sort();
+    assert_eq!(answers, vec!["1.1.1.1", "10.0.0.1"]);
+    assert_eq!(upstreams.stats()["merge"].disagreements, 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_guard() {
+    let nxdomain = MessageBuilder::from_target(BytesMut::new())
+        .unwrap()
+        .start_answer(&*QUERY, Rcode::NXDomain)
+        .unwrap()
+        .into_message();
+    let nxdomain = Message::from_octets(BytesMut::from(nxdomain.as_slice())).unwrap();
+    let mut builder = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream(
+            "guarded",
+            UpstreamBuilder::Guard(GuardBuilder::new("redirecting", "trusted")),
+        )
+        .add_upstream(
+            "unguarded",
+            UpstreamBuilder::Guard(GuardBuilder::new("redirecting", "honest")),
+        );
+    for (tag, addr, msg) in [
+        ("redirecting", "127.0.0.1:53549", POISONED_MSG.clone()),
+        ("trusted", "127.0.0.1:53550", nxdomain),
+        ("honest", "127.0.0.1:53551", DUMMY_MSG.clone()),
+    ] {
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        tokio::spawn(Server::new(socket, vec![0; 1024], None).run(msg));
+        builder = builder.add_upstream(
+            tag,
+            UpstreamBuilder::Udp(UdpBuilder {
+                max_pool_size: 256,
+                timeout: 10,
+                ..UdpBuilder::new(addr.parse().unwrap())
+            }),
+        );
+    }
+    let upstreams: Upstreams = builder.async_try_into().await.unwrap();
+
+    // The redirection is caught and the NXDOMAIN is returned instead.
+    let resp = upstreams
+        .send(&"guarded".into(), &CacheMode::Disabled, &QUERY)
+        .await
+        .unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+    assert_eq!(resp.header_counts().ancount(), 0);
+    assert_eq!(upstreams.stats()["redirecting"].hijacks, 1);
+
+    // Answers disagreeing otherwise are left alone.
+    let resp = upstreams
+        .send(&"unguarded".into(), &CacheMode::Disabled, &QUERY)
+        .await
+        .unwrap();
+    assert_eq!(resp.into_octets(), POISONED_MSG.clone().into_octets());
+    assert_eq!(upstreams.stats()["redirecting"].hijacks, 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_udp_retransmit() {
+    // An upstream losing the first transmission of the query
+    let mock = MockUpstream::new()
+        .otherwise([
+            Reply::dropped(),
+            Reply::answer(Message::from_octets(DUMMY_MSG.as_slice().to_vec().into()).unwrap()),
+        ])
+        .spawn()
+        .await
+        .unwrap();
+
+    let upstreams: Upstreams = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream(
+            "lossy",
+            UpstreamBuilder::Udp(UdpBuilder {
+                max_pool_size: 1,
+                timeout: 2,
+                retransmit: Some(Retransmit {
+                    initial: 100,
+                    ..Default::default()
+                }),
+                ..UdpBuilder::new(mock.addr())
+            }),
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+
+    let start = std::time::Instant::now();
+    let resp = upstreams
+        .send(&"lossy".into(), &CacheMode::Disabled, &QUERY)
+        .await
+        .unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::NoError);
+    // Answered on the retransmission long before the timeout
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    assert_eq!(mock.received(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_mock_upstream() {
+    let mock = MockUpstream::new()
+        .on("blocked.example.com", [Reply::rcode(Rcode::Refused)])
+        .on(
+            "slow.example.com",
+            [Reply::addrs(["192.0.2.2".parse().unwrap()], 60)
+                .delay(std::time::Duration::from_millis(300))],
+        )
+        .otherwise([Reply::addrs(
+            ["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()],
+            60,
+        )])
+        .spawn()
+        .await
+        .unwrap();
+
+    let upstreams: Upstreams = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream(
+            "mock",
+            UpstreamBuilder::Udp(UdpBuilder {
+                max_pool_size: 1,
+                timeout: 2,
+                ..UdpBuilder::new(mock.addr())
+            }),
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+
+    let query = |name: &str| {
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        builder.header_mut().set_id(42);
+        let mut builder = builder.question();
+        builder
+            .push((Dname::<Bytes>::from_str(name).unwrap(), Rtype::A))
+            .unwrap();
+        builder.into_message()
+    };
+
+    let resp = upstreams
+        .send(
+            &"mock".into(),
+            &CacheMode::Disabled,
+            &query("www.example.com"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.header().id(), 42);
+    let answers: Vec<_> = resp
+        .answer()
+        .unwrap()
+        .limit_to::<A>()
+        .map(|r| r.unwrap().data().addr())
+        .collect();
+    assert_eq!(
+        answers,
+        vec!["192.0.2.1".parse::<std::net::Ipv4Addr>().unwrap()]
+    );
+
+    let resp = upstreams
+        .send(
+            &"mock".into(),
+            &CacheMode::Disabled,
+            &query("ads.blocked.example.com"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::Refused);
+
+    let start = std::time::Instant::now();
+    upstreams
+        .send(
+            &"mock".into(),
+            &CacheMode::Disabled,
+            &query("slow.example.com"),
+        )
+        .await
+        .unwrap();
+    assert!(start.elapsed() >= std::time::Duration::from_millis(300));
+    assert_eq!(mock.received(), 3);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_servfail_cache() {
+    let servfail = MessageBuilder::from_target(BytesMut::new())
+        .unwrap()
+        .start_answer(&*QUERY, Rcode::ServFail)
+        .unwrap()
+        .into_message();
+    let servfail = Message::from_octets(BytesMut::from(servfail.as_slice())).unwrap();
+    let socket = UdpSocket::bind(&"127.0.0.1:53558").await.unwrap();
+    tokio::spawn(Server::new(socket, vec![0; 1024], None).run(servfail));
+
+    let upstreams: Upstreams = UpstreamsBuilder::new(10)
+        .unwrap()
+        .servfail_ttl(60)
+        .add_upstream(
+            "broken",
+            UpstreamBuilder::Udp(UdpBuilder {
+                max_pool_size: 256,
+                timeout: 10,
+                ..UdpBuilder::new("127.0.0.1:53558".parse().unwrap())
+            }),
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+
+    // The SERVFAIL is passed on the first time, and cached afterwards.
+    let resp = upstreams
+        .send(&"broken".into(), &CacheMode::Standard, &QUERY)
+        .await
+        .unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::ServFail);
+    let e = upstreams
+        .send(&"broken".into(), &CacheMode::Standard, &QUERY)
+        .await
+        .unwrap_err();
+    assert!(matches!(e, UpstreamError::CachedFailure(_)));
+    assert_eq!(upstreams.stats()["broken"].cached_failures, 1);
+
+    // Queries bypassing the cache still reach the upstream.
+    let resp = upstreams
+        .send(&"broken".into(), &CacheMode::Disabled, &QUERY)
+        .await
+        .unwrap();
+    assert_eq!(resp.header().rcode(), Rcode::ServFail);
+    assert_eq!(upstreams.stats()["broken"].cached_failures, 1);
+}
+
+#[tokio::test]
+async fn test_offline() {
+    let socket = UdpSocket::bind("127.0.0.1:53552").await.unwrap();
+    tokio::spawn(Server::new(socket, vec![0; 1024], None).run(DUMMY_MSG.clone()));
+    let upstreams: Upstreams = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream(
+            "udp",
+            UpstreamBuilder::Udp(UdpBuilder {
+                max_pool_size: 256,
+                timeout: 10,
+                ..UdpBuilder::new("127.0.0.1:53552".parse().unwrap())
+            }),
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+    let aaaa = {
+        let name = Dname::<Bytes>::from_str("cloudflare-dns.com").unwrap();
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.push((&name, Rtype::Aaaa)).unwrap();
+        builder.into_message()
+    };
+
+    upstreams
+        .send(&"udp".into(), &CacheMode::Standard, &QUERY)
+        .await
+        .unwrap();
+    upstreams.set_offline(true);
+    assert!(upstreams.offline());
+
+    // Cached responses are still served, even if the cache is bypassed otherwise.
+    let resp = upstreams
+        .send(&"udp".into(), &CacheMode::Disabled, &QUERY)
+        .await
+        .unwrap();
+    assert_eq!(resp.header_counts().ancount(), 1);
+    assert_eq!(upstreams.cache_stats().hits, 1);
+
+    // Queries not cached fail instead of reaching the upstream.
+    let err = upstreams
+        .send(&"udp".into(), &CacheMode::Standard, &aaaa)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Policy);
+
+    upstreams.set_offline(false);
+    assert!(upstreams
+        .send(&"udp".into(), &CacheMode::Disabled, &QUERY)
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn test_connectivity() {
+    let socket = UdpSocket::bind("127.0.0.1:53554").await.unwrap();
+    tokio::spawn(Server::new(socket, vec![0; 1024], None).run(DUMMY_MSG.clone()));
+    let udp = |addr: &str| {
+        UpstreamBuilder::Udp(UdpBuilder {
+            max_pool_size: 256,
+            timeout: 1,
+            ..UdpBuilder::new(addr.parse().unwrap())
+        })
+    };
+    let upstreams: Upstreams = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream("v4", udp("127.0.0.1:53554"))
+        .add_upstream("v6", udp("[::1]:53555"))
+        .add_upstream(
+            "hybrid",
+            UpstreamBuilder::Hybrid(HybridBuilder::new().add_tag("v4").add_tag("v6")),
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+    assert_eq!(upstreams.connectivity(), (true, true));
+
+    // The IPv6 upstream is left out of the race without IPv6 connectivity.
+    upstreams.set_connectivity(true, false);
+    upstreams
+        .send(&"hybrid".into(), &CacheMode::Disabled, &QUERY)
+        .await
+        .unwrap();
+    let stats = upstreams.stats();
+    assert_eq!(stats[&Label::from("v4")].queries, 1);
+    assert_eq!(stats[&Label::from("v6")].queries, 0);
+}
+
+#[tokio::test]
+async fn test_send_fallback() {
+    for (addr, msg) in [
+        ("127.0.0.1:53556", &POISONED_MSG),
+        ("127.0.0.1:53557", &DUMMY_MSG),
+    ] {
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        tokio::spawn(Server::new(socket, vec![0; 1024], None).run((*msg).clone()));
+    }
+    let udp = |addr: &str| {
+        UpstreamBuilder::Udp(UdpBuilder {
+            max_pool_size: 256,
+            timeout: 10,
+            ..UdpBuilder::new(addr.parse().unwrap())
+        })
+    };
+    let upstreams: Upstreams = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream("domestic", udp("127.0.0.1:53556"))
+        .add_upstream("secure", udp("127.0.0.1:53557"))
+        .async_try_into()
+        .await
+        .unwrap();
+    let send = |reject: fn(IpAddr) -> bool| {
+        upstreams.send_fallback(
+            &"domestic".into(),
+            &"secure".into(),
+            &CacheMode::Disabled,
+            &QUERY,
+            reject,
+        )
+    };
+
+    // Answers are kept unless rejected.
+    assert_eq!(
+        send(|_| false).await.unwrap().into_octets(),
+        POISONED_MSG.clone().into_octets()
+    );
+    assert_eq!(
+        send(|ip| ip == IpAddr::from([10, 0, 0, 1]))
+            .await
+            .unwrap()
+            .into_octets(),
+        DUMMY_MSG.clone().into_octets()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tcp_pipelined() {
+    // A TCP server answering every query with the dummy message, keeping the ID of the query.
+    let listener = TcpListener::bind("127.0.0.1:53535").await.unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        loop {
+            let mut len = [0; 2];
+            if stream.read_exact(&mut len).await.is_err() {
+                return;
+            }
+            let mut buf = vec![0; u16::from_be_bytes(len).into()];
+            stream.read_exact(&mut buf).await.unwrap();
+            let mut resp = DUMMY_MSG.clone();
+            resp.header_mut()
+                .set_id(u16::from_be_bytes([buf[0], buf[1]]));
+            let resp = resp.into_octets();
+            stream
+                .write_all(&u16::try_from(resp.len()).unwrap().to_be_bytes())
+                .await
+                .unwrap();
+            stream.write_all(&resp).await.unwrap();
+        }
+    });
+
+    let router = RouterBuilder::new(
+        NativeScriptBuilder::new(resolve_script),
+        UpstreamsBuilder::new(1).unwrap().add_upstream(
+            "mock",
+            TcpBuilder {
+                addr: "127.0.0.1:53535".parse().unwrap(),
+                connections: 1,
+                reuse_timeout: 60000,
+                max_reuse: 2000,
+                ratelimit: None,
+                timeout: 10,
+                warmup: false,
+            },
+        ),
+    )
+    .async_try_into()
+    .await
+    .unwrap();
+
+    // Both queries go through the single connection.
+    let (a, b) = tokio::join!(
+        router.resolve(QUERY.clone(), None),
+        router.resolve(QUERY.clone(), None)
+    );
+    for resp in [a, b] {
+        assert_eq!(resp.unwrap().into_octets(), DUMMY_MSG.clone().into_octets());
+    }
+}
+
+#[tokio::test]
+async fn test_tcp_warmup() {
+    let listener = TcpListener::bind("127.0.0.1:53553").await.unwrap();
+    let upstreams: Upstreams = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream(
+            "mock",
+            TcpBuilder {
+                addr: "127.0.0.1:53553".parse().unwrap(),
+                connections: 2,
+                reuse_timeout: 60000,
+                max_reuse: 2000,
+                ratelimit: None,
+                timeout: 10,
+                warmup: true,
+            },
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+
+    // Both connections are established without any query sent, and again once reconnected.
+    for round in 0..2 {
+        if round > 0 {
+            upstreams.reconnect().await;
+        }
+        for _ in 0..2 {
+            tokio::time::timeout(std::time::Duration::from_secs(5), listener.accept())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_system_upstream() {
+    let path = std::env::temp_dir().join("dcompass-test-resolv.conf");
+    // Loopback name servers are likely ourselves, and thus left out.
+    std::fs::write(&path, "nameserver 127.0.0.1\nnameserver ::1\n").unwrap();
+
+    let upstreams: Upstreams = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream(
+            "system",
+            UpstreamBuilder::System(SystemBuilder {
+                path: path.clone(),
+                ..Default::default()
+            }),
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+
+    let e = upstreams
+        .send(&"system".into(), &CacheMode::Disabled, &QUERY)
+        .await
+        .unwrap_err();
+    assert!(e.to_string().contains("no name server found"));
+    std::fs::remove_file(path).unwrap();
+}
+
+// Check the context of the queries from `test_resolve_raw` before resolving them.
+async fn context_script(
+    upstreams: Upstreams,
+    query: Message<Bytes>,
+    ctx: Option<QueryContext>,
+) -> Result<Message<Bytes>, ScriptError> {
+    let ctx = ctx.unwrap();
+    assert_eq!(ctx.ip, IpAddr::from([127, 0, 0, 1]));
+    assert_eq!(ctx.listener, Listener::Udp);
+    assert!(!ctx.encrypted);
+    assert!(!ctx.edns && !ctx.dnssec_ok);
+    assert!(ctx.entry.is_none());
+    resolve_script(upstreams, query, None).await
 }
 
 async fn resolve_script(